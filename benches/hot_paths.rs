@@ -0,0 +1,131 @@
+//! Benchmarks of the hot paths of training and inference, for catching performance
+//! regressions: `cargo bench`, or `cargo bench -- --save-baseline main` on one commit
+//! and `cargo bench -- --baseline main` on another. Inputs come from
+//! `femto_gpt::fixtures`, so every run measures the same work.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use femto_gpt::fixtures;
+use femto_gpt::funcs::{MatMul, Softmax};
+use femto_gpt::gpt::TrainingState;
+use femto_gpt::optimizer::AdamW;
+use femto_gpt::tokenizer::{SimpleTokenizer, Tokenizer};
+
+const VOCAB_SIZE: usize = 65;
+const BATCH_SIZE: usize = 8;
+
+fn matmul(c: &mut Criterion) {
+    let mut group = c.benchmark_group("matmul");
+    for n in [64, 128, 256] {
+        let (a, b) = (fixtures::tensor(&[n, n]), fixtures::tensor(&[n, n]));
+        group.throughput(Throughput::Elements((2 * n * n * n) as u64));
+        group.bench_function(format!("{}x{}", n, n), |bench| {
+            let mut f = MatMul::new();
+            bench.iter(|| f.run(&[&a, &b], false).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn softmax(c: &mut Criterion) {
+    let mut group = c.benchmark_group("softmax");
+    for (rows, cols) in [(64, 64), (256, VOCAB_SIZE), (64, 1024)] {
+        let x = fixtures::tensor(&[rows, cols]);
+        let out_grad = fixtures::tensor(&[rows, cols]);
+        let mut f = Softmax::new();
+        group.throughput(Throughput::Elements((rows * cols) as u64));
+        group.bench_function(format!("forward {}x{}", rows, cols), |bench| {
+            bench.iter(|| f.run(&[&x], false).unwrap())
+        });
+        f.run(&[&x], true).unwrap();
+        group.bench_function(format!("backward {}x{}", rows, cols), |bench| {
+            bench.iter(|| f.grad(&[&x], &out_grad).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn forward(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forward");
+    for (name, config) in [
+        ("tiny", fixtures::tiny_model()),
+        ("small", fixtures::small_model()),
+    ] {
+        let gpt = fixtures::gpt(&config, VOCAB_SIZE).unwrap();
+        let prompt = fixtures::tokens(VOCAB_SIZE, config.num_tokens);
+        group.throughput(Throughput::Elements(config.num_tokens as u64));
+        group.bench_function(name, |bench| bench.iter(|| gpt.logits(&prompt).unwrap()));
+    }
+    group.finish();
+}
+
+fn train_step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("train_step");
+    group.sample_size(10);
+    for (name, config) in [
+        ("tiny", fixtures::tiny_model()),
+        ("small", fixtures::small_model()),
+    ] {
+        let mut gpt = fixtures::gpt(&config, VOCAB_SIZE).unwrap();
+        let dataset = fixtures::tokens(VOCAB_SIZE, (config.num_tokens + 1) * 64);
+        group.throughput(Throughput::Elements(
+            (config.num_tokens * BATCH_SIZE) as u64,
+        ));
+        group.bench_function(name, |bench| {
+            bench.iter(|| gpt.train_step(&dataset, BATCH_SIZE, None, 0.).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn tokenization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenization");
+    let text = fixtures::text(1 << 16);
+    let tokenizer = SimpleTokenizer::new(&text);
+    let tokens = tokenizer.tokenize(&text).unwrap();
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    group.bench_function("tokenize", |bench| {
+        bench.iter(|| tokenizer.tokenize(black_box(&text)).unwrap())
+    });
+    group.bench_function("untokenize", |bench| {
+        bench.iter(|| tokenizer.untokenize(black_box(&tokens)).unwrap())
+    });
+    group.finish();
+}
+
+fn serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialization");
+    let gpt = fixtures::gpt(&fixtures::small_model(), VOCAB_SIZE).unwrap();
+    let state = gpt.get_training_state().unwrap();
+    let bytes = bincode::serialize(&state).unwrap();
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("serialize", |bench| {
+        bench.iter(|| bincode::serialize(black_box(&state)).unwrap())
+    });
+    group.bench_function("deserialize", |bench| {
+        bench.iter(|| bincode::deserialize::<TrainingState<AdamW>>(black_box(&bytes)).unwrap())
+    });
+    group.bench_function("set_training_state", |bench| {
+        bench.iter_batched(
+            || {
+                (
+                    fixtures::gpt(&fixtures::small_model(), VOCAB_SIZE).unwrap(),
+                    state.clone(),
+                )
+            },
+            |(mut gpt, state)| gpt.set_training_state(state, true).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    matmul,
+    softmax,
+    forward,
+    train_step,
+    tokenization,
+    serialization
+);
+criterion_main!(benches);
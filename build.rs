@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto/femto_gpt.proto");
+    // The gRPC service is generated from its protobuf definition, compiled in pure
+    // Rust so that `protoc` isn't needed
+    #[cfg(feature = "grpc")]
+    {
+        let descriptors = protox::compile(["proto/femto_gpt.proto"], ["proto"])
+            .expect("Invalid protobuf definition");
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("Unable to generate the gRPC service");
+    }
+}
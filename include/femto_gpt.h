@@ -0,0 +1,114 @@
+#ifndef FEMTO_GPT_H
+#define FEMTO_GPT_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A loaded model, along with its tokenizer
+ */
+typedef struct FemtoGpt FemtoGpt;
+
+/**
+ * Called with the text of each generated token (A NUL-terminated UTF-8 string, only
+ * valid during the call). Returns whether the generation should go on.
+ */
+typedef bool (*FemtoGptTokenCallback)(const char *text, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The reason of the last failure on this thread, or `NULL`. Valid until the next
+ * call that fails.
+ */
+const char *femto_gpt_last_error(void);
+
+/**
+ * Loads a model, given its config (The TOML saved along the training state), its
+ * saved tokenizer (Which may be empty for the ASCII tokenizer) and its weights
+ * (A training state, or the output of `GPT::write_params`). The returned model
+ * should be freed with `femto_gpt_free`.
+ *
+ * # Safety
+ *
+ * `config` should be a NUL-terminated string, and `tokenizer`/`weights` should point
+ * to `tokenizer_len`/`weights_len` bytes.
+ */
+struct FemtoGpt *femto_gpt_new(const char *config,
+                               const uint8_t *tokenizer,
+                               size_t tokenizer_len,
+                               const uint8_t *weights,
+                               size_t weights_len);
+
+/**
+ * Frees a model returned by `femto_gpt_new`. (Does nothing on `NULL`)
+ *
+ * # Safety
+ *
+ * `model` should be `NULL` or a model that's not freed yet.
+ */
+void femto_gpt_free(struct FemtoGpt *model);
+
+/**
+ * Zero for a `NULL` model
+ *
+ * # Safety
+ *
+ * `model` should be `NULL` or a valid model.
+ */
+size_t femto_gpt_vocab_size(const struct FemtoGpt *model);
+
+/**
+ * Zero for a `NULL` model
+ *
+ * # Safety
+ *
+ * `model` should be `NULL` or a valid model.
+ */
+size_t femto_gpt_context_size(const struct FemtoGpt *model);
+
+/**
+ * Writes the tokens of `text` to `out`, up to `out_len` of them. Returns the number of
+ * tokens of `text` (Which may be more than `out_len`), or -1 on failure. (Including
+ * a `NULL` model, or tokens which don't fit in 32 bits)
+ *
+ * # Safety
+ *
+ * `model` should be `NULL` or a valid model, `text` a NUL-terminated string and `out` should
+ * point to `out_len` writable tokens. (`out` may be `NULL` if `out_len` is zero)
+ */
+ptrdiff_t femto_gpt_tokenize(const struct FemtoGpt *model,
+                             const char *text,
+                             uint32_t *out,
+                             size_t out_len);
+
+/**
+ * Continues `prompt` with up to `max_tokens` tokens, handing each of them to
+ * `callback` along with `user_data`. (Zero `temperature` or `top_p` means greedy
+ * decoding) Only the last tokens of the prompt that fit in the context of the model
+ * are considered. Returns the number of generated tokens, or -1 on failure.
+ *
+ * # Safety
+ *
+ * `model` should be `NULL` or a valid model and `prompt` a NUL-terminated string.
+ */
+ptrdiff_t femto_gpt_generate(const struct FemtoGpt *model,
+                             const char *prompt,
+                             size_t max_tokens,
+                             float temperature,
+                             float top_p,
+                             FemtoGptTokenCallback callback,
+                             void *user_data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FEMTO_GPT_H */
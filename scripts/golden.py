@@ -0,0 +1,418 @@
+#!/usr/bin/env python3
+"""Exports the fixtures of the golden-value tests (src/golden.rs).
+
+A tiny model is given fixed weights, and the reference values of its forward pass,
+its loss on a fixed window, its gradient norm and its weights after the first AdamW
+step are computed here in float64, following the architecture of `GPT::new` op by
+op, with the same semantics as their PyTorch counterparts (LayerNorm with biased
+variance and eps=1e-5, softmax, cross-entropy averaged over the positions, AdamW
+with decoupled weight decay).
+
+Only the standard library is needed, the gradients being computed by a small
+reverse-mode autodiff. When PyTorch is installed, the same model is also run with
+its ops and optimizer in float64, and the fixtures are only written if both agree
+(`--torch` makes PyTorch required). Run from the root of the repository:
+
+    python3 scripts/golden.py [--torch]
+
+and commit the updated fixtures/golden/*.npz. Regenerate them only when the model
+is changed on purpose, never to make a failing test pass.
+"""
+
+import io
+import math
+import os
+import struct
+import sys
+import zipfile
+
+# Hyperparameters of the model, mirrored in src/golden.rs
+VOCAB_SIZE = 5
+EMBEDDING_DEGREE = 4
+NUM_TOKENS = 3
+NUM_LAYERS = 2
+NUM_HEADS = 2
+HEAD_SIZE = 2
+# The window trained on: the context, then the token completing it. Tokens repeat,
+# for the gradients of their occurrences to be summed in their embedding.
+WINDOW = [3, 1, 3, 1]
+LEARNING_RATE = 0.01
+BETA1, BETA2, WEIGHT_DECAY, ADAM_EPS = 0.9, 0.999, 0.01, 1e-8
+LAYER_NORM_EPS = 1e-5
+
+OUT_DIR = os.path.join(os.path.dirname(__file__), "..", "fixtures", "golden")
+
+
+class Value:
+    """A scalar of the computation, remembering how to backpropagate through it"""
+
+    __slots__ = ("data", "grad", "parents")
+
+    def __init__(self, data, parents=()):
+        self.data = data
+        self.grad = 0.0
+        # (Parent, local derivative) pairs
+        self.parents = parents
+
+    def __add__(self, other):
+        other = lift(other)
+        return Value(self.data + other.data, ((self, 1.0), (other, 1.0)))
+
+    __radd__ = __add__
+
+    def __neg__(self):
+        return self * -1.0
+
+    def __sub__(self, other):
+        return self + -lift(other)
+
+    def __rsub__(self, other):
+        return lift(other) - self
+
+    def __mul__(self, other):
+        other = lift(other)
+        return Value(self.data * other.data, ((self, other.data), (other, self.data)))
+
+    __rmul__ = __mul__
+
+    def __truediv__(self, other):
+        return self * lift(other) ** -1.0
+
+    def __pow__(self, k):
+        return Value(self.data**k, ((self, k * self.data ** (k - 1)),))
+
+    def exp(self):
+        e = math.exp(self.data)
+        return Value(e, ((self, e),))
+
+    def log(self):
+        return Value(math.log(self.data), ((self, 1.0 / self.data),))
+
+    def leaky_relu(self):
+        slope = 1.0 if self.data > 0 else 0.01
+        return Value(self.data * slope, ((self, slope),))
+
+    def backward(self):
+        order, seen = [], set()
+        stack = [(self, False)]
+        while stack:
+            v, expanded = stack.pop()
+            if expanded:
+                order.append(v)
+            elif id(v) not in seen:
+                seen.add(id(v))
+                stack.append((v, True))
+                stack.extend((p, False) for p, _ in v.parents)
+        self.grad = 1.0
+        for v in reversed(order):
+            for p, d in v.parents:
+                p.grad += d * v.grad
+
+
+def lift(x):
+    return x if isinstance(x, Value) else Value(float(x))
+
+
+def f32(x):
+    return struct.unpack("<f", struct.pack("<f", x))[0]
+
+
+class Param:
+    def __init__(self, name, shape, values):
+        self.name = name
+        self.shape = shape
+        self.values = [Value(f32(v)) for v in values]
+
+    def rows(self):
+        cols = self.shape[-1]
+        return [self.values[i : i + cols] for i in range(0, len(self.values), cols)]
+
+
+def init(index, shape, kind):
+    """Deterministic weights, of the magnitude of a trained model's"""
+    size = math.prod(shape)
+    values = [math.sin(1.7 * j + 0.9 * index + 0.3) for j in range(size)]
+    if kind == "gain":
+        return [1.0 + 0.2 * v for v in values]
+    if kind == "bias":
+        return [0.1 * v for v in values]
+    return [0.5 * v for v in values]
+
+
+def build_params():
+    """The parameters, in the order `GPT::new` allocates them"""
+    specs = [
+        ("token_embedding", [VOCAB_SIZE, EMBEDDING_DEGREE], "weight"),
+        ("pos_embedding", [NUM_TOKENS, EMBEDDING_DEGREE], "weight"),
+    ]
+    for l in range(NUM_LAYERS):
+        specs += [
+            (f"norm_{l}_coeff", [EMBEDDING_DEGREE], "gain"),
+            (f"norm_{l}_bias", [EMBEDDING_DEGREE], "bias"),
+        ]
+        for h in range(NUM_HEADS):
+            specs += [
+                (f"head_{l}_{h}_{w}", [EMBEDDING_DEGREE, HEAD_SIZE], "weight")
+                for w in "kqv"
+            ]
+        specs += [
+            (f"proj_{l}_weights", [NUM_HEADS * HEAD_SIZE, EMBEDDING_DEGREE], "weight"),
+            (f"proj_{l}_bias", [EMBEDDING_DEGREE], "bias"),
+            (f"atten_norm_{l}_coeff", [EMBEDDING_DEGREE], "gain"),
+            (f"atten_norm_{l}_bias", [EMBEDDING_DEGREE], "bias"),
+            (f"feedforward1_{l}_weights", [EMBEDDING_DEGREE, 4 * EMBEDDING_DEGREE], "weight"),
+            (f"feedforward1_{l}_bias", [4 * EMBEDDING_DEGREE], "bias"),
+            (f"feedforward2_{l}_weights", [4 * EMBEDDING_DEGREE, EMBEDDING_DEGREE], "weight"),
+            (f"feedforward2_{l}_bias", [EMBEDDING_DEGREE], "bias"),
+        ]
+    specs += [
+        ("head_norm_coeff", [EMBEDDING_DEGREE], "gain"),
+        ("head_norm_bias", [EMBEDDING_DEGREE], "bias"),
+        ("head_map_weights", [EMBEDDING_DEGREE, VOCAB_SIZE], "weight"),
+        ("head_map_bias", [VOCAB_SIZE], "bias"),
+    ]
+    return {
+        name: Param(name, shape, init(i, shape, kind))
+        for i, (name, shape, kind) in enumerate(specs)
+    }
+
+
+def matmul(a, b):
+    """[n, k] x [k, m] (As lists of rows)"""
+    return [[sum((x * y for x, y in zip(row, col)), Value(0.0)) for col in zip(*b)] for row in a]
+
+
+def add_bias(a, bias):
+    return [[x + b for x, b in zip(row, bias)] for row in a]
+
+
+def add(a, b):
+    return [[x + y for x, y in zip(r, s)] for r, s in zip(a, b)]
+
+
+def layer_norm(a, gain, bias):
+    out = []
+    for row in a:
+        n = len(row)
+        mean = sum(row, Value(0.0)) / n
+        var = sum(((x - mean) ** 2 for x in row), Value(0.0)) / n
+        std_inv = (var + LAYER_NORM_EPS) ** -0.5
+        out.append([(x - mean) * std_inv * g + b for x, g, b in zip(row, gain, bias)])
+    return out
+
+
+def softmax(row):
+    m = max(v.data for v in row if v is not None)
+    exps = [(v - m).exp() if v is not None else Value(0.0) for v in row]
+    total = sum(exps, Value(0.0))
+    return [e / total for e in exps]
+
+
+def forward(p, tokens):
+    """Hidden states after each block, the normalized output and the logits"""
+    x = [
+        [t + q for t, q in zip(p["token_embedding"].rows()[tok], p["pos_embedding"].rows()[i])]
+        for i, tok in enumerate(tokens)
+    ]
+    hidden = []
+    for l in range(NUM_LAYERS):
+        norm = layer_norm(x, p[f"norm_{l}_coeff"].values, p[f"norm_{l}_bias"].values)
+        heads = []
+        for h in range(NUM_HEADS):
+            k = matmul(norm, p[f"head_{l}_{h}_k"].rows())
+            q = matmul(norm, p[f"head_{l}_{h}_q"].rows())
+            v = matmul(norm, p[f"head_{l}_{h}_v"].rows())
+            # Scores are k·qᵀ, and position i attends to positions j <= i
+            scores = [
+                [
+                    sum((a * b for a, b in zip(k[i], q[j])), Value(0.0)) * HEAD_SIZE**-0.5
+                    if j <= i
+                    else None
+                    for j in range(len(tokens))
+                ]
+                for i in range(len(tokens))
+            ]
+            heads.append(matmul([softmax(row) for row in scores], v))
+        cat = [sum((head[i] for head in heads), []) for i in range(len(tokens))]
+        proj = add_bias(matmul(cat, p[f"proj_{l}_weights"].rows()), p[f"proj_{l}_bias"].values)
+        # The residual connection is taken after the normalization
+        atten = layer_norm(
+            add(norm, proj),
+            p[f"atten_norm_{l}_coeff"].values,
+            p[f"atten_norm_{l}_bias"].values,
+        )
+        ff = add_bias(
+            matmul(atten, p[f"feedforward1_{l}_weights"].rows()),
+            p[f"feedforward1_{l}_bias"].values,
+        )
+        ff = [[v.leaky_relu() for v in row] for row in ff]
+        ff = add_bias(
+            matmul(ff, p[f"feedforward2_{l}_weights"].rows()),
+            p[f"feedforward2_{l}_bias"].values,
+        )
+        x = add(atten, ff)
+        hidden.append(x)
+    out = layer_norm(x, p["head_norm_coeff"].values, p["head_norm_bias"].values)
+    logits = add_bias(matmul(out, p["head_map_weights"].rows()), p["head_map_bias"].values)
+    return hidden, out, logits
+
+
+def log_softmax(row):
+    m = max(v.data for v in row)
+    lse = sum(((v - m).exp() for v in row), Value(0.0)).log() + m
+    return [v - lse for v in row]
+
+
+def adamw(param):
+    """The first step of AdamW, from zero moments"""
+    out = []
+    for v in param.values:
+        g = v.grad
+        m = (1 - BETA1) * g
+        s = (1 - BETA2) * g * g
+        m_hat = m / (1 - BETA1)
+        s_hat = s / (1 - BETA2)
+        w = v.data * (1 - LEARNING_RATE * WEIGHT_DECAY)
+        out.append(w - LEARNING_RATE * m_hat / (math.sqrt(s_hat) + ADAM_EPS))
+    return out
+
+
+def npy(shape, values):
+    dims = ", ".join(str(d) for d in shape) + ("," if len(shape) == 1 else "")
+    header = "{'descr': '<f4', 'fortran_order': False, 'shape': (%s), }" % dims
+    # The header is padded so that the data is aligned on 64 bytes
+    header = header.ljust(-(-(10 + len(header) + 1) // 64) * 64 - 10 - 1) + "\n"
+    data = b"".join(struct.pack("<f", v) for v in values)
+    return b"\x93NUMPY\x01\x00" + struct.pack("<H", len(header)) + header.encode() + data
+
+
+def write_npz(path, tensors):
+    buf = io.BytesIO()
+    with zipfile.ZipFile(buf, "w", zipfile.ZIP_STORED) as z:
+        for name, (shape, values) in tensors.items():
+            info = zipfile.ZipInfo(f"{name}.npy", date_time=(1980, 1, 1, 0, 0, 0))
+            z.writestr(info, npy(shape, values))
+    with open(path, "wb") as f:
+        f.write(buf.getvalue())
+    print(f"Wrote {path}")
+
+
+def flatten(rows):
+    return [v.data for row in rows for v in row]
+
+
+def torch_reference(params):
+    """The fixtures of `main`, computed with PyTorch"""
+    import torch
+    import torch.nn.functional as F
+
+    t = {
+        n: torch.tensor([v.data for v in p.values], dtype=torch.float64)
+        .reshape(p.shape)
+        .requires_grad_()
+        for n, p in params.items()
+    }
+    context = torch.tensor(WINDOW[:-1])
+    target = torch.tensor(WINDOW[1:])
+    n = len(context)
+
+    def norm(x, name):
+        return F.layer_norm(
+            x, (EMBEDDING_DEGREE,), t[f"{name}_coeff"], t[f"{name}_bias"], LAYER_NORM_EPS
+        )
+
+    x = t["token_embedding"][context] + t["pos_embedding"][:n]
+    causal = torch.ones(n, n, dtype=torch.bool).tril()
+    hidden = []
+    for l in range(NUM_LAYERS):
+        normed = norm(x, f"norm_{l}")
+        heads = []
+        for h in range(NUM_HEADS):
+            k, q, v = (normed @ t[f"head_{l}_{h}_{w}"] for w in "kqv")
+            scores = (k @ q.T * HEAD_SIZE**-0.5).masked_fill(~causal, float("-inf"))
+            heads.append(torch.softmax(scores, dim=-1) @ v)
+        proj = torch.cat(heads, dim=-1) @ t[f"proj_{l}_weights"] + t[f"proj_{l}_bias"]
+        atten = norm(normed + proj, f"atten_norm_{l}")
+        ff = F.leaky_relu(atten @ t[f"feedforward1_{l}_weights"] + t[f"feedforward1_{l}_bias"], 0.01)
+        x = atten + ff @ t[f"feedforward2_{l}_weights"] + t[f"feedforward2_{l}_bias"]
+        hidden.append(x)
+    out = norm(x, "head_norm")
+    logits = out @ t["head_map_weights"] + t["head_map_bias"]
+    loss = F.cross_entropy(logits, target)
+    loss.backward()
+    grad_norm = torch.sqrt(sum((p.grad**2).sum() for p in t.values()))
+    log_probs = torch.log_softmax(logits, dim=-1)[torch.arange(n), target]
+    torch.optim.AdamW(
+        t.values(),
+        lr=LEARNING_RATE,
+        betas=(BETA1, BETA2),
+        eps=ADAM_EPS,
+        weight_decay=WEIGHT_DECAY,
+    ).step()
+
+    def values(x):
+        return x.detach().flatten().tolist()
+
+    reference = {
+        "output": values(out),
+        "log_probs": values(log_probs),
+        "loss": [loss.item()],
+        "grad_norm": [grad_norm.item()],
+    }
+    for l, h in enumerate(hidden):
+        reference[f"hidden_{l}"] = values(h)
+    for name, p in t.items():
+        reference[f"step_1/{name}"] = values(p)
+    return reference
+
+
+def cross_check(params, expected):
+    """Fails unless PyTorch computes the same fixtures, when it's installed"""
+    try:
+        reference = torch_reference(params)
+    except ImportError:
+        if "--torch" in sys.argv:
+            raise
+        print("PyTorch isn't installed, the fixtures aren't cross-checked")
+        return
+    for name, values in reference.items():
+        for a, b in zip(expected[name][1], values):
+            if not math.isclose(a, b, rel_tol=1e-9, abs_tol=1e-12):
+                sys.exit(f"{name} differs from PyTorch's: {a} != {b}")
+    print("The fixtures match PyTorch's")
+
+
+def main():
+    params = build_params()
+    context, target = WINDOW[:-1], WINDOW[1:]
+
+    hidden, out, logits = forward(params, context)
+    log_probs = [log_softmax(row) for row in logits]
+    loss = -sum((lp[t] for lp, t in zip(log_probs, target)), Value(0.0)) / len(target)
+    loss.backward()
+    grad_norm = math.sqrt(sum(v.grad**2 for p in params.values() for v in p.values))
+
+    expected = {
+        "context": ([len(context)], [float(t) for t in context]),
+        "target": ([len(target)], [float(t) for t in target]),
+        "output": ([len(context), EMBEDDING_DEGREE], flatten(out)),
+        "log_probs": ([len(target)], [lp[t].data for lp, t in zip(log_probs, target)]),
+        "loss": ([], [loss.data]),
+        "grad_norm": ([], [grad_norm]),
+    }
+    for l, h in enumerate(hidden):
+        expected[f"hidden_{l}"] = ([len(context), EMBEDDING_DEGREE], flatten(h))
+    for n, p in params.items():
+        expected[f"step_1/{n}"] = (p.shape, adamw(p))
+    cross_check(params, expected)
+
+    os.makedirs(OUT_DIR, exist_ok=True)
+    write_npz(
+        os.path.join(OUT_DIR, "weights.npz"),
+        {n: (p.shape, [v.data for v in p.values]) for n, p in params.items()},
+    )
+    write_npz(os.path.join(OUT_DIR, "expected.npz"), expected)
+
+
+if __name__ == "__main__":
+    main()
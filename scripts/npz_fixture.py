@@ -0,0 +1,49 @@
+#!/usr/bin/env python3
+"""Exports the fixture of the `.npz` reading test (src/tensor/npy.rs).
+
+The archive is written with the very `zipfile` calls `numpy.savez` makes, which
+stream every array into a zip64 entry: its local header holds 0xffffffff sizes, the
+actual ones being in a zip64 extra field. When numpy is installed, the archive is
+checked to load back with `numpy.load`. Run from the root of the repository:
+
+    python3 scripts/npz_fixture.py
+"""
+
+import os
+import struct
+import zipfile
+
+from golden import npy
+
+OUT = os.path.join(os.path.dirname(__file__), "..", "fixtures", "npz", "savez.npz")
+
+TENSORS = {
+    "a": ((2, 3), [1.0, -2.0, 3.5, 0.0, 1e-3, 7.0]),
+    "b": ((4,), [0.25, 0.5, 0.75, 1.0]),
+    "c": ((), [42.0]),
+}
+
+
+def main():
+    # As `numpy.lib.npyio._savez`, with a fixed date for the fixture to be reproducible
+    with zipfile.ZipFile(OUT, mode="w", compression=zipfile.ZIP_STORED, allowZip64=True) as z:
+        for name, (shape, values) in TENSORS.items():
+            info = zipfile.ZipInfo(f"{name}.npy", date_time=(1980, 1, 1, 0, 0, 0))
+            with z.open(info, "w", force_zip64=True) as f:
+                f.write(npy(shape, values))
+    try:
+        import numpy
+    except ImportError:
+        pass
+    else:
+        with numpy.load(OUT) as loaded:
+            for name, (shape, values) in TENSORS.items():
+                assert loaded[name].shape == shape
+                assert loaded[name].ravel().tolist() == [
+                    struct.unpack("<f", struct.pack("<f", v))[0] for v in values
+                ]
+    print(f"Wrote {OUT}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,168 @@
+//! Benchmarks of the building blocks of training and inference, for comparing machines
+//! and checking optimizations. (GPU kernels are left out, as the command-line tool is
+//! only built without the `gpu` feature)
+
+use femto_gpt::config::ModelConfig;
+use femto_gpt::gpt::Sampling;
+use femto_gpt::graph::GraphError;
+use femto_gpt::optimizer::AdamW;
+use femto_gpt::tensor::Tensor;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// What to benchmark, and how hard
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Sizes of the square matrices multiplied
+    pub matmul_sizes: Vec<usize>,
+    /// The model trained and sampled from
+    pub model: ModelConfig,
+    pub vocab_size: usize,
+    pub batch_size: usize,
+    /// Tokens generated per generation run
+    pub gen_tokens: usize,
+    /// Every measurement is repeated at least this many times, and until it took this
+    /// long. The median is reported.
+    pub min_runs: usize,
+    pub min_time: Duration,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            matmul_sizes: vec![128, 256, 512],
+            model: ModelConfig::default(),
+            vocab_size: 65,
+            batch_size: 32,
+            gen_tokens: 64,
+            min_runs: 3,
+            min_time: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A single measurement of the report
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub name: String,
+    pub value: f64,
+    pub unit: &'static str,
+}
+
+/// Median duration of `f`, after a warm-up run
+fn measure<F: FnMut() -> Result<(), GraphError>>(
+    opts: &BenchOptions,
+    mut f: F,
+) -> Result<Duration, GraphError> {
+    f()?;
+    let mut times = Vec::new();
+    let start = Instant::now();
+    while times.len() < opts.min_runs.max(1) || start.elapsed() < opts.min_time {
+        let timer = Instant::now();
+        f()?;
+        times.push(timer.elapsed());
+    }
+    times.sort();
+    Ok(times[times.len() / 2])
+}
+
+pub fn run(opts: &BenchOptions) -> Result<Vec<BenchResult>, GraphError> {
+    let mut rng = rand::thread_rng();
+    let mut results = Vec::new();
+
+    for &n in opts.matmul_sizes.iter() {
+        let a = Tensor::<f32>::rand(&mut rng, &[n, n]);
+        let b = Tensor::<f32>::rand(&mut rng, &[n, n]);
+        let time = measure(opts, || {
+            (&a ^ &b)?;
+            Ok(())
+        })?;
+        results.push(BenchResult {
+            name: format!("matmul {}x{}", n, n),
+            value: 2. * (n * n * n) as f64 / time.as_secs_f64() / 1e9,
+            unit: "GFLOPS",
+        });
+    }
+
+    let m = &opts.model;
+    let mut gpt = m.build(&mut rng, opts.vocab_size, AdamW::new())?;
+    let dataset = (0..(m.num_tokens + 1) * 64)
+        .map(|_| rng.gen_range(0..opts.vocab_size))
+        .collect::<Vec<_>>();
+    let mut tokens_per_second = 0.;
+    let time = measure(opts, || {
+        let stats = gpt.train_step(&dataset, opts.batch_size, None, 0.)?;
+        tokens_per_second = stats.tokens_per_second;
+        Ok(())
+    })?;
+    let name = format!(
+        "train step ({} layers, {} embedding-degree, {} tokens, batch {})",
+        m.num_layers, m.embedding_degree, m.num_tokens, opts.batch_size
+    );
+    results.push(BenchResult {
+        name: name.clone(),
+        value: time.as_secs_f64() * 1e3,
+        unit: "ms",
+    });
+    results.push(BenchResult {
+        name,
+        value: tokens_per_second as f64,
+        unit: "tokens/s",
+    });
+
+    // Greedy, so that every run does the same work
+    let sampling = Sampling {
+        temperature: 0.,
+        top_p: 1.,
+    };
+    let prompt = &dataset[..m.num_tokens];
+    let time = measure(opts, || {
+        gpt.generate(&mut rng, prompt, opts.gen_tokens, &sampling, |_| true)?;
+        Ok(())
+    })?;
+    results.push(BenchResult {
+        name: format!("generation ({} tokens of context)", m.num_tokens),
+        value: opts.gen_tokens as f64 / time.as_secs_f64(),
+        unit: "tokens/s",
+    });
+    Ok(results)
+}
+
+pub fn report(results: &[BenchResult]) -> String {
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    results
+        .iter()
+        .map(|r| format!("{:width$}  {:>10.2} {}\n", r.name, r.value, r.unit))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench() {
+        let opts = BenchOptions {
+            matmul_sizes: vec![8],
+            model: ModelConfig {
+                num_tokens: 4,
+                embedding_degree: 8,
+                num_layers: 1,
+                num_heads: 2,
+                dropout: 0.,
+                ..Default::default()
+            },
+            vocab_size: 10,
+            batch_size: 2,
+            gen_tokens: 3,
+            min_runs: 2,
+            min_time: Duration::ZERO,
+        };
+        let results = run(&opts).unwrap();
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|r| r.value > 0.));
+        let report = report(&results);
+        assert_eq!(report.lines().count(), 4);
+        assert!(report.starts_with("matmul 8x8"));
+    }
+}
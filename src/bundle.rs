@@ -0,0 +1,353 @@
+//! Single-file `.femto` bundles, holding the config, the tokenizer, the weights and
+//! optionally the optimizer state of a model, so that they can't get out of sync.
+//!
+//! A bundle starts with the `FEMTO` magic and a version byte, followed by the index:
+//! the number of sections, then the name, offset, length and XXH64 of each, and the
+//! XXH64 of everything up to there. The sections come after the index:
+//!
+//! - `config`: the config of the model, as TOML
+//! - `tokenizer`: the saved tokenizer (Empty for the ASCII tokenizer)
+//! - `weights`: the parameters, as written by `GPT::write_params`
+//! - `optimizer`: the optimizer state and the data seed (Bincode), for resuming the
+//!   training (Optional)
+//! - `run`: the run that trained the model, as JSON (Optional, see `run::RunInfo`)
+//! - `provenance`: the hashes of the dataset and the tokenizer the model was trained
+//!   with, as JSON (Optional, see `gpt::Provenance`)
+//!
+//! Unknown sections are skipped, so that newer bundles stay readable.
+
+use crate::config::{Config, ConfigError};
+use crate::gpt::{Provenance, TrainingState, GPT};
+use crate::graph::GraphError;
+use crate::optimizer::AdamW;
+use crate::run::RunInfo;
+use crate::tensor::{write_tensors, xxh64};
+use crate::tokenizer::Tokenizer;
+use std::io::{Read, Write};
+use std::path::Path;
+
+pub const EXTENSION: &str = "femto";
+const MAGIC: &[u8] = b"FEMTO";
+const VERSION: u8 = 1;
+
+/// Everything a model is made of
+#[derive(Clone)]
+pub struct Bundle {
+    pub config: Config,
+    /// The saved tokenizer (Empty for the ASCII tokenizer)
+    pub tokenizer: Vec<u8>,
+    /// The parameters, as written by `GPT::write_params`
+    pub weights: Vec<u8>,
+    /// The optimizer state and the data seed, left out of bundles for inference
+    pub optimizer: Option<(AdamW, u64)>,
+    pub run: Option<RunInfo>,
+    pub provenance: Provenance,
+}
+
+fn invalid<S: Into<String>>(reason: S) -> ConfigError {
+    ConfigError::InvalidBundle(reason.into())
+}
+
+/// Reads the little-endian integer at `pos`, moving past it
+fn read_u64(bytes: &[u8], pos: &mut usize) -> Result<u64, ConfigError> {
+    let v = bytes
+        .get(*pos..*pos + 8)
+        .ok_or_else(|| invalid("truncated index"))?;
+    *pos += 8;
+    Ok(u64::from_le_bytes(v.try_into().unwrap()))
+}
+
+/// Whether `path` is a bundle: going by its contents if it exists, or else by its
+/// extension (For the files about to be written)
+pub fn is_bundle<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    match std::fs::File::open(path) {
+        Ok(mut f) => {
+            let mut magic = [0; MAGIC.len()];
+            f.read_exact(&mut magic).is_ok() && magic == MAGIC
+        }
+        Err(_) => path.extension().and_then(|e| e.to_str()) == Some(EXTENSION),
+    }
+}
+
+impl Bundle {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ConfigError> {
+        let mut sections = vec![
+            ("config", self.config.to_toml().into_bytes()),
+            ("tokenizer", self.tokenizer.clone()),
+            ("weights", self.weights.clone()),
+        ];
+        if let Some(optimizer) = &self.optimizer {
+            sections.push(("optimizer", bincode::serialize(optimizer)?));
+        }
+        if let Some(run) = &self.run {
+            sections.push(("run", run.to_json().into_bytes()));
+        }
+        if self.provenance != Provenance::default() {
+            let json = serde_json::to_vec(&self.provenance).map_err(|e| invalid(e.to_string()))?;
+            sections.push(("provenance", json));
+        }
+        let index_size = MAGIC.len()
+            + 1
+            + 8
+            + sections
+                .iter()
+                .map(|(n, _)| 8 + n.len() + 24)
+                .sum::<usize>()
+            + 8;
+        let mut out = Vec::new();
+        out.extend(MAGIC);
+        out.push(VERSION);
+        out.extend((sections.len() as u64).to_le_bytes());
+        let mut offset = index_size;
+        for (name, data) in sections.iter() {
+            out.extend((name.len() as u64).to_le_bytes());
+            out.extend(name.as_bytes());
+            out.extend((offset as u64).to_le_bytes());
+            out.extend((data.len() as u64).to_le_bytes());
+            out.extend(xxh64(data, 0).to_le_bytes());
+            offset += data.len();
+        }
+        out.extend(xxh64(&out, 0).to_le_bytes());
+        for (_, data) in sections {
+            out.extend(data);
+        }
+        Ok(out)
+    }
+
+    /// Reads a bundle, failing on corrupted or truncated input
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ConfigError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(invalid("not a bundle"));
+        }
+        let mut pos = MAGIC.len();
+        match bytes.get(pos) {
+            Some(&VERSION) => pos += 1,
+            Some(v) => return Err(invalid(format!("unsupported version {}", v))),
+            None => return Err(invalid("truncated index")),
+        }
+        let count = read_u64(bytes, &mut pos)?;
+        let mut sections = Vec::new();
+        for _ in 0..count {
+            let name_len = read_u64(bytes, &mut pos)? as usize;
+            let name = bytes
+                .get(pos..pos.saturating_add(name_len))
+                .ok_or_else(|| invalid("truncated index"))?;
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|_| invalid("section name is not utf-8"))?;
+            pos += name_len;
+            let (offset, len, checksum) = (
+                read_u64(bytes, &mut pos)? as usize,
+                read_u64(bytes, &mut pos)? as usize,
+                read_u64(bytes, &mut pos)?,
+            );
+            sections.push((name, offset, len, checksum));
+        }
+        let index = &bytes[..pos];
+        if read_u64(bytes, &mut pos)? != xxh64(index, 0) {
+            return Err(invalid("checksum mismatch in the index"));
+        }
+        let section = |name: &str| -> Result<Option<&[u8]>, ConfigError> {
+            let Some((_, offset, len, checksum)) = sections.iter().find(|s| s.0 == name) else {
+                return Ok(None);
+            };
+            let data = bytes
+                .get(*offset..offset.saturating_add(*len))
+                .ok_or_else(|| invalid(format!("truncated section '{}'", name)))?;
+            if xxh64(data, 0) != *checksum {
+                return Err(invalid(format!("checksum mismatch in section '{}'", name)));
+            }
+            Ok(Some(data))
+        };
+        let required = |name: &str| {
+            section(name)?.ok_or_else(|| invalid(format!("missing section '{}'", name)))
+        };
+        let config =
+            std::str::from_utf8(required("config")?).map_err(|_| invalid("config is not utf-8"))?;
+        Ok(Self {
+            config: Config::from_toml(config)?,
+            tokenizer: required("tokenizer")?.to_vec(),
+            weights: required("weights")?.to_vec(),
+            optimizer: section("optimizer")?
+                .map(bincode::deserialize)
+                .transpose()?,
+            run: section("run")?.map(RunInfo::from_json).transpose()?,
+            provenance: section("provenance")?
+                .map(serde_json::from_slice)
+                .transpose()
+                .map_err(|e| invalid(e.to_string()))?
+                .unwrap_or_default(),
+        })
+    }
+
+    /// The bundle of a model, from a snapshot of it (See `GPT::get_training_state`)
+    pub fn from_state(
+        config: Config,
+        tokenizer: Vec<u8>,
+        state: TrainingState<AdamW>,
+        with_optimizer: bool,
+    ) -> Result<Self, ConfigError> {
+        let tensors = state
+            .tensors
+            .iter()
+            .map(|(name, t)| (name.as_str(), t))
+            .collect::<Vec<_>>();
+        let mut weights = Vec::new();
+        write_tensors(&mut weights, &tensors).map_err(GraphError::from)?;
+        Ok(Self {
+            config,
+            tokenizer,
+            weights,
+            optimizer: with_optimizer.then_some((state.optimizer, state.data_seed)),
+            run: None,
+            provenance: state.provenance,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        Ok(std::fs::write(path, self.to_bytes()?)?)
+    }
+
+    /// Loads the weights (And the optimizer state, if any) into `gpt`, which has to
+    /// have been built from the config of the bundle
+    pub fn load_into(&self, gpt: &mut GPT<AdamW>) -> Result<(), ConfigError> {
+        let missing = gpt.read_params(&self.weights[..])?;
+        if !missing.is_empty() {
+            return Err(invalid(format!("no weights for {}", missing.join(", "))));
+        }
+        if let Some((optimizer, data_seed)) = &self.optimizer {
+            let state = TrainingState {
+                tensors: Default::default(),
+                optimizer: optimizer.clone(),
+                data_seed: *data_seed,
+                provenance: self.provenance,
+            };
+            gpt.set_training_state(state, true)?;
+        }
+        gpt.set_provenance(self.provenance);
+        Ok(())
+    }
+}
+
+impl GPT<AdamW> {
+    /// Writes the model as a bundle, along with its config and tokenizer (As saved,
+    /// empty for the ASCII tokenizer), and its optimizer state if `with_optimizer`
+    pub fn save_bundle<W: Write>(
+        &self,
+        mut w: W,
+        config: &Config,
+        tokenizer: &[u8],
+        with_optimizer: bool,
+    ) -> Result<(), ConfigError> {
+        let mut weights = Vec::new();
+        self.write_params(&mut weights)?;
+        let bundle = Bundle {
+            config: config.clone(),
+            tokenizer: tokenizer.to_vec(),
+            weights,
+            optimizer: with_optimizer.then(|| (self.optimizer().clone(), self.data_seed())),
+            run: None,
+            provenance: self.provenance(),
+        };
+        w.write_all(&bundle.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Reads a model written by `save_bundle`, along with its tokenizer and config
+    pub fn load_bundle<R: Read>(
+        mut r: R,
+    ) -> Result<(Self, Box<dyn Tokenizer + Send + Sync>, Config), ConfigError> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let bundle = Bundle::from_bytes(&bytes)?;
+        let tokenizer = bundle.config.load_tokenizer(&bundle.tokenizer)?;
+        let mut gpt = bundle.config.model.build(
+            &mut rand::thread_rng(),
+            tokenizer.vocab_size(),
+            bundle.config.optimizer.build(),
+        )?;
+        bundle.load_into(&mut gpt)?;
+        gpt.check_tokenizer(tokenizer.as_ref())?;
+        Ok((gpt, tokenizer, bundle.config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::Optimizer;
+    use crate::tensor::TensorOps;
+    use crate::tokenizer::SimpleTokenizer;
+
+    #[test]
+    fn test_bundle() {
+        let mut config = Config::default();
+        config.model.num_tokens = 4;
+        config.model.embedding_degree = 8;
+        config.model.num_layers = 1;
+        config.model.num_heads = 2;
+        let tokenizer = SimpleTokenizer::new("ROMEO: Hi.\n");
+        let tokenizer_bytes = bincode::serialize(&tokenizer).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut gpt = config
+            .model
+            .build(&mut rng, tokenizer.vocab_size(), config.optimizer.build())
+            .unwrap();
+        gpt.set_data_seed(42);
+        gpt.set_provenance(Provenance {
+            dataset_hash: Some(7),
+            tokenizer_hash: Some(crate::run::tokenizer_hash(&tokenizer)),
+        });
+
+        let mut bytes = Vec::new();
+        gpt.save_bundle(&mut bytes, &config, &tokenizer_bytes, true)
+            .unwrap();
+        let (loaded, loaded_tokenizer, loaded_config) = GPT::load_bundle(&bytes[..]).unwrap();
+        assert_eq!(loaded_config, config);
+        assert_eq!(loaded_tokenizer.vocab_size(), tokenizer.vocab_size());
+        assert_eq!(loaded.data_seed(), 42);
+        assert_eq!(loaded.provenance(), gpt.provenance());
+        assert!(loaded.check_tokenizer(loaded_tokenizer.as_ref()).is_ok());
+        assert_eq!(loaded.optimizer().step_num(), gpt.optimizer().step_num());
+        let state = gpt.get_training_state().unwrap();
+        let loaded_state = loaded.get_training_state().unwrap();
+        for (name, t) in state.tensors {
+            assert_eq!(loaded_state.tensors[&name].blob(), t.blob());
+        }
+
+        // Without the optimizer state
+        let mut small = Vec::new();
+        gpt.save_bundle(&mut small, &config, &tokenizer_bytes, false)
+            .unwrap();
+        assert!(small.len() < bytes.len());
+        assert!(Bundle::from_bytes(&small).unwrap().optimizer.is_none());
+        // Or from a snapshot
+        let snapshot = Bundle::from_state(
+            config.clone(),
+            tokenizer_bytes.clone(),
+            gpt.get_training_state().unwrap(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(snapshot.to_bytes().unwrap(), small);
+
+        // Corrupted and truncated bundles are rejected
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(Bundle::from_bytes(&corrupted).is_err());
+        assert!(Bundle::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Bundle::from_bytes(&bytes[..20]).is_err());
+        assert!(Bundle::from_bytes(b"FGTM").is_err());
+
+        // The weights have to fit the config
+        let mut wider = config.clone();
+        wider.model.embedding_degree = 16;
+        let mut bundle = Bundle::from_bytes(&bytes).unwrap();
+        bundle.config = wider;
+        assert!(GPT::load_bundle(&bundle.to_bytes().unwrap()[..]).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Writing checkpoints in the background, so that training goes on meanwhile.
+//!
+//! Tensors share their storage until written to (Copy-on-write), so snapshotting a
+//! model with `GPT::get_training_state` is cheap: it's serializing and writing the
+//! snapshot that takes long, and that's what `AsyncWriter` does on its own thread.
+
+use crate::config::ConfigError;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+/// Writes one checkpoint at a time on a background thread
+#[derive(Default)]
+pub struct AsyncWriter {
+    pending: Mutex<Option<JoinHandle<Result<(), ConfigError>>>>,
+}
+
+/// Writes next to `path` first, so that a crash midway leaves the previous file whole.
+/// The temporary file has a name of its own (No two writes share one, even of the same
+/// path), and is flushed to the disk before it replaces `path`.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), ConfigError> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = PathBuf::from(tmp);
+    let written = std::fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| std::fs::rename(&tmp, path)) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+impl AsyncWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits for the previous checkpoint to be written, then serializes with
+    /// `serialize` and writes to `path` on a background thread. Fails if writing the
+    /// previous one did (This one being started all the same).
+    pub fn write<P, F>(&self, path: P, serialize: F) -> Result<(), ConfigError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce() -> Result<Vec<u8>, ConfigError> + Send + 'static,
+    {
+        let mut pending = self.pending.lock().unwrap();
+        let previous = match pending.take() {
+            Some(handle) => handle.join().expect("The checkpoint writer panicked"),
+            None => Ok(()),
+        };
+        let path = path.as_ref().to_path_buf();
+        *pending = Some(std::thread::spawn(move || {
+            write_atomic(&path, &serialize()?)?;
+            tracing::debug!(path = %path.display(), "Wrote the checkpoint");
+            Ok(())
+        }));
+        previous
+    }
+
+    /// Waits for the checkpoint being written, if any
+    pub fn wait(&self) -> Result<(), ConfigError> {
+        match self.pending.lock().unwrap().take() {
+            Some(handle) => handle.join().expect("The checkpoint writer panicked"),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a checkpoint is being written
+    pub fn is_busy(&self) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|h| !h.is_finished())
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.wait() {
+            tracing::error!(error = %e, "Unable to write the checkpoint");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelConfig;
+    use crate::gpt::TrainingState;
+    use crate::optimizer::AdamW;
+    use crate::tensor::{Tensor, TensorOps};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_async_writer() {
+        let dir = std::env::temp_dir().join("femto_gpt_test_async_writer");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.dat");
+        let config = ModelConfig {
+            num_tokens: 4,
+            embedding_degree: 8,
+            num_layers: 1,
+            num_heads: 2,
+            ..Default::default()
+        };
+        let mut gpt = config
+            .build(&mut rand::thread_rng(), 5, AdamW::new())
+            .unwrap();
+        let snapshot = gpt.get_training_state().unwrap();
+        let expected = bincode::serialize(&snapshot).unwrap();
+
+        let writer = AsyncWriter::new();
+        writer
+            .write(&path, move || Ok(bincode::serialize(&snapshot)?))
+            .unwrap();
+        // Changing the model doesn't change the snapshot being written
+        let mut state = gpt.get_training_state().unwrap();
+        for t in state.tensors.values_mut() {
+            *t = Tensor::constant(t.shape(), 0.);
+        }
+        gpt.set_training_state(state, false).unwrap();
+        writer.wait().unwrap();
+        assert!(!writer.is_busy());
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        let written: TrainingState<AdamW> = bincode::deserialize(&expected).unwrap();
+        assert!(written
+            .tensors
+            .values()
+            .any(|t| t.blob().iter().any(|v| *v != 0.)));
+
+        // Errors are reported by the next call, which only starts writing once the
+        // failed write is over
+        let done = Arc::new(AtomicBool::new(false));
+        let failed = done.clone();
+        writer
+            .write(dir.join("missing").join("state.dat"), move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                failed.store(true, Ordering::SeqCst);
+                Ok(vec![1])
+            })
+            .unwrap();
+        let result = writer.write(&path, move || match done.load(Ordering::SeqCst) {
+            true => Ok(vec![2]),
+            false => Err(ConfigError::Invalid("overlapping writes".into())),
+        });
+        assert!(matches!(result, Err(ConfigError::IoError(_))));
+        writer.wait().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![2]);
+        // No temporary file is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
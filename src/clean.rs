@@ -0,0 +1,205 @@
+//! Cleaning a text dataset before training on it: control characters are stripped,
+//! paragraphs (Or lines) too short or too long are dropped, and so are those repeating
+//! an earlier one, exactly or nearly. (Duplicated text is what most often makes a model
+//! repeat itself)
+//!
+//! Near-duplicates are found with MinHash: each unit is summarized by the smallest
+//! hashes of its shingles (Its substrings of a few characters) under several seeds,
+//! which agree in about the same proportion as the shingles of two units overlap
+//! (Their Jaccard similarity). Units sharing a band of their signatures are compared
+//! (Locality-sensitive hashing), so they aren't all compared with each other.
+
+use crate::prepare::{units, Unit};
+use femto_gpt::tensor::xxh64;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Hashes of a MinHash signature
+const NUM_HASHES: usize = 64;
+/// Hashes per band of the signatures, units sharing a band being compared
+const BAND_SIZE: usize = 4;
+
+/// What `clean` does
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cleaning {
+    /// Drop the control characters, but the newlines and tabs
+    pub strip_control: bool,
+    /// Drop the units repeating an earlier one exactly (Once trimmed)
+    pub exact_duplicates: bool,
+    /// Drop the units whose shingles overlap those of an earlier one by at least this
+    /// fraction (Jaccard similarity)
+    pub near_duplicates: Option<f64>,
+    /// Characters per shingle
+    pub shingle_size: usize,
+    pub min_chars: usize,
+    pub max_chars: Option<usize>,
+}
+
+impl Default for Cleaning {
+    fn default() -> Self {
+        Self {
+            strip_control: true,
+            exact_duplicates: true,
+            near_duplicates: None,
+            shingle_size: 5,
+            min_chars: 0,
+            max_chars: None,
+        }
+    }
+}
+
+/// What `clean` dropped
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub units: usize,
+    pub kept: usize,
+    pub control_chars: usize,
+    pub too_short: usize,
+    pub too_long: usize,
+    pub exact_duplicates: usize,
+    pub near_duplicates: usize,
+}
+
+impl Report {
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "Units: {}", self.units).unwrap();
+        writeln!(out, "Control characters stripped: {}", self.control_chars).unwrap();
+        writeln!(out, "Too short: {}", self.too_short).unwrap();
+        writeln!(out, "Too long: {}", self.too_long).unwrap();
+        writeln!(out, "Exact duplicates: {}", self.exact_duplicates).unwrap();
+        writeln!(out, "Near-duplicates: {}", self.near_duplicates).unwrap();
+        writeln!(out, "Kept: {}", self.kept).unwrap();
+        out
+    }
+}
+
+/// The MinHash signature of the shingles of `size` characters of `text` (Of the whole
+/// of it when shorter)
+fn signature(text: &str, size: usize) -> Vec<u64> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let shingles = chars
+        .windows(size.clamp(1, chars.len().max(1)))
+        .map(|w| w.iter().collect::<String>())
+        .collect::<HashSet<_>>();
+    (0..NUM_HASHES as u64)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|s| xxh64(s.as_bytes(), seed))
+                .min()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+/// Estimated Jaccard similarity of the shingles of two units
+fn similarity(a: &[u64], b: &[u64]) -> f64 {
+    a.iter().zip(b).filter(|(x, y)| x == y).count() as f64 / a.len().max(1) as f64
+}
+
+/// The paragraphs (Or lines) of `text` that `cleaning` keeps, in order
+pub fn clean(text: &str, unit: Unit, cleaning: &Cleaning) -> (String, Report) {
+    let mut report = Report::default();
+    let mut kept = Vec::new();
+    let mut seen = HashSet::new();
+    let mut signatures: Vec<Vec<u64>> = Vec::new();
+    let mut buckets = HashMap::<(usize, u64), Vec<usize>>::new();
+    for part in units(text, unit) {
+        report.units += 1;
+        let part = if cleaning.strip_control {
+            let stripped = part
+                .chars()
+                .filter(|ch| !ch.is_control() || *ch == '\n' || *ch == '\t')
+                .collect::<String>();
+            report.control_chars += part.chars().count() - stripped.chars().count();
+            stripped
+        } else {
+            part.to_string()
+        };
+        let len = part.trim().chars().count();
+        if len == 0 || len < cleaning.min_chars {
+            report.too_short += 1;
+            continue;
+        }
+        if cleaning.max_chars.is_some_and(|max| len > max) {
+            report.too_long += 1;
+            continue;
+        }
+        if cleaning.exact_duplicates && !seen.insert(xxh64(part.trim().as_bytes(), 0)) {
+            report.exact_duplicates += 1;
+            continue;
+        }
+        if let Some(threshold) = cleaning.near_duplicates {
+            let sig = signature(part.trim(), cleaning.shingle_size);
+            let bands = sig
+                .chunks(BAND_SIZE)
+                .enumerate()
+                .map(|(b, band)| {
+                    let bytes = band
+                        .iter()
+                        .flat_map(|h| h.to_le_bytes())
+                        .collect::<Vec<_>>();
+                    (b, xxh64(&bytes, 0))
+                })
+                .collect::<Vec<_>>();
+            let duplicate = bands
+                .iter()
+                .filter_map(|band| buckets.get(band))
+                .flatten()
+                .any(|i| similarity(&sig, &signatures[*i]) >= threshold);
+            if duplicate {
+                report.near_duplicates += 1;
+                continue;
+            }
+            for band in bands {
+                buckets.entry(band).or_default().push(signatures.len());
+            }
+            signatures.push(sig);
+        }
+        kept.push(part);
+    }
+    report.kept = kept.len();
+    let text = match kept.is_empty() {
+        true => String::new(),
+        false => kept.join(unit.separator()) + "\n",
+    };
+    (text, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean() {
+        let text = "ROMEO:\nHi.\n\nJULIET:\nHi\u{7}.\r\n\nROMEO:\nHi.\n\nOk\n\n\
+            The quick brown fox jumps over the lazy dog.\n\n\
+            The quick brown fox jumps over the lazy dog!\n\n\
+            Something else entirely, and quite a bit longer than the others.\n";
+        let (cleaned, report) = clean(text, Unit::Paragraph, &Cleaning::default());
+        assert_eq!(report.units, 7);
+        assert_eq!(report.control_chars, 2);
+        assert_eq!(report.exact_duplicates, 1);
+        assert_eq!(report.kept, 6);
+        assert!(cleaned.starts_with("ROMEO:\nHi.\n\nJULIET:\nHi.\n\nOk\n\n"));
+
+        let cleaning = Cleaning {
+            near_duplicates: Some(0.7),
+            min_chars: 3,
+            max_chars: Some(50),
+            ..Default::default()
+        };
+        let (cleaned, report) = clean(text, Unit::Paragraph, &cleaning);
+        assert_eq!((report.too_short, report.too_long), (1, 1));
+        assert_eq!(report.near_duplicates, 1);
+        assert_eq!(report.kept, 3);
+        assert!(cleaned.contains("lazy dog.") && !cleaned.contains("lazy dog!"));
+        assert_eq!(cleaned.matches("ROMEO").count(), 1);
+
+        let (_, report) = clean("abc\nabc\n", Unit::Line, &Cleaning::default());
+        assert_eq!((report.units, report.kept), (2, 1));
+        assert!(similarity(&signature("abcdefgh", 3), &signature("abcdefgh", 3)) == 1.);
+        assert!(similarity(&signature("abcdefgh", 3), &signature("stuvwxyz", 3)) < 0.1);
+    }
+}
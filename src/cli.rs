@@ -0,0 +1,1127 @@
+use crate::clean::Cleaning;
+use crate::convert::Format;
+use crate::prepare::Unit;
+use clap::{Args, Parser, Subcommand};
+use femto_gpt::bundle::{self, Bundle};
+use femto_gpt::config::{Config, ConfigError, DistillationConfig, DpSgdConfig, ScheduleKind};
+use femto_gpt::gpt::{Activation, Pooling, PositionalEncoding, Truncation};
+use femto_gpt::graph::Precision;
+use femto_gpt::tensor::DType;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Minimal GPT training and inference",
+    after_help = "Running without a command is the same as `femto-gpt train`."
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Log more (-v for every step's details, -vv for everything)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only log warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+impl Cli {
+    pub fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            // The dashboard takes the whole terminal
+            _ if matches!(&self.command, Some(Command::Train(args)) if args.dashboard()) => {
+                LevelFilter::OFF
+            }
+            (true, _) => LevelFilter::WARN,
+            (_, 0) => LevelFilter::INFO,
+            (_, 1) => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
+    }
+
+    pub fn into_command(self) -> Command {
+        let mut command = self
+            .command
+            .unwrap_or_else(|| Cli::parse_from(["femto-gpt", "train"]).command.unwrap());
+        if let Command::Train(args) = &mut command {
+            args.resolve_run_dir();
+        }
+        command
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Train a model on a text file (Resumes from the saved state, if any)
+    Train(Box<TrainArgs>),
+    /// Generate text with a trained model
+    Infer(InferArgs),
+    /// Generate samples, recorded with their seed, settings and checkpoint (As JSONL)
+    Sample(SampleArgs),
+    /// Print embeddings of texts (As JSONL), for similarity search or clustering
+    Embed(EmbedArgs),
+    /// Save the attention weights of every head over a prompt (As .npz)
+    Attention(AttentionArgs),
+    /// Export the token embeddings for the TensorFlow Embedding Projector (As TSV), or
+    /// projected to 2D with PCA
+    Projector(ProjectorArgs),
+    /// Print the token each transformer block would predict at every position (The
+    /// logit lens)
+    Lens(LensArgs),
+    /// Record what a forward and a backward pass over a text do, function by function
+    /// (As JSON)
+    Tape(TapeArgs),
+    /// Chat with a trained model
+    Interactive(InteractiveArgs),
+    /// Serve a trained model over an OpenAI-compatible HTTP API
+    #[cfg(feature = "server")]
+    Serve(ServeArgs),
+    /// Print the token ids of a text
+    Tokenize(TokenizeArgs),
+    /// Describe what a checkpoint contains
+    Inspect(InspectArgs),
+    /// Convert a checkpoint to another format or precision
+    Convert(ConvertArgs),
+    /// Compare the weights of two checkpoints of the same model, tensor by tensor and
+    /// block by block
+    Diff(DiffArgs),
+    /// Average the weights of checkpoints of the same model (A "model soup")
+    Soup(SoupArgs),
+    /// Print the validation perplexity of a model with more and more of its weights
+    /// pruned (See `train --prune`, to fine-tune a pruned model)
+    Prune(PruneArgs),
+    /// Run a fixed battery of seeded prompts, comparing the outputs with those of an
+    /// earlier checkpoint (Saved with --save)
+    Quality(QualityArgs),
+    /// Measure the speed of matrix multiplication, training and generation
+    Bench(BenchArgs),
+    /// Estimate the memory, disk space and time training with a config takes
+    Plan(PlanArgs),
+    /// Print statistics of a dataset, and optionally split it for validation
+    Dataset(DatasetArgs),
+    /// Precompute the logits of a teacher over a dataset, for distilling it without
+    /// running it (See `--teacher`)
+    SoftTargets(SoftTargetsArgs),
+    /// Keep track of training runs
+    #[command(subcommand)]
+    Runs(RunsCommand),
+}
+
+/// Options describing the model and where it's stored. Those not given are taken from
+/// `--config`, or else from the config saved along the training state (If exists).
+#[derive(Args, Debug, Clone)]
+pub struct ModelArgs {
+    /// Experiment config file (TOML)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Context length [default: 64]
+    #[arg(long)]
+    pub num_tokens: Option<usize>,
+    /// Size of the embeddings [default: 64]
+    #[arg(long)]
+    pub embedding_degree: Option<usize>,
+    /// Number of transformer blocks [default: 4]
+    #[arg(long = "layers")]
+    pub num_layers: Option<usize>,
+    /// Number of attention heads per block, should divide the embedding-degree [default: 4]
+    #[arg(long = "heads")]
+    pub num_heads: Option<usize>,
+    /// Dropout probability [default: 0]
+    #[arg(long)]
+    pub dropout: Option<f64>,
+    /// Nonlinearity of the feedforward blocks: relu, gelu, silu or tanh [default: relu]
+    #[arg(long)]
+    pub activation: Option<Activation>,
+    /// Positional encoding: learned, sinusoidal, rope, alibi or none [default: learned]
+    #[arg(long)]
+    pub positional_encoding: Option<PositionalEncoding>,
+    /// Run the attention and the feed-forward layer of each block in parallel (GPT-J style)
+    #[arg(long)]
+    pub parallel_residual: bool,
+    /// Training state file (Or a `.femto` bundle, holding the config and tokenizer too,
+    /// or the `.json` index of a sharded checkpoint)
+    #[arg(long, default_value = "training_state.dat")]
+    pub state: PathBuf,
+    /// Number of files a sharded checkpoint is saved in
+    #[arg(long, default_value_t = 4)]
+    pub shards: usize,
+    /// Tokenizer file
+    #[arg(long, default_value = "tokenizer.dat")]
+    pub tokenizer: PathBuf,
+}
+
+fn set<T: Clone>(field: &mut T, arg: &Option<T>) {
+    if let Some(v) = arg {
+        *field = v.clone();
+    }
+}
+
+/// A duration given in seconds, e.g. "30" or "1.5"
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid number of seconds '{}'", s))
+}
+
+impl ModelArgs {
+    /// Where the config of the model is saved, next to its training state
+    pub fn saved_config(&self) -> PathBuf {
+        self.state.with_extension("toml")
+    }
+
+    /// Whether the model is stored as a bundle, which holds its config and tokenizer
+    pub fn has_bundle(&self) -> bool {
+        self.state.is_file() && bundle::is_bundle(&self.state)
+    }
+
+    /// The config given with `--config` (Or the saved one), overridden by the flags
+    pub fn config(&self) -> Result<Config, ConfigError> {
+        let saved = self.saved_config();
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None if self.has_bundle() => Bundle::load(&self.state)?.config,
+            None if saved.is_file() => Config::load(saved)?,
+            None => Config::default(),
+        };
+        let m = &mut config.model;
+        set(&mut m.num_tokens, &self.num_tokens);
+        set(&mut m.embedding_degree, &self.embedding_degree);
+        set(&mut m.num_layers, &self.num_layers);
+        set(&mut m.num_heads, &self.num_heads);
+        set(&mut m.dropout, &self.dropout);
+        set(&mut m.activation, &self.activation);
+        set(&mut m.positional_encoding, &self.positional_encoding);
+        if self.parallel_residual {
+            m.parallel_residual = true;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct TrainArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Directory everything of the run is kept in: its config, tokenizer, checkpoints
+    /// and metrics, the training resuming from them if they exist (Relative --state,
+    /// --tokenizer, --metrics and --histograms paths are in it) [default: the current
+    /// one, without metrics]
+    #[arg(long)]
+    pub run_dir: Option<PathBuf>,
+    /// Text to train on [default: dataset.txt]
+    #[arg(long)]
+    pub dataset: Option<PathBuf>,
+    /// Number of steps to train for [default: 100000]
+    #[arg(long)]
+    pub steps: Option<usize>,
+    /// Stop once this many passes over the dataset are done, counting the ones of the
+    /// runs resumed from
+    #[arg(long)]
+    pub epochs: Option<usize>,
+    /// Stop after training for this many seconds
+    #[arg(long, value_parser = parse_seconds)]
+    pub max_duration: Option<Duration>,
+    /// Stop once the loss, averaged over the last steps, is down to this
+    #[arg(long)]
+    pub target_loss: Option<f32>,
+    /// Windows per step [default: 32]
+    #[arg(long)]
+    pub batch_size: Option<usize>,
+    /// Start the windows at multiples of it (The context size for disjoint ones),
+    /// instead of anywhere
+    #[arg(long)]
+    pub stride: Option<usize>,
+    /// Weight the loss of each target token by its inverse frequency in the dataset
+    #[arg(long)]
+    pub frequency_weighted_loss: bool,
+    /// Peak learning rate [default: 0.001]
+    #[arg(long)]
+    pub learning_rate: Option<f64>,
+    /// Learning-rate schedule: linear, cosine (With warm restarts) or plateau (Lowered
+    /// when the validation loss stops improving) [default: linear]
+    #[arg(long)]
+    pub schedule: Option<ScheduleKind>,
+    /// Held-out text to compute the validation loss on, every 50 steps
+    #[arg(long)]
+    pub validation: Option<PathBuf>,
+    /// Factor of the learning rate from each block to the one below it, for
+    /// fine-tuning (The output layer getting the full one) [default: 1.0]
+    #[arg(long)]
+    pub layer_decay: Option<f64>,
+    /// Only update the rows of the embeddings each step trains on (Saving time with
+    /// large vocabularies)
+    #[arg(long)]
+    pub sparse_embeddings: bool,
+    /// Precision the weights and activations are stored in: f32, or mixed (f16,
+    /// with f32 master weights) [default: f32]
+    #[arg(long)]
+    pub precision: Option<Precision>,
+    /// Tensors kept in f32 in mixed precision, by name (E.g. *norm*,logits)
+    #[arg(long, value_delimiter = ',')]
+    pub full_precision: Vec<String>,
+    /// CSV file histograms of the weights and gradients are appended to, every 50 steps
+    #[arg(long)]
+    pub histograms: Option<PathBuf>,
+    /// Estimate the gradient noise scale, logging the batch-size it suggests every 50 steps
+    #[arg(long)]
+    pub noise_scale: bool,
+    /// JSONL file the metrics of every step are appended to, after the metadata of the run
+    #[arg(long)]
+    pub metrics: Option<PathBuf>,
+    /// Rotate the metrics and histograms files before they grow over this many bytes
+    #[arg(long)]
+    pub log_max_bytes: Option<u64>,
+    /// Rotate the metrics and histograms files once they were started this many hours ago
+    #[arg(long)]
+    pub log_max_hours: Option<f64>,
+    /// Write the checkpoints on a background thread, without pausing the training
+    #[arg(long)]
+    pub async_checkpoints: bool,
+    /// Distill this model into the trained one: its training state (With its config
+    /// saved next to it), its bundle, or soft targets
+    #[arg(long)]
+    pub teacher: Option<PathBuf>,
+    /// Train with differential privacy (DP-SGD), adding noise of this standard
+    /// deviation relative to the clip norm to the gradients of every batch
+    #[arg(long)]
+    pub dp_noise_multiplier: Option<f32>,
+    /// Clip the gradients of every window to this norm, training with DP-SGD [default: 1]
+    #[arg(long)]
+    pub dp_clip_norm: Option<f32>,
+    /// Threads to split the products by the weights of each window over, on top of
+    /// spreading the windows [default: 1]
+    #[arg(long)]
+    pub tensor_parallel: Option<usize>,
+    /// Run the chains of functions that have a fused implementation (E.g. the products
+    /// by the weights with their biases and activations) as one function each
+    #[arg(long)]
+    pub fuse_ops: bool,
+    /// Train quantization-aware: the outputs of these functions (E.g. matmul,gelu)
+    /// are quantized to 8 bits and back
+    #[arg(long, value_delimiter = ',')]
+    pub fake_quant: Vec<String>,
+    /// Before training, run a window in f64 as well, logging the functions whose
+    /// results stray the most from it
+    #[arg(long)]
+    pub check_numerics: bool,
+    /// Before training, check that the model memorizes a single batch of the dataset,
+    /// failing otherwise
+    #[arg(long)]
+    pub overfit_check: bool,
+    /// Zero this fraction of the weights of the products before training, keeping them
+    /// zero, for fine-tuning a sparse model
+    #[arg(long)]
+    pub prune: Option<f32>,
+    /// Seed of the initial weights and of the order of the data [default: random]
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Fail unless the dataset has this hash (As logged, and recorded in the runs)
+    #[arg(long)]
+    pub dataset_hash: Option<String>,
+    /// Add the characters of the dataset missing from the vocabulary of the model,
+    /// with new rows in its embeddings and output layer, instead of failing on them
+    #[arg(long)]
+    pub extend_vocab: bool,
+    /// Show a live dashboard instead of the log
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub dashboard: bool,
+}
+
+impl TrainArgs {
+    /// Puts the files of the run in `--run-dir` (If given), the metrics being logged to
+    /// `metrics.jsonl` there unless told otherwise
+    fn resolve_run_dir(&mut self) {
+        let Some(dir) = &self.run_dir else {
+            return;
+        };
+        self.model.state = dir.join(&self.model.state);
+        self.model.tokenizer = dir.join(&self.model.tokenizer);
+        let metrics = self
+            .metrics
+            .take()
+            .unwrap_or_else(|| "metrics.jsonl".into());
+        self.metrics = Some(dir.join(metrics));
+        self.histograms = self.histograms.take().map(|h| dir.join(h));
+    }
+
+    pub fn config(&self) -> Result<Config, ConfigError> {
+        let mut config = self.model.config()?;
+        set(&mut config.training.dataset, &self.dataset);
+        set(&mut config.training.steps, &self.steps);
+        if let Some(epochs) = self.epochs {
+            config.training.epochs = Some(epochs);
+        }
+        if let Some(duration) = self.max_duration {
+            config.training.max_duration = Some(duration.as_secs_f64());
+        }
+        if let Some(loss) = self.target_loss {
+            config.training.target_loss = Some(loss);
+        }
+        set(&mut config.training.batch_size, &self.batch_size);
+        if let Some(stride) = self.stride {
+            config.training.stride = Some(stride);
+        }
+        if self.frequency_weighted_loss {
+            config.training.frequency_weighted_loss = true;
+        }
+        set(&mut config.scheduler.base_lr, &self.learning_rate);
+        set(&mut config.scheduler.kind, &self.schedule);
+        set(&mut config.optimizer.layer_decay, &self.layer_decay);
+        if self.sparse_embeddings {
+            config.optimizer.sparse_embeddings = true;
+        }
+        if let Some(path) = &self.validation {
+            config.training.validation = Some(path.clone());
+        }
+        set(&mut config.training.precision, &self.precision);
+        if !self.full_precision.is_empty() {
+            config.training.full_precision = self.full_precision.clone();
+        }
+        if let Some(path) = &self.histograms {
+            config.training.histograms = Some(path.clone());
+        }
+        if self.noise_scale {
+            config.training.noise_scale = true;
+        }
+        if let Some(path) = &self.metrics {
+            config.training.metrics = Some(path.clone());
+        }
+        if let Some(bytes) = self.log_max_bytes {
+            config.training.log_rotation.max_bytes = Some(bytes);
+        }
+        if let Some(hours) = self.log_max_hours {
+            config.training.log_rotation.max_hours = Some(hours);
+        }
+        if self.async_checkpoints {
+            config.training.async_checkpoints = true;
+        }
+        if self.check_numerics {
+            config.training.check_numerics = true;
+        }
+        if self.overfit_check {
+            config.training.overfit_check = true;
+        }
+        if let Some(sparsity) = self.prune {
+            config.training.prune = Some(sparsity);
+        }
+        if let Some(seed) = self.seed {
+            config.training.seed = Some(seed);
+        }
+        if let Some(hash) = &self.dataset_hash {
+            config.training.dataset_hash = Some(hash.clone());
+        }
+        set(&mut config.training.tensor_parallel, &self.tensor_parallel);
+        if self.fuse_ops {
+            config.training.fuse_ops = true;
+        }
+        if !self.fake_quant.is_empty() {
+            config.training.fake_quant = self.fake_quant.clone();
+        }
+        if let Some(teacher) = &self.teacher {
+            config
+                .training
+                .distillation
+                .get_or_insert_with(DistillationConfig::default)
+                .teacher = teacher.clone();
+        }
+        if self.dp_noise_multiplier.is_some() || self.dp_clip_norm.is_some() {
+            let dp = config
+                .training
+                .dp_sgd
+                .get_or_insert_with(DpSgdConfig::default);
+            set(&mut dp.noise_multiplier, &self.dp_noise_multiplier);
+            set(&mut dp.clip_norm, &self.dp_clip_norm);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn dashboard(&self) -> bool {
+        #[cfg(feature = "tui")]
+        return self.dashboard;
+        #[cfg(not(feature = "tui"))]
+        false
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct InferArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Text to continue
+    #[arg(long, default_value = "\n", hide_default_value = true)]
+    pub prompt: String,
+    /// Number of tokens to generate
+    #[arg(long, default_value_t = 200)]
+    pub max_tokens: usize,
+    /// How creative? (0.0 is greedy)
+    #[arg(long, default_value_t = 0.5)]
+    pub temperature: f32,
+    /// Seed of the sampling, for reproducible outputs [default: random]
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Training state of a smaller model sharing the tokenizer, drafting tokens for
+    /// this one to verify (Its config is the one saved next to it)
+    #[arg(long)]
+    pub draft: Option<PathBuf>,
+    /// Tokens drafted at a time
+    #[arg(long, default_value_t = 4)]
+    pub draft_tokens: usize,
+    /// Training state of another model sharing the tokenizer, whose logits are averaged
+    /// with this one's (Its config is the one saved next to it; may be repeated, not
+    /// done with --draft or --only-chars)
+    #[arg(long)]
+    pub ensemble: Vec<PathBuf>,
+    /// Keep the last token of the prompt, instead of letting the model pick a token
+    /// completing its text (Token healing, for tokenizers with multi-character tokens;
+    /// not done with --draft)
+    #[arg(long)]
+    pub no_token_healing: bool,
+    /// Only generate tokens made of these characters, the output layer being sliced to
+    /// them (E.g. "0123456789"; turns token healing off, not done with --draft)
+    #[arg(long)]
+    pub only_chars: Option<String>,
+    /// Tokens at the start of the prompt (E.g. instructions) kept in the context once
+    /// it's full, the others rolling out as tokens are generated (Not done with --draft
+    /// or --ensemble)
+    #[arg(long, default_value_t = 0)]
+    pub pinned_tokens: usize,
+    /// Part of a prompt too long for the context to keep, room being left for the
+    /// generated tokens: head, tail or ends (Both, without the middle) [default: tail]
+    #[arg(long, conflicts_with = "pinned_tokens")]
+    pub truncate: Option<Truncation>,
+    /// Writes the probability of each generated token and the likeliest alternatives
+    /// at its step to this JSON file (Not done with --draft or --ensemble)
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+    /// Alternatives traced per token
+    #[arg(long, default_value_t = 5)]
+    pub trace_top_k: usize,
+    /// Read the weights off the disk as they're needed, instead of loading them all,
+    /// for models larger than the memory (Slow; the state must be a params file, see
+    /// `convert --to params`, or a sharded checkpoint)
+    #[arg(long, conflicts_with_all = ["draft", "ensemble", "only_chars"])]
+    pub stream: bool,
+    /// Skip the zeros of the weights in the products by them, for pruned models (Not
+    /// done with --draft or --ensemble)
+    #[arg(long)]
+    pub sparse: bool,
+    /// Tokens of context to generate with instead of the trained ones: fewer for speed,
+    /// or more with RoPE, ALiBi or no positional encoding
+    #[arg(long, conflicts_with = "stream")]
+    pub context: Option<usize>,
+    /// Seconds the generation may take, after which it stops with what it has (As it
+    /// does on Ctrl-C)
+    #[arg(long, value_parser = parse_seconds)]
+    pub max_duration: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+pub struct SampleArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Text to continue
+    #[arg(long, default_value = "\n", hide_default_value = true)]
+    pub prompt: String,
+    /// Number of samples
+    #[arg(long, default_value_t = 1)]
+    pub n: usize,
+    /// Seed of the first sample, the next ones get the following seeds [default: random]
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Number of tokens to generate per sample
+    #[arg(long, default_value_t = 200)]
+    pub max_tokens: usize,
+    /// How creative? (0.0 is greedy)
+    #[arg(long, default_value_t = 0.5)]
+    pub temperature: f32,
+    /// Only sample from the likeliest tokens whose probabilities sum up to this
+    #[arg(long, default_value_t = 1.0)]
+    pub top_p: f32,
+    /// File the samples are appended to [default: stdout]
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct EmbedArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Texts to embed [default: the lines of stdin]
+    pub texts: Vec<String>,
+    /// Transformer block whose outputs are pooled (Counting from zero) [default: the
+    /// normalized output of the last one]
+    #[arg(long)]
+    pub layer: Option<usize>,
+    /// How the states of the tokens are combined (mean or last)
+    #[arg(long, default_value = "mean")]
+    pub pooling: Pooling,
+}
+
+#[derive(Args, Debug)]
+pub struct ProjectorArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Where the embeddings go, a token per line (Load it in
+    /// https://projector.tensorflow.org along with --metadata)
+    #[arg(long, default_value = "vectors.tsv")]
+    pub vectors: PathBuf,
+    /// Where the text of the tokens goes, one per line
+    #[arg(long, default_value = "metadata.tsv")]
+    pub metadata: PathBuf,
+    /// Also project the embeddings on their first principal components, writing the
+    /// text and coordinates of every token to this file
+    #[arg(long)]
+    pub pca: Option<PathBuf>,
+    /// Number of principal components for --pca
+    #[arg(long, default_value_t = 2)]
+    pub dims: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct AttentionArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Text whose tokens attend to each other (Only the last ones fitting in the
+    /// context are kept)
+    #[arg(long)]
+    pub prompt: String,
+    /// Where the weights are saved, as `attention` ([layers, heads, tokens, tokens])
+    /// along with the `tokens`
+    #[arg(long, default_value = "attention.npz")]
+    pub out: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct TapeArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Text the model learns to predict (Only the last tokens fitting in the context
+    /// are kept)
+    #[arg(long)]
+    pub prompt: String,
+    /// Where the steps of the passes are saved: the op, the shapes of its inputs and
+    /// output, and the norms of the gradients
+    #[arg(long, default_value = "tape.json")]
+    pub out: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct LensArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Text to run the model on (Only the last tokens fitting in the context are kept)
+    #[arg(long)]
+    pub prompt: String,
+}
+
+#[derive(Args, Debug)]
+pub struct InteractiveArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Number of tokens to generate for each input
+    #[arg(long, default_value_t = 200)]
+    pub max_tokens: usize,
+    /// How creative? (0.0 is greedy)
+    #[arg(long, default_value_t = 0.5)]
+    pub temperature: f32,
+    /// Tokens the model has to generate before it may end its turn
+    #[arg(long, default_value_t = 0)]
+    pub min_new_tokens: usize,
+    /// Added to the odds (In logits) of the model ending its turn for every token it
+    /// generated after --min-new-tokens (Negative values give longer answers)
+    #[arg(long, default_value_t = 0., allow_negative_numbers = true)]
+    pub length_penalty: f32,
+    /// Seconds an answer may take, after which it's cut short (As it is on Ctrl-C)
+    #[arg(long, value_parser = parse_seconds)]
+    pub max_duration: Option<Duration>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub addr: String,
+    /// Name of the model in the API
+    #[arg(long, default_value = "femto-gpt")]
+    pub name: String,
+    /// Number of requests handled concurrently
+    #[arg(long, default_value_t = 4)]
+    pub workers: usize,
+    /// Reload the model whenever its training state gets rewritten (E.g. by a training
+    /// run going on), checking every few seconds
+    #[arg(long)]
+    pub watch: bool,
+    /// Memory the chat sessions kept on the server may take, in MiB (The least
+    /// recently used ones being dropped)
+    #[arg(long, default_value_t = 64)]
+    pub session_budget: usize,
+    /// Batch the generations going on, packing them into shared forward passes, the
+    /// requests arriving within this many milliseconds of each other starting together
+    #[arg(long)]
+    pub batch_window: Option<u64>,
+    /// Part of the prompts too long for the context to keep, unless the requests say
+    /// otherwise: head, tail or ends (Both, without the middle)
+    #[arg(long, default_value = "tail")]
+    pub truncate: Truncation,
+    /// Requests a client (By IP address) may make per minute, in bursts of up to as
+    /// many (Others get 429 Too Many Requests)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+    /// Tokens the prompt of a request may have
+    #[arg(long)]
+    pub max_prompt_tokens: Option<usize>,
+    /// `max_tokens` a request may ask for
+    #[arg(long)]
+    pub max_tokens: Option<usize>,
+    /// Size of the body of a request, in KiB (Larger ones get 413 Payload Too Large)
+    #[arg(long, default_value_t = 1024)]
+    pub max_body_kib: usize,
+    /// Seconds a generation may take, whatever the request asks for (Those running out
+    /// of time end with the finish reason "timeout")
+    #[arg(long, value_parser = parse_seconds)]
+    pub max_duration: Option<Duration>,
+    /// Address to serve the gRPC service on too (E.g. 127.0.0.1:50051)
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    pub grpc_addr: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct TokenizeArgs {
+    /// Text to tokenize
+    #[arg(long)]
+    pub text: String,
+    /// Tokenizer file
+    #[arg(long, default_value = "tokenizer.dat")]
+    pub tokenizer: PathBuf,
+    /// Text to build the tokenizer from, when there is no tokenizer file
+    #[arg(long, default_value = "dataset.txt")]
+    pub dataset: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// Training state (Or weights) file
+    #[arg(default_value = "training_state.dat")]
+    pub state: PathBuf,
+    /// Config of the model [default: the one saved along the training state]
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Tokenizer file
+    #[arg(long, default_value = "tokenizer.dat")]
+    pub tokenizer: PathBuf,
+    /// Also write the computation graph of the model to this file, in the DOT
+    /// language of Graphviz
+    #[arg(long)]
+    pub dot: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// Checkpoint to convert (Its format is detected)
+    pub input: PathBuf,
+    /// Where to write the converted checkpoint
+    pub output: PathBuf,
+    /// Output format [default: guessed from the extension of the output, .safetensors,
+    /// .gguf or .femto, otherwise a training state]
+    #[arg(long, value_enum)]
+    pub to: Option<Format>,
+    /// Precision of the weights (f32, f16 or int8), for safetensors and GGUF outputs
+    #[arg(long, default_value = "f32")]
+    pub dtype: DType,
+    /// Leave the optimizer state out of training states (About 2/3 of their size)
+    #[arg(long)]
+    pub drop_optimizer: bool,
+    /// Config of the model [default: the one saved along the input, or embedded in it]
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Tokenizer to put in bundles, when the input has none
+    #[arg(long, default_value = "tokenizer.dat")]
+    pub tokenizer: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct PruneArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Fractions of the weights of the products to prune
+    #[arg(long, value_delimiter = ',', default_value = "0.25,0.5,0.75,0.9")]
+    pub sparsity: Vec<f32>,
+    /// Held-out text the perplexity is computed on
+    #[arg(long)]
+    pub validation: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct QualityArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Outputs of an earlier checkpoint to compare with (As JSON)
+    #[arg(long, default_value = "quality.json")]
+    pub reference: PathBuf,
+    /// Save the outputs of this checkpoint as the reference instead of comparing
+    #[arg(long)]
+    pub save: bool,
+    /// Prompts to run when saving, one per line (`\n` standing for a newline) [default:
+    /// a built-in battery]
+    #[arg(long)]
+    pub prompts: Option<PathBuf>,
+    /// Seed of the first prompt when saving, the next ones get the following seeds
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Number of tokens to generate per prompt when saving
+    #[arg(long, default_value_t = 64)]
+    pub max_tokens: usize,
+    /// Temperature when saving (0.0, greedy, gives the outputs a checkpoint is most
+    /// sure of)
+    #[arg(long, default_value_t = 0.)]
+    pub temperature: f32,
+    /// Fail when the mean similarity of the outputs to the reference ones is below this
+    #[arg(long)]
+    pub min_similarity: Option<f32>,
+}
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// The earlier checkpoint (Formats are detected)
+    pub before: PathBuf,
+    /// The later checkpoint
+    pub after: PathBuf,
+    /// Number of the most changed tensors to show
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct SoupArgs {
+    /// Checkpoints to average, of models with the same config (Formats are detected)
+    #[arg(num_args = 2.., required = true)]
+    pub inputs: Vec<PathBuf>,
+    /// Where to write the soup (Its format going by the extension, as with `convert`)
+    #[arg(long, default_value = "soup.dat")]
+    pub output: PathBuf,
+    /// Only average the checkpoints that lower the loss on --validation, best ones first
+    #[arg(long, requires = "validation")]
+    pub greedy: bool,
+    /// Held-out text the checkpoints are compared on, for --greedy
+    #[arg(long)]
+    pub validation: Option<PathBuf>,
+    /// Config of the models [default: the one saved along the first checkpoint, or
+    /// embedded in it]
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Tokenizer of the models, when the first checkpoint has none
+    #[arg(long, default_value = "tokenizer.dat")]
+    pub tokenizer: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Fewer and shorter runs, for a rough idea
+    #[arg(long)]
+    pub quick: bool,
+    /// Number of threads [default: as many as CPUs]
+    #[arg(long)]
+    pub threads: Option<usize>,
+}
+
+#[derive(Args, Debug)]
+pub struct PlanArgs {
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Number of steps to train for [default: 100000]
+    #[arg(long)]
+    pub steps: Option<usize>,
+    /// Windows per step [default: 32]
+    #[arg(long)]
+    pub batch_size: Option<usize>,
+    /// Don't time a few train steps (Which takes a while for large models)
+    #[arg(long)]
+    pub no_measure: bool,
+}
+
+impl PlanArgs {
+    pub fn config(&self) -> Result<Config, ConfigError> {
+        let mut config = self.model.config()?;
+        set(&mut config.training.steps, &self.steps);
+        set(&mut config.training.batch_size, &self.batch_size);
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct DatasetArgs {
+    /// Text file
+    #[arg(default_value = "dataset.txt")]
+    pub dataset: PathBuf,
+    /// Tokenizer to count the tokens with (If exists)
+    #[arg(long, default_value = "tokenizer.dat")]
+    pub tokenizer: PathBuf,
+    /// Fraction of the dataset to hold out for validation, writing both parts
+    #[arg(long)]
+    pub val_fraction: Option<f64>,
+    /// What the dataset is split into
+    #[arg(long, value_enum, default_value = "paragraph")]
+    pub by: Unit,
+    /// Seed of the split (The same seed always gives the same split)
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Where to write the training part [default: <DATASET>.train.txt]
+    #[arg(long)]
+    pub train_out: Option<PathBuf>,
+    /// Where to write the validation part [default: <DATASET>.val.txt]
+    #[arg(long)]
+    pub val_out: Option<PathBuf>,
+    /// Clean the dataset (Before splitting it): strip the control characters and drop
+    /// the paragraphs (Or lines) repeating an earlier one
+    #[arg(long)]
+    pub clean: bool,
+    /// Also drop those nearly repeating an earlier one: whose substrings of a few
+    /// characters overlap with its by at least this fraction (E.g. 0.8)
+    #[arg(long)]
+    pub near_duplicates: Option<f64>,
+    /// Drop the paragraphs (Or lines) shorter than this many characters
+    #[arg(long)]
+    pub min_chars: Option<usize>,
+    /// Drop the paragraphs (Or lines) longer than this many characters
+    #[arg(long)]
+    pub max_chars: Option<usize>,
+    /// Where to write the cleaned dataset [default: <DATASET>.clean.txt]
+    #[arg(long)]
+    pub clean_out: Option<PathBuf>,
+}
+
+impl DatasetArgs {
+    /// How the dataset is cleaned, if it is
+    pub fn cleaning(&self) -> Option<Cleaning> {
+        let clean = self.clean
+            || self.near_duplicates.is_some()
+            || self.min_chars.is_some()
+            || self.max_chars.is_some();
+        clean.then(|| Cleaning {
+            near_duplicates: self.near_duplicates,
+            min_chars: self.min_chars.unwrap_or(0),
+            max_chars: self.max_chars,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RunsCommand {
+    /// List the runs the checkpoints of a directory (And its subdirectories) come from
+    List {
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct SoftTargetsArgs {
+    /// The teacher
+    #[command(flatten)]
+    pub model: ModelArgs,
+    /// Text to run the teacher on [default: the dataset of its config]
+    #[arg(long)]
+    pub dataset: Option<PathBuf>,
+    /// Where the soft targets are written
+    #[arg(long, default_value = "soft_targets.dat")]
+    pub out: PathBuf,
+}
+
+impl SoftTargetsArgs {
+    pub fn config(&self) -> Result<Config, ConfigError> {
+        let mut config = self.model.config()?;
+        set(&mut config.training.dataset, &self.dataset);
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn parse(args: &[&str]) -> Result<Command, clap::Error> {
+        Ok(
+            Cli::try_parse_from(std::iter::once("femto-gpt").chain(args.iter().copied()))?
+                .into_command(),
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        match parse(&[
+            "train",
+            "--layers",
+            "6",
+            "--steps=10",
+            "--max-duration=90",
+            "--target-loss=1.5",
+            "--state",
+            "missing.dat",
+        ])
+        .unwrap()
+        {
+            Command::Train(args) => {
+                let config = args.config().unwrap();
+                assert_eq!(config.model.num_layers, 6);
+                assert_eq!(config.model.num_heads, 4);
+                assert_eq!(config.training.steps, 10);
+                assert_eq!(config.training.max_duration, Some(90.));
+                assert_eq!(config.training.target_loss, Some(1.5));
+                assert_eq!(config.training.epochs, None);
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+        assert!(matches!(parse(&[]).unwrap(), Command::Train(_)));
+        assert!(matches!(
+            parse(&["infer", "--prompt", "Hi", "--temperature", "0.7"]).unwrap(),
+            Command::Infer(args) if args.temperature == 0.7
+        ));
+        assert!(matches!(
+            parse(&["infer", "--draft", "small.dat"]).unwrap(),
+            Command::Infer(args) if args.draft.as_deref() == Some(Path::new("small.dat"))
+                && args.draft_tokens == 4
+        ));
+        assert!(matches!(
+            parse(&["infer", "--no-token-healing", "--only-chars", "01"]).unwrap(),
+            Command::Infer(args) if args.no_token_healing && args.only_chars.as_deref() == Some("01")
+        ));
+        assert!(matches!(
+            parse(&["infer", "--max-duration", "1.5"]).unwrap(),
+            Command::Infer(args) if args.max_duration == Some(Duration::from_millis(1500))
+        ));
+        assert!(parse(&["interactive", "--max-duration", "-1"]).is_err());
+        #[cfg(feature = "server")]
+        {
+            assert!(parse(&["serve", "--rate-limit", "0"]).is_err());
+            assert!(parse(&["serve", "--rate-limit", "60"]).is_ok());
+        }
+        assert!(matches!(
+            parse(&["attention", "--prompt", "Hi"]).unwrap(),
+            Command::Attention(args) if args.prompt == "Hi" && args.out == Path::new("attention.npz")
+        ));
+        assert!(matches!(
+            parse(&["lens", "--prompt", "Hi"]).unwrap(),
+            Command::Lens(args) if args.prompt == "Hi"
+        ));
+        assert!(matches!(
+            parse(&["inspect", "model.dat"]).unwrap(),
+            Command::Inspect(args) if args.state == Path::new("model.dat")
+        ));
+        assert!(matches!(
+            parse(&["convert", "a.dat", "b.gguf", "--dtype", "int8"]).unwrap(),
+            Command::Convert(args) if args.dtype == DType::Int8 && args.to.is_none()
+        ));
+        assert!(parse(&["convert", "a.dat", "b.gguf", "--dtype", "i4"]).is_err());
+        assert!(matches!(
+            parse(&["convert", "a.dat", "b.femto", "--tokenizer", "t.dat"]).unwrap(),
+            Command::Convert(args) if Format::from_path(&args.output) == Format::Bundle
+        ));
+        assert!(matches!(
+            parse(&["dataset", "--val-fraction", "0.1", "--by", "line"]).unwrap(),
+            Command::Dataset(args) if args.by == Unit::Line && args.val_fraction == Some(0.1)
+        ));
+        match parse(&["dataset", "--near-duplicates", "0.8"]).unwrap() {
+            Command::Dataset(args) => {
+                let cleaning = args.cleaning().unwrap();
+                assert!(cleaning.exact_duplicates && cleaning.near_duplicates == Some(0.8));
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+        assert!(matches!(
+            parse(&["dataset"]).unwrap(),
+            Command::Dataset(args) if args.cleaning().is_none()
+        ));
+        assert!(matches!(
+            parse(&["sample", "--seed", "42", "--n", "10", "--out", "samples.jsonl"]).unwrap(),
+            Command::Sample(args) if args.seed == Some(42) && args.n == 10
+        ));
+        assert!(matches!(
+            parse(&["embed", "a", "b", "--layer", "1", "--pooling", "last"]).unwrap(),
+            Command::Embed(args) if args.texts.len() == 2 && args.pooling == Pooling::Last
+        ));
+        assert!(matches!(
+            parse(&["plan", "--config", "cfg.toml", "--batch-size", "8", "--no-measure"]).unwrap(),
+            Command::Plan(args) if args.batch_size == Some(8) && args.no_measure
+        ));
+        assert!(matches!(
+            parse(&["tape", "--prompt", "abc"]).unwrap(),
+            Command::Tape(args) if args.out == Path::new("tape.json")
+        ));
+        assert!(matches!(
+            parse(&["soft-targets", "--state", "big.dat"]).unwrap(),
+            Command::SoftTargets(args) if args.out == Path::new("soft_targets.dat")
+        ));
+        match parse(&["train", "--teacher", "big.dat", "--state", "missing.dat"]).unwrap() {
+            Command::Train(args) => {
+                let distillation = args.config().unwrap().training.distillation.unwrap();
+                assert_eq!(distillation.teacher, Path::new("big.dat"));
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+        match parse(&[
+            "train",
+            "--run-dir",
+            "runs/exp1",
+            "--histograms",
+            "/tmp/h.csv",
+        ])
+        .unwrap()
+        {
+            Command::Train(args) => {
+                assert_eq!(args.model.state, Path::new("runs/exp1/training_state.dat"));
+                assert_eq!(args.model.tokenizer, Path::new("runs/exp1/tokenizer.dat"));
+                assert_eq!(
+                    args.model.saved_config(),
+                    Path::new("runs/exp1/training_state.toml")
+                );
+                assert_eq!(
+                    args.metrics.as_deref(),
+                    Some(Path::new("runs/exp1/metrics.jsonl"))
+                );
+                assert_eq!(args.histograms.as_deref(), Some(Path::new("/tmp/h.csv")));
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+        match parse(&["train", "--dp-clip-norm", "0.5", "--state", "missing.dat"]).unwrap() {
+            Command::Train(args) => {
+                let dp = args.config().unwrap().training.dp_sgd.unwrap();
+                assert_eq!((dp.clip_norm, dp.noise_multiplier), (0.5, 1.));
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+        assert!(matches!(
+            parse(&["runs", "list", "experiments"]).unwrap(),
+            Command::Runs(RunsCommand::List { dir }) if dir == Path::new("experiments")
+        ));
+        assert!(parse(&["tokenize"]).is_err());
+        let level = |args: &[&str]| {
+            Cli::try_parse_from(std::iter::once("femto-gpt").chain(args.iter().copied()))
+                .map(|cli| cli.log_level())
+        };
+        assert_eq!(level(&["train"]).unwrap(), LevelFilter::INFO);
+        assert_eq!(level(&["train", "-vv"]).unwrap(), LevelFilter::TRACE);
+        assert_eq!(level(&["-q", "bench"]).unwrap(), LevelFilter::WARN);
+        assert!(level(&["bench", "-q", "-v"]).is_err());
+        assert!(parse(&["train", "--layer", "6"]).is_err());
+        assert!(parse(&["train", "--steps", "many"]).is_err());
+        assert!(parse(&["fly"]).is_err());
+        match parse(&["train", "--heads", "5", "--state", "missing.dat"]).unwrap() {
+            Command::Train(args) => assert!(args.config().is_err()),
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+    }
+}
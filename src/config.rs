@@ -0,0 +1,1021 @@
+use crate::bundle::{self, Bundle};
+use crate::funcs::registry;
+use crate::gpt::{
+    Activation, Distillation, PositionalEncoding, SoftTargets, Teacher, TrainingState, GPT,
+};
+use crate::graph::{GraphError, Precision};
+use crate::optimizer::{AdamW, DecayMode, WeightDecay};
+use crate::privacy::DpSgd;
+use crate::rotate::Rotation;
+use crate::template::{ChatTemplate, Conversation};
+use crate::tokenizer::{AsciiTokenizer, SimpleTokenizer, Tokenizer};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("parse error: {0}")]
+    ParseError(#[from] toml::de::Error),
+    #[error("invalid config: {0}")]
+    Invalid(String),
+    #[error("invalid checkpoint: {0}")]
+    InvalidCheckpoint(#[from] bincode::Error),
+    #[error("invalid bundle: {0}")]
+    InvalidBundle(String),
+    #[error("invalid sharded checkpoint: {0}")]
+    InvalidShards(String),
+    #[error("graph error: {0}")]
+    GraphError(#[from] GraphError),
+    #[error("tokenizer error: {0}")]
+    TokenizerError(#[from] crate::tokenizer::TokenizerError),
+    #[error("the dataset has hash {found}, not the expected {expected}")]
+    DatasetMismatch { expected: String, found: String },
+}
+
+/// Everything needed to reproduce an experiment, readable from a TOML file. Missing
+/// sections and fields take their default values. (E.g. a file with only
+/// `[model]\nnum_layers = 6` is a valid config)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model: ModelConfig,
+    pub tokenizer: TokenizerConfig,
+    pub optimizer: OptimizerConfig,
+    pub scheduler: SchedulerConfig,
+    pub training: TrainingConfig,
+    /// How conversations are written, for training on chats and chatting
+    pub chat: ChatTemplate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub num_tokens: usize,
+    pub embedding_degree: usize,
+    pub num_layers: usize,
+    pub num_heads: usize,
+    pub dropout: f64,
+    /// The nonlinearity of the feedforward blocks (`relu`, `gelu`, `silu` or `tanh`).
+    /// Weights trained with one don't work with the others.
+    pub activation: Activation,
+    /// How positions are encoded (`learned`, `sinusoidal`, `rope`, `alibi` or `none`)
+    pub positional_encoding: PositionalEncoding,
+    /// Whether the attention and the feed-forward layer of each block read the same
+    /// normalized input, their results being summed (As in GPT-J), instead of one
+    /// after the other
+    pub parallel_residual: bool,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            num_tokens: 64,
+            embedding_degree: 64,
+            num_layers: 4,
+            num_heads: 4,
+            dropout: 0.0,
+            activation: Activation::Relu,
+            positional_encoding: PositionalEncoding::Learned,
+            parallel_residual: false,
+        }
+    }
+}
+
+impl ModelConfig {
+    pub fn head_size(&self) -> usize {
+        self.embedding_degree / self.num_heads
+    }
+
+    pub fn build<R: Rng>(
+        &self,
+        rng: &mut R,
+        vocab_size: usize,
+        optimizer: AdamW,
+    ) -> Result<GPT<AdamW>, GraphError> {
+        GPT::new(rng, vocab_size, self, optimizer)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenizerKind {
+    /// One token per unique character of the dataset (`SimpleTokenizer`)
+    Simple,
+    /// One token per ASCII character (`AsciiTokenizer`)
+    Ascii,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenizerConfig {
+    pub kind: TokenizerKind,
+}
+
+impl Default for TokenizerConfig {
+    fn default() -> Self {
+        Self {
+            kind: TokenizerKind::Simple,
+        }
+    }
+}
+
+/// Hyperparameters of `AdamW`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OptimizerConfig {
+    pub beta1: f64,
+    pub beta2: f64,
+    /// 0 for no weight decay at all
+    pub weight_decay: f64,
+    /// `decoupled` (AdamW, as in PyTorch's `AdamW`) or `l2` (Added to the gradients,
+    /// as in PyTorch's `Adam`)
+    pub decay_mode: DecayMode,
+    /// Parameters whose names contain any of these aren't decayed (E.g. `["bias",
+    /// "norm"]`)
+    pub no_decay: Vec<String>,
+    /// Factor of the learning rate from each block to the one below it, for
+    /// fine-tuning (See `GPT::set_layer_decay`); 1 for none
+    pub layer_decay: f64,
+    /// Only update the rows of the embeddings each step trains on (See
+    /// `GPT::set_sparse_embeddings`)
+    pub sparse_embeddings: bool,
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        Self {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.01,
+            decay_mode: DecayMode::Decoupled,
+            no_decay: Vec::new(),
+            layer_decay: 1.,
+            sparse_embeddings: false,
+        }
+    }
+}
+
+impl OptimizerConfig {
+    pub fn build(&self) -> AdamW {
+        AdamW::with_hyperparams(
+            self.beta1 as f32,
+            self.beta2 as f32,
+            self.weight_decay as f32,
+        )
+    }
+
+    /// How the weight decay is applied, for `GPT::set_weight_decay`
+    pub fn weight_decay(&self) -> WeightDecay {
+        WeightDecay {
+            mode: self.decay_mode,
+            no_decay: self.no_decay.clone(),
+        }
+    }
+}
+
+/// What the learning rate does after the warmup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleKind {
+    /// Linear decay from `base_lr` to `min_lr`, over `decay_steps`
+    #[default]
+    Linear,
+    /// Cosine annealing from `base_lr` to `min_lr` with warm restarts (SGDR): the first
+    /// cycle takes `restart_steps`, each next one `restart_mult` times as many
+    Cosine,
+    /// `base_lr`, multiplied by `plateau_factor` (Down to `min_lr`) whenever the
+    /// validation loss hasn't improved for `plateau_patience` evaluations
+    Plateau,
+}
+
+impl std::str::FromStr for ScheduleKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(ScheduleKind::Linear),
+            "cosine" => Ok(ScheduleKind::Cosine),
+            "plateau" => Ok(ScheduleKind::Plateau),
+            _ => Err(format!(
+                "unknown schedule '{}' (Expected linear, cosine or plateau)",
+                s
+            )),
+        }
+    }
+}
+
+/// Linear warmup from 0 to `base_lr`, followed by the schedule of `kind`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    pub kind: ScheduleKind,
+    pub base_lr: f64,
+    pub min_lr: f64,
+    pub warmup_steps: usize,
+    pub decay_steps: usize,
+    pub restart_steps: usize,
+    pub restart_mult: usize,
+    pub plateau_factor: f64,
+    pub plateau_patience: usize,
+    /// Relative decrease of the validation loss below which it hasn't improved
+    pub plateau_threshold: f64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            kind: ScheduleKind::Linear,
+            base_lr: 0.001,
+            min_lr: 0.00001,
+            warmup_steps: 100,
+            decay_steps: 50000,
+            restart_steps: 5000,
+            restart_mult: 2,
+            plateau_factor: 0.5,
+            plateau_patience: 3,
+            plateau_threshold: 0.001,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    /// The learning rate at `step`, for a plateau schedule the learning rate was never
+    /// reduced by (See `Scheduler` for the others)
+    pub fn learning_rate(&self, step: usize) -> f32 {
+        self.scaled_learning_rate(step, 1.)
+    }
+
+    fn scaled_learning_rate(&self, step: usize, scale: f64) -> f32 {
+        let lr = if step < self.warmup_steps {
+            (self.base_lr / self.warmup_steps as f64) * step as f64
+        } else {
+            let step = step - self.warmup_steps;
+            match self.kind {
+                // Fancy LR tuning, thanks to https://github.com/cutoken!
+                ScheduleKind::Linear => f64::max(
+                    self.min_lr,
+                    self.base_lr
+                        - (self.base_lr - self.min_lr) * step as f64 / self.decay_steps as f64,
+                ),
+                ScheduleKind::Cosine => {
+                    // Finds the cycle the step is in, and how far into it
+                    let (mut cycle, mut t) = (self.restart_steps.max(1), step);
+                    while t >= cycle {
+                        t -= cycle;
+                        cycle = cycle.saturating_mul(self.restart_mult.max(1));
+                    }
+                    let progress = t as f64 / cycle as f64;
+                    self.min_lr
+                        + (self.base_lr - self.min_lr)
+                            * (1. + (std::f64::consts::PI * progress).cos())
+                            / 2.
+                }
+                ScheduleKind::Plateau => f64::max(self.min_lr, self.base_lr * scale),
+            }
+        };
+        lr as f32
+    }
+}
+
+/// A learning-rate schedule, along with what it learned from the validation losses
+/// (For plateau schedules). Serializable, so that it survives resuming.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scheduler {
+    pub config: SchedulerConfig,
+    /// Lowest validation loss so far
+    pub best_loss: Option<f32>,
+    /// Evaluations since the validation loss last improved
+    pub bad_evals: usize,
+    /// What `base_lr` is multiplied by
+    pub scale: f64,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            best_loss: None,
+            bad_evals: 0,
+            scale: 1.,
+        }
+    }
+
+    pub fn learning_rate(&self, step: usize) -> f32 {
+        self.config.scaled_learning_rate(step, self.scale)
+    }
+
+    /// Records a validation loss. Returns true if the learning rate was reduced.
+    pub fn observe(&mut self, loss: f32) -> bool {
+        let threshold = 1. - self.config.plateau_threshold as f32;
+        match self.best_loss {
+            Some(best) if loss >= best * threshold => self.bad_evals += 1,
+            _ => {
+                self.best_loss = Some(loss);
+                self.bad_evals = 0;
+            }
+        }
+        if self.config.kind == ScheduleKind::Plateau
+            && self.bad_evals >= self.config.plateau_patience
+        {
+            self.bad_evals = 0;
+            self.scale *= self.config.plateau_factor;
+            return true;
+        }
+        false
+    }
+
+    /// Where the scheduler of a training state is saved
+    pub fn path_of(state: &Path) -> PathBuf {
+        state.with_extension("scheduler.json")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| ConfigError::Invalid(format!("invalid scheduler: {}", e)))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let json = serde_json::to_string_pretty(self).expect("Always representable in JSON");
+        Ok(std::fs::write(path, json)?)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainingConfig {
+    /// A text file, a directory of them (Read recursively) or a glob (E.g.
+    /// `data/*.txt`, the wildcards being only allowed in the file name)
+    pub dataset: PathBuf,
+    /// Text put between the files of the dataset
+    pub document_separator: String,
+    pub steps: usize,
+    /// Stop once this many passes over the dataset are done (Counting the ones of the
+    /// runs resumed from), if before `steps`
+    pub epochs: Option<usize>,
+    /// Stop after training for this many seconds, if before `steps`
+    pub max_duration: Option<f64>,
+    /// Stop once the loss, averaged over the last steps, is down to this, if before
+    /// `steps`
+    pub target_loss: Option<f32>,
+    pub batch_size: usize,
+    /// Windows start at multiples of it, overlapping by `num_tokens - stride` tokens
+    /// (Or disjoint when it's `num_tokens`), instead of anywhere. The validation
+    /// perplexity goes by it too, as sliding-window perplexity. (See
+    /// `dataset::Strided`)
+    pub stride: Option<usize>,
+    /// Weight the loss of each target token by its inverse frequency in the dataset,
+    /// for the model not to over-predict whitespace and common characters (See
+    /// `dataset::inverse_frequencies`)
+    pub frequency_weighted_loss: bool,
+    /// Threads to spread the samples of a batch over (As many as CPUs if not given)
+    pub num_threads: Option<usize>,
+    /// Threads to split the products by the weights of each sample over, on top of
+    /// spreading the samples (1 for none; see `GPT::set_tensor_parallel`)
+    pub tensor_parallel: usize,
+    /// Run the chains of functions that have a fused implementation (E.g. the products
+    /// by the weights with their biases and activations) as one function each (See
+    /// `GPT::fuse`). Not with `tensor_parallel`, which doesn't split fused products.
+    pub fuse_ops: bool,
+    /// Rewind and lower the learning-rate for a while whenever the loss explodes
+    pub spike_guard: bool,
+    /// `mixed` stores the weights and the activations in f16, halving the memory they
+    /// take, with f32 master weights and dynamic loss scaling
+    pub precision: Precision,
+    /// Tensors kept in f32 in mixed precision, by name (Where `*` stands for any
+    /// characters, e.g. `*norm*` for the layer norms, `logits` for the output of the
+    /// model; see `GPT::set_full_precision`)
+    pub full_precision: Vec<String>,
+    /// CSV file histograms of the weights and gradients are appended to at every
+    /// evaluation (See `histogram::HistogramCsv`)
+    pub histograms: Option<PathBuf>,
+    /// Estimate the gradient noise scale, and log the batch-size it suggests at every
+    /// evaluation (See `gpt::NoiseScale`)
+    pub noise_scale: bool,
+    /// JSONL file the metrics of every step are appended to, after the metadata of
+    /// the run (See `run::MetricsLog`)
+    pub metrics: Option<PathBuf>,
+    /// When the metrics and histograms files are rotated, for long runs (See
+    /// `rotate::Rotation`)
+    pub log_rotation: Rotation,
+    /// Held-out text the validation loss is computed on at every evaluation (Which
+    /// plateau schedules go by)
+    pub validation: Option<PathBuf>,
+    /// Distill a teacher into the model (See `gpt::Distillation`)
+    pub distillation: Option<DistillationConfig>,
+    /// Train with differential privacy (See `privacy::DpSgd`)
+    pub dp_sgd: Option<DpSgdConfig>,
+    /// Write the checkpoints on a background thread while training goes on (See
+    /// `checkpoint::AsyncWriter`)
+    pub async_checkpoints: bool,
+    /// Functions (By registered name, e.g. `matmul`) whose outputs are quantized to
+    /// `fake_quant_bits` bits and back, for models meant to run in int8 to be trained
+    /// quantization-aware (See `GPT::set_fake_quant`)
+    pub fake_quant: Vec<String>,
+    pub fake_quant_bits: u32,
+    /// Before training, run a window in f64 as well, logging the functions whose results
+    /// stray the most from it (See `GPT::check_numerics`)
+    pub check_numerics: bool,
+    /// Before training, check that the model memorizes a single batch of the dataset,
+    /// failing otherwise (See `GPT::overfit_sanity_check`)
+    pub overfit_check: bool,
+    /// Before training, zero this fraction of the weights of the products, which then
+    /// stay zero, for fine-tuning a sparse model (See `GPT::prune`)
+    pub prune: Option<f32>,
+    /// Seed of the initial weights, and so of the order of the data, for runs to be
+    /// reproducible (Random if not given; see `gpt::SeededRng`)
+    pub seed: Option<u64>,
+    /// Hash the dataset should have (As in the `dataset_hash` of the runs, see
+    /// `run::dataset_hash`), for the data not to change unnoticed between the runs of
+    /// an experiment, e.g. when fine-tuning
+    pub dataset_hash: Option<String>,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        Self {
+            dataset: "dataset.txt".into(),
+            document_separator: "\n\n".into(),
+            steps: 100000,
+            epochs: None,
+            max_duration: None,
+            target_loss: None,
+            batch_size: 32,
+            stride: None,
+            frequency_weighted_loss: false,
+            num_threads: None,
+            tensor_parallel: 1,
+            fuse_ops: false,
+            spike_guard: true,
+            precision: Precision::F32,
+            full_precision: Vec::new(),
+            histograms: None,
+            noise_scale: false,
+            metrics: None,
+            log_rotation: Rotation::default(),
+            validation: None,
+            distillation: None,
+            dp_sgd: None,
+            async_checkpoints: false,
+            fake_quant: Vec::new(),
+            fake_quant_bits: 8,
+            check_numerics: false,
+            overfit_check: false,
+            prune: None,
+            seed: None,
+            dataset_hash: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DistillationConfig {
+    /// The training state of the teacher (Its config being the one saved next to it),
+    /// its bundle, or soft targets precomputed with `femto-gpt soft-targets`
+    pub teacher: PathBuf,
+    /// Temperature both distributions are softened by
+    pub temperature: f32,
+    /// Weight of the KL divergence to the teacher, the cross-entropy to the actual
+    /// tokens getting the rest
+    pub alpha: f32,
+}
+
+impl Default for DistillationConfig {
+    fn default() -> Self {
+        Self {
+            teacher: "teacher.dat".into(),
+            temperature: 2.,
+            alpha: 0.5,
+        }
+    }
+}
+
+impl DistillationConfig {
+    /// Loads the teacher, for a student with a vocabulary of `vocab_size` tokens and a
+    /// context of `num_tokens`. Precomputed soft targets are returned too, as they are
+    /// then the dataset to train on.
+    pub fn load(
+        &self,
+        vocab_size: usize,
+        num_tokens: usize,
+    ) -> Result<(Distillation, Option<Arc<SoftTargets>>), ConfigError> {
+        let bytes = std::fs::read(&self.teacher)?;
+        let (teacher, soft_targets): (Arc<dyn Teacher>, _) = if SoftTargets::is_soft_targets(&bytes)
+        {
+            let soft_targets = Arc::new(SoftTargets::read(&bytes[..])?);
+            if soft_targets.context_size() < num_tokens {
+                return Err(ConfigError::Invalid(format!(
+                    "the soft targets are for windows of {} tokens, the model needs {}",
+                    soft_targets.context_size(),
+                    num_tokens
+                )));
+            }
+            (soft_targets.clone(), Some(soft_targets))
+        } else if bundle::is_bundle(&self.teacher) {
+            let bundle = Bundle::from_bytes(&bytes)?;
+            let mut gpt = bundle.config.model.build(
+                &mut rand::thread_rng(),
+                vocab_size,
+                bundle.config.optimizer.build(),
+            )?;
+            bundle.load_into(&mut gpt)?;
+            (Arc::new(gpt), None)
+        } else {
+            let config = Config::load(self.teacher.with_extension("toml"))?;
+            let mut gpt = config.model.build(
+                &mut rand::thread_rng(),
+                vocab_size,
+                config.optimizer.build(),
+            )?;
+            if bytes.starts_with(b"FGTM") {
+                gpt.read_params(&bytes[..])?;
+            } else {
+                let state: TrainingState<AdamW> = bincode::deserialize(&bytes)?;
+                gpt.set_training_state(state, false)?;
+            }
+            (Arc::new(gpt), None)
+        };
+        let mut distillation = Distillation::new(teacher);
+        distillation.temperature = self.temperature;
+        distillation.alpha = self.alpha;
+        Ok((distillation, soft_targets))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DpSgdConfig {
+    /// Maximum L2 norm of the gradients of a window
+    pub clip_norm: f32,
+    /// Standard deviation of the noise added to the gradients of a batch, relative to
+    /// `clip_norm`: the higher, the less privacy each step spends
+    pub noise_multiplier: f32,
+    /// Probability of the reported privacy budget not holding, well below one over
+    /// the number of windows of the dataset
+    pub delta: f64,
+}
+
+impl Default for DpSgdConfig {
+    fn default() -> Self {
+        Self {
+            clip_norm: 1.,
+            noise_multiplier: 1.,
+            delta: 1e-5,
+        }
+    }
+}
+
+impl DpSgdConfig {
+    pub fn build(&self) -> DpSgd {
+        DpSgd::new(self.clip_norm, self.noise_multiplier, self.delta)
+    }
+}
+
+impl Config {
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("A config is always representable in TOML")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        Ok(std::fs::write(path, self.to_toml())?)
+    }
+
+    /// The tokenizer of the model, given the saved one (Which is ignored by the ASCII
+    /// tokenizer)
+    pub fn load_tokenizer(
+        &self,
+        tokenizer: &[u8],
+    ) -> Result<Box<dyn Tokenizer + Send + Sync>, ConfigError> {
+        Ok(match self.tokenizer.kind {
+            TokenizerKind::Simple => Box::new(bincode::deserialize::<SimpleTokenizer>(tokenizer)?),
+            TokenizerKind::Ascii => Box::new(AsciiTokenizer),
+        })
+    }
+
+    /// Builds the model and loads its weights, `weights` being either a `TrainingState`
+    /// or the output of `GPT::write_params`, and `tokenizer` the saved tokenizer (Which
+    /// is ignored by the ASCII tokenizer). Fails if the training state was trained with
+    /// another tokenizer.
+    pub fn load_model(
+        &self,
+        tokenizer: &[u8],
+        weights: &[u8],
+    ) -> Result<(GPT<AdamW>, Box<dyn Tokenizer + Send + Sync>), ConfigError> {
+        let tokenizer = self.load_tokenizer(tokenizer)?;
+        let mut rng = rand::thread_rng();
+        let mut gpt = self
+            .model
+            .build(&mut rng, tokenizer.vocab_size(), self.optimizer.build())?;
+        if weights.starts_with(b"FGTM") {
+            gpt.read_params(weights)?;
+        } else {
+            let state: TrainingState<AdamW> = bincode::deserialize(weights)?;
+            gpt.set_training_state(state, false)?;
+        }
+        gpt.check_tokenizer(tokenizer.as_ref())?;
+        Ok((gpt, tokenizer))
+    }
+
+    /// The files of the dataset, in a stable order
+    pub fn dataset_files(&self) -> Result<Vec<PathBuf>, ConfigError> {
+        let path = &self.training.dataset;
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let mut files = Vec::new();
+        if name.contains(['*', '?']) {
+            let dir = match path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            };
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?.path();
+                let matches = entry
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| wildcard_match(name, n));
+                if matches && entry.is_file() {
+                    files.push(entry);
+                }
+            }
+        } else if path.is_dir() {
+            collect_files(path, &mut files)?;
+        } else {
+            files.push(path.clone());
+        }
+        files.sort();
+        if files.is_empty() {
+            return Err(ConfigError::Invalid(format!(
+                "no dataset files in {}",
+                path.display()
+            )));
+        }
+        Ok(files)
+    }
+
+    /// The files of the dataset and their text. `.jsonl` files hold a conversation
+    /// per line, written out with the chat template.
+    /// Fails if they don't have the `dataset_hash` of the config.
+    pub fn load_documents(&self) -> Result<Vec<(PathBuf, String)>, ConfigError> {
+        let documents = self
+            .dataset_files()?
+            .into_iter()
+            .map(|path| {
+                let text = std::fs::read_to_string(&path)?;
+                if path.extension().and_then(|e| e.to_str()) != Some("jsonl") {
+                    return Ok((path, text));
+                }
+                let mut out = String::new();
+                for (i, line) in text.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let conversation: Conversation = serde_json::from_str(line).map_err(|e| {
+                        ConfigError::Invalid(format!("{}, line {}: {}", path.display(), i + 1, e))
+                    })?;
+                    out.push_str(&self.chat.render(&conversation.messages, false));
+                }
+                Ok((path, out))
+            })
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+        if let Some(expected) = &self.training.dataset_hash {
+            let found = format!("{:016x}", crate::run::dataset_hash(&documents));
+            if !expected.eq_ignore_ascii_case(&found) {
+                return Err(ConfigError::DatasetMismatch {
+                    expected: expected.clone(),
+                    found,
+                });
+            }
+        }
+        Ok(documents)
+    }
+
+    /// The text to train on: the documents, separated by the document separator
+    pub fn load_dataset(&self) -> Result<String, ConfigError> {
+        let documents = self.load_documents()?;
+        Ok(documents
+            .iter()
+            .map(|(_, text)| text.as_str())
+            .collect::<Vec<_>>()
+            .join(&self.training.document_separator))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let m = &self.model;
+        if m.num_heads == 0 || !m.embedding_degree.is_multiple_of(m.num_heads) {
+            return Err(ConfigError::Invalid(format!(
+                "the embedding-degree ({}) should be divisible by the number of heads ({})",
+                m.embedding_degree, m.num_heads
+            )));
+        }
+        if m.num_tokens == 0 || self.training.batch_size == 0 {
+            return Err(ConfigError::Invalid(
+                "num-tokens and batch-size should be positive".into(),
+            ));
+        }
+        if !(0.0..1.0).contains(&m.dropout) {
+            return Err(ConfigError::Invalid(format!(
+                "dropout ({}) should be in [0, 1)",
+                m.dropout
+            )));
+        }
+        if !(self.optimizer.layer_decay > 0. && self.optimizer.layer_decay <= 1.) {
+            return Err(ConfigError::Invalid(format!(
+                "layer_decay ({}) should be in (0, 1]",
+                self.optimizer.layer_decay
+            )));
+        }
+        if let Some(secs) = self.training.max_duration {
+            if Duration::try_from_secs_f64(secs).is_err() {
+                return Err(ConfigError::Invalid(format!(
+                    "max_duration ({}) should be a number of seconds",
+                    secs
+                )));
+            }
+        }
+        if self.training.target_loss.is_some_and(|l| !l.is_finite()) {
+            return Err(ConfigError::Invalid("target_loss should be finite".into()));
+        }
+        if self.scheduler.kind == ScheduleKind::Plateau && self.training.validation.is_none() {
+            return Err(ConfigError::Invalid(
+                "plateau schedules need a validation set".into(),
+            ));
+        }
+        if let Some(op) = self
+            .training
+            .fake_quant
+            .iter()
+            .find(|op| !registry::is_registered(op))
+        {
+            return Err(ConfigError::Invalid(format!(
+                "no function named '{}' to fake-quantize",
+                op
+            )));
+        }
+        if let Some(stride) = self.training.stride {
+            if stride == 0 || stride > self.model.num_tokens {
+                return Err(ConfigError::Invalid(format!(
+                    "stride ({}) should be in [1, num_tokens ({})]",
+                    stride, self.model.num_tokens
+                )));
+            }
+        }
+        if self.training.tensor_parallel == 0 {
+            return Err(ConfigError::Invalid(
+                "tensor_parallel should be at least 1".into(),
+            ));
+        }
+        if self.training.fuse_ops && self.training.tensor_parallel > 1 {
+            return Err(ConfigError::Invalid(
+                "fuse_ops can't be combined with tensor_parallel".into(),
+            ));
+        }
+        if let Some(sparsity) = self.training.prune {
+            if !(0.0..=1.0).contains(&sparsity) {
+                return Err(ConfigError::Invalid(format!(
+                    "prune ({}) should be in [0, 1]",
+                    sparsity
+                )));
+            }
+        }
+        if let Some(hash) = &self.training.dataset_hash {
+            if hash.len() != 16 || !hash.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                return Err(ConfigError::Invalid(format!(
+                    "dataset_hash ({}) should be 16 hexadecimal digits",
+                    hash
+                )));
+            }
+        }
+        let rotation = &self.training.log_rotation;
+        if rotation.max_bytes == Some(0)
+            || rotation.max_hours.is_some_and(|h| h.is_nan() || h <= 0.)
+            || rotation.keep == Some(0)
+        {
+            return Err(ConfigError::Invalid(
+                "the log_rotation limits and the number of files kept should be positive".into(),
+            ));
+        }
+        if !(2..=16).contains(&self.training.fake_quant_bits) {
+            return Err(ConfigError::Invalid(format!(
+                "fake_quant_bits ({}) should be in [2, 16]",
+                self.training.fake_quant_bits
+            )));
+        }
+        if let Some(d) = &self.training.distillation {
+            if d.temperature <= 0. || !(0.0..=1.0).contains(&d.alpha) {
+                return Err(ConfigError::Invalid(format!(
+                    "the distillation temperature ({}) should be positive and alpha ({}) in [0, 1]",
+                    d.temperature, d.alpha
+                )));
+            }
+        }
+        if let Some(dp) = &self.training.dp_sgd {
+            if !(dp.clip_norm > 0. && dp.noise_multiplier > 0. && dp.delta > 0. && dp.delta < 1.) {
+                return Err(ConfigError::Invalid(format!(
+                    "the DP-SGD clip_norm ({}) and noise_multiplier ({}) should be positive and delta ({}) in (0, 1)",
+                    dp.clip_norm, dp.noise_multiplier, dp.delta
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The regular files under `dir`, hidden ones excepted
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), ConfigError> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any characters and `?` for
+/// a single one
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (p, n) = (
+        pattern.chars().collect::<Vec<_>>(),
+        name.chars().collect::<Vec<_>>(),
+    );
+    // matches[j]: whether the pattern so far matches the first j characters
+    let mut matches = vec![false; n.len() + 1];
+    matches[0] = true;
+    for pc in p {
+        let prev = matches.clone();
+        matches[0] = pc == '*' && prev[0];
+        for j in 1..=n.len() {
+            matches[j] = match pc {
+                '*' => prev[j] || matches[j - 1],
+                '?' => prev[j - 1],
+                c => prev[j - 1] && n[j - 1] == c,
+            };
+        }
+    }
+    matches[n.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = Config::from_toml(
+            "[model]\nnum_layers = 6\n\n[tokenizer]\nkind = \"ascii\"\n\n[scheduler]\nwarmup_steps = 10\n",
+        )
+        .unwrap();
+        assert_eq!(config.model.num_layers, 6);
+        assert_eq!(config.model.num_heads, 4);
+        assert_eq!(config.tokenizer.kind, TokenizerKind::Ascii);
+        assert_eq!(config.scheduler.learning_rate(5), 0.0005);
+        assert_eq!(Config::from_toml(&config.to_toml()).unwrap(), config);
+
+        assert!(Config::from_toml("[model]\nnum_heads = 5\n").is_err());
+        assert!(Config::from_toml("[model]\nnum_layer = 6\n").is_err());
+        assert!(Config::from_toml("[modle]\n").is_err());
+
+        let gelu = Config::from_toml("[model]\nactivation = \"gelu\"\n").unwrap();
+        assert_eq!(gelu.model.activation, Activation::Gelu);
+        assert_eq!(Config::from_toml(&gelu.to_toml()).unwrap(), gelu);
+        assert!(Config::from_toml("[model]\nactivation = \"swish\"\n").is_err());
+        let rope = Config::from_toml("[model]\npositional_encoding = \"rope\"\n").unwrap();
+        assert_eq!(rope.model.positional_encoding, PositionalEncoding::Rope);
+        let distill =
+            Config::from_toml("[training.distillation]\nteacher = \"big.dat\"\nalpha = 0.9\n")
+                .unwrap();
+        let d = distill.training.distillation.as_ref().unwrap();
+        assert_eq!((d.alpha, d.temperature), (0.9, 2.));
+        assert_eq!(Config::from_toml(&distill.to_toml()).unwrap(), distill);
+        assert!(Config::from_toml("[training.distillation]\ntemperature = 0.0\n").is_err());
+        let dp = Config::from_toml("[training.dp_sgd]\nnoise_multiplier = 0.8\n").unwrap();
+        let d = dp.training.dp_sgd.as_ref().unwrap();
+        assert_eq!((d.clip_norm, d.noise_multiplier, d.delta), (1., 0.8, 1e-5));
+        assert_eq!(Config::from_toml(&dp.to_toml()).unwrap(), dp);
+        assert!(Config::from_toml("[training.dp_sgd]\ndelta = 1.0\n").is_err());
+        assert!(Config::from_toml("[training.dp_sgd]\nclip_norm = 0.0\n").is_err());
+        let l2 = Config::from_toml("[optimizer]\ndecay_mode = \"l2\"\nno_decay = [\"bias\"]\n")
+            .unwrap()
+            .optimizer
+            .weight_decay();
+        assert_eq!(l2.mode, DecayMode::L2);
+        assert!(l2.applies_to("head_weights") && !l2.applies_to("feedforward1_0_bias"));
+        assert!(Config::from_toml("[optimizer]\nlayer_decay = 0.8\n").is_ok());
+        assert!(Config::from_toml("[optimizer]\nlayer_decay = 1.5\n").is_err());
+        assert!(Config::from_toml("[training]\nfake_quant = [\"matmul\", \"gelu\"]\n").is_ok());
+        assert!(Config::from_toml("[training]\nfake_quant = [\"conv\"]\n").is_err());
+        assert!(Config::from_toml("[training]\nfake_quant_bits = 1\n").is_err());
+        assert!(Config::from_toml("[training]\nstride = 32\n").is_ok());
+        assert!(Config::from_toml("[training]\nstride = 0\n").is_err());
+        assert!(Config::from_toml("[training]\ntensor_parallel = 4\n").is_ok());
+        assert!(Config::from_toml("[training]\ntensor_parallel = 0\n").is_err());
+        assert!(Config::from_toml("[training]\nfuse_ops = true\n").is_ok());
+        assert!(Config::from_toml("[training]\nfuse_ops = true\ntensor_parallel = 2\n").is_err());
+        assert!(Config::from_toml("[training]\nprune = 0.5\n").is_ok());
+        assert!(Config::from_toml("[training]\nprune = 1.5\n").is_err());
+        assert!(Config::from_toml("[training]\ndataset_hash = \"0123456789abcdef\"\n").is_ok());
+        assert!(Config::from_toml("[training]\ndataset_hash = \"0123\"\n").is_err());
+        let rotation = Config::from_toml("[training.log_rotation]\nmax_hours = 24.0\n").unwrap();
+        assert_eq!(rotation.training.log_rotation.max_hours, Some(24.));
+        assert!(rotation.training.log_rotation.compress);
+        assert!(Config::from_toml("[training.log_rotation]\nmax_bytes = 0\n").is_err());
+        assert!(Config::from_toml("[training]\nstride = 100000\n").is_err());
+
+        let mut config =
+            Config::from_toml("[chat]\nuser_prefix = \"Q: \"\nassistant_prefix = \"A: \"\n")
+                .unwrap();
+        let dataset = std::env::temp_dir().join("femto_gpt_test_chat.jsonl");
+        std::fs::write(
+            &dataset,
+            "{\"messages\": [{\"role\": \"user\", \"content\": \"Hi\"}, {\"role\": \"assistant\", \"content\": \"Hey\"}]}\n\n",
+        )
+        .unwrap();
+        config.training.dataset = dataset.clone();
+        assert_eq!(config.load_dataset().unwrap(), "Q: Hi\nA: Hey\n");
+        std::fs::write(&dataset, "{\"messages\": 1}\n").unwrap();
+        assert!(config.load_dataset().is_err());
+        std::fs::remove_file(dataset).unwrap();
+
+        let dir = std::env::temp_dir().join("femto_gpt_test_dataset");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("more")).unwrap();
+        std::fs::write(dir.join("b.txt"), "B").unwrap();
+        std::fs::write(dir.join("a.txt"), "A").unwrap();
+        std::fs::write(dir.join("more/c.md"), "C").unwrap();
+        std::fs::write(dir.join(".hidden"), "H").unwrap();
+        config.training.dataset = dir.clone();
+        config.training.document_separator = "|".into();
+        assert_eq!(config.load_dataset().unwrap(), "A|B|C");
+        config.training.dataset = dir.join("*.txt");
+        assert_eq!(config.load_dataset().unwrap(), "A|B");
+        let hash = crate::run::dataset_hash(&config.load_documents().unwrap());
+        config.training.dataset_hash = Some(format!("{:016X}", hash));
+        assert!(config.load_documents().is_ok());
+        config.training.dataset_hash = Some(format!("{:016x}", hash ^ 1));
+        assert!(matches!(
+            config.load_documents(),
+            Err(ConfigError::DatasetMismatch { .. })
+        ));
+        config.training.dataset_hash = None;
+        config.training.dataset = dir.join("*.csv");
+        assert!(config.load_dataset().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(wildcard_match("a*b?.txt", "axxbc.txt"));
+        assert!(!wildcard_match("a*.txt", "a.md"));
+    }
+
+    #[test]
+    fn test_schedules() {
+        let config = Config::from_toml(
+            "[scheduler]\nkind = \"cosine\"\nwarmup_steps = 0\nmin_lr = 0.0\nrestart_steps = 10\n",
+        )
+        .unwrap();
+        let s = &config.scheduler;
+        assert_eq!(s.learning_rate(0), 0.001);
+        assert!((s.learning_rate(5) - 0.0005).abs() < 1e-7);
+        // Restarts after 10 steps, then after 20 more
+        assert!(s.learning_rate(9) < s.learning_rate(10));
+        assert_eq!(s.learning_rate(10), 0.001);
+        assert!((s.learning_rate(20) - 0.0005).abs() < 1e-7);
+        assert_eq!(s.learning_rate(30), 0.001);
+
+        let toml = "[scheduler]\nkind = \"plateau\"\nwarmup_steps = 0\nplateau_patience = 2\n";
+        assert!(Config::from_toml(toml).is_err());
+        let plateau =
+            Config::from_toml(&format!("{}[training]\nvalidation = \"val.txt\"\n", toml)).unwrap();
+        let mut scheduler = Scheduler::new(plateau.scheduler.clone());
+        assert!(!scheduler.observe(2.0));
+        assert!(!scheduler.observe(1.5));
+        assert!(!scheduler.observe(1.5));
+        assert!(scheduler.observe(1.6));
+        assert_eq!(scheduler.learning_rate(100), 0.0005);
+        let json = serde_json::to_string(&scheduler).unwrap();
+        assert_eq!(serde_json::from_str::<Scheduler>(&json).unwrap(), scheduler);
+    }
+}
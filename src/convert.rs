@@ -0,0 +1,310 @@
+use femto_gpt::bundle::{self, Bundle};
+use femto_gpt::config::{Config, ConfigError, TokenizerKind};
+use femto_gpt::gpt::{Provenance, TrainingState};
+use femto_gpt::graph::GraphError;
+use femto_gpt::optimizer::AdamW;
+use femto_gpt::run::RunInfo;
+use femto_gpt::tensor::{
+    read_gguf, read_safetensors, read_tensors, write_gguf, write_safetensors, DType, GgufValue,
+    Tensor, TensorError,
+};
+use std::path::Path;
+
+/// Key of the metadata holding the config of the model (As TOML)
+const SAFETENSORS_CONFIG_KEY: &str = "femto_gpt.config";
+const GGUF_ARCHITECTURE: &str = "femto-gpt";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Bincode `TrainingState`, as saved by `femto-gpt train`
+    State,
+    /// Weights written by `GPT::write_params`
+    Params,
+    Safetensors,
+    Gguf,
+    /// Config, tokenizer, weights and optimizer state in a single file
+    Bundle,
+}
+
+impl Format {
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(b"GGUF") {
+            Format::Gguf
+        } else if bytes.starts_with(b"FEMTO") {
+            Format::Bundle
+        } else if bytes.starts_with(b"FGTM") {
+            Format::Params
+        } else if bytes.len() > 8 && bytes[8] == b'{' {
+            // The length of the JSON header, then the header
+            Format::Safetensors
+        } else {
+            Format::State
+        }
+    }
+
+    /// The format of a file, going by its extension
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("safetensors") => Format::Safetensors,
+            Some("gguf") => Format::Gguf,
+            Some(bundle::EXTENSION) => Format::Bundle,
+            _ => Format::State,
+        }
+    }
+}
+
+/// A checkpoint, in whatever format it was stored
+pub struct Checkpoint {
+    pub format: Format,
+    pub tensors: Vec<(String, Tensor<f32>)>,
+    /// Only in training states
+    pub optimizer: Option<AdamW>,
+    pub data_seed: Option<u64>,
+    /// Only in training states and bundles
+    pub provenance: Provenance,
+    /// The config embedded in the metadata, if any
+    pub config: Option<Config>,
+    /// Only in bundles
+    pub tokenizer: Option<Vec<u8>>,
+    /// The run that trained the model, kept in bundles (Or saved next to the file)
+    pub run: Option<RunInfo>,
+}
+
+fn tensor_error(e: TensorError) -> ConfigError {
+    GraphError::from(e).into()
+}
+
+impl Checkpoint {
+    pub fn read(bytes: &[u8]) -> Result<Self, ConfigError> {
+        let format = Format::detect(bytes);
+        let mut checkpoint = Checkpoint {
+            format,
+            tensors: Vec::new(),
+            optimizer: None,
+            data_seed: None,
+            provenance: Provenance::default(),
+            config: None,
+            tokenizer: None,
+            run: None,
+        };
+        match format {
+            Format::State => {
+                let state: TrainingState<AdamW> = bincode::deserialize(bytes)?;
+                let mut tensors = state.tensors.into_iter().collect::<Vec<_>>();
+                tensors.sort_by(|a, b| a.0.cmp(&b.0));
+                checkpoint.tensors = tensors;
+                checkpoint.optimizer = Some(state.optimizer);
+                checkpoint.data_seed = Some(state.data_seed);
+                checkpoint.provenance = state.provenance;
+            }
+            Format::Params => {
+                checkpoint.tensors = read_tensors(bytes).map_err(tensor_error)?;
+            }
+            Format::Safetensors => {
+                let (tensors, metadata) = read_safetensors(bytes).map_err(tensor_error)?;
+                checkpoint.tensors = tensors;
+                if let Some((_, toml)) = metadata.iter().find(|(k, _)| k == SAFETENSORS_CONFIG_KEY)
+                {
+                    checkpoint.config = Some(Config::from_toml(toml)?);
+                }
+            }
+            Format::Bundle => {
+                let bundle = Bundle::from_bytes(bytes)?;
+                checkpoint.tensors = read_tensors(&bundle.weights[..]).map_err(tensor_error)?;
+                if let Some((optimizer, data_seed)) = bundle.optimizer {
+                    checkpoint.optimizer = Some(optimizer);
+                    checkpoint.data_seed = Some(data_seed);
+                }
+                checkpoint.config = Some(bundle.config);
+                checkpoint.tokenizer = Some(bundle.tokenizer);
+                checkpoint.run = bundle.run;
+                checkpoint.provenance = bundle.provenance;
+            }
+            Format::Gguf => {
+                let (tensors, metadata) = read_gguf(bytes).map_err(tensor_error)?;
+                checkpoint.tensors = tensors;
+                let key = format!("{}.config", GGUF_ARCHITECTURE);
+                if let Some((_, GgufValue::String(toml))) = metadata.iter().find(|(k, _)| *k == key)
+                {
+                    checkpoint.config = Some(Config::from_toml(toml)?);
+                }
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    /// Serializes the checkpoint as `format`, with its weights converted to `dtype`.
+    /// `config` is embedded in the formats that have metadata, and bundles need it
+    /// (Along with the tokenizer, unless it's the ASCII one).
+    pub fn write(
+        &self,
+        format: Format,
+        dtype: DType,
+        config: Option<&Config>,
+    ) -> Result<Vec<u8>, ConfigError> {
+        let tensors = self
+            .tensors
+            .iter()
+            .map(|(n, t)| (n.as_str(), t))
+            .collect::<Vec<_>>();
+        if dtype != DType::F32 && matches!(format, Format::State | Format::Params | Format::Bundle)
+        {
+            return Err(ConfigError::Invalid(format!(
+                "{:?} files can only hold f32 weights",
+                format
+            )));
+        }
+        let mut out = Vec::new();
+        match format {
+            Format::State => {
+                let state = TrainingState {
+                    tensors: self.tensors.iter().cloned().collect(),
+                    // Training resumes with a fresh optimizer when its state is missing
+                    optimizer: self
+                        .optimizer
+                        .clone()
+                        .unwrap_or_else(|| config.map_or_else(AdamW::new, |c| c.optimizer.build())),
+                    data_seed: self.data_seed.unwrap_or_else(rand::random),
+                    provenance: self.provenance,
+                };
+                out = bincode::serialize(&state)?;
+            }
+            Format::Params => {
+                femto_gpt::tensor::write_tensors(&mut out, &tensors).map_err(tensor_error)?
+            }
+            Format::Bundle => {
+                let config =
+                    config.ok_or_else(|| ConfigError::Invalid("bundles need a config".into()))?;
+                let tokenizer = match (&self.tokenizer, config.tokenizer.kind) {
+                    (Some(t), _) => t.clone(),
+                    (None, TokenizerKind::Ascii) => Vec::new(),
+                    (None, TokenizerKind::Simple) => {
+                        return Err(ConfigError::Invalid("bundles need a tokenizer".into()))
+                    }
+                };
+                let mut weights = Vec::new();
+                femto_gpt::tensor::write_tensors(&mut weights, &tensors).map_err(tensor_error)?;
+                let bundle = Bundle {
+                    config: config.clone(),
+                    tokenizer,
+                    weights,
+                    optimizer: self
+                        .optimizer
+                        .clone()
+                        .map(|o| (o, self.data_seed.unwrap_or_else(rand::random))),
+                    run: self.run.clone(),
+                    provenance: self.provenance,
+                };
+                out = bundle.to_bytes()?;
+            }
+            Format::Safetensors => {
+                let toml = config.map(|c| c.to_toml());
+                let metadata = toml
+                    .iter()
+                    .map(|t| (SAFETENSORS_CONFIG_KEY, t.as_str()))
+                    .collect::<Vec<_>>();
+                write_safetensors(&mut out, &tensors, dtype, &metadata).map_err(tensor_error)?;
+            }
+            Format::Gguf => {
+                let file_type = match dtype {
+                    DType::F32 => 0,
+                    DType::F16 => 1,
+                    DType::Int8 => 7,
+                };
+                let key = |k: &str| format!("{}.{}", GGUF_ARCHITECTURE, k);
+                let mut metadata = vec![
+                    (
+                        "general.architecture".to_string(),
+                        GgufValue::String(GGUF_ARCHITECTURE.into()),
+                    ),
+                    ("general.file_type".into(), GgufValue::U32(file_type)),
+                ];
+                if let Some(config) = config {
+                    let m = &config.model;
+                    metadata.extend([
+                        (key("context_length"), GgufValue::U32(m.num_tokens as u32)),
+                        (
+                            key("embedding_length"),
+                            GgufValue::U32(m.embedding_degree as u32),
+                        ),
+                        (key("block_count"), GgufValue::U32(m.num_layers as u32)),
+                        (
+                            key("attention.head_count"),
+                            GgufValue::U32(m.num_heads as u32),
+                        ),
+                        (key("config"), GgufValue::String(config.to_toml())),
+                    ]);
+                }
+                let metadata = metadata
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.clone()))
+                    .collect::<Vec<_>>();
+                write_gguf(&mut out, &tensors, dtype, &metadata).map_err(tensor_error)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let config = Config::default();
+        let mut rng = rand::thread_rng();
+        let gpt = config
+            .model
+            .build(&mut rng, 32, config.optimizer.build())
+            .unwrap();
+        let state = bincode::serialize(&gpt.get_training_state().unwrap()).unwrap();
+        let checkpoint = Checkpoint::read(&state).unwrap();
+        assert_eq!(checkpoint.format, Format::State);
+
+        for (format, dtype) in [
+            (Format::Safetensors, DType::F16),
+            (Format::Gguf, DType::Int8),
+            (Format::Params, DType::F32),
+        ] {
+            let bytes = checkpoint.write(format, dtype, Some(&config)).unwrap();
+            assert_eq!(Format::detect(&bytes), format);
+            let converted = Checkpoint::read(&bytes).unwrap();
+            assert!(converted.optimizer.is_none());
+            assert_eq!(converted.config.is_some(), format != Format::Params);
+            let names =
+                |c: &Checkpoint| c.tensors.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+            assert_eq!(names(&converted), names(&checkpoint));
+
+            // Back to a training state, loadable by the model
+            let state = converted.write(Format::State, DType::F32, None).unwrap();
+            let mut loaded = config
+                .model
+                .build(&mut rng, 32, config.optimizer.build())
+                .unwrap();
+            loaded
+                .set_training_state(bincode::deserialize(&state).unwrap(), true)
+                .unwrap();
+        }
+        assert!(checkpoint.write(Format::State, DType::F16, None).is_err());
+
+        // Bundles keep the optimizer state, and need a config and a tokenizer
+        assert!(checkpoint
+            .write(Format::Bundle, DType::F32, Some(&config))
+            .is_err());
+        let mut with_tokenizer = Checkpoint::read(&state).unwrap();
+        with_tokenizer.tokenizer = Some(vec![1, 2, 3]);
+        assert!(with_tokenizer
+            .write(Format::Bundle, DType::F32, None)
+            .is_err());
+        let bytes = with_tokenizer
+            .write(Format::Bundle, DType::F32, Some(&config))
+            .unwrap();
+        assert_eq!(Format::detect(&bytes), Format::Bundle);
+        let bundle = Checkpoint::read(&bytes).unwrap();
+        assert_eq!(bundle.tokenizer.as_deref(), Some(&[1, 2, 3][..]));
+        assert_eq!(bundle.config.as_ref(), Some(&config));
+        assert!(bundle.optimizer.is_some());
+        assert_eq!(bundle.tensors.len(), checkpoint.tensors.len());
+    }
+}
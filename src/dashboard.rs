@@ -0,0 +1,202 @@
+//! Full-screen view of a training run, redrawn after every step, for long runs where
+//! the scrolling log is hard to follow.
+
+use femto_gpt::gpt::TrainContext;
+use femto_gpt::optimizer::Optimizer;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph, Sparkline, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::time::Duration;
+
+/// Steps of loss history kept for the sparkline
+const HISTORY: usize = 512;
+
+#[derive(Debug, Clone, Default)]
+pub struct DashboardState {
+    pub step: usize,
+    pub epoch: usize,
+    pub losses: Vec<f32>,
+    pub learning_rate: f32,
+    /// Top-1 next-token accuracy of the last batch
+    pub accuracy: f32,
+    pub tokens_per_second: f32,
+    pub eta: Option<Duration>,
+    /// Resident memory of the process, in bytes
+    pub memory: Option<u64>,
+    pub sample: String,
+    /// Last notable event (A loss spike, an interruption...)
+    pub status: String,
+}
+
+impl DashboardState {
+    pub fn update<O: Optimizer>(&mut self, ctx: &TrainContext<O>) {
+        self.step = ctx.stats.step;
+        self.epoch = ctx.epoch;
+        self.learning_rate = ctx.stats.learning_rate;
+        self.accuracy = ctx.stats.accuracy.top1_rate();
+        self.tokens_per_second = ctx.stats.tokens_per_second;
+        self.eta = ctx.eta;
+        self.memory = resident_memory();
+        self.losses.push(ctx.stats.loss);
+        if self.losses.len() > HISTORY {
+            self.losses.remove(0);
+        }
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        let [stats, loss, sample, help] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(6),
+            Constraint::Min(6),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let eta = self.eta.map_or("-".into(), |eta| {
+            let m = eta.as_secs() / 60;
+            format!("{}h{:02}m", m / 60, m % 60)
+        });
+        let memory = self
+            .memory
+            .map_or("-".into(), |m| format!("{:.1} MB", m as f64 / 1e6));
+        let line = format!(
+            "Step {}  Epoch {}  LR {:.2e}  Accuracy {:.1}%  {:.0} tokens/s  ETA {}  Memory {}",
+            self.step,
+            self.epoch,
+            self.learning_rate,
+            self.accuracy * 100.,
+            self.tokens_per_second,
+            eta,
+            memory
+        );
+        frame.render_widget(
+            Paragraph::new(line).block(Block::default().borders(Borders::ALL).title("femtoGPT")),
+            stats,
+        );
+
+        // Only the most recent losses fit, one per column
+        let width = loss.width.saturating_sub(2) as usize;
+        let recent = &self.losses[self.losses.len().saturating_sub(width)..];
+        let (min, max) = recent
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(a, b), l| {
+                (a.min(*l), b.max(*l))
+            });
+        // Relative to the lowest loss shown, so that small improvements stay visible
+        let data = recent
+            .iter()
+            .map(|l| ((l - min) / (max - min).max(1e-6) * 100.) as u64 + 1)
+            .collect::<Vec<_>>();
+        let title = match self.losses.last() {
+            Some(last) => format!("Loss {:.4} (Range {:.4} - {:.4})", last, min, max),
+            None => "Loss".into(),
+        };
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .data(&data)
+                .style(Style::default().fg(Color::Cyan)),
+            loss,
+        );
+
+        frame.render_widget(
+            Paragraph::new(self.sample.as_str())
+                .wrap(Wrap { trim: false })
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Latest sample"),
+                ),
+            sample,
+        );
+        frame.render_widget(
+            Line::from(format!("q: stop and save  {}", self.status)),
+            help,
+        );
+    }
+}
+
+/// Owns the terminal while training, and gives it back when dropped
+pub struct Dashboard {
+    terminal: DefaultTerminal,
+    pub state: DashboardState,
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self {
+            terminal: ratatui::init(),
+            state: DashboardState::default(),
+        }
+    }
+
+    pub fn draw(&mut self) -> std::io::Result<()> {
+        let state = &self.state;
+        self.terminal.draw(|frame| state.render(frame))?;
+        Ok(())
+    }
+
+    /// Whether the user asked to stop. (The terminal is in raw mode, so Ctrl-C comes
+    /// as a key press instead of a signal)
+    pub fn stop_requested(&mut self) -> std::io::Result<bool> {
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || ctrl_c) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+/// Resident set size of the process (Only known on Linux)
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    #[test]
+    fn test_render() {
+        let state = DashboardState {
+            step: 42,
+            losses: vec![4.2, 3.9, 3.1],
+            learning_rate: 1e-3,
+            eta: Some(Duration::from_secs(3720)),
+            sample: "ROMEO: Hi.".into(),
+            ..Default::default()
+        };
+        let mut terminal = Terminal::new(TestBackend::new(80, 20)).unwrap();
+        terminal.draw(|frame| state.render(frame)).unwrap();
+        let screen = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|c| c.symbol())
+            .collect::<String>();
+        assert!(screen.contains("Step 42"));
+        assert!(screen.contains("ETA 1h02m"));
+        assert!(screen.contains("Loss 3.1000"));
+        assert!(screen.contains("ROMEO: Hi."));
+    }
+}
@@ -0,0 +1,363 @@
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+
+/// A training window drawn from a `Dataset`
+#[derive(Debug, Clone)]
+pub struct Window {
+    /// `context_size + 1` consecutive tokens, or fewer (At least 2) for a sequence
+    /// shorter than the context, which is right-padded and whose padding is left out of
+    /// the loss. (The causal mask already keeps the tokens from attending to it)
+    pub tokens: Vec<usize>,
+    /// Index of the source the window was drawn from
+    pub source: usize,
+    /// Index of the document each token belongs to, when several documents are
+    /// packed into the same window. Tokens may only attend to their own document.
+    pub documents: Option<Vec<usize>>,
+}
+
+pub trait Dataset: Sync {
+    /// Total number of tokens
+    fn num_tokens(&self) -> usize;
+    /// Number of underlying sources the windows may be drawn from
+    fn num_sources(&self) -> usize {
+        1
+    }
+    /// Number of distinct windows of `context_size + 1` tokens `sample` draws from,
+    /// one per token by default (For the privacy accountant of DP-SGD)
+    fn num_windows(&self, _context_size: usize) -> usize {
+        self.num_tokens()
+    }
+    /// Draws a window of `context_size + 1` consecutive tokens (Or fewer, see
+    /// `Window::tokens`)
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window;
+}
+
+impl Dataset for [usize] {
+    fn num_tokens(&self) -> usize {
+        self.len()
+    }
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window {
+        let start: usize = rng.gen_range(0..self.len());
+        let tokens = self
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(context_size + 1)
+            .cloned()
+            .collect::<Vec<_>>();
+        Window {
+            tokens,
+            source: 0,
+            documents: None,
+        }
+    }
+}
+
+impl Dataset for Vec<usize> {
+    fn num_tokens(&self) -> usize {
+        self.as_slice().num_tokens()
+    }
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window {
+        self.as_slice().sample(rng, context_size)
+    }
+}
+
+/// A tokenized dataset whose windows start at multiples of `stride` (Instead of
+/// anywhere): consecutive windows overlap by `context_size + 1 - stride` tokens, or
+/// are disjoint when `stride` is the context size, so that an epoch covers the dataset
+/// with as few windows as wanted.
+pub struct Strided<'a> {
+    tokens: &'a [usize],
+    stride: usize,
+}
+
+impl<'a> Strided<'a> {
+    pub fn new(tokens: &'a [usize], stride: usize) -> Option<Self> {
+        if tokens.is_empty() || stride == 0 {
+            return None;
+        }
+        Some(Self { tokens, stride })
+    }
+}
+
+impl Dataset for Strided<'_> {
+    fn num_tokens(&self) -> usize {
+        self.tokens.len()
+    }
+    fn num_windows(&self, context_size: usize) -> usize {
+        self.tokens.len().saturating_sub(context_size + 1) / self.stride + 1
+    }
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window {
+        let start = rng.gen_range(0..self.num_windows(context_size)) * self.stride;
+        // Wraps around like the windows of a plain dataset, if it's too short
+        let tokens = self
+            .tokens
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(context_size + 1)
+            .cloned()
+            .collect::<Vec<_>>();
+        Window {
+            tokens,
+            source: 0,
+            documents: None,
+        }
+    }
+}
+
+/// Several tokenized datasets, each window being drawn from one of them with a
+/// probability proportional to its weight. (E.g. 80% corpus A, 20% corpus B)
+pub struct Mixture<'a> {
+    datasets: Vec<&'a [usize]>,
+    weights: Vec<f32>,
+    dist: WeightedIndex<f32>,
+}
+
+impl<'a> Mixture<'a> {
+    pub fn new(sources: &[(&'a [usize], f32)]) -> Option<Self> {
+        if sources.iter().any(|(d, _)| d.is_empty()) {
+            return None;
+        }
+        let weights = sources.iter().map(|(_, w)| *w).collect::<Vec<_>>();
+        Some(Self {
+            datasets: sources.iter().map(|(d, _)| *d).collect(),
+            dist: WeightedIndex::new(&weights).ok()?,
+            weights,
+        })
+    }
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+}
+
+impl Dataset for Mixture<'_> {
+    fn num_tokens(&self) -> usize {
+        self.datasets.iter().map(|d| d.len()).sum()
+    }
+    fn num_sources(&self) -> usize {
+        self.datasets.len()
+    }
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window {
+        let source = self.dist.sample(rng);
+        Window {
+            source,
+            ..self.datasets[source].sample(rng, context_size)
+        }
+    }
+}
+
+/// Many short documents, packed into each window one after another (In random
+/// order) and separated by an `eos` token, instead of wasting most of a window on
+/// a single document. Attention across document boundaries is masked.
+pub struct Packed<'a> {
+    documents: Vec<&'a [usize]>,
+    eos: usize,
+}
+
+impl<'a> Packed<'a> {
+    pub fn new(documents: Vec<&'a [usize]>, eos: usize) -> Option<Self> {
+        if documents.is_empty() {
+            return None;
+        }
+        Some(Self { documents, eos })
+    }
+}
+
+impl Dataset for Packed<'_> {
+    fn num_tokens(&self) -> usize {
+        self.documents.iter().map(|d| d.len() + 1).sum()
+    }
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window {
+        let mut tokens = Vec::with_capacity(context_size + 1);
+        let mut documents = Vec::with_capacity(context_size + 1);
+        // Start in the middle of a document, so that its tail gets trained on as well
+        let first = self.documents[rng.gen_range(0..self.documents.len())];
+        let mut doc = &first[rng.gen_range(0..first.len() + 1)..];
+        let mut doc_id = 0;
+        while tokens.len() < context_size + 1 {
+            // The separator belongs to the document it terminates
+            for &t in doc.iter().chain(std::iter::once(&self.eos)) {
+                if tokens.len() == context_size + 1 {
+                    break;
+                }
+                tokens.push(t);
+                documents.push(doc_id);
+            }
+            doc = self.documents[rng.gen_range(0..self.documents.len())];
+            doc_id += 1;
+        }
+        Window {
+            tokens,
+            source: 0,
+            documents: Some(documents),
+        }
+    }
+}
+
+/// Documents trained on one per window, instead of being packed together: a window
+/// holds the start of a document, or where the previous window of a longer one left
+/// off, and is right-padded when the document ends before the context does. (E.g. the
+/// examples of an instruction dataset, which shouldn't be mixed with one another)
+pub struct Padded<'a> {
+    documents: Vec<&'a [usize]>,
+}
+
+impl<'a> Padded<'a> {
+    /// `documents` of fewer than 2 tokens, having nothing to predict, are left out
+    pub fn new(documents: Vec<&'a [usize]>) -> Option<Self> {
+        let documents = documents
+            .into_iter()
+            .filter(|d| d.len() >= 2)
+            .collect::<Vec<_>>();
+        if documents.is_empty() {
+            return None;
+        }
+        Some(Self { documents })
+    }
+}
+
+/// Windows a document of `len` tokens is cut into, consecutive ones sharing a token
+fn padded_windows(len: usize, context_size: usize) -> usize {
+    (len - 1).div_ceil(context_size.max(1))
+}
+
+impl Dataset for Padded<'_> {
+    fn num_tokens(&self) -> usize {
+        self.documents.iter().map(|d| d.len()).sum()
+    }
+    fn num_windows(&self, context_size: usize) -> usize {
+        self.documents
+            .iter()
+            .map(|d| padded_windows(d.len(), context_size))
+            .sum()
+    }
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window {
+        let context_size = context_size.max(1);
+        let mut index = rng.gen_range(0..self.num_windows(context_size));
+        for doc in self.documents.iter() {
+            let count = padded_windows(doc.len(), context_size);
+            if index < count {
+                let start = index * context_size;
+                return Window {
+                    tokens: doc[start..(start + context_size + 1).min(doc.len())].to_vec(),
+                    source: 0,
+                    documents: None,
+                };
+            }
+            index -= count;
+        }
+        unreachable!("the window is in one of the documents")
+    }
+}
+
+/// Loss weight of each of the `vocab_size` tokens, inversely proportional to its
+/// frequency in `tokens` and normalized so that an average token weighs 1: whitespace
+/// and common characters weigh less, rare ones more. (Tokens that never occur weigh 1)
+pub fn inverse_frequencies(tokens: &[usize], vocab_size: usize) -> Vec<f32> {
+    let mut counts = vec![0usize; vocab_size];
+    for &t in tokens {
+        if let Some(count) = counts.get_mut(t) {
+            *count += 1;
+        }
+    }
+    let seen = counts.iter().filter(|c| **c > 0).count();
+    let total = counts.iter().sum::<usize>();
+    counts
+        .iter()
+        .map(|&c| {
+            if c == 0 {
+                1.
+            } else {
+                total as f32 / (seen * c) as f32
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mixture_weights() {
+        let a = vec![0; 100];
+        let b = vec![1; 100];
+        let mix = Mixture::new(&[(&a, 0.8), (&b, 0.2)]).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut counts = [0; 2];
+        for _ in 0..10000 {
+            let window = mix.sample(&mut rng, 4);
+            assert!(window.tokens.iter().all(|t| *t == window.source));
+            counts[window.source] += 1;
+        }
+        let ratio = counts[0] as f32 / 10000.;
+        assert!(ratio > 0.75 && ratio < 0.85);
+    }
+
+    #[test]
+    fn test_strided() {
+        let tokens = (0..20).collect::<Vec<_>>();
+        assert!(Strided::new(&tokens, 0).is_none());
+        let disjoint = Strided::new(&tokens, 4).unwrap();
+        assert_eq!(disjoint.num_windows(4), 4);
+        let overlapping = Strided::new(&tokens, 2).unwrap();
+        assert_eq!(overlapping.num_windows(4), 8);
+        let mut rng = rand::thread_rng();
+        let mut starts = std::collections::BTreeSet::new();
+        for _ in 0..1000 {
+            let window = disjoint.sample(&mut rng, 4);
+            assert_eq!(window.tokens.len(), 5);
+            assert!(window.tokens.windows(2).all(|w| w[1] == w[0] + 1));
+            starts.insert(window.tokens[0]);
+        }
+        assert_eq!(starts.into_iter().collect::<Vec<_>>(), [0, 4, 8, 12]);
+        // Shorter than a window
+        let short = Strided::new(&tokens[..3], 4).unwrap();
+        assert_eq!(short.sample(&mut rng, 4).tokens, [0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_inverse_frequencies() {
+        let weights = inverse_frequencies(&[0, 0, 0, 1, 2, 2], 4);
+        assert_eq!(weights, [2. / 3., 2., 1., 1.]);
+        // An average token of the dataset weighs 1
+        let mean = [0, 0, 0, 1, 2, 2].iter().map(|t| weights[*t]).sum::<f32>() / 6.;
+        assert!((mean - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_padded() {
+        let (a, b, c) = (vec![1, 2, 3], (4..11).collect::<Vec<_>>(), vec![11]);
+        assert!(Padded::new(vec![&c]).is_none());
+        let padded = Padded::new(vec![&a, &b, &c]).unwrap();
+        assert_eq!(padded.num_tokens(), 10);
+        assert_eq!(padded.num_windows(4), 3);
+        assert_eq!(padded.num_windows(2), 4);
+        let mut rng = rand::thread_rng();
+        let windows = (0..1000)
+            .map(|_| padded.sample(&mut rng, 4).tokens)
+            .collect::<std::collections::BTreeSet<_>>();
+        let expected = [vec![1, 2, 3], vec![4, 5, 6, 7, 8], vec![8, 9, 10]];
+        assert_eq!(windows, expected.into_iter().collect());
+    }
+
+    #[test]
+    fn test_packed_documents() {
+        let a = vec![1, 1, 1];
+        let b = vec![2, 2];
+        let packed = Packed::new(vec![&a, &b], 0).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let window = packed.sample(&mut rng, 16);
+            let documents = window.documents.unwrap();
+            assert_eq!(window.tokens.len(), 17);
+            assert_eq!(documents.len(), 17);
+            for i in 1..17 {
+                // A new document only starts right after a separator
+                let new_doc = documents[i] != documents[i - 1];
+                assert_eq!(new_doc, window.tokens[i - 1] == 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Comparing the parameters of two checkpoints of a model, tensor by tensor and block
+//! by block: what fine-tuning (Or merging a LoRA) changed most, and which parameters
+//! it didn't change at all (E.g. accidentally frozen ones).
+
+use crate::tensor::{Tensor, TensorError, TensorOps};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Part of the model a parameter belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Layer {
+    Embeddings,
+    Block(usize),
+    /// The output layer, and whatever else is outside of the blocks
+    Output,
+}
+
+impl Layer {
+    /// The layer of the parameter named `name`, blocks having their index in the names
+    /// of their parameters (E.g. `proj_2_bias`)
+    pub fn of(name: &str) -> Self {
+        match name.split('_').find_map(|part| part.parse::<usize>().ok()) {
+            Some(l) => Layer::Block(l),
+            None if name.ends_with("embedding") => Layer::Embeddings,
+            None => Layer::Output,
+        }
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::Embeddings => write!(f, "embeddings"),
+            Layer::Block(l) => write!(f, "block {}", l),
+            Layer::Output => write!(f, "output"),
+        }
+    }
+}
+
+/// How a tensor (Or all those of a layer) changed from one checkpoint to the other
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Change {
+    pub num_params: usize,
+    /// Norm of the difference
+    pub l2_distance: f32,
+    /// Distance over the norm in the first checkpoint
+    pub relative_change: f32,
+    /// Cosine similarity of the two, flattened
+    pub cosine_similarity: f32,
+    pub max_abs_change: f32,
+}
+
+impl Change {
+    pub fn is_unchanged(&self) -> bool {
+        self.l2_distance == 0.
+    }
+}
+
+/// Sums the `Change`s are computed from, in `f64` for large tensors
+#[derive(Debug, Clone, Copy, Default)]
+struct Sums {
+    num_params: usize,
+    diff: f64,
+    a: f64,
+    b: f64,
+    dot: f64,
+    max_abs: f32,
+}
+
+impl Sums {
+    fn new(a: &[f32], b: &[f32]) -> Self {
+        let mut sums = Sums {
+            num_params: a.len(),
+            ..Default::default()
+        };
+        for (x, y) in a.iter().zip(b) {
+            let (x64, y64) = (*x as f64, *y as f64);
+            sums.diff += (x64 - y64) * (x64 - y64);
+            sums.a += x64 * x64;
+            sums.b += y64 * y64;
+            sums.dot += x64 * y64;
+            sums.max_abs = sums.max_abs.max((x - y).abs());
+        }
+        sums
+    }
+
+    fn add(&mut self, other: &Sums) {
+        self.num_params += other.num_params;
+        self.diff += other.diff;
+        self.a += other.a;
+        self.b += other.b;
+        self.dot += other.dot;
+        self.max_abs = self.max_abs.max(other.max_abs);
+    }
+
+    fn change(&self) -> Change {
+        let l2_distance = self.diff.sqrt();
+        let norms = self.a.sqrt() * self.b.sqrt();
+        Change {
+            num_params: self.num_params,
+            l2_distance: l2_distance as f32,
+            relative_change: match self.a {
+                0. if l2_distance == 0. => 0.,
+                0. => f32::INFINITY,
+                a => (l2_distance / a.sqrt()) as f32,
+            },
+            // Two zero tensors are alike
+            cosine_similarity: if norms > 0. {
+                (self.dot / norms) as f32
+            } else if self.diff == 0. {
+                1.
+            } else {
+                0.
+            },
+            max_abs_change: self.max_abs,
+        }
+    }
+}
+
+/// The differences between the parameters of two checkpoints
+#[derive(Debug, Clone, Default)]
+pub struct Diff {
+    /// By name
+    pub tensors: Vec<(String, Change)>,
+    /// In the order of the model
+    pub layers: Vec<(Layer, Change)>,
+    /// Parameters only in the first checkpoint
+    pub removed: Vec<String>,
+    /// Parameters only in the second checkpoint
+    pub added: Vec<String>,
+}
+
+impl Diff {
+    /// Compares the tensors of `a` and `b` with the same names, which should have the
+    /// same shapes
+    pub fn new(
+        a: &HashMap<String, Tensor<f32>>,
+        b: &HashMap<String, Tensor<f32>>,
+    ) -> Result<Self, TensorError> {
+        let mut diff = Diff::default();
+        let mut layers = HashMap::<Layer, Sums>::new();
+        for (name, ta) in a.iter() {
+            let Some(tb) = b.get(name) else {
+                diff.removed.push(name.clone());
+                continue;
+            };
+            if ta.shape() != tb.shape() {
+                return Err(TensorError::shape_mismatch("diff", ta.shape(), tb.shape()));
+            }
+            let sums = Sums::new(ta.blob(), tb.blob());
+            layers.entry(Layer::of(name)).or_default().add(&sums);
+            diff.tensors.push((name.clone(), sums.change()));
+        }
+        diff.added = b.keys().filter(|k| !a.contains_key(*k)).cloned().collect();
+        diff.tensors.sort_by(|x, y| x.0.cmp(&y.0));
+        diff.removed.sort();
+        diff.added.sort();
+        diff.layers = layers.into_iter().map(|(l, s)| (l, s.change())).collect();
+        diff.layers.sort_by_key(|(l, _)| *l);
+        Ok(diff)
+    }
+
+    /// The `n` tensors of the largest relative changes, largest first
+    pub fn most_changed(&self, n: usize) -> Vec<&(String, Change)> {
+        let mut tensors = self.tensors.iter().collect::<Vec<_>>();
+        tensors.sort_by(|x, y| y.1.relative_change.total_cmp(&x.1.relative_change));
+        tensors.truncate(n);
+        tensors
+    }
+
+    /// The tensors that are the same in both checkpoints
+    pub fn unchanged(&self) -> impl Iterator<Item = &str> {
+        self.tensors
+            .iter()
+            .filter(|(_, c)| c.is_unchanged())
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(Layer::of("head_1_0_k"), Layer::Block(1));
+        assert_eq!(Layer::of("token_embedding"), Layer::Embeddings);
+        assert_eq!(Layer::of("head_map_weights"), Layer::Output);
+
+        let tensors = |pairs: &[(&str, Tensor<f32>)]| {
+            pairs
+                .iter()
+                .map(|(n, t)| (n.to_string(), t.clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        let a = tensors(&[
+            ("token_embedding", Tensor::vector(&[1., 0.])),
+            ("proj_0_weights", Tensor::vector(&[3., 4.])),
+            ("proj_0_bias", Tensor::vector(&[0., 0.])),
+            ("norm_1_coeff", Tensor::vector(&[1., 1.])),
+        ]);
+        let b = tensors(&[
+            ("token_embedding", Tensor::vector(&[0., 1.])),
+            ("proj_0_weights", Tensor::vector(&[3., 4.])),
+            ("proj_0_bias", Tensor::vector(&[0., 0.])),
+            ("head_map_bias", Tensor::vector(&[0.])),
+        ]);
+        let diff = Diff::new(&a, &b).unwrap();
+        assert_eq!(diff.removed, ["norm_1_coeff"]);
+        assert_eq!(diff.added, ["head_map_bias"]);
+        assert_eq!(
+            diff.unchanged().collect::<Vec<_>>(),
+            ["proj_0_bias", "proj_0_weights"]
+        );
+        let (name, change) = diff.most_changed(1)[0];
+        assert_eq!(name, "token_embedding");
+        assert_eq!(change.cosine_similarity, 0.);
+        assert!((change.l2_distance - 2f32.sqrt()).abs() < 1e-6);
+        assert_eq!(change.max_abs_change, 1.);
+        assert_eq!(
+            diff.layers.iter().map(|(l, _)| *l).collect::<Vec<_>>(),
+            [Layer::Embeddings, Layer::Block(0)]
+        );
+        assert_eq!(diff.layers[1].1.num_params, 4);
+        assert_eq!(diff.layers[1].1.cosine_similarity, 1.);
+
+        let b = tensors(&[("proj_0_weights", Tensor::vector(&[3.]))]);
+        assert!(Diff::new(&a, &b).is_err());
+    }
+}
@@ -0,0 +1,534 @@
+//! Training on several machines, the gradients of each step being summed over them
+//! through plain TCP.
+//!
+//! On slow networks, the gradients can be sent as their signs (One bit per value,
+//! plus a scale per tensor) instead of as floats, cutting the traffic by about 30x.
+//! What the signs fail to carry is kept by each node, and added to its gradients of
+//! the next step (Error feedback), so that nothing gets lost on the way.
+//!
+//! Workers introduce themselves with the version of the protocol and the number of
+//! parameters of their model, which have to match the coordinator's, and no message
+//! may be larger than what the training state of such a model takes.
+
+use crate::gpt::TokenAccuracy;
+use crate::tensor::{Tensor, TensorError, TensorOps};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// Version of the messages exchanged, bumped whenever they change
+const PROTOCOL_VERSION: u32 = 1;
+/// Largest message of the handshake
+const MAX_HANDSHAKE: usize = 1 << 10;
+
+/// What a worker first sends to the coordinator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Hello {
+    version: u32,
+    num_params: usize,
+}
+
+/// Contribution of a node to a training step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gradients {
+    /// Gradients summed over the samples of the node (In the order of the parameters)
+    pub grads: Vec<Tensor<f32>>,
+    /// Sum of the losses of the samples
+    pub loss: f32,
+    pub samples: usize,
+    /// Predictions of the next tokens of the samples
+    pub accuracy: TokenAccuracy,
+}
+
+/// A tensor compressed to the signs of its values, all of them having the magnitude
+/// `scale` (The mean of their absolute values)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignTensor {
+    pub shape: Vec<usize>,
+    pub scale: f32,
+    /// One bit per value, set if negative
+    pub signs: Vec<u8>,
+}
+
+impl SignTensor {
+    /// Compresses `grad` plus `residual`, leaving in `residual` what got lost
+    pub fn compress(grad: &Tensor<f32>, residual: &mut Vec<f32>) -> Self {
+        residual.resize(grad.size(), 0.);
+        for (r, g) in residual.iter_mut().zip(grad.blob()) {
+            *r += g;
+        }
+        let scale = residual.iter().map(|r| r.abs()).sum::<f32>() / residual.len().max(1) as f32;
+        let mut signs = vec![0u8; residual.len().div_ceil(8)];
+        for (i, r) in residual.iter_mut().enumerate() {
+            if r.is_sign_negative() {
+                signs[i / 8] |= 1 << (i % 8);
+                *r += scale;
+            } else {
+                *r -= scale;
+            }
+        }
+        // Overflowed gradients (See `LossScaler`) are skipped, and mustn't poison
+        // the next ones
+        if !scale.is_finite() {
+            residual.fill(0.);
+        }
+        Self {
+            shape: grad.shape().to_vec(),
+            scale,
+            signs,
+        }
+    }
+
+    /// The tensor, which should be of a parameter of `size` values. (The shape and
+    /// the signs come from the network, and aren't trusted)
+    pub fn decompress(&self, size: usize) -> Result<Tensor<f32>, TensorError> {
+        let len = self
+            .shape
+            .iter()
+            .try_fold(1usize, |len, d| len.checked_mul(*d));
+        if len != Some(size) {
+            return Err(TensorError::unexpected_shape("SignTensor", &self.shape));
+        }
+        if self.signs.len() != size.div_ceil(8) {
+            return Err(TensorError::shape_mismatch(
+                "SignTensor",
+                &[size.div_ceil(8)],
+                &[self.signs.len()],
+            ));
+        }
+        let blob = (0..size)
+            .map(|i| match self.signs[i / 8] & (1 << (i % 8)) {
+                0 => self.scale,
+                _ => -self.scale,
+            })
+            .collect();
+        Tensor::raw(&self.shape, blob)
+    }
+}
+
+/// Gradients as sent over the network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Message {
+    Dense(Gradients),
+    Signs {
+        grads: Vec<SignTensor>,
+        loss: f32,
+        samples: usize,
+        accuracy: TokenAccuracy,
+    },
+}
+
+impl Message {
+    /// The gradients, of parameters of `sizes` values
+    fn into_gradients(self, sizes: &[usize]) -> io::Result<Gradients> {
+        match self {
+            Message::Dense(grads) => Ok(grads),
+            Message::Signs { grads, .. } if grads.len() != sizes.len() => Err(io::Error::other(
+                TensorError::shape_mismatch("all_reduce", &[sizes.len()], &[grads.len()]),
+            )),
+            Message::Signs {
+                grads,
+                loss,
+                samples,
+                accuracy,
+            } => Ok(Gradients {
+                grads: grads
+                    .iter()
+                    .zip(sizes)
+                    .map(|(g, size)| g.decompress(*size))
+                    .collect::<Result<_, _>>()
+                    .map_err(io::Error::other)?,
+                loss,
+                samples,
+                accuracy,
+            }),
+        }
+    }
+}
+
+/// Sends `local` as is, or compressed if there are `residuals` to keep what gets lost
+fn encode(local: Gradients, residuals: Option<&mut Vec<Vec<f32>>>) -> Message {
+    match residuals {
+        None => Message::Dense(local),
+        Some(residuals) => {
+            residuals.resize(local.grads.len(), Vec::new());
+            Message::Signs {
+                grads: local
+                    .grads
+                    .iter()
+                    .zip(residuals.iter_mut())
+                    .map(|(g, r)| SignTensor::compress(g, r))
+                    .collect(),
+                loss: local.loss,
+                samples: local.samples,
+                accuracy: local.accuracy,
+            }
+        }
+    }
+}
+
+enum Role {
+    Coordinator { workers: Vec<TcpStream> },
+    Worker { coordinator: TcpStream, rank: usize },
+}
+
+/// A group of machines training the same model, each on its own batches. After
+/// each step, the coordinator sums the gradients of all the nodes and sends the
+/// result back, so that every node applies the very same update.
+pub struct Cluster {
+    role: Role,
+    num_params: usize,
+    /// What the signs failed to carry, per parameter, if compressing
+    residuals: Option<Vec<Vec<f32>>>,
+}
+
+fn send<T: Serialize>(stream: &mut TcpStream, msg: &T) -> io::Result<()> {
+    let bytes = bincode::serialize(msg).map_err(io::Error::other)?;
+    stream.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+/// Receives a message of at most `max_len` bytes
+fn recv<T: DeserializeOwned>(stream: &mut TcpStream, max_len: usize) -> io::Result<T> {
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > max_len as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message of {} bytes, over the limit of {}", len, max_len),
+        ));
+    }
+    let mut bytes = vec![0u8; len as usize];
+    stream.read_exact(&mut bytes)?;
+    bincode::deserialize(&bytes).map_err(io::Error::other)
+}
+
+impl Cluster {
+    /// Listens on `addr` and waits for `num_workers` workers of a model of
+    /// `num_params` parameters to join
+    pub fn coordinate<A: ToSocketAddrs>(
+        addr: A,
+        num_workers: usize,
+        num_params: usize,
+    ) -> io::Result<Self> {
+        Self::coordinate_on(TcpListener::bind(addr)?, num_workers, num_params)
+    }
+
+    /// As `coordinate`, on a listener already bound (E.g. to a port chosen by the
+    /// system, whose address has to be known before the workers can join)
+    pub fn coordinate_on(
+        listener: TcpListener,
+        num_workers: usize,
+        num_params: usize,
+    ) -> io::Result<Self> {
+        let mut workers = Vec::with_capacity(num_workers);
+        for rank in 1..=num_workers {
+            let (mut stream, peer) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            let hello = recv::<Hello>(&mut stream, MAX_HANDSHAKE)?;
+            let welcome = if hello.version != PROTOCOL_VERSION {
+                Err(format!(
+                    "Protocol version {} of {} doesn't match the coordinator's {}",
+                    hello.version, peer, PROTOCOL_VERSION
+                ))
+            } else if hello.num_params != num_params {
+                Err(format!(
+                    "Model of {} parameters on {}, the coordinator's having {}",
+                    hello.num_params, peer, num_params
+                ))
+            } else {
+                Ok(rank)
+            };
+            send(&mut stream, &welcome)?;
+            welcome.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            workers.push(stream);
+        }
+        Ok(Self {
+            role: Role::Coordinator { workers },
+            num_params,
+            residuals: None,
+        })
+    }
+
+    /// Joins the cluster coordinated at `addr`, with a model of `num_params` parameters
+    pub fn join<A: ToSocketAddrs>(addr: A, num_params: usize) -> io::Result<Self> {
+        let mut coordinator = TcpStream::connect(addr)?;
+        coordinator.set_nodelay(true)?;
+        send(
+            &mut coordinator,
+            &Hello {
+                version: PROTOCOL_VERSION,
+                num_params,
+            },
+        )?;
+        let rank = recv::<Result<usize, String>>(&mut coordinator, MAX_HANDSHAKE)?
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            role: Role::Worker { coordinator, rank },
+            num_params,
+            residuals: None,
+        })
+    }
+
+    /// Number of parameters of the model trained by the cluster
+    pub fn num_params(&self) -> usize {
+        self.num_params
+    }
+
+    /// Largest message the nodes exchange: the training state, with room for four
+    /// floats per parameter (The weights and the state of the optimizer) and metadata
+    fn max_message(&self) -> usize {
+        self.num_params.saturating_mul(16).saturating_add(1 << 20)
+    }
+
+    pub fn is_coordinator(&self) -> bool {
+        matches!(self.role, Role::Coordinator { .. })
+    }
+
+    /// Index of the machine in the cluster, the coordinator being 0
+    pub fn rank(&self) -> usize {
+        match &self.role {
+            Role::Coordinator { .. } => 0,
+            Role::Worker { rank, .. } => *rank,
+        }
+    }
+
+    /// Sends gradients as their signs rather than as floats: a worker compresses what
+    /// it sends to the coordinator, and the coordinator the totals it sends back, so
+    /// the nodes don't need to agree on it
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.residuals = enabled.then(Vec::new);
+    }
+
+    pub fn is_compressing(&self) -> bool {
+        self.residuals.is_some()
+    }
+
+    /// The coordinator sends `value` to every worker, and the workers get it back
+    pub fn broadcast<T: Serialize + DeserializeOwned>(&mut self, value: T) -> io::Result<T> {
+        let max_len = self.max_message();
+        match &mut self.role {
+            Role::Coordinator { workers } => {
+                for w in workers.iter_mut() {
+                    send(w, &value)?;
+                }
+                Ok(value)
+            }
+            Role::Worker { coordinator, .. } => recv(coordinator, max_len),
+        }
+    }
+
+    /// Sums the contributions of all the nodes, every node getting the same total
+    pub fn all_reduce(&mut self, local: Gradients) -> io::Result<Gradients> {
+        let max_len = self.max_message();
+        let sizes = local.grads.iter().map(|g| g.size()).collect::<Vec<_>>();
+        match &mut self.role {
+            Role::Coordinator { workers } => {
+                let mut total = local;
+                for w in workers.iter_mut() {
+                    let other = recv::<Message>(w, max_len)?.into_gradients(&sizes)?;
+                    if other.grads.len() != total.grads.len() {
+                        return Err(io::Error::other(TensorError::shape_mismatch(
+                            "all_reduce",
+                            &[total.grads.len()],
+                            &[other.grads.len()],
+                        )));
+                    }
+                    total.grads = total
+                        .grads
+                        .iter()
+                        .zip(other.grads.iter())
+                        .map(|(a, b)| a + b)
+                        .collect::<Result<Vec<_>, TensorError>>()
+                        .map_err(io::Error::other)?;
+                    total.loss += other.loss;
+                    total.samples += other.samples;
+                    total.accuracy.merge(&other.accuracy);
+                }
+                let msg = encode(total, self.residuals.as_mut());
+                for w in workers.iter_mut() {
+                    send(w, &msg)?;
+                }
+                // Workers get the signs, so that's what the coordinator applies too
+                msg.into_gradients(&sizes)
+            }
+            Role::Worker { coordinator, .. } => {
+                send(coordinator, &encode(local, self.residuals.as_mut()))?;
+                recv::<Message>(coordinator, max_len)?.into_gradients(&sizes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_reduce() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let worker = std::thread::spawn(move || {
+            let mut cluster = Cluster::join(addr, 2).unwrap();
+            assert_eq!(cluster.rank(), 1);
+            assert_eq!(cluster.broadcast(0usize).unwrap(), 7);
+            cluster
+                .all_reduce(Gradients {
+                    grads: vec![Tensor::vector(&[1., 2.])],
+                    loss: 1.,
+                    samples: 1,
+                    accuracy: TokenAccuracy {
+                        tokens: 4,
+                        top1: 1,
+                        top5: 3,
+                    },
+                })
+                .unwrap()
+        });
+        let mut cluster = Cluster::coordinate_on(listener, 1, 2).unwrap();
+        assert_eq!(cluster.rank(), 0);
+        assert_eq!(cluster.broadcast(7usize).unwrap(), 7);
+        let total = cluster
+            .all_reduce(Gradients {
+                grads: vec![Tensor::vector(&[3., 4.])],
+                loss: 2.,
+                samples: 2,
+                accuracy: TokenAccuracy {
+                    tokens: 8,
+                    top1: 2,
+                    top5: 4,
+                },
+            })
+            .unwrap();
+        let other = worker.join().unwrap();
+        for t in [total, other] {
+            assert_eq!(t.grads[0].blob(), &[4., 6.]);
+            assert_eq!(t.loss, 3.);
+            assert_eq!(t.samples, 3);
+            assert_eq!((t.accuracy.top1, t.accuracy.top5_rate()), (3, 7. / 12.));
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        // Models of different sizes
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let worker = std::thread::spawn(move || Cluster::join(addr, 3).map(|_| ()));
+        let err = Cluster::coordinate_on(listener, 1, 2).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            worker.join().unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Messages over the limit are refused before being read
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let worker = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&u64::MAX.to_le_bytes()).unwrap();
+            let _ = stream.read(&mut [0]);
+        });
+        let err = Cluster::coordinate_on(listener, 1, 2).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        worker.join().unwrap();
+
+        // Past the handshake, the limit is set by the size of the model
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let worker = std::thread::spawn(move || {
+            let mut cluster = Cluster::join(addr, 2).unwrap();
+            assert_eq!(cluster.num_params(), 2);
+            cluster.broadcast(Vec::<u8>::new()).unwrap_err().kind()
+        });
+        let mut cluster = Cluster::coordinate_on(listener, 1, 2).unwrap();
+        let big = vec![0u8; cluster.max_message()];
+        // Fails once the worker hangs up
+        let _ = cluster.broadcast(big);
+        assert_eq!(worker.join().unwrap(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_sign_compression() {
+        let grads = [
+            Tensor::vector(&[1., -2., 0.5]),
+            Tensor::vector(&[0.25, 1., -1.]),
+        ];
+        let mut residual = Vec::new();
+        let mut sent = [0.; 3];
+        for g in grads.iter() {
+            let c = SignTensor::compress(g, &mut residual);
+            assert_eq!(c.signs.len(), 1);
+            for (s, d) in sent.iter_mut().zip(c.decompress(3).unwrap().blob()) {
+                *s += d;
+            }
+        }
+        // Nothing gets lost, only delayed
+        for i in 0..3 {
+            let total = grads[0].blob()[i] + grads[1].blob()[i];
+            assert!((sent[i] + residual[i] - total).abs() < 1e-6);
+        }
+        let c = SignTensor::compress(&Tensor::vector(&[1., -2., 0.5]), &mut Vec::new());
+        assert_eq!(
+            c.decompress(3).unwrap().blob(),
+            &[7. / 6., -7. / 6., 7. / 6.]
+        );
+        // Corrupt ones are rejected, not read as they come
+        assert!(c.decompress(4).is_err());
+        let huge = SignTensor {
+            shape: vec![usize::MAX, 2],
+            ..c.clone()
+        };
+        assert!(huge.decompress(3).is_err());
+        let short = SignTensor {
+            shape: vec![9],
+            ..c.clone()
+        };
+        assert!(short.decompress(9).is_err());
+
+        let big = Tensor::raw(&[256, 256], vec![0.5; 65536]).unwrap();
+        let dense = bincode::serialize(&big).unwrap().len();
+        let signs = bincode::serialize(&SignTensor::compress(&big, &mut Vec::new()))
+            .unwrap()
+            .len();
+        assert!(dense > 30 * signs);
+    }
+
+    #[test]
+    fn test_all_reduce_compressed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accuracy = TokenAccuracy::default();
+        let worker = std::thread::spawn(move || {
+            let mut cluster = Cluster::join(addr, 2).unwrap();
+            cluster.set_compression(true);
+            cluster
+                .all_reduce(Gradients {
+                    grads: vec![Tensor::vector(&[1., 2.])],
+                    loss: 1.,
+                    samples: 1,
+                    accuracy,
+                })
+                .unwrap()
+        });
+        let mut cluster = Cluster::coordinate_on(listener, 1, 2).unwrap();
+        cluster.set_compression(true);
+        let total = cluster
+            .all_reduce(Gradients {
+                grads: vec![Tensor::vector(&[3., 4.])],
+                loss: 2.,
+                samples: 2,
+                accuracy,
+            })
+            .unwrap();
+        let other = worker.join().unwrap();
+        // [1.5, 1.5] from the worker, plus [3, 4], sent back as signs
+        for t in [total, other] {
+            assert_eq!(t.grads[0].blob(), &[5., 5.]);
+            assert_eq!(t.samples, 3);
+        }
+    }
+}
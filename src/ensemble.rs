@@ -0,0 +1,193 @@
+//! Several models of the same vocabulary predicting together: the logits of the next
+//! token are the weighted mean of theirs, which often beats the best of them alone.
+
+use crate::gpt::{select, Sampling, GPT};
+use crate::graph::GraphError;
+use crate::optimizer::Optimizer;
+use crate::tensor::{Tensor, TensorError, TensorMutOps, TensorOps};
+use rand::Rng;
+
+/// Models generating and scoring text as one
+pub struct Ensemble<O: Optimizer> {
+    members: Vec<(GPT<O>, f32)>,
+}
+
+impl<O: Optimizer> Default for Ensemble<O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<O: Optimizer> Ensemble<O> {
+    pub fn new() -> Self {
+        Self {
+            members: Vec::new(),
+        }
+    }
+
+    /// Adds `gpt`, its logits counting `weight` times those of a model of weight 1
+    pub fn add(&mut self, gpt: GPT<O>, weight: f32) -> Result<(), GraphError> {
+        if let Some((first, _)) = self.members.first() {
+            if gpt.vocab_size() != first.vocab_size() {
+                return Err(TensorError::shape_mismatch(
+                    "Ensemble::add",
+                    &[first.vocab_size()],
+                    &[gpt.vocab_size()],
+                )
+                .into());
+            }
+        }
+        if weight.is_nan() || weight <= 0. {
+            return Err(TensorError::InvalidFormat(format!("invalid weight {}", weight)).into());
+        }
+        self.members.push((gpt, weight));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.members.first().map_or(0, |(g, _)| g.vocab_size())
+    }
+
+    /// Tokens every member can see at once: the context of the smallest one
+    pub fn num_tokens(&self) -> usize {
+        self.members
+            .iter()
+            .map(|(g, _)| g.num_tokens())
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Weighted mean of the logits of the members (See `GPT::logits`), over the last
+    /// `num_tokens` of `tokens`
+    pub fn logits(&self, tokens: &[usize]) -> Result<Tensor<f32>, GraphError> {
+        if self.members.is_empty() {
+            return Err(TensorError::unexpected_shape("Ensemble::logits", &[0]).into());
+        }
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens())..];
+        let total = self.members.iter().map(|(_, w)| w).sum::<f32>();
+        let mut mean = Tensor::zeros(&[tokens.len(), self.vocab_size()]);
+        for (gpt, weight) in self.members.iter() {
+            mean.scaled_add(weight / total, &gpt.logits(tokens)?)?;
+        }
+        Ok(mean)
+    }
+
+    /// Same as `GPT::token_log_probs`, from the logits of the ensemble
+    pub fn token_log_probs(&self, tokens: &[usize]) -> Result<Vec<f32>, GraphError> {
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens() + 1)..];
+        if tokens.len() < 2 {
+            return Ok(Vec::new());
+        }
+        let logits = self.logits(&tokens[..tokens.len() - 1])?;
+        tokens[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let logits = logits.get(i)?;
+                let max = logits
+                    .blob()
+                    .iter()
+                    .fold(f32::NEG_INFINITY, |a, b| f32::max(a, *b));
+                let sum = logits.blob().iter().map(|f| (f - max).exp()).sum::<f32>();
+                Ok(logits.blob()[*t] - max - sum.ln())
+            })
+            .collect()
+    }
+
+    /// Same as `GPT::generate`, from the logits of the ensemble. The prompt may be
+    /// longer than the context, only its end being seen.
+    pub fn generate<R: Rng, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        count: usize,
+        sampling: &Sampling,
+        mut callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        if prompt.is_empty() {
+            return Err(TensorError::unexpected_shape("Ensemble::generate", &[0]).into());
+        }
+        let mut context = prompt.to_vec();
+        let mut chs = Vec::new();
+        for _ in 0..count {
+            let logits = self.logits(&context)?;
+            let last = logits.get(logits.shape()[0] - 1)?;
+            let next = select(rng, &last, sampling)?;
+            chs.push(next);
+            if !callback(next) {
+                break;
+            }
+            context.push(next);
+        }
+        Ok(chs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelConfig;
+    use crate::optimizer::Naive;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn model(seed: u64, vocab_size: usize, num_tokens: usize) -> GPT<Naive> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        GPT::new(
+            &mut rng,
+            vocab_size,
+            &ModelConfig {
+                num_tokens,
+                embedding_degree: 8,
+                num_layers: 1,
+                num_heads: 2,
+                ..Default::default()
+            },
+            Naive::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ensemble() {
+        let tokens = [1, 2, 3, 4, 5, 6];
+        let (a, b) = (model(0, 7, 8), model(1, 7, 4));
+        let (la, lb) = (a.logits(&tokens[2..]).unwrap(), b.logits(&tokens).unwrap());
+        let mut ensemble = Ensemble::new();
+        ensemble.add(a, 3.).unwrap();
+        ensemble.add(b, 1.).unwrap();
+        assert!(ensemble.add(model(2, 5, 8), 1.).is_err());
+        assert_eq!(ensemble.num_tokens(), 4);
+
+        let logits = ensemble.logits(&tokens).unwrap();
+        assert_eq!(logits.shape(), [4, 7]);
+        for ((l, a), b) in logits.blob().iter().zip(la.blob()).zip(lb.blob()) {
+            assert!((l - (0.75 * a + 0.25 * b)).abs() < 1e-5);
+        }
+        let log_probs = ensemble.token_log_probs(&tokens).unwrap();
+        assert_eq!(log_probs.len(), 4);
+        assert!(log_probs.iter().all(|p| *p < 0.));
+
+        // Greedy generation picks the likeliest token of the mean
+        let greedy = Sampling {
+            temperature: 0.,
+            top_p: 1.,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let generated = ensemble
+            .generate(&mut rng, &tokens, 3, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(generated.len(), 3);
+        assert_eq!(
+            generated[0],
+            logits.get(3).unwrap().argmax().unwrap().scalar().unwrap()
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use crate::gpt::{TokenAccuracy, GPT};
+use crate::graph::GraphError;
+use crate::optimizer::Optimizer;
+use crate::tokenizer::Tokenizer;
+
+/// A multiple-choice example: the model is right when the choice it finds most
+/// likely as a continuation of `context` is the `answer`th one.
+#[derive(Debug, Clone)]
+pub struct Example {
+    pub context: String,
+    pub choices: Vec<String>,
+    pub answer: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Task {
+    pub name: String,
+    pub examples: Vec<Example>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskResult {
+    pub name: String,
+    pub examples: usize,
+    /// Fraction of examples where the answer had the highest total log-likelihood
+    pub accuracy: f32,
+    /// Same as `accuracy`, but with log-likelihoods divided by the length of the choices
+    pub accuracy_norm: f32,
+}
+
+impl Task {
+    /// Parses a task out of tab-separated lines: `context<TAB>choice<TAB>choice...<TAB>answer`,
+    /// where `answer` is the index of the right choice. Escape sequences `\n` and `\t`
+    /// may be used inside the fields. Empty lines are skipped.
+    pub fn from_tsv(name: &str, tsv: &str) -> Option<Self> {
+        let unescape = |s: &str| s.replace("\\n", "\n").replace("\\t", "\t");
+        let examples = tsv
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| {
+                let fields = l.split('\t').collect::<Vec<_>>();
+                if fields.len() < 4 {
+                    return None;
+                }
+                let answer = fields[fields.len() - 1].trim().parse::<usize>().ok()?;
+                let choices = fields[1..fields.len() - 1]
+                    .iter()
+                    .map(|c| unescape(c))
+                    .collect::<Vec<_>>();
+                if answer >= choices.len() {
+                    return None;
+                }
+                Some(Example {
+                    context: unescape(fields[0]),
+                    choices,
+                    answer,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            name: name.into(),
+            examples,
+        })
+    }
+}
+
+/// Total log-likelihood of `continuation` following `context`, along with the
+/// number of tokens it was computed over
+pub fn continuation_log_likelihood<O: Optimizer>(
+    gpt: &GPT<O>,
+    context: &[usize],
+    continuation: &[usize],
+) -> Result<(f32, usize), GraphError> {
+    let tokens = [context, continuation].concat();
+    let log_probs = gpt.token_log_probs(&tokens)?;
+    let n = continuation.len().min(log_probs.len());
+    Ok((log_probs[log_probs.len() - n..].iter().sum(), n))
+}
+
+pub fn evaluate<O: Optimizer, T: Tokenizer>(
+    gpt: &GPT<O>,
+    tokenizer: &T,
+    task: &Task,
+) -> Result<TaskResult, GraphError> {
+    let _span = tracing::info_span!("evaluate", task = %task.name).entered();
+    let mut correct = 0;
+    let mut correct_norm = 0;
+    for example in task.examples.iter() {
+        let context = tokenizer.tokenize(&example.context)?;
+        let scores = example
+            .choices
+            .iter()
+            .map(|c| continuation_log_likelihood(gpt, &context, &tokenizer.tokenize(c)?))
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        let best = |f: &dyn Fn(&(f32, usize)) -> f32| {
+            scores
+                .iter()
+                .enumerate()
+                .max_by(|a, b| f(a.1).total_cmp(&f(b.1)))
+                .map(|(i, _)| i)
+        };
+        if best(&|s| s.0) == Some(example.answer) {
+            correct += 1;
+        }
+        if best(&|s| s.0 / s.1.max(1) as f32) == Some(example.answer) {
+            correct_norm += 1;
+        }
+    }
+    let total = task.examples.len().max(1) as f32;
+    let result = TaskResult {
+        name: task.name.clone(),
+        examples: task.examples.len(),
+        accuracy: correct as f32 / total,
+        accuracy_norm: correct_norm as f32 / total,
+    };
+    tracing::info!(
+        examples = result.examples,
+        accuracy = result.accuracy,
+        accuracy_norm = result.accuracy_norm,
+        "Evaluated"
+    );
+    Ok(result)
+}
+
+/// Perplexity of the model on `tokens`, evaluated over consecutive windows
+pub fn perplexity<O: Optimizer>(gpt: &GPT<O>, tokens: &[usize]) -> Result<f32, GraphError> {
+    strided_perplexity(gpt, tokens, gpt.num_tokens())
+}
+
+/// Perplexity of the model on `tokens`, evaluated over windows starting every `stride`
+/// tokens (At most the context size), each only scoring the tokens the previous ones
+/// didn't: the smaller the stride, the more context the tokens are predicted from
+/// (Sliding-window perplexity), and the slower.
+pub fn strided_perplexity<O: Optimizer>(
+    gpt: &GPT<O>,
+    tokens: &[usize],
+    stride: usize,
+) -> Result<f32, GraphError> {
+    let window = gpt.num_tokens() + 1;
+    let stride = stride.clamp(1, gpt.num_tokens());
+    let mut sum = 0.;
+    let mut count = 0;
+    let mut start = 0;
+    // Tokens before it are scored (The first one can't be)
+    let mut scored = 1;
+    while scored < tokens.len() {
+        let end = (start + window).min(tokens.len());
+        let log_probs = gpt.token_log_probs(&tokens[start..end])?;
+        let new = end - scored;
+        sum += log_probs[log_probs.len() - new..].iter().sum::<f32>();
+        count += new;
+        scored = end;
+        start += stride;
+    }
+    Ok((-sum / count.max(1) as f32).exp())
+}
+
+/// Next-token accuracy of the model on `tokens`, over the same windows as `perplexity`
+pub fn token_accuracy<O: Optimizer>(
+    gpt: &GPT<O>,
+    tokens: &[usize],
+) -> Result<TokenAccuracy, GraphError> {
+    let window = gpt.num_tokens() + 1;
+    let mut accuracy = TokenAccuracy::default();
+    let mut start = 0;
+    while start + 1 < tokens.len() {
+        let end = (start + window).min(tokens.len());
+        for rank in gpt.token_ranks(&tokens[start..end])? {
+            accuracy.record(rank);
+        }
+        start = end - 1;
+    }
+    Ok(accuracy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelConfig;
+    use crate::optimizer::Naive;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_from_tsv() {
+        let task = Task::from_tsv("test", "a\\nb\tc\td\t1\n\nx\ty\tz\t0\n").unwrap();
+        assert_eq!(task.examples.len(), 2);
+        assert_eq!(task.examples[0].context, "a\nb");
+        assert_eq!(task.examples[0].choices, vec!["c", "d"]);
+        assert_eq!(task.examples[0].answer, 1);
+        assert!(Task::from_tsv("test", "a\tb\tc\t2").is_none());
+    }
+
+    #[test]
+    fn test_strided_perplexity() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let gpt = GPT::new(
+            &mut rng,
+            7,
+            &ModelConfig {
+                num_tokens: 4,
+                embedding_degree: 8,
+                num_layers: 1,
+                num_heads: 2,
+                ..Default::default()
+            },
+            Naive::new(),
+        )
+        .unwrap();
+        let tokens = (0..23).map(|i| i * i % 7).collect::<Vec<_>>();
+        let disjoint = perplexity(&gpt, &tokens).unwrap();
+        assert_eq!(strided_perplexity(&gpt, &tokens, 4).unwrap(), disjoint);
+        assert_eq!(strided_perplexity(&gpt, &tokens, 100).unwrap(), disjoint);
+        for stride in 1..4 {
+            let sliding = strided_perplexity(&gpt, &tokens, stride).unwrap();
+            assert!(sliding.is_finite() && sliding > 1.);
+            assert_ne!(sliding, disjoint);
+        }
+        let direct = gpt.token_log_probs(&tokens[..5]).unwrap();
+        let expected = (-direct.iter().sum::<f32>() / 4.).exp();
+        assert!((strided_perplexity(&gpt, &tokens[..5], 1).unwrap() - expected).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,324 @@
+//! A C interface, for embedding models in C/C++ applications. The declarations are in
+//! `include/femto_gpt.h`, generated with `cbindgen --config cbindgen.toml --output
+//! include/femto_gpt.h`.
+//!
+//! Functions that fail return `NULL` (Or a negative number), and the reason can then
+//! be read with `femto_gpt_last_error`. Panics are caught at the boundary and reported
+//! the same way, never unwinding into the caller.
+
+use crate::config::Config;
+use crate::gpt::{Sampling, GPT};
+use crate::optimizer::AdamW;
+use crate::tokenizer::{StreamDecoder, Tokenizer};
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// A loaded model, along with its tokenizer
+pub struct FemtoGpt {
+    gpt: GPT<AdamW>,
+    tokenizer: Box<dyn Tokenizer + Send + Sync>,
+}
+
+/// Called with the text of each generated token (A NUL-terminated UTF-8 string, only
+/// valid during the call). Returns whether the generation should go on.
+pub type FemtoGptTokenCallback =
+    Option<unsafe extern "C" fn(text: *const c_char, user_data: *mut c_void) -> bool>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error<E: std::fmt::Display>(e: E) {
+    let msg = CString::new(e.to_string().replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Runs `f`, recording why it failed (Or panicked) for `femto_gpt_last_error`
+fn guard<T, F: FnOnce() -> Result<T, String>>(f: F) -> Option<T> {
+    let panicked = |payload: Box<dyn Any + Send>| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        format!("panicked: {}", msg)
+    };
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => Some(v),
+        Ok(Err(e)) => {
+            set_error(e);
+            None
+        }
+        Err(payload) => {
+            set_error(panicked(payload));
+            None
+        }
+    }
+}
+
+unsafe fn to_model<'a>(model: *const FemtoGpt) -> Result<&'a FemtoGpt, String> {
+    model.as_ref().ok_or_else(|| "unexpected null model".into())
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err("unexpected null string".into());
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| "string is not valid UTF-8".into())
+}
+
+unsafe fn to_bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if data.is_null() || len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len)
+    }
+}
+
+impl FemtoGpt {
+    fn tokenize(&self, text: &str) -> Result<Vec<usize>, String> {
+        self.tokenizer.tokenize(text).map_err(|e| e.to_string())
+    }
+}
+
+/// The reason of the last failure on this thread, or `NULL`. Valid until the next
+/// call that fails.
+#[no_mangle]
+pub extern "C" fn femto_gpt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Loads a model, given its config (The TOML saved along the training state), its
+/// saved tokenizer (Which may be empty for the ASCII tokenizer) and its weights
+/// (A training state, or the output of `GPT::write_params`). The returned model
+/// should be freed with `femto_gpt_free`.
+///
+/// # Safety
+///
+/// `config` should be a NUL-terminated string, and `tokenizer`/`weights` should point
+/// to `tokenizer_len`/`weights_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_new(
+    config: *const c_char,
+    tokenizer: *const u8,
+    tokenizer_len: usize,
+    weights: *const u8,
+    weights_len: usize,
+) -> *mut FemtoGpt {
+    let load = || {
+        let config = Config::from_toml(to_str(config)?).map_err(|e| e.to_string())?;
+        let (gpt, tokenizer) = config
+            .load_model(
+                to_bytes(tokenizer, tokenizer_len),
+                to_bytes(weights, weights_len),
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(FemtoGpt { gpt, tokenizer })))
+    };
+    guard(load).unwrap_or(ptr::null_mut())
+}
+
+/// Frees a model returned by `femto_gpt_new`. (Does nothing on `NULL`)
+///
+/// # Safety
+///
+/// `model` should be `NULL` or a model that's not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_free(model: *mut FemtoGpt) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// Zero for a `NULL` model
+///
+/// # Safety
+///
+/// `model` should be `NULL` or a valid model.
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_vocab_size(model: *const FemtoGpt) -> usize {
+    model.as_ref().map_or(0, |m| m.tokenizer.vocab_size())
+}
+
+/// Zero for a `NULL` model
+///
+/// # Safety
+///
+/// `model` should be `NULL` or a valid model.
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_context_size(model: *const FemtoGpt) -> usize {
+    model.as_ref().map_or(0, |m| m.gpt.num_tokens())
+}
+
+/// Writes the tokens of `text` to `out`, up to `out_len` of them. Returns the number of
+/// tokens of `text` (Which may be more than `out_len`), or -1 on failure. (Including
+/// a `NULL` model, or tokens which don't fit in 32 bits)
+///
+/// # Safety
+///
+/// `model` should be `NULL` or a valid model, `text` a NUL-terminated string and `out` should
+/// point to `out_len` writable tokens. (`out` may be `NULL` if `out_len` is zero)
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_tokenize(
+    model: *const FemtoGpt,
+    text: *const c_char,
+    out: *mut u32,
+    out_len: usize,
+) -> isize {
+    let tokenize = || {
+        let tokens = to_model(model)?.tokenize(to_str(text)?)?;
+        let ids = tokens
+            .iter()
+            .map(|t| u32::try_from(*t).map_err(|_| format!("token {} doesn't fit in 32 bits", t)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, t) in ids.into_iter().take(out_len).enumerate() {
+            *out.add(i) = t;
+        }
+        Ok(tokens.len() as isize)
+    };
+    guard(tokenize).unwrap_or(-1)
+}
+
+/// Continues `prompt` with up to `max_tokens` tokens, handing each of them to
+/// `callback` along with `user_data`. (Zero `temperature` or `top_p` means greedy
+/// decoding) Only the last tokens of the prompt that fit in the context of the model
+/// are considered. Returns the number of generated tokens, or -1 on failure.
+///
+/// # Safety
+///
+/// `model` should be `NULL` or a valid model and `prompt` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_generate(
+    model: *const FemtoGpt,
+    prompt: *const c_char,
+    max_tokens: usize,
+    temperature: f32,
+    top_p: f32,
+    callback: FemtoGptTokenCallback,
+    user_data: *mut c_void,
+) -> isize {
+    let generate = || {
+        let model = to_model(model)?;
+        let tokens = model.tokenize(to_str(prompt)?)?;
+        if tokens.is_empty() {
+            return Err("the prompt is empty".into());
+        }
+        let prompt = &tokens[tokens.len().saturating_sub(model.gpt.num_tokens())..];
+        let sampling = Sampling { temperature, top_p };
+        let mut rng = rand::thread_rng();
+        let mut decoder = StreamDecoder::new(model.tokenizer.as_ref());
+        let send = |text: String| {
+            let Some(callback) = callback else {
+                return true;
+            };
+            let text = CString::new(text.replace('\0', "")).unwrap();
+            callback(text.as_ptr(), user_data)
+        };
+        let output = model
+            .gpt
+            .generate(&mut rng, prompt, max_tokens, &sampling, |tkn| match decoder
+                .push(tkn)
+                .unwrap_or_default()
+            {
+                text if text.is_empty() => true,
+                text => send(text),
+            })
+            .map_err(|e| e.to_string())?;
+        let rest = decoder.finish();
+        if !rest.is_empty() {
+            send(rest);
+        }
+        Ok(output.len() as isize)
+    };
+    guard(generate).unwrap_or(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn collect(text: *const c_char, user_data: *mut c_void) -> bool {
+        let out = &mut *(user_data as *mut String);
+        out.push_str(CStr::from_ptr(text).to_str().unwrap());
+        out.len() < 5
+    }
+
+    #[test]
+    fn test_ffi() {
+        let config = CString::new(
+            "[model]\nnum_tokens = 4\nembedding_degree = 8\nnum_layers = 1\nnum_heads = 2\n\n[tokenizer]\nkind = \"ascii\"\n",
+        )
+        .unwrap();
+        let parsed = Config::from_toml(config.to_str().unwrap()).unwrap();
+        let mut rng = rand::thread_rng();
+        let gpt = parsed.model.build(&mut rng, 128, AdamW::new()).unwrap();
+        let mut weights = Vec::new();
+        gpt.write_params(&mut weights).unwrap();
+
+        unsafe {
+            let model = femto_gpt_new(
+                config.as_ptr(),
+                ptr::null(),
+                0,
+                weights.as_ptr(),
+                weights.len(),
+            );
+            assert!(!model.is_null());
+            assert_eq!(femto_gpt_vocab_size(model), 128);
+
+            let mut tokens = [0u32; 2];
+            let text = CString::new("Hey").unwrap();
+            assert_eq!(
+                femto_gpt_tokenize(model, text.as_ptr(), tokens.as_mut_ptr(), 2),
+                3
+            );
+            assert_eq!(tokens, [72, 101]);
+            let text = CString::new("Hé").unwrap();
+            assert_eq!(
+                femto_gpt_tokenize(model, text.as_ptr(), ptr::null_mut(), 0),
+                -1
+            );
+            assert!(!femto_gpt_last_error().is_null());
+
+            // Generation is stopped by the callback after 5 tokens
+            let mut out = String::new();
+            let prompt = CString::new("Hello").unwrap();
+            let count = femto_gpt_generate(
+                model,
+                prompt.as_ptr(),
+                10,
+                1.,
+                1.,
+                Some(collect),
+                &mut out as *mut String as *mut c_void,
+            );
+            assert_eq!(count, 5);
+            assert_eq!(out.len(), 5);
+            femto_gpt_free(model);
+
+            let model = femto_gpt_new(config.as_ptr(), ptr::null(), 0, ptr::null(), 0);
+            assert!(model.is_null());
+
+            assert_eq!(femto_gpt_vocab_size(ptr::null()), 0);
+            assert_eq!(
+                femto_gpt_tokenize(ptr::null(), text.as_ptr(), ptr::null_mut(), 0),
+                -1
+            );
+            let null = ptr::null();
+            let count = femto_gpt_generate(null, prompt.as_ptr(), 1, 1., 1., None, ptr::null_mut());
+            assert_eq!(count, -1);
+            let error = CStr::from_ptr(femto_gpt_last_error()).to_str().unwrap();
+            assert_eq!(error, "unexpected null model");
+        }
+
+        // Panics don't unwind past the interface
+        assert_eq!(guard::<(), _>(|| panic!("boom")), None);
+        let error = unsafe { CStr::from_ptr(femto_gpt_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "panicked: boom");
+    }
+}
@@ -0,0 +1,141 @@
+//! Deterministic inputs for the benchmarks (See `benches/`) and tests: models, token
+//! streams and text generated from fixed seeds, so that two runs, or two commits, measure
+//! exactly the same work.
+
+use crate::config::ModelConfig;
+use crate::gpt::{seeded_rng, GPT};
+use crate::graph::GraphError;
+use crate::optimizer::AdamW;
+use crate::tensor::*;
+use rand::Rng;
+
+/// Seed everything here is generated from
+pub const SEED: u64 = 0;
+
+/// Characters the generated text is made of
+const ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz ,.\n";
+
+/// A model small enough for a benchmark iteration to take milliseconds
+pub fn tiny_model() -> ModelConfig {
+    ModelConfig {
+        num_tokens: 16,
+        embedding_degree: 32,
+        num_layers: 1,
+        num_heads: 4,
+        dropout: 0.,
+        ..Default::default()
+    }
+}
+
+/// A model of the proportions of the default one, but shallower
+pub fn small_model() -> ModelConfig {
+    ModelConfig {
+        num_tokens: 64,
+        embedding_degree: 64,
+        num_layers: 2,
+        num_heads: 4,
+        dropout: 0.,
+        ..Default::default()
+    }
+}
+
+/// `config` built with weights drawn from `SEED`
+pub fn gpt(config: &ModelConfig, vocab_size: usize) -> Result<GPT<AdamW>, GraphError> {
+    let mut gpt = config.build(&mut seeded_rng(SEED), vocab_size, AdamW::new())?;
+    gpt.set_data_seed(SEED);
+    Ok(gpt)
+}
+
+/// `len` tokens below `vocab_size` drawn from `SEED`
+pub fn tokens(vocab_size: usize, len: usize) -> Vec<usize> {
+    let mut rng = seeded_rng(SEED);
+    (0..len).map(|_| rng.gen_range(0..vocab_size)).collect()
+}
+
+/// `len` characters of `ALPHABET` drawn from `SEED`
+pub fn text(len: usize) -> String {
+    let alphabet = ALPHABET.chars().collect::<Vec<_>>();
+    let mut rng = seeded_rng(SEED);
+    (0..len)
+        .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+        .collect()
+}
+
+/// A tensor of `shape` with values drawn from `SEED`
+pub fn tensor(shape: &[usize]) -> Tensor<f32> {
+    Tensor::<f32>::rand(&mut seeded_rng(SEED), shape)
+}
+
+/// The allocator of the tests, counting the bytes allocated by each thread (See
+/// `allocated`)
+#[cfg(test)]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        pub static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count(bytes: usize) {
+        // Not counted while the thread is being torn down
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() + bytes));
+    }
+
+    pub struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc(layout)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(new_size.saturating_sub(layout.size()));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+}
+
+/// `f`'s result, and the number of bytes it allocated (On a single thread, for all of
+/// them to be counted)
+#[cfg(test)]
+pub(crate) fn allocated<R: Send, F: FnOnce() -> R + Send>(f: F) -> (R, usize) {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    pool.install(|| {
+        let start = counting::ALLOCATED.with(|a| a.get());
+        let result = f();
+        (result, counting::ALLOCATED.with(|a| a.get()) - start)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixtures() {
+        assert_eq!(tokens(10, 100), tokens(10, 100));
+        assert!(tokens(10, 100).iter().all(|t| *t < 10));
+        assert_eq!(text(50), text(50));
+        assert_eq!(text(50).chars().count(), 50);
+        assert_eq!(tensor(&[3, 4]).blob(), tensor(&[3, 4]).blob());
+
+        let config = tiny_model();
+        let a = gpt(&config, 10).unwrap();
+        let b = gpt(&config, 10).unwrap();
+        let prompt = tokens(10, config.num_tokens);
+        assert_eq!(
+            a.logits(&prompt).unwrap().blob(),
+            b.logits(&prompt).unwrap().blob()
+        );
+    }
+}
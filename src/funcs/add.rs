@@ -9,15 +9,21 @@ impl Add {
     }
 }
 impl Function for Add {
+    fn name(&self) -> &'static str {
+        "add"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         inps[0] + inps[1]
     }
     fn grad(
         &self,
-        _inps: &[&Tensor<f32>],
+        inps: &[&Tensor<f32>],
         out_grad: &Tensor<f32>,
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
-        Ok(vec![out_grad.clone(), out_grad.clone()])
+        Ok(vec![
+            out_grad.sum_to(inps[0].shape())?,
+            out_grad.sum_to(inps[1].shape())?,
+        ])
     }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
@@ -0,0 +1,33 @@
+use super::Loss;
+use crate::tensor::*;
+
+/// Weighted sum of two losses: `alpha * a + (1 - alpha) * b`
+#[derive(Debug)]
+pub struct Blend {
+    a: Box<dyn Loss>,
+    b: Box<dyn Loss>,
+    alpha: f32,
+}
+impl Blend {
+    pub fn new(a: Box<dyn Loss>, b: Box<dyn Loss>, alpha: f32) -> Box<dyn Loss> {
+        Box::new(Self { a, b, alpha })
+    }
+}
+
+impl Loss for Blend {
+    fn run(&self, inp: &Tensor<f32>) -> Result<(Tensor<f32>, Tensor<f32>), TensorError> {
+        let (a_loss, a_grad) = self.a.run(inp)?;
+        let (b_loss, b_grad) = self.b.run(inp)?;
+        let mix = |a: &Tensor<f32>, b: &Tensor<f32>| {
+            Tensor::raw(
+                a.shape(),
+                a.blob()
+                    .iter()
+                    .zip(b.blob().iter())
+                    .map(|(a, b)| self.alpha * a + (1. - self.alpha) * b)
+                    .collect(),
+            )
+        };
+        Ok((mix(&a_loss, &b_loss)?, mix(&a_grad, &b_grad)?))
+    }
+}
@@ -10,27 +10,24 @@ impl Cat {
 }
 
 impl Function for Cat {
+    fn name(&self) -> &'static str {
+        "cat"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
-        let first_input = inps.get(0).ok_or(TensorError::UnexpectedShape)?; // TODO: Better error?
-        let shape = first_input.shape().to_vec();
-        if !inps.iter().all(|t| t.shape() == shape) {
-            return Err(TensorError::UnexpectedShape);
+        let first_input = inps
+            .first()
+            .ok_or(TensorError::unexpected_shape("Cat", &[0]))?;
+        if first_input.dim() == 0 {
+            return Err(TensorError::unexpected_shape("Cat", first_input.shape()));
         }
-        let each_sz = first_input.size();
-        let group_size = shape.last().ok_or(TensorError::UnexpectedShape)?;
-        let mut offset = 0;
-        let mut data = Vec::with_capacity(each_sz * inps.len());
-
-        while offset < each_sz {
-            for inp in inps {
-                data.extend(&inp.blob()[offset..offset + group_size]);
-            }
-            offset += group_size;
+        if let Some(t) = inps.iter().find(|t| t.shape() != first_input.shape()) {
+            return Err(TensorError::shape_mismatch(
+                "Cat",
+                first_input.shape(),
+                t.shape(),
+            ));
         }
-
-        let mut target_shape = shape.clone();
-        target_shape[shape.len() - 1] = target_shape[shape.len() - 1] * inps.len();
-        Tensor::raw(&target_shape, data)
+        Tensor::cat(inps, first_input.dim() - 1)
     }
     fn grad(
         &self,
@@ -41,17 +38,17 @@ impl Function for Cat {
         let last_dim = *out_grad
             .shape()
             .last()
-            .ok_or(TensorError::UnexpectedShape)?;
+            .ok_or(TensorError::unexpected_shape("Cat", out_grad.shape()))?;
         let group_size = last_dim / cnt;
         if group_size * cnt != last_dim {
-            return Err(TensorError::UnexpectedShape);
+            return Err(TensorError::unexpected_shape("Cat", out_grad.shape()));
         }
         let mut result = vec![Vec::new(); cnt];
         let mut offset = 0;
         let out_grad_blob = out_grad.blob();
         while offset < out_grad.size() {
-            for i in 0..cnt {
-                result[i].extend(&out_grad_blob[offset..offset + group_size]);
+            for r in result.iter_mut() {
+                r.extend(&out_grad_blob[offset..offset + group_size]);
                 offset += group_size;
             }
         }
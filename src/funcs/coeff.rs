@@ -1,3 +1,4 @@
+use super::registry::OpParams;
 use super::Function;
 use crate::tensor::*;
 
@@ -11,6 +12,12 @@ impl Coeff {
     }
 }
 impl Function for Coeff {
+    fn name(&self) -> &'static str {
+        "coeff"
+    }
+    fn params(&self) -> OpParams {
+        OpParams::scalars(&[self.coeff])
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         Ok(inps[0].map_values(|f| f * self.coeff))
     }
@@ -5,14 +5,63 @@ use crate::tensor::*;
 pub struct CrossEntropy {
     classes: usize,
     target: Tensor<usize>,
+    weights: Option<Tensor<f32>>,
 }
 impl CrossEntropy {
     pub fn new(classes: usize, target: Tensor<usize>) -> Box<dyn Loss> {
-        Box::new(Self { classes, target })
+        Box::new(Self {
+            classes,
+            target,
+            weights: None,
+        })
+    }
+    /// Cross-entropy where the loss of each position is scaled by its weight (E.g. 0
+    /// for padding). Weights are normalized so that the mean loss is a weighted average.
+    pub fn weighted(classes: usize, target: Tensor<usize>, weights: Tensor<f32>) -> Box<dyn Loss> {
+        Box::new(Self {
+            classes,
+            target,
+            weights: Some(weights),
+        })
     }
 }
 
 impl Loss for CrossEntropy {
+    fn name(&self) -> &'static str {
+        "crossentropy"
+    }
+    fn run_f64(&self, inp: &Tensor<f32>) -> Option<(Vec<f64>, Vec<f64>)> {
+        let rows = inp.blob().chunks(self.classes.max(1)).collect::<Vec<_>>();
+        if rows.len() != self.target.size() || inp.size() != rows.len() * self.classes {
+            return None;
+        }
+        let weights = match &self.weights {
+            Some(w) if w.size() != rows.len() => return None,
+            Some(w) => {
+                let total = w.blob().iter().map(|w| *w as f64).sum::<f64>();
+                let coeff = if total > 0. {
+                    rows.len() as f64 / total
+                } else {
+                    0.
+                };
+                w.blob().iter().map(|w| *w as f64 * coeff).collect()
+            }
+            None => vec![1.; rows.len()],
+        };
+        let mut losses = Vec::with_capacity(rows.len());
+        let mut grad = Vec::with_capacity(inp.size());
+        for ((row, t), w) in rows.iter().zip(self.target.blob()).zip(weights) {
+            let row = row.iter().map(|f| *f as f64).collect::<Vec<_>>();
+            let max = row.iter().fold(f64::NEG_INFINITY, |a, b| a.max(*b));
+            let sum = row.iter().map(|f| (f - max).exp()).sum::<f64>();
+            losses.push((sum.ln() + max - row[*t]) * w);
+            grad.extend(row.iter().enumerate().map(|(c, f)| {
+                let p = (f - max).exp() / sum;
+                (if c == *t { p - 1. } else { p }) * w
+            }));
+        }
+        Some((losses, grad))
+    }
     fn run(&self, inp: &Tensor<f32>) -> Result<(Tensor<f32>, Tensor<f32>), TensorError> {
         let grad_shape = inp.shape().to_vec();
         let mut loss_shape = grad_shape.clone();
@@ -43,9 +92,58 @@ impl Loss for CrossEntropy {
             })
             .unzip();
 
+        let (loss, grad) = if let Some(weights) = &self.weights {
+            if weights.size() != loss.len() {
+                return Err(TensorError::shape_mismatch(
+                    "CrossEntropy",
+                    &[loss.len()],
+                    weights.shape(),
+                ));
+            }
+            let total = weights.blob().iter().sum::<f32>();
+            let coeff = if total > 0. {
+                loss.len() as f32 / total
+            } else {
+                0.
+            };
+            let (loss, grad): (Vec<f32>, Vec<Vec<f32>>) = loss
+                .into_iter()
+                .zip(grad)
+                .zip(weights.blob().iter())
+                .map(|((l, g), w)| {
+                    let w = w * coeff;
+                    (l * w, g.into_iter().map(|g| g * w).collect())
+                })
+                .unzip();
+            (loss, grad)
+        } else {
+            (loss, grad)
+        };
+
         Ok((
             Tensor::raw(&loss_shape, loss)?,
             Tensor::raw(&grad_shape, grad.into_iter().flatten().collect())?,
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_ignores_padding() {
+        let inp = Tensor::raw(&[3, 2], vec![1., 2., 0.5, -1., 3., 3.]).unwrap();
+        let target = Tensor::raw(&[3], vec![1, 0, 1]).unwrap();
+        let (loss, grad) = CrossEntropy::new(2, target.clone()).run(&inp).unwrap();
+        let weights = Tensor::raw(&[3], vec![1., 1., 0.]).unwrap();
+        let (w_loss, w_grad) = CrossEntropy::weighted(2, target, weights)
+            .run(&inp)
+            .unwrap();
+
+        let expected = (loss.blob()[0] + loss.blob()[1]) / 2.;
+        assert!((w_loss.mean() - expected).abs() < 1e-5);
+        assert_eq!(&w_grad.blob()[4..], &[0., 0.]);
+        assert!((w_grad.blob()[0] - grad.blob()[0] * 1.5).abs() < 1e-5);
+    }
+}
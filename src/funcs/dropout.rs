@@ -1,5 +1,20 @@
+use super::registry::OpParams;
 use super::Function;
 use crate::tensor::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::cell::RefCell;
+
+thread_local! {
+    static RNG: RefCell<Option<ChaCha8Rng>> = const { RefCell::new(None) };
+}
+
+/// Draws the masks of the dropouts run on this thread from `seed` from now on, so that
+/// they can be replayed (E.g. when resuming the training from a checkpoint). Until
+/// then, they're drawn from `rand::thread_rng`.
+pub fn seed_dropout(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha8Rng::seed_from_u64(seed)));
+}
 
 #[derive(Debug, Clone)]
 pub struct Dropout {
@@ -16,10 +31,20 @@ impl Dropout {
 }
 
 impl Function for Dropout {
+    fn name(&self) -> &'static str {
+        "dropout"
+    }
+    fn params(&self) -> OpParams {
+        OpParams::scalars(&[self.rate])
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], training: bool) -> Result<Tensor<f32>, TensorError> {
         Ok(if training {
-            let mut rng = rand::thread_rng();
-            let rnd = Tensor::<f32>::rand_range(&mut rng, 0., 1.0, inps[0].shape());
+            let rnd = RNG.with(|rng| match rng.borrow_mut().as_mut() {
+                Some(rng) => Tensor::<f32>::rand_range(rng, 0., 1.0, inps[0].shape()),
+                None => {
+                    Tensor::<f32>::rand_range(&mut rand::thread_rng(), 0., 1.0, inps[0].shape())
+                }
+            });
             let scale = 1. / (1. - self.rate);
             self.mask = rnd.map_values(|v| if v > self.rate { scale } else { 0. });
             (inps[0] * &self.mask)?
@@ -0,0 +1,73 @@
+use super::registry::OpParams;
+use super::Function;
+use crate::tensor::*;
+
+/// Another function whose output is quantized to `bits` bits and back (Symmetrically,
+/// the largest magnitude mapping to the largest level), so that a model trained with it
+/// learns to live with the rounding of the int8 inference path. The gradient goes
+/// straight through the rounding. Graphs keep the plain function in their spec.
+#[derive(Debug)]
+pub struct FakeQuant {
+    inner: Box<dyn Function>,
+    bits: u32,
+}
+
+impl FakeQuant {
+    pub fn wrap(inner: Box<dyn Function>, bits: u32) -> Box<dyn Function> {
+        Box::new(Self { inner, bits })
+    }
+}
+
+/// `t` quantized to `bits` bits and back. Infinities (E.g. of masked attention scores)
+/// and NaNs are left as they are.
+pub fn fake_quantize(t: &Tensor<f32>, bits: u32) -> Tensor<f32> {
+    let max = t
+        .blob()
+        .iter()
+        .filter(|v| v.is_finite())
+        .fold(0f32, |m, v| m.max(v.abs()));
+    let levels = ((1u32 << (bits - 1)) - 1) as f32;
+    let scale = max / levels;
+    if scale == 0. {
+        return t.clone();
+    }
+    t.map_values(|v| {
+        if v.is_finite() {
+            (v / scale).round() * scale
+        } else {
+            v
+        }
+    })
+}
+
+impl Function for FakeQuant {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+    fn params(&self) -> OpParams {
+        self.inner.params()
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], training: bool) -> Result<Tensor<f32>, TensorError> {
+        Ok(fake_quantize(&self.inner.run(inps, training)?, self.bits))
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        self.inner.grad(inps, out_grad)
+    }
+    /// The unquantized output, the rounding showing as a divergence
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        self.inner.run_f64(inps)
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        self.inner.grad_f64(inps, out_grad)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(Self {
+            inner: self.inner.clone_box(),
+            bits: self.bits,
+        })
+    }
+}
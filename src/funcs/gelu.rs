@@ -1,14 +1,14 @@
-use super::Function;
+use super::{elementwise_grad_f64, to_f64, Function};
 use crate::tensor::*;
 
-const SQRT_2_OVER_PI: f32 = 0.7978845608;
+const SQRT_2_OVER_PI: f32 = 0.797_884_6;
 const GELU_CONST: f32 = 0.044715;
 
-fn gelu(x: f32) -> f32 {
+pub(super) fn gelu(x: f32) -> f32 {
     0.5 * x * ((SQRT_2_OVER_PI * (x + GELU_CONST * x.powi(3))).tanh() + 1.)
 }
 
-fn gelu_prime(x: f32) -> f32 {
+pub(super) fn gelu_prime(x: f32) -> f32 {
     let x2 = x * x;
     let x3 = x2 * x;
     let v = SQRT_2_OVER_PI * x + SQRT_2_OVER_PI * GELU_CONST * x3;
@@ -17,6 +17,11 @@ fn gelu_prime(x: f32) -> f32 {
     0.5 * (1. + v.tanh() + x * sech_2 * v_prime)
 }
 
+// For calculating high-accuracy numerical derivatives, and checking the numerics
+fn gelu_f64(x: f64) -> f64 {
+    0.5 * x * ((SQRT_2_OVER_PI as f64 * (x + GELU_CONST as f64 * x.powi(3))).tanh() + 1.)
+}
+
 #[derive(Debug, Clone)]
 pub struct Gelu;
 impl Gelu {
@@ -25,6 +30,9 @@ impl Gelu {
     }
 }
 impl Function for Gelu {
+    fn name(&self) -> &'static str {
+        "gelu"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         Ok(inps[0].map_values(gelu))
     }
@@ -36,6 +44,18 @@ impl Function for Gelu {
         let der = inps[0].map_values(gelu_prime);
         Ok(vec![(&der * out_grad)?])
     }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        Some(to_f64(inps[0]).into_iter().map(gelu_f64).collect())
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        elementwise_grad_f64(inps[0], out_grad, |x| {
+            let c = SQRT_2_OVER_PI as f64;
+            let k = GELU_CONST as f64;
+            let v = c * (x + k * x.powi(3));
+            let sech_2 = 1. / v.cosh().powi(2);
+            0.5 * (1. + v.tanh() + x * sech_2 * c * (1. + 3. * k * x * x))
+        })
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
@@ -45,11 +65,6 @@ impl Function for Gelu {
 mod tests {
     use super::*;
 
-    // For calculating high-accuracy numerical derivative
-    fn gelu_f64(x: f64) -> f64 {
-        0.5 * x * ((SQRT_2_OVER_PI as f64 * (x + GELU_CONST as f64 * x.powi(3))).tanh() + 1.)
-    }
-
     #[test]
     fn test_derivative() {
         const EPSILON: f64 = 1e-5;
@@ -0,0 +1,134 @@
+use super::Loss;
+use crate::tensor::*;
+
+/// Softmax of `logits / temperature`
+fn soft(logits: &[f32], temperature: f32) -> Vec<f32> {
+    let max = logits
+        .iter()
+        .fold(f32::NEG_INFINITY, |a, b| f32::max(a, *b));
+    let exps = logits
+        .iter()
+        .map(|l| ((l - max) / temperature).exp())
+        .collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f32>();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// KL divergence from the distributions of a teacher (Given as logits) to the ones of
+/// the input, both softened by a temperature. The loss is scaled by the squared
+/// temperature, so that the scale of its gradients doesn't depend on it.
+#[derive(Debug)]
+pub struct KlDivergence {
+    classes: usize,
+    teacher: Tensor<f32>,
+    temperature: f32,
+    weights: Option<Tensor<f32>>,
+}
+impl KlDivergence {
+    pub fn new(classes: usize, teacher: Tensor<f32>, temperature: f32) -> Box<dyn Loss> {
+        Box::new(Self {
+            classes,
+            teacher,
+            temperature,
+            weights: None,
+        })
+    }
+    /// Like `CrossEntropy::weighted`, the weights being normalized
+    pub fn weighted(
+        classes: usize,
+        teacher: Tensor<f32>,
+        temperature: f32,
+        weights: Tensor<f32>,
+    ) -> Box<dyn Loss> {
+        Box::new(Self {
+            classes,
+            teacher,
+            temperature,
+            weights: Some(weights),
+        })
+    }
+}
+
+impl Loss for KlDivergence {
+    fn run(&self, inp: &Tensor<f32>) -> Result<(Tensor<f32>, Tensor<f32>), TensorError> {
+        if self.teacher.size() != inp.size() {
+            return Err(TensorError::shape_mismatch(
+                "KlDivergence",
+                inp.shape(),
+                self.teacher.shape(),
+            ));
+        }
+        let grad_shape = inp.shape().to_vec();
+        let mut loss_shape = grad_shape.clone();
+        loss_shape.pop();
+        let rows = inp.size() / self.classes;
+        let weights = match &self.weights {
+            Some(weights) if weights.size() != rows => {
+                return Err(TensorError::shape_mismatch(
+                    "KlDivergence",
+                    &[rows],
+                    weights.shape(),
+                ));
+            }
+            Some(weights) => {
+                let total = weights.blob().iter().sum::<f32>();
+                let coeff = if total > 0. { rows as f32 / total } else { 0. };
+                weights.blob().iter().map(|w| w * coeff).collect()
+            }
+            None => vec![1.; rows],
+        };
+        let t = self.temperature;
+        let mut loss = Vec::with_capacity(rows);
+        let mut grad = Vec::with_capacity(inp.size());
+        for ((o, teacher), w) in inp
+            .blob()
+            .chunks(self.classes)
+            .zip(self.teacher.blob().chunks(self.classes))
+            .zip(weights)
+        {
+            let p = soft(teacher, t);
+            let q = soft(o, t);
+            let kl = p
+                .iter()
+                .zip(q.iter())
+                .filter(|(p, _)| **p > 0.)
+                .map(|(p, q)| p * (p.ln() - q.max(f32::MIN_POSITIVE).ln()))
+                .sum::<f32>();
+            loss.push(kl * t * t * w);
+            grad.extend(p.iter().zip(q.iter()).map(|(p, q)| (q - p) * t * w));
+        }
+        Ok((
+            Tensor::raw(&loss_shape, loss)?,
+            Tensor::raw(&grad_shape, grad)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kl_divergence() {
+        let inp = Tensor::raw(&[2, 3], vec![1., 2., 0.5, -1., 3., 0.]).unwrap();
+        let (loss, _) = KlDivergence::new(3, inp.clone(), 2.).run(&inp).unwrap();
+        assert!(loss.blob().iter().all(|l| l.abs() < 1e-6));
+
+        // The gradients are the ones of the loss
+        let teacher = Tensor::raw(&[2, 3], vec![0., 1., 2., 2., -1., 0.5]).unwrap();
+        let kl = KlDivergence::new(3, teacher, 2.);
+        let (loss, grad) = kl.run(&inp).unwrap();
+        assert!(loss.blob().iter().all(|l| *l > 0.));
+        for i in 0..inp.size() {
+            let mut blob = inp.blob().to_vec();
+            blob[i] += 1e-2;
+            let plus = kl
+                .run(&Tensor::raw(&[2, 3], blob.clone()).unwrap())
+                .unwrap();
+            blob[i] -= 2e-2;
+            let minus = kl.run(&Tensor::raw(&[2, 3], blob).unwrap()).unwrap();
+            let numeric = (plus.0.blob()[i / 3] - minus.0.blob()[i / 3]) / 2e-2;
+            assert!((numeric - grad.blob()[i]).abs() < 1e-3);
+        }
+    }
+}
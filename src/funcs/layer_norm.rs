@@ -1,4 +1,4 @@
-use super::Function;
+use super::{rows_f64, to_f64, Function};
 use crate::tensor::*;
 
 #[derive(Debug, Clone)]
@@ -15,20 +15,28 @@ impl LayerNorm {
 
 const EPSILON: f32 = 1e-5;
 
+fn normalize_f64(row: &[f64]) -> Vec<f64> {
+    let n = row.len() as f64;
+    let avg = row.iter().sum::<f64>() / n;
+    let var = row.iter().map(|f| (f - avg).powi(2)).sum::<f64>() / n;
+    let std_inv = 1. / (var + EPSILON as f64).sqrt();
+    row.iter().map(|f| (f - avg) * std_inv).collect()
+}
+
 impl Function for LayerNorm {
+    fn name(&self) -> &'static str {
+        "layer_norm"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
-        self.norm = inps[0].map(1, |l| {
-            let size_inv = 1. / l.size() as f32;
-            let avg = l.blob().iter().sum::<f32>() * size_inv;
-            let var = (l.blob().iter().map(|f| (f - avg).powi(2)).sum::<f32>() * size_inv
-                + EPSILON)
-                .sqrt();
-            let var_inv = 1. / var;
-            Tensor::raw(
-                l.shape(),
-                l.blob().iter().map(|v| (v - avg) * var_inv).collect(),
-            )
-        })?;
+        let last = inps[0]
+            .dim()
+            .checked_sub(1)
+            .ok_or(TensorError::unexpected_shape("LayerNorm", inps[0].shape()))?;
+        let avg = inps[0].mean_axis(last, true)?;
+        let std_inv = inps[0]
+            .var_axis(last, true)?
+            .map_values(|v| 1. / (v + EPSILON).sqrt());
+        self.norm = (&(inps[0] - &avg)? * &std_inv)?;
         &(&self.norm * inps[1])? + inps[2]
     }
     fn grad(
@@ -41,7 +49,7 @@ impl Function for LayerNorm {
             .inners()
             .iter()
             .zip(out_grad.keep_right(1)?.inners().iter())
-            .map(|(l, o)| {
+            .flat_map(|(l, o)| {
                 let l_blob = l.blob();
                 let o_blob = o.blob();
                 let inp1_blob = inps[1].blob();
@@ -72,7 +80,6 @@ impl Function for LayerNorm {
                 }
                 data
             })
-            .flatten()
             .collect::<Vec<_>>();
         Ok(vec![
             Tensor::raw(out_grad.shape(), grad_inp0)?,
@@ -80,6 +87,60 @@ impl Function for LayerNorm {
             out_grad.clone(),
         ])
     }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        let (coeff, bias) = (to_f64(inps[1]), to_f64(inps[2]));
+        let rows = rows_f64(inps[0])?;
+        if rows[0].len() != coeff.len() || coeff.len() != bias.len() {
+            return None;
+        }
+        Some(
+            rows.iter()
+                .flat_map(|r| {
+                    normalize_f64(r)
+                        .into_iter()
+                        .zip(coeff.iter().zip(bias.iter()))
+                        .map(|(n, (c, b))| n * c + b)
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+        )
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        let coeff = to_f64(inps[1]);
+        let rows = rows_f64(inps[0])?;
+        if rows[0].len() != coeff.len() || inps[0].shape() != out_grad.shape() {
+            return None;
+        }
+        let mut grad_inp = Vec::with_capacity(inps[0].size());
+        let mut grad_coeff = Vec::with_capacity(inps[0].size());
+        for (r, g) in rows.iter().zip(rows_f64(out_grad)?) {
+            let n = r.len() as f64;
+            let avg = r.iter().sum::<f64>() / n;
+            let var = r.iter().map(|f| (f - avg).powi(2)).sum::<f64>() / n;
+            let std_inv = 1. / (var + EPSILON as f64).sqrt();
+            let norm = normalize_f64(r);
+            let scaled = g
+                .iter()
+                .zip(coeff.iter())
+                .map(|(g, c)| g * c)
+                .collect::<Vec<_>>();
+            let mean = scaled.iter().sum::<f64>() / n;
+            let mean_norm = scaled
+                .iter()
+                .zip(norm.iter())
+                .map(|(s, x)| s * x)
+                .sum::<f64>()
+                / n;
+            grad_inp.extend(
+                scaled
+                    .iter()
+                    .zip(norm.iter())
+                    .map(|(s, x)| std_inv * (s - mean - x * mean_norm)),
+            );
+            grad_coeff.extend(g.iter().zip(norm).map(|(g, x)| g * x));
+        }
+        Some(vec![grad_inp, grad_coeff, to_f64(out_grad)])
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
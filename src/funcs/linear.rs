@@ -0,0 +1,137 @@
+use super::gelu::{gelu, gelu_prime};
+use super::registry::OpParams;
+use super::silu::sigmoid;
+use super::{Function, MatMul};
+use crate::tensor::*;
+
+/// Elementwise functions a `Linear` may end with (Those of `gpt::Activation`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinearActivation {
+    Gelu,
+    Relu,
+    Silu,
+    Tanh,
+}
+
+impl LinearActivation {
+    pub const ALL: [Self; 4] = [Self::Gelu, Self::Relu, Self::Silu, Self::Tanh];
+
+    /// The activation computed by the function registered as `name`
+    pub fn by_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.name() == name)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gelu => "gelu",
+            Self::Relu => "relu",
+            Self::Silu => "silu",
+            Self::Tanh => "tanh",
+        }
+    }
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            Self::Gelu => gelu(x),
+            Self::Relu => {
+                if x > 0. {
+                    x
+                } else {
+                    0.01 * x
+                }
+            }
+            Self::Silu => x * sigmoid(x),
+            Self::Tanh => x.tanh(),
+        }
+    }
+    fn derivative(&self, x: f32) -> f32 {
+        match self {
+            Self::Gelu => gelu_prime(x),
+            Self::Relu => {
+                if x > 0. {
+                    1.
+                } else {
+                    0.01
+                }
+            }
+            Self::Silu => {
+                let s = sigmoid(x);
+                s + x * s * (1. - s)
+            }
+            Self::Tanh => 1. - x.tanh().powi(2),
+        }
+    }
+}
+
+/// `activation(x * w + b)` for inputs `x`, `w` and `b`, the bias and the activation
+/// being applied in place, which `Graph::fuse` replaces a `MatMul` feeding an `Add`
+/// (Feeding an elementwise activation, or not) with
+#[derive(Debug, Clone)]
+pub struct Linear {
+    activation: Option<LinearActivation>,
+    /// `x * w + b`, before the activation
+    pre: Tensor<f32>,
+    product_shape: Vec<usize>,
+}
+impl Linear {
+    pub fn new(activation: Option<LinearActivation>) -> Box<dyn Function> {
+        Box::new(Self {
+            activation,
+            pre: Tensor::scalar(0.),
+            product_shape: Vec::new(),
+        })
+    }
+}
+impl Function for Linear {
+    fn name(&self) -> &'static str {
+        "linear"
+    }
+    fn params(&self) -> OpParams {
+        // The index of the activation among `LinearActivation::ALL`, if any
+        let index = self
+            .activation
+            .and_then(|a| LinearActivation::ALL.iter().position(|b| *b == a));
+        OpParams::scalars(&index.map(|i| i as f32).into_iter().collect::<Vec<_>>())
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
+        let mut product = (inps[0] ^ inps[1])?;
+        self.product_shape = product.shape().to_vec();
+        // Biases of the width of the rows (As usual) are added in place
+        self.pre = if inps[2].dim() == 1 && product.shape().last() == Some(&inps[2].size()) {
+            let bias = inps[2].blob();
+            for row in product.blob_mut().chunks_mut(bias.len().max(1)) {
+                row.iter_mut().zip(bias).for_each(|(v, b)| *v += b);
+            }
+            product
+        } else {
+            (&product + inps[2])?
+        };
+        Ok(match self.activation {
+            Some(a) => self.pre.map_values(|f| a.apply(f)),
+            None => self.pre.clone(),
+        })
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let pre_grad = match self.activation {
+            Some(a) => {
+                let dat = self
+                    .pre
+                    .blob()
+                    .iter()
+                    .zip(out_grad.blob().iter())
+                    .map(|(p, g)| a.derivative(*p) * g)
+                    .collect::<Vec<_>>();
+                Tensor::raw(out_grad.shape(), dat)?
+            }
+            None => out_grad.clone(),
+        };
+        let mut grads = MatMul.grad(&inps[..2], &pre_grad.sum_to(&self.product_shape)?)?;
+        grads.push(pre_grad.sum_to(inps[2].shape())?);
+        Ok(grads)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
@@ -1,3 +1,4 @@
+use super::registry::OpParams;
 use super::Function;
 use crate::tensor::*;
 
@@ -18,6 +19,15 @@ impl Mask {
 }
 
 impl Function for Mask {
+    fn name(&self) -> &'static str {
+        "mask"
+    }
+    fn params(&self) -> OpParams {
+        OpParams {
+            scalars: vec![self.value],
+            tensors: vec![self.mask.clone()],
+        }
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         inps[0].map(self.mask.dim(), |t| {
             let dat = t
@@ -26,7 +36,7 @@ impl Function for Mask {
                 .zip(self.mask.blob().iter())
                 .map(|(v, m)| if *m == 1. { self.value } else { *v })
                 .collect::<Vec<_>>();
-            Ok(Tensor::raw(t.shape(), dat)?)
+            Tensor::raw(t.shape(), dat)
         })
     }
     fn grad(
@@ -0,0 +1,76 @@
+use super::registry::OpParams;
+use super::Function;
+use crate::tensor::*;
+
+/// The softmax of the input with the values where `mask` is set replaced by `value`,
+/// in one pass, which `Graph::fuse` replaces a `Mask` feeding a `Softmax` with
+#[derive(Debug, Clone)]
+pub struct MaskedSoftmax {
+    mask: Tensor<f32>,
+    value: f32,
+    out: Tensor<f32>,
+}
+impl MaskedSoftmax {
+    pub fn new(mask: Tensor<bool>, value: f32) -> Box<dyn Function> {
+        Box::new(Self {
+            mask: (&mask).into(),
+            value,
+            out: Tensor::scalar(0.),
+        })
+    }
+}
+impl Function for MaskedSoftmax {
+    fn name(&self) -> &'static str {
+        "masked_softmax"
+    }
+    fn params(&self) -> OpParams {
+        OpParams {
+            scalars: vec![self.value],
+            tensors: vec![self.mask.clone()],
+        }
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
+        let n = self.mask.shape().last().copied().unwrap_or(1).max(1);
+        self.out = inps[0].map(self.mask.dim(), |t| {
+            let mut dat = Vec::with_capacity(t.size());
+            for (row, mask) in t.blob().chunks(n).zip(self.mask.blob().chunks(n)) {
+                let masked = row
+                    .iter()
+                    .zip(mask)
+                    .map(|(v, m)| if *m == 1. { self.value } else { *v });
+                let max = masked.clone().fold(f32::NEG_INFINITY, f32::max);
+                let sum = masked.clone().map(|f| (f - max).exp()).sum::<f32>();
+                dat.extend(masked.map(|f| (f - max).exp() / sum));
+            }
+            Tensor::raw(t.shape(), dat)
+        })?;
+        Ok(self.out.clone())
+    }
+    fn grad(
+        &self,
+        _inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let n = self.mask.shape().last().copied().unwrap_or(1).max(1);
+        let mut dat = Vec::with_capacity(out_grad.size());
+        for ((s, g), m) in self
+            .out
+            .blob()
+            .chunks(n)
+            .zip(out_grad.blob().chunks(n))
+            .zip(self.mask.blob().chunks(n).cycle())
+        {
+            let dot = s.iter().zip(g).map(|(s, g)| s * g).sum::<f32>();
+            dat.extend(
+                s.iter()
+                    .zip(g)
+                    .zip(m)
+                    .map(|((s, g), m)| if *m == 1. { 0. } else { s * (g - dot) }),
+            );
+        }
+        Ok(vec![Tensor::raw(out_grad.shape(), dat)?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
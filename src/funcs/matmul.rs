@@ -1,6 +1,52 @@
-use super::Function;
+use super::{to_f64, Function};
 use crate::tensor::*;
 
+/// `a` [m, k] times `b` [k, n]
+fn matmul_f64(a: &[f64], b: &[f64], m: usize, k: usize, n: usize) -> Vec<f64> {
+    let mut out = vec![0.; m * n];
+    for i in 0..m {
+        for p in 0..k {
+            let a = a[i * k + p];
+            for j in 0..n {
+                out[i * n + j] += a * b[p * n + j];
+            }
+        }
+    }
+    out
+}
+
+/// The number of matrices `a` and `b` hold (`b` may be a single one, multiplying all
+/// of those of `a`) and their sizes (`a` being [m, k] and `b` [k, n])
+fn batched_dims(a: &Tensor<f32>, b: &Tensor<f32>) -> Option<(usize, usize, usize, usize)> {
+    let (&[m, k], &[k2, n]) = (a.shape().last_chunk()?, b.shape().last_chunk()?);
+    let batch = a.size() / (m * k).max(1);
+    let batched = b.dim() > 2;
+    (k == k2 && (!batched || b.size() == batch * k * n)).then_some((batch, m, k, n))
+}
+
+/// The `i`th chunk of `size` values of `v`, or all of it if it holds only one
+fn batch_of(v: &[f64], i: usize, size: usize) -> &[f64] {
+    if v.len() == size {
+        v
+    } else {
+        &v[i * size..(i + 1) * size]
+    }
+}
+
+fn transpose_f64(a: &[f64], rows: usize, cols: usize) -> Vec<f64> {
+    (0..cols)
+        .flat_map(|j| (0..rows).map(move |i| a[i * cols + j]))
+        .collect()
+}
+
+/// `t` with its last two dimensions swapped, without copying it
+fn transposed(t: &Tensor<f32>) -> Result<StridedView<'_, f32>, TensorError> {
+    if t.dim() < 2 {
+        return Err(TensorError::unexpected_shape("matmul", t.shape()));
+    }
+    t.strided().transpose(t.dim() - 2, t.dim() - 1)
+}
+
 #[derive(Debug, Clone)]
 pub struct MatMul;
 impl MatMul {
@@ -9,6 +55,9 @@ impl MatMul {
     }
 }
 impl Function for MatMul {
+    fn name(&self) -> &'static str {
+        "matmul"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         inps[0] ^ inps[1]
     }
@@ -18,10 +67,37 @@ impl Function for MatMul {
         out_grad: &Tensor<f32>,
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
         Ok(vec![
-            (out_grad ^ &inps[1].transpose()?)?,
-            (&inps[0].transpose()? ^ out_grad)?,
+            out_grad.strided().matmul(&transposed(inps[1])?)?,
+            transposed(inps[0])?.matmul(&out_grad.strided())?,
         ])
     }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        let (batch, m, k, n) = batched_dims(inps[0], inps[1])?;
+        let (a, b) = (to_f64(inps[0]), to_f64(inps[1]));
+        Some(
+            (0..batch)
+                .flat_map(|i| matmul_f64(batch_of(&a, i, m * k), batch_of(&b, i, k * n), m, k, n))
+                .collect(),
+        )
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        let (batch, m, k, n) = batched_dims(inps[0], inps[1])?;
+        if out_grad.size() != batch * m * n {
+            return None;
+        }
+        let (a, b, g) = (to_f64(inps[0]), to_f64(inps[1]), to_f64(out_grad));
+        let (mut grad_a, mut grad_b) = (Vec::new(), Vec::new());
+        for i in 0..batch {
+            let (a, b, g) = (
+                batch_of(&a, i, m * k),
+                batch_of(&b, i, k * n),
+                batch_of(&g, i, m * n),
+            );
+            grad_a.extend(matmul_f64(g, &transpose_f64(b, k, n), m, n, k));
+            grad_b.extend(matmul_f64(&transpose_f64(a, m, k), g, k, m, n));
+        }
+        Some(vec![grad_a, grad_b])
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
@@ -1,32 +1,69 @@
+// The functions' `new` hand out `Box<dyn Function>`, ready for `Graph::call`
+#![allow(clippy::new_ret_no_self)]
+
 mod add;
+mod blend;
 mod cat;
 mod coeff;
 mod crossentropy;
 mod dropout;
+mod fake_quant;
 mod gelu;
+mod kldivergence;
 mod layer_norm;
+mod linear;
 mod mask;
+mod masked_softmax;
 mod matmul;
+mod mul;
+#[cfg(test)]
+mod proptests;
+pub mod registry;
 mod relu;
+mod rope;
+mod scaled_add;
+mod sharded;
+mod silu;
 mod softmax;
+mod sparse_matmul;
+mod tanh;
 mod transpose;
 
 pub use add::*;
+pub use blend::*;
 pub use cat::*;
 pub use coeff::*;
 pub use crossentropy::*;
 pub use dropout::*;
+pub use fake_quant::*;
 pub use gelu::*;
+pub use kldivergence::*;
 pub use layer_norm::*;
+pub use linear::*;
 pub use mask::*;
+pub use masked_softmax::*;
 pub use matmul::*;
+pub use mul::*;
 pub use relu::*;
+pub use rope::*;
+pub use scaled_add::*;
+pub use sharded::*;
+pub use silu::*;
 pub use softmax::*;
+pub use sparse_matmul::*;
+pub use tanh::*;
 pub use transpose::*;
 
 use super::tensor::*;
+use registry::OpParams;
 
 pub trait Function: std::fmt::Debug {
+    /// Name the function is registered under (See `registry`)
+    fn name(&self) -> &'static str;
+    /// Parameters the factory registered for the function rebuilds it from
+    fn params(&self) -> OpParams {
+        OpParams::default()
+    }
     fn clone_box(&self) -> Box<dyn Function>;
     fn run(&mut self, inps: &[&Tensor<f32>], training: bool) -> Result<Tensor<f32>, TensorError>;
     fn grad(
@@ -34,8 +71,193 @@ pub trait Function: std::fmt::Debug {
         inps: &[&Tensor<f32>],
         out_grad: &Tensor<f32>,
     ) -> Result<Vec<Tensor<f32>>, TensorError>;
+    /// The output of `run` computed in f64 from the same inputs, for finding the
+    /// functions losing the most precision (See `Graph::set_numerics_check`). None for
+    /// the functions without one, or inputs it doesn't handle.
+    fn run_f64(&self, _inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        None
+    }
+    /// The gradients of `grad` computed in f64, as `run_f64`
+    fn grad_f64(&self, _inps: &[&Tensor<f32>], _out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        None
+    }
 }
 
 pub trait Loss: std::fmt::Debug {
     fn run(&self, inp: &Tensor<f32>) -> Result<(Tensor<f32>, Tensor<f32>), TensorError>;
+    /// Name the loss is reported under by the numerics checks
+    fn name(&self) -> &'static str {
+        "loss"
+    }
+    /// The losses and the gradient of `run` computed in f64 (See `Function::run_f64`)
+    fn run_f64(&self, _inp: &Tensor<f32>) -> Option<(Vec<f64>, Vec<f64>)> {
+        None
+    }
+}
+
+fn to_f64(t: &Tensor<f32>) -> Vec<f64> {
+    t.blob().iter().map(|v| *v as f64).collect()
+}
+
+/// The rows of the last dimension of `t` (Whose dimension has to be at least one)
+fn rows_f64(t: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+    let n = *t.shape().last()?;
+    Some(to_f64(t).chunks(n.max(1)).map(|r| r.to_vec()).collect())
+}
+
+/// Gradient of an elementwise function whose derivative is `der`
+fn elementwise_grad_f64<F: Fn(f64) -> f64>(
+    inp: &Tensor<f32>,
+    out_grad: &Tensor<f32>,
+    der: F,
+) -> Option<Vec<Vec<f64>>> {
+    (inp.shape() == out_grad.shape()).then(|| {
+        vec![to_f64(inp)
+            .into_iter()
+            .zip(to_f64(out_grad))
+            .map(|(x, g)| g * der(x))
+            .collect()]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Compares the gradients computed by `f` against central finite differences
+    /// of the (Random) linear functional `sum(out * r)`
+    fn check_grads(mut f: Box<dyn Function>, inps: &[Tensor<f32>]) {
+        const EPSILON: f32 = 1e-2;
+        let mut rng = StdRng::seed_from_u64(0);
+        let refs = inps.iter().collect::<Vec<_>>();
+        let out = f.run(&refs, false).unwrap();
+        let r = Tensor::<f32>::rand_range(&mut rng, -1., 1., out.shape());
+        let loss = |f: &mut Box<dyn Function>, inps: &[Tensor<f32>]| {
+            let refs = inps.iter().collect::<Vec<_>>();
+            let out = f.run(&refs, false).unwrap();
+            out.blob()
+                .iter()
+                .zip(r.blob().iter())
+                .map(|(o, r)| o * r)
+                .sum::<f32>()
+        };
+        f.run(&refs, false).unwrap();
+        let grads = f.grad(&refs, &r).unwrap();
+        for (i, inp) in inps.iter().enumerate() {
+            let mut numeric = Vec::with_capacity(inp.size());
+            for j in 0..inp.size() {
+                let mut perturbed = inps.to_vec();
+                perturbed[i].blob_mut()[j] += EPSILON;
+                let plus = loss(&mut f, &perturbed);
+                perturbed[i].blob_mut()[j] -= 2. * EPSILON;
+                let minus = loss(&mut f, &perturbed);
+                numeric.push((plus - minus) / (2. * EPSILON));
+            }
+            let numeric = Tensor::raw(inp.shape(), numeric).unwrap();
+            assert_close(&grads[i].sum_to(inp.shape()).unwrap(), &numeric, 1e-2, 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_function_grads() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut rand = |shape: &[usize]| Tensor::<f32>::rand_range(&mut rng, -1., 1., shape);
+        check_grads(Add::new(), &[rand(&[2, 3]), rand(&[3])]);
+        check_grads(Mul::new(), &[rand(&[2, 1, 3]), rand(&[2, 2, 3])]);
+        check_grads(Coeff::new(0.5), &[rand(&[4])]);
+        check_grads(Gelu::new(), &[rand(&[2, 3])]);
+        check_grads(Silu::new(), &[rand(&[2, 3])]);
+        check_grads(Tanh::new(), &[rand(&[2, 3])]);
+        check_grads(Rope::new(10000.), &[rand(&[2, 3, 4])]);
+        check_grads(Softmax::new(), &[rand(&[2, 4])]);
+        check_grads(MatMul::new(), &[rand(&[2, 3]), rand(&[3, 4])]);
+        check_grads(Transpose::new(), &[rand(&[2, 3])]);
+        check_grads(Cat::new(), &[rand(&[2, 3]), rand(&[2, 3])]);
+        check_grads(LayerNorm::new(), &[rand(&[2, 4]), rand(&[4]), rand(&[4])]);
+    }
+
+    #[test]
+    fn test_fake_quant() {
+        let t = Tensor::vector(&[1.27, -0.5, 0.004, f32::NEG_INFINITY]);
+        let q = fake_quantize(&t, 8);
+        assert!((q.blob()[0] - 1.27).abs() < 1e-6);
+        assert!((q.blob()[1] + 0.5).abs() < 1e-6);
+        assert_eq!(q.blob()[2], 0.);
+        assert_eq!(q.blob()[3], f32::NEG_INFINITY);
+
+        // The name and the gradient are those of the wrapped function
+        let mut f = FakeQuant::wrap(Coeff::new(2.), 2);
+        assert_eq!(f.name(), "coeff");
+        let out = f.run(&[&Tensor::vector(&[1., 0.4, -0.6])], false).unwrap();
+        assert_eq!(out.blob(), &[2., 0., -2.]);
+        let grad = f.grad(
+            &[&Tensor::vector(&[1., 0.4, -0.6])],
+            &Tensor::vector(&[1., 1., 1.]),
+        );
+        assert_eq!(grad.unwrap()[0].blob(), &[2., 2., 2.]);
+    }
+
+    #[test]
+    fn test_sharded() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (a, b) = (
+            Tensor::<f32>::rand(&mut rng, &[3, 5]),
+            Tensor::<f32>::rand(&mut rng, &[5, 7]),
+        );
+        let g = Tensor::<f32>::rand(&mut rng, &[3, 7]);
+        let expected = MatMul::new().run(&[&a, &b], false).unwrap();
+        let expected_grads = MatMul::new().grad(&[&a, &b], &g).unwrap();
+        for sharding in [Sharding::Columns, Sharding::Rows] {
+            // More shards than columns or rows too
+            for shards in [1, 2, 3, 8] {
+                let mut f = Sharded::wrap(MatMul::new(), sharding, shards);
+                assert_eq!(f.name(), "matmul");
+                let out = f.run(&[&a, &b], false).unwrap();
+                assert_eq!(out.shape(), expected.shape());
+                for (x, y) in out.blob().iter().zip(expected.blob()) {
+                    assert!((x - y).abs() < 1e-5);
+                }
+                let grads = f.grad(&[&a, &b], &g).unwrap();
+                for (grad, expected) in grads.iter().zip(expected_grads.iter()) {
+                    assert_eq!(grad.blob(), expected.blob());
+                }
+            }
+        }
+        // Batched products are left to the wrapped function
+        let batched = Tensor::<f32>::rand(&mut rng, &[2, 3, 5]);
+        let mut f = Sharded::wrap(MatMul::new(), Sharding::Columns, 2);
+        assert_eq!(
+            f.run(&[&batched, &b], false).unwrap().blob(),
+            MatMul::new().run(&[&batched, &b], false).unwrap().blob()
+        );
+    }
+
+    #[test]
+    fn test_no_copies() {
+        use crate::fixtures::{allocated, tensor};
+        // Like the products of the attention, over the heads
+        let (a, b, g) = (
+            tensor(&[4, 16, 8]),
+            tensor(&[4, 8, 16]),
+            tensor(&[4, 16, 16]),
+        );
+        let bytes = |ts: &[&Tensor<f32>]| ts.iter().map(|t| t.size() * 4).sum::<usize>();
+        // Just the results, and a few shapes and reference counts (Copies of the
+        // transposed inputs alone would take 4KB)
+        let (out, used) = allocated(|| MatMul::new().run(&[&a, &b], false).unwrap());
+        assert!(used < bytes(&[&out]) + 1024);
+        let (grads, used) = allocated(|| MatMul::new().grad(&[&a, &b], &g).unwrap());
+        assert!(used < bytes(&[&grads[0], &grads[1]]) + 1024);
+        let (out, used) = allocated(|| Transpose::new().run(&[&a], false).unwrap());
+        assert!(used < bytes(&[&out]) + 1024);
+        assert_eq!(
+            out.blob(),
+            a.strided()
+                .transpose(1, 2)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+    }
 }
@@ -0,0 +1,54 @@
+use super::Function;
+use crate::tensor::*;
+
+/// Elementwise product of two (Broadcastable) tensors
+#[derive(Debug, Clone)]
+pub struct Mul;
+impl Mul {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+impl Function for Mul {
+    fn name(&self) -> &'static str {
+        "mul"
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
+        inps[0] * inps[1]
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        Ok(vec![
+            (out_grad * inps[1])?.sum_to(inps[0].shape())?,
+            (out_grad * inps[0])?.sum_to(inps[1].shape())?,
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mul_broadcast_grad() {
+        let a = Tensor::raw(&[2, 1, 3], vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let b = Tensor::raw(&[2, 2, 3], (0..12).map(|i| i as f32).collect()).unwrap();
+        let mut mul = Mul::new();
+        let out = mul.run(&[&a, &b], true).unwrap();
+        assert_eq!(out.shape(), &[2, 2, 3]);
+        assert_eq!(&out.blob()[..6], &[0., 2., 6., 3., 8., 15.]);
+
+        let grads = mul.grad(&[&a, &b], &Tensor::ones(&[2, 2, 3])).unwrap();
+        assert_eq!(grads[0].shape(), a.shape());
+        assert_eq!(grads[0].blob(), &[3., 5., 7., 15., 17., 19.]);
+        assert_eq!(grads[1].shape(), b.shape());
+        assert_eq!(&grads[1].blob()[..6], &[1., 2., 3., 1., 2., 3.]);
+        assert!(mul.run(&[&a, &Tensor::zeros(&[2])], true).is_err());
+    }
+}
@@ -0,0 +1,468 @@
+//! Property-based checks of every `Function`: on random shapes and values, the
+//! gradients have to match central finite differences, and a function cloned with
+//! `clone_box` (After a run), or rebuilt by the registry, has to behave exactly like
+//! the original.
+//!
+//! New functions are added to `ops`, with a strategy generating valid inputs
+//! for them. `test_ops_are_complete` fails until they are.
+
+use super::*;
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestRunner};
+use rand::{rngs::StdRng, SeedableRng};
+
+/// Random cases tried per function
+const CASES: u32 = 32;
+/// Step of the finite differences
+const EPSILON: f32 = 1e-2;
+/// Largest size of a dimension
+const MAX_DIM: usize = 4;
+
+/// How far the analytic gradients may be from the finite differences. The defaults
+/// suit smooth functions of values in [-1, 1], evaluated in f32.
+#[derive(Debug, Clone, Copy)]
+struct Tolerance {
+    rtol: f32,
+    atol: f32,
+}
+
+const DEFAULT_TOLERANCE: Tolerance = Tolerance {
+    rtol: 1e-2,
+    atol: 1e-2,
+};
+
+/// A function, along with inputs it accepts
+#[derive(Debug)]
+struct Case {
+    func: Box<dyn Function>,
+    inputs: Vec<Tensor<f32>>,
+}
+
+impl Clone for Case {
+    fn clone(&self) -> Self {
+        Self {
+            func: self.func.clone_box(),
+            inputs: self.inputs.clone(),
+        }
+    }
+}
+
+/// A function under test: the name of its module, and how to generate cases for it
+struct Op {
+    name: &'static str,
+    cases: BoxedStrategy<Case>,
+    tolerance: Tolerance,
+}
+
+impl Op {
+    fn new<S: Strategy<Value = Case> + 'static>(name: &'static str, cases: S) -> Self {
+        Self {
+            name,
+            cases: cases.boxed(),
+            tolerance: DEFAULT_TOLERANCE,
+        }
+    }
+
+    fn tolerance(mut self, rtol: f32, atol: f32) -> Self {
+        self.tolerance = Tolerance { rtol, atol };
+        self
+    }
+}
+
+/// Shapes of `min_rank` to `max_rank` dimensions
+fn shape(min_rank: usize, max_rank: usize) -> impl Strategy<Value = Vec<usize>> {
+    prop::collection::vec(1..=MAX_DIM, min_rank..=max_rank)
+}
+
+/// A tensor of the given shape, with values in [-1, 1]
+fn tensor(shape: Vec<usize>) -> impl Strategy<Value = Tensor<f32>> {
+    let size = shape.iter().product::<usize>();
+    prop::collection::vec(-1f32..=1., size).prop_map(move |v| Tensor::raw(&shape, v).unwrap())
+}
+
+/// Like `tensor`, but keeping `margin` away from zero (Where some functions have a
+/// kink the finite differences would step over)
+fn tensor_off_zero(shape: Vec<usize>, margin: f32) -> impl Strategy<Value = Tensor<f32>> {
+    let size = shape.iter().product::<usize>();
+    prop::collection::vec((margin..=1f32, any::<bool>()), size).prop_map(move |v| {
+        let v = v.into_iter().map(|(x, neg)| if neg { -x } else { x });
+        Tensor::raw(&shape, v.collect()).unwrap()
+    })
+}
+
+/// A shape `other` broadcasts to: some of its dimensions set to 1, and possibly
+/// fewer leading dimensions
+fn broadcastable(shape: Vec<usize>) -> impl Strategy<Value = Vec<usize>> {
+    let rank = shape.len();
+    (
+        0..=rank.saturating_sub(1),
+        prop::collection::vec(any::<bool>(), rank),
+    )
+        .prop_map(move |(skip, ones)| {
+            shape
+                .iter()
+                .zip(ones)
+                .skip(skip)
+                .map(|(d, one)| if one { 1 } else { *d })
+                .collect()
+        })
+}
+
+fn unary(
+    func: fn() -> Box<dyn Function>,
+    inputs: impl Strategy<Value = Tensor<f32>>,
+) -> impl Strategy<Value = Case> {
+    inputs.prop_map(move |x| Case {
+        func: func(),
+        inputs: vec![x],
+    })
+}
+
+fn binary_broadcast(func: fn() -> Box<dyn Function>) -> impl Strategy<Value = Case> {
+    shape(1, 3)
+        .prop_flat_map(|s| (Just(s.clone()), broadcastable(s)))
+        .prop_flat_map(|(a, b)| (tensor(a), tensor(b)))
+        .prop_flat_map(|(a, b)| {
+            // Either side may be the smaller one
+            (Just(a), Just(b), any::<bool>())
+        })
+        .prop_map(move |(a, b, swap)| Case {
+            func: func(),
+            inputs: if swap { vec![b, a] } else { vec![a, b] },
+        })
+}
+
+/// Every `Function` of the crate
+fn ops() -> Vec<Op> {
+    vec![
+        Op::new("add", binary_broadcast(Add::new)),
+        Op::new("mul", binary_broadcast(Mul::new)),
+        Op::new(
+            "coeff",
+            (-2f32..=2., shape(1, 3).prop_flat_map(tensor)).prop_map(|(c, x)| Case {
+                func: Coeff::new(c),
+                inputs: vec![x],
+            }),
+        ),
+        Op::new("gelu", unary(Gelu::new, shape(1, 3).prop_flat_map(tensor))),
+        Op::new("silu", unary(Silu::new, shape(1, 3).prop_flat_map(tensor))),
+        Op::new("tanh", unary(Tanh::new, shape(1, 3).prop_flat_map(tensor))),
+        Op::new(
+            "relu",
+            unary(
+                Relu::new,
+                shape(1, 3).prop_flat_map(|s| tensor_off_zero(s, 2. * EPSILON)),
+            ),
+        ),
+        Op::new(
+            "rope",
+            (1f32..=10000., shape(2, 3).prop_flat_map(tensor)).prop_map(|(base, x)| Case {
+                func: Rope::new(base),
+                inputs: vec![x],
+            }),
+        ),
+        Op::new(
+            "softmax",
+            unary(Softmax::new, shape(1, 3).prop_flat_map(tensor)),
+        ),
+        Op::new(
+            "transpose",
+            unary(Transpose::new, shape(2, 3).prop_flat_map(tensor)),
+        ),
+        Op::new(
+            "matmul",
+            (
+                prop::option::of(1..=MAX_DIM),
+                1..=MAX_DIM,
+                1..=MAX_DIM,
+                1..=MAX_DIM,
+                any::<bool>(),
+            )
+                .prop_flat_map(|(batch, m, k, n, batched_rhs)| {
+                    let lhs = batch.into_iter().chain([m, k]).collect::<Vec<_>>();
+                    let rhs = batch
+                        .filter(|_| batched_rhs)
+                        .into_iter()
+                        .chain([k, n])
+                        .collect::<Vec<_>>();
+                    (tensor(lhs), tensor(rhs))
+                })
+                .prop_map(|(a, b)| Case {
+                    func: MatMul::new(),
+                    inputs: vec![a, b],
+                }),
+        ),
+        Op::new(
+            "sparse_matmul",
+            (
+                prop::collection::vec(1..=MAX_DIM, 1..=2),
+                1..=MAX_DIM,
+                1..=MAX_DIM,
+            )
+                .prop_flat_map(|(rows, k, n)| {
+                    let x = rows.into_iter().chain([k]).collect::<Vec<_>>();
+                    let zeros = prop::collection::vec(any::<bool>(), k * n);
+                    (tensor(x), tensor(vec![k, n]), zeros)
+                })
+                .prop_map(|(x, w, zeros)| {
+                    let values = w
+                        .blob()
+                        .iter()
+                        .zip(zeros)
+                        .map(|(v, zero)| if zero { 0. } else { *v })
+                        .collect();
+                    Case {
+                        func: SparseMatMul::new(),
+                        inputs: vec![x, Tensor::raw(w.shape(), values).unwrap()],
+                    }
+                }),
+        ),
+        Op::new(
+            "cat",
+            (shape(1, 3), 1..=3usize)
+                .prop_flat_map(|(s, n)| prop::collection::vec(tensor(s), n))
+                .prop_map(|inputs| Case {
+                    func: Cat::new(),
+                    inputs,
+                }),
+        ),
+        Op::new(
+            "layer_norm",
+            (shape(0, 2), 3..=6usize)
+                .prop_flat_map(|(outer, n)| {
+                    let s = outer.into_iter().chain([n]).collect::<Vec<_>>();
+                    (tensor(s), tensor(vec![n]), tensor(vec![n]))
+                })
+                // Rows of (Nearly) equal values are ill-conditioned
+                .prop_filter("low variance", |(x, _, _)| {
+                    x.keep_right(1)
+                        .unwrap()
+                        .inners()
+                        .iter()
+                        .all(|row| row.var_axis(0, false).unwrap().blob()[0] > 0.05)
+                })
+                .prop_map(|(x, g, b)| Case {
+                    func: LayerNorm::new(),
+                    inputs: vec![x, g, b],
+                }),
+        )
+        .tolerance(5e-2, 5e-2),
+        Op::new(
+            "mask",
+            shape(1, 2)
+                .prop_flat_map(|s| {
+                    let size = s.iter().product::<usize>();
+                    (
+                        tensor(s.clone()),
+                        prop::collection::vec(any::<bool>(), size)
+                            .prop_map(move |m| Tensor::raw(&s, m).unwrap()),
+                        -2f32..=2.,
+                    )
+                })
+                .prop_map(|(x, mask, value)| Case {
+                    func: Mask::new(mask, value),
+                    inputs: vec![x],
+                }),
+        ),
+        Op::new(
+            "scaled_add",
+            (-2f32..=2., shape(1, 3))
+                .prop_flat_map(|(c, s)| (Just(c), Just(s.clone()), broadcastable(s)))
+                .prop_flat_map(|(c, a, b)| (Just(c), tensor(a), tensor(b)))
+                .prop_map(|(c, a, b)| Case {
+                    func: ScaledAdd::new(c),
+                    inputs: vec![a, b],
+                }),
+        ),
+        Op::new(
+            "masked_softmax",
+            shape(1, 2)
+                .prop_flat_map(|s| {
+                    let size = s.iter().product::<usize>();
+                    (
+                        (0..=1usize)
+                            .prop_flat_map(move |lead| prop::collection::vec(1..=MAX_DIM, lead)),
+                        Just(s.clone()),
+                        prop::collection::vec(any::<bool>(), size)
+                            .prop_map(move |m| Tensor::raw(&s, m).unwrap()),
+                        -2f32..=2.,
+                    )
+                })
+                .prop_flat_map(|(lead, s, mask, value)| {
+                    let s = lead.into_iter().chain(s).collect::<Vec<_>>();
+                    (tensor(s), Just(mask), Just(value))
+                })
+                .prop_map(|(x, mask, value)| Case {
+                    func: MaskedSoftmax::new(mask, value),
+                    inputs: vec![x],
+                }),
+        ),
+        Op::new(
+            "linear",
+            (
+                prop::option::of(1..=MAX_DIM),
+                1..=MAX_DIM,
+                1..=MAX_DIM,
+                1..=MAX_DIM,
+                // Not the relu, whose kink the sums fed to it can't be kept off
+                prop::option::of(prop::sample::select(vec![
+                    LinearActivation::Gelu,
+                    LinearActivation::Silu,
+                    LinearActivation::Tanh,
+                ])),
+            )
+                .prop_flat_map(|(batch, m, k, n, activation)| {
+                    let x = batch.into_iter().chain([m, k]).collect::<Vec<_>>();
+                    (
+                        tensor(x),
+                        tensor(vec![k, n]),
+                        tensor(vec![n]),
+                        Just(activation),
+                    )
+                })
+                .prop_map(|(x, w, b, activation)| Case {
+                    func: Linear::new(activation),
+                    inputs: vec![x, w, b],
+                }),
+        )
+        .tolerance(5e-2, 5e-2),
+        Op::new(
+            "dropout",
+            (0f32..0.9, shape(1, 3).prop_flat_map(tensor)).prop_map(|(rate, x)| Case {
+                func: Dropout::new(rate),
+                inputs: vec![x],
+            }),
+        ),
+    ]
+}
+
+/// The gradients of `sum(out * r)` (A random linear functional of the output)
+/// against central finite differences. Functions are evaluated in inference mode,
+/// where they are deterministic.
+fn check_grads(case: &Case, tolerance: Tolerance) -> Result<(), TestCaseError> {
+    let mut f = case.func.clone_box();
+    let refs = case.inputs.iter().collect::<Vec<_>>();
+    let out = f.run(&refs, false).map_err(fail)?;
+    let mut rng = StdRng::seed_from_u64(0);
+    let r = Tensor::<f32>::rand_range(&mut rng, -1., 1., out.shape());
+    let grads = f.grad(&refs, &r).map_err(fail)?;
+    prop_assert_eq!(grads.len(), case.inputs.len());
+
+    let mut loss = |inputs: &[Tensor<f32>]| -> Result<f32, TestCaseError> {
+        let refs = inputs.iter().collect::<Vec<_>>();
+        let out = f.run(&refs, false).map_err(fail)?;
+        Ok(out.blob().iter().zip(r.blob()).map(|(o, r)| o * r).sum())
+    };
+    for (i, inp) in case.inputs.iter().enumerate() {
+        let mut numeric = Vec::with_capacity(inp.size());
+        let mut perturbed = case.inputs.clone();
+        for j in 0..inp.size() {
+            perturbed[i].blob_mut()[j] += EPSILON;
+            let plus = loss(&perturbed)?;
+            perturbed[i].blob_mut()[j] -= 2. * EPSILON;
+            let minus = loss(&perturbed)?;
+            perturbed[i].blob_mut()[j] += EPSILON;
+            numeric.push((plus - minus) / (2. * EPSILON));
+        }
+        let numeric = Tensor::raw(inp.shape(), numeric).unwrap();
+        // Gradients of broadcast inputs are summed by the graph
+        let analytic = grads[i].sum_to(inp.shape()).map_err(fail)?;
+        compare(&analytic, &numeric, tolerance.rtol, tolerance.atol)
+            .map_err(|m| fail(format!("gradient of input {}: {}", i, m)))?;
+    }
+    Ok(())
+}
+
+/// A clone taken after a training run keeps its state (E.g. the mask of a
+/// dropout), so it computes the same gradients, and runs the same afterwards.
+fn check_clone(case: &Case) -> Result<(), TestCaseError> {
+    let mut f = case.func.clone_box();
+    let refs = case.inputs.iter().collect::<Vec<_>>();
+    let out = f.run(&refs, true).map_err(fail)?;
+    let mut g = f.clone_box();
+    let out_grad = Tensor::<f32>::ones(out.shape());
+    let (fg, gg) = (
+        f.grad(&refs, &out_grad).map_err(fail)?,
+        g.grad(&refs, &out_grad).map_err(fail)?,
+    );
+    for (a, b) in fg.iter().zip(gg.iter()) {
+        prop_assert_eq!(a.shape(), b.shape());
+        prop_assert_eq!(a.blob(), b.blob());
+    }
+    let (fo, go) = (
+        f.run(&refs, false).map_err(fail)?,
+        g.run(&refs, false).map_err(fail)?,
+    );
+    prop_assert_eq!(fo.blob(), go.blob());
+    Ok(())
+}
+
+/// The function the registry rebuilds from the name and the parameters of a
+/// function computes the same outputs
+fn check_registry(case: &Case) -> Result<(), TestCaseError> {
+    let mut f = case.func.clone_box();
+    let mut g = registry::create(f.name(), &f.params()).map_err(fail)?;
+    let refs = case.inputs.iter().collect::<Vec<_>>();
+    let (fo, go) = (
+        f.run(&refs, false).map_err(fail)?,
+        g.run(&refs, false).map_err(fail)?,
+    );
+    prop_assert_eq!(fo.shape(), go.shape());
+    prop_assert_eq!(fo.blob(), go.blob());
+    Ok(())
+}
+
+fn fail<E: std::fmt::Display>(e: E) -> TestCaseError {
+    TestCaseError::fail(e.to_string())
+}
+
+#[test]
+fn test_function_properties() {
+    for op in ops() {
+        let mut runner = TestRunner::new(Config {
+            cases: CASES,
+            failure_persistence: None,
+            ..Config::default()
+        });
+        let result = runner.run(&op.cases, |case| {
+            prop_assert_eq!(case.func.name(), op.name);
+            check_grads(&case, op.tolerance)?;
+            check_clone(&case)?;
+            check_registry(&case)
+        });
+        if let Err(e) = result {
+            panic!("{}: {}", op.name, e);
+        }
+    }
+}
+
+/// Every module of `funcs` defining a `Function` has an entry here, and in
+/// `registry`
+#[test]
+fn test_ops_are_complete() {
+    let names = ops().iter().map(|op| op.name).collect::<Vec<_>>();
+    let modules = include_str!("mod.rs")
+        .lines()
+        .filter_map(|l| l.strip_prefix("pub use ")?.strip_suffix("::*;"))
+        // Losses, not `Function`s, and wrappers of the others
+        .filter(|m| {
+            ![
+                "blend",
+                "crossentropy",
+                "kldivergence",
+                "fake_quant",
+                "sharded",
+            ]
+            .contains(m)
+        })
+        .collect::<Vec<_>>();
+    assert!(!modules.is_empty());
+    for module in modules {
+        assert!(names.contains(&module), "{} has no strategy", module);
+        assert!(
+            registry::is_registered(module),
+            "{} is not registered",
+            module
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Registry of the functions a graph may be made of, by name, so that graphs can be
+//! rebuilt from their serialized form (See `Graph::spec`). Crates defining their own
+//! `Function`s register them here with a factory:
+//!
+//! ```ignore
+//! registry::register("swish", |params| Ok(Swish::new(params.scalar(0)?)))?;
+//! ```
+
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("no function registered as '{0}'")]
+    UnknownOp(String),
+    #[error("a function is already registered as '{0}'")]
+    AlreadyRegistered(String),
+    #[error("invalid parameters for '{op}': {reason}")]
+    InvalidParams { op: String, reason: String },
+}
+
+/// The parameters a function is rebuilt from, as returned by `Function::params`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OpParams {
+    pub scalars: Vec<f32>,
+    pub tensors: Vec<Tensor<f32>>,
+}
+
+impl OpParams {
+    pub fn scalars(scalars: &[f32]) -> Self {
+        Self {
+            scalars: scalars.to_vec(),
+            tensors: Vec::new(),
+        }
+    }
+    pub fn scalar(&self, index: usize) -> Result<f32, RegistryError> {
+        self.scalars
+            .get(index)
+            .copied()
+            .ok_or_else(|| self.missing("scalar", index))
+    }
+    pub fn tensor(&self, index: usize) -> Result<&Tensor<f32>, RegistryError> {
+        self.tensors
+            .get(index)
+            .ok_or_else(|| self.missing("tensor", index))
+    }
+    fn missing(&self, kind: &str, index: usize) -> RegistryError {
+        RegistryError::InvalidParams {
+            op: String::new(),
+            reason: format!("missing {} #{}", kind, index),
+        }
+    }
+}
+
+pub type Factory = Arc<dyn Fn(&OpParams) -> Result<Box<dyn Function>, RegistryError> + Send + Sync>;
+
+/// The mask of a function masking its input (Stored as 0s and 1s)
+fn bool_mask(op: &str, p: &OpParams) -> Result<Tensor<bool>, RegistryError> {
+    let mask = p.tensor(0)?;
+    Tensor::raw(mask.shape(), mask.blob().iter().map(|m| *m != 0.).collect()).map_err(|e| {
+        RegistryError::InvalidParams {
+            op: op.into(),
+            reason: e.to_string(),
+        }
+    })
+}
+
+fn registry() -> &'static RwLock<HashMap<String, Factory>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, Factory>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut ops: HashMap<String, Factory> = HashMap::new();
+        let mut add = |name: &str, f: fn(&OpParams) -> Result<Box<dyn Function>, RegistryError>| {
+            ops.insert(name.into(), Arc::new(f));
+        };
+        add("add", |_| Ok(Add::new()));
+        add("cat", |_| Ok(Cat::new()));
+        add("coeff", |p| Ok(Coeff::new(p.scalar(0)?)));
+        add("dropout", |p| Ok(Dropout::new(p.scalar(0)?)));
+        add("gelu", |_| Ok(Gelu::new()));
+        add("layer_norm", |_| Ok(LayerNorm::new()));
+        add("linear", |p| {
+            let activation = match p.scalars.first() {
+                Some(i) => Some(*LinearActivation::ALL.get(*i as usize).ok_or_else(|| {
+                    RegistryError::InvalidParams {
+                        op: "linear".into(),
+                        reason: format!("no activation #{}", i),
+                    }
+                })?),
+                None => None,
+            };
+            Ok(Linear::new(activation))
+        });
+        add("mask", |p| {
+            Ok(Mask::new(bool_mask("mask", p)?, p.scalar(0)?))
+        });
+        add("masked_softmax", |p| {
+            Ok(MaskedSoftmax::new(
+                bool_mask("masked_softmax", p)?,
+                p.scalar(0)?,
+            ))
+        });
+        add("matmul", |_| Ok(MatMul::new()));
+        add("mul", |_| Ok(Mul::new()));
+        add("relu", |_| Ok(Relu::new()));
+        add("rope", |p| Ok(Rope::new(p.scalar(0)?)));
+        add("scaled_add", |p| Ok(ScaledAdd::new(p.scalar(0)?)));
+        add("silu", |_| Ok(Silu::new()));
+        add("softmax", |_| Ok(Softmax::new()));
+        add("sparse_matmul", |_| Ok(SparseMatMul::new()));
+        add("tanh", |_| Ok(Tanh::new()));
+        add("transpose", |_| Ok(Transpose::new()));
+        RwLock::new(ops)
+    })
+}
+
+/// Registers a new kind of function under `name`, which has to be the one its
+/// `Function::name` returns
+pub fn register<F>(name: &str, factory: F) -> Result<(), RegistryError>
+where
+    F: Fn(&OpParams) -> Result<Box<dyn Function>, RegistryError> + Send + Sync + 'static,
+{
+    let mut ops = registry().write().unwrap();
+    if ops.contains_key(name) {
+        return Err(RegistryError::AlreadyRegistered(name.into()));
+    }
+    ops.insert(name.into(), Arc::new(factory));
+    Ok(())
+}
+
+/// Builds the function registered as `name`
+pub fn create(name: &str, params: &OpParams) -> Result<Box<dyn Function>, RegistryError> {
+    let factory = registry()
+        .read()
+        .unwrap()
+        .get(name)
+        .cloned()
+        .ok_or_else(|| RegistryError::UnknownOp(name.into()))?;
+    factory(params).map_err(|e| match e {
+        RegistryError::InvalidParams { op, reason } if op.is_empty() => {
+            RegistryError::InvalidParams {
+                op: name.into(),
+                reason,
+            }
+        }
+        e => e,
+    })
+}
+
+pub fn is_registered(name: &str) -> bool {
+    registry().read().unwrap().contains_key(name)
+}
+
+/// Names of all the registered functions, sorted
+pub fn registered() -> Vec<String> {
+    let mut names = registry()
+        .read()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct Square;
+
+    impl Function for Square {
+        fn name(&self) -> &'static str {
+            "test_square"
+        }
+        fn run(
+            &mut self,
+            inps: &[&Tensor<f32>],
+            _training: bool,
+        ) -> Result<Tensor<f32>, TensorError> {
+            Ok(inps[0].map_values(|f| f * f))
+        }
+        fn grad(
+            &self,
+            inps: &[&Tensor<f32>],
+            out_grad: &Tensor<f32>,
+        ) -> Result<Vec<Tensor<f32>>, TensorError> {
+            Ok(vec![(out_grad * &inps[0].map_values(|f| 2. * f))?])
+        }
+        fn clone_box(&self) -> Box<dyn Function> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        assert!(matches!(
+            create("test_square", &OpParams::default()),
+            Err(RegistryError::UnknownOp(_))
+        ));
+        register("test_square", |_| Ok(Box::new(Square))).unwrap();
+        assert!(register("test_square", |_| Ok(Box::new(Square))).is_err());
+        assert!(is_registered("test_square"));
+        assert!(registered().contains(&"test_square".to_string()));
+
+        let mut f = create("test_square", &OpParams::default()).unwrap();
+        let out = f.run(&[&Tensor::vector(&[3.])], false).unwrap();
+        assert_eq!(out.blob(), &[9.]);
+
+        let err = create("coeff", &OpParams::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid parameters for 'coeff': missing scalar #0"
+        );
+    }
+}
@@ -9,6 +9,9 @@ impl Relu {
     }
 }
 impl Function for Relu {
+    fn name(&self) -> &'static str {
+        "relu"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         Ok(inps[0].map_values(|f| if f > 0. { f } else { 0.01 * f }))
     }
@@ -0,0 +1,106 @@
+use super::registry::OpParams;
+use super::Function;
+use crate::tensor::*;
+
+/// Rotary position embedding: rotates the pairs of features `(2i, 2i + 1)` of the
+/// `p`th row of its input by `p * base^(-2i / d)`, so that the dot products of
+/// rotated queries and keys only depend on their relative positions. (A trailing
+/// feature, when `d` is odd, is left as is)
+#[derive(Debug, Clone)]
+pub struct Rope {
+    base: f32,
+}
+impl Rope {
+    pub fn new(base: f32) -> Box<dyn Function> {
+        Box::new(Self { base })
+    }
+
+    fn rotate(&self, t: &Tensor<f32>, sign: f32) -> Result<Tensor<f32>, TensorError> {
+        rope_rotate(t, self.base, sign, 0)
+    }
+}
+
+/// Rotates the rows of `t` as `Rope` does, the first one being at position `first`
+pub(crate) fn rope_rotate(
+    t: &Tensor<f32>,
+    base: f32,
+    sign: f32,
+    first: usize,
+) -> Result<Tensor<f32>, TensorError> {
+    let shape = t.shape();
+    if shape.len() < 2 {
+        return Err(TensorError::unexpected_shape("rope", shape));
+    }
+    let (rows, d) = (shape[shape.len() - 2], shape[shape.len() - 1]);
+    let mut data = t.blob().to_vec();
+    for (r, row) in data.chunks_mut(d.max(1)).enumerate() {
+        let p = (first + r % rows) as f32;
+        for i in 0..d / 2 {
+            let theta = p * base.powf(-2. * i as f32 / d as f32);
+            let (sin, cos) = (sign * theta).sin_cos();
+            let (x0, x1) = (row[2 * i], row[2 * i + 1]);
+            row[2 * i] = x0 * cos - x1 * sin;
+            row[2 * i + 1] = x0 * sin + x1 * cos;
+        }
+    }
+    Tensor::raw(shape, data)
+}
+impl Function for Rope {
+    fn name(&self) -> &'static str {
+        "rope"
+    }
+    fn params(&self) -> OpParams {
+        OpParams::scalars(&[self.base])
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
+        self.rotate(inps[0], 1.)
+    }
+    fn grad(
+        &self,
+        _inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        // Rotations are orthogonal, their transpose is the inverse rotation
+        Ok(vec![self.rotate(out_grad, -1.)?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative() {
+        let mut rope = Rope::new(10000.);
+        let q = Tensor::raw(&[1, 2], vec![0.3, -0.7]).unwrap();
+        let k = Tensor::raw(&[1, 2], vec![0.5, 0.2]).unwrap();
+        // The same vectors at positions (p + 1, p) get the same score for all `p`
+        let scores = (0..4)
+            .map(|p| {
+                let mut qs = vec![0.; 2 * 6];
+                let mut ks = vec![0.; 2 * 6];
+                qs[2 * (p + 1)..2 * (p + 2)].copy_from_slice(q.blob());
+                ks[2 * p..2 * (p + 1)].copy_from_slice(k.blob());
+                let qs = rope
+                    .run(&[&Tensor::raw(&[6, 2], qs).unwrap()], false)
+                    .unwrap();
+                let ks = rope
+                    .run(&[&Tensor::raw(&[6, 2], ks).unwrap()], false)
+                    .unwrap();
+                let (q, k) = (qs.get(p + 1).unwrap(), ks.get(p).unwrap());
+                q.blob()
+                    .iter()
+                    .zip(k.blob())
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>()
+            })
+            .collect::<Vec<_>>();
+        assert!(scores.iter().all(|s| (s - scores[0]).abs() < 1e-5));
+        assert!(rope
+            .run(&[&Tensor::raw(&[2], vec![1., 2.]).unwrap()], false)
+            .is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use super::registry::OpParams;
+use super::Function;
+use crate::tensor::*;
+
+/// `coeff * a + b` in one pass, which `Graph::fuse` replaces a `Coeff` feeding an
+/// `Add` with
+#[derive(Debug, Clone)]
+pub struct ScaledAdd {
+    coeff: f32,
+}
+impl ScaledAdd {
+    pub fn new(coeff: f32) -> Box<dyn Function> {
+        Box::new(Self { coeff })
+    }
+}
+impl Function for ScaledAdd {
+    fn name(&self) -> &'static str {
+        "scaled_add"
+    }
+    fn params(&self) -> OpParams {
+        OpParams::scalars(&[self.coeff])
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
+        if inps[0].shape() != inps[1].shape() {
+            return &inps[0].map_values(|f| f * self.coeff) + inps[1];
+        }
+        let dat = inps[0]
+            .blob()
+            .iter()
+            .zip(inps[1].blob().iter())
+            .map(|(a, b)| self.coeff * a + b)
+            .collect::<Vec<_>>();
+        Tensor::raw(inps[0].shape(), dat)
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        Ok(vec![
+            out_grad
+                .map_values(|d| d * self.coeff)
+                .sum_to(inps[0].shape())?,
+            out_grad.sum_to(inps[1].shape())?,
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
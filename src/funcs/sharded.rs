@@ -0,0 +1,190 @@
+use super::registry::OpParams;
+use super::Function;
+use crate::tensor::*;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// How a sharded product splits its weight matrix over the threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharding {
+    /// Each thread computes the output columns of its slice of the columns of the
+    /// weights, the slices being put side by side (E.g. the query/key/value
+    /// projections, the first feed-forward layer)
+    Columns,
+    /// Each thread multiplies its slice of the rows of the weights by the matching
+    /// columns of the input, the partial products being summed (E.g. the output
+    /// projection of the attention, the second feed-forward layer)
+    Rows,
+}
+
+/// A matrix product by a weight matrix (Another function, e.g. `MatMul`, which is
+/// run instead for inputs that aren't both matrices), split over `shards` threads of
+/// the pool it runs in: tensor parallelism, for single windows too large to be worth
+/// a thread each. The gradients are split column-wise. Graphs keep the plain function
+/// in their spec.
+#[derive(Debug)]
+pub struct Sharded {
+    inner: Box<dyn Function>,
+    sharding: Sharding,
+    shards: usize,
+}
+
+impl Sharded {
+    pub fn wrap(inner: Box<dyn Function>, sharding: Sharding, shards: usize) -> Box<dyn Function> {
+        Box::new(Self {
+            inner,
+            sharding,
+            shards,
+        })
+    }
+}
+
+/// `0..len` split in up to `shards` ranges of about the same length
+fn ranges(len: usize, shards: usize) -> Vec<Range<usize>> {
+    let shards = shards.clamp(1, len.max(1));
+    (0..shards)
+        .map(|s| s * len / shards..(s + 1) * len / shards)
+        .collect()
+}
+
+/// `a` [m, k] times the columns `cols` of `b` [k, n], as a [m, cols] matrix
+fn matmul_cols(
+    a: &[f32],
+    b: &[f32],
+    m: usize,
+    k: usize,
+    n: usize,
+    cols: &Range<usize>,
+) -> Vec<f32> {
+    let width = cols.len();
+    let mut out = vec![0.; m * width];
+    for i in 0..m {
+        for p in 0..k {
+            let a = a[i * k + p];
+            let b = &b[p * n + cols.start..p * n + cols.end];
+            for (o, b) in out[i * width..(i + 1) * width].iter_mut().zip(b) {
+                *o += a * b;
+            }
+        }
+    }
+    out
+}
+
+/// `a` [m, k] times `b` [k, n], over the columns `rows` of `a` (And rows of `b`) only
+fn matmul_rows(
+    a: &[f32],
+    b: &[f32],
+    m: usize,
+    k: usize,
+    n: usize,
+    rows: &Range<usize>,
+) -> Vec<f32> {
+    let mut out = vec![0.; m * n];
+    for i in 0..m {
+        for p in rows.clone() {
+            let a = a[i * k + p];
+            for (o, b) in out[i * n..(i + 1) * n]
+                .iter_mut()
+                .zip(&b[p * n..(p + 1) * n])
+            {
+                *o += a * b;
+            }
+        }
+    }
+    out
+}
+
+/// `a` [m, k] times `b` [k, n], the columns of the result split over `shards` threads
+fn par_matmul(a: &[f32], b: &[f32], m: usize, k: usize, n: usize, shards: usize) -> Vec<f32> {
+    let ranges = ranges(n, shards);
+    let blocks = ranges
+        .par_iter()
+        .map(|cols| matmul_cols(a, b, m, k, n, cols))
+        .collect::<Vec<_>>();
+    let mut out = vec![0.; m * n];
+    for (cols, block) in ranges.iter().zip(blocks) {
+        for (row, block) in out
+            .chunks_mut(n.max(1))
+            .zip(block.chunks(cols.len().max(1)))
+        {
+            row[cols.clone()].copy_from_slice(block);
+        }
+    }
+    out
+}
+
+fn transpose(a: &[f32], rows: usize, cols: usize) -> Vec<f32> {
+    (0..cols)
+        .flat_map(|j| (0..rows).map(move |i| a[i * cols + j]))
+        .collect()
+}
+
+/// The sizes of the matrices `inps` holds, (m, k, n) for `a` [m, k] and `b` [k, n]
+fn dims(inps: &[&Tensor<f32>]) -> Option<(usize, usize, usize)> {
+    match (inps[0].shape(), inps[1].shape()) {
+        (&[m, k], &[k2, n]) if k == k2 => Some((m, k, n)),
+        _ => None,
+    }
+}
+
+impl Function for Sharded {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+    fn params(&self) -> OpParams {
+        self.inner.params()
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], training: bool) -> Result<Tensor<f32>, TensorError> {
+        let Some((m, k, n)) = dims(inps) else {
+            return self.inner.run(inps, training);
+        };
+        let (a, b) = (inps[0].blob(), inps[1].blob());
+        let out = match self.sharding {
+            Sharding::Columns => par_matmul(a, b, m, k, n, self.shards),
+            Sharding::Rows => ranges(k, self.shards)
+                .par_iter()
+                .map(|rows| matmul_rows(a, b, m, k, n, rows))
+                .reduce(
+                    || vec![0.; m * n],
+                    |mut sum, partial| {
+                        sum.iter_mut().zip(partial).for_each(|(s, p)| *s += p);
+                        sum
+                    },
+                ),
+        };
+        Tensor::raw(&[m, n], out)
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let Some((m, k, n)) = dims(inps).filter(|(m, _, n)| out_grad.shape() == [*m, *n]) else {
+            return self.inner.grad(inps, out_grad);
+        };
+        let (a, b, g) = (inps[0].blob(), inps[1].blob(), out_grad.blob());
+        Ok(vec![
+            Tensor::raw(
+                &[m, k],
+                par_matmul(g, &transpose(b, k, n), m, n, k, self.shards),
+            )?,
+            Tensor::raw(
+                &[k, n],
+                par_matmul(&transpose(a, m, k), g, k, m, n, self.shards),
+            )?,
+        ])
+    }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        self.inner.run_f64(inps)
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        self.inner.grad_f64(inps, out_grad)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(Self {
+            inner: self.inner.clone_box(),
+            sharding: self.sharding,
+            shards: self.shards,
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use super::{elementwise_grad_f64, to_f64, Function};
+use crate::tensor::*;
+
+pub(super) fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+/// `x * sigmoid(x)`, a.k.a. swish
+#[derive(Debug, Clone)]
+pub struct Silu;
+impl Silu {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+impl Function for Silu {
+    fn name(&self) -> &'static str {
+        "silu"
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
+        Ok(inps[0].map_values(|f| f * sigmoid(f)))
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let der = inps[0].map_values(|f| {
+            let s = sigmoid(f);
+            s + f * s * (1. - s)
+        });
+        Ok(vec![(&der * out_grad)?])
+    }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        Some(
+            to_f64(inps[0])
+                .into_iter()
+                .map(|x| x / (1. + (-x).exp()))
+                .collect(),
+        )
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        elementwise_grad_f64(inps[0], out_grad, |x| {
+            let s = 1. / (1. + (-x).exp());
+            s + x * s * (1. - s)
+        })
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
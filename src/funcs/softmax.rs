@@ -1,6 +1,12 @@
-use super::Function;
+use super::{rows_f64, Function};
 use crate::tensor::*;
 
+fn softmax_f64(row: &[f64]) -> Vec<f64> {
+    let max = row.iter().fold(f64::NEG_INFINITY, |a, b| a.max(*b));
+    let sum = row.iter().map(|f| (f - max).exp()).sum::<f64>();
+    row.iter().map(|f| (f - max).exp() / sum).collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Softmax {
     out: Tensor<f32>,
@@ -13,6 +19,9 @@ impl Softmax {
     }
 }
 impl Function for Softmax {
+    fn name(&self) -> &'static str {
+        "softmax"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         self.out = inps[0].map(1, |l| {
             let max = l
@@ -36,7 +45,7 @@ impl Function for Softmax {
             .inners()
             .iter()
             .zip(out_grad.keep_right(1)?.inners().iter())
-            .map(|(l, o)| {
+            .flat_map(|(l, o)| {
                 let l_blob = l.blob();
                 let o_blob = o.blob();
                 let n = l.shape()[0];
@@ -52,10 +61,32 @@ impl Function for Softmax {
                 }
                 data
             })
-            .flatten()
             .collect::<Vec<_>>();
         Ok(vec![Tensor::raw(out_grad.shape(), grad_inp0)?])
     }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        Some(
+            rows_f64(inps[0])?
+                .iter()
+                .flat_map(|r| softmax_f64(r))
+                .collect(),
+        )
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        let grad = rows_f64(inps[0])?
+            .iter()
+            .zip(rows_f64(out_grad)?)
+            .flat_map(|(r, g)| {
+                let s = softmax_f64(r);
+                let dot = s.iter().zip(g.iter()).map(|(s, g)| s * g).sum::<f64>();
+                s.iter()
+                    .zip(g)
+                    .map(|(s, g)| s * (g - dot))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Some(vec![grad])
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
@@ -0,0 +1,71 @@
+use super::{Function, MatMul};
+use crate::tensor::*;
+
+/// A product by a matrix of weights most of which are zero (E.g. pruned, see
+/// `GPT::prune`), skipping them: each row of the weights is reduced to its non-zero
+/// values, once per run. The same as `MatMul` otherwise, which it falls back to for
+/// weights that aren't a single matrix.
+#[derive(Debug, Clone)]
+pub struct SparseMatMul;
+impl SparseMatMul {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+
+/// The non-zero values of each row of `w` [k, n], with their columns
+fn sparse_rows(w: &[f32], k: usize, n: usize) -> Vec<Vec<(usize, f32)>> {
+    (0..k)
+        .map(|p| {
+            w[p * n..(p + 1) * n]
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| **v != 0.)
+                .map(|(j, v)| (j, *v))
+                .collect()
+        })
+        .collect()
+}
+
+impl Function for SparseMatMul {
+    fn name(&self) -> &'static str {
+        "sparse_matmul"
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], training: bool) -> Result<Tensor<f32>, TensorError> {
+        let (x, w) = (inps[0], inps[1]);
+        let (&[k, n], Some(&k2)) = (w.shape(), x.shape().last()) else {
+            return MatMul.run(inps, training);
+        };
+        if k != k2 {
+            return MatMul.run(inps, training);
+        }
+        let rows = sparse_rows(w.blob(), k, n);
+        let mut out = vec![0.; x.size() / k.max(1) * n];
+        for (x, out) in x.blob().chunks(k.max(1)).zip(out.chunks_mut(n.max(1))) {
+            for (a, row) in x.iter().zip(rows.iter()) {
+                for (j, v) in row {
+                    out[*j] += a * v;
+                }
+            }
+        }
+        let mut shape = x.shape().to_vec();
+        *shape.last_mut().unwrap() = n;
+        Tensor::raw(&shape, out)
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        MatMul.grad(inps, out_grad)
+    }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        MatMul.run_f64(inps)
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        MatMul.grad_f64(inps, out_grad)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
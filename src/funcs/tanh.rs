@@ -0,0 +1,35 @@
+use super::{elementwise_grad_f64, to_f64, Function};
+use crate::tensor::*;
+
+#[derive(Debug, Clone)]
+pub struct Tanh;
+impl Tanh {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+impl Function for Tanh {
+    fn name(&self) -> &'static str {
+        "tanh"
+    }
+    fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
+        Ok(inps[0].map_values(|f| f.tanh()))
+    }
+    fn grad(
+        &self,
+        inps: &[&Tensor<f32>],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let der = inps[0].map_values(|f| 1. - f.tanh().powi(2));
+        Ok(vec![(&der * out_grad)?])
+    }
+    fn run_f64(&self, inps: &[&Tensor<f32>]) -> Option<Vec<f64>> {
+        Some(to_f64(inps[0]).into_iter().map(f64::tanh).collect())
+    }
+    fn grad_f64(&self, inps: &[&Tensor<f32>], out_grad: &Tensor<f32>) -> Option<Vec<Vec<f64>>> {
+        elementwise_grad_f64(inps[0], out_grad, |x| 1. - x.tanh().powi(2))
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
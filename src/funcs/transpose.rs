@@ -10,6 +10,9 @@ impl Transpose {
 }
 
 impl Function for Transpose {
+    fn name(&self) -> &'static str {
+        "transpose"
+    }
     fn run(&mut self, inps: &[&Tensor<f32>], _training: bool) -> Result<Tensor<f32>, TensorError> {
         inps[0].transpose()
     }
@@ -0,0 +1,134 @@
+//! Golden-value tests: a tiny model with fixed weights has to reproduce the forward
+//! pass, loss, gradient norm and first AdamW step of a float64 reference
+//! implementation, so that numerical drift introduced by a refactor gets caught.
+//!
+//! The fixtures are exported by `scripts/golden.py`, whose hyperparameters have to
+//! match the ones here.
+
+use crate::config::ModelConfig;
+use crate::dataset::{Dataset, Window};
+use crate::gpt::GPT;
+use crate::optimizer::AdamW;
+use crate::tensor::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashMap;
+
+const VOCAB_SIZE: usize = 5;
+const EMBEDDING_DEGREE: usize = 4;
+const NUM_TOKENS: usize = 3;
+const NUM_LAYERS: usize = 2;
+const NUM_HEADS: usize = 2;
+const LEARNING_RATE: f32 = 0.01;
+
+/// The reference is computed in float64, the model in float32
+const RTOL: f32 = 1e-4;
+const ATOL: f32 = 1e-5;
+
+const WEIGHTS: &[u8] = include_bytes!("../fixtures/golden/weights.npz");
+const EXPECTED: &[u8] = include_bytes!("../fixtures/golden/expected.npz");
+
+/// Always the same window
+struct Fixed(Vec<usize>);
+
+impl Dataset for Fixed {
+    fn num_tokens(&self) -> usize {
+        self.0.len()
+    }
+    fn sample<R: Rng>(&self, _rng: &mut R, context_size: usize) -> Window {
+        Window {
+            tokens: self.0[..context_size + 1].to_vec(),
+            source: 0,
+            documents: None,
+        }
+    }
+}
+
+fn expected() -> HashMap<String, Tensor<f32>> {
+    read_npz(EXPECTED).unwrap().into_iter().collect()
+}
+
+fn model() -> GPT<AdamW> {
+    let mut gpt = GPT::new(
+        &mut StdRng::seed_from_u64(0),
+        VOCAB_SIZE,
+        &ModelConfig {
+            num_tokens: NUM_TOKENS,
+            embedding_degree: EMBEDDING_DEGREE,
+            num_layers: NUM_LAYERS,
+            num_heads: NUM_HEADS,
+            ..Default::default()
+        },
+        AdamW::new(),
+    )
+    .unwrap();
+    let missing = gpt.import_npz(WEIGHTS).unwrap();
+    assert!(missing.is_empty(), "no fixture for {:?}", missing);
+    gpt
+}
+
+fn tokens(t: &Tensor<f32>) -> Vec<usize> {
+    t.blob().iter().map(|v| *v as usize).collect()
+}
+
+fn check<T: TensorOps<f32>>(name: &str, actual: &T, expected: &Tensor<f32>) {
+    if let Err(m) = compare(actual, expected, RTOL, ATOL) {
+        panic!("{} drifted from the reference: {}", name, m);
+    }
+}
+
+#[test]
+fn test_golden_forward() {
+    let expected = expected();
+    let gpt = model();
+    let context = tokens(&expected["context"]);
+    let target = tokens(&expected["target"]);
+
+    for l in 0..NUM_LAYERS {
+        let name = format!("hidden_{}", l);
+        check(
+            &name,
+            &gpt.hidden_states(&context, Some(l)).unwrap(),
+            &expected[&name],
+        );
+    }
+    check(
+        "output",
+        &gpt.hidden_states(&context, None).unwrap(),
+        &expected["output"],
+    );
+    let mut window = context.clone();
+    window.push(*target.last().unwrap());
+    let log_probs = gpt.token_log_probs(&window).unwrap();
+    check(
+        "log_probs",
+        &Tensor::vector(&log_probs),
+        &expected["log_probs"],
+    );
+}
+
+#[test]
+fn test_golden_first_step() {
+    let expected = expected();
+    let mut gpt = model();
+    let mut window = tokens(&expected["context"]);
+    window.push(*tokens(&expected["target"]).last().unwrap());
+
+    let stats = gpt
+        .train_step(&Fixed(window), 1, None, LEARNING_RATE)
+        .unwrap();
+    check("loss", &Tensor::scalar(stats.loss), &expected["loss"]);
+    check(
+        "grad_norm",
+        &Tensor::scalar(stats.grad_norm),
+        &expected["grad_norm"],
+    );
+
+    let mut params = Vec::new();
+    gpt.export_npz(&mut params).unwrap();
+    let params = read_npz::<f32, _>(&params[..]).unwrap();
+    let stepped = expected.keys().filter(|k| k.starts_with("step_1/")).count();
+    assert_eq!(params.len(), stepped);
+    for (name, param) in params {
+        check(&name, &param, &expected[&format!("step_1/{}", name)]);
+    }
+}
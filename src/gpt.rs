@@ -1,436 +1,6028 @@
+use crate::config::{wildcard_match, ModelConfig};
+use crate::dataset::{Dataset, Window};
+use crate::distributed::{Cluster, Gradients};
 use crate::funcs::*;
-use crate::graph::{Graph, GraphError, TensorId};
-use crate::optimizer::Optimizer;
-use crate::tensor::{Tensor, TensorError, TensorMutOps, TensorOps};
-use rand::Rng;
+use crate::graph::{
+    Divergence, Graph, GraphError, Precision, SparseGrad, Tape, TensorId, TensorSource,
+};
+use crate::histogram::{Histogram, ParamHistograms};
+use crate::optimizer::{Optimizer, WeightDecay};
+use crate::privacy::DpSgd;
+use crate::tensor::{
+    read_npz, read_tensors, write_npz, write_tensors, Tensor, TensorError, TensorMutOps, TensorOps,
+};
+use crate::tokenizer::Tokenizer;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::StandardNormal;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Written with bincode's default options, i.e. little-endian, fixed-width integers,
+/// the tensors being serialized in a portable form of their own (See `codec.rs`).
+///
+/// The file starts with `STATE_MAGIC` and the version of its layout, then has the
+/// fields in order. Training states of earlier versions, which start right with the
+/// tensors, are read as well (Those without a data seed getting a random one).
+#[derive(Debug, Clone)]
 pub struct TrainingState<O: Clone> {
     pub tensors: HashMap<String, Tensor<f32>>,
     pub optimizer: O,
+    /// Seed of the order the training windows are drawn in
+    pub data_seed: u64,
+    /// What the model was trained on (Unknown for the states of earlier versions)
+    pub provenance: Provenance,
 }
 
-pub struct GPT<O: Optimizer> {
-    graph: Graph,
-    vocab_size: usize,
-    num_tokens: usize,
-    params: Vec<TensorId>,
-    token_embedding: TensorId,
-    pos_embedding: TensorId,
-    token_input: TensorId,
-    pos_input: TensorId,
-    output: TensorId,
-    optimizer: O,
+/// Hashes of the dataset a model was trained on and of its tokenizer (See
+/// `run::dataset_hash` and `run::tokenizer_hash`), kept in its training state so
+/// that the checkpoint can be checked against them wherever it's copied to (See
+/// `GPT::check_tokenizer`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub dataset_hash: Option<u64>,
+    pub tokenizer_hash: Option<u64>,
 }
 
-fn sample_dataset<R: Rng>(
-    dataset: &[usize],
-    batch_size: usize,
-    context_size: usize,
-    rng: &mut R,
-) -> (Tensor<usize>, Tensor<usize>) {
-    let mut xs: Vec<usize> = Vec::with_capacity(batch_size * context_size);
-    let mut ys: Vec<usize> = Vec::with_capacity(batch_size * context_size);
-    for _i in 0..batch_size {
-        let start: usize = rng.gen_range(0..dataset.len());
-        let all = dataset
-            .iter()
-            .cycle()
-            .skip(start)
-            .take(context_size + 1)
-            .cloned()
-            .collect::<Vec<_>>();
-        xs.extend(&all[0..context_size]);
-        ys.extend(&all[1..context_size + 1]);
+/// First bytes of a training state, "femtoGPT". (Read as the number of tensors the
+/// states of earlier versions start with, it would be far too many)
+pub const STATE_MAGIC: u64 = u64::from_le_bytes(*b"femtoGPT");
+/// Version of the layout of the training states written
+pub const STATE_VERSION: u32 = 2;
+
+impl<O: Clone + Serialize> Serialize for TrainingState<O> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (
+            STATE_MAGIC,
+            STATE_VERSION,
+            &self.tensors,
+            &self.optimizer,
+            self.data_seed,
+            self.provenance,
+        )
+            .serialize(serializer)
     }
+}
+
+struct StateVisitor<O>(std::marker::PhantomData<O>);
 
-    (
-        Tensor::raw(&[batch_size, context_size], xs).unwrap(),
-        Tensor::raw(&[batch_size, context_size], ys).unwrap(),
-    )
+/// The data seed of a state of before the version header, which may end before it.
+/// It's read byte by byte, as bincode writes it, for a state ending there (Which
+/// bincode tells as a failure to read on) to be told from one cut inside it.
+struct LegacySeed(Option<u64>);
+
+impl<'de> Deserialize<'de> for LegacySeed {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(8, LegacySeedVisitor)
+    }
 }
 
-use std::collections::HashMap;
-fn unembed(
-    s: &Tensor<usize>,
-    s_result: &Tensor<f32>,
-    embedding: &mut Tensor<f32>,
-) -> Result<(), TensorError> {
-    let mut embeds: HashMap<usize, Vec<Tensor<f32>>> = HashMap::new();
-    for (ch, embed) in s.blob().iter().zip(s_result.keep_right(1)?.inners().iter()) {
-        embeds.entry(*ch).or_default().push(embed.clone().into());
+struct LegacySeedVisitor;
+
+impl<'de> serde::de::Visitor<'de> for LegacySeedVisitor {
+    type Value = LegacySeed;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a data seed")
     }
-    for (ch, vals) in embeds {
-        let mut avg = Tensor::scalar(0.);
-        for v in vals.iter() {
-            avg = (&avg + v)?;
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        use serde::de::Error;
+        let mut bytes = [0; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            match seq.next_element() {
+                Ok(Some(b)) => *byte = b,
+                // The end of the state
+                Ok(None) | Err(_) if i == 0 => return Ok(LegacySeed(None)),
+                Ok(None) => return Err(A::Error::invalid_length(i, &self)),
+                Err(e) => return Err(e),
+            }
         }
-        avg = (&avg * &Tensor::scalar(1. / vals.len() as f32))?;
-        embedding.get_mut(ch)?.set(avg.clone())?;
+        Ok(LegacySeed(Some(u64::from_le_bytes(bytes))))
     }
-    Ok(())
 }
 
-fn select<R: Rng, T: TensorOps<f32>>(
-    rng: &mut R,
-    t: &T,
-    temperature: f32,
-) -> Result<usize, TensorError> {
-    let t = Softmax::new().run(&[&Tensor::<f32>::raw(t.shape(), t.blob().to_vec())?], false)?;
-    let mut ts = t.blob().iter().cloned().enumerate().collect::<Vec<_>>();
-    ts.sort_by_key(|(_, b)| (b * 1000.) as usize);
-    let dice = rng.gen_range(0.0..temperature);
-    let mut accum = 0.;
-    for (id, t) in ts.iter().rev() {
-        accum += t;
-        if dice < accum {
-            return Ok(*id);
+impl<'de, O: Clone + Deserialize<'de>> serde::de::Visitor<'de> for StateVisitor<O> {
+    type Value = TrainingState<O>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a training state")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        use serde::de::Error;
+        let first: u64 = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        if first == STATE_MAGIC {
+            let version: u32 = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+            if version > STATE_VERSION {
+                return Err(A::Error::custom(format!(
+                    "training state of version {}, newer than this one ({})",
+                    version, STATE_VERSION
+                )));
+            }
+            let tensors = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(2, &self))?;
+            let optimizer = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(3, &self))?;
+            let data_seed = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(4, &self))?;
+            // Version 1 has no provenance
+            let provenance = match version {
+                1 => Provenance::default(),
+                _ => seq
+                    .next_element()?
+                    .ok_or_else(|| A::Error::invalid_length(5, &self))?,
+            };
+            return Ok(TrainingState {
+                tensors,
+                optimizer,
+                data_seed,
+                provenance,
+            });
+        }
+        // A state of an earlier version: `first` is the number of tensors, which come
+        // as (Name, tensor) pairs
+        let mut tensors = HashMap::new();
+        for i in 0..first {
+            let (name, tensor): (String, Tensor<f32>) = seq
+                .next_element()?
+                .ok_or_else(|| A::Error::invalid_length(i as usize + 1, &self))?;
+            tensors.insert(name, tensor);
         }
+        let optimizer = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(first as usize + 1, &self))?;
+        // Those of before the data seed end there
+        let data_seed = match seq.next_element()? {
+            Some(LegacySeed(Some(seed))) => seed,
+            _ => rand::random(),
+        };
+        Ok(TrainingState {
+            tensors,
+            optimizer,
+            data_seed,
+            provenance: Provenance::default(),
+        })
     }
-    panic!();
 }
 
-impl<O: Optimizer> GPT<O> {
-    pub fn new<R: Rng>(
+impl<'de, O: Clone + Deserialize<'de>> Deserialize<'de> for TrainingState<O> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // As many elements as there are, the layout depending on the version
+        deserializer.deserialize_tuple(usize::MAX, StateVisitor(std::marker::PhantomData))
+    }
+}
+
+impl<O: Clone> TrainingState<O> {
+    /// Makes room for the tokens added to the vocabulary (After the existing ones, see
+    /// `SimpleTokenizer::extend`) in the token embeddings and the output layer, with
+    /// random weights. Those of the existing tokens are kept as they are, and the
+    /// moments of AdamW for these parameters start over.
+    pub fn grow_vocab<R: Rng>(
+        &mut self,
         rng: &mut R,
         vocab_size: usize,
-        embedding_degree: usize,
-        num_tokens: usize,
-        num_layers: usize,
-        num_heads: usize,
-        head_size: usize,
-        dropout: f32,
-        optimizer: O,
-    ) -> Result<Self, GraphError> {
-        let mut g = Graph::new();
-
-        let token_embedding = g.alloc_rand(
-            rng,
-            &[vocab_size, embedding_degree],
-            "token_embedding".into(),
-        );
-        let pos_embedding =
-            g.alloc_rand(rng, &[num_tokens, embedding_degree], "pos_embedding".into());
-
-        let token_input = g.alloc_rand(rng, &[num_tokens, embedding_degree], "token_input".into());
-        let pos_input = g.alloc_rand(rng, &[num_tokens, embedding_degree], "pos_input".into());
-        let inp = g.call(Add::new(), &[token_input, pos_input])?;
+    ) -> Result<(), TensorError> {
+        let param = |tensors: &HashMap<String, Tensor<f32>>, name: &str| {
+            tensors
+                .get(name)
+                .cloned()
+                .ok_or_else(|| TensorError::InvalidFormat(format!("no {} tensor", name)))
+        };
+        let embedding = param(&self.tensors, "token_embedding")?;
+        let (old, degree) = (embedding.shape()[0], embedding.shape()[1]);
+        if vocab_size < old {
+            return Err(TensorError::shape_mismatch(
+                "grow_vocab",
+                &[old],
+                &[vocab_size],
+            ));
+        }
+        let added = vocab_size - old;
+        if added == 0 {
+            return Ok(());
+        }
 
-        // Keep track of tensor-ids of learnable tensors!
-        let mut params: Vec<TensorId> = Vec::new();
+        let mut blob = embedding.blob().to_vec();
+        blob.extend_from_slice(Tensor::<f32>::rand(rng, &[added, degree]).blob());
+        let embedding = Tensor::raw(&[vocab_size, degree], blob)?;
 
-        params.extend(&[token_embedding, pos_embedding]);
+        let weights = param(&self.tensors, "head_map_weights")?;
+        let extra = Tensor::<f32>::rand(rng, &[degree, added]);
+        let blob = weights
+            .blob()
+            .chunks(old)
+            .zip(extra.blob().chunks(added))
+            .flat_map(|(row, extra)| row.iter().chain(extra).copied())
+            .collect();
+        let weights = Tensor::raw(&[degree, vocab_size], blob)?;
 
-        let mut curr_inp = inp;
-        for l in 0..num_layers {
-            // Normalize input before applying multi-head attention
-            let norm_coeff = g.alloc_rand(rng, &[embedding_degree], format!("norm_{}_coeff", l));
-            let norm_bias = g.alloc_rand(rng, &[embedding_degree], format!("norm_{}_bias", l));
-            params.extend(&[norm_coeff, norm_bias]);
-            let norm_inp = g.call(LayerNorm::new(), &[curr_inp, norm_coeff, norm_bias])?;
+        let mut bias = param(&self.tensors, "head_map_bias")?.blob().to_vec();
+        bias.extend_from_slice(Tensor::<f32>::rand(rng, &[added]).blob());
+        let bias = Tensor::vector(&bias);
 
-            let mut heads = Vec::new();
+        self.tensors.insert("token_embedding".into(), embedding);
+        self.tensors.insert("head_map_weights".into(), weights);
+        self.tensors.insert("head_map_bias".into(), bias);
+        Ok(())
+    }
+}
 
-            // Multi-head Attention
-            for h in 0..num_heads {
-                let k_params = g.alloc_rand(
-                    rng,
-                    &[embedding_degree, head_size],
-                    format!("head_{}_{}_k", l, h),
-                );
-                let q_params = g.alloc_rand(
-                    rng,
-                    &[embedding_degree, head_size],
-                    format!("head_{}_{}_q", l, h),
-                );
-                let v_params = g.alloc_rand(
-                    rng,
-                    &[embedding_degree, head_size],
-                    format!("head_{}_{}_v", l, h),
-                );
-                params.extend(&[k_params, q_params, v_params]);
-                let k = g.call(MatMul::new(), &[norm_inp, k_params])?;
-                let q = g.call(MatMul::new(), &[norm_inp, q_params])?;
-                let v = g.call(MatMul::new(), &[norm_inp, v_params])?;
-                let q_t = g.call(Transpose::new(), &[q])?;
-                let kq = g.call(MatMul::new(), &[k, q_t])?;
+/// Diagnostics of a single optimization step, handed to the training callback.
+#[derive(Debug, Clone)]
+pub struct StepStats {
+    pub step: usize,
+    /// Whether the parameters were left as they were, for the gradients overflowed
+    /// (See `LossScaler`)
+    pub skipped: bool,
+    pub loss: f32,
+    pub learning_rate: f32,
+    /// L2 norm of the averaged gradients of all parameters
+    pub grad_norm: f32,
+    /// Ratio of `|update|` to `|weights|` for each parameter tensor, by name. (Only
+    /// measured on the steps `on_eval` gets called for, when training with
+    /// `GPT::train`, since it takes a copy of all the weights)
+    pub update_ratios: Vec<(String, f32)>,
+    /// Wall-clock time spent on the step
+    pub elapsed: Duration,
+    /// Number of windows of the batch drawn from each source of the dataset
+    pub sources: Vec<usize>,
+    /// Number of tokens per window the model was trained on
+    pub context: usize,
+    /// Tokens trained on per second, over the whole cluster (If any)
+    pub tokens_per_second: f32,
+    /// How often the model predicted the next tokens of the batch, before the step
+    pub accuracy: TokenAccuracy,
+    /// Batch-size suggested by the gradient noise scale, when estimated (See
+    /// `NoiseScale`)
+    pub noise_scale: Option<f32>,
+    /// Privacy budget spent so far, when training with DP-SGD (See `set_dp_sgd`)
+    pub epsilon: Option<f64>,
+}
 
-                let head_size_sqrt_inv = (head_size as f32).powf(-0.5);
-                let kq_coeff = g.call(Coeff::new(head_size_sqrt_inv), &[kq])?;
+/// Counts of next-token predictions where the actual token was the most likely one,
+/// or among the 5 most likely ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenAccuracy {
+    pub tokens: usize,
+    pub top1: usize,
+    pub top5: usize,
+}
 
-                let masked_kq = g.call(
-                    Mask::new(!&Tensor::<bool>::tril(num_tokens), f32::NEG_INFINITY),
-                    &[kq_coeff],
-                )?;
-                let soft_masked_kq = g.call(Softmax::new(), &[masked_kq])?;
-                let dropped_soft_masked_kq = g.call(Dropout::new(dropout), &[soft_masked_kq])?;
-                let atten = g.call(MatMul::new(), &[dropped_soft_masked_kq, v])?;
-                heads.push(atten);
-            }
+impl TokenAccuracy {
+    /// Records a prediction, `rank` being the number of tokens found likelier than
+    /// the actual one
+    pub fn record(&mut self, rank: usize) {
+        self.tokens += 1;
+        self.top1 += usize::from(rank < 1);
+        self.top5 += usize::from(rank < 5);
+    }
 
-            // Concat head results and project into embedding_degree
-            let cat = g.call(Cat::new(), &heads)?;
-            let proj_params = g.alloc_rand(
-                rng,
-                &[num_heads * head_size, embedding_degree],
-                format!("proj_{}_weights", l),
-            );
-            let proj_bias_params =
-                g.alloc_rand(rng, &[embedding_degree], format!("proj_{}_bias", l));
-            let proj_cat = g.call(MatMul::new(), &[cat, proj_params])?;
-            let proj_cat_bias = g.call(Add::new(), &[proj_cat, proj_bias_params])?;
-            let dropped_proj_cat_bias = g.call(Dropout::new(dropout), &[proj_cat_bias])?;
+    pub fn merge(&mut self, other: &TokenAccuracy) {
+        self.tokens += other.tokens;
+        self.top1 += other.top1;
+        self.top5 += other.top5;
+    }
 
-            // Add attention results to input and then normalize
-            let add_atten = g.call(Add::new(), &[norm_inp, dropped_proj_cat_bias])?;
-            let add_atten_norm_coeff =
-                g.alloc_rand(rng, &[embedding_degree], format!("atten_norm_{}_coeff", l));
-            let add_atten_norm_bias =
-                g.alloc_rand(rng, &[embedding_degree], format!("atten_norm_{}_bias", l));
-            let add_atten_norm = g.call(
-                LayerNorm::new(),
-                &[add_atten, add_atten_norm_coeff, add_atten_norm_bias],
-            )?;
+    /// Fraction of the predictions that were right (Zero when there were none)
+    pub fn top1_rate(&self) -> f32 {
+        self.top1 as f32 / self.tokens.max(1) as f32
+    }
 
-            // A feed-forward layer:
-            // Linear embedding_degree -> 4*embedding_degree
-            // Relu
-            // Linear 4*embedding_degree -> embedding_degree
-            let lin1_params = g.alloc_rand(
-                rng,
-                &[embedding_degree, 4 * embedding_degree],
-                format!("feedforward1_{}_weights", l),
-            );
-            let bias1_params = g.alloc_rand(
-                rng,
-                &[4 * embedding_degree],
-                format!("feedforward1_{}_bias", l),
-            );
-            let lin1_result = g.call(MatMul::new(), &[add_atten_norm, lin1_params])?;
-            let lin1_bias_result = g.call(Add::new(), &[lin1_result, bias1_params])?;
-            let lin1_act = g.call(Relu::new(), &[lin1_bias_result])?;
-            let lin2_params = g.alloc_rand(
-                rng,
-                &[4 * embedding_degree, embedding_degree],
-                format!("feedforward2_{}_weights", l),
-            );
-            let bias2_params =
-                g.alloc_rand(rng, &[embedding_degree], format!("feedforward2_{}_bias", l));
-            let lin2_result = g.call(MatMul::new(), &[lin1_act, lin2_params])?;
-            let lin2_bias_result = g.call(Add::new(), &[lin2_result, bias2_params])?;
+    /// Fraction of the predictions that had the actual token in their top 5
+    pub fn top5_rate(&self) -> f32 {
+        self.top5 as f32 / self.tokens.max(1) as f32
+    }
+}
 
-            params.extend(&[
-                proj_params,
-                proj_bias_params,
-                lin1_params,
-                bias1_params,
-                lin2_params,
-                bias2_params,
-                add_atten_norm_coeff,
-                add_atten_norm_bias,
-            ]);
+/// Number of logits higher than the one of `target`
+fn rank(logits: &[f32], target: usize) -> usize {
+    logits.iter().filter(|l| **l > logits[target]).count()
+}
 
-            curr_inp = g.call(Add::new(), &[add_atten_norm, lin2_bias_result])?;
-        }
+/// Outcome of `GPT::lr_find`
+#[derive(Debug, Clone)]
+pub struct LrFinder {
+    /// (Learning-rate, smoothed loss) pairs, in the order they were tried
+    pub history: Vec<(f32, f32)>,
+    /// A tenth of the learning-rate with the lowest smoothed loss
+    pub suggested_lr: f32,
+}
 
-        // Normalize the output after the last layer
-        let norm_out_coeff = g.alloc_rand(rng, &[embedding_degree], format!("head_norm_coeff"));
-        let norm_out_bias = g.alloc_rand(rng, &[embedding_degree], format!("head_norm_bias"));
-        params.extend(&[norm_out_coeff, norm_out_bias]);
-        let norm_out = g.call(LayerNorm::new(), &[curr_inp, norm_out_coeff, norm_out_bias])?;
+/// Largest batch-size found by `find_batch_sizes` for a context length
+#[derive(Debug, Clone)]
+pub struct BatchSizeProbe {
+    pub num_tokens: usize,
+    pub batch_size: usize,
+    /// Estimated peak memory of a training step with this configuration, in bytes
+    pub memory: usize,
+}
 
-        // Map from embedding_degree to vocab_size through a linear layer
-        let to_vocab = g.alloc_rand(
-            rng,
-            &[embedding_degree, vocab_size],
-            format!("head_map_weights"),
-        );
-        let to_vocab_bias = g.alloc_rand(rng, &[vocab_size], format!("head_map_bias"));
-        let result_lin = g.call(MatMul::new(), &[norm_out, to_vocab])?;
-        let output = g.call(Add::new(), &[result_lin, to_vocab_bias])?;
-        params.extend(&[to_vocab, to_vocab_bias]);
+/// Bytes of memory that can be taken without swapping, as `MemAvailable` of
+/// /proc/meminfo tells (Only known on Linux)
+pub fn available_memory() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    kb.checked_mul(1024)
+}
 
-        Ok(Self {
-            graph: g,
-            vocab_size,
+/// For each of `context_lengths`, builds the model through `build` and probes
+/// increasing batch-sizes (Up to `max_batch_size`) until the estimated peak memory
+/// of a training step (See `GPT::step_memory`) exceeds `ram_cap` bytes, or the
+/// memory available on the machine (See `available_memory`), whichever is lower.
+///
+/// Context lengths for which not even a batch of one fits are left out.
+pub fn find_batch_sizes<O: Optimizer, F: Fn(usize) -> Result<GPT<O>, GraphError>>(
+    build: F,
+    context_lengths: &[usize],
+    ram_cap: usize,
+    max_batch_size: usize,
+) -> Result<Vec<BatchSizeProbe>, GraphError> {
+    let budget = available_memory().map_or(ram_cap, |available| available.min(ram_cap));
+    let fits = |memory: usize| memory <= budget;
+    let mut result = Vec::new();
+    for &num_tokens in context_lengths {
+        let gpt = build(num_tokens)?;
+        if !fits(gpt.step_memory(1)) {
+            continue;
+        }
+        // Double the batch-size until it no longer fits, then binary-search the gap
+        let mut lo = 1;
+        while lo < max_batch_size && fits(gpt.step_memory((lo * 2).min(max_batch_size))) {
+            lo = (lo * 2).min(max_batch_size);
+        }
+        let mut hi = (lo * 2).min(max_batch_size + 1);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if fits(gpt.step_memory(mid)) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        result.push(BatchSizeProbe {
             num_tokens,
-            params,
-            token_input,
-            pos_input,
-            output,
-            token_embedding,
-            pos_embedding,
-            optimizer,
-        })
+            batch_size: lo,
+            memory: gpt.step_memory(lo),
+        });
     }
+    Ok(result)
+}
 
-    pub fn num_params(&self) -> usize {
-        self.params
-            .iter()
-            .map(|p| self.graph.get(*p).unwrap().size())
-            .sum::<usize>()
-    }
+/// Optional protection of the training loop against loss spikes. When the loss of
+/// a step is not finite, or exceeds `threshold` times its moving average, the model
+/// is rewound to its last good checkpoint and trained with a reduced learning-rate
+/// for a while. (The first one being the model training started with; training
+/// fails with `GraphError::Diverged` when the loss of that very state isn't finite)
+#[derive(Debug, Clone)]
+pub struct SpikeGuard {
+    pub threshold: f32,
+    /// Multiplier of the learning-rate after a spike
+    pub lr_backoff: f32,
+    /// Number of steps the reduced learning-rate is kept for
+    pub backoff_steps: usize,
+}
 
-    pub fn set_training_state(
-        &mut self,
-        training_state: TrainingState<O>,
-        load_optimizer: bool,
-    ) -> Result<(), GraphError> {
-        for p in self.params.iter() {
-            let name = self.graph.name_of(*p)?;
-            if let Some(t) = training_state.tensors.get(name) {
-                self.graph.load(*p, t);
-            }
+impl Default for SpikeGuard {
+    fn default() -> Self {
+        Self {
+            threshold: 2.,
+            lr_backoff: 0.1,
+            backoff_steps: 100,
         }
-        if load_optimizer {
-            self.optimizer = training_state.optimizer;
+    }
+}
+
+/// Optional estimate of the gradient noise scale (McCandlish et al., "An Empirical
+/// Model of Large-Batch Training"): the batch-size up to which larger batches still
+/// speed up the training about linearly. It is `tr(Σ) / |G|²`, the noise of the
+/// gradients of single samples over the squared norm of the true gradient, both being
+/// estimated from the norms of the gradients of the samples and of the whole batch.
+/// The estimates are noisy, so they are averaged over the steps.
+#[derive(Debug, Clone)]
+pub struct NoiseScale {
+    /// Weight of the past steps in the moving averages
+    pub decay: f32,
+    grad_sq: Option<f32>,
+    trace: Option<f32>,
+}
+
+impl Default for NoiseScale {
+    fn default() -> Self {
+        Self {
+            decay: 0.99,
+            grad_sq: None,
+            trace: None,
         }
-        Ok(())
     }
+}
 
-    pub fn get_training_state(&self) -> Result<TrainingState<O>, GraphError> {
-        let mut state = TrainingState {
-            tensors: Default::default(),
-            optimizer: self.optimizer.clone(),
+impl NoiseScale {
+    /// Updates the estimates given the mean squared norm of the gradients of single
+    /// samples, and the squared norm of their average over a batch of `batch_size`
+    pub fn update(&mut self, sample_sq: f32, batch_sq: f32, batch_size: usize) {
+        if batch_size < 2 || !sample_sq.is_finite() || !batch_sq.is_finite() {
+            return;
+        }
+        let b = batch_size as f32;
+        let grad_sq = (b * batch_sq - sample_sq) / (b - 1.);
+        let trace = (sample_sq - batch_sq) / (1. - 1. / b);
+        let ema = |avg: Option<f32>, v: f32| {
+            Some(avg.map_or(v, |a| self.decay * a + (1. - self.decay) * v))
         };
-        for p in self.params.iter() {
-            let k = self.graph.name_of(*p)?.to_string();
-            let v = self.graph.get(*p)?.clone();
-            state.tensors.insert(k, v);
+        self.grad_sq = ema(self.grad_sq, grad_sq);
+        self.trace = ema(self.trace, trace);
+    }
+
+    /// The suggested batch-size, in samples (None until the true gradient is
+    /// estimated to be nonzero)
+    pub fn batch_size(&self) -> Option<f32> {
+        match (self.trace, self.grad_sq) {
+            (Some(trace), Some(grad_sq)) if grad_sq > 0. => Some(trace.max(0.) / grad_sq),
+            _ => None,
         }
-        Ok(state)
     }
+}
 
-    pub fn train<F: Fn(usize) -> f32, C: Fn(&Self) -> Result<(), GraphError>>(
-        &mut self,
-        dataset: &[usize],
-        num_batches: usize,
-        batch_size: usize,
-        limit: Option<usize>,
-        learning_rate: F,
-        callback: C,
-    ) -> Result<(), GraphError> {
-        for i in 0..num_batches {
-            let timer = Instant::now();
-            let (graphs, errs): (Vec<Graph>, Vec<f32>) = (0..batch_size)
-                .into_par_iter()
-                .map(|_| {
-                    let mut rng = rand::thread_rng();
-                    let mut graph = self.graph.clone();
-                    let poses = Tensor::raw(
-                        &[self.num_tokens],
-                        (0..self.num_tokens)
-                            .cycle()
-                            .take(self.num_tokens * 1)
-                            .collect(),
-                    )?;
-                    let (xs, ys) = sample_dataset(dataset, 1, self.num_tokens, &mut rng);
-                    graph.embed(self.token_input, self.token_embedding, &xs)?;
-                    graph.embed(self.pos_input, self.pos_embedding, &poses)?;
-                    graph.forward(true)?;
-                    graph.zero_grad();
-                    let err = graph.backward_all(
-                        self.output,
-                        CrossEntropy::new(self.vocab_size, ys.clone()),
-                        limit,
-                    )?;
-                    let mut token_embedding_grad =
-                        Tensor::<f32>::zeros(graph.get(self.token_embedding)?.shape());
-                    let mut pos_embedding_grad =
-                        Tensor::<f32>::zeros(graph.get(self.pos_embedding)?.shape());
-                    unembed(
-                        &xs,
-                        graph.get_grad(self.token_input)?,
-                        &mut token_embedding_grad,
-                    )?;
-                    unembed(
-                        &poses,
-                        graph.get_grad(self.pos_input)?,
-                        &mut pos_embedding_grad,
-                    )?;
-                    graph.load_grad(self.token_embedding, &token_embedding_grad);
-                    graph.load_grad(self.pos_embedding, &pos_embedding_grad);
-                    Ok((graph, err))
-                })
-                .collect::<Result<Vec<(Graph, f32)>, GraphError>>()?
-                .into_iter()
-                .unzip();
-            for (id, avg) in self
-                .params
-                .par_iter()
-                .map(|id| {
-                    let mut avg = Tensor::<f32>::scalar(0.);
-                    for g in graphs.iter() {
-                        avg = (&avg + g.get_grad(*id)?)?;
-                    }
-                    avg = avg.map_values(|f| f / graphs.len() as f32);
-                    Ok((id, avg))
-                })
-                .collect::<Result<Vec<_>, GraphError>>()?
-            {
-                self.graph.load_grad(*id, &avg);
-            }
-            let avg_loss = errs.iter().sum::<f32>() / errs.len() as f32;
-            let lr = learning_rate(self.optimizer.step_num());
-            self.graph.optimize(
-                &mut self.optimizer,
-                &self.params.iter().cloned().collect(),
-                lr,
-            )?;
-            if i % 50 == 0 {
-                callback(self)?;
-            }
-            println!(
-                "Step: {} Loss: {} (Elapsed: {}ms)",
-                self.optimizer.step_num(),
-                avg_loss,
-                timer.elapsed().as_millis()
-            );
+/// Dynamic loss scaling, for small gradients not to underflow in mixed precision:
+/// the loss is multiplied by `scale` before being backpropagated, and the gradients
+/// divided by it in f32, when the parameters are updated. Steps whose gradients overflow are skipped and the scale
+/// halved, and it doubles back after `growth_interval` steps without overflow.
+#[derive(Debug, Clone)]
+pub struct LossScaler {
+    pub scale: f32,
+    pub growth_interval: usize,
+    good_steps: usize,
+}
+
+impl Default for LossScaler {
+    fn default() -> Self {
+        Self {
+            scale: 65536.,
+            growth_interval: 2000,
+            good_steps: 0,
         }
-        Ok(())
     }
+}
 
-    pub fn infer<R: Rng, F: Fn(usize) -> ()>(
-        &self,
+impl LossScaler {
+    /// Adjusts the scale after a step, given whether its gradients were finite
+    pub fn update(&mut self, finite: bool) {
+        if finite {
+            self.good_steps += 1;
+            if self.good_steps >= self.growth_interval {
+                self.scale *= 2.;
+                self.good_steps = 0;
+            }
+        } else {
+            self.scale = (self.scale / 2.).max(1.);
+            self.good_steps = 0;
+        }
+    }
+}
+
+/// Curriculum over the context length: training starts on windows of `start`
+/// tokens, growing linearly to the full context of the model within `steps` steps.
+/// Short windows are right-padded, and the padding is left out of the loss.
+#[derive(Debug, Clone)]
+pub struct Curriculum {
+    pub start: usize,
+    pub steps: usize,
+}
+
+impl Curriculum {
+    pub fn context_length(&self, step: usize, num_tokens: usize) -> usize {
+        if step >= self.steps {
+            return num_tokens;
+        }
+        let start = self.start.clamp(1, num_tokens);
+        start + (num_tokens - start) * step / self.steps
+    }
+}
+
+/// Source of the soft targets of knowledge distillation
+pub trait Teacher: Send + Sync {
+    /// Logits of the teacher for each of the first `context` tokens of `window`, as
+    /// a `[context, vocab_size]` tensor
+    fn soft_targets(&self, window: &Window, context: usize) -> Result<Tensor<f32>, GraphError>;
+}
+
+/// Knowledge distillation: the model is trained on the KL divergence from the
+/// distributions of `teacher` to its own, both softened by `temperature`, blended
+/// with the cross-entropy to the actual tokens. (The teacher shares the vocabulary)
+#[derive(Clone)]
+pub struct Distillation {
+    pub teacher: Arc<dyn Teacher>,
+    pub temperature: f32,
+    /// Weight of the KL divergence, the cross-entropy getting the rest
+    pub alpha: f32,
+}
+
+impl Distillation {
+    pub fn new(teacher: Arc<dyn Teacher>) -> Self {
+        Self {
+            teacher,
+            temperature: 2.,
+            alpha: 0.5,
+        }
+    }
+}
+
+/// An objective trained along with the prediction of the next tokens (E.g. predicting
+/// the tokens after them): the loss of another output of the model
+pub trait Objective: Send + Sync {
+    /// The loss of the output for the first `context` tokens of `window`
+    fn loss(&self, window: &Window, context: usize) -> Result<Box<dyn Loss>, GraphError>;
+}
+
+/// An output of the model trained on `objective`, its loss weighted by `weight`
+/// relative to the next-token loss (See `GPT::add_auxiliary_loss`)
+#[derive(Clone)]
+pub struct AuxiliaryLoss {
+    pub output: TensorId,
+    pub objective: Arc<dyn Objective>,
+    pub weight: f32,
+}
+
+/// Predicting, at each position, the token `offset` tokens after the next one, within
+/// the same document (Multi-token prediction, see `GPT::add_prediction_head`)
+pub struct FutureTokens {
+    pub vocab_size: usize,
+    pub num_tokens: usize,
+    pub offset: usize,
+}
+
+impl Objective for FutureTokens {
+    fn loss(&self, window: &Window, context: usize) -> Result<Box<dyn Loss>, GraphError> {
+        let mut targets = vec![0; self.num_tokens];
+        let mut weights = vec![0.; self.num_tokens];
+        for i in 0..context.saturating_sub(self.offset) {
+            let j = i + 1 + self.offset;
+            let same_document = window.documents.as_ref().is_none_or(|d| d[i] == d[j]);
+            if same_document {
+                targets[i] = window.tokens[j];
+                weights[i] = 1.;
+            }
+        }
+        Ok(CrossEntropy::weighted(
+            self.vocab_size,
+            Tensor::raw(&[1, self.num_tokens], targets)?,
+            Tensor::raw(&[1, self.num_tokens], weights)?,
+        ))
+    }
+}
+
+const SOFT_TARGETS_MAGIC: &[u8] = b"FGST";
+
+/// Logits of a teacher precomputed over consecutive windows of a dataset, for
+/// distilling without running the teacher. It's also the dataset to train on, as
+/// only its windows have soft targets.
+#[derive(Serialize, Deserialize)]
+pub struct SoftTargets {
+    context_size: usize,
+    /// `context_size + 1` tokens per window
+    windows: Vec<Vec<usize>>,
+    /// `[context_size, vocab_size]` logits per window
+    logits: Vec<Tensor<f32>>,
+    #[serde(skip)]
+    index: HashMap<Vec<usize>, usize>,
+}
+
+impl SoftTargets {
+    /// Runs `teacher` over `tokens`, cut into windows of `context_size + 1` tokens
+    /// (Each one starting with the last token of the previous one)
+    pub fn compute(
+        teacher: &dyn Teacher,
+        tokens: &[usize],
+        context_size: usize,
+    ) -> Result<Self, GraphError> {
+        if context_size == 0 || tokens.len() <= context_size {
+            return Err(TensorError::unexpected_shape("SoftTargets", &[tokens.len()]).into());
+        }
+        let windows = (0..tokens.len() - context_size)
+            .step_by(context_size)
+            .map(|start| tokens[start..start + context_size + 1].to_vec())
+            .collect::<Vec<_>>();
+        let logits = windows
+            .par_iter()
+            .map(|tokens| {
+                let window = Window {
+                    tokens: tokens.clone(),
+                    source: 0,
+                    documents: None,
+                };
+                teacher.soft_targets(&window, context_size)
+            })
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        Ok(Self::from_parts(context_size, windows, logits))
+    }
+
+    fn from_parts(context_size: usize, windows: Vec<Vec<usize>>, logits: Vec<Tensor<f32>>) -> Self {
+        let index = windows
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (w.clone(), i))
+            .collect();
+        Self {
+            context_size,
+            windows,
+            logits,
+            index,
+        }
+    }
+
+    pub fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    pub fn num_windows(&self) -> usize {
+        self.windows.len()
+    }
+
+    pub fn write<W: std::io::Write>(&self, mut w: W) -> Result<(), GraphError> {
+        w.write_all(SOFT_TARGETS_MAGIC)?;
+        bincode::serialize_into(w, self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()).into())
+    }
+
+    pub fn read<R: std::io::Read>(mut r: R) -> Result<Self, GraphError> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let mut magic = [0; SOFT_TARGETS_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != SOFT_TARGETS_MAGIC {
+            return Err(invalid("not a soft targets file".into()).into());
+        }
+        let SoftTargets {
+            context_size,
+            windows,
+            logits,
+            ..
+        } = bincode::deserialize_from(r).map_err(|e| invalid(e.to_string()))?;
+        Ok(Self::from_parts(context_size, windows, logits))
+    }
+
+    /// Whether `bytes` start like a soft targets file
+    pub fn is_soft_targets(bytes: &[u8]) -> bool {
+        bytes.starts_with(SOFT_TARGETS_MAGIC)
+    }
+}
+
+impl Dataset for SoftTargets {
+    fn num_tokens(&self) -> usize {
+        self.windows.len() * self.context_size
+    }
+    fn num_windows(&self, _context_size: usize) -> usize {
+        self.windows.len()
+    }
+    /// Draws one of the windows, cut to `context_size + 1` tokens (Which should be at
+    /// most the context size of the soft targets)
+    fn sample<R: Rng>(&self, rng: &mut R, context_size: usize) -> Window {
+        let tokens = &self.windows[rng.gen_range(0..self.windows.len())];
+        Window {
+            tokens: tokens[..(context_size + 1).min(tokens.len())].to_vec(),
+            source: 0,
+            documents: None,
+        }
+    }
+}
+
+impl Teacher for SoftTargets {
+    fn soft_targets(&self, window: &Window, context: usize) -> Result<Tensor<f32>, GraphError> {
+        let tokens = window
+            .tokens
+            .get(..context + 1)
+            .ok_or_else(|| TensorError::unexpected_shape("SoftTargets", &[window.tokens.len()]))?;
+        let found = match self.index.get(tokens) {
+            Some(i) => Some(*i),
+            // Windows cut by a curriculum are looked up by prefix
+            None => self.windows.iter().position(|w| w.starts_with(tokens)),
+        };
+        let logits = found
+            .map(|i| &self.logits[i])
+            .ok_or_else(|| TensorError::unexpected_shape("SoftTargets", &[tokens.len()]))?;
+        // Only as many as the context they were computed at
+        let vocab_size = match logits.shape() {
+            [rows, vocab_size] if context <= *rows => *vocab_size,
+            shape => {
+                return Err(TensorError::shape_mismatch("SoftTargets", &[context], shape).into())
+            }
+        };
+        Ok(Tensor::raw(
+            &[context, vocab_size],
+            logits.blob()[..context * vocab_size].to_vec(),
+        )?)
+    }
+}
+
+/// When `GPT::train` should stop. Training goes on until any of the limits is hit.
+#[derive(Debug, Clone, Default)]
+pub struct StopCriteria {
+    /// Number of steps of this run
+    pub max_steps: Option<usize>,
+    /// Number of full passes over the dataset, counting the ones of previous runs
+    pub max_epochs: Option<usize>,
+    /// Wall-clock budget of this run
+    pub max_duration: Option<Duration>,
+    /// Loss to train down to, as averaged over the last steps (Exponentially, with a
+    /// weight of 0.1 for the latest one)
+    pub target_loss: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Steps,
+    Epochs,
+    Duration,
+    TargetLoss,
+    Interrupted,
+}
+
+impl StopCriteria {
+    pub fn steps(max_steps: usize) -> Self {
+        Self {
+            max_steps: Some(max_steps),
+            ..Default::default()
+        }
+    }
+    /// Estimated time left, given the number of steps done in this run and in total,
+    /// the number of steps per epoch, and the average duration of a step
+    fn remaining(
+        &self,
+        steps: usize,
+        total_steps: usize,
+        steps_per_epoch: f32,
+        elapsed: Duration,
+        step_duration: Duration,
+    ) -> Option<Duration> {
+        let mut left: Option<Duration> = None;
+        let mut bound = |d: Duration| left = Some(left.map_or(d, |l| l.min(d)));
+        if let Some(m) = self.max_steps {
+            bound(step_duration * m.saturating_sub(steps) as u32);
+        }
+        if let Some(m) = self.max_epochs {
+            let target = (m as f32 * steps_per_epoch).ceil() as usize;
+            bound(step_duration * target.saturating_sub(total_steps) as u32);
+        }
+        if let Some(m) = self.max_duration {
+            bound(m.saturating_sub(elapsed));
+        }
+        left
+    }
+    fn check(
+        &self,
+        steps: usize,
+        epoch: usize,
+        elapsed: Duration,
+        loss: Option<f32>,
+    ) -> Option<StopReason> {
+        if self.max_steps.is_some_and(|m| steps >= m) {
+            Some(StopReason::Steps)
+        } else if self.max_epochs.is_some_and(|m| epoch >= m) {
+            Some(StopReason::Epochs)
+        } else if self.max_duration.is_some_and(|m| elapsed >= m) {
+            Some(StopReason::Duration)
+        } else if self
+            .target_loss
+            .is_some_and(|m| loss.is_some_and(|l| l <= m))
+        {
+            Some(StopReason::TargetLoss)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a `TrainCallback` gets to see of the training loop.
+pub struct TrainContext<'a, O: Optimizer> {
+    pub gpt: &'a GPT<O>,
+    /// Number of full passes over the dataset done so far
+    pub epoch: usize,
+    pub stats: &'a StepStats,
+    /// Estimated time left until the `StopCriteria` are met, based on the average
+    /// duration of the steps so far (None when training has no limits)
+    pub eta: Option<Duration>,
+}
+
+/// How a run of `GPT::train` ended, as told to `TrainCallback::on_stop`
+pub struct TrainSummary<'a, O: Optimizer> {
+    pub gpt: &'a GPT<O>,
+    pub reason: StopReason,
+    /// Number of steps of this run
+    pub steps: usize,
+    /// Number of full passes over the dataset done so far
+    pub epoch: usize,
+    pub elapsed: Duration,
+    /// Loss averaged over the last steps, as `StopCriteria::target_loss` goes by (None
+    /// if no step was run)
+    pub loss: Option<f32>,
+}
+
+/// Hooks into the training loop. Only the learning-rate schedule is mandatory,
+/// all the event handlers are no-ops by default.
+///
+/// A pair of callbacks is a callback too, which makes it possible to compose
+/// loggers, samplers and checkpointers. (The learning-rate of the pair is decided
+/// by its first element)
+pub trait TrainCallback<O: Optimizer> {
+    fn learning_rate(&mut self, step: usize) -> f32;
+    /// Called after every optimization step
+    fn on_step(&mut self, _ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        Ok(())
+    }
+    /// Called every `EVAL_INTERVAL` steps, e.g. for sampling text from the model
+    fn on_eval(&mut self, _ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        Ok(())
+    }
+    /// Called every `EVAL_INTERVAL` steps right after `on_eval`, e.g. for saving the model
+    fn on_checkpoint(&mut self, _ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        Ok(())
+    }
+    /// Called when the number of tokens seen passes a multiple of the dataset size
+    fn on_epoch_end(&mut self, _ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        Ok(())
+    }
+    /// Called when the `SpikeGuard` has rewound the model, `ctx.stats` being the
+    /// stats of the offending step
+    fn on_rewind(&mut self, _ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        Ok(())
+    }
+    /// Called once training stops, whatever the reason (Unless it failed), e.g. for
+    /// saving the final model
+    fn on_stop(&mut self, _summary: &TrainSummary<O>) -> Result<(), GraphError> {
+        Ok(())
+    }
+}
+
+impl<O: Optimizer, A: TrainCallback<O>, B: TrainCallback<O>> TrainCallback<O> for (A, B) {
+    fn learning_rate(&mut self, step: usize) -> f32 {
+        self.0.learning_rate(step)
+    }
+    fn on_step(&mut self, ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        self.0.on_step(ctx)?;
+        self.1.on_step(ctx)
+    }
+    fn on_eval(&mut self, ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        self.0.on_eval(ctx)?;
+        self.1.on_eval(ctx)
+    }
+    fn on_checkpoint(&mut self, ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        self.0.on_checkpoint(ctx)?;
+        self.1.on_checkpoint(ctx)
+    }
+    fn on_epoch_end(&mut self, ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        self.0.on_epoch_end(ctx)?;
+        self.1.on_epoch_end(ctx)
+    }
+    fn on_rewind(&mut self, ctx: &TrainContext<O>) -> Result<(), GraphError> {
+        self.0.on_rewind(ctx)?;
+        self.1.on_rewind(ctx)
+    }
+    fn on_stop(&mut self, summary: &TrainSummary<O>) -> Result<(), GraphError> {
+        self.0.on_stop(summary)?;
+        self.1.on_stop(summary)
+    }
+}
+
+pub const EVAL_INTERVAL: usize = 50;
+
+/// Steps `GPT::overfit_sanity_check` gives a model to memorize its batch in
+pub const OVERFIT_STEPS: usize = 500;
+/// Loss below which a batch counts as memorized
+pub const OVERFIT_LOSS: f32 = 0.05;
+
+/// The weights `GPT::prune` prunes: those of the products of the attention, the
+/// feed-forward layers and the output (Not the embeddings, biases and norms)
+pub const PRUNED_WEIGHTS: [&str; 7] = [
+    "head_*_*_k",
+    "head_*_*_q",
+    "head_*_*_v",
+    "proj_*_weights",
+    "feedforward1_*_weights",
+    "feedforward2_*_weights",
+    "head_map_weights",
+];
+
+/// How sparse `GPT::prune` left the weights
+#[derive(Debug, Clone, PartialEq)]
+pub struct PruneStats {
+    /// The fraction of each pruned tensor that is zero, by name
+    pub tensors: Vec<(String, f32)>,
+    /// Zeros among the weights of the pruned tensors
+    pub zeros: usize,
+    pub total: usize,
+}
+
+impl PruneStats {
+    pub fn sparsity(&self) -> f32 {
+        self.zeros as f32 / self.total.max(1) as f32
+    }
+}
+
+pub struct GPT<O: Optimizer> {
+    graph: Graph,
+    vocab_size: usize,
+    num_tokens: usize,
+    architecture: Architecture,
+    params: Vec<TensorId>,
+    token_embedding: TensorId,
+    pos_embedding: TensorId,
+    token_input: TensorId,
+    pos_input: TensorId,
+    positional_encoding: PositionalEncoding,
+    attention_bias: TensorId,
+    /// Outputs of the transformer blocks, then the normalized output of the last one
+    hidden: Vec<TensorId>,
+    /// Attention weights of each head of each transformer block
+    attention: Vec<Vec<TensorId>>,
+    /// Parameters of the output head: the coefficients and biases of its layer
+    /// normalization, then the weights and biases of its linear layer
+    output_head: [TensorId; 4],
+    output: TensorId,
+    optimizer: O,
+    interrupted: Arc<AtomicBool>,
+    spike_guard: Option<SpikeGuard>,
+    loss_scaler: Option<LossScaler>,
+    noise_scale: Option<NoiseScale>,
+    dp_sgd: Option<DpSgd>,
+    curriculum: Option<Curriculum>,
+    distillation: Option<Distillation>,
+    /// Outputs trained along with the logits (See `add_auxiliary_loss`)
+    auxiliary_losses: Vec<AuxiliaryLoss>,
+    /// Loss weight of each target token (See `set_token_weights`)
+    token_weights: Option<Vec<f32>>,
+    weight_decay: WeightDecay,
+    layer_decay: f32,
+    sparse_embeddings: bool,
+    /// Whether the next steps measure `StepStats::update_ratios`
+    measure_updates: bool,
+    /// Tokens generation is restricted to (See `restrict_vocab`)
+    vocab_subset: Option<Vec<usize>>,
+    pinned_tokens: usize,
+    /// Whether `set_fake_quant` quantized any function (See `can_cache`)
+    fake_quantized: bool,
+    /// The weights kept by `prune`, zeroed again after every step
+    prune_masks: BTreeMap<TensorId, Vec<bool>>,
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+    cluster: Option<Cluster>,
+    data_seed: u64,
+    provenance: Provenance,
+}
+
+/// What the graph of the model is built from, besides its vocabulary and context (For
+/// building it again, see `GPT::with_context_length`)
+#[derive(Debug, Clone, Copy)]
+struct Architecture {
+    embedding_degree: usize,
+    num_layers: usize,
+    num_heads: usize,
+    head_size: usize,
+    dropout: f32,
+    activation: Activation,
+    parallel_residual: bool,
+}
+
+/// Gradients of a batch, summed over its samples (In the order of the parameters)
+struct BatchGradients {
+    grads: Vec<Tensor<f32>>,
+    /// The rows outside of which the gradients of the embedding tables are zero (None
+    /// for the other parameters)
+    rows: Vec<Option<Vec<usize>>>,
+    losses: Vec<f32>,
+    accuracy: TokenAccuracy,
+    /// Sum of the squared norms of the gradients of the samples, when estimating the
+    /// noise scale
+    sample_sq_norms: f32,
+    windows: Vec<Window>,
+}
+
+/// What the random numbers of a training step are drawn for (See `GPT::step_rng`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RngStream {
+    Data,
+    Dropout,
+    Noise,
+}
+
+/// A parameter and its name (Its f32 master copy, in mixed precision)
+type NamedParam<'a> = (&'a str, Cow<'a, Tensor<f32>>);
+
+/// Inputs, targets and per-token loss weights of a training window
+type WindowTensors = (Tensor<usize>, Tensor<usize>, Tensor<f32>);
+
+/// Gradients of the embedding tables, by parameter
+type SparseGrads = Vec<(TensorId, SparseGrad)>;
+
+/// Inputs, targets and loss weights of the first `context_size` tokens of `window`,
+/// right-padded to `padded_size` tokens. (The weights being zero for padding)
+fn window_tensors(
+    window: &Window,
+    context_size: usize,
+    padded_size: usize,
+) -> Result<WindowTensors, TensorError> {
+    let mut row = window.tokens[..context_size].to_vec();
+    row.resize(padded_size, 0);
+    let xs = Tensor::raw(&[1, padded_size], row)?;
+    // The targets are the inputs shifted by one, the last one completing the window
+    let mut ys = xs.shift(1, 0)?;
+    ys.blob_mut()[context_size - 1] = window.tokens[context_size];
+    let mut weights = vec![1.; context_size];
+    weights.resize(padded_size, 0.);
+    Ok((xs, ys, Tensor::raw(&[1, padded_size], weights)?))
+}
+
+/// Additive attention bias keeping the tokens of a packed window from attending to
+/// the tokens of other documents
+fn document_mask(documents: &[usize]) -> Result<Tensor<f32>, TensorError> {
+    let n = documents.len();
+    let mut bias = vec![0.; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            if documents[i] != documents[j] {
+                bias[i * n + j] = f32::NEG_INFINITY;
+            }
+        }
+    }
+    Tensor::raw(&[n, n], bias)
+}
+
+/// The gradient of the embedding table from that of the embeddings of the first `len`
+/// tokens of `s` (The rest being padding): the sum of the gradients of each token, in
+/// its row
+fn unembed(
+    s: &Tensor<usize>,
+    s_result: &Tensor<f32>,
+    len: usize,
+) -> Result<SparseGrad, TensorError> {
+    let width = *s_result
+        .shape()
+        .last()
+        .ok_or(TensorError::unexpected_shape("unembed", s_result.shape()))?;
+    let mut embeds: HashMap<usize, Vec<&[f32]>> = HashMap::new();
+    for (ch, embed) in s.blob().iter().zip(s_result.blob().chunks(width)).take(len) {
+        embeds.entry(*ch).or_default().push(embed);
+    }
+    let mut grad = SparseGrad::new(width);
+    for (ch, vals) in embeds {
+        let mut sum = vec![0.; width];
+        for v in vals.iter() {
+            sum.iter_mut().zip(v.iter()).for_each(|(a, v)| *a += v);
+        }
+        grad.add_row(ch, &sum)?;
+    }
+    Ok(grad)
+}
+
+fn l2_norm<T: TensorOps<f32>>(t: &T) -> f32 {
+    t.blob().iter().map(|f| f * f).sum::<f32>().sqrt()
+}
+
+/// The nonlinearity of the feedforward blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activation {
+    /// Leaky, as the models trained before it was configurable
+    #[default]
+    Relu,
+    Gelu,
+    Silu,
+    Tanh,
+}
+
+impl Activation {
+    pub fn function(&self) -> Box<dyn Function> {
+        match self {
+            Activation::Relu => Relu::new(),
+            Activation::Gelu => Gelu::new(),
+            Activation::Silu => Silu::new(),
+            Activation::Tanh => Tanh::new(),
+        }
+    }
+}
+
+impl std::str::FromStr for Activation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relu" => Ok(Activation::Relu),
+            "gelu" => Ok(Activation::Gelu),
+            "silu" => Ok(Activation::Silu),
+            "tanh" => Ok(Activation::Tanh),
+            _ => Err(format!(
+                "unknown activation '{}' (Expected relu, gelu, silu or tanh)",
+                s
+            )),
+        }
+    }
+}
+
+/// How the model tells the positions of the tokens apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionalEncoding {
+    /// A trained embedding of each position, added to the token embeddings
+    #[default]
+    Learned,
+    /// Fixed sines and cosines of the position, added to the token embeddings
+    Sinusoidal,
+    /// Queries and keys rotated by angles proportional to their positions (See `Rope`)
+    Rope,
+    /// Attention scores lowered linearly with the distance between the tokens, with a
+    /// slope per head
+    Alibi,
+    /// Nothing but the causal mask
+    None,
+}
+
+impl std::str::FromStr for PositionalEncoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "learned" => Ok(PositionalEncoding::Learned),
+            "sinusoidal" => Ok(PositionalEncoding::Sinusoidal),
+            "rope" => Ok(PositionalEncoding::Rope),
+            "alibi" => Ok(PositionalEncoding::Alibi),
+            "none" => Ok(PositionalEncoding::None),
+            _ => Err(format!(
+                "unknown positional encoding '{}' (Expected learned, sinusoidal, rope, alibi or none)",
+                s
+            )),
+        }
+    }
+}
+
+const ROPE_BASE: f32 = 10000.;
+
+/// The sinusoidal encodings of `num_tokens` positions, as in "Attention Is All You
+/// Need"
+fn sinusoidal_encoding(num_tokens: usize, degree: usize) -> Tensor<f32> {
+    let mut data = vec![0.; num_tokens * degree];
+    for p in 0..num_tokens {
+        for i in 0..degree {
+            let angle = p as f32 / ROPE_BASE.powf((i / 2 * 2) as f32 / degree as f32);
+            data[p * degree + i] = if i % 2 == 0 { angle.sin() } else { angle.cos() };
+        }
+    }
+    Tensor::raw(&[num_tokens, degree], data).unwrap()
+}
+
+/// The ALiBi biases of the attention scores of head `head`: the `i`th token attending
+/// to the `j`th one gets `-slope * (i - j)`, the slopes being a geometric sequence
+/// from `2^(-8 / num_heads)` to `2^-8` over the heads
+fn alibi_bias(num_tokens: usize, head: usize, num_heads: usize) -> Tensor<f32> {
+    let slope = 2f32.powf(-8. * (head + 1) as f32 / num_heads as f32);
+    let mut data = vec![0.; num_tokens * num_tokens];
+    for i in 0..num_tokens {
+        for j in 0..=i {
+            data[i * num_tokens + j] = -slope * (i - j) as f32;
+        }
+    }
+    Tensor::raw(&[num_tokens, num_tokens], data).unwrap()
+}
+
+/// How the hidden states of the tokens of a text are turned into a single embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pooling {
+    /// The average over the tokens
+    #[default]
+    Mean,
+    /// The state of the last token, which attended to all the others
+    Last,
+}
+
+impl std::str::FromStr for Pooling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Pooling::Mean),
+            "last" => Ok(Pooling::Last),
+            _ => Err(format!("unknown pooling '{}' (Expected mean or last)", s)),
+        }
+    }
+}
+
+/// Which part of a prompt too long for the context is kept (See `GPT::fit_prompt`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    /// The start, e.g. for instructions followed by a document
+    Head,
+    /// The end, which the next tokens follow
+    #[default]
+    Tail,
+    /// Both ends, half of the budget each, the middle being left out
+    Ends,
+}
+
+impl std::str::FromStr for Truncation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "head" => Ok(Truncation::Head),
+            "tail" => Ok(Truncation::Tail),
+            "ends" => Ok(Truncation::Ends),
+            _ => Err(format!(
+                "unknown truncation '{}' (Expected head, tail or ends)",
+                s
+            )),
+        }
+    }
+}
+
+/// At most `budget` of `tokens`, the ones `truncation` keeps
+pub fn truncate_prompt(tokens: &[usize], budget: usize, truncation: Truncation) -> Vec<usize> {
+    if tokens.len() <= budget {
+        return tokens.to_vec();
+    }
+    match truncation {
+        Truncation::Head => tokens[..budget].to_vec(),
+        Truncation::Tail => tokens[tokens.len() - budget..].to_vec(),
+        Truncation::Ends => {
+            let head = budget / 2;
+            let mut kept = tokens[..head].to_vec();
+            kept.extend_from_slice(&tokens[tokens.len() - (budget - head)..]);
+            kept
+        }
+    }
+}
+
+/// How the next token is picked from the output of the model
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sampling {
+    /// Logits are divided by the temperature before the softmax, lower values making
+    /// likely tokens likelier. (Zero means greedy decoding)
+    pub temperature: f32,
+    /// Only the most likely tokens whose probabilities sum up to `top_p` are sampled
+    /// from. (E.g. 1.0 considers all the tokens, zero means greedy decoding)
+    pub top_p: f32,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            temperature: 1.,
+            top_p: 1.,
+        }
+    }
+}
+
+impl Sampling {
+    /// Always the likeliest token
+    pub fn greedy() -> Self {
+        Self {
+            temperature: 0.,
+            top_p: 1.,
+        }
+    }
+
+    /// Whether the likeliest token is always picked, no random number being drawn
+    pub fn is_greedy(&self) -> bool {
+        self.temperature <= 0. || self.top_p <= 0.
+    }
+}
+
+/// Where the randomness of generation comes from, if anywhere (See
+/// `GPT::generate_with`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum SamplingStrategy {
+    /// The likeliest token, always: same prompt, same output
+    Greedy,
+    /// Sampled, from a random seed
+    Random(Sampling),
+    /// Sampled, from an RNG seeded with `seed`: same prompt and seed, same output
+    Deterministic { sampling: Sampling, seed: u64 },
+}
+
+/// The generator of everything drawn from a seed (Sampling, initial weights...):
+/// ChaCha8, whose output is specified, so that a seed gives the same results on any
+/// platform and with any version of `rand`. (Unlike `StdRng`, whose algorithm may
+/// change)
+pub type SeededRng = ChaCha8Rng;
+
+pub fn seeded_rng(seed: u64) -> SeededRng {
+    SeededRng::seed_from_u64(seed)
+}
+
+/// Stops a generation from elsewhere (E.g. a Ctrl-C handler, or another thread), or
+/// once it has run for too long. It's checked before every token, the generation
+/// returning the tokens it got so far (See `GPT::generate_cancellable`). Clones share
+/// the same flag, but not the deadline.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also cancelled once `timeout` has passed from now (Or the earlier deadline, if
+    /// there's one already). A timeout too long for the clock is none.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        if let Some(deadline) = Instant::now().checked_add(timeout) {
+            self.deadline = Some(self.deadline.map_or(deadline, |d| d.min(deadline)));
+        }
+        self
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Clears the flag, for the token to be reused by the next generation
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// Whether the deadline has passed
+    pub fn timed_out(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst) || self.timed_out()
+    }
+}
+
+/// Restricts the tokens generation may pick at each step, e.g. to digits, or to what a
+/// grammar allows next (See `GPT::generate_constrained`)
+pub trait TokenConstraint {
+    /// Which of the `vocab_size` tokens may follow `generated`, the tokens generated
+    /// so far (Not counting the prompt). Generation stops when none may.
+    fn allowed(&mut self, generated: &[usize], vocab_size: usize) -> Vec<bool>;
+
+    /// What to add to the logits of the `vocab_size` tokens before picking the one
+    /// following `generated`, if anything
+    fn bias(&mut self, _generated: &[usize], _vocab_size: usize) -> Option<Vec<f32>> {
+        None
+    }
+}
+
+impl<F: FnMut(&[usize], usize) -> Vec<bool>> TokenConstraint for F {
+    fn allowed(&mut self, generated: &[usize], vocab_size: usize) -> Vec<bool> {
+        self(generated, vocab_size)
+    }
+}
+
+/// Controls how long generations that end on their own get, ending on one of the
+/// `end_tokens` (E.g. the first token of a stop sequence, such as the one starting the
+/// next turn of the user in a chat). A `TokenConstraint`, see
+/// `GPT::generate_constrained`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LengthControl {
+    pub end_tokens: Vec<usize>,
+    /// The end tokens can't be picked before this many tokens were generated, e.g. for
+    /// a chat fine-tune not to end its turn at once
+    pub min_new_tokens: usize,
+    /// Added to the logits of the end tokens, times the number of tokens generated
+    /// after `min_new_tokens`: positive values make longer outputs ever less likely,
+    /// negative ones more
+    pub length_penalty: f32,
+}
+
+impl LengthControl {
+    /// Whether it changes nothing to the generation
+    pub fn is_none(&self) -> bool {
+        self.end_tokens.is_empty() || (self.min_new_tokens == 0 && self.length_penalty == 0.)
+    }
+}
+
+impl TokenConstraint for LengthControl {
+    fn allowed(&mut self, generated: &[usize], vocab_size: usize) -> Vec<bool> {
+        let mut allowed = vec![true; vocab_size];
+        if generated.len() < self.min_new_tokens {
+            for t in self.end_tokens.iter().filter(|t| **t < vocab_size) {
+                allowed[*t] = false;
+            }
+        }
+        allowed
+    }
+
+    fn bias(&mut self, generated: &[usize], vocab_size: usize) -> Option<Vec<f32>> {
+        let over = generated.len().checked_sub(self.min_new_tokens)?;
+        if self.length_penalty == 0. || over == 0 {
+            return None;
+        }
+        let mut bias = vec![0.; vocab_size];
+        for t in self.end_tokens.iter().filter(|t| **t < vocab_size) {
+            bias[*t] = self.length_penalty * over as f32;
+        }
+        Some(bias)
+    }
+}
+
+/// Counts of a speculative generation (See `GPT::generate_speculative`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeculativeStats {
+    /// Tokens proposed by the draft model
+    pub drafted: usize,
+    /// Drafted tokens the model agreed with
+    pub accepted: usize,
+    /// Forward passes of the model itself (Not counting the draft model's)
+    pub passes: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted == 0 {
+            return 0.;
+        }
+        self.accepted as f32 / self.drafted as f32
+    }
+}
+
+/// A token a model considered at a step of a generation, with the probability it
+/// gave it (Before the temperature and top-p of the sampling)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenProbability {
+    pub token: usize,
+    pub probability: f32,
+    /// Text of the token, if labelled (See `GenerationTrace::label`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+/// A step of a traced generation: the token picked, and the likeliest ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceStep {
+    pub picked: TokenProbability,
+    /// The `top_k` likeliest tokens, likeliest first (Whether they were allowed by the
+    /// constraint of the generation or not)
+    pub alternatives: Vec<TokenProbability>,
+}
+
+/// The tokens of a generation along with their probabilities, for analysis and for
+/// visualizing how sure the model was of each token (See `GPT::generate_traced`)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationTrace {
+    pub prompt: Vec<usize>,
+    pub steps: Vec<TraceStep>,
+}
+
+impl GenerationTrace {
+    /// The prompt followed by the generated tokens
+    pub fn tokens(&self) -> Vec<usize> {
+        let generated = self.steps.iter().map(|s| s.picked.token);
+        self.prompt.iter().cloned().chain(generated).collect()
+    }
+
+    /// Sets the text of each token of the steps (E.g. untokenizing it)
+    pub fn label<F: Fn(usize) -> Option<String>>(&mut self, text: F) {
+        for step in self.steps.iter_mut() {
+            for t in std::iter::once(&mut step.picked).chain(step.alternatives.iter_mut()) {
+                t.text = text(t.token);
+            }
+        }
+    }
+}
+
+/// The step picking `picked` out of `logits`, the `i`th one being of `tokens[i]`
+/// (Or of token `i`, if there's no subset)
+fn trace_step(logits: &[f32], picked: usize, top_k: usize, tokens: Option<&[usize]>) -> TraceStep {
+    let probs = distribution(logits, &Sampling::default());
+    let prob = |i: usize| TokenProbability {
+        token: tokens.map_or(i, |s| s[i]),
+        probability: probs[i],
+        text: None,
+    };
+    let mut order = (0..probs.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+    TraceStep {
+        picked: prob(picked),
+        alternatives: order.into_iter().take(top_k).map(prob).collect(),
+    }
+}
+
+/// What the attention of a model keeps of the tokens it has seen, for
+/// `GPT::logits_cached` to only run the new ones through it: the queries and the values
+/// of each head of each layer. (The model scores the keys of a token against the
+/// queries of the ones up to it, so it's those that get reused)
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    /// The window the rows are of, from its start
+    tokens: Vec<usize>,
+    /// Rows of `head_size` values, by layer and head
+    queries: Vec<Vec<Vec<f32>>>,
+    values: Vec<Vec<Vec<f32>>>,
+}
+
+impl KvCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of tokens whose attention is kept
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Bytes taken by the rows (And the tokens they're of)
+    pub fn memory(&self) -> usize {
+        let rows = self
+            .queries
+            .iter()
+            .chain(self.values.iter())
+            .flatten()
+            .map(|r| r.len())
+            .sum::<usize>();
+        rows * std::mem::size_of::<f32>() + self.tokens.len() * std::mem::size_of::<usize>()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Keeps the first `len` tokens, making room for the heads of `arch`
+    fn truncate(&mut self, len: usize, arch: &Architecture) {
+        let shape = |rows: &mut Vec<Vec<Vec<f32>>>| {
+            rows.resize(arch.num_layers, Vec::new());
+            for heads in rows.iter_mut() {
+                heads.resize(arch.num_heads, Vec::new());
+                for head in heads.iter_mut() {
+                    head.truncate(len * arch.head_size);
+                }
+            }
+        };
+        shape(&mut self.queries);
+        shape(&mut self.values);
+        self.tokens.truncate(len);
+    }
+}
+
+/// The tokens the model sees out of `tokens`, at most `size` of them: the first
+/// `pinned`, and as many of the last ones as fit after them
+fn rolling_window(tokens: &[usize], pinned: usize, size: usize) -> Vec<usize> {
+    if tokens.len() <= size {
+        return tokens.to_vec();
+    }
+    let pinned = pinned.min(size - 1);
+    let mut window = tokens[..pinned].to_vec();
+    window.extend_from_slice(&tokens[tokens.len() - (size - pinned)..]);
+    window
+}
+
+/// Probabilities `select` picks each token with, given the logits
+fn distribution(logits: &[f32], sampling: &Sampling) -> Vec<f32> {
+    let mut probs = vec![0.; logits.len()];
+    if sampling.is_greedy() {
+        let argmax = (0..logits.len()).fold(0, |a, i| if logits[i] > logits[a] { i } else { a });
+        probs[argmax] = 1.;
+        return probs;
+    }
+    let max = logits
+        .iter()
+        .fold(f32::NEG_INFINITY, |a, b| f32::max(a, *b));
+    let exps = logits
+        .iter()
+        .map(|l| ((l - max) / sampling.temperature).exp())
+        .collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f32>();
+    let mut order = (0..logits.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| exps[*b].total_cmp(&exps[*a]));
+    // The dice of `select` falls within the first `top_p` of the probability mass
+    let top_p = sampling.top_p.min(1.);
+    let mut accum = 0.;
+    for id in order {
+        let p = exps[id] / sum;
+        probs[id] = p.min((top_p - accum).max(0.)) / top_p;
+        accum += p;
+    }
+    probs
+}
+
+/// Picks a token with the given (Not necessarily normalized) probabilities
+fn sample_from<R: Rng>(rng: &mut R, probs: &[f32]) -> usize {
+    let sum = probs.iter().sum::<f32>();
+    let dice = rng.gen_range(0.0..sum.max(f32::MIN_POSITIVE));
+    let mut accum = 0.;
+    for (id, p) in probs.iter().enumerate() {
+        accum += p;
+        if dice < accum {
+            return id;
+        }
+    }
+    (0..probs.len()).fold(0, |a, i| if probs[i] > probs[a] { i } else { a })
+}
+
+pub(crate) fn select<R: Rng, T: TensorOps<f32>>(
+    rng: &mut R,
+    t: &T,
+    sampling: &Sampling,
+) -> Result<usize, TensorError> {
+    // Greedy decoding
+    if sampling.is_greedy() {
+        return t.argmax()?.scalar();
+    }
+    let logits = t.blob().iter().map(|v| v / sampling.temperature).collect();
+    let t = Softmax::new().run(&[&Tensor::<f32>::raw(t.shape(), logits)?], false)?;
+    let dice = rng.gen_range(0.0..sampling.top_p.min(1.));
+    let mut accum = 0.;
+    let order = t.argsort(true)?;
+    for id in order.blob() {
+        accum += t.blob()[*id];
+        if dice < accum {
+            return Ok(*id);
+        }
+    }
+    Ok(order.blob()[order.size() - 1])
+}
+
+impl<O: Optimizer> Teacher for GPT<O> {
+    fn soft_targets(&self, window: &Window, context: usize) -> Result<Tensor<f32>, GraphError> {
+        if context > self.num_tokens {
+            return Err(TensorError::unexpected_shape("soft_targets", &[context]).into());
+        }
+        let mut graph = self.inference_graph()?;
+        if let Some(documents) = &window.documents {
+            let mut documents = documents[..context].to_vec();
+            documents.resize(self.num_tokens, usize::MAX);
+            graph.load(self.attention_bias, &document_mask(&documents)?);
+        }
+        let mut tokens = window.tokens[..context].to_vec();
+        tokens.resize(self.num_tokens, 0);
+        graph.embed(
+            self.token_input,
+            self.token_embedding,
+            &Tensor::raw(&[self.num_tokens], tokens)?,
+        )?;
+        graph.forward_inference()?;
+        let output = graph.get(self.output)?;
+        Ok(Tensor::raw(
+            &[context, self.vocab_size],
+            output.blob()[..context * self.vocab_size].to_vec(),
+        )?)
+    }
+}
+
+impl<O: Optimizer> GPT<O> {
+    /// A randomly initialized model with the architecture of `config`
+    pub fn new<R: Rng>(
+        rng: &mut R,
+        vocab_size: usize,
+        config: &ModelConfig,
+        optimizer: O,
+    ) -> Result<Self, GraphError> {
+        Self::new_in(Graph::new(), rng, vocab_size, config, optimizer)
+    }
+
+    /// Like `new`, building the model in `g` (E.g. `Graph::placeholders`)
+    pub(crate) fn new_in<R: Rng>(
+        mut g: Graph,
+        rng: &mut R,
+        vocab_size: usize,
+        config: &ModelConfig,
+        optimizer: O,
+    ) -> Result<Self, GraphError> {
+        let &ModelConfig {
+            num_tokens,
+            embedding_degree,
+            num_layers,
+            num_heads,
+            activation,
+            positional_encoding,
+            parallel_residual,
+            ..
+        } = config;
+        let head_size = config.head_size();
+        let dropout = config.dropout as f32;
+        let token_embedding = g.alloc_rand(
+            rng,
+            &[vocab_size, embedding_degree],
+            "token_embedding".into(),
+        );
+        // Only trained when learned. (Of the other encodings, only the sinusoidal one
+        // adds anything to the token embeddings)
+        let pos_embedding = match positional_encoding {
+            PositionalEncoding::Learned => {
+                g.alloc_rand(rng, &[num_tokens, embedding_degree], "pos_embedding".into())
+            }
+            PositionalEncoding::Sinusoidal => g.alloc(
+                sinusoidal_encoding(num_tokens, embedding_degree),
+                "pos_embedding".into(),
+            ),
+            _ => g.alloc(
+                Tensor::zeros(&[num_tokens, embedding_degree]),
+                "pos_embedding".into(),
+            ),
+        };
+
+        let token_input = g.alloc_rand(rng, &[num_tokens, embedding_degree], "token_input".into());
+        let pos_input = g.alloc_rand(rng, &[num_tokens, embedding_degree], "pos_input".into());
+        let inp = g.call(Add::new(), &[token_input, pos_input])?;
+
+        // Added to the attention scores of all heads, zero unless documents are packed
+        let attention_bias = g.alloc(
+            Tensor::zeros(&[num_tokens, num_tokens]),
+            "attention_bias".into(),
+        );
+
+        // Keep track of tensor-ids of learnable tensors!
+        let mut params: Vec<TensorId> = Vec::new();
+
+        params.push(token_embedding);
+        if positional_encoding == PositionalEncoding::Learned {
+            params.push(pos_embedding);
+        }
+
+        // Shared by the blocks
+        let alibi = if positional_encoding == PositionalEncoding::Alibi {
+            (0..num_heads)
+                .map(|h| g.alloc(alibi_bias(num_tokens, h, num_heads), format!("alibi_{}", h)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut curr_inp = inp;
+        let mut hidden = Vec::with_capacity(num_layers + 1);
+        let mut attention = Vec::with_capacity(num_layers);
+        for l in 0..num_layers {
+            // Normalize input before applying multi-head attention
+            let norm_coeff = g.alloc_rand(rng, &[embedding_degree], format!("norm_{}_coeff", l));
+            let norm_bias = g.alloc_rand(rng, &[embedding_degree], format!("norm_{}_bias", l));
+            params.extend(&[norm_coeff, norm_bias]);
+            let norm_inp = g.call(LayerNorm::new(), &[curr_inp, norm_coeff, norm_bias])?;
+
+            let mut heads = Vec::new();
+            let mut weights = Vec::with_capacity(num_heads);
+
+            // Multi-head Attention
+            for h in 0..num_heads {
+                let k_params = g.alloc_rand(
+                    rng,
+                    &[embedding_degree, head_size],
+                    format!("head_{}_{}_k", l, h),
+                );
+                let q_params = g.alloc_rand(
+                    rng,
+                    &[embedding_degree, head_size],
+                    format!("head_{}_{}_q", l, h),
+                );
+                let v_params = g.alloc_rand(
+                    rng,
+                    &[embedding_degree, head_size],
+                    format!("head_{}_{}_v", l, h),
+                );
+                params.extend(&[k_params, q_params, v_params]);
+                let k = g.call(MatMul::new(), &[norm_inp, k_params])?;
+                let q = g.call(MatMul::new(), &[norm_inp, q_params])?;
+                let v = g.call(MatMul::new(), &[norm_inp, v_params])?;
+                let (k, q) = if positional_encoding == PositionalEncoding::Rope {
+                    (
+                        g.call(Rope::new(ROPE_BASE), &[k])?,
+                        g.call(Rope::new(ROPE_BASE), &[q])?,
+                    )
+                } else {
+                    (k, q)
+                };
+                let q_t = g.call(Transpose::new(), &[q])?;
+                let kq = g.call(MatMul::new(), &[k, q_t])?;
+
+                let head_size_sqrt_inv = (head_size as f32).powf(-0.5);
+                let kq_coeff = g.call(Coeff::new(head_size_sqrt_inv), &[kq])?;
+
+                let masked_kq = g.call(
+                    Mask::new(!&Tensor::<bool>::tril(num_tokens), f32::NEG_INFINITY),
+                    &[kq_coeff],
+                )?;
+                let mut biased_kq = g.call(Add::new(), &[masked_kq, attention_bias])?;
+                if let Some(alibi) = alibi.get(h) {
+                    biased_kq = g.call(Add::new(), &[biased_kq, *alibi])?;
+                }
+                let soft_masked_kq = g.call(Softmax::new(), &[biased_kq])?;
+                weights.push(soft_masked_kq);
+                let dropped_soft_masked_kq = g.call(Dropout::new(dropout), &[soft_masked_kq])?;
+                let atten = g.call(MatMul::new(), &[dropped_soft_masked_kq, v])?;
+                heads.push(atten);
+            }
+
+            attention.push(weights);
+
+            // Concat head results and project into embedding_degree
+            let cat = g.call(Cat::new(), &heads)?;
+            let proj_params = g.alloc_rand(
+                rng,
+                &[num_heads * head_size, embedding_degree],
+                format!("proj_{}_weights", l),
+            );
+            let proj_bias_params =
+                g.alloc_rand(rng, &[embedding_degree], format!("proj_{}_bias", l));
+            let proj_cat = g.call(MatMul::new(), &[cat, proj_params])?;
+            let proj_cat_bias = g.call(Add::new(), &[proj_cat, proj_bias_params])?;
+            let dropped_proj_cat_bias = g.call(Dropout::new(dropout), &[proj_cat_bias])?;
+
+            // Add attention results to input and then normalize. (Parallel blocks feed
+            // the feed-forward layer the same normalized input as the attention, and
+            // add both results to it, GPT-J style)
+            let add_atten = g.call(Add::new(), &[norm_inp, dropped_proj_cat_bias])?;
+            let mut atten_norm_params = Vec::new();
+            let add_atten_norm = if parallel_residual {
+                norm_inp
+            } else {
+                let add_atten_norm_coeff =
+                    g.alloc_rand(rng, &[embedding_degree], format!("atten_norm_{}_coeff", l));
+                let add_atten_norm_bias =
+                    g.alloc_rand(rng, &[embedding_degree], format!("atten_norm_{}_bias", l));
+                atten_norm_params.extend([add_atten_norm_coeff, add_atten_norm_bias]);
+                g.call(
+                    LayerNorm::new(),
+                    &[add_atten, add_atten_norm_coeff, add_atten_norm_bias],
+                )?
+            };
+
+            // A feed-forward layer:
+            // Linear embedding_degree -> 4*embedding_degree
+            // Activation
+            // Linear 4*embedding_degree -> embedding_degree
+            let lin1_params = g.alloc_rand(
+                rng,
+                &[embedding_degree, 4 * embedding_degree],
+                format!("feedforward1_{}_weights", l),
+            );
+            let bias1_params = g.alloc_rand(
+                rng,
+                &[4 * embedding_degree],
+                format!("feedforward1_{}_bias", l),
+            );
+            let lin1_result = g.call(MatMul::new(), &[add_atten_norm, lin1_params])?;
+            let lin1_bias_result = g.call(Add::new(), &[lin1_result, bias1_params])?;
+            let lin1_act = g.call(activation.function(), &[lin1_bias_result])?;
+            let lin2_params = g.alloc_rand(
+                rng,
+                &[4 * embedding_degree, embedding_degree],
+                format!("feedforward2_{}_weights", l),
+            );
+            let bias2_params =
+                g.alloc_rand(rng, &[embedding_degree], format!("feedforward2_{}_bias", l));
+            let lin2_result = g.call(MatMul::new(), &[lin1_act, lin2_params])?;
+            let lin2_bias_result = g.call(Add::new(), &[lin2_result, bias2_params])?;
+
+            params.extend(&[
+                proj_params,
+                proj_bias_params,
+                lin1_params,
+                bias1_params,
+                lin2_params,
+                bias2_params,
+            ]);
+            params.extend(atten_norm_params);
+
+            let residual = if parallel_residual {
+                add_atten
+            } else {
+                add_atten_norm
+            };
+            curr_inp = g.call(Add::new(), &[residual, lin2_bias_result])?;
+            hidden.push(curr_inp);
+        }
+
+        // Normalize the output after the last layer
+        let norm_out_coeff = g.alloc_rand(rng, &[embedding_degree], "head_norm_coeff".to_string());
+        let norm_out_bias = g.alloc_rand(rng, &[embedding_degree], "head_norm_bias".to_string());
+        params.extend(&[norm_out_coeff, norm_out_bias]);
+        let norm_out = g.call(LayerNorm::new(), &[curr_inp, norm_out_coeff, norm_out_bias])?;
+        hidden.push(norm_out);
+
+        // Map from embedding_degree to vocab_size through a linear layer
+        let to_vocab = g.alloc_rand(
+            rng,
+            &[embedding_degree, vocab_size],
+            "head_map_weights".to_string(),
+        );
+        let to_vocab_bias = g.alloc_rand(rng, &[vocab_size], "head_map_bias".to_string());
+        let result_lin = g.call(MatMul::new(), &[norm_out, to_vocab])?;
+        let output = g.call(Add::new(), &[result_lin, to_vocab_bias])?;
+        g.set_name(output, "logits".into())?;
+        params.extend(&[to_vocab, to_vocab_bias]);
+
+        let variables = params
+            .iter()
+            .chain([&token_input, &pos_input, &attention_bias])
+            .copied()
+            .collect();
+        let outputs = hidden
+            .iter()
+            .chain(attention.iter().flatten())
+            .chain([&output])
+            .copied()
+            .collect::<Vec<_>>();
+        let stats = g.simplify(&variables, &outputs)?;
+        tracing::debug!(?stats, "Simplified the graph");
+
+        Ok(Self {
+            graph: g,
+            vocab_size,
+            num_tokens,
+            architecture: Architecture {
+                embedding_degree,
+                num_layers,
+                num_heads,
+                head_size,
+                dropout,
+                activation,
+                parallel_residual,
+            },
+            params,
+            token_input,
+            pos_input,
+            positional_encoding,
+            attention_bias,
+            hidden,
+            attention,
+            output_head: [norm_out_coeff, norm_out_bias, to_vocab, to_vocab_bias],
+            output,
+            token_embedding,
+            pos_embedding,
+            optimizer,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            spike_guard: None,
+            loss_scaler: None,
+            noise_scale: None,
+            dp_sgd: None,
+            curriculum: None,
+            weight_decay: WeightDecay::default(),
+            layer_decay: 1.,
+            sparse_embeddings: false,
+            measure_updates: true,
+            distillation: None,
+            auxiliary_losses: Vec::new(),
+            token_weights: None,
+            vocab_subset: None,
+            pinned_tokens: 0,
+            fake_quantized: false,
+            prune_masks: BTreeMap::new(),
+            thread_pool: None,
+            cluster: None,
+            data_seed: rng.gen(),
+            provenance: Provenance::default(),
+        })
+    }
+
+    /// The same model, its graph built for a context of `num_tokens` tokens instead of
+    /// the one it was trained with: shorter for faster generation, or longer with
+    /// positions the model extrapolates to (RoPE, ALiBi, or no positional encoding;
+    /// learned position embeddings only go as far as they were trained). Settings of
+    /// the graph other than its precision (E.g. `fuse`, `set_sparse_products`) are
+    /// to be applied again, and the auxiliary heads are left out. For inference only.
+    pub fn with_context_length(&self, num_tokens: usize) -> Result<Self, GraphError> {
+        if self.graph.is_streamed() {
+            return Err(GraphError::Streamed);
+        }
+        let learned = self.positional_encoding == PositionalEncoding::Learned;
+        if num_tokens == 0 || (learned && num_tokens > self.num_tokens) {
+            return Err(TensorError::unexpected_shape("with_context_length", &[num_tokens]).into());
+        }
+        let a = self.architecture;
+        // The weights are replaced by the trained ones
+        let config = ModelConfig {
+            num_tokens,
+            embedding_degree: a.embedding_degree,
+            num_layers: a.num_layers,
+            num_heads: a.num_heads,
+            dropout: a.dropout as f64,
+            activation: a.activation,
+            positional_encoding: self.positional_encoding,
+            parallel_residual: a.parallel_residual,
+        };
+        let mut gpt = Self::new_in(
+            Graph::new(),
+            &mut StdRng::seed_from_u64(self.data_seed),
+            self.vocab_size,
+            &config,
+            self.optimizer.clone(),
+        )?;
+        for (id, trained) in gpt.params.clone().into_iter().zip(self.params.iter()) {
+            let tensor = self.graph.get_param(*trained)?;
+            if id == gpt.pos_embedding {
+                // The positions of the shorter context
+                let rows = tensor.blob()[..num_tokens * a.embedding_degree].to_vec();
+                gpt.graph
+                    .load(id, &Tensor::raw(&[num_tokens, a.embedding_degree], rows)?);
+            } else {
+                gpt.graph.load(id, tensor.as_ref());
+            }
+        }
+        gpt.set_precision(self.precision());
+        gpt.vocab_subset = self.vocab_subset.clone();
+        gpt.pinned_tokens = self.pinned_tokens;
+        gpt.thread_pool = self.thread_pool.clone();
+        gpt.data_seed = self.data_seed;
+        gpt.provenance = self.provenance;
+        Ok(gpt)
+    }
+
+    /// The computation graph of the model, in the DOT language (See `Graph::to_dot`)
+    pub fn to_dot(&self) -> String {
+        self.graph.to_dot()
+    }
+
+    /// Size of the context of the model
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn num_params(&self) -> usize {
+        self.params
+            .iter()
+            .map(|p| self.graph.get_shape(*p).unwrap().iter().product::<usize>())
+            .sum::<usize>()
+    }
+
+    /// Estimated peak memory (In bytes) of a training step with the given batch-size.
+    /// Each thread works on its own copy of the graph and accumulates the gradients
+    /// of its samples, while the model itself keeps the weights and the two moment
+    /// estimates of the optimizer.
+    pub fn step_memory(&self, batch_size: usize) -> usize {
+        let params = self.num_params() * std::mem::size_of::<f32>();
+        let shards = self.num_threads().clamp(1, batch_size.max(1));
+        (self.graph.memory_usage() + params) * shards + self.graph.memory_usage() + 2 * params
+    }
+
+    /// Bytes taken by the activations of a single sample, and by their gradients
+    pub fn activation_memory(&self) -> usize {
+        // The weights and their gradients, and their master copies in mixed precision
+        let params = self
+            .params
+            .iter()
+            .map(|p| {
+                let size = self.graph.get_shape(*p).map_or(0, |s| s.iter().product());
+                let precision = self.graph.precision_of(*p);
+                let mut bytes = 2 * size * precision.bytes_per_value();
+                if precision == Precision::Mixed {
+                    bytes += size * std::mem::size_of::<f32>();
+                }
+                bytes
+            })
+            .sum::<usize>();
+        self.graph.memory_usage().saturating_sub(params)
+    }
+
+    /// Flag that, once set (E.g. from a Ctrl-C handler), makes `train` return
+    /// right after the step it is currently running.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    pub fn set_spike_guard(&mut self, spike_guard: Option<SpikeGuard>) {
+        self.spike_guard = spike_guard;
+    }
+
+    /// Estimates the gradient noise scale during the training, at the cost of a pass
+    /// over the gradients of each sample
+    pub fn set_noise_scale(&mut self, noise_scale: Option<NoiseScale>) {
+        self.noise_scale = noise_scale;
+    }
+
+    pub fn noise_scale(&self) -> Option<&NoiseScale> {
+        self.noise_scale.as_ref()
+    }
+
+    /// Trains with DP-SGD: the gradients of every sample are clipped, and noise is
+    /// added to those of the batch, the privacy spent being reported with every step.
+    /// Not supported in a cluster.
+    pub fn set_dp_sgd(&mut self, dp_sgd: Option<DpSgd>) {
+        self.dp_sgd = dp_sgd;
+    }
+
+    pub fn dp_sgd(&self) -> Option<&DpSgd> {
+        self.dp_sgd.as_ref()
+    }
+
+    /// Stores the weights and the activations in `precision` (Computing in f32 either
+    /// way). Mixed precision comes with dynamic loss scaling.
+    pub fn set_precision(&mut self, precision: Precision) {
+        let params = self.params.iter().cloned().collect();
+        self.graph.set_precision(precision, &params);
+        self.loss_scaler = (precision == Precision::Mixed).then(LossScaler::default);
+    }
+
+    /// Keeps the tensors whose names match one of `patterns` in f32 in mixed precision
+    /// (E.g. `*norm*` for the parameters of the layer norms, `logits` for the output),
+    /// the numerically sensitive ones (See `Graph::set_full_precision`). Returns how
+    /// many tensors match.
+    pub fn set_full_precision(&mut self, patterns: &[String]) -> usize {
+        let count = self.graph.set_full_precision(patterns);
+        let params = self.params.iter().cloned().collect();
+        self.graph.set_precision(self.precision(), &params);
+        count
+    }
+
+    /// Trains quantization-aware: the outputs of the functions registered as one of
+    /// `ops` (E.g. `matmul`) are quantized to `bits` bits and back (See
+    /// `Graph::fake_quantize`). Returns how many functions were.
+    pub fn set_fake_quant(&mut self, ops: &[String], bits: u32) -> usize {
+        let count = self.graph.fake_quantize(ops, bits);
+        self.fake_quantized |= count > 0;
+        count
+    }
+
+    /// Splits the products by the weights of the attention and feed-forward layers over
+    /// `shards` threads (Tensor parallelism, see `Graph::shard_matmuls`): the query,
+    /// key, value and first feed-forward weights column-wise, the output projection and
+    /// second feed-forward weights row-wise. For single windows too large for the
+    /// samples of a batch to keep the threads busy on their own. (Before
+    /// `set_fake_quant`) Returns how many products were.
+    pub fn set_tensor_parallel(&mut self, shards: usize) -> usize {
+        let columns = [
+            "head_*_*_k",
+            "head_*_*_q",
+            "head_*_*_v",
+            "feedforward1_*_weights",
+        ];
+        let rows = ["proj_*_weights", "feedforward2_*_weights"];
+        self.graph
+            .shard_matmuls(&columns.map(String::from), &rows.map(String::from), shards)
+    }
+
+    /// Zeroes the `sparsity` fraction of the weights of each of `PRUNED_WEIGHTS` of the
+    /// smallest magnitudes (Magnitude pruning), and keeps them zero through the next
+    /// training steps, for the model to be fine-tuned around them. (Pruning again
+    /// replaces the masks, zero removes them) See `set_sparse_products` for running
+    /// the pruned products faster.
+    pub fn prune(&mut self, sparsity: f32) -> Result<PruneStats, GraphError> {
+        let sparsity = sparsity.clamp(0., 1.);
+        self.prune_masks.clear();
+        let mut stats = PruneStats {
+            tensors: Vec::new(),
+            zeros: 0,
+            total: 0,
+        };
+        for id in self.params.clone() {
+            let name = self.graph.name_of(id)?.clone();
+            if !PRUNED_WEIGHTS.iter().any(|p| wildcard_match(p, &name)) {
+                continue;
+            }
+            let mut t = self.graph.get_param(id)?.into_owned();
+            let mut order = (0..t.size()).collect::<Vec<_>>();
+            order.sort_by(|a, b| t.blob()[*a].abs().total_cmp(&t.blob()[*b].abs()));
+            let mut mask = vec![true; t.size()];
+            for i in order
+                .into_iter()
+                .take((sparsity * t.size() as f32).round() as usize)
+            {
+                mask[i] = false;
+            }
+            t.blob_mut()
+                .iter_mut()
+                .zip(mask.iter())
+                .filter(|(_, keep)| !**keep)
+                .for_each(|(v, _)| *v = 0.);
+            self.graph.load(id, &t);
+            let zeros = t.blob().iter().filter(|v| **v == 0.).count();
+            stats
+                .tensors
+                .push((name, zeros as f32 / t.size().max(1) as f32));
+            stats.zeros += zeros;
+            stats.total += t.size();
+            if sparsity > 0. {
+                self.prune_masks.insert(id, mask);
+            }
+        }
+        Ok(stats)
+    }
+
+    fn apply_prune_masks(&mut self) -> Result<(), GraphError> {
+        for (id, mask) in self.prune_masks.iter() {
+            let mut t = self.graph.get_param(*id)?.into_owned();
+            t.blob_mut()
+                .iter_mut()
+                .zip(mask.iter())
+                .filter(|(_, keep)| !**keep)
+                .for_each(|(v, _)| *v = 0.);
+            self.graph.load(*id, &t);
+        }
+        Ok(())
+    }
+
+    /// Runs the products by the weights of `PRUNED_WEIGHTS` skipping their zeros (See
+    /// `SparseMatMul`), faster once they're pruned enough. (Before `set_fake_quant`)
+    /// Returns how many products were.
+    pub fn set_sparse_products(&mut self) -> usize {
+        self.graph
+            .sparsify_matmuls(&PRUNED_WEIGHTS.map(String::from))
+    }
+
+    /// Replaces the chains of functions of the model having a fused implementation with
+    /// it (See `Graph::fuse`): the products by the weights of the projections, the
+    /// feed-forward layers and the output, with their biases and activations. The
+    /// hidden states, the attention weights and the logits (Of the auxiliary heads
+    /// too) are kept. (Before `set_tensor_parallel` and `set_fake_quant`, which don't
+    /// see the fused products) Returns how many chains were.
+    pub fn fuse(&mut self) -> Result<usize, GraphError> {
+        let outputs = self
+            .hidden
+            .iter()
+            .chain(self.attention.iter().flatten())
+            .chain([&self.output])
+            .chain(self.auxiliary_losses.iter().map(|a| &a.output))
+            .copied()
+            .collect::<Vec<_>>();
+        self.graph.fuse(&outputs)
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.graph.precision()
+    }
+
+    pub fn loss_scaler(&self) -> Option<&LossScaler> {
+        self.loss_scaler.as_ref()
+    }
+
+    /// Restricts generation to `tokens`, or lifts the restriction with `None`. The
+    /// output layer is sliced to their columns, so the other tokens can't be sampled
+    /// (E.g. a char-level model kept to digits), and the logits take less to compute.
+    pub fn restrict_vocab(&mut self, tokens: Option<&[usize]>) -> Result<(), GraphError> {
+        // The output layer would be sliced while it isn't loaded
+        if tokens.is_some() && self.graph.is_streamed() {
+            return Err(GraphError::Streamed);
+        }
+        self.vocab_subset = match tokens {
+            Some(tokens) => {
+                let mut tokens = tokens.to_vec();
+                tokens.sort_unstable();
+                tokens.dedup();
+                if tokens.last().is_none_or(|t| *t >= self.vocab_size) {
+                    return Err(
+                        TensorError::unexpected_shape("restrict_vocab", &[tokens.len()]).into(),
+                    );
+                }
+                Some(tokens)
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn vocab_subset(&self) -> Option<&[usize]> {
+        self.vocab_subset.as_deref()
+    }
+
+    /// Keeps the first `pinned_tokens` of the prompt (E.g. instructions) in the context
+    /// of `generate` once full, only the tokens after them rolling out. (At most
+    /// `num_tokens - 1` of them, so that there's room for the last token)
+    pub fn set_pinned_tokens(&mut self, pinned_tokens: usize) {
+        self.pinned_tokens = pinned_tokens;
+    }
+
+    /// Tokens of the context left to the prompt when generating `max_new_tokens` after
+    /// it, though never less than half of the context: longer generations have the
+    /// prompt roll out of it anyway
+    pub fn prompt_budget(&self, max_new_tokens: usize) -> usize {
+        self.num_tokens
+            .saturating_sub(max_new_tokens)
+            .max(self.num_tokens.div_ceil(2))
+    }
+
+    /// The tokens of `prompt` that fit in the `prompt_budget` for generating
+    /// `max_new_tokens`, `truncation` telling which ones
+    pub fn fit_prompt(
+        &self,
+        prompt: &[usize],
+        max_new_tokens: usize,
+        truncation: Truncation,
+    ) -> Vec<usize> {
+        truncate_prompt(prompt, self.prompt_budget(max_new_tokens), truncation)
+    }
+
+    pub fn set_distillation(&mut self, distillation: Option<Distillation>) {
+        self.distillation = distillation;
+    }
+
+    /// Trains another output of the model along with the logits (See `AuxiliaryLoss`),
+    /// the gradients of all the losses being accumulated before each step of the
+    /// optimizer. The reported loss stays the next-token one.
+    pub fn add_auxiliary_loss(&mut self, loss: AuxiliaryLoss) {
+        self.auxiliary_losses.push(loss);
+    }
+
+    /// Adds a linear head predicting, from the normalized output of the last block, the
+    /// token `offset` tokens after the next one (See `FutureTokens`), trained with a
+    /// loss weighted by `weight`. Its parameters are trained and saved along with the
+    /// others. Returns its logits.
+    pub fn add_prediction_head<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        offset: usize,
+        weight: f32,
+    ) -> Result<TensorId, GraphError> {
+        let norm_out = self.hidden[self.hidden.len() - 1];
+        let embedding_degree = self.graph.get_shape(norm_out)?.last().copied().unwrap_or(0);
+        let weights = self.graph.alloc_rand(
+            rng,
+            &[embedding_degree, self.vocab_size],
+            format!("aux_head{}_weights", offset),
+        );
+        let bias =
+            self.graph
+                .alloc_rand(rng, &[self.vocab_size], format!("aux_head{}_bias", offset));
+        let product = self.graph.call(MatMul::new(), &[norm_out, weights])?;
+        let logits = self.graph.call(Add::new(), &[product, bias])?;
+        self.params.extend([weights, bias]);
+        let params = self.params.iter().cloned().collect();
+        self.graph.set_precision(self.precision(), &params);
+        self.add_auxiliary_loss(AuxiliaryLoss {
+            output: logits,
+            objective: Arc::new(FutureTokens {
+                vocab_size: self.vocab_size,
+                num_tokens: self.num_tokens,
+                offset,
+            }),
+            weight,
+        });
+        Ok(logits)
+    }
+
+    /// Scales the loss of each position by the weight of its target token, e.g. its
+    /// inverse frequency in the dataset (See `dataset::inverse_frequencies`), so that
+    /// the model isn't pushed to over-predict whitespace and common characters. (The
+    /// weights of a window being normalized, only their ratios matter)
+    pub fn set_token_weights(&mut self, token_weights: Option<Vec<f32>>) -> Result<(), GraphError> {
+        if let Some(weights) = &token_weights {
+            if weights.len() != self.vocab_size {
+                return Err(
+                    TensorError::unexpected_shape("set_token_weights", &[weights.len()]).into(),
+                );
+            }
+        }
+        self.token_weights = token_weights;
+        Ok(())
+    }
+
+    /// How the optimizer applies weight decay, and to which parameters (By name)
+    pub fn set_weight_decay(&mut self, weight_decay: WeightDecay) {
+        self.weight_decay = weight_decay;
+    }
+
+    /// Layer-wise learning-rate decay, for fine-tuning: the learning rate of each block
+    /// is `layer_decay` times that of the block above it, the output layer getting the
+    /// full learning rate, and the embeddings the lowest. (1 for none)
+    pub fn set_layer_decay(&mut self, layer_decay: f32) {
+        self.layer_decay = layer_decay;
+    }
+
+    /// Only update the rows of the embedding tables the windows of each step touch
+    /// (Lazily: the moments of the other rows aren't decayed either, as in PyTorch's
+    /// `SparseAdam`), which saves most of the work of the optimizer for large
+    /// vocabularies. Not in clusters.
+    pub fn set_sparse_embeddings(&mut self, sparse_embeddings: bool) {
+        self.sparse_embeddings = sparse_embeddings;
+    }
+
+    /// Factor of the learning rate of the parameter named `name` (See `set_layer_decay`)
+    fn lr_scale(&self, name: &str) -> f32 {
+        let num_layers = self.hidden.len() - 1;
+        // Blocks have their index in the names of their parameters, e.g. `proj_2_bias`
+        let depth = match name.split('_').find_map(|part| part.parse::<usize>().ok()) {
+            Some(l) => l + 1,
+            None if name.ends_with("embedding") => 0,
+            None => num_layers + 1,
+        };
+        self.layer_decay.powi((num_layers + 1 - depth) as i32)
+    }
+
+    pub fn set_curriculum(&mut self, curriculum: Option<Curriculum>) {
+        self.curriculum = curriculum;
+    }
+
+    /// Number of tokens per window the next training step will use
+    pub fn context_length(&self) -> usize {
+        self.curriculum.as_ref().map_or(self.num_tokens, |c| {
+            c.context_length(self.optimizer.step_num(), self.num_tokens)
+        })
+    }
+
+    pub fn set_training_state(
+        &mut self,
+        training_state: TrainingState<O>,
+        load_optimizer: bool,
+    ) -> Result<(), GraphError> {
+        for p in self.params.iter() {
+            let name = self.graph.name_of(*p)?;
+            if let Some(t) = training_state.tensors.get(name) {
+                let expected = self.graph.get_shape(*p)?;
+                if t.shape() != expected {
+                    return Err(TensorError::shape_mismatch(
+                        "set_training_state",
+                        expected,
+                        t.shape(),
+                    )
+                    .into());
+                }
+                self.graph.load(*p, t);
+            }
+        }
+        if load_optimizer {
+            self.optimizer = training_state.optimizer;
+            self.data_seed = training_state.data_seed;
+        }
+        self.provenance = training_state.provenance;
+        Ok(())
+    }
+
+    pub fn get_training_state(&self) -> Result<TrainingState<O>, GraphError> {
+        let mut state = TrainingState {
+            tensors: Default::default(),
+            optimizer: self.optimizer.clone(),
+            data_seed: self.data_seed,
+            provenance: self.provenance,
+        };
+        for p in self.params.iter() {
+            let k = self.graph.name_of(*p)?.to_string();
+            let v = self.graph.get_param(*p)?.into_owned();
+            state.tensors.insert(k, v);
+        }
+        Ok(state)
+    }
+
+    pub(crate) fn named_params(&self) -> Result<Vec<NamedParam<'_>>, GraphError> {
+        self.params
+            .iter()
+            .map(|p| Ok((self.graph.name_of(*p)?.as_str(), self.graph.get_param(*p)?)))
+            .collect()
+    }
+
+    /// Loads the given tensors into the parameters with the same names. Returns
+    /// the names of the parameters that were not given.
+    fn load_named_params(
+        &mut self,
+        op: &'static str,
+        tensors: Vec<(String, Tensor<f32>)>,
+    ) -> Result<Vec<String>, GraphError> {
+        let tensors = tensors.into_iter().collect::<HashMap<_, _>>();
+        let mut missing = Vec::new();
+        for p in self.params.iter() {
+            let name = self.graph.name_of(*p)?.clone();
+            match tensors.get(&name) {
+                Some(t) => {
+                    let expected = self.graph.get_shape(*p)?;
+                    if t.shape() != expected {
+                        return Err(TensorError::shape_mismatch(op, expected, t.shape()).into());
+                    }
+                    self.graph.load(*p, t);
+                }
+                None => missing.push(name),
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Loads `tensor` into the parameter named `name`. Returns false if the model has
+    /// no such parameter.
+    pub(crate) fn load_param(
+        &mut self,
+        op: &'static str,
+        name: &str,
+        tensor: &Tensor<f32>,
+    ) -> Result<bool, GraphError> {
+        for p in self.params.iter() {
+            if self.graph.name_of(*p)? == name {
+                let expected = self.graph.get_shape(*p)?;
+                if tensor.shape() != expected {
+                    return Err(TensorError::shape_mismatch(op, expected, tensor.shape()).into());
+                }
+                self.graph.load(*p, tensor);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Writes the parameters as a NumPy `.npz` archive, keyed by their names, so
+    /// that they can be inspected with `numpy.load`
+    pub fn export_npz<W: std::io::Write>(&self, w: W) -> Result<(), GraphError> {
+        let params = self.named_params()?;
+        let params = params
+            .iter()
+            .map(|(name, t)| (*name, t.as_ref()))
+            .collect::<Vec<_>>();
+        Ok(write_npz(w, &params)?)
+    }
+
+    /// Loads the parameters found in a `.npz` archive, by name. Returns the names
+    /// of the parameters that were not present in the archive.
+    pub fn import_npz<R: std::io::Read>(&mut self, r: R) -> Result<Vec<String>, GraphError> {
+        self.load_named_params("import_npz", read_npz::<f32, _>(r)?)
+    }
+
+    /// Writes the parameters in the checksummed format of `write_tensors`
+    pub fn write_params<W: std::io::Write>(&self, w: W) -> Result<(), GraphError> {
+        let params = self.named_params()?;
+        let params = params
+            .iter()
+            .map(|(name, t)| (*name, t.as_ref()))
+            .collect::<Vec<_>>();
+        Ok(write_tensors(w, &params)?)
+    }
+
+    /// Loads the parameters written by `write_params`, failing on corrupted or
+    /// truncated input. Returns the names of the parameters that were not present.
+    pub fn read_params<R: std::io::Read>(&mut self, r: R) -> Result<Vec<String>, GraphError> {
+        self.load_named_params("read_params", read_tensors::<f32, _>(r)?)
+    }
+
+    /// Runs the model off the parameters of `source`, by name: each is only read, and
+    /// held, while the functions that take it run (See `Graph::stream`), for models
+    /// larger than the memory to generate, slowly. The model can't be trained nor
+    /// saved afterwards. (See `stream::MappedParams`, for streaming them from disk)
+    pub fn stream_params(&mut self, source: Arc<dyn TensorSource>) -> Result<(), GraphError> {
+        self.graph.stream(source, &self.params)
+    }
+
+    /// Trains on `batch` (A few sequences, see `train_weighted`) over and over, checking
+    /// that its loss gets below `OVERFIT_LOSS` within `OVERFIT_STEPS` steps, as any
+    /// model wired right to its tokenizer and optimizer manages: a diagnostic to run
+    /// before a long run. The model and its optimizer are restored afterwards. Returns
+    /// the loss of each step, or `GraphError::OverfitFailed`.
+    pub fn overfit_sanity_check(
+        &mut self,
+        batch: &[Vec<usize>],
+        lr: f32,
+    ) -> Result<Vec<f32>, GraphError> {
+        let state = self.get_training_state()?;
+        let guards = (
+            self.spike_guard.take(),
+            self.noise_scale.take(),
+            self.dp_sgd.take(),
+            self.loss_scaler.clone(),
+        );
+        let result = self.overfit(batch, lr);
+        self.set_training_state(state, true)?;
+        (
+            self.spike_guard,
+            self.noise_scale,
+            self.dp_sgd,
+            self.loss_scaler,
+        ) = guards;
+        result
+    }
+
+    fn overfit(&mut self, batch: &[Vec<usize>], lr: f32) -> Result<Vec<f32>, GraphError> {
+        let weights = vec![1.; batch.len()];
+        let mut losses = Vec::with_capacity(OVERFIT_STEPS);
+        for _ in 0..OVERFIT_STEPS {
+            let loss = self.train_weighted(batch, &weights, lr)?.loss;
+            losses.push(loss);
+            if loss < OVERFIT_LOSS {
+                return Ok(losses);
+            }
+        }
+        Err(GraphError::OverfitFailed {
+            loss: losses.iter().copied().fold(f32::INFINITY, f32::min),
+            steps: OVERFIT_STEPS,
+        })
+    }
+
+    /// Backpropagates the loss of `window` with every function also run in f64 (See
+    /// `Graph::set_numerics_check`), without touching the model. Returns the functions,
+    /// the most divergent first.
+    pub fn check_numerics(&self, window: &Window) -> Result<Vec<Divergence>, GraphError> {
+        let mut graph = self.graph.clone();
+        graph.set_numerics_check(true);
+        let context = (window.tokens.len() - 1).min(self.num_tokens);
+        self.backward_window(&mut graph, window, context, None)?;
+        Ok(graph.divergences())
+    }
+
+    /// Records a forward and a backward pass of the loss of predicting each of `tokens`
+    /// (The last ones fitting in the context) from the ones before it, function by
+    /// function (See `Graph::set_tape`), without touching the model
+    pub fn record_tape(&self, tokens: &[usize]) -> Result<Tape, GraphError> {
+        if tokens.len() < 2 {
+            return Err(TensorError::unexpected_shape("record_tape", &[tokens.len()]).into());
+        }
+        let window = Window {
+            tokens: tokens[tokens.len().saturating_sub(self.num_tokens + 1)..].to_vec(),
+            source: 0,
+            documents: None,
+        };
+        let mut graph = self.graph.clone();
+        graph.set_tape(true);
+        self.backward_window(&mut graph, &window, window.tokens.len() - 1, None)?;
+        Ok(graph.tape().cloned().unwrap_or_default())
+    }
+
+    /// Backpropagates the loss of the first `context` tokens of `window` through
+    /// `graph`, leaving the gradients of the parameters in it. Returns the loss, how
+    /// well the tokens of the window were predicted, and the gradients of the
+    /// embedding tables, zero outside of a few rows.
+    fn backward_window(
+        &self,
+        graph: &mut Graph,
+        window: &Window,
+        context: usize,
+        limit: Option<usize>,
+    ) -> Result<(f32, TokenAccuracy, SparseGrads), GraphError> {
+        if graph.is_streamed() {
+            return Err(GraphError::Streamed);
+        }
+        if context == 0 || context >= window.tokens.len() {
+            return Err(
+                TensorError::unexpected_shape("backward_window", &[window.tokens.len()]).into(),
+            );
+        }
+        let poses = Tensor::raw(
+            &[self.num_tokens],
+            (0..self.num_tokens).cycle().take(self.num_tokens).collect(),
+        )?;
+        let (xs, ys, mut weights) = window_tensors(window, context, self.num_tokens)?;
+        if let Some(token_weights) = &self.token_weights {
+            for (w, t) in weights.blob_mut().iter_mut().zip(ys.blob()).take(context) {
+                *w *= token_weights[*t];
+            }
+        }
+        if let Some(documents) = &window.documents {
+            // Padding gets a document of its own
+            let mut documents = documents[..context].to_vec();
+            documents.resize(self.num_tokens, usize::MAX);
+            graph.load(self.attention_bias, &document_mask(&documents)?);
+        }
+        graph.embed(self.token_input, self.token_embedding, &xs)?;
+        graph.embed(self.pos_input, self.pos_embedding, &poses)?;
+        graph.forward(true)?;
+        graph.zero_grad();
+        let mut accuracy = TokenAccuracy::default();
+        let output = graph.get(self.output)?;
+        for (logits, target) in output
+            .blob()
+            .chunks(self.vocab_size)
+            .zip(ys.blob())
+            .take(context)
+        {
+            accuracy.record(rank(logits, *target));
+        }
+        let loss = if let Some(distillation) = &self.distillation {
+            let soft_targets = distillation.teacher.soft_targets(window, context)?;
+            if soft_targets.shape() != [context, self.vocab_size] {
+                return Err(TensorError::shape_mismatch(
+                    "Distillation",
+                    &[context, self.vocab_size],
+                    soft_targets.shape(),
+                )
+                .into());
+            }
+            // The teacher gives no targets for the padding, whose weights are zero
+            let mut soft_targets = soft_targets.blob().to_vec();
+            soft_targets.resize(self.num_tokens * self.vocab_size, 0.);
+            let soft_targets = Tensor::raw(&[self.num_tokens, self.vocab_size], soft_targets)?;
+            Blend::new(
+                KlDivergence::weighted(
+                    self.vocab_size,
+                    soft_targets,
+                    distillation.temperature,
+                    weights.clone(),
+                ),
+                CrossEntropy::weighted(self.vocab_size, ys, weights),
+                distillation.alpha,
+            )
+        } else if context < self.num_tokens || self.token_weights.is_some() {
+            CrossEntropy::weighted(self.vocab_size, ys, weights)
+        } else {
+            CrossEntropy::new(self.vocab_size, ys)
+        };
+        let mut heads = vec![(self.output, loss, 1.)];
+        for aux in self.auxiliary_losses.iter() {
+            heads.push((aux.output, aux.objective.loss(window, context)?, aux.weight));
+        }
+        let err = graph.backward_heads(heads, limit)?[0];
+        // Only the rows of the tokens (And positions) of the window get gradients
+        let mut sparse = vec![(
+            self.token_embedding,
+            unembed(&xs, graph.get_grad(self.token_input)?.as_ref(), context)?,
+        )];
+        if self.positional_encoding == PositionalEncoding::Learned {
+            sparse.push((
+                self.pos_embedding,
+                unembed(&poses, graph.get_grad(self.pos_input)?.as_ref(), context)?,
+            ));
+        }
+        for (id, grad) in sparse.iter() {
+            graph.add_sparse_grad(*id, grad)?;
+        }
+        Ok((err, accuracy, sparse))
+    }
+
+    /// Random generator of the `index`th window of the current step. The order of the
+    /// data only depends on the seed, the step (Which is stored with the optimizer),
+    /// and the rank of the machine in the cluster, so it survives restarts and doesn't
+    /// depend on the number of threads.
+    fn data_rng(&self, index: usize) -> StdRng {
+        self.step_rng(RngStream::Data, index)
+    }
+
+    /// Random generator of `stream` for the `index`th window of the current step, as
+    /// `data_rng`. The training state holding the seed and the step, it's all the
+    /// state of the random generators there is to save.
+    fn step_rng(&self, stream: RngStream, index: usize) -> StdRng {
+        let rank = self.cluster.as_ref().map_or(0, |c| c.rank());
+        let mut seed = self.data_seed;
+        // The data stream mixes in nothing more, so that the data order is the same
+        // as that of the states saved before there were other streams
+        let values = [self.optimizer.step_num(), rank, index, stream as usize];
+        for v in &values[..3 + usize::from(stream != RngStream::Data)] {
+            let v = *v;
+            // SplitMix64
+            seed = seed.wrapping_add(v as u64).wrapping_add(0x9E3779B97F4A7C15);
+            seed = (seed ^ (seed >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            seed = (seed ^ (seed >> 27)).wrapping_mul(0x94D049BB133111EB);
+            seed ^= seed >> 31;
+        }
+        StdRng::seed_from_u64(seed)
+    }
+
+    pub fn data_seed(&self) -> u64 {
+        self.data_seed
+    }
+
+    pub fn set_data_seed(&mut self, data_seed: u64) {
+        self.data_seed = data_seed;
+    }
+
+    pub fn provenance(&self) -> Provenance {
+        self.provenance
+    }
+
+    /// What the model is trained on from now on, saved with its training state
+    pub fn set_provenance(&mut self, provenance: Provenance) {
+        self.provenance = provenance;
+    }
+
+    /// Fails if `tokenizer` isn't the one the model was trained with, as its training
+    /// state tells (Those of earlier versions, which don't, accept any)
+    pub fn check_tokenizer<T: Tokenizer + ?Sized>(&self, tokenizer: &T) -> Result<(), GraphError> {
+        let found = crate::run::tokenizer_hash(tokenizer);
+        match self.provenance.tokenizer_hash {
+            Some(expected) if expected != found => {
+                Err(GraphError::TokenizerMismatch { expected, found })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of threads the samples of a batch are spread over
+    pub fn num_threads(&self) -> usize {
+        self.thread_pool
+            .as_ref()
+            .map_or_else(rayon::current_num_threads, |p| p.current_num_threads())
+    }
+
+    /// Use a dedicated pool of `num_threads` threads for training, instead of the
+    /// global one of rayon (Which has as many threads as CPUs)
+    pub fn set_num_threads(&mut self, num_threads: usize) -> Result<(), GraphError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .map_err(|e| GraphError::ThreadPoolError(e.to_string()))?;
+        self.thread_pool = Some(Arc::new(pool));
+        Ok(())
+    }
+
+    /// Computes the gradients of a batch of `batch_size` windows, summed over the batch
+    /// (In the order of the parameters). `sample` gives the `index`th window, how many
+    /// of its tokens to train on, and the weight of its loss. The batch is split in one
+    /// shard per thread, each thread working on its own copy of the graph.
+    fn batch_gradients<F>(
+        &self,
+        batch_size: usize,
+        sample: F,
+        limit: Option<usize>,
+    ) -> Result<BatchGradients, GraphError>
+    where
+        F: Fn(usize) -> (Window, usize, f32) + Sync,
+    {
+        let loss_scale = self.loss_scaler.as_ref().map_or(1., |s| s.scale);
+        let num_shards = self.num_threads().clamp(1, batch_size.max(1));
+        let run = || {
+            (0..num_shards)
+                .into_par_iter()
+                .map(|shard| {
+                    let count =
+                        batch_size / num_shards + usize::from(shard < batch_size % num_shards);
+                    let first =
+                        shard * (batch_size / num_shards) + shard.min(batch_size % num_shards);
+                    let mut graph = self.graph.clone();
+                    let mut grads = self
+                        .params
+                        .iter()
+                        .map(|id| Ok(Tensor::<f32>::zeros(self.graph.get_shape(*id)?)))
+                        .collect::<Result<Vec<_>, GraphError>>()?;
+                    let mut rows: Vec<Option<BTreeSet<usize>>> = vec![None; self.params.len()];
+                    let mut errs = Vec::with_capacity(count);
+                    let mut accuracy = TokenAccuracy::default();
+                    let mut sample_sq_norms = 0.;
+                    let mut windows = Vec::with_capacity(count);
+                    for index in first..first + count {
+                        let (window, context, weight) = sample(index);
+                        crate::funcs::seed_dropout(self.step_rng(RngStream::Dropout, index).gen());
+                        // Weighing the loss is weighing its gradients
+                        graph.set_loss_scale(loss_scale * weight);
+                        let (err, window_accuracy, mut sparse) =
+                            self.backward_window(&mut graph, &window, context, limit)?;
+                        // DP-SGD bounds the contribution of every sample to the batch
+                        let clip = match &self.dp_sgd {
+                            Some(dp) => {
+                                let mut sq_norm = 0.;
+                                for id in self.params.iter() {
+                                    sq_norm += match sparse.iter().find(|(s, _)| s == id) {
+                                        Some((_, sparse)) => sparse.sq_norm(),
+                                        None => graph
+                                            .get_grad(*id)?
+                                            .blob()
+                                            .iter()
+                                            .map(|g| g * g)
+                                            .sum::<f32>(),
+                                    };
+                                }
+                                dp.clip_factor(sq_norm.sqrt() / loss_scale)
+                            }
+                            None => 1.,
+                        };
+                        if clip != 1. {
+                            sparse.iter_mut().for_each(|(_, s)| s.scale(clip));
+                        }
+                        for ((grad, rows), id) in grads
+                            .iter_mut()
+                            .zip(rows.iter_mut())
+                            .zip(self.params.iter())
+                        {
+                            if let Some((_, sparse)) = sparse.iter().find(|(s, _)| s == id) {
+                                if self.noise_scale.is_some() {
+                                    sample_sq_norms += sparse.sq_norm();
+                                }
+                                sparse.add_to(grad)?;
+                                rows.get_or_insert_with(BTreeSet::new)
+                                    .extend(sparse.indices());
+                                continue;
+                            }
+                            let mut sample_grad = graph.get_grad(*id)?;
+                            if clip != 1. {
+                                sample_grad = Cow::Owned(sample_grad.map_values(|g| g * clip));
+                            }
+                            if self.noise_scale.is_some() {
+                                sample_sq_norms +=
+                                    sample_grad.blob().iter().map(|g| g * g).sum::<f32>();
+                            }
+                            grad.add_assign(sample_grad.as_ref())?;
+                        }
+                        errs.push(err * weight);
+                        accuracy.merge(&window_accuracy);
+                        windows.push(window);
+                    }
+                    Ok((grads, rows, errs, accuracy, sample_sq_norms, windows))
+                })
+                .collect::<Result<Vec<_>, GraphError>>()
+        };
+        let shards = match &self.thread_pool {
+            Some(pool) => pool.install(run)?,
+            None => run()?,
+        };
+        let mut grads: Option<Vec<Tensor<f32>>> = None;
+        let mut rows: Vec<Option<BTreeSet<usize>>> = vec![None; self.params.len()];
+        let mut errs = Vec::with_capacity(batch_size);
+        let mut accuracy = TokenAccuracy::default();
+        let mut sample_sq_norms = 0.;
+        let mut windows = Vec::with_capacity(batch_size);
+        for (shard_grads, shard_rows, shard_errs, shard_accuracy, shard_sq_norms, shard_windows) in
+            shards
+        {
+            match &mut grads {
+                Some(grads) => {
+                    for (a, b) in grads.iter_mut().zip(shard_grads.iter()) {
+                        a.add_assign(b)?;
+                    }
+                }
+                None => grads = Some(shard_grads),
+            }
+            for (rows, shard_rows) in rows.iter_mut().zip(shard_rows) {
+                if let Some(shard_rows) = shard_rows {
+                    rows.get_or_insert_with(BTreeSet::new).extend(shard_rows);
+                }
+            }
+            errs.extend(shard_errs);
+            accuracy.merge(&shard_accuracy);
+            sample_sq_norms += shard_sq_norms;
+            windows.extend(shard_windows);
+        }
+        Ok(BatchGradients {
+            grads: grads.unwrap_or_default(),
+            rows: rows
+                .into_iter()
+                .map(|r| r.map(|r| r.into_iter().collect()))
+                .collect(),
+            losses: errs,
+            accuracy,
+            sample_sq_norms,
+            windows,
+        })
+    }
+
+    /// Trains as a member of `cluster`: gradients get averaged over all the machines
+    /// of the cluster on each step. The weights and the optimizer of the coordinator
+    /// are sent to the workers first, so that all of them start from the same state.
+    pub fn set_cluster(&mut self, mut cluster: Cluster) -> Result<(), GraphError> {
+        if cluster.num_params() != self.num_params() {
+            return Err(TensorError::shape_mismatch(
+                "set_cluster",
+                &[self.num_params()],
+                &[cluster.num_params()],
+            )
+            .into());
+        }
+        let state = cluster.broadcast(self.get_training_state()?)?;
+        if !cluster.is_coordinator() {
+            self.set_training_state(state, true)?;
+        }
+        self.cluster = Some(cluster);
+        Ok(())
+    }
+
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.as_ref()
+    }
+
+    /// Runs a single optimization step on a random batch of `dataset`
+    pub fn train_step<D: Dataset + ?Sized>(
+        &mut self,
+        dataset: &D,
+        batch_size: usize,
+        limit: Option<usize>,
+        lr: f32,
+    ) -> Result<StepStats, GraphError> {
+        let _span =
+            tracing::debug_span!("train_step", step = self.optimizer.step_num() + 1).entered();
+        let timer = Instant::now();
+        let context = self.context_length();
+        let batch = self.batch_gradients(
+            batch_size,
+            |index| {
+                let window = dataset.sample(&mut self.data_rng(index), context);
+                // Shorter windows are right-padded
+                let padded = window.tokens.len().saturating_sub(1).min(context);
+                (window, padded, 1.)
+            },
+            limit,
+        )?;
+        let mut sources = vec![0; dataset.num_sources()];
+        for w in batch.windows.iter() {
+            sources[w.source] += 1;
+        }
+        let sample_rate = batch_size as f64 / dataset.num_windows(context).max(1) as f64;
+        self.apply_batch(batch, sources, context, lr, sample_rate, timer)
+    }
+
+    /// Runs a single optimization step on `samples`, the loss of each sequence being
+    /// scaled by its weight. (E.g. its reward, for reward-weighted regression, or one
+    /// for the best of n generations and zero for the others, for best-of-n
+    /// distillation) Sequences longer than `num_tokens + 1` tokens are truncated.
+    pub fn train_weighted(
+        &mut self,
+        samples: &[Vec<usize>],
+        weights: &[f32],
+        lr: f32,
+    ) -> Result<StepStats, GraphError> {
+        if samples.len() != weights.len() {
+            return Err(TensorError::shape_mismatch(
+                "train_weighted",
+                &[samples.len()],
+                &[weights.len()],
+            )
+            .into());
+        }
+        if let Some(short) = samples.iter().find(|s| s.len() < 2) {
+            return Err(TensorError::unexpected_shape("train_weighted", &[short.len()]).into());
+        }
+        if let Some(token) = samples.iter().flatten().find(|t| **t >= self.vocab_size) {
+            return Err(TensorError::unexpected_shape("train_weighted", &[*token]).into());
+        }
+        let _span = tracing::debug_span!(
+            "train_weighted",
+            step = self.optimizer.step_num() + 1,
+            samples = samples.len()
+        )
+        .entered();
+        let timer = Instant::now();
+        let context = |s: &[usize]| (s.len() - 1).min(self.num_tokens);
+        let batch = self.batch_gradients(
+            samples.len(),
+            |index| {
+                let tokens = &samples[index];
+                let window = Window {
+                    tokens: tokens[..context(tokens) + 1].to_vec(),
+                    source: 0,
+                    documents: None,
+                };
+                (window, context(tokens), weights[index])
+            },
+            None,
+        )?;
+        let longest = samples.iter().map(|s| context(s)).max().unwrap_or(0);
+        // The samples are all there is to train on, as far as privacy goes
+        self.apply_batch(batch, vec![samples.len()], longest, lr, 1., timer)
+    }
+
+    /// Averages the gradients of a batch (Over the cluster, if any) and updates the
+    /// parameters with them. `sample_rate` is the probability of a sample being in the
+    /// batch, for the privacy accountant of DP-SGD.
+    fn apply_batch(
+        &mut self,
+        batch: BatchGradients,
+        sources: Vec<usize>,
+        context: usize,
+        lr: f32,
+        sample_rate: f64,
+        timer: Instant,
+    ) -> Result<StepStats, GraphError> {
+        if self.dp_sgd.is_some() && self.cluster.is_some() {
+            return Err(GraphError::DpSgdCluster);
+        }
+        let loss_scale = self.loss_scaler.as_ref().map_or(1., |s| s.scale);
+        self.graph.set_loss_scale(loss_scale);
+        let BatchGradients {
+            grads,
+            rows,
+            losses,
+            accuracy,
+            sample_sq_norms,
+            ..
+        } = batch;
+        // The gradients of the samples are only known locally, which is enough for
+        // their mean squared norm
+        let sample_sq = sample_sq_norms / (losses.len().max(1) as f32 * loss_scale * loss_scale);
+        let mut total = Gradients {
+            grads,
+            loss: losses.iter().sum(),
+            samples: losses.len(),
+            accuracy,
+        };
+        if let Some(cluster) = &mut self.cluster {
+            total = cluster.all_reduce(total)?;
+        }
+        let avg_loss = total.loss / total.samples as f32;
+        let mut noise_rng = self.step_rng(RngStream::Noise, 0);
+        if let Some(dp) = &mut self.dp_sgd {
+            // Noise of the summed gradients, which are scaled like the loss
+            let std = dp.noise_std() * loss_scale;
+            if std > 0. {
+                let rng = &mut noise_rng;
+                for grad in total.grads.iter_mut() {
+                    let noisy = grad
+                        .blob()
+                        .iter()
+                        .map(|g| g + std * rng.sample::<f32, _>(StandardNormal))
+                        .collect();
+                    *grad = Tensor::raw(grad.shape(), noisy)?;
+                }
+            }
+            dp.account(sample_rate, 1);
+        }
+        // Still scaled, for the small ones not to underflow in f16 (The graph unscales
+        // them in f32)
+        let coeff = 1. / total.samples as f32;
+        for (id, grad) in self.params.clone().into_iter().zip(total.grads) {
+            self.graph.load_grad(id, &grad.map_values(|f| f * coeff));
+        }
+        let grad_norm = self.graph.grad_norm(&self.params)?;
+        if let Some(scaler) = &mut self.loss_scaler {
+            // Overflowing in f32, or once stored in f16
+            let finite = grad_norm.is_finite();
+            scaler.update(finite);
+            if !finite {
+                tracing::debug!(
+                    loss_scale = scaler.scale,
+                    "Gradients overflowed, step skipped"
+                );
+                let elapsed = timer.elapsed();
+                return Ok(StepStats {
+                    step: self.optimizer.step_num(),
+                    skipped: true,
+                    loss: avg_loss,
+                    learning_rate: lr,
+                    grad_norm,
+                    update_ratios: Vec::new(),
+                    elapsed,
+                    sources,
+                    context,
+                    tokens_per_second: (total.samples * context) as f32 / elapsed.as_secs_f32(),
+                    accuracy: total.accuracy,
+                    noise_scale: self.noise_scale.as_ref().and_then(|n| n.batch_size()),
+                    epsilon: self.dp_sgd.as_ref().map(|dp| dp.epsilon()),
+                });
+            }
+        }
+        if let Some(noise_scale) = &mut self.noise_scale {
+            noise_scale.update(sample_sq, grad_norm * grad_norm, total.samples);
+        }
+        let old_params = if self.measure_updates {
+            self.params
+                .iter()
+                .map(|id| Ok(self.graph.get_param(*id)?.into_owned()))
+                .collect::<Result<Vec<_>, GraphError>>()?
+        } else {
+            Vec::new()
+        };
+        // The optimizer is given the parameters in the order of their ids
+        let mut ids = self.params.clone();
+        ids.sort();
+        let mask = ids
+            .iter()
+            .map(|id| Ok(self.weight_decay.applies_to(self.graph.name_of(*id)?)))
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        self.optimizer
+            .set_weight_decay(self.weight_decay.mode, mask);
+        // The rows touched by the machines of a cluster aren't known, and the noise of
+        // DP-SGD touches all of them
+        if self.sparse_embeddings && self.cluster.is_none() && self.dp_sgd.is_none() {
+            let rows = ids
+                .iter()
+                .map(|id| rows[self.params.iter().position(|p| p == id).unwrap()].clone())
+                .collect();
+            self.optimizer.set_sparse_rows(rows);
+        }
+        if self.layer_decay != 1. {
+            let scales = ids
+                .iter()
+                .map(|id| Ok(self.lr_scale(self.graph.name_of(*id)?)))
+                .collect::<Result<Vec<_>, GraphError>>()?;
+            self.optimizer.set_lr_scales(scales);
+        }
+        self.graph.optimize(
+            &mut self.optimizer,
+            &self.params.iter().cloned().collect(),
+            lr,
+        )?;
+        self.apply_prune_masks()?;
+        let elapsed = timer.elapsed();
+        let update_ratios = self
+            .params
+            .iter()
+            .zip(old_params.iter())
+            .map(|(id, old)| {
+                let new = self.graph.get_param(*id)?;
+                let update = (new.as_ref() - old)?;
+                let ratio = l2_norm(&update) / (l2_norm(old) + 1e-12);
+                Ok((self.graph.name_of(*id)?.clone(), ratio))
+            })
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        tracing::debug!(
+            loss = avg_loss,
+            grad_norm,
+            lr,
+            samples = total.samples,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Optimized"
+        );
+        Ok(StepStats {
+            step: self.optimizer.step_num(),
+            skipped: false,
+            loss: avg_loss,
+            learning_rate: lr,
+            grad_norm,
+            update_ratios,
+            elapsed,
+            sources,
+            context,
+            tokens_per_second: (total.samples * context) as f32 / elapsed.as_secs_f32(),
+            accuracy: total.accuracy,
+            noise_scale: self.noise_scale.as_ref().and_then(|n| n.batch_size()),
+            epsilon: self.dp_sgd.as_ref().map(|dp| dp.epsilon()),
+        })
+    }
+
+    /// Trains the model until one of the `stop` criteria is met (Or until it gets
+    /// interrupted), and tells which one it was. A summary of the run is logged, and
+    /// given to `TrainCallback::on_stop`.
+    pub fn train<D: Dataset + ?Sized, C: TrainCallback<O>>(
+        &mut self,
+        dataset: &D,
+        stop: &StopCriteria,
+        batch_size: usize,
+        limit: Option<usize>,
+        callback: &mut C,
+    ) -> Result<StopReason, GraphError> {
+        let _span = tracing::info_span!("train", batch_size).entered();
+        let tokens_per_step = batch_size * self.num_tokens;
+        // The state training started from, then the last one whose loss was fine
+        let mut last_good = match self.spike_guard {
+            Some(_) => Some(self.get_training_state()?),
+            None => None,
+        };
+        // Whether the model is still in the state of `last_good`
+        let mut at_good = true;
+        let mut loss_avg: Option<f32> = None;
+        let mut backoff_until = 0;
+        let timer = Instant::now();
+        let steps_per_epoch = dataset.num_tokens() as f32 / tokens_per_step as f32;
+        let mut step_duration: Option<Duration> = None;
+        let epoch = |gpt: &Self| gpt.optimizer.step_num() * tokens_per_step / dataset.num_tokens();
+        let mut steps = 0;
+        let reason = loop {
+            let i = steps;
+            if let Some(reason) = stop.check(i, epoch(self), timer.elapsed(), loss_avg) {
+                break reason;
+            }
+            steps += 1;
+            let mut lr = callback.learning_rate(self.optimizer.step_num());
+            if let Some(guard) = &self.spike_guard {
+                if self.optimizer.step_num() < backoff_until {
+                    lr *= guard.lr_backoff;
+                }
+            }
+            // Kept as the last good state if the loss of the step is fine
+            let candidate = match &self.spike_guard {
+                Some(_) if i > 0 && i % EVAL_INTERVAL == 0 => Some(self.get_training_state()?),
+                _ => None,
+            };
+            let prev_epoch = epoch(self);
+            self.measure_updates = i % EVAL_INTERVAL == 0;
+            let stats = self.train_step(dataset, batch_size, limit, lr);
+            self.measure_updates = true;
+            let stats = stats?;
+            let epoch = stats.step * tokens_per_step / dataset.num_tokens();
+            let avg = step_duration.map_or(stats.elapsed, |d| {
+                d.mul_f32(0.9) + stats.elapsed.mul_f32(0.1)
+            });
+            step_duration = Some(avg);
+            let eta = stop.remaining(i + 1, stats.step, steps_per_epoch, timer.elapsed(), avg);
+
+            if let Some(guard) = self.spike_guard.clone() {
+                let spiked = !stats.loss.is_finite()
+                    || loss_avg.is_some_and(|avg| stats.loss > guard.threshold * avg);
+                if spiked {
+                    tracing::debug!(
+                        step = stats.step,
+                        loss = stats.loss,
+                        average = loss_avg,
+                        "Loss spike"
+                    );
+                    match &last_good {
+                        Some(_) if at_good && !stats.loss.is_finite() => {
+                            return Err(GraphError::Diverged { step: stats.step });
+                        }
+                        Some(good) => self.set_training_state(good.clone(), true)?,
+                        None => return Err(GraphError::Diverged { step: stats.step }),
+                    }
+                    at_good = true;
+                    backoff_until = self.optimizer.step_num() + guard.backoff_steps;
+                    callback.on_rewind(&TrainContext {
+                        gpt: self,
+                        epoch,
+                        stats: &stats,
+                        eta,
+                    })?;
+                    if self.is_interrupted() {
+                        break StopReason::Interrupted;
+                    }
+                    continue;
+                }
+                if candidate.is_some() {
+                    last_good = candidate;
+                }
+                at_good = false;
+            }
+            loss_avg = Some(loss_avg.map_or(stats.loss, |avg| 0.9 * avg + 0.1 * stats.loss));
+
+            let ctx = TrainContext {
+                gpt: self,
+                epoch,
+                stats: &stats,
+                eta,
+            };
+            callback.on_step(&ctx)?;
+            if i % EVAL_INTERVAL == 0 {
+                callback.on_eval(&ctx)?;
+                callback.on_checkpoint(&ctx)?;
+            }
+            if !stats.skipped && epoch > prev_epoch {
+                callback.on_epoch_end(&ctx)?;
+            }
+            if self.is_interrupted() {
+                break StopReason::Interrupted;
+            }
+        };
+        let summary = TrainSummary {
+            gpt: self,
+            reason,
+            steps,
+            epoch: epoch(self),
+            elapsed: timer.elapsed(),
+            loss: loss_avg,
+        };
+        tracing::info!(
+            ?reason,
+            step = self.optimizer.step_num(),
+            steps,
+            epoch = summary.epoch,
+            elapsed_s = summary.elapsed.as_secs(),
+            loss = loss_avg,
+            "Training stopped"
+        );
+        callback.on_stop(&summary)?;
+        Ok(reason)
+    }
+
+    /// Learning-rate range test: trains for up to `num_steps` steps while growing
+    /// the learning-rate exponentially from `min_lr` to `max_lr`, and records the
+    /// loss of each step. The sweep stops early once the loss explodes. The weights
+    /// and the optimizer are restored afterwards, so the model is left untouched.
+    pub fn lr_find<D: Dataset + ?Sized>(
+        &mut self,
+        dataset: &D,
+        batch_size: usize,
+        limit: Option<usize>,
+        min_lr: f32,
+        max_lr: f32,
+        num_steps: usize,
+    ) -> Result<LrFinder, GraphError> {
+        let snapshot = self.get_training_state()?;
+        let mult = (max_lr / min_lr).powf(1. / (num_steps.max(2) - 1) as f32);
+        let beta = 0.98;
+        let mut sweep = || {
+            let mut avg_loss = 0.;
+            let mut best = (f32::INFINITY, min_lr);
+            let mut history = Vec::new();
+            let mut lr = min_lr;
+            for i in 0..num_steps {
+                let stats = self.train_step(dataset, batch_size, limit, lr)?;
+                // Exponential moving average, with bias correction
+                avg_loss = beta * avg_loss + (1. - beta) * stats.loss;
+                let smoothed = avg_loss / (1. - beta.powi(i as i32 + 1));
+                history.push((lr, smoothed));
+                if !smoothed.is_finite() || smoothed > 4. * best.0 {
+                    break;
+                }
+                if smoothed < best.0 {
+                    best = (smoothed, lr);
+                }
+                lr *= mult;
+            }
+            Ok::<_, GraphError>(LrFinder {
+                history,
+                suggested_lr: best.1 / 10.,
+            })
+        };
+        // Restored whether the sweep went through or not
+        let finder = sweep();
+        self.set_training_state(snapshot, true)?;
+        finder
+    }
+
+    /// Hidden states of the model for each of the last `num_tokens` of `tokens`, as a
+    /// `[tokens, embedding_degree]` tensor. They are the outputs of the transformer
+    /// block `layer` (Counting from zero), or by default the normalized output of the
+    /// last one, which the model maps to the logits.
+    pub fn hidden_states(
+        &self,
+        tokens: &[usize],
+        layer: Option<usize>,
+    ) -> Result<Tensor<f32>, GraphError> {
+        let num_layers = self.hidden.len() - 1;
+        let id = match layer {
+            None => self.hidden[num_layers],
+            Some(l) if l < num_layers => self.hidden[l],
+            Some(l) => return Err(TensorError::unexpected_shape("hidden_states", &[l]).into()),
+        };
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        let graph = self.run_window(tokens)?;
+        let states = graph.get(id)?;
+        let degree = states.shape()[states.dim() - 1];
+        let mut data = Vec::with_capacity(tokens.len() * degree);
+        for i in 0..tokens.len() {
+            data.extend_from_slice(states.get(i)?.blob());
+        }
+        Ok(Tensor::raw(&[tokens.len(), degree], data)?)
+    }
+
+    /// Runs the model on `tokens` (At most `num_tokens` of them), right-padded. Attention
+    /// is causal, so the padding doesn't change anything for the given tokens.
+    fn run_window(&self, tokens: &[usize]) -> Result<Graph, GraphError> {
+        let mut context = tokens.to_vec();
+        context.resize(self.num_tokens, 0);
+        let mut graph = self.inference_graph()?;
+        graph.embed(
+            self.token_input,
+            self.token_embedding,
+            &Tensor::raw(&[self.num_tokens], context)?,
+        )?;
+        graph.forward_inference()?;
+        Ok(graph)
+    }
+
+    /// Logits of the token following each of the last `num_tokens` of `tokens`, as a
+    /// `[tokens, vocab_size]` tensor
+    pub fn logits(&self, tokens: &[usize]) -> Result<Tensor<f32>, GraphError> {
+        if tokens.is_empty() {
+            return Err(TensorError::unexpected_shape("logits", &[0]).into());
+        }
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        let graph = self.run_window(tokens)?;
+        let output = graph.get(self.output)?;
+        Ok(Tensor::raw(
+            &[tokens.len(), self.vocab_size],
+            output.blob()[..tokens.len() * self.vocab_size].to_vec(),
+        )?)
+    }
+
+    /// Whether `logits_cached` can keep the attention of the model: it computes in f32
+    /// on its own, which only matches models whose graph does too. (Not those stored
+    /// in mixed precision, trained quantization-aware, or streamed)
+    pub fn can_cache(&self) -> bool {
+        self.precision() == Precision::F32 && !self.fake_quantized && !self.graph.is_streamed()
+    }
+
+    /// Logits of the token following `tokens` (Of which the last `num_tokens` are
+    /// seen, as by `logits`), only running the tokens `cache` doesn't hold through the
+    /// model: the attention of the tokens the window shares with the one of the last
+    /// call, from its start, is read from the cache, and that of the others is added
+    /// to it. (Rolling windows share little with the previous ones, unless their first
+    /// tokens are pinned, see `set_pinned_tokens`) A cache belongs to a single model,
+    /// and is stale once its weights change. The models that can't be cached (See
+    /// `can_cache`) run the whole window.
+    pub fn logits_cached(
+        &self,
+        cache: &mut KvCache,
+        tokens: &[usize],
+    ) -> Result<Tensor<f32>, GraphError> {
+        if tokens.is_empty() {
+            return Err(TensorError::unexpected_shape("logits_cached", &[0]).into());
+        }
+        if let Some(token) = tokens.iter().find(|t| **t >= self.vocab_size) {
+            return Err(TensorError::unexpected_shape("logits_cached", &[*token]).into());
+        }
+        let window = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        if !self.can_cache() {
+            cache.clear();
+            let graph = self.run_window(window)?;
+            return Ok(graph.get(self.output)?.get(window.len() - 1)?.into());
+        }
+        let arch = self.architecture;
+        let (d, head_size) = (arch.embedding_degree, arch.head_size);
+        // The last token is run again, for its logits
+        let start = cache
+            .tokens
+            .iter()
+            .zip(window)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(window.len() - 1);
+        cache.truncate(start, &arch);
+        let new = &window[start..];
+
+        let ids = self
+            .params
+            .iter()
+            .map(|id| Ok((self.graph.name_of(*id)?.as_str(), *id)))
+            .collect::<Result<HashMap<_, _>, GraphError>>()?;
+        let param = |name: String| match ids.get(name.as_str()) {
+            Some(id) => self.graph.get_param(*id),
+            None => Err(TensorError::InvalidFormat(format!("no tensor named {}", name)).into()),
+        };
+        let norm = |x: &Tensor<f32>, coeff: &Tensor<f32>, bias: &Tensor<f32>| {
+            LayerNorm::new().run(&[x, coeff, bias], false)
+        };
+        let linear = |x: &Tensor<f32>, name: &str, l: usize| -> Result<_, GraphError> {
+            let weights = param(format!("{}_{}_weights", name, l))?;
+            let bias = param(format!("{}_{}_bias", name, l))?;
+            Ok((&(x ^ &*weights)? + &*bias)?)
+        };
+
+        let (embedding, positions) = (
+            param("token_embedding".into())?,
+            self.graph.get(self.pos_embedding)?,
+        );
+        let mut rows = Vec::with_capacity(new.len() * d);
+        for (r, token) in new.iter().enumerate() {
+            let (e, p) = (embedding.get(*token)?, positions.get(start + r)?);
+            rows.extend(e.blob().iter().zip(p.blob()).map(|(e, p)| e + p));
+        }
+        let mut x = Tensor::raw(&[new.len(), d], rows)?;
+        let scale = (head_size as f32).powf(-0.5);
+        for l in 0..arch.num_layers {
+            let coeff = param(format!("norm_{}_coeff", l))?;
+            let norm_inp = norm(&x, &coeff, &*param(format!("norm_{}_bias", l))?)?;
+            // The heads side by side, as `Cat` puts them
+            let mut heads = vec![0.; new.len() * arch.num_heads * head_size];
+            for h in 0..arch.num_heads {
+                let project = |w: &str| -> Result<Tensor<f32>, GraphError> {
+                    let t = (&norm_inp ^ &*param(format!("head_{}_{}_{}", l, h, w))?)?;
+                    Ok(match self.positional_encoding {
+                        PositionalEncoding::Rope if w != "v" => {
+                            rope_rotate(&t, ROPE_BASE, 1., start)?
+                        }
+                        _ => t,
+                    })
+                };
+                let (k, q, v) = (project("k")?, project("q")?, project("v")?);
+                let queries = &mut cache.queries[l][h];
+                queries.extend_from_slice(q.blob());
+                let values = &mut cache.values[l][h];
+                values.extend_from_slice(v.blob());
+                let slope = (self.positional_encoding == PositionalEncoding::Alibi)
+                    .then(|| 2f32.powf(-8. * (h + 1) as f32 / arch.num_heads as f32));
+                // The same operations, in the same order, as the graph, whose masked
+                // scores only add zeros
+                for (r, k) in k.blob().chunks(head_size).enumerate() {
+                    let i = start + r;
+                    let scores = queries
+                        .chunks(head_size)
+                        .take(i + 1)
+                        .enumerate()
+                        .map(|(j, q)| {
+                            let mut score = 0.;
+                            for (a, b) in k.iter().zip(q) {
+                                score += a * b;
+                            }
+                            let score = score * scale;
+                            slope.map_or(score, |slope| score + -slope * (i - j) as f32)
+                        })
+                        .collect::<Vec<f32>>();
+                    let max = scores
+                        .iter()
+                        .fold(f32::NEG_INFINITY, |a, b| f32::max(a, *b));
+                    let sum = scores.iter().map(|f| (f - max).exp()).sum::<f32>();
+                    let out = &mut heads[(r * arch.num_heads + h) * head_size..][..head_size];
+                    for (s, v) in scores.iter().zip(values.chunks(head_size)) {
+                        let weight = (s - max).exp() / sum;
+                        for (o, v) in out.iter_mut().zip(v) {
+                            *o += weight * v;
+                        }
+                    }
+                }
+            }
+            let heads = Tensor::raw(&[new.len(), arch.num_heads * head_size], heads)?;
+            let add_atten = (&norm_inp + &linear(&heads, "proj", l)?)?;
+            let ff_inp = if arch.parallel_residual {
+                norm_inp
+            } else {
+                let coeff = param(format!("atten_norm_{}_coeff", l))?;
+                norm(
+                    &add_atten,
+                    &coeff,
+                    &*param(format!("atten_norm_{}_bias", l))?,
+                )?
+            };
+            let hidden = arch
+                .activation
+                .function()
+                .run(&[&linear(&ff_inp, "feedforward1", l)?], false)?;
+            let residual = if arch.parallel_residual {
+                &add_atten
+            } else {
+                &ff_inp
+            };
+            x = (residual + &linear(&hidden, "feedforward2", l)?)?;
+        }
+        cache.tokens = window.to_vec();
+
+        let [norm_coeff, norm_bias, weights, bias] = self.output_head;
+        let last = Tensor::raw(&[1, d], x.get(new.len() - 1)?.blob().to_vec())?;
+        let norm_out = norm(
+            &last,
+            &*self.graph.get_param(norm_coeff)?,
+            &*self.graph.get_param(norm_bias)?,
+        )?;
+        let logits =
+            (&(&norm_out ^ &*self.graph.get_param(weights)?)? + &*self.graph.get_param(bias)?)?;
+        Ok(Tensor::raw(&[self.vocab_size], logits.blob().to_vec())?)
+    }
+
+    /// Attention weights of the model over the last `num_tokens` of `tokens`, as a
+    /// `[layers, heads, tokens, tokens]` tensor: entry `[l, h, i, j]` is how much the
+    /// `i`th token attends to the `j`th one, in head `h` of block `l`. (Rows sum up to
+    /// one, and are zero past the diagonal)
+    pub fn attention_maps(&self, tokens: &[usize]) -> Result<Tensor<f32>, GraphError> {
+        if tokens.is_empty() {
+            return Err(TensorError::unexpected_shape("attention_maps", &[0]).into());
+        }
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        let graph = self.run_window(tokens)?;
+        let n = tokens.len();
+        let num_heads = self.attention.first().map_or(0, |l| l.len());
+        let mut data = Vec::with_capacity(self.attention.len() * num_heads * n * n);
+        for id in self.attention.iter().flatten() {
+            let weights = graph.get(*id)?;
+            for i in 0..n {
+                data.extend_from_slice(&weights.get(i)?.blob()[..n]);
+            }
+        }
+        Ok(Tensor::raw(&[self.attention.len(), num_heads, n, n], data)?)
+    }
+
+    /// The learned embedding of every token of the vocabulary ([vocab_size,
+    /// embedding_degree]), e.g. for `projector`
+    pub fn token_embeddings(&self) -> Result<Tensor<f32>, GraphError> {
+        Ok(self.graph.get_param(self.token_embedding)?.into_owned())
+    }
+
+    /// Writes the attention weights of the model over `tokens` (See `attention_maps`)
+    /// as a NumPy `.npz` archive, under `attention`, along with the `tokens` themselves
+    pub fn export_attention_npz<W: std::io::Write>(
+        &self,
+        tokens: &[usize],
+        w: W,
+    ) -> Result<(), GraphError> {
+        let maps = self.attention_maps(tokens)?;
+        let n = maps.shape()[2];
+        let tokens = Tensor::vector(
+            &tokens[tokens.len() - n..]
+                .iter()
+                .map(|t| *t as f32)
+                .collect::<Vec<_>>(),
+        );
+        Ok(write_npz(w, &[("attention", &maps), ("tokens", &tokens)])?)
+    }
+
+    /// Histograms of each parameter tensor and of its gradients, as left by the last
+    /// training step (Zero before the first one), with `bins` bins each
+    pub fn histograms(&self, bins: usize) -> Result<Vec<ParamHistograms>, GraphError> {
+        self.params
+            .iter()
+            .map(|id| {
+                Ok(ParamHistograms {
+                    name: self.graph.name_of(*id)?.clone(),
+                    weights: Histogram::new(self.graph.get_param(*id)?.blob(), bins),
+                    grads: Histogram::new(
+                        self.graph
+                            .get_grad(*id)?
+                            .map_values(|g| g / self.graph.loss_scale())
+                            .blob(),
+                        bins,
+                    ),
+                })
+            })
+            .collect()
+    }
+
+    /// Logit lens: the logits the output head of the model gives for the outputs of
+    /// each transformer block, as a `[layers, tokens, vocab_size]` tensor over the last
+    /// `num_tokens` of `tokens`. How early the predictions settle shows what each block
+    /// contributes. (Those of the last block are the output of the model)
+    pub fn logit_lens(&self, tokens: &[usize]) -> Result<Tensor<f32>, GraphError> {
+        if tokens.is_empty() {
+            return Err(TensorError::unexpected_shape("logit_lens", &[0]).into());
+        }
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        let graph = self.run_window(tokens)?;
+        let [norm_coeff, norm_bias, weights, bias] = self.output_head;
+        let (norm_coeff, norm_bias) = (graph.get(norm_coeff)?, graph.get(norm_bias)?);
+        let (weights, bias) = (graph.get(weights)?, graph.get(bias)?);
+        let num_layers = self.hidden.len() - 1;
+        let n = tokens.len();
+        let mut data = Vec::with_capacity(num_layers * n * self.vocab_size);
+        for id in &self.hidden[..num_layers] {
+            let states = graph.get(*id)?;
+            let norm = LayerNorm::new().run(&[&states, &norm_coeff, &norm_bias], false)?;
+            let logits = MatMul::new().run(&[&norm, &weights], false)?;
+            let logits = Add::new().run(&[&logits, &bias], false)?;
+            for i in 0..n {
+                data.extend_from_slice(logits.get(i)?.blob());
+            }
+        }
+        Ok(Tensor::raw(&[num_layers, n, self.vocab_size], data)?)
+    }
+
+    /// A single vector representing `tokens`, for similarity search or clustering:
+    /// the hidden states of `layer` (See `hidden_states`), pooled over the tokens
+    pub fn embed_text(
+        &self,
+        tokens: &[usize],
+        layer: Option<usize>,
+        pooling: Pooling,
+    ) -> Result<Tensor<f32>, GraphError> {
+        if tokens.is_empty() {
+            return Err(TensorError::unexpected_shape("embed_text", &[0]).into());
+        }
+        let states = self.hidden_states(tokens, layer)?;
+        let (n, degree) = (states.shape()[0], states.shape()[1]);
+        let data = match pooling {
+            Pooling::Mean => (0..degree)
+                .map(|j| (0..n).map(|i| states.blob()[i * degree + j]).sum::<f32>() / n as f32)
+                .collect(),
+            Pooling::Last => states.blob()[(n - 1) * degree..].to_vec(),
+        };
+        Ok(Tensor::raw(&[degree], data)?)
+    }
+
+    /// Log-probabilities the model assigns to each of `tokens` given the ones before
+    /// it. Only the last `num_tokens + 1` tokens fit in the context of the model, so
+    /// at most `num_tokens` values are returned. (The first token gets none)
+    pub fn token_log_probs(&self, tokens: &[usize]) -> Result<Vec<f32>, GraphError> {
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens + 1)..];
+        if tokens.len() < 2 {
+            return Ok(Vec::new());
+        }
+        self.check_targets("token_log_probs", &tokens[1..])?;
+        let mut context = tokens[..tokens.len() - 1].to_vec();
+        context.resize(self.num_tokens, 0);
+        let mut graph = self.inference_graph()?;
+        graph.embed(
+            self.token_input,
+            self.token_embedding,
+            &Tensor::raw(&[self.num_tokens], context)?,
+        )?;
+        graph.forward_inference()?;
+        let output = graph.get(self.output)?;
+        tokens[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let logits = output.get(i)?;
+                let max = logits
+                    .blob()
+                    .iter()
+                    .fold(f32::NEG_INFINITY, |a, b| f32::max(a, *b));
+                let sum = logits.blob().iter().map(|f| (f - max).exp()).sum::<f32>();
+                Ok(logits.blob()[*t] - max - sum.ln())
+            })
+            .collect()
+    }
+
+    /// Fails on the tokens out of the vocabulary (Which `embed` checks for the inputs,
+    /// but not for the targets)
+    fn check_targets(&self, op: &'static str, tokens: &[usize]) -> Result<(), GraphError> {
+        match tokens.iter().find(|t| **t >= self.vocab_size) {
+            Some(t) => Err(TensorError::unexpected_shape(op, &[*t]).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Like `token_log_probs`, but gives the number of tokens the model found likelier
+    /// than each of `tokens` instead. (Zero when it was the prediction of the model)
+    pub fn token_ranks(&self, tokens: &[usize]) -> Result<Vec<usize>, GraphError> {
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens + 1)..];
+        if tokens.len() < 2 {
+            return Ok(Vec::new());
+        }
+        self.check_targets("token_ranks", &tokens[1..])?;
+        let graph = self.run_window(&tokens[..tokens.len() - 1])?;
+        let output = graph.get(self.output)?;
+        tokens[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| Ok(rank(output.get(i)?.blob(), *t)))
+            .collect()
+    }
+
+    pub fn infer<R: Rng, F: Fn(usize)>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        count: usize,
+        temperature: f32,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        for ch in prompt {
+            callback(*ch);
+        }
+        let mut chs = prompt.to_vec();
+        chs.extend(self.generate(
+            rng,
+            prompt,
+            count,
+            &Sampling {
+                temperature: 1.,
+                top_p: temperature,
+            },
+            |ch| {
+                callback(ch);
+                true
+            },
+        )?);
+        Ok(chs)
+    }
+
+    /// Like `infer`, stopping early once `cancel` is cancelled (E.g. by the user, or
+    /// after a timeout), with the tokens streamed so far
+    pub fn infer_stream<R: Rng, F: Fn(usize)>(
+        &self,
         rng: &mut R,
         prompt: &[usize],
         count: usize,
         temperature: f32,
+        cancel: &CancellationToken,
         callback: F,
     ) -> Result<Vec<usize>, GraphError> {
-        let mut cnt = prompt.len();
-        let mut context = vec![0; self.num_tokens];
-        context[..prompt.len()].copy_from_slice(prompt);
+        for ch in prompt {
+            callback(*ch);
+        }
+        let mut unconstrained = |_: &[usize], vocab_size: usize| vec![true; vocab_size];
+        let mut chs = prompt.to_vec();
+        chs.extend(self.generate_cancellable(
+            rng,
+            prompt,
+            &mut unconstrained,
+            count,
+            &Sampling {
+                temperature: 1.,
+                top_p: temperature,
+            },
+            cancel,
+            |ch| {
+                callback(ch);
+                true
+            },
+        )?);
+        Ok(chs)
+    }
+
+    /// Like `infer`, also returning the probability of each generated token and the
+    /// `top_k` likeliest tokens at its step
+    pub fn infer_traced<R: Rng, F: Fn(usize)>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        count: usize,
+        temperature: f32,
+        top_k: usize,
+        callback: F,
+    ) -> Result<GenerationTrace, GraphError> {
+        for ch in prompt {
+            callback(*ch);
+        }
+        let mut unconstrained = |_: &[usize], vocab_size: usize| vec![true; vocab_size];
+        self.generate_traced(
+            rng,
+            prompt,
+            &mut unconstrained,
+            count,
+            &Sampling {
+                temperature: 1.,
+                top_p: temperature,
+            },
+            top_k,
+            |ch| {
+                callback(ch);
+                true
+            },
+        )
+    }
+
+    /// Logits of the model for the last `n` of `tokens`, which are cut to the context.
+    /// (`graph` is a clone of the model's, with the positions embedded)
+    fn last_logits(
+        &self,
+        graph: &mut Graph,
+        tokens: &[usize],
+        n: usize,
+    ) -> Result<Vec<Vec<f32>>, GraphError> {
+        let tokens = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        let mut context = tokens.to_vec();
+        context.resize(self.num_tokens, 0);
+        graph.embed(
+            self.token_input,
+            self.token_embedding,
+            &Tensor::raw(&[self.num_tokens], context)?,
+        )?;
+        graph.forward_inference()?;
+        let output = graph.get(self.output)?;
+        (tokens.len() - n..tokens.len())
+            .map(|i| Ok(output.get(i)?.blob().to_vec()))
+            .collect()
+    }
+
+    /// A copy of the graph to run for inference (See `Graph::forward_inference`), with
+    /// the positions embedded
+    fn inference_graph(&self) -> Result<Graph, GraphError> {
         let poses = Tensor::raw(&[self.num_tokens], (0..self.num_tokens).collect())?;
+        let mut graph = self.graph.without_grads();
+        graph.embed(self.pos_input, self.pos_embedding, &poses)?;
+        Ok(graph)
+    }
+
+    /// Like `generate`, but with a smaller `draft` model (Sharing the vocabulary)
+    /// proposing `draft_tokens` tokens at a time, which this model scores in a single
+    /// forward pass. Drafted tokens are accepted with the probability that keeps the
+    /// output distributed as `generate`'s, so that greedy decoding gives the same
+    /// tokens, only faster when the draft model is often right. (Up to the context
+    /// being full, drafts being then scored against the same window; tokens aren't
+    /// pinned)
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_speculative<P: Optimizer, R: Rng, F: FnMut(usize) -> bool>(
+        &self,
+        draft: &GPT<P>,
+        rng: &mut R,
+        prompt: &[usize],
+        count: usize,
+        draft_tokens: usize,
+        sampling: &Sampling,
+        mut callback: F,
+    ) -> Result<(Vec<usize>, SpeculativeStats), GraphError> {
+        if prompt.is_empty() {
+            return Err(
+                TensorError::unexpected_shape("generate_speculative", &[prompt.len()]).into(),
+            );
+        }
+        if draft.vocab_size() != self.vocab_size() {
+            return Err(TensorError::unexpected_shape(
+                "generate_speculative",
+                &[draft.vocab_size()],
+            )
+            .into());
+        }
+        // The last token before the drafts has to fit in the context too
+        let draft_tokens = draft_tokens.min(self.num_tokens - 1);
+        let mut graph = self.inference_graph()?;
+        let mut draft_graph = draft.inference_graph()?;
+        let mut stats = SpeculativeStats::default();
+        let mut tokens = prompt.to_vec();
+        let mut chs = Vec::new();
+        while chs.len() < count {
+            // Every round yields a token of this model's after the accepted ones
+            let k = draft_tokens.min(count - chs.len() - 1);
+            let mut seq = tokens.clone();
+            let mut drafts = Vec::with_capacity(k);
+            for _ in 0..k {
+                let logits = draft.last_logits(&mut draft_graph, &seq, 1)?;
+                let q = distribution(&logits[0], sampling);
+                let t = sample_from(rng, &q);
+                seq.push(t);
+                drafts.push((t, q));
+            }
+            let ps = self
+                .last_logits(&mut graph, &seq, k + 1)?
+                .iter()
+                .map(|logits| distribution(logits, sampling))
+                .collect::<Vec<_>>();
+            stats.drafted += k;
+            stats.passes += 1;
+
+            let mut accepted = Vec::with_capacity(k + 1);
+            let mut rejected = None;
+            for ((t, q), p) in drafts.iter().zip(&ps) {
+                if rng.gen::<f32>() * q[*t] < p[*t] {
+                    accepted.push(*t);
+                    continue;
+                }
+                // The replacement is picked where this model is likelier than the draft
+                let residual = p
+                    .iter()
+                    .zip(q)
+                    .map(|(p, q)| (p - q).max(0.))
+                    .collect::<Vec<_>>();
+                rejected = Some(if residual.iter().sum::<f32>() > 0. {
+                    sample_from(rng, &residual)
+                } else {
+                    sample_from(rng, p)
+                });
+                break;
+            }
+            stats.accepted += accepted.len();
+            let next = match rejected {
+                Some(t) => t,
+                None => sample_from(rng, &ps[k]),
+            };
+            accepted.push(next);
+            for t in accepted {
+                chs.push(t);
+                tokens.push(t);
+                if !callback(t) {
+                    return Ok((chs, stats));
+                }
+            }
+        }
+        Ok((chs, stats))
+    }
+
+    /// Generates up to `count` tokens following `prompt` (Which shouldn't be empty).
+    /// Once the context is full, the window rolls: the oldest tokens make way for the
+    /// new ones (See `set_pinned_tokens`), so there's no limit on the length of the
+    /// prompt nor of the output. Each new token is handed to `callback`, which returns
+    /// whether the generation should go on. (Tokens are picked among the ones of
+    /// `restrict_vocab`, if any)
+    pub fn generate<R: Rng, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        count: usize,
+        sampling: &Sampling,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        let mut unconstrained = |_: &[usize], vocab_size: usize| vec![true; vocab_size];
+        self.generate_constrained(rng, prompt, &mut unconstrained, count, sampling, callback)
+    }
+
+    /// Like `generate`, with the randomness given by `strategy`, for evaluations and
+    /// tests to get reproducible outputs
+    pub fn generate_with<F: FnMut(usize) -> bool>(
+        &self,
+        strategy: &SamplingStrategy,
+        prompt: &[usize],
+        count: usize,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        let (sampling, seed) = match strategy {
+            // Greedy decoding draws no random number, whatever the seed
+            SamplingStrategy::Greedy => (Sampling::greedy(), 0),
+            SamplingStrategy::Random(sampling) => (sampling.clone(), rand::random()),
+            SamplingStrategy::Deterministic { sampling, seed } => (sampling.clone(), *seed),
+        };
+        self.generate(&mut seeded_rng(seed), prompt, count, &sampling, callback)
+    }
+
+    /// Like `generate`, the first token being one of `allowed` (Unless it's empty),
+    /// for token healing (See `tokenizer::heal_prompt`)
+    pub fn generate_healed<R: Rng, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        allowed: &[usize],
+        count: usize,
+        sampling: &Sampling,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        let mut first = vec![allowed.is_empty(); self.vocab_size];
+        for t in allowed {
+            *first.get_mut(*t).ok_or(TensorError::InvalidIndex)? = true;
+        }
+        let mut constraint = |generated: &[usize], vocab_size: usize| match generated {
+            [] => first.clone(),
+            _ => vec![true; vocab_size],
+        };
+        self.generate_constrained(rng, prompt, &mut constraint, count, sampling, callback)
+    }
+
+    /// Like `generate`, each token being picked among the ones `constraint` allows
+    pub fn generate_constrained<R: Rng, C: TokenConstraint + ?Sized, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        constraint: &mut C,
+        count: usize,
+        sampling: &Sampling,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        self.generate_inner(
+            rng, prompt, constraint, count, sampling, None, None, None, callback,
+        )
+    }
+
+    /// Like `generate_constrained`, stopping early once `cancel` is cancelled
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_cancellable<R: Rng, C: TokenConstraint + ?Sized, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        constraint: &mut C,
+        count: usize,
+        sampling: &Sampling,
+        cancel: &CancellationToken,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        self.generate_inner(
+            rng,
+            prompt,
+            constraint,
+            count,
+            sampling,
+            None,
+            Some(cancel),
+            None,
+            callback,
+        )
+    }
+
+    /// Like `generate_cancellable`, reading the attention of the tokens `cache` holds
+    /// instead of running them through the model again, and keeping that of the new
+    /// ones in it (See `logits_cached`), for a conversation to only pay for the tokens
+    /// added to it. (Models that can't be cached run their whole window, the cache
+    /// being cleared)
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_cached<R: Rng, C: TokenConstraint + ?Sized, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        cache: &mut KvCache,
+        prompt: &[usize],
+        constraint: &mut C,
+        count: usize,
+        sampling: &Sampling,
+        cancel: &CancellationToken,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        self.generate_inner(
+            rng,
+            prompt,
+            constraint,
+            count,
+            sampling,
+            None,
+            Some(cancel),
+            Some(cache),
+            callback,
+        )
+    }
+
+    /// Like `generate_constrained`, also recording the probability of each generated
+    /// token and the `top_k` likeliest tokens at its step
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_traced<R: Rng, C: TokenConstraint + ?Sized, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        constraint: &mut C,
+        count: usize,
+        sampling: &Sampling,
+        top_k: usize,
+        callback: F,
+    ) -> Result<GenerationTrace, GraphError> {
+        let mut steps = Vec::new();
+        self.generate_inner(
+            rng,
+            prompt,
+            constraint,
+            count,
+            sampling,
+            Some((&mut steps, top_k)),
+            None,
+            None,
+            callback,
+        )?;
+        Ok(GenerationTrace {
+            prompt: prompt.to_vec(),
+            steps,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate_inner<R: Rng, C: TokenConstraint + ?Sized, F: FnMut(usize) -> bool>(
+        &self,
+        rng: &mut R,
+        prompt: &[usize],
+        constraint: &mut C,
+        count: usize,
+        sampling: &Sampling,
+        mut trace: Option<(&mut Vec<TraceStep>, usize)>,
+        cancel: Option<&CancellationToken>,
+        cache: Option<&mut KvCache>,
+        mut callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        if prompt.is_empty() {
+            return Err(TensorError::unexpected_shape("generate", &[prompt.len()]).into());
+        }
+        let pinned = self.pinned_tokens.min(prompt.len());
+        let mut tokens = prompt.to_vec();
+        let mut cache = match cache {
+            Some(cache) if !self.can_cache() => {
+                cache.clear();
+                None
+            }
+            cache => cache,
+        };
+        // Only needed when the model runs whole windows
+        let mut graph = match cache {
+            Some(_) => None,
+            None => Some(self.inference_graph()?),
+        };
+        let subset = self.vocab_subset.as_deref();
+        if let (Some(subset), Some(graph)) = (subset, graph.as_mut()) {
+            // The logits of the subset only, the `i`th one being of `subset[i]`
+            let [_, _, weights, bias] = self.output_head;
+            let (w, b) = (graph.get(weights)?, graph.get(bias)?);
+            let sliced = w
+                .blob()
+                .chunks(self.vocab_size)
+                .flat_map(|row| subset.iter().map(|t| row[*t]))
+                .collect();
+            let sliced = Tensor::raw(&[w.shape()[0], subset.len()], sliced)?;
+            let sliced_bias = Tensor::raw(
+                &[subset.len()],
+                subset.iter().map(|t| b.blob()[*t]).collect(),
+            )?;
+            graph.load(weights, &sliced);
+            graph.load(bias, &sliced_bias);
+        }
+        let mut chs = Vec::new();
+        for _ in 0..count {
+            if cancel.is_some_and(|c| c.is_cancelled()) {
+                break;
+            }
+            let mut context = rolling_window(&tokens, pinned, self.num_tokens);
+            let logits: Tensor<f32> = match (cache.as_deref_mut(), graph.as_mut()) {
+                (Some(cache), _) => {
+                    let logits = self.logits_cached(cache, &context)?;
+                    match subset {
+                        Some(subset) => Tensor::raw(
+                            &[subset.len()],
+                            subset.iter().map(|t| logits.blob()[*t]).collect(),
+                        )?,
+                        None => logits,
+                    }
+                }
+                (None, Some(graph)) => {
+                    let cnt = context.len();
+                    context.resize(self.num_tokens, 0);
+                    graph.embed(
+                        self.token_input,
+                        self.token_embedding,
+                        &Tensor::raw(&[self.num_tokens], context)?,
+                    )?;
+                    graph.forward_inference()?;
+                    graph.get(self.output)?.get(cnt - 1)?.into()
+                }
+                (None, None) => unreachable!(),
+            };
+            let mask = constraint.allowed(&chs, self.vocab_size);
+            if mask.len() != self.vocab_size {
+                return Err(TensorError::shape_mismatch(
+                    "TokenConstraint",
+                    &[self.vocab_size],
+                    &[mask.len()],
+                )
+                .into());
+            }
+            let mask = match subset {
+                Some(subset) => subset.iter().map(|t| mask[*t]).collect(),
+                None => mask,
+            };
+            let bias = match constraint.bias(&chs, self.vocab_size) {
+                Some(bias) if bias.len() != self.vocab_size => {
+                    return Err(TensorError::shape_mismatch(
+                        "TokenConstraint",
+                        &[self.vocab_size],
+                        &[bias.len()],
+                    )
+                    .into());
+                }
+                Some(bias) => match subset {
+                    Some(subset) => Some(subset.iter().map(|t| bias[*t]).collect::<Vec<_>>()),
+                    None => Some(bias),
+                },
+                None => None,
+            };
+            let next = if mask.iter().all(|a| *a) && bias.is_none() {
+                select(rng, &logits, sampling)?
+            } else if mask.iter().any(|a| *a) {
+                let masked = logits
+                    .blob()
+                    .iter()
+                    .zip(mask.iter())
+                    .enumerate()
+                    .map(|(i, (l, a))| match (*a, &bias) {
+                        (false, _) => f32::NEG_INFINITY,
+                        (true, Some(bias)) => l + bias[i],
+                        (true, None) => *l,
+                    })
+                    .collect();
+                select(rng, &Tensor::raw(&[mask.len()], masked)?, sampling)?
+            } else {
+                // Nothing may follow
+                break;
+            };
+            if let Some((steps, top_k)) = trace.as_mut() {
+                steps.push(trace_step(logits.blob(), next, *top_k, subset));
+            }
+            let next_ch = subset.map_or(next, |s| s[next]);
+            chs.push(next_ch);
+            if !callback(next_ch) {
+                break;
+            }
+            tokens.push(next_ch);
+        }
+        Ok(chs)
+    }
+
+    /// The next token of each of `sequences`, sampled as by the matching `sampling`, in
+    /// as few forward passes as possible: windows shorter than the context are packed
+    /// into the same one, attention being masked between them and their positions
+    /// counted from their own start, as if each ran alone. (For a server to batch the
+    /// generations going on)
+    pub fn sample_packed<R: Rng>(
+        &self,
+        rng: &mut R,
+        sequences: &[&[usize]],
+        sampling: &[Sampling],
+    ) -> Result<Vec<usize>, GraphError> {
+        if sequences.len() != sampling.len() || sequences.iter().any(|s| s.is_empty()) {
+            return Err(TensorError::unexpected_shape("sample_packed", &[sequences.len()]).into());
+        }
+        let windows = sequences
+            .iter()
+            .map(|s| rolling_window(s, self.pinned_tokens, self.num_tokens))
+            .collect::<Vec<_>>();
+        // First fit, the windows of each pack and the tokens they take
+        let mut packs: Vec<(Vec<usize>, usize)> = Vec::new();
+        for (i, window) in windows.iter().enumerate() {
+            match packs
+                .iter_mut()
+                .find(|(_, len)| len + window.len() <= self.num_tokens)
+            {
+                Some((pack, len)) => {
+                    pack.push(i);
+                    *len += window.len();
+                }
+                None => packs.push((vec![i], window.len())),
+            }
+        }
+        let run = || {
+            packs
+                .par_iter()
+                .map(|(pack, _)| {
+                    let pack = pack
+                        .iter()
+                        .map(|i| windows[*i].as_slice())
+                        .collect::<Vec<_>>();
+                    self.run_packed(&pack)
+                })
+                .collect::<Result<Vec<_>, GraphError>>()
+        };
+        let logits = match &self.thread_pool {
+            Some(pool) => pool.install(run)?,
+            None => run()?,
+        };
+        let mut next = vec![0; sequences.len()];
+        for ((pack, _), logits) in packs.iter().zip(logits) {
+            for (i, logits) in pack.iter().zip(logits) {
+                next[*i] = select(rng, &logits, &sampling[*i])?;
+            }
+        }
+        Ok(next)
+    }
+
+    /// Logits of the token following each of `windows`, packed into a single window.
+    /// (Those of the tokens out of `vocab_subset` being -inf)
+    fn run_packed(&self, windows: &[&[usize]]) -> Result<Vec<Tensor<f32>>, GraphError> {
+        let mut tokens = Vec::with_capacity(self.num_tokens);
+        let mut poses = Vec::with_capacity(self.num_tokens);
+        let mut documents = Vec::with_capacity(self.num_tokens);
+        for (doc, window) in windows.iter().enumerate() {
+            tokens.extend_from_slice(window);
+            poses.extend(0..window.len());
+            documents.extend(std::iter::repeat_n(doc, window.len()));
+        }
+        // Padding gets a document of its own
+        tokens.resize(self.num_tokens, 0);
+        poses.resize(self.num_tokens, 0);
+        documents.resize(self.num_tokens, usize::MAX);
+        let mut graph = self.inference_graph()?;
+        graph.load(self.attention_bias, &document_mask(&documents)?);
+        graph.embed(
+            self.pos_input,
+            self.pos_embedding,
+            &Tensor::raw(&[self.num_tokens], poses)?,
+        )?;
+        graph.embed(
+            self.token_input,
+            self.token_embedding,
+            &Tensor::raw(&[self.num_tokens], tokens)?,
+        )?;
+        graph.forward_inference()?;
+        let output = graph.get(self.output)?;
+        let mut end = 0;
+        windows
+            .iter()
+            .map(|window| {
+                end += window.len();
+                let mut logits = output.get(end - 1)?.blob().to_vec();
+                if let Some(subset) = &self.vocab_subset {
+                    let allowed = subset.iter().copied().collect::<BTreeSet<_>>();
+                    for (t, l) in logits.iter_mut().enumerate() {
+                        if !allowed.contains(&t) {
+                            *l = f32::NEG_INFINITY;
+                        }
+                    }
+                }
+                Ok(Tensor::raw(&[self.vocab_size], logits)?)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::Padded;
+    use crate::graph::Pass;
+    use crate::optimizer::{AdamW, Naive};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn config(embedding_degree: usize, num_layers: usize) -> ModelConfig {
+        ModelConfig {
+            num_tokens: 12,
+            embedding_degree,
+            num_layers,
+            num_heads: 2,
+            ..Default::default()
+        }
+    }
+
+    fn model(seed: u64, embedding_degree: usize, num_layers: usize) -> GPT<Naive> {
+        model_with(seed, &config(embedding_degree, num_layers))
+    }
+
+    fn model_with(seed: u64, config: &ModelConfig) -> GPT<Naive> {
+        GPT::new(&mut StdRng::seed_from_u64(seed), 7, config, Naive::new()).unwrap()
+    }
+
+    struct ConstantLr(f32);
+
+    impl<O: Optimizer> TrainCallback<O> for ConstantLr {
+        fn learning_rate(&mut self, _step: usize) -> f32 {
+            self.0
+        }
+    }
+
+    /// The summaries of the runs it was told about
+    #[derive(Default)]
+    struct Stops(Vec<(StopReason, usize, Option<f32>)>);
+
+    impl<O: Optimizer> TrainCallback<O> for Stops {
+        fn learning_rate(&mut self, _step: usize) -> f32 {
+            0.01
+        }
+        fn on_stop(&mut self, summary: &TrainSummary<O>) -> Result<(), GraphError> {
+            self.0.push((summary.reason, summary.steps, summary.loss));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stop_criteria() {
+        let dataset = (0..200).map(|i| i % 7).collect::<Vec<_>>();
+        let mut gpt = crate::fixtures::gpt(&crate::fixtures::tiny_model(), 7).unwrap();
+        let mut stops = Stops::default();
+        let stop = StopCriteria {
+            target_loss: Some(100.),
+            ..StopCriteria::steps(5)
+        };
+        let reason = gpt.train(&dataset, &stop, 2, None, &mut stops).unwrap();
+        assert_eq!(reason, StopReason::TargetLoss);
+        // 32 tokens per step, so an epoch is 7 steps, counting the one already done
+        let stop = StopCriteria {
+            max_epochs: Some(1),
+            ..StopCriteria::steps(10)
+        };
+        assert_eq!(
+            gpt.train(&dataset, &stop, 2, None, &mut stops).unwrap(),
+            StopReason::Epochs
+        );
+        let stop = StopCriteria {
+            max_duration: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert_eq!(
+            gpt.train(&dataset, &stop, 2, None, &mut stops).unwrap(),
+            StopReason::Duration
+        );
+        assert_eq!(stops.0[0].0, StopReason::TargetLoss);
+        assert_eq!(stops.0[0].1, 1);
+        assert!(stops.0[0].2.is_some_and(|l| l < 100.));
+        assert_eq!((stops.0[1].0, stops.0[1].1), (StopReason::Epochs, 6));
+        assert_eq!(stops.0[2], (StopReason::Duration, 0, None));
+    }
+
+    #[test]
+    fn test_train_naive() {
+        // Naive doesn't count its steps, which are all number 0
+        let dataset = (0..200).map(|i| i % 7).collect::<Vec<_>>();
+        let mut gpt = model(0, 8, 1);
+        let mut stops = Stops::default();
+        let reason = gpt
+            .train(&dataset, &StopCriteria::steps(3), 2, None, &mut stops)
+            .unwrap();
+        assert_eq!(reason, StopReason::Steps);
+        assert_eq!(stops.0[0].1, 3);
+    }
 
-        let mut graph = self.graph.clone();
+    /// Whether each step was skipped, and the number of epochs ended
+    #[derive(Default)]
+    struct Skips(Vec<bool>, usize);
 
-        graph.embed(self.pos_input, self.pos_embedding, &poses)?;
-        for ch in prompt {
-            callback(*ch);
+    impl<O: Optimizer> TrainCallback<O> for Skips {
+        fn learning_rate(&mut self, _step: usize) -> f32 {
+            0.01
         }
-        let mut chs = prompt.to_vec();
-        for _ in 0..count {
-            graph.embed(
-                self.token_input,
-                self.token_embedding,
-                &Tensor::raw(&[self.num_tokens], context.clone())?,
-            )?;
-            graph.forward(false)?;
-            let next_ch = select(rng, &graph.get(self.output)?.get(cnt - 1)?, temperature)?;
-            chs.push(next_ch);
-            callback(next_ch);
-            if cnt == self.num_tokens {
-                context.remove(0);
-                context.push(0);
-                cnt -= 1;
+        fn on_step(&mut self, ctx: &TrainContext<O>) -> Result<(), GraphError> {
+            self.0.push(ctx.stats.skipped);
+            Ok(())
+        }
+        fn on_epoch_end(&mut self, _ctx: &TrainContext<O>) -> Result<(), GraphError> {
+            self.1 += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_train_overflow() {
+        let dataset = (0..200).map(|i| i % 7).collect::<Vec<_>>();
+        let mut gpt = crate::fixtures::gpt(&crate::fixtures::tiny_model(), 7).unwrap();
+        gpt.set_precision(Precision::Mixed);
+        let start = gpt.get_training_state().unwrap();
+        // The first steps overflow in f16, the scale being halved after each
+        gpt.loss_scaler.as_mut().unwrap().scale = 2f32.powi(40);
+        let mut skips = Skips::default();
+        gpt.train(&dataset, &StopCriteria::steps(40), 2, None, &mut skips)
+            .unwrap();
+        let skipped = skips.0.iter().filter(|s| **s).count();
+        assert!(skips.0[0] && !skips.0[39]);
+        assert_eq!(gpt.optimizer.step_num(), 40 - skipped);
+        // 32 tokens per step, the skipped ones not counting
+        assert_eq!(skips.1, (40 - skipped) * 32 / 200);
+        assert_ne!(
+            gpt.get_training_state().unwrap().tensors["token_embedding"].blob(),
+            start.tensors["token_embedding"].blob()
+        );
+    }
+
+    #[test]
+    fn test_lr_find() {
+        let dataset = (0..200).map(|i| i % 7).collect::<Vec<_>>();
+        let mut gpt = crate::fixtures::gpt(&crate::fixtures::tiny_model(), 7).unwrap();
+        gpt.train_step(&dataset, 2, None, 0.01).unwrap();
+        let state = gpt.get_training_state().unwrap();
+        let step = gpt.optimizer.step_num();
+        let same = |gpt: &GPT<AdamW>| {
+            let after = gpt.get_training_state().unwrap();
+            gpt.optimizer.step_num() == step
+                && state
+                    .tensors
+                    .iter()
+                    .all(|(name, t)| after.tensors[name].blob() == t.blob())
+        };
+        let finder = gpt.lr_find(&dataset, 2, None, 1e-4, 1., 10).unwrap();
+        assert!(same(&gpt));
+        assert!(!finder.history.is_empty() && finder.history.len() <= 10);
+        assert!(finder.suggested_lr >= 1e-5 && finder.suggested_lr <= 0.1);
+        // Even when a step fails, on tokens outside of the vocabulary
+        let unknown = (0..200).map(|i| i % 9).collect::<Vec<_>>();
+        assert!(gpt.lr_find(&unknown, 2, None, 1e-4, 1., 10).is_err());
+        assert!(same(&gpt));
+    }
+
+    #[test]
+    fn test_find_batch_sizes() {
+        let build = |num_tokens| {
+            let config = crate::config::ModelConfig {
+                num_tokens,
+                ..crate::fixtures::tiny_model()
+            };
+            let mut gpt = crate::fixtures::gpt(&config, 7)?;
+            gpt.set_num_threads(8)?;
+            Ok(gpt)
+        };
+        let cap = build(8).unwrap().step_memory(3);
+        let probes = find_batch_sizes(build, &[4, 8, 64], cap, 16).unwrap();
+        // Shorter contexts take less memory per thread, the longest doesn't fit at all
+        assert_eq!(probes.len(), 2);
+        assert_eq!((probes[1].num_tokens, probes[1].batch_size), (8, 3));
+        assert_eq!(probes[1].memory, cap);
+        assert!(probes[0].batch_size >= 3);
+        assert!(probes[0].memory <= cap);
+        // Past the number of threads, larger batches take no more memory
+        let probes = find_batch_sizes(build, &[8], usize::MAX, 16).unwrap();
+        assert_eq!(probes[0].batch_size, 16);
+    }
+
+    /// The number of update ratios of each step
+    struct Ratios(Vec<usize>);
+
+    impl<O: Optimizer> TrainCallback<O> for Ratios {
+        fn learning_rate(&mut self, _step: usize) -> f32 {
+            0.01
+        }
+        fn on_step(&mut self, ctx: &TrainContext<O>) -> Result<(), GraphError> {
+            self.0.push(ctx.stats.update_ratios.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_update_ratios() {
+        let dataset = (0..200).map(|i| i % 7).collect::<Vec<_>>();
+        let mut gpt = crate::fixtures::gpt(&crate::fixtures::tiny_model(), 7).unwrap();
+        let mut ratios = Ratios(Vec::new());
+        gpt.train(&dataset, &StopCriteria::steps(3), 2, None, &mut ratios)
+            .unwrap();
+        // Only measured on the evaluation steps
+        assert_eq!(ratios.0, vec![gpt.params.len(), 0, 0]);
+        let stats = gpt.train_step(&dataset, 2, None, 0.01).unwrap();
+        assert_eq!(stats.update_ratios.len(), gpt.params.len());
+        assert!(stats.update_ratios.iter().all(|(_, r)| *r > 0.));
+    }
+
+    #[test]
+    fn test_spike_guard() {
+        let dataset = (0..200).map(|i| i % 7).collect::<Vec<_>>();
+        let mut gpt = crate::fixtures::gpt(&crate::fixtures::tiny_model(), 7).unwrap();
+        gpt.set_spike_guard(Some(SpikeGuard::default()));
+        let start = gpt.get_training_state().unwrap();
+        // The first step blows the weights up, the loss of the second isn't finite and
+        // the model is rewound to where it started
+        let mut lr = ConstantLr(f32::INFINITY);
+        gpt.train(&dataset, &StopCriteria::steps(2), 2, None, &mut lr)
+            .unwrap();
+        let state = gpt.get_training_state().unwrap();
+        for (name, t) in &start.tensors {
+            assert_eq!(state.tensors[name].blob(), t.blob());
+        }
+
+        // Nothing to rewind to
+        let mut broken = start.clone();
+        for t in broken.tensors.values_mut() {
+            *t = Tensor::constant(t.shape(), f32::NAN);
+        }
+        gpt.set_training_state(broken, true).unwrap();
+        let mut lr = ConstantLr(0.01);
+        assert!(matches!(
+            gpt.train(&dataset, &StopCriteria::steps(2), 2, None, &mut lr),
+            Err(GraphError::Diverged { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resume_replays() {
+        // The dropout masks of a step are drawn from the training state, as the data
+        let dataset = (0..200).map(|i| i % 7).collect::<Vec<_>>();
+        let config = crate::config::ModelConfig {
+            dropout: 0.5,
+            ..crate::fixtures::tiny_model()
+        };
+        let mut gpt = crate::fixtures::gpt(&config, 7).unwrap();
+        let state = gpt.get_training_state().unwrap();
+        let first = gpt.train_step(&dataset, 2, None, 0.01).unwrap().loss;
+        let trained = gpt.get_training_state().unwrap();
+        gpt.set_training_state(state, true).unwrap();
+        assert_eq!(gpt.train_step(&dataset, 2, None, 0.01).unwrap().loss, first);
+        let replayed = gpt.get_training_state().unwrap();
+        for (name, t) in &trained.tensors {
+            assert_eq!(replayed.tensors[name].blob(), t.blob());
+        }
+    }
+
+    #[test]
+    fn test_distribution() {
+        let logits = [1., 3., 2., 0.];
+        let greedy = Sampling {
+            temperature: 0.,
+            top_p: 1.,
+        };
+        assert_eq!(distribution(&logits, &greedy), vec![0., 1., 0., 0.]);
+        let probs = distribution(&logits, &Sampling::default());
+        assert!((probs.iter().sum::<f32>() - 1.).abs() < 1e-6);
+        assert!(probs[1] > probs[2] && probs[2] > probs[0] && probs[0] > probs[3]);
+        // Only the likeliest token gets picked with a small enough top_p
+        let nucleus = Sampling {
+            temperature: 1.,
+            top_p: 0.5,
+        };
+        assert_eq!(distribution(&logits, &nucleus), vec![0., 1., 0., 0.]);
+    }
+
+    #[test]
+    fn test_generate_with() {
+        let gpt = model(0, 8, 1);
+        let prompt = [1, 2, 3];
+        let generate = |strategy| gpt.generate_with(&strategy, &prompt, 20, |_| true).unwrap();
+        let greedy = generate(SamplingStrategy::Greedy);
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(
+            gpt.generate(&mut rng, &prompt, 20, &Sampling::greedy(), |_| true)
+                .unwrap(),
+            greedy
+        );
+        let seeded = |seed| SamplingStrategy::Deterministic {
+            sampling: Sampling::default(),
+            seed,
+        };
+        assert_eq!(generate(seeded(7)), generate(seeded(7)));
+        assert_ne!(generate(seeded(7)), generate(seeded(8)));
+        assert_eq!(
+            generate(SamplingStrategy::Random(Sampling::default())).len(),
+            20
+        );
+    }
+
+    #[test]
+    fn test_generate_traced() {
+        let gpt = model(0, 8, 1);
+        let prompt = [1, 2, 3];
+        let mut rng = StdRng::seed_from_u64(0);
+        let trace = gpt
+            .infer_traced(&mut rng, &prompt, 10, 0., 3, |_| {})
+            .unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let tokens = gpt.infer(&mut rng, &prompt, 10, 0., |_| {}).unwrap();
+        assert_eq!(trace.tokens(), tokens);
+        for step in trace.steps.iter() {
+            assert_eq!(step.alternatives.len(), 3);
+            assert!(step
+                .alternatives
+                .windows(2)
+                .all(|w| w[0].probability >= w[1].probability));
+            // Greedy decoding picks the likeliest token
+            assert_eq!(step.alternatives[0], step.picked);
+        }
+
+        // The probabilities of the tokens the constraint forbids are traced too
+        let mut odd =
+            |_: &[usize], vocab_size: usize| (0..vocab_size).map(|t| t % 2 == 1).collect();
+        let trace = gpt
+            .generate_traced(
+                &mut rng,
+                &prompt,
+                &mut odd,
+                5,
+                &Sampling::default(),
+                7,
+                |_| true,
+            )
+            .unwrap();
+        for step in trace.steps.iter() {
+            assert_eq!(step.picked.token % 2, 1);
+            let sum = step.alternatives.iter().map(|t| t.probability).sum::<f32>();
+            assert!((sum - 1.).abs() < 1e-5);
+        }
+
+        let mut trace = trace;
+        trace.label(|t| Some(t.to_string()));
+        let json = serde_json::to_string(&trace).unwrap();
+        assert_eq!(
+            serde_json::from_str::<GenerationTrace>(&json).unwrap(),
+            trace
+        );
+    }
+
+    #[test]
+    fn test_provenance() {
+        let mut gpt = model(0, 8, 1);
+        let tokenizer = crate::tokenizer::SimpleTokenizer::new("abcdefg");
+        assert!(gpt
+            .check_tokenizer(&crate::tokenizer::AsciiTokenizer)
+            .is_ok());
+        gpt.set_provenance(Provenance {
+            dataset_hash: Some(1),
+            tokenizer_hash: Some(crate::run::tokenizer_hash(&tokenizer)),
+        });
+        let bytes = bincode::serialize(&gpt.get_training_state().unwrap()).unwrap();
+        let mut loaded = model(1, 8, 1);
+        loaded
+            .set_training_state(bincode::deserialize(&bytes).unwrap(), true)
+            .unwrap();
+        assert_eq!(loaded.provenance(), gpt.provenance());
+        assert!(loaded.check_tokenizer(&tokenizer).is_ok());
+        assert!(matches!(
+            loaded.check_tokenizer(&crate::tokenizer::SimpleTokenizer::new("abcdefh")),
+            Err(GraphError::TokenizerMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_portable_checkpoint() {
+        // A checkpoint as written on any platform, byte by byte
+        let mut bytes = Vec::new();
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend(b"w");
+        bytes.extend(2u64.to_le_bytes());
+        bytes.extend(0.5f32.to_le_bytes());
+        bytes.extend((-1f32).to_le_bytes());
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend(2u64.to_le_bytes());
+        for v in [0.9f32, 0.999, 0.01] {
+            bytes.extend(v.to_le_bytes());
+        }
+        bytes.extend(0u64.to_le_bytes());
+        bytes.extend(0u64.to_le_bytes());
+        bytes.extend(3u64.to_le_bytes());
+        bytes.extend(7u64.to_le_bytes());
+
+        let mut versioned = b"femtoGPT".to_vec();
+        versioned.extend(STATE_VERSION.to_le_bytes());
+        versioned.extend(&bytes);
+        // The provenance: a dataset hash, and no tokenizer hash
+        versioned.push(1);
+        versioned.extend(0x0123456789abcdefu64.to_le_bytes());
+        versioned.push(0);
+        let state: TrainingState<AdamW> = bincode::deserialize(&versioned).unwrap();
+        assert_eq!(state.tensors["w"].shape(), &[2]);
+        assert_eq!(state.tensors["w"].blob(), &[0.5, -1.]);
+        assert_eq!(state.optimizer.step_num(), 3);
+        assert_eq!(state.data_seed, 7);
+        assert_eq!(state.provenance.dataset_hash, Some(0x0123456789abcdef));
+        assert_eq!(state.provenance.tokenizer_hash, None);
+        assert_eq!(bincode::serialize(&state).unwrap(), versioned);
+
+        // Those of earlier versions: with no provenance, with no header, and with no
+        // data seed either
+        let mut v1 = b"femtoGPT".to_vec();
+        v1.extend(1u32.to_le_bytes());
+        v1.extend(&bytes);
+        let state: TrainingState<AdamW> = bincode::deserialize(&v1).unwrap();
+        assert_eq!(
+            (state.data_seed, state.provenance),
+            (7, Provenance::default())
+        );
+        let state: TrainingState<AdamW> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(state.tensors["w"].blob(), &[0.5, -1.]);
+        assert_eq!(state.data_seed, 7);
+        let legacy = &bytes[..bytes.len() - 8];
+        let state: TrainingState<AdamW> = bincode::deserialize(legacy).unwrap();
+        assert_eq!(state.optimizer.step_num(), 3);
+        // Cut inside the data seed, which isn't made up
+        let truncated = &bytes[..bytes.len() - 3];
+        assert!(bincode::deserialize::<TrainingState<AdamW>>(truncated).is_err());
+        assert_eq!(state.tensors["w"].blob(), &[0.5, -1.]);
+        assert!(bincode::deserialize::<TrainingState<AdamW>>(&legacy[..legacy.len() - 4]).is_err());
+        versioned[8] = STATE_VERSION as u8 + 1;
+        assert!(bincode::deserialize::<TrainingState<AdamW>>(&versioned).is_err());
+    }
+
+    #[test]
+    fn test_seeded_rng() {
+        // The stream of a seed is pinned, for seeded runs to be reproducible anywhere
+        let mut rng = seeded_rng(42);
+        let values = (0..3).map(|_| rng.gen::<u32>()).collect::<Vec<_>>();
+        assert_eq!(values, [962419617, 2928721845, 628724104]);
+        let weights = |seed| {
+            let gpt = GPT::new(&mut seeded_rng(seed), 7, &config(8, 1), Naive::new()).unwrap();
+            let mut tensors = gpt
+                .get_training_state()
+                .unwrap()
+                .tensors
+                .into_iter()
+                .collect::<Vec<_>>();
+            tensors.sort_by(|a, b| a.0.cmp(&b.0));
+            tensors
+                .into_iter()
+                .map(|(_, t)| t.blob().to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(weights(1), weights(1));
+        assert_ne!(weights(1), weights(2));
+    }
+
+    #[test]
+    fn test_truncate_prompt() {
+        let tokens = [1, 2, 3, 4, 5, 6];
+        assert_eq!(truncate_prompt(&tokens, 8, Truncation::Head), tokens);
+        assert_eq!(truncate_prompt(&tokens, 3, Truncation::Head), [1, 2, 3]);
+        assert_eq!(truncate_prompt(&tokens, 3, Truncation::Tail), [4, 5, 6]);
+        assert_eq!(truncate_prompt(&tokens, 3, Truncation::Ends), [1, 5, 6]);
+        assert_eq!(truncate_prompt(&tokens, 4, Truncation::Ends), [1, 2, 5, 6]);
+        assert_eq!("ends".parse::<Truncation>(), Ok(Truncation::Ends));
+
+        let gpt = model(0, 8, 2);
+        assert_eq!(gpt.prompt_budget(2), 10);
+        assert_eq!(gpt.prompt_budget(100), 6);
+        let prompt = (0..14).collect::<Vec<_>>();
+        assert_eq!(
+            gpt.fit_prompt(&prompt, 8, Truncation::Ends),
+            [0, 1, 2, 11, 12, 13]
+        );
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let tokens = [1, 2, 3, 4, 5, 6];
+        assert_eq!(rolling_window(&tokens, 2, 8), tokens);
+        assert_eq!(rolling_window(&tokens, 0, 4), [3, 4, 5, 6]);
+        assert_eq!(rolling_window(&tokens, 2, 4), [1, 2, 5, 6]);
+        assert_eq!(rolling_window(&tokens, 9, 4), [1, 2, 3, 6]);
+
+        // Generation goes on past the context, from prompts longer than it
+        let mut gpt = model(0, 8, 1);
+        let greedy = Sampling {
+            temperature: 0.,
+            top_p: 1.,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let prompt = (0..20).map(|i| i % 7).collect::<Vec<_>>();
+        let long = gpt
+            .generate(&mut rng, &prompt, 30, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(long.len(), 30);
+        let short = gpt
+            .generate(&mut rng, &prompt[8..], 30, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(long, short);
+        gpt.set_pinned_tokens(4);
+        let pinned = gpt
+            .generate(&mut rng, &prompt, 30, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(pinned.len(), 30);
+    }
+
+    #[test]
+    fn test_lr_scale() {
+        let mut gpt = model(0, 8, 2);
+        gpt.set_layer_decay(0.5);
+        assert_eq!(gpt.lr_scale("head_map_weights"), 1.);
+        assert_eq!(gpt.lr_scale("head_norm_coeff"), 1.);
+        assert_eq!(gpt.lr_scale("feedforward1_1_weights"), 0.5);
+        assert_eq!(gpt.lr_scale("head_0_1_k"), 0.25);
+        assert_eq!(gpt.lr_scale("token_embedding"), 0.125);
+        assert_eq!(gpt.lr_scale("pos_embedding"), 0.125);
+    }
+
+    #[test]
+    fn test_fake_quant() {
+        let mut gpt = model(0, 8, 1);
+        let tokens = [1, 2, 3, 4];
+        let plain = gpt.logits(&tokens).unwrap();
+        assert_eq!(gpt.set_fake_quant(&["gelu".into()], 8), 0);
+        assert!(gpt.set_fake_quant(&["matmul".into()], 8) > 0);
+        let quantized = gpt.logits(&tokens).unwrap();
+        assert_ne!(plain.blob(), quantized.blob());
+        for (p, q) in plain.blob().iter().zip(quantized.blob()) {
+            assert!((p - q).abs() < 0.1, "{} {}", p, q);
+        }
+    }
+
+    #[test]
+    fn test_tensor_parallel() {
+        let dataset = (0..100).map(|i| (i * i) % 7).collect::<Vec<_>>();
+        let mut plain = model(0, 8, 2);
+        let mut sharded = model(0, 8, 2);
+        // The query, key and value of 2 heads, the projection and 2 feed-forward
+        // layers, in 2 blocks
+        assert_eq!(sharded.set_tensor_parallel(3), 18);
+        let tokens = [1, 2, 3, 4];
+        let (a, b) = (
+            plain.logits(&tokens).unwrap(),
+            sharded.logits(&tokens).unwrap(),
+        );
+        for (a, b) in a.blob().iter().zip(b.blob()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        for _ in 0..3 {
+            let a = plain.train_step(&dataset, 2, None, 0.1).unwrap();
+            let b = sharded.train_step(&dataset, 2, None, 0.1).unwrap();
+            assert!((a.loss - b.loss).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_fuse() {
+        let dataset = (0..100).map(|i| (i * i) % 7).collect::<Vec<_>>();
+        let mut plain = model(0, 8, 2);
+        let mut fused = model(0, 8, 2);
+        // The projection and 2 feed-forward layers (And the activation of the first
+        // one) in 2 blocks, and the output
+        assert_eq!(fused.fuse().unwrap(), 9);
+        assert_eq!(fused.fuse().unwrap(), 0);
+        let tokens = [1, 2, 3, 4];
+        let (a, b) = (
+            plain.logits(&tokens).unwrap(),
+            fused.logits(&tokens).unwrap(),
+        );
+        for (a, b) in a.blob().iter().zip(b.blob()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        for _ in 0..3 {
+            let a = plain.train_step(&dataset, 2, None, 0.1).unwrap();
+            let b = fused.train_step(&dataset, 2, None, 0.1).unwrap();
+            assert!((a.loss - b.loss).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_prune() {
+        let dataset = (0..100).map(|i| (i * i) % 7).collect::<Vec<_>>();
+        let mut gpt = model(0, 8, 2);
+        let stats = gpt.prune(0.5).unwrap();
+        // k, q, v of 2 heads, the projection and 2 feed-forward layers in 2 blocks, and
+        // the output
+        assert_eq!(stats.tensors.len(), 19);
+        assert!((stats.sparsity() - 0.5).abs() < 0.01);
+        let zeros = |gpt: &GPT<Naive>| {
+            let state = gpt.get_training_state().unwrap();
+            let t = &state.tensors["feedforward1_0_weights"];
+            t.blob().iter().filter(|v| **v == 0.).count()
+        };
+        assert_eq!(zeros(&gpt), 8 * 32 / 2);
+        // The pruned weights stay zero
+        gpt.train_step(&dataset, 2, None, 0.1).unwrap();
+        assert_eq!(zeros(&gpt), 8 * 32 / 2);
+
+        let tokens = [1, 2, 3, 4];
+        let dense = gpt.logits(&tokens).unwrap();
+        assert_eq!(gpt.set_sparse_products(), 19);
+        let sparse = gpt.logits(&tokens).unwrap();
+        for (a, b) in dense.blob().iter().zip(sparse.blob()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        gpt.train_step(&dataset, 2, None, 0.1).unwrap();
+        assert_eq!(zeros(&gpt), 8 * 32 / 2);
+
+        // Unpruned
+        gpt.prune(0.).unwrap();
+        gpt.train_step(&dataset, 2, None, 0.1).unwrap();
+        assert!(zeros(&gpt) < 8 * 32 / 2);
+    }
+
+    #[test]
+    fn test_prediction_head() {
+        let dataset = (0..100).map(|i| (i * i) % 7).collect::<Vec<_>>();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut plain = model(0, 8, 1);
+        let mut ignored = model(0, 8, 1);
+        let mut trained = model(0, 8, 1);
+        ignored.add_prediction_head(&mut rng, 1, 0.).unwrap();
+        let head = trained.add_prediction_head(&mut rng, 1, 1.).unwrap();
+        assert_eq!(trained.params.len(), plain.params.len() + 2);
+        let tokens = [1, 2, 3, 4];
+        assert_eq!(
+            plain.logits(&tokens).unwrap().blob(),
+            trained.logits(&tokens).unwrap().blob()
+        );
+        let state = trained.get_training_state().unwrap();
+        let before = state.tensors["aux_head1_weights"].clone();
+        for _ in 0..3 {
+            let a = plain.train_step(&dataset, 2, None, 0.1).unwrap();
+            let b = ignored.train_step(&dataset, 2, None, 0.1).unwrap();
+            let c = trained.train_step(&dataset, 2, None, 0.1).unwrap();
+            // Without weight, the head changes nothing to the rest of the model
+            assert!((a.loss - b.loss).abs() < 1e-5);
+            assert!(c.loss.is_finite());
+        }
+        let state = trained.get_training_state().unwrap();
+        assert_ne!(state.tensors["aux_head1_weights"].blob(), before.blob());
+        assert_ne!(
+            plain.logits(&tokens).unwrap().blob(),
+            trained.logits(&tokens).unwrap().blob()
+        );
+        assert!(trained.graph.get(head).is_ok());
+    }
+
+    #[test]
+    fn test_overfit_sanity_check() {
+        let mut gpt = model(0, 8, 1);
+        let tokens = [1, 2, 3, 4];
+        let before = gpt.logits(&tokens).unwrap();
+        let batch = vec![vec![1, 2, 3, 4, 5, 6], vec![6, 5, 4, 3, 2, 1]];
+        let losses = gpt.overfit_sanity_check(&batch, 1.).unwrap();
+        assert!(losses.len() < OVERFIT_STEPS);
+        assert!(losses[losses.len() - 1] < OVERFIT_LOSS);
+        assert!(losses[0] > 1.);
+        // Untouched
+        assert_eq!(gpt.logits(&tokens).unwrap().blob(), before.blob());
+        assert_eq!(gpt.optimizer.step_num(), 0);
+
+        // The same context can't be followed by two different tokens
+        let ambiguous = vec![vec![1, 2, 3], vec![1, 2, 4]];
+        assert!(matches!(
+            gpt.overfit_sanity_check(&ambiguous, 0.1),
+            Err(GraphError::OverfitFailed { loss, .. }) if loss > 0.2
+        ));
+        assert_eq!(gpt.logits(&tokens).unwrap().blob(), before.blob());
+    }
+
+    #[test]
+    fn test_full_precision() {
+        let mut gpt = model(0, 8, 1);
+        gpt.set_precision(Precision::Mixed);
+        let half = gpt.activation_memory();
+        // 3 layer norms of 2 parameters, and the logits
+        assert_eq!(
+            gpt.set_full_precision(&["*norm*".into(), "logits".into()]),
+            7
+        );
+        assert!(gpt.activation_memory() > half);
+        for p in gpt.params.iter() {
+            let name = gpt.graph.name_of(*p).unwrap();
+            let expected = match name.contains("norm") {
+                true => Precision::F32,
+                false => Precision::Mixed,
+            };
+            assert_eq!(gpt.graph.precision_of(*p), expected, "{}", name);
+        }
+        assert!(gpt
+            .logits(&[1, 2, 3])
+            .unwrap()
+            .blob()
+            .iter()
+            .all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn test_record_tape() {
+        let gpt = model(0, 8, 1);
+        assert!(gpt.record_tape(&[1]).is_err());
+        let tape = gpt.record_tape(&[1, 2, 3, 4, 5]).unwrap();
+        let passes = tape.steps.iter().map(|s| s.pass).collect::<Vec<_>>();
+        let forward = passes.iter().filter(|p| **p == Pass::Forward).count();
+        // Forward, the loss, then backward through the same functions in reverse
+        assert_eq!(passes.len(), 2 * forward + 1);
+        assert_eq!(passes[forward], Pass::Loss);
+        let loss = &tape.steps[forward];
+        assert_eq!(loss.op, "crossentropy");
+        assert_eq!(loss.inputs[0].name, "logits");
+        assert_eq!(loss.inputs[0].shape.last(), Some(&gpt.vocab_size));
+        for (f, b) in tape.steps[..forward]
+            .iter()
+            .zip(tape.steps[forward + 1..].iter().rev())
+        {
+            assert_eq!((f.op, f.id), (b.op, b.id));
+            assert_eq!(b.grad_norms.len(), b.inputs.len());
+        }
+        let json: serde_json::Value = serde_json::from_str(&tape.to_json()).unwrap();
+        assert_eq!(json["steps"][forward]["pass"], "loss");
+    }
+
+    #[test]
+    fn test_check_numerics() {
+        let mut gpt = model(0, 8, 1);
+        let window = Window {
+            tokens: (0..13).map(|i| i % 7).collect(),
+            source: 0,
+            documents: None,
+        };
+        let divergences = gpt.check_numerics(&window).unwrap();
+        for op in ["softmax", "layer_norm", "matmul", "crossentropy"] {
+            assert!(divergences.iter().any(|d| d.op == op), "{}", op);
+        }
+        assert!(divergences.windows(2).all(|w| w[0].max() >= w[1].max()));
+        assert!(
+            divergences.iter().all(|d| d.max() < 1e-3),
+            "{:?}",
+            divergences
+        );
+        // An op losing precision stands out
+        gpt.set_fake_quant(&["softmax".into()], 4);
+        assert_eq!(gpt.check_numerics(&window).unwrap()[0].op, "softmax");
+    }
+
+    #[test]
+    fn test_grow_vocab() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut state = model(0, 8, 1).get_training_state().unwrap();
+        let old = state.clone();
+        state.grow_vocab(&mut rng, 9).unwrap();
+        let mut grown = GPT::new(&mut rng, 9, &config(8, 1), Naive::new()).unwrap();
+        grown.set_training_state(state, true).unwrap();
+        let state = grown.get_training_state().unwrap();
+        let embedding = &state.tensors["token_embedding"];
+        assert_eq!(embedding.shape(), [9, 8]);
+        assert_eq!(
+            &embedding.blob()[..7 * 8],
+            old.tensors["token_embedding"].blob()
+        );
+        let weights = &state.tensors["head_map_weights"];
+        let old_weights = &old.tensors["head_map_weights"];
+        for (row, old_row) in weights.blob().chunks(9).zip(old_weights.blob().chunks(7)) {
+            assert_eq!(&row[..7], old_row);
+        }
+        assert_eq!(
+            &state.tensors["head_map_bias"].blob()[..7],
+            old.tensors["head_map_bias"].blob()
+        );
+        assert!(state.clone().grow_vocab(&mut rng, 8).is_err());
+    }
+
+    #[test]
+    fn test_generate_healed() {
+        let gpt = model(0, 8, 2);
+        let greedy = Sampling {
+            temperature: 0.,
+            top_p: 1.,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let prompt = [1, 2, 3];
+        let expected = gpt
+            .generate(&mut rng, &prompt, 5, &greedy, |_| true)
+            .unwrap();
+        let healed = gpt
+            .generate_healed(&mut rng, &prompt, &[], 5, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(healed, expected);
+
+        // Only the first token is constrained
+        let other = (expected[0] + 1) % 7;
+        let healed = gpt
+            .generate_healed(&mut rng, &prompt, &[other], 5, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(healed[0], other);
+        assert_eq!(healed.len(), 5);
+        for _ in 0..10 {
+            let healed = gpt
+                .generate_healed(&mut rng, &prompt, &[4, 6], 1, &Sampling::default(), |_| {
+                    true
+                })
+                .unwrap();
+            assert!([4, 6].contains(&healed[0]));
+        }
+        assert!(gpt
+            .generate_healed(&mut rng, &prompt, &[7], 1, &greedy, |_| true)
+            .is_err());
+    }
+
+    #[test]
+    fn test_generate_constrained() {
+        let gpt = model(0, 8, 2);
+        let mut rng = StdRng::seed_from_u64(0);
+        // Even tokens only, and a 5 right after every 2
+        let mut format = |generated: &[usize], vocab_size: usize| {
+            (0..vocab_size)
+                .map(|t| match generated.last() {
+                    Some(2) => t == 5,
+                    _ => t % 2 == 0,
+                })
+                .collect()
+        };
+        for _ in 0..5 {
+            let chs = gpt
+                .generate_constrained(
+                    &mut rng,
+                    &[1, 2, 3],
+                    &mut format,
+                    20,
+                    &Sampling::default(),
+                    |_| true,
+                )
+                .unwrap();
+            assert_eq!(chs.len(), 20);
+            for (i, t) in chs.iter().enumerate() {
+                match i.checked_sub(1).map(|j| chs[j]) {
+                    Some(2) => assert_eq!(*t, 5),
+                    _ => assert_eq!(t % 2, 0),
+                }
             }
-            context[cnt] = next_ch;
-            cnt += 1;
         }
-        Ok(chs)
+        // Generation stops when no token is allowed
+        let mut three =
+            |generated: &[usize], vocab_size: usize| vec![generated.len() < 3; vocab_size];
+        let chs = gpt
+            .generate_constrained(&mut rng, &[1], &mut three, 10, &Sampling::default(), |_| {
+                true
+            })
+            .unwrap();
+        assert_eq!(chs.len(), 3);
+        let mut wrong = |_: &[usize], _: usize| vec![true; 3];
+        assert!(gpt
+            .generate_constrained(&mut rng, &[1], &mut wrong, 1, &Sampling::default(), |_| {
+                true
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let gpt = model(0, 8, 2);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut any = |_: &[usize], vocab_size: usize| vec![true; vocab_size];
+        let cancel = CancellationToken::new();
+        let handle = cancel.clone();
+        let chs = gpt
+            .generate_cancellable(
+                &mut rng,
+                &[1, 2],
+                &mut any,
+                20,
+                &Sampling::default(),
+                &cancel,
+                |_| {
+                    // E.g. from another thread
+                    handle.cancel();
+                    true
+                },
+            )
+            .unwrap();
+        assert_eq!(chs.len(), 1);
+        assert!(cancel.is_cancelled() && !cancel.timed_out());
+
+        cancel.reset();
+        let chs = gpt
+            .infer_stream(&mut rng, &[1, 2], 5, 1., &cancel, |_| {})
+            .unwrap();
+        assert_eq!(chs.len(), 7);
+
+        let timed = cancel.clone().with_timeout(Duration::ZERO);
+        assert!(timed.timed_out() && !cancel.is_cancelled());
+        let chs = gpt
+            .infer_stream(&mut rng, &[1, 2], 5, 1., &timed, |_| {})
+            .unwrap();
+        assert_eq!(chs, vec![1, 2]);
+        let later = CancellationToken::new().with_timeout(Duration::from_secs(3600));
+        assert!(!later.with_timeout(Duration::from_secs(7200)).is_cancelled());
+        assert!(!CancellationToken::new()
+            .with_timeout(Duration::MAX)
+            .is_cancelled());
+    }
+
+    #[test]
+    fn test_restrict_vocab() {
+        let mut gpt = model(0, 8, 2);
+        let greedy = Sampling {
+            temperature: 0.,
+            top_p: 1.,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(gpt.restrict_vocab(Some(&[])).is_err());
+        assert!(gpt.restrict_vocab(Some(&[1, 7])).is_err());
+
+        // Slicing the output layer gives what masking the other logits does
+        let mut only =
+            |_: &[usize], vocab_size: usize| (0..vocab_size).map(|t| t == 2 || t == 5).collect();
+        let masked = gpt
+            .generate_constrained(&mut rng, &[1, 2, 3], &mut only, 20, &greedy, |_| true)
+            .unwrap();
+        gpt.restrict_vocab(Some(&[5, 2, 5])).unwrap();
+        assert_eq!(gpt.vocab_subset(), Some(&[2, 5][..]));
+        let sliced = gpt
+            .generate(&mut rng, &[1, 2, 3], 20, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(sliced, masked);
+        let sampled = gpt
+            .generate(&mut rng, &[1, 2, 3], 20, &Sampling::default(), |_| true)
+            .unwrap();
+        assert!(sampled.iter().all(|t| [2, 5].contains(t)));
+        // Constraints still apply, on top of it
+        let mut fives = |_: &[usize], vocab_size: usize| (0..vocab_size).map(|t| t == 5).collect();
+        let chs = gpt
+            .generate_constrained(&mut rng, &[1], &mut fives, 3, &Sampling::default(), |_| {
+                true
+            })
+            .unwrap();
+        assert_eq!(chs, [5, 5, 5]);
+
+        gpt.restrict_vocab(None).unwrap();
+        assert_eq!(gpt.vocab_subset(), None);
+    }
+
+    #[test]
+    fn test_sample_packed() {
+        let gpt = model(0, 8, 2);
+        let greedy = Sampling::greedy();
+        let mut rng = StdRng::seed_from_u64(0);
+        // The last one fills a window of its own
+        let sequences: [&[usize]; 4] = [&[1, 2, 3], &[4], &[5, 6, 0, 1, 2], &[3; 20]];
+        let packed = gpt
+            .run_packed(&[sequences[0], sequences[1], sequences[2]])
+            .unwrap();
+        for (sequence, logits) in sequences.iter().zip(packed) {
+            let alone = gpt.logits(sequence).unwrap();
+            let alone = alone.get(sequence.len() - 1).unwrap();
+            for (a, b) in logits.blob().iter().zip(alone.blob()) {
+                assert!((a - b).abs() < 1e-5);
+            }
+        }
+        let next = gpt
+            .sample_packed(&mut rng, &sequences, &vec![greedy.clone(); sequences.len()])
+            .unwrap();
+        for (sequence, next) in sequences.iter().zip(next) {
+            let expected = gpt
+                .generate(&mut rng, sequence, 1, &greedy, |_| true)
+                .unwrap();
+            assert_eq!(next, expected[0]);
+        }
+        assert!(gpt
+            .sample_packed(&mut rng, &[&[]], std::slice::from_ref(&greedy))
+            .is_err());
+        assert!(gpt.sample_packed(&mut rng, &[&[1]], &[]).is_err());
+    }
+
+    #[test]
+    fn test_length_control() {
+        let gpt = model(0, 8, 2);
+        let mut rng = StdRng::seed_from_u64(0);
+        let greedy = Sampling::greedy();
+        let prompt = [1, 2, 3];
+        let expected = gpt
+            .generate(&mut rng, &prompt, 6, &greedy, |_| true)
+            .unwrap();
+        let mut length = LengthControl {
+            end_tokens: vec![expected[0]],
+            ..Default::default()
+        };
+        assert!(length.is_none());
+        let mut generate = |length: &mut LengthControl| {
+            gpt.generate_constrained(&mut rng, &prompt, length, 6, &greedy, |_| true)
+                .unwrap()
+        };
+        assert_eq!(generate(&mut length), expected);
+
+        // The end token can't come first
+        length.min_new_tokens = 1;
+        assert_ne!(generate(&mut length)[0], expected[0]);
+
+        // Then it's ever likelier, from the token after
+        length.length_penalty = 1e3;
+        let end = generate(&mut length).iter().position(|t| *t == expected[0]);
+        assert!(matches!(end, Some(1 | 2)));
+        length.length_penalty = -1e3;
+        assert!(!generate(&mut length)[2..].contains(&expected[0]));
+    }
+
+    #[test]
+    fn test_generate_speculative() {
+        let gpt = model(0, 8, 2);
+        let draft = model(1, 4, 1);
+        let greedy = Sampling {
+            temperature: 0.,
+            top_p: 1.,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let prompt = [1, 2, 3];
+        let expected = gpt
+            .generate(&mut rng, &prompt, 9, &greedy, |_| true)
+            .unwrap();
+
+        // Greedy decoding gives the same tokens, whatever the draft
+        let (chs, stats) = gpt
+            .generate_speculative(&draft, &mut rng, &prompt, 9, 4, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(chs, expected);
+        assert!(stats.accepted <= stats.drafted);
+
+        // A draft that is always right gets all its tokens accepted
+        let (chs, stats) = gpt
+            .generate_speculative(&gpt, &mut rng, &prompt, 9, 4, &greedy, |_| true)
+            .unwrap();
+        assert_eq!(chs, expected);
+        assert_eq!(stats.acceptance_rate(), 1.);
+        assert_eq!(stats.passes, 2);
+
+        let (chs, _) = gpt
+            .generate_speculative(&gpt, &mut rng, &prompt, 20, 4, &Sampling::default(), |_| {
+                true
+            })
+            .unwrap();
+        assert_eq!(chs.len(), 20);
+        let mut seen = 0;
+        let (chs, _) = gpt
+            .generate_speculative(&draft, &mut rng, &prompt, 20, 4, &greedy, |_| {
+                seen += 1;
+                seen < 3
+            })
+            .unwrap();
+        assert_eq!(chs.len(), 3);
+
+        let other_vocab = GPT::new(&mut rng, 9, &config(4, 1), Naive::new()).unwrap();
+        assert!(gpt
+            .generate_speculative(&other_vocab, &mut rng, &prompt, 9, 4, &greedy, |_| true)
+            .is_err());
+    }
+
+    #[test]
+    fn test_attention_maps() {
+        let gpt = model(0, 8, 2);
+        let maps = gpt.attention_maps(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(maps.shape(), &[2, 2, 4, 4]);
+        for row in maps.blob().chunks(4).enumerate() {
+            let (i, row) = (row.0 % 4, row.1);
+            assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-5);
+            assert!(row[i + 1..].iter().all(|w| *w == 0.));
+        }
+        // Attention is causal, so a prefix gets the same weights
+        let prefix = gpt.attention_maps(&[1, 2]).unwrap();
+        for (l, h) in [(0, 0), (1, 1)] {
+            for i in 0..2 {
+                for j in 0..2 {
+                    let a = maps.blob()[((l * 2 + h) * 4 + i) * 4 + j];
+                    let b = prefix.blob()[((l * 2 + h) * 2 + i) * 2 + j];
+                    assert!((a - b).abs() < 1e-6);
+                }
+            }
+        }
+        // Only the last `num_tokens` tokens are kept
+        let long = (0..20).map(|i| i % 7).collect::<Vec<_>>();
+        assert_eq!(gpt.attention_maps(&long).unwrap().shape(), &[2, 2, 12, 12]);
+        assert!(gpt.attention_maps(&[]).is_err());
+
+        let mut npz = Vec::new();
+        gpt.export_attention_npz(&[1, 2, 3], &mut npz).unwrap();
+        let arrays = read_npz::<f32, _>(&npz[..]).unwrap();
+        assert_eq!(arrays[0].0, "attention");
+        assert_eq!(arrays[0].1.shape(), &[2, 2, 3, 3]);
+        assert_eq!(arrays[1].1.blob(), &[1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_logit_lens() {
+        let gpt = model(0, 8, 3);
+        let tokens = [1, 2, 3, 4, 5];
+        let lens = gpt.logit_lens(&tokens).unwrap();
+        assert_eq!(lens.shape(), &[3, 5, 7]);
+
+        // The lens of the last block is the output of the model
+        let mut window = tokens.to_vec();
+        window.push(6);
+        let log_probs = gpt.token_log_probs(&window).unwrap();
+        let last = &lens.blob()[2 * 5 * 7..];
+        for (i, logits) in last.chunks(7).enumerate() {
+            let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+            let sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>();
+            let log_prob = logits[window[i + 1]] - max - sum.ln();
+            assert!((log_prob - log_probs[i]).abs() < 1e-4);
+        }
+        assert!(gpt.logit_lens(&[]).is_err());
+        assert!(gpt.token_log_probs(&[1, 2, 7]).is_err());
+        assert!(gpt.token_ranks(&[1, 2, 7]).is_err());
+    }
+
+    #[test]
+    fn test_activation() {
+        let tokens = [1, 2, 3, 4, 5];
+        let mut outputs = Vec::new();
+        for activation in ["relu", "gelu", "silu", "tanh"] {
+            let activation = activation.parse::<Activation>().unwrap();
+            let mut gpt = model_with(
+                0,
+                &ModelConfig {
+                    activation,
+                    ..config(8, 1)
+                },
+            );
+            let dataset = (0..100).map(|i| i % 7).collect::<Vec<_>>();
+            gpt.train_step(&dataset, 2, None, 0.001).unwrap();
+            outputs.push(gpt.token_log_probs(&tokens).unwrap());
+        }
+        // Same weights, different nonlinearities
+        for (i, a) in outputs.iter().enumerate() {
+            assert!(outputs[i + 1..].iter().all(|b| a != b));
+        }
+        assert!("swish".parse::<Activation>().is_err());
+    }
+
+    #[test]
+    fn test_positional_encoding() {
+        for encoding in ["learned", "sinusoidal", "rope", "alibi", "none"] {
+            let encoding = encoding.parse::<PositionalEncoding>().unwrap();
+            let mut gpt = model_with(
+                0,
+                &ModelConfig {
+                    positional_encoding: encoding,
+                    ..config(8, 1)
+                },
+            );
+            let learned = encoding == PositionalEncoding::Learned;
+            let pos_embedding = gpt.graph.name_of(gpt.pos_embedding).unwrap().clone();
+            assert_eq!(gpt.params.contains(&gpt.pos_embedding), learned);
+            assert_eq!(
+                gpt.named_params()
+                    .unwrap()
+                    .iter()
+                    .any(|(name, _)| *name == pos_embedding),
+                learned
+            );
+            let dataset = (0..100).map(|i| i % 7).collect::<Vec<_>>();
+            gpt.train_step(&dataset, 2, None, 0.001).unwrap();
+
+            // A single block can only tell the order of the context apart through the
+            // positions. (With weights large enough for attention not to be uniform)
+            let mut rng = StdRng::seed_from_u64(1);
+            for p in gpt.params.clone() {
+                let shape = gpt.graph.get_shape(p).unwrap().to_vec();
+                gpt.graph
+                    .load(p, &Tensor::rand_normal(&mut rng, 0., 1., &shape));
+            }
+            let last = |tokens: &[usize]| {
+                let states = gpt.hidden_states(tokens, Some(0)).unwrap();
+                states.get(2).unwrap().blob().to_vec()
+            };
+            let (a, b) = (last(&[1, 2, 3]), last(&[2, 1, 3]));
+            let diff = a
+                .iter()
+                .zip(&b)
+                .fold(0f32, |d, (a, b)| d.max((a - b).abs()));
+            assert_eq!(
+                diff < 1e-4,
+                encoding == PositionalEncoding::None,
+                "{:?}",
+                encoding
+            );
+        }
+        assert!("nope".parse::<PositionalEncoding>().is_err());
+    }
+
+    #[test]
+    fn test_parallel_residual() {
+        let build = |parallel| {
+            model_with(
+                0,
+                &ModelConfig {
+                    parallel_residual: parallel,
+                    ..config(8, 2)
+                },
+            )
+        };
+        let sequential = build(false);
+        let mut parallel = build(true);
+        // No normalization between the attention and the feed-forward layer
+        assert_eq!(parallel.params.len(), sequential.params.len() - 2 * 2);
+        assert!(parallel
+            .named_params()
+            .unwrap()
+            .iter()
+            .all(|(name, _)| !name.starts_with("atten_norm")));
+
+        let dataset = (0..100).map(|i| i % 7).collect::<Vec<_>>();
+        let before = parallel.token_log_probs(&[1, 2, 3, 4]).unwrap();
+        for _ in 0..5 {
+            parallel.train_step(&dataset, 2, None, 0.01).unwrap();
+        }
+        assert_ne!(parallel.token_log_probs(&[1, 2, 3, 4]).unwrap(), before);
+    }
+
+    #[test]
+    fn test_logits_cached() {
+        let tokens = (0..16).map(|i| (i * 5 + i / 3) % 7).collect::<Vec<_>>();
+        for encoding in ["learned", "sinusoidal", "rope", "alibi", "none"] {
+            for parallel in [false, true] {
+                let gpt = model_with(
+                    0,
+                    &ModelConfig {
+                        activation: Activation::Gelu,
+                        positional_encoding: encoding.parse().unwrap(),
+                        parallel_residual: parallel,
+                        ..config(8, 2)
+                    },
+                );
+                assert!(gpt.can_cache());
+                let mut cache = KvCache::new();
+                // Growing windows, then rolling ones, then a branching one
+                for end in (1..=tokens.len()).chain([5]) {
+                    let prefix = &tokens[..end];
+                    let cached = gpt.logits_cached(&mut cache, prefix).unwrap();
+                    let logits = gpt.logits(prefix).unwrap();
+                    let expected = logits.get(logits.shape()[0] - 1).unwrap();
+                    for (a, b) in cached.blob().iter().zip(expected.blob()) {
+                        assert!((a - b).abs() < 1e-4, "{} {}: {} {}", encoding, end, a, b);
+                    }
+                    assert_eq!(cache.len(), end.min(12));
+                }
+                assert!(cache.memory() > 0);
+
+                let mut rng = StdRng::seed_from_u64(0);
+                let cancel = CancellationToken::new();
+                let mut unconstrained = |_: &[usize], vocab_size: usize| vec![true; vocab_size];
+                let greedy = Sampling::greedy();
+                let generated = gpt
+                    .generate_cached(
+                        &mut rng,
+                        &mut cache,
+                        &tokens[..3],
+                        &mut unconstrained,
+                        15,
+                        &greedy,
+                        &cancel,
+                        |_| true,
+                    )
+                    .unwrap();
+                let expected = gpt
+                    .generate(&mut rng, &tokens[..3], 15, &greedy, |_| true)
+                    .unwrap();
+                assert_eq!(generated, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_token_accuracy() {
+        let mut accuracy = TokenAccuracy::default();
+        for rank in [0, 3, 7] {
+            accuracy.record(rank);
+        }
+        assert_eq!((accuracy.top1, accuracy.top5, accuracy.tokens), (1, 2, 3));
+        assert_eq!(TokenAccuracy::default().top1_rate(), 0.);
+
+        let mut gpt = model(0, 8, 1);
+        let tokens = [1, 2, 3, 4, 5, 6];
+        let ranks = gpt.token_ranks(&tokens).unwrap();
+        let log_probs = gpt.token_log_probs(&tokens).unwrap();
+        assert_eq!(ranks.len(), log_probs.len());
+        assert!(ranks.iter().all(|r| *r < 7));
+        let eval = |gpt: &GPT<Naive>| {
+            let tokens = (0..40).map(|i| i % 7).collect::<Vec<_>>();
+            crate::eval::token_accuracy(gpt, &tokens).unwrap()
+        };
+        let before = eval(&gpt);
+        assert_eq!(before.tokens, 39);
+
+        // Training on a repeating sequence makes it predictable
+        let dataset = (0..196).map(|i| i % 7).collect::<Vec<_>>();
+        let first = gpt.train_step(&dataset, 4, None, 0.1).unwrap();
+        assert_eq!(first.accuracy.tokens, 4 * 12);
+        for _ in 0..100 {
+            gpt.train_step(&dataset, 4, None, 0.1).unwrap();
+        }
+        let last = gpt.train_step(&dataset, 4, None, 0.1).unwrap();
+        assert!(last.accuracy.top1_rate() > first.accuracy.top1_rate());
+        assert!(eval(&gpt).top1_rate() > before.top1_rate());
+    }
+
+    #[test]
+    fn test_sparse_embeddings() {
+        let train = |sparse| {
+            let mut rng = StdRng::seed_from_u64(0);
+            let mut gpt =
+                GPT::new(&mut rng, 7, &config(8, 1), crate::optimizer::AdamW::new()).unwrap();
+            let before = gpt.get_training_state().unwrap().tensors["token_embedding"].clone();
+            gpt.set_sparse_embeddings(sparse);
+            // Only tokens 0 to 3 are trained on
+            let dataset = (0..100).map(|i| i % 4).collect::<Vec<_>>();
+            for _ in 0..3 {
+                gpt.train_step(&dataset, 2, None, 0.01).unwrap();
+            }
+            let after = gpt.get_training_state().unwrap().tensors["token_embedding"].clone();
+            (before, after)
+        };
+        let (before, after) = train(true);
+        assert_eq!(&before.blob()[32..], &after.blob()[32..]);
+        assert_ne!(&before.blob()[..32], &after.blob()[..32]);
+        let (before, after) = train(false);
+        assert_ne!(&before.blob()[32..], &after.blob()[32..]);
+    }
+
+    #[test]
+    fn test_context_length() {
+        let build = |encoding| {
+            model_with(
+                0,
+                &ModelConfig {
+                    num_tokens: 8,
+                    positional_encoding: encoding,
+                    ..config(8, 1)
+                },
+            )
+        };
+        let prompt = [1, 2, 3];
+        for encoding in [PositionalEncoding::Rope, PositionalEncoding::Alibi] {
+            let gpt = build(encoding);
+            let logits = gpt.logits(&prompt).unwrap();
+            for num_tokens in [4, 16] {
+                let elastic = gpt.with_context_length(num_tokens).unwrap();
+                assert_eq!(elastic.num_tokens(), num_tokens);
+                let other = elastic.logits(&prompt).unwrap();
+                assert!(logits
+                    .blob()
+                    .iter()
+                    .zip(other.blob())
+                    .all(|(a, b)| (a - b).abs() < 1e-4));
+            }
+            // Longer generations than the trained context, in a single window
+            let long = gpt.with_context_length(16).unwrap();
+            let greedy = Sampling::greedy();
+            let out = long.generate(&mut seeded_rng(0), &prompt, 12, &greedy, |_| true);
+            assert_eq!(out.unwrap().len(), 12);
+        }
+
+        // Learned positions only go as far as they were trained
+        let gpt = build(PositionalEncoding::Learned);
+        assert!(gpt.with_context_length(16).is_err());
+        assert!(gpt.with_context_length(0).is_err());
+        let short = gpt.with_context_length(4).unwrap();
+        let (a, b) = (gpt.logits(&prompt).unwrap(), short.logits(&prompt).unwrap());
+        assert!(a
+            .blob()
+            .iter()
+            .zip(b.blob())
+            .all(|(a, b)| (a - b).abs() < 1e-4));
+        // The window rolls over the last 4 tokens
+        assert_eq!(short.logits(&[0, 1, 2, 3, 4]).unwrap().shape(), [4, 7]);
+    }
+
+    #[test]
+    fn test_noise_scale() {
+        let mut noise_scale = NoiseScale::default();
+        assert_eq!(noise_scale.batch_size(), None);
+        // |G|² = (4 * 2 - 5) / 3 = 1, tr(Σ) = (5 - 2) / (3 / 4) = 4
+        noise_scale.update(5., 2., 4);
+        assert_eq!(noise_scale.batch_size(), Some(4.));
+        noise_scale.update(5., 2., 1);
+        noise_scale.update(f32::NAN, 2., 4);
+        assert_eq!(noise_scale.batch_size(), Some(4.));
+        // Samples agreeing on the gradient need no larger batches
+        let mut same = NoiseScale::default();
+        same.update(3., 3., 8);
+        assert_eq!(same.batch_size(), Some(0.));
+
+        let mut gpt = model(0, 8, 1);
+        let dataset = (0..100).map(|i| i % 7).collect::<Vec<_>>();
+        let stats = gpt.train_step(&dataset, 4, None, 0.001).unwrap();
+        assert_eq!(stats.noise_scale, None);
+        gpt.set_noise_scale(Some(NoiseScale::default()));
+        for _ in 0..3 {
+            gpt.train_step(&dataset, 4, None, 0.001).unwrap();
+        }
+        let stats = gpt.train_step(&dataset, 4, None, 0.001).unwrap();
+        assert!(stats.noise_scale.is_some_and(|b| b.is_finite() && b >= 0.));
+        assert_eq!(stats.noise_scale, gpt.noise_scale().unwrap().batch_size());
+    }
+
+    #[test]
+    fn test_dp_sgd() {
+        let mut gpt = model(0, 8, 1);
+        let dataset = (0..100).map(|i| i % 7).collect::<Vec<_>>();
+        let stats = gpt.train_step(&dataset, 4, None, 0.001).unwrap();
+        assert_eq!(stats.epsilon, None);
+        assert!(stats.grad_norm > 1e-3);
+
+        // Without noise, the average of the clipped gradients is within the clip norm
+        gpt.set_dp_sgd(Some(DpSgd::new(1e-3, 0., 1e-5)));
+        let stats = gpt.train_step(&dataset, 4, None, 0.001).unwrap();
+        assert!(stats.grad_norm <= 1e-3 * 1.0001);
+
+        gpt.set_dp_sgd(Some(DpSgd::new(1e-3, 1., 1e-5)));
+        let first = gpt.train_step(&dataset, 4, None, 0.001).unwrap();
+        let second = gpt.train_step(&dataset, 4, None, 0.001).unwrap();
+        assert!(first.epsilon.is_some_and(|e| e > 0. && e.is_finite()));
+        assert!(second.epsilon > first.epsilon);
+        assert_eq!(gpt.dp_sgd().unwrap().steps(), 2);
+    }
+
+    #[test]
+    fn test_train_weighted() {
+        let good = vec![1, 2, 3, 4, 5];
+        let bad = vec![5, 4, 3, 2, 1, 0, 6, 5, 4, 3, 2, 1, 0, 6];
+        let samples = [good.clone(), bad.clone()];
+        let mut gpt = model(0, 8, 1);
+        assert!(gpt.train_weighted(&samples, &[1.], 0.1).is_err());
+        assert!(gpt.train_weighted(&[vec![1]], &[1.], 0.1).is_err());
+        assert!(gpt.train_weighted(&[vec![1, 7]], &[1.], 0.1).is_err());
+
+        let log_prob = |gpt: &GPT<Naive>, tokens: &[usize]| {
+            gpt.token_log_probs(tokens).unwrap().iter().sum::<f32>()
+        };
+        let before = (log_prob(&gpt, &good), log_prob(&gpt, &bad));
+        for _ in 0..20 {
+            let stats = gpt.train_weighted(&samples, &[1., 0.], 0.5).unwrap();
+            assert_eq!(stats.accuracy.tokens, 4 + 12);
+        }
+        // Only the sequence with a weight is learned (The other one getting less
+        // likely, as it goes the other way)
+        assert!(log_prob(&gpt, &good) - before.0 > 1.);
+        assert!(log_prob(&gpt, &bad) < before.1);
+
+        // With plain SGD, doubling the weights is doubling the learning-rate
+        let mut a = model(0, 8, 1);
+        let mut b = model(0, 8, 1);
+        a.train_weighted(&samples, &[2., 1.], 0.05).unwrap();
+        b.train_weighted(&samples, &[1., 0.5], 0.1).unwrap();
+        let (a, b) = (a.named_params().unwrap(), b.named_params().unwrap());
+        for ((_, a), (_, b)) in a.iter().zip(b.iter()) {
+            for (x, y) in a.blob().iter().zip(b.blob()) {
+                assert!((x - y).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_padded_windows() {
+        let short = vec![1, 2, 3];
+        let long = (0..30).map(|t| t % 7).collect::<Vec<_>>();
+        let mut gpt = model(0, 8, 1);
+        // The padding changes nothing: a short window gives the loss of a model whose
+        // context it fills
+        let dataset = Padded::new(vec![&short]).unwrap();
+        let mut exact = gpt.with_context_length(2).unwrap();
+        let expected = exact.train_step(&dataset, 1, None, 0.).unwrap();
+        let padded = gpt.train_step(&dataset, 1, None, 0.).unwrap();
+        assert!((padded.loss - expected.loss).abs() < 1e-5);
+        assert_eq!(padded.accuracy.tokens, 2);
+
+        let dataset = Padded::new(vec![&short, &long]).unwrap();
+        let first = gpt.train_step(&dataset, 8, None, 0.5).unwrap();
+        for _ in 0..20 {
+            gpt.train_step(&dataset, 8, None, 0.5).unwrap();
+        }
+        let last = gpt.train_step(&dataset, 8, None, 0.5).unwrap();
+        assert!(last.loss < first.loss);
+    }
+
+    #[test]
+    fn test_token_weights() {
+        let dataset = (0..100).map(|i| (i * i) % 7).collect::<Vec<_>>();
+        let mut gpt = model(0, 8, 1);
+        assert!(gpt.set_token_weights(Some(vec![1.; 6])).is_err());
+
+        // Equal weights change nothing, once normalized
+        let mut weighted = model(0, 8, 1);
+        weighted.set_token_weights(Some(vec![2.; 7])).unwrap();
+        let a = gpt.train_step(&dataset, 2, None, 0.1).unwrap();
+        let b = weighted.train_step(&dataset, 2, None, 0.1).unwrap();
+        assert!((a.loss - b.loss).abs() < 1e-5);
+
+        // Only the positions predicting a token with a weight are learned from
+        let mut only_ones = model(0, 8, 1);
+        let mut weights = vec![0.; 7];
+        weights[1] = 1.;
+        only_ones.set_token_weights(Some(weights)).unwrap();
+        let log_prob = |gpt: &GPT<Naive>, target: usize| {
+            let tokens = [&dataset[..12], &[target]].concat();
+            *gpt.token_log_probs(&tokens).unwrap().last().unwrap()
+        };
+        let before = log_prob(&only_ones, 1);
+        for _ in 0..20 {
+            only_ones.train_step(&dataset, 2, None, 0.1).unwrap();
+        }
+        assert!(log_prob(&only_ones, 1) > before);
+    }
+
+    #[test]
+    fn test_distillation() {
+        // A teacher far from uniform, for there to be something to learn
+        let mut teacher = model(1, 8, 2);
+        let mut rng = StdRng::seed_from_u64(0);
+        for id in teacher.params.clone() {
+            let shape = teacher.graph.get_shape(id).unwrap();
+            teacher
+                .graph
+                .load(id, &Tensor::rand_normal(&mut rng, 0., 1., shape));
+        }
+        let teacher = Arc::new(teacher);
+        let dataset = (0..120).map(|i| (i * i + i / 7) % 7).collect::<Vec<_>>();
+        let window = Window {
+            tokens: dataset[..13].to_vec(),
+            source: 0,
+            documents: None,
+        };
+        let kl = |student: &GPT<Naive>| {
+            let soft_targets = teacher.soft_targets(&window, 12).unwrap();
+            let (loss, _) = KlDivergence::new(7, soft_targets, 1.)
+                .run(&student.soft_targets(&window, 12).unwrap())
+                .unwrap();
+            loss.mean()
+        };
+
+        let mut student = model(0, 4, 1);
+        let mut distillation = Distillation::new(teacher.clone());
+        distillation.temperature = 1.;
+        distillation.alpha = 1.;
+        student.set_distillation(Some(distillation.clone()));
+        let before = kl(&student);
+        for _ in 0..30 {
+            student.train_step(&dataset, 4, None, 0.1).unwrap();
+        }
+        assert!(kl(&student) < before * 0.8);
+
+        // Precomputed soft targets are the logits of the teacher
+        let soft_targets = SoftTargets::compute(teacher.as_ref(), &dataset, 12).unwrap();
+        assert_eq!(soft_targets.num_windows(), (120 - 1) / 12);
+        let mut bytes = Vec::new();
+        soft_targets.write(&mut bytes).unwrap();
+        assert!(SoftTargets::is_soft_targets(&bytes));
+        let soft_targets = Arc::new(SoftTargets::read(&bytes[..]).unwrap());
+        for context in [12, 5] {
+            let expected = teacher.soft_targets(&window, context).unwrap();
+            let found = soft_targets.soft_targets(&window, context).unwrap();
+            assert_eq!(found.shape(), expected.shape());
+            for (a, b) in found.blob().iter().zip(expected.blob()) {
+                assert!((a - b).abs() < 1e-5);
+            }
+        }
+        // Windows too short, and contexts longer than the one they were computed at
+        let short = Window {
+            tokens: window.tokens[..4].to_vec(),
+            ..window.clone()
+        };
+        assert!(soft_targets.soft_targets(&short, 5).is_err());
+        let long = Window {
+            tokens: dataset[..20].to_vec(),
+            ..window.clone()
+        };
+        assert!(soft_targets.soft_targets(&long, 16).is_err());
+        distillation.teacher = soft_targets.clone();
+        student.set_distillation(Some(distillation));
+        student
+            .train_step(soft_targets.as_ref(), 4, None, 0.1)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_histograms() {
+        let mut gpt = model(0, 8, 1);
+        let before = gpt.histograms(8).unwrap();
+        assert_eq!(before.len(), gpt.params.len());
+        assert!(before
+            .iter()
+            .all(|h| h.weights.counts.iter().sum::<usize>() > 0));
+        assert!(before
+            .iter()
+            .all(|h| h.grads.max == 0. && h.grads.min == 0.));
+
+        let dataset = (0..100).map(|i| i % 7).collect::<Vec<_>>();
+        gpt.train_step(&dataset, 2, None, 0.001).unwrap();
+        let after = gpt.histograms(8).unwrap();
+        let head = after.iter().find(|h| h.name == "head_map_weights").unwrap();
+        assert!(head.grads.max > head.grads.min);
+        assert_eq!(head.grads.counts.iter().sum::<usize>(), 8 * 7);
     }
 }
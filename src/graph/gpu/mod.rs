@@ -3,6 +3,12 @@ use super::*;
 
 use program::{Brand, Buffer, Device, Program};
 
+/// Functions (By their registered names, see `funcs::registry`) having a kernel of
+/// the same name, used when all their inputs have the shape of their output. Other
+/// functions, including the ones registered by other crates, run on the CPU. The
+/// kernels take the buffers of the inputs, then the scalar parameters of the function.
+const KERNELS: &[&str] = &["add", "scaled_add"];
+
 pub struct GpuTensor {
     mirror: Tensor<f32>, // Mirror of GPU on CPU
     buffer: Buffer<f32>,
@@ -27,6 +33,12 @@ impl GpuGraph {
                     out[id] = a[id] + b[id];
                 }
             }
+            __kernel void scaled_add(__global float* out, uint sz, __global float* a, __global float* b, float coeff) {
+                uint id = get_global_id(0);
+                if(id < sz) {
+                    out[id] = coeff * a[id] + b[id];
+                }
+            }
         "#;
         let program = Program::from_opencl(&device, src)?;
         Ok(Self {
@@ -97,32 +109,79 @@ impl GpuGraph {
         );
         Ok(child)
     }
-    pub fn forward(&mut self, _training: bool) -> Result<(), GraphError> {
-        for (out, c) in self.computations.iter_mut() {
-            let inps = c
-                .inps
-                .iter()
-                .map(|id| self.tensors.get(*id).ok_or(GraphError::TensorNotFound(*id)))
-                .collect::<Result<Vec<_>, GraphError>>()?;
-            let out_tensor = self
-                .tensors
-                .get(*out)
-                .ok_or(GraphError::TensorNotFound(*out))?;
-
-            let works = out_tensor.mirror.size();
-            let local_work_size = 32;
-            let global_work_size =
-                works + ((local_work_size - (works % local_work_size)) % local_work_size);
-            let mut kern = self
-                .program
-                .create_kernel("add", global_work_size, local_work_size);
-            kern = kern.arg(&out_tensor.buffer);
-            kern = kern.arg(works as u32);
-            for inp in inps.iter() {
-                kern = kern.arg(&inp.buffer);
+    /// Replaces the chains of functions whose fused function has a kernel with it (See
+    /// `Graph::fuse`), the others staying as they are. Returns how many chains were.
+    pub fn fuse(&mut self, outputs: &[TensorId]) -> Result<usize, GraphError> {
+        fuse_chains(&mut self.computations, self.tensors.len(), outputs, |op| {
+            KERNELS.contains(&op)
+        })
+    }
+    pub fn forward(&mut self, training: bool) -> Result<(), GraphError> {
+        let outs = self.computations.keys().cloned().collect::<Vec<_>>();
+        for out in outs {
+            let comp = &self.computations[&out];
+            let out_shape = self.get(out)?.mirror.shape();
+            let on_gpu = KERNELS.contains(&comp.func.name())
+                && comp
+                    .inps
+                    .iter()
+                    .all(|id| self.tensors.get(*id).map(|t| t.mirror.shape()) == Some(out_shape));
+            let inps = comp.inps.clone();
+            if on_gpu {
+                self.run_kernel(out, &inps)?;
+            } else {
+                // No kernel for the function: run it on the CPU, on the mirrors
+                for id in inps.iter() {
+                    self.fetch(*id)?;
+                }
+                let tensors = inps
+                    .iter()
+                    .map(|id| {
+                        self.tensors
+                            .get(*id)
+                            .map(|t| &t.mirror)
+                            .ok_or(GraphError::TensorNotFound(*id))
+                    })
+                    .collect::<Result<Vec<_>, GraphError>>()?;
+                let result = self
+                    .computations
+                    .get_mut(&out)
+                    .ok_or(GraphError::TensorNotFound(out))?
+                    .func
+                    .run(&tensors, training)?;
+                self.load(out, &result)?;
             }
-            kern.run()?;
         }
         Ok(())
     }
+    fn run_kernel(&mut self, out: TensorId, inps: &[TensorId]) -> Result<(), GraphError> {
+        let name = self.computations[&out].func.name();
+        let inps = inps
+            .iter()
+            .map(|id| self.tensors.get(*id).ok_or(GraphError::TensorNotFound(*id)))
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        let out_tensor = self
+            .tensors
+            .get(out)
+            .ok_or(GraphError::TensorNotFound(out))?;
+
+        let works = out_tensor.mirror.size();
+        let local_work_size = 32;
+        let global_work_size =
+            works + ((local_work_size - (works % local_work_size)) % local_work_size);
+        let mut kern = self
+            .program
+            .create_kernel(name, global_work_size, local_work_size);
+        kern = kern.arg(&out_tensor.buffer);
+        kern = kern.arg(works as u32);
+        for inp in inps.iter() {
+            kern = kern.arg(&inp.buffer);
+        }
+        for scalar in self.computations[&out].func.params().scalars {
+            kern = kern.arg(scalar);
+        }
+        kern.run()?;
+        self.tensors[out].is_sync = false;
+        Ok(())
+    }
 }
@@ -1,11 +1,21 @@
 #[cfg(feature = "gpu")]
 pub mod gpu;
+mod sparse;
+mod tape;
 
-use crate::funcs::{Function, Loss};
+pub use sparse::SparseGrad;
+pub use tape::{Pass, Tape, TapeInput, TapeStep};
+
+use crate::config::wildcard_match;
+use crate::funcs::registry::{self, OpParams, RegistryError};
+use crate::funcs::{FakeQuant, Function, LinearActivation, Loss, Sharded, Sharding, SparseMatMul};
 use crate::optimizer::Optimizer;
 use crate::tensor::*;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
 use thiserror::Error;
 
 pub type TensorId = usize;
@@ -27,12 +37,180 @@ impl Clone for Computation {
 unsafe impl Send for Computation {}
 unsafe impl Sync for Computation {}
 
+/// How the values and the gradients of the tensors of a graph are stored. They are
+/// computed in f32 either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    F32,
+    /// Stored as f16, which halves the memory they take. The parameters are updated
+    /// in f32, on master copies kept by the graph, and losses should be scaled (See
+    /// `Graph::set_loss_scale`) for small gradients not to underflow: they're stored
+    /// scaled, and only unscaled in f32, by `Graph::optimize`.
+    Mixed,
+}
+
+impl Precision {
+    pub fn bytes_per_value(&self) -> usize {
+        match self {
+            Precision::F32 => std::mem::size_of::<f32>(),
+            Precision::Mixed => std::mem::size_of::<F16>(),
+        }
+    }
+}
+
+impl std::str::FromStr for Precision {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(Precision::F32),
+            "mixed" => Ok(Precision::Mixed),
+            _ => Err(format!("unknown precision '{}' (Expected f32 or mixed)", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Stored {
+    Full(Tensor<f32>),
+    Half(Tensor<F16>),
+}
+
+impl Stored {
+    fn new(t: Tensor<f32>, precision: Precision) -> Self {
+        match precision {
+            Precision::F32 => Stored::Full(t),
+            Precision::Mixed => Stored::Half(t.to_f16()),
+        }
+    }
+    fn get(&self) -> Cow<'_, Tensor<f32>> {
+        match self {
+            Stored::Full(t) => Cow::Borrowed(t),
+            Stored::Half(t) => Cow::Owned(t.to_f32()),
+        }
+    }
+    fn shape(&self) -> &[usize] {
+        match self {
+            Stored::Full(t) => t.shape(),
+            Stored::Half(t) => t.shape(),
+        }
+    }
+    fn bytes(&self) -> usize {
+        match self {
+            Stored::Full(t) => t.size() * std::mem::size_of::<f32>(),
+            Stored::Half(t) => t.size() * std::mem::size_of::<F16>(),
+        }
+    }
+    /// Applies `f` to the value in f32
+    fn update<F: FnOnce(&mut Tensor<f32>) -> Result<(), GraphError>>(
+        &mut self,
+        f: F,
+    ) -> Result<(), GraphError> {
+        match self {
+            Stored::Full(t) => f(t),
+            Stored::Half(t) => {
+                let mut full = t.to_f32();
+                f(&mut full)?;
+                *t = full.to_f16();
+                Ok(())
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Graph {
-    tensors: Vec<Tensor<f32>>,
-    grads: Vec<Tensor<f32>>,
+    tensors: Vec<Stored>,
+    grads: Vec<Stored>,
     names: Vec<String>,
     computations: BTreeMap<TensorId, Computation>,
+    precision: Precision,
+    /// f32 copies of the parameters, in mixed precision
+    masters: BTreeMap<TensorId, Tensor<f32>>,
+    /// Tensors stored in f32 whatever the precision (See `set_full_precision`)
+    full_precision: HashSet<TensorId>,
+    loss_scale: f32,
+    /// Divergences of the functions from f64, when checking the numerics
+    numerics: Option<Numerics>,
+    /// The functions run, when recording them
+    tape: Option<Tape>,
+    /// Whether `alloc_rand` allocates zeros instead (See `placeholders`)
+    placeholders: bool,
+    /// Where the streamed tensors are loaded from (See `stream`)
+    streaming: Option<Streaming>,
+}
+
+/// Where the tensors a graph streams are read from, by name (See `Graph::stream`)
+pub trait TensorSource: Send + Sync {
+    fn contains(&self, name: &str) -> bool;
+    fn load(&self, name: &str) -> Result<Tensor<f32>, GraphError>;
+}
+
+#[derive(Clone)]
+struct Streaming {
+    source: Arc<dyn TensorSource>,
+    /// The last function reading each streamed tensor, by tensor
+    last_uses: BTreeMap<TensorId, TensorId>,
+    /// The streamed tensors currently loaded
+    loaded: HashSet<TensorId>,
+}
+
+/// How far the results of a function strayed from the ones computed in f64 (See
+/// `Graph::set_numerics_check`): the largest relative errors seen, in the norm of the
+/// output and of the gradients of the inputs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// The output of the function (Or the tensor the loss is of)
+    pub id: TensorId,
+    pub op: &'static str,
+    pub forward: f64,
+    pub backward: f64,
+}
+
+impl Divergence {
+    pub fn max(&self) -> f64 {
+        self.forward.max(self.backward)
+    }
+}
+
+type Numerics = BTreeMap<(TensorId, &'static str), Divergence>;
+
+fn record_divergence(
+    numerics: &mut Numerics,
+    id: TensorId,
+    op: &'static str,
+    forward: f64,
+    backward: f64,
+) {
+    let entry = numerics.entry((id, op)).or_insert(Divergence {
+        id,
+        op,
+        forward: 0.,
+        backward: 0.,
+    });
+    entry.forward = entry.forward.max(forward);
+    entry.backward = entry.backward.max(backward);
+}
+
+/// `|actual - expected| / |expected|`, over the finite values of `expected`. (Infinite
+/// if `actual` has NaNs there)
+fn relative_error(actual: &[f32], expected: &[f64]) -> f64 {
+    let (mut diff, mut norm) = (0., 0.);
+    for (a, e) in actual.iter().zip(expected).filter(|(_, e)| e.is_finite()) {
+        diff += (*a as f64 - e).powi(2);
+        norm += e * e;
+    }
+    let err = if norm > 0. {
+        (diff / norm).sqrt()
+    } else {
+        diff.sqrt()
+    };
+    if err.is_nan() {
+        f64::INFINITY
+    } else {
+        err
+    }
 }
 
 #[derive(Error, Debug)]
@@ -41,6 +219,37 @@ pub enum GraphError {
     TensorError(#[from] TensorError),
     #[error("tensor with id {0} not found")]
     TensorNotFound(usize),
+    #[error("thread-pool error: {0}")]
+    ThreadPoolError(String),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("tokenizer error: {0}")]
+    TokenizerError(#[from] crate::tokenizer::TokenizerError),
+    #[error("registry error: {0}")]
+    RegistryError(#[from] RegistryError),
+    #[error("the graph has no gradients (It's for inference only)")]
+    NoGradients,
+    #[error("no tensor named {0} to stream")]
+    MissingStreamedTensor(String),
+    #[error("the parameters of the model are streamed (It's for inference only)")]
+    Streamed,
+    #[error("the loss of a single batch only went down to {loss} in {steps} steps (See `GPT::overfit_sanity_check`)")]
+    OverfitFailed { loss: f32, steps: usize },
+    #[error("the loss isn't finite at step {step}, and there's no earlier state to rewind to")]
+    Diverged { step: usize },
+    #[error(
+        "the tokenizer has hash {found:016x}, not the {expected:016x} the model was trained with"
+    )]
+    TokenizerMismatch { expected: u64, found: u64 },
+    #[error("DP-SGD can't train in a cluster (Its noise would differ on every machine)")]
+    DpSgdCluster,
+    #[error("{op} (Tensor {id}) can't take inputs of shapes {shapes:?}: {source}")]
+    InvalidCall {
+        op: &'static str,
+        id: TensorId,
+        shapes: Vec<Vec<usize>>,
+        source: TensorError,
+    },
 
     #[cfg(feature = "gpu")]
     #[error("gpu error: {0}")]
@@ -54,6 +263,173 @@ impl From<ocl::Error> for GraphError {
     }
 }
 
+fn load_streamed(
+    source: &dyn TensorSource,
+    name: &str,
+    shape: &[usize],
+) -> Result<Tensor<f32>, GraphError> {
+    let t = source.load(name)?;
+    if t.shape() != shape {
+        return Err(TensorError::shape_mismatch("stream", shape, t.shape()).into());
+    }
+    Ok(t)
+}
+
+impl Streaming {
+    fn load_inputs(
+        &mut self,
+        tensors: &mut [Stored],
+        names: &[String],
+        inps: &[TensorId],
+    ) -> Result<(), GraphError> {
+        for id in inps {
+            if self.last_uses.contains_key(id) && self.loaded.insert(*id) {
+                let t = load_streamed(self.source.as_ref(), &names[*id], tensors[*id].shape())?;
+                tensors[*id] = Stored::Full(t);
+            }
+        }
+        Ok(())
+    }
+    fn evict_inputs(&mut self, tensors: &mut [Stored], out: TensorId, inps: &[TensorId]) {
+        for id in inps {
+            if self.last_uses.get(id) == Some(&out) && self.loaded.remove(id) {
+                tensors[*id] = Stored::Full(Tensor::zeros(tensors[*id].shape()));
+            }
+        }
+    }
+}
+
+/// A node of a `GraphSpec`: either a tensor allocated with its initial value, or
+/// the output of a function of previous nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NodeSpec {
+    Tensor {
+        name: String,
+        value: Tensor<f32>,
+    },
+    Call {
+        op: String,
+        params: OpParams,
+        inps: Vec<TensorId>,
+    },
+}
+
+/// The serializable form of a graph, in which functions are referred to by the
+/// names they are registered under (See `funcs::registry`). Nodes are in the
+/// order of their ids.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GraphSpec {
+    pub nodes: Vec<NodeSpec>,
+}
+
+/// What `Graph::simplify` did to the computations of a graph
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimplifyStats {
+    /// Computations of constants, replaced with their values
+    pub folded: usize,
+    /// `Coeff`s merged into the one they fed
+    pub merged: usize,
+    /// Computations none of the outputs depend on
+    pub removed: usize,
+}
+
+fn invalid_call(
+    f: &dyn Function,
+    id: TensorId,
+    inps: &[&Tensor<f32>],
+    e: TensorError,
+) -> GraphError {
+    GraphError::InvalidCall {
+        op: f.name(),
+        id,
+        shapes: inps.iter().map(|t| t.shape().to_vec()).collect(),
+        source: e,
+    }
+}
+
+/// Functions giving a different result at every run, which can't be folded
+const STOCHASTIC: &[&str] = &["dropout"];
+
+/// Replaces the chains of `computations` with the fused function doing the same, when
+/// `available` (Given its registered name, e.g. a backend having a kernel for it): a
+/// `Coeff` feeding an `Add` with a `ScaledAdd`, a `Mask` feeding a `Softmax` with a
+/// `MaskedSoftmax`, and a `MatMul` feeding an `Add`, and possibly an activation, with
+/// a `Linear`. The intermediate tensors of a chain may only be used by the next
+/// function, and not be among `outputs`. Returns how many chains were fused.
+fn fuse_chains<A: Fn(&str) -> bool>(
+    computations: &mut BTreeMap<TensorId, Computation>,
+    num_tensors: usize,
+    outputs: &[TensorId],
+    available: A,
+) -> Result<usize, GraphError> {
+    let mut consumers = vec![0; num_tensors];
+    for c in computations.values() {
+        for i in c.inps.iter() {
+            consumers[*i] += 1;
+        }
+    }
+    let mut count = 0;
+    let ids = computations.keys().copied().collect::<Vec<_>>();
+    for id in ids {
+        let c = &computations[&id];
+        // The intermediate tensor, and the fused computation
+        let mut fused = None;
+        for (i, inp) in c.inps.iter().enumerate() {
+            let prev = match computations.get(inp) {
+                Some(prev) if consumers[*inp] == 1 && !outputs.contains(inp) => prev,
+                _ => continue,
+            };
+            let other = c.inps.get(1 - i.min(1)).copied();
+            let (op, params, inps) = match (prev.func.name(), c.func.name(), other) {
+                ("coeff", "add", Some(other)) => {
+                    ("scaled_add", prev.func.params(), vec![prev.inps[0], other])
+                }
+                ("mask", "softmax", _) => ("masked_softmax", prev.func.params(), prev.inps.clone()),
+                ("matmul", "add", Some(other)) => (
+                    "linear",
+                    OpParams::default(),
+                    vec![prev.inps[0], prev.inps[1], other],
+                ),
+                // A `Linear` without an activation yet
+                ("linear", act, _) if prev.func.params().scalars.is_empty() => {
+                    let Some(index) = LinearActivation::ALL.iter().position(|a| a.name() == act)
+                    else {
+                        continue;
+                    };
+                    (
+                        "linear",
+                        OpParams::scalars(&[index as f32]),
+                        prev.inps.clone(),
+                    )
+                }
+                _ => continue,
+            };
+            if available(op) {
+                fused = Some((
+                    *inp,
+                    Computation {
+                        func: registry::create(op, &params)?,
+                        inps,
+                    },
+                ));
+                break;
+            }
+        }
+        if let Some((inp, fused)) = fused {
+            computations.remove(&inp);
+            computations.insert(id, fused);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+impl Default for Graph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Graph {
     pub fn new() -> Self {
         Self {
@@ -61,19 +437,109 @@ impl Graph {
             grads: Default::default(),
             computations: Default::default(),
             names: Default::default(),
+            precision: Precision::F32,
+            masters: Default::default(),
+            full_precision: Default::default(),
+            loss_scale: 1.,
+            numerics: None,
+            tape: None,
+            placeholders: false,
+            streaming: None,
+        }
+    }
+    /// A graph whose `alloc_rand` allocates zeros instead of drawing random values,
+    /// which take no memory until written: for building models whose parameters are
+    /// loaded, or streamed, afterwards
+    pub fn placeholders() -> Self {
+        Self {
+            placeholders: true,
+            ..Self::new()
+        }
+    }
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+    /// How the value and the gradient of a tensor are stored
+    pub fn precision_of(&self, id: TensorId) -> Precision {
+        match self.full_precision.contains(&id) {
+            true => Precision::F32,
+            false => self.precision,
         }
     }
+    /// Converts all the tensors and gradients to `precision`, but for the ones kept in
+    /// f32 (See `set_full_precision`). In mixed precision, the other `params` keep f32
+    /// master copies, which the optimizer updates.
+    pub fn set_precision(&mut self, precision: Precision, params: &HashSet<TensorId>) {
+        self.precision = precision;
+        for id in 0..self.tensors.len() {
+            let full = self
+                .masters
+                .remove(&id)
+                .unwrap_or_else(|| self.tensors[id].get().into_owned());
+            let precision = self.precision_of(id);
+            if precision == Precision::Mixed && params.contains(&id) {
+                self.masters.insert(id, full.clone());
+            }
+            self.tensors[id] = Stored::new(full, precision);
+            if let Some(g) = self.grads.get(id) {
+                self.grads[id] = Stored::new(g.get().into_owned(), precision);
+            }
+        }
+    }
+    /// Keeps the tensors whose names match one of `patterns` (Where `*` stands for any
+    /// characters and `?` for a single one, e.g. `*norm*`) in f32 whatever the
+    /// precision, for the numerically sensitive ones. Takes effect at the next
+    /// `set_precision`. Returns how many tensors match.
+    pub fn set_full_precision(&mut self, patterns: &[String]) -> usize {
+        self.full_precision = self
+            .names
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| patterns.iter().any(|p| wildcard_match(p, name)))
+            .map(|(id, _)| id)
+            .collect();
+        self.full_precision.len()
+    }
+    /// Names a tensor (E.g. an output, which are unnamed), for `set_full_precision`
+    pub fn set_name(&mut self, id: TensorId, name: String) -> Result<(), GraphError> {
+        *self
+            .names
+            .get_mut(id)
+            .ok_or(GraphError::TensorNotFound(id))? = name;
+        Ok(())
+    }
+    /// The gradient of the loss is multiplied by `scale` before being backpropagated,
+    /// so that the gradients (And the ones loaded by `load_grad`) are divided by it
+    /// when the parameters are updated
+    pub fn set_loss_scale(&mut self, scale: f32) {
+        self.loss_scale = scale;
+    }
+    pub fn loss_scale(&self) -> f32 {
+        self.loss_scale
+    }
     pub fn alloc_rand<R: Rng>(&mut self, rng: &mut R, shape: &[usize], name: String) -> TensorId {
-        self.alloc(Tensor::<f32>::rand(rng, shape), name)
+        let t = match self.placeholders {
+            true => Tensor::zeros(shape),
+            false => Tensor::<f32>::rand(rng, shape),
+        };
+        self.alloc(t, name)
     }
-    fn alloc(&mut self, t: Tensor<f32>, name: String) -> TensorId {
-        self.grads.push(Tensor::zeros(t.shape()));
-        self.tensors.push(t);
+    pub fn alloc(&mut self, t: Tensor<f32>, name: String) -> TensorId {
+        let precision = self.precision_of(self.tensors.len());
+        if self.has_grads() {
+            self.grads
+                .push(Stored::new(Tensor::zeros(t.shape()), precision));
+        }
+        self.tensors.push(Stored::new(t, precision));
         self.names.push(name);
         self.tensors.len() - 1
     }
     pub fn load<T: TensorOps<f32>>(&mut self, tensor_id: TensorId, tensor: &T) {
-        self.tensors[tensor_id] = tensor.view().into();
+        let t: Tensor<f32> = tensor.view().into();
+        if let Some(master) = self.masters.get_mut(&tensor_id) {
+            *master = t.clone();
+        }
+        self.tensors[tensor_id] = Stored::new(t, self.precision_of(tensor_id));
     }
     pub fn embed<T: TensorOps<usize>>(
         &mut self,
@@ -81,55 +547,173 @@ impl Graph {
         embedding_id: TensorId,
         input: &T,
     ) -> Result<(), GraphError> {
-        let embedding = self.get(embedding_id)?;
-        self.load(
-            tensor_id,
-            &input.map(0, |s| Ok(embedding.get(s.scalar()?)?.into()))?,
-        );
+        let embedded = Tensor::raw(input.shape(), input.blob().to_vec())?
+            .gather(self.get(embedding_id)?.as_ref())?;
+        self.load(tensor_id, &embedded);
         Ok(())
     }
     pub fn load_grad<T: TensorOps<f32>>(&mut self, tensor_id: TensorId, tensor: &T) {
-        self.grads[tensor_id] = tensor.view().into();
+        let precision = self.precision_of(tensor_id);
+        if let Some(grad) = self.grads.get_mut(tensor_id) {
+            *grad = Stored::new(tensor.view().into(), precision);
+        }
+    }
+    /// Whether the graph has gradients, i.e. isn't one of `without_grads`
+    pub fn has_grads(&self) -> bool {
+        self.grads.len() == self.tensors.len()
+    }
+    /// A copy of the graph for inference only, without the gradients (Nor the f32
+    /// master copies of mixed precision), which therefore can't be backpropagated
+    /// through. It takes no memory for gradients, even once run.
+    pub fn without_grads(&self) -> Self {
+        let mut graph = Self {
+            tensors: self.tensors.clone(),
+            grads: Vec::new(),
+            names: self.names.clone(),
+            computations: self.computations.clone(),
+            precision: self.precision,
+            masters: Default::default(),
+            full_precision: self.full_precision.clone(),
+            loss_scale: self.loss_scale,
+            numerics: self.numerics.clone(),
+            tape: self.tape.clone(),
+            placeholders: self.placeholders,
+            streaming: self.streaming.clone(),
+        };
+        // The values of the parameters, as the optimizer left them
+        for (id, master) in self.masters.iter() {
+            graph.tensors[*id] = Stored::new(master.clone(), self.precision_of(*id));
+        }
+        graph
     }
     pub fn zero_grad(&mut self) {
-        self.grads.iter_mut().for_each(|t| {
-            t.fill(0.);
+        let precision = self.precision;
+        self.grads.iter_mut().for_each(|t| match t {
+            Stored::Full(t) => t.fill(0.),
+            Stored::Half(_) => *t = Stored::new(Tensor::zeros(t.shape()), precision),
         });
     }
     pub fn add_grad<T: TensorOps<f32>>(&mut self, id: TensorId, add: T) -> Result<(), GraphError> {
-        let shape = self.get(id)?.shape().to_vec();
+        let shape = self.get_shape(id)?.to_vec();
         let grad = self
             .grads
             .get_mut(id)
             .ok_or(GraphError::TensorNotFound(id))?;
-        if add.dim() >= shape.len() {
-            for t in add.keep_right(shape.len())?.inners().iter() {
-                *grad = (&*grad + t)?;
+        grad.update(|grad| {
+            if grad.shape() != shape.as_slice() {
+                // The tensor got a new shape (E.g. a different input) since its gradient
+                // was allocated
+                *grad = (&*grad + &Tensor::zeros(&shape))?;
             }
-        } else {
-            *grad = (&*grad + &add.view())?;
-        }
-        Ok(())
+            if add.dim() >= shape.len() {
+                for t in add.keep_right(shape.len())?.inners().iter() {
+                    grad.add_assign(t)?;
+                }
+            } else {
+                grad.add_assign(&add)?;
+            }
+            Ok(())
+        })
+    }
+    /// Adds a gradient which is zero outside of a few rows, touching only these
+    pub fn add_sparse_grad(&mut self, id: TensorId, add: &SparseGrad) -> Result<(), GraphError> {
+        let grad = self
+            .grads
+            .get_mut(id)
+            .ok_or(GraphError::TensorNotFound(id))?;
+        grad.update(|grad| Ok(add.add_to(grad)?))
+    }
+    /// Bytes taken by the values and the gradients of all the tensors of the graph,
+    /// and by the master copies of the parameters in mixed precision
+    pub fn memory_usage(&self) -> usize {
+        self.tensors
+            .iter()
+            .chain(self.grads.iter())
+            .map(|t| t.bytes())
+            .sum::<usize>()
+            + self
+                .masters
+                .values()
+                .map(|t| t.size() * std::mem::size_of::<f32>())
+                .sum::<usize>()
     }
     pub fn name_of(&self, id: TensorId) -> Result<&String, GraphError> {
         self.names.get(id).ok_or(GraphError::TensorNotFound(id))
     }
-    pub fn get(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError> {
-        self.tensors.get(id).ok_or(GraphError::TensorNotFound(id))
+    /// The value of a tensor (Converted to f32 in mixed precision)
+    pub fn get(&self, id: TensorId) -> Result<Cow<'_, Tensor<f32>>, GraphError> {
+        Ok(self
+            .tensors
+            .get(id)
+            .ok_or(GraphError::TensorNotFound(id))?
+            .get())
+    }
+    pub fn get_shape(&self, id: TensorId) -> Result<&[usize], GraphError> {
+        Ok(self
+            .tensors
+            .get(id)
+            .ok_or(GraphError::TensorNotFound(id))?
+            .shape())
+    }
+    /// The value of a parameter, from its master copy in mixed precision
+    pub fn get_param(&self, id: TensorId) -> Result<Cow<'_, Tensor<f32>>, GraphError> {
+        // Streamed parameters are only held while they're used
+        if let Some(streaming) = &self.streaming {
+            if streaming.last_uses.contains_key(&id) {
+                return Err(GraphError::Streamed);
+            }
+        }
+        match self.masters.get(&id) {
+            Some(master) => Ok(Cow::Borrowed(master)),
+            None => self.get(id),
+        }
     }
-    pub fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError> {
-        self.grads.get(id).ok_or(GraphError::TensorNotFound(id))
+    pub fn get_grad(&self, id: TensorId) -> Result<Cow<'_, Tensor<f32>>, GraphError> {
+        Ok(self
+            .grads
+            .get(id)
+            .ok_or(GraphError::TensorNotFound(id))?
+            .get())
     }
+    /// L2 norm of the gradients of `params`, unscaled (See `set_loss_scale`)
+    pub fn grad_norm(&self, params: &[TensorId]) -> Result<f32, GraphError> {
+        let mut sum = 0.;
+        for id in params {
+            sum += self
+                .get_grad(*id)?
+                .blob()
+                .iter()
+                .map(|f| f * f)
+                .sum::<f32>();
+        }
+        Ok(sum.sqrt() / self.loss_scale)
+    }
+    /// Backpropagates the loss of the output `id`, averaged over its positions (And
+    /// multiplied by the loss scale). Returns the loss.
     pub fn backward_all(
         &mut self,
         id: TensorId,
         loss_fn: Box<dyn Loss>,
         limit: Option<usize>,
     ) -> Result<f32, GraphError> {
-        let output = self.get(id)?;
-        let (loss, grad) = loss_fn.run(output)?;
-        let mean_coeff = 1. / loss.size() as f32;
-        self.add_grad(id, (&grad * &Tensor::scalar(mean_coeff))?)?;
+        let losses = self.backward_heads(vec![(id, loss_fn, 1.)], limit)?;
+        Ok(losses[0])
+    }
+    /// Backpropagates the sum of the losses of several outputs, each averaged over its
+    /// positions and multiplied by its weight (E.g. the next-token loss, and the loss
+    /// of an auxiliary head), in a single pass. Returns the losses, unweighted.
+    pub fn backward_heads(
+        &mut self,
+        heads: Vec<(TensorId, Box<dyn Loss>, f32)>,
+        limit: Option<usize>,
+    ) -> Result<Vec<f32>, GraphError> {
+        if !self.has_grads() {
+            return Err(GraphError::NoGradients);
+        }
+        let mut losses = Vec::with_capacity(heads.len());
+        for (id, loss_fn, weight) in heads {
+            losses.push(self.seed_loss(id, loss_fn.as_ref(), weight)?);
+        }
 
         for (i, (id, comp)) in self.computations.clone().iter().rev().enumerate() {
             if let Some(limit) = limit {
@@ -140,29 +724,214 @@ impl Graph {
             let inps = comp
                 .inps
                 .iter()
-                .map(|id| &self.tensors[*id])
+                .map(|id| self.tensors[*id].get())
                 .collect::<Vec<_>>();
-            let grad_out = &self.grads[*id];
-            let grads = comp.func.grad(&inps, grad_out)?;
-            for (id, grad) in comp.inps.clone().into_iter().zip(grads.into_iter()) {
+            let inps = inps.iter().map(|t| t.as_ref()).collect::<Vec<_>>();
+            let grad_out = self.grads[*id].get();
+            let grads = comp.func.grad(&inps, &grad_out)?;
+            if let Some(expected) = self
+                .numerics
+                .as_ref()
+                .and(comp.func.grad_f64(&inps, &grad_out))
+            {
+                let backward = grads
+                    .iter()
+                    .zip(expected.iter())
+                    .filter(|(g, e)| g.size() == e.len())
+                    .map(|(g, e)| relative_error(g.blob(), e))
+                    .fold(0., f64::max);
+                if let Some(numerics) = self.numerics.as_mut() {
+                    record_divergence(numerics, *id, comp.func.name(), 0., backward);
+                }
+            }
+            if let Some(tape) = self.tape.as_mut() {
+                tape.steps.push(TapeStep {
+                    pass: Pass::Backward,
+                    op: comp.func.name(),
+                    id: *id,
+                    inputs: comp
+                        .inps
+                        .iter()
+                        .zip(inps.iter())
+                        .map(|(i, t)| tape::input(&self.names, *i, t))
+                        .collect(),
+                    output_shape: grad_out.shape().to_vec(),
+                    norm: tape::norm(&grad_out),
+                    grad_norms: grads.iter().map(tape::norm).collect(),
+                });
+            }
+            for (id, grad) in comp.inps.clone().into_iter().zip(grads) {
                 self.add_grad(id, grad)?;
             }
         }
 
+        Ok(losses)
+    }
+    /// Adds the gradient of the loss of the output `id`, averaged over its positions
+    /// and multiplied by `weight` (And the loss scale), to the one of `id`. Returns the
+    /// loss.
+    fn seed_loss(
+        &mut self,
+        id: TensorId,
+        loss_fn: &dyn Loss,
+        weight: f32,
+    ) -> Result<f32, GraphError> {
+        let output = self.get(id)?;
+        let (loss, grad) = loss_fn.run(&output)?;
+        let input = self
+            .tape
+            .is_some()
+            .then(|| tape::input(&self.names, id, &output));
+        if let Some(expected) = self.numerics.as_ref().and(loss_fn.run_f64(&output)) {
+            let forward = relative_error(loss.blob(), &expected.0);
+            let backward = relative_error(grad.blob(), &expected.1);
+            if let Some(numerics) = self.numerics.as_mut() {
+                record_divergence(numerics, id, loss_fn.name(), forward, backward);
+            }
+        }
+        let mean_coeff = weight * self.loss_scale / loss.size() as f32;
+        let grad = (&grad * &Tensor::scalar(mean_coeff))?;
+        if let (Some(tape), Some(input)) = (self.tape.as_mut(), input) {
+            tape.steps.push(TapeStep {
+                pass: Pass::Loss,
+                op: loss_fn.name(),
+                id,
+                inputs: vec![input],
+                output_shape: loss.shape().to_vec(),
+                norm: loss.mean(),
+                grad_norms: vec![tape::norm(&grad)],
+            });
+        }
+        self.add_grad(id, grad)?;
         Ok(loss.mean())
     }
     pub fn forward(&mut self, training: bool) -> Result<(), GraphError> {
         for (out, c) in self.computations.iter_mut() {
+            if let Some(streaming) = self.streaming.as_mut() {
+                streaming.load_inputs(&mut self.tensors, &self.names, &c.inps)?;
+            }
             let tensors = c
                 .inps
                 .iter()
-                .map(|id| self.tensors.get(*id).ok_or(GraphError::TensorNotFound(*id)))
+                .map(|id| {
+                    self.tensors
+                        .get(*id)
+                        .map(|t| t.get())
+                        .ok_or(GraphError::TensorNotFound(*id))
+                })
                 .collect::<Result<Vec<_>, GraphError>>()?;
-            let result = c.func.run(&tensors, training)?;
-            self.tensors[*out] = result;
+            let tensors = tensors.iter().map(|t| t.as_ref()).collect::<Vec<_>>();
+            let result = c
+                .func
+                .run(&tensors, training)
+                .map_err(|e| invalid_call(c.func.as_ref(), *out, &tensors, e))?;
+            if let Some(numerics) = self.numerics.as_mut() {
+                if let Some(expected) = c.func.run_f64(&tensors) {
+                    let forward = relative_error(result.blob(), &expected);
+                    record_divergence(numerics, *out, c.func.name(), forward, 0.);
+                }
+            }
+            if let Some(tape) = self.tape.as_mut() {
+                tape.steps.push(TapeStep {
+                    pass: Pass::Forward,
+                    op: c.func.name(),
+                    id: *out,
+                    inputs: c
+                        .inps
+                        .iter()
+                        .zip(tensors.iter())
+                        .map(|(i, t)| tape::input(&self.names, *i, t))
+                        .collect(),
+                    output_shape: result.shape().to_vec(),
+                    norm: tape::norm(&result),
+                    grad_norms: Vec::new(),
+                });
+            }
+            let precision = match self.full_precision.contains(out) {
+                true => Precision::F32,
+                false => self.precision,
+            };
+            self.tensors[*out] = Stored::new(result, precision);
+            if let Some(streaming) = self.streaming.as_mut() {
+                streaming.evict_inputs(&mut self.tensors, *out, &c.inps);
+            }
+        }
+        Ok(())
+    }
+    /// Reads the tensors `ids` off `source` (By name) only when running the functions
+    /// that take them, one function after the other, each being emptied again once the
+    /// last of them ran: the graph only ever holds the ones of the function being run.
+    /// (Those that no function takes, e.g. embeddings, are read once and for all.)
+    /// For running models larger than the memory, slowly, for inference only: the
+    /// tensors read aren't kept, so training them would be lost.
+    pub fn stream(
+        &mut self,
+        source: Arc<dyn TensorSource>,
+        ids: &[TensorId],
+    ) -> Result<(), GraphError> {
+        let mut last_uses = BTreeMap::new();
+        for (out, c) in self.computations.iter() {
+            for id in c.inps.iter().filter(|id| ids.contains(id)) {
+                last_uses.insert(*id, *out);
+            }
         }
+        for id in ids {
+            let name = self.names.get(*id).ok_or(GraphError::TensorNotFound(*id))?;
+            if !source.contains(name) {
+                return Err(GraphError::MissingStreamedTensor(name.clone()));
+            }
+        }
+        for id in ids {
+            let name = &self.names[*id];
+            let t = match last_uses.contains_key(id) {
+                // Zeros take no memory until written
+                true => Tensor::zeros(self.tensors[*id].shape()),
+                false => load_streamed(source.as_ref(), name, self.tensors[*id].shape())?,
+            };
+            self.tensors[*id] = Stored::Full(t);
+        }
+        self.streaming = Some(Streaming {
+            source,
+            last_uses,
+            loaded: HashSet::new(),
+        });
         Ok(())
     }
+    /// Whether some tensors are streamed (See `stream`)
+    pub fn is_streamed(&self) -> bool {
+        self.streaming.is_some()
+    }
+    /// Also runs the functions (And losses) that have an f64 implementation in f64, at
+    /// every forward and backward pass, keeping track of how far the f32 results stray
+    /// from them (See `divergences`). Slow: it's for tracking down the functions that
+    /// lose too much precision.
+    pub fn set_numerics_check(&mut self, enabled: bool) {
+        self.numerics = enabled.then(BTreeMap::new);
+    }
+    /// Records the functions run by the next passes, forward and backward, in a `Tape`
+    /// (See `tape`), for seeing how backpropagation goes. Disabling it drops the tape.
+    pub fn set_tape(&mut self, enabled: bool) {
+        self.tape = enabled.then(Tape::default);
+    }
+    /// The functions run since `set_tape`
+    pub fn tape(&self) -> Option<&Tape> {
+        self.tape.as_ref()
+    }
+    /// The functions checked since `set_numerics_check`, the most divergent first
+    pub fn divergences(&self) -> Vec<Divergence> {
+        let mut divergences = self
+            .numerics
+            .iter()
+            .flat_map(|n| n.values().cloned())
+            .collect::<Vec<_>>();
+        divergences.sort_by(|a, b| b.max().total_cmp(&a.max()));
+        divergences
+    }
+    /// Runs the graph for inference: stochastic functions (E.g. dropout) are off,
+    /// whatever they were run with before, and no gradient is touched
+    pub fn forward_inference(&mut self) -> Result<(), GraphError> {
+        self.forward(false)
+    }
     pub fn call(
         &mut self,
         mut f: Box<dyn Function>,
@@ -172,7 +941,12 @@ impl Graph {
             .iter()
             .map(|id| self.get(*id))
             .collect::<Result<Vec<_>, GraphError>>()?;
-        let out = f.run(&tensors, false)?;
+        let tensors = tensors.iter().map(|t| t.as_ref()).collect::<Vec<_>>();
+        // Functions run once as they're added, so that shape mismatches are caught when
+        // the graph is built, instead of at its first forward pass
+        let out = f
+            .run(&tensors, false)
+            .map_err(|e| invalid_call(f.as_ref(), self.tensors.len(), &tensors, e))?;
         let child = self.alloc(out, "".into());
         self.computations.insert(
             child,
@@ -183,25 +957,573 @@ impl Graph {
         );
         Ok(child)
     }
+    /// Quantizes the outputs of the functions registered as one of `ops` to `bits` bits
+    /// and back, for quantization-aware training (See `FakeQuant`). Returns how many
+    /// functions were. (Calling it twice quantizes them twice)
+    pub fn fake_quantize(&mut self, ops: &[String], bits: u32) -> usize {
+        let mut count = 0;
+        for c in self.computations.values_mut() {
+            if ops.iter().any(|op| op == c.func.name()) {
+                c.func = FakeQuant::wrap(c.func.clone_box(), bits);
+                count += 1;
+            }
+        }
+        count
+    }
+    /// Splits the matrix products by the tensors whose names match one of `columns`
+    /// (Wildcards as in `set_full_precision`) column-wise over `shards` threads, and
+    /// those by the ones matching `rows` row-wise (See `Sharded`). Returns how many
+    /// products were. (To be run before `fake_quantize`, whose quantization the
+    /// sharded products would skip)
+    pub fn shard_matmuls(&mut self, columns: &[String], rows: &[String], shards: usize) -> usize {
+        let mut count = 0;
+        for c in self.computations.values_mut() {
+            if c.func.name() != "matmul" {
+                continue;
+            }
+            let Some(name) = c.inps.get(1).and_then(|id| self.names.get(*id)) else {
+                continue;
+            };
+            let sharding = if columns.iter().any(|p| wildcard_match(p, name)) {
+                Sharding::Columns
+            } else if rows.iter().any(|p| wildcard_match(p, name)) {
+                Sharding::Rows
+            } else {
+                continue;
+            };
+            c.func = Sharded::wrap(c.func.clone_box(), sharding, shards);
+            count += 1;
+        }
+        count
+    }
+    /// Replaces the matrix products by the tensors whose names match one of `weights`
+    /// (Wildcards as in `set_full_precision`) with `SparseMatMul`, which skips their
+    /// zeros. Returns how many products were.
+    pub fn sparsify_matmuls(&mut self, weights: &[String]) -> usize {
+        let mut count = 0;
+        for c in self.computations.values_mut() {
+            let Some(name) = c.inps.get(1).and_then(|id| self.names.get(*id)) else {
+                continue;
+            };
+            if c.func.name() == "matmul" && weights.iter().any(|p| wildcard_match(p, name)) {
+                c.func = SparseMatMul::new();
+                count += 1;
+            }
+        }
+        count
+    }
+    /// Shrinks the computations run at every step, without changing the values of
+    /// `outputs`: computations whose inputs are all constants (Tensors not among
+    /// `variables`, which are the inputs and parameters, nor computed from them) are
+    /// folded into their values, chains of `Coeff`s are merged into one, and
+    /// computations none of `outputs` depend on are removed. The ids of the tensors
+    /// stay the same. (To be run before `fake_quantize`, whose wrappers would be merged
+    /// away)
+    pub fn simplify(
+        &mut self,
+        variables: &HashSet<TensorId>,
+        outputs: &[TensorId],
+    ) -> Result<SimplifyStats, GraphError> {
+        let mut stats = SimplifyStats::default();
+
+        let ids = self.computations.keys().copied().collect::<Vec<_>>();
+        let mut varying = variables.clone();
+        for id in ids.iter() {
+            let c = &self.computations[id];
+            if c.inps.iter().any(|i| varying.contains(i))
+                || STOCHASTIC.contains(&c.func.name())
+                || variables.contains(id)
+            {
+                varying.insert(*id);
+                continue;
+            }
+            let mut c = self.computations.remove(id).unwrap();
+            let inps = c
+                .inps
+                .iter()
+                .map(|i| self.get(*i).map(|t| t.into_owned()))
+                .collect::<Result<Vec<_>, GraphError>>()?;
+            let value = c.func.run(&inps.iter().collect::<Vec<_>>(), false)?;
+            self.tensors[*id] = Stored::new(value, self.precision_of(*id));
+            stats.folded += 1;
+        }
+
+        let mut consumers = vec![0; self.tensors.len()];
+        for c in self.computations.values() {
+            for i in c.inps.iter() {
+                consumers[*i] += 1;
+            }
+        }
+        let ids = self.computations.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            let c = &self.computations[&id];
+            if c.func.name() != "coeff" {
+                continue;
+            }
+            let inp = c.inps[0];
+            let merged = match self.computations.get(&inp) {
+                Some(prev)
+                    if prev.func.name() == "coeff"
+                        && consumers[inp] == 1
+                        && !outputs.contains(&inp) =>
+                {
+                    let coeff = c.func.params().scalar(0)? * prev.func.params().scalar(0)?;
+                    Computation {
+                        func: registry::create("coeff", &OpParams::scalars(&[coeff]))?,
+                        inps: prev.inps.clone(),
+                    }
+                }
+                _ => continue,
+            };
+            consumers[inp] -= 1;
+            consumers[merged.inps[0]] += 1;
+            self.computations.insert(id, merged);
+            stats.merged += 1;
+        }
+
+        let mut needed = outputs.iter().copied().collect::<HashSet<_>>();
+        for (id, c) in self.computations.iter().rev() {
+            if needed.contains(id) {
+                needed.extend(c.inps.iter().copied());
+            }
+        }
+        let before = self.computations.len();
+        self.computations.retain(|id, _| needed.contains(id));
+        stats.removed = before - self.computations.len();
+
+        Ok(stats)
+    }
+    /// Replaces the chains of functions that have a fused implementation with it
+    /// (See `fuse_chains`), without changing the values of `outputs`, nor the ids of
+    /// the tensors. Returns how many chains were. (To be run after `simplify`, whose
+    /// `Coeff`s it would no longer merge, and before `shard_matmuls` and
+    /// `fake_quantize`, whose wrappers would be fused away)
+    pub fn fuse(&mut self, outputs: &[TensorId]) -> Result<usize, GraphError> {
+        // All the fused functions run on the CPU
+        fuse_chains(&mut self.computations, self.tensors.len(), outputs, |_| {
+            true
+        })
+    }
+    pub fn spec(&self) -> GraphSpec {
+        let nodes = (0..self.tensors.len())
+            .map(|id| match self.computations.get(&id) {
+                Some(c) => NodeSpec::Call {
+                    op: c.func.name().into(),
+                    params: c.func.params(),
+                    inps: c.inps.clone(),
+                },
+                None => NodeSpec::Tensor {
+                    name: self.names[id].clone(),
+                    value: self.tensors[id].get().into_owned(),
+                },
+            })
+            .collect();
+        GraphSpec { nodes }
+    }
+    /// Rebuilds a graph from its spec. All of its functions have to be registered.
+    pub fn from_spec(spec: &GraphSpec) -> Result<Self, GraphError> {
+        let mut g = Graph::new();
+        for node in spec.nodes.iter() {
+            match node {
+                NodeSpec::Tensor { name, value } => {
+                    g.alloc(value.clone(), name.clone());
+                }
+                NodeSpec::Call { op, params, inps } => {
+                    g.call(registry::create(op, params)?, inps)?;
+                }
+            }
+        }
+        Ok(g)
+    }
+    /// The graph in the DOT language of Graphviz: tensors are ellipses labeled with
+    /// their names and shapes, functions are boxes. Functions missing from the
+    /// registry (So that the graph can't be rebuilt from its spec) are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph {\n");
+        for (id, t) in self.tensors.iter().enumerate() {
+            dot.push_str(&format!(
+                "  t{} [label=\"{} {:?}\"];\n",
+                id,
+                self.names[id].escape_default(),
+                t.shape()
+            ));
+        }
+        for (out, c) in self.computations.iter() {
+            let name = c.func.name();
+            let color = if registry::is_registered(name) {
+                ""
+            } else {
+                ", color=red"
+            };
+            dot.push_str(&format!(
+                "  f{} [label=\"{}\", shape=box{}];\n",
+                out,
+                name.escape_default(),
+                color
+            ));
+            for inp in c.inps.iter() {
+                dot.push_str(&format!("  t{} -> f{};\n", inp, out));
+            }
+            dot.push_str(&format!("  f{} -> t{};\n", out, out));
+        }
+        dot.push_str("}\n");
+        dot
+    }
     pub fn optimize<O: Optimizer>(
         &mut self,
         opt: &mut O,
         params: &HashSet<TensorId>,
         learning_rate: f32,
     ) -> Result<(), GraphError> {
-        let (params, grads): (Vec<&mut Tensor<f32>>, Vec<&Tensor<f32>>) = self
-            .tensors
-            .iter_mut()
+        if !self.has_grads() {
+            return Err(GraphError::NoGradients);
+        }
+        let grads = self
+            .grads
+            .iter()
             .enumerate()
             .filter(|(id, _)| params.contains(id))
-            .map(|(id, params)| {
-                let grad = self.grads.get(id).ok_or(GraphError::TensorNotFound(id))?;
-                Ok((params, grad))
+            .map(|(_, g)| match self.loss_scale {
+                1. => g.get(),
+                scale => Cow::Owned(g.get().map_values(|f| f / scale)),
             })
-            .collect::<Result<Vec<_>, GraphError>>()?
-            .into_iter()
-            .unzip();
-        opt.step(params, grads, learning_rate)?;
+            .collect::<Vec<_>>();
+        let grads = grads.iter().map(|g| g.as_ref()).collect::<Vec<_>>();
+        match self.precision {
+            Precision::F32 => {
+                let params = self
+                    .tensors
+                    .iter_mut()
+                    .enumerate()
+                    .filter(|(id, _)| params.contains(id))
+                    .map(|(_, t)| match t {
+                        Stored::Full(t) => t,
+                        Stored::Half(_) => unreachable!("Tensors are stored in f32"),
+                    })
+                    .collect::<Vec<_>>();
+                opt.step(params, grads, learning_rate)?;
+            }
+            Precision::Mixed => {
+                for id in params.iter() {
+                    let half = matches!(self.tensors.get(*id), Some(Stored::Half(_)));
+                    if half && !self.masters.contains_key(id) {
+                        let master = self.get(*id)?.into_owned();
+                        self.masters.insert(*id, master);
+                    }
+                }
+                // The ones kept in f32 are updated in place, the others on their masters
+                let mut targets = self
+                    .masters
+                    .iter_mut()
+                    .filter(|(id, _)| params.contains(id))
+                    .map(|(id, t)| (*id, t))
+                    .collect::<Vec<_>>();
+                targets.extend(
+                    self.tensors
+                        .iter_mut()
+                        .enumerate()
+                        .filter_map(|(id, t)| match t {
+                            Stored::Full(t) if params.contains(&id) => Some((id, t)),
+                            _ => None,
+                        }),
+                );
+                targets.sort_by_key(|(id, _)| *id);
+                let targets = targets.into_iter().map(|(_, t)| t).collect::<Vec<_>>();
+                opt.step(targets, grads, learning_rate)?;
+                for (id, master) in self.masters.iter() {
+                    if params.contains(id) {
+                        self.tensors[*id] = Stored::new(master.clone(), Precision::Mixed);
+                    }
+                }
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::*;
+    use crate::optimizer::Naive;
+
+    #[test]
+    fn test_spec_roundtrip() {
+        let mut g = Graph::new();
+        let a = g.alloc(
+            Tensor::raw(&[2, 2], vec![1., -2., 3., 4.]).unwrap(),
+            "a".into(),
+        );
+        let b = g.alloc(Tensor::raw(&[2], vec![0.5, 1.]).unwrap(), "b".into());
+        let ab = g.call(MatMul::new(), &[a, a]).unwrap();
+        let scaled = g.call(Coeff::new(0.5), &[ab]).unwrap();
+        let mask = Tensor::raw(&[2], vec![false, true]).unwrap();
+        let masked = g
+            .call(Mask::new(mask, f32::NEG_INFINITY), &[scaled])
+            .unwrap();
+        let out = g.call(Add::new(), &[masked, b]).unwrap();
+
+        let spec = bincode::serialize(&g.spec()).unwrap();
+        let mut h = Graph::from_spec(&bincode::deserialize(&spec).unwrap()).unwrap();
+        h.forward(false).unwrap();
+        assert_eq!(h.name_of(b).unwrap(), "b");
+        assert_eq!(h.get(out).unwrap().blob(), g.get(out).unwrap().blob());
+
+        let dot = g.to_dot();
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains("f4 [label=\"mask\", shape=box];"));
+        assert!(dot.contains("t3 -> f4;"));
+
+        let mut spec = g.spec();
+        spec.nodes.push(NodeSpec::Call {
+            op: "unknown".into(),
+            params: OpParams::default(),
+            inps: vec![out],
+        });
+        assert!(matches!(
+            Graph::from_spec(&spec),
+            Err(GraphError::RegistryError(RegistryError::UnknownOp(_)))
+        ));
+    }
+
+    #[test]
+    fn test_simplify() {
+        let mut g = Graph::new();
+        let x = g.alloc(Tensor::vector(&[1., 2.]), "x".into());
+        let c = g.alloc(Tensor::vector(&[3., 4.]), "c".into());
+        let c2 = g.call(Coeff::new(2.), &[c]).unwrap();
+        let c3 = g.call(Relu::new(), &[c2]).unwrap();
+        let half = g.call(Coeff::new(0.5), &[x]).unwrap();
+        let quarter = g.call(Coeff::new(0.5), &[half]).unwrap();
+        let eighth = g.call(Coeff::new(0.5), &[quarter]).unwrap();
+        let out = g.call(Add::new(), &[eighth, c3]).unwrap();
+        let unused = g.call(Coeff::new(3.), &[x]).unwrap();
+        g.load(x, &Tensor::vector(&[8., 16.]));
+        g.forward(false).unwrap();
+        let expected = g.get(out).unwrap().into_owned();
+
+        let stats = g.simplify(&HashSet::from([x]), &[out]).unwrap();
+        assert_eq!(
+            stats,
+            SimplifyStats {
+                folded: 2,
+                merged: 2,
+                removed: 3,
+            }
+        );
+        assert_eq!(g.computations.len(), 2);
+        assert!(!g.computations.contains_key(&unused));
+        g.forward(false).unwrap();
+        assert_eq!(g.get(out).unwrap().blob(), expected.blob());
+        assert_eq!(expected.blob(), &[7., 10.]);
+    }
+
+    #[test]
+    fn test_fuse() {
+        let mut g = Graph::new();
+        let x = g.alloc(
+            Tensor::raw(&[2, 2], vec![1., -2., 3., 0.5]).unwrap(),
+            "x".into(),
+        );
+        let y = g.alloc(
+            Tensor::raw(&[2, 2], vec![0.5, 1., -1., 2.]).unwrap(),
+            "y".into(),
+        );
+        let scaled = g.call(Coeff::new(2.), &[x]).unwrap();
+        let sum = g.call(Add::new(), &[y, scaled]).unwrap();
+        let masked = g
+            .call(Mask::new(!&Tensor::<bool>::tril(2), -1.), &[sum])
+            .unwrap();
+        let soft = g.call(Softmax::new(), &[masked]).unwrap();
+        // Kept, as an output
+        let product = g.call(MatMul::new(), &[soft, y]).unwrap();
+        let biased = g.call(Add::new(), &[product, x]).unwrap();
+        let out = g.call(Gelu::new(), &[biased]).unwrap();
+        g.forward(false).unwrap();
+        let expected = g.get(out).unwrap().into_owned();
+
+        assert_eq!(g.fuse(&[out, product]).unwrap(), 2);
+        assert_eq!(g.computations[&sum].func.name(), "scaled_add");
+        assert_eq!(g.computations[&soft].func.name(), "masked_softmax");
+        assert!(!g.computations.contains_key(&scaled));
+        assert!(!g.computations.contains_key(&masked));
+        assert_eq!(g.computations[&biased].func.name(), "add");
+        g.forward(false).unwrap();
+        assert_close(g.get(out).unwrap().as_ref(), &expected, 1e-6, 1e-6);
+
+        // Unless it isn't
+        assert_eq!(g.fuse(&[out]).unwrap(), 2);
+        assert_eq!(g.computations[&out].func.name(), "linear");
+        g.forward(false).unwrap();
+        assert_close(g.get(out).unwrap().as_ref(), &expected, 1e-6, 1e-6);
+    }
+
+    #[test]
+    fn test_invalid_call() {
+        let mut g = Graph::new();
+        let a = g.alloc(Tensor::zeros(&[2, 3]), "a".into());
+        let b = g.alloc(Tensor::zeros(&[2, 3]), "b".into());
+        let err = g.call(MatMul::new(), &[a, b]).unwrap_err();
+        assert!(matches!(
+            &err,
+            GraphError::InvalidCall { op: "matmul", id: 2, shapes, .. }
+                if shapes == &[vec![2, 3], vec![2, 3]]
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("matmul (Tensor 2) can't take inputs"));
+
+        // Or when an input gets loaded with another shape
+        let sum = g.call(Add::new(), &[a, b]).unwrap();
+        g.load(b, &Tensor::zeros(&[4]));
+        assert!(matches!(
+            g.forward(false),
+            Err(GraphError::InvalidCall { op: "add", id, .. }) if id == sum
+        ));
+    }
+
+    #[test]
+    fn test_backward_heads() {
+        let mut g = Graph::new();
+        let x = g.alloc(
+            Tensor::raw(&[2, 3], vec![0.5, -1., 2., 0., 1., -0.5]).unwrap(),
+            "x".into(),
+        );
+        let a = g.call(Tanh::new(), &[x]).unwrap();
+        let b = g.call(Coeff::new(3.), &[x]).unwrap();
+        g.forward(true).unwrap();
+        let targets = || Tensor::raw(&[2], vec![2, 0]).unwrap();
+        let grad_of = |g: &mut Graph, heads: Vec<(TensorId, Box<dyn Loss>, f32)>| {
+            g.zero_grad();
+            let losses = g.backward_heads(heads, None).unwrap();
+            (losses, g.get_grad(x).unwrap().into_owned())
+        };
+        let (a_loss, a_grad) = grad_of(&mut g, vec![(a, CrossEntropy::new(3, targets()), 1.)]);
+        let (b_loss, b_grad) = grad_of(&mut g, vec![(b, CrossEntropy::new(3, targets()), 1.)]);
+        let (losses, grad) = grad_of(
+            &mut g,
+            vec![
+                (a, CrossEntropy::new(3, targets()), 1.),
+                (b, CrossEntropy::new(3, targets()), 0.5),
+            ],
+        );
+        assert_eq!(losses, [a_loss[0], b_loss[0]]);
+        let expected = (&a_grad + &b_grad.map_values(|g| g * 0.5)).unwrap();
+        assert_close(&grad, &expected, 1e-6, 1e-6);
+    }
+
+    #[test]
+    fn test_numerics_check() {
+        let mut g = Graph::new();
+        let x = g.alloc(Tensor::vector(&[1e4, 1e4 + 1., 0.]), "x".into());
+        let soft = g.call(Softmax::new(), &[x]).unwrap();
+        let out = g.call(Tanh::new(), &[soft]).unwrap();
+        g.set_numerics_check(true);
+        g.forward(false).unwrap();
+        let target = Tensor::raw(&[1], vec![1]).unwrap();
+        g.backward_all(out, CrossEntropy::new(3, target), None)
+            .unwrap();
+        let divergences = g.divergences();
+        assert_eq!(divergences.len(), 3);
+        assert!(divergences.iter().all(|d| d.max() < 1e-5));
+        assert_eq!(divergences.iter().find(|d| d.op == "tanh").unwrap().id, out);
+        g.set_numerics_check(false);
+        assert!(g.divergences().is_empty());
+    }
+
+    #[test]
+    fn test_without_grads() {
+        let mut g = Graph::new();
+        let x = g.alloc(Tensor::raw(&[4], vec![1., 2., 3., 4.]).unwrap(), "x".into());
+        let y = g.call(Dropout::new(0.5), &[x]).unwrap();
+        let z = g.call(Coeff::new(2.), &[y]).unwrap();
+        g.forward(true).unwrap();
+
+        let mut h = g.without_grads();
+        assert!(!h.has_grads() && g.has_grads());
+        assert_eq!(h.memory_usage() * 2, g.memory_usage());
+        h.forward_inference().unwrap();
+        assert_eq!(h.get(z).unwrap().blob(), &[2., 4., 6., 8.]);
+        assert!(matches!(
+            h.backward_all(z, CrossEntropy::new(4, Tensor::vector(&[0])), None),
+            Err(GraphError::NoGradients)
+        ));
+        assert!(matches!(h.get_grad(x), Err(GraphError::TensorNotFound(_))));
+        // Tensors allocated later get no gradient either
+        let w = h.alloc(Tensor::scalar(1.), "w".into());
+        assert!(!h.has_grads());
+        assert!(h.optimize(&mut Naive::new(), &[w].into(), 0.1).is_err());
+    }
+
+    #[test]
+    fn test_mixed_precision() {
+        let mut g = Graph::new();
+        let w = g.alloc(Tensor::raw(&[2], vec![1., 0.3]).unwrap(), "w".into());
+        let x = g.alloc(Tensor::raw(&[2], vec![2., -1.]).unwrap(), "x".into());
+        let y = g.call(Mul::new(), &[w, x]).unwrap();
+        let full = g.memory_usage();
+        let params = [w].into_iter().collect::<HashSet<_>>();
+        g.set_precision(Precision::Mixed, &params);
+        // Halved, but for the f32 master copy of `w`
+        assert_eq!(g.memory_usage(), full / 2 + 2 * 4);
+        g.forward(false).unwrap();
+        assert_close(&*g.get(y).unwrap(), &Tensor::vector(&[2., -0.3]), 1e-3, 0.);
+
+        // Updates below the resolution of f16 accumulate in the master copy, the
+        // gradients being loaded scaled like the loss
+        g.set_loss_scale(8.);
+        g.backward_all(y, CrossEntropy::new(2, Tensor::vector(&[0])), None)
+            .unwrap();
+        let grad = g.get_grad(y).unwrap().into_owned();
+        let (_, expected) = CrossEntropy::new(2, Tensor::vector(&[0]))
+            .run(&g.get(y).unwrap())
+            .unwrap();
+        assert_close(&grad, &(&expected * &Tensor::scalar(8.)).unwrap(), 1e-2, 0.);
+        for _ in 0..10 {
+            g.load_grad(w, &Tensor::vector(&[8e-5, 0.]));
+            g.optimize(&mut Naive::new(), &params, 1.).unwrap();
+        }
+        assert_eq!(g.get(w).unwrap().blob()[0], 1.);
+        assert!((g.get_param(w).unwrap().blob()[0] - 0.9999).abs() < 1e-6);
+        // Gradients below the range of f16 too
+        g.set_loss_scale(65536.);
+        g.load_grad(w, &Tensor::vector(&[1e-8 * 65536., 0.]));
+        assert!((g.grad_norm(&[w]).unwrap() - 1e-8).abs() < 1e-11);
+        g.optimize(&mut Naive::new(), &params, 1e3).unwrap();
+        assert!((g.get_param(w).unwrap().blob()[0] - 0.99989).abs() < 1e-6);
+
+        g.set_precision(Precision::F32, &params);
+        assert_eq!(g.memory_usage(), full);
+        assert!((g.get(w).unwrap().blob()[0] - 0.99989).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_full_precision() {
+        let mut g = Graph::new();
+        let w = g.alloc(Tensor::raw(&[2], vec![1., 0.5]).unwrap(), "w".into());
+        let scale = g.alloc(
+            Tensor::raw(&[2], vec![1., 1.]).unwrap(),
+            "norm_scale".into(),
+        );
+        let y = g.call(Mul::new(), &[w, scale]).unwrap();
+        g.set_name(y, "logits".into()).unwrap();
+        let full = g.memory_usage();
+        assert_eq!(g.set_full_precision(&["*norm*".into(), "logits".into()]), 2);
+        let params = [w, scale].into_iter().collect::<HashSet<_>>();
+        g.set_precision(Precision::Mixed, &params);
+        assert_eq!(g.precision_of(w), Precision::Mixed);
+        assert_eq!(g.precision_of(scale), Precision::F32);
+        // `w` halved with a master copy, the others left as they were
+        assert_eq!(g.memory_usage(), full - 2 * 2 * 2 + 2 * 4);
+        g.forward(false).unwrap();
+        assert_eq!(g.get(y).unwrap().blob(), &[1., 0.5]);
+
+        // Both updated in f32, `scale` in place
+        g.load_grad(w, &Tensor::vector(&[1e-5, 0.]));
+        g.load_grad(scale, &Tensor::vector(&[1e-5, 0.]));
+        g.optimize(&mut Naive::new(), &params, 1.).unwrap();
+        assert_eq!(g.get(w).unwrap().blob()[0], 1.);
+        assert_eq!(g.get_param(w).unwrap().blob()[0], 1. - 1e-5);
+        assert_eq!(g.get(scale).unwrap().blob()[0], 1. - 1e-5);
+    }
+}
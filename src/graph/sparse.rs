@@ -0,0 +1,116 @@
+use crate::tensor::*;
+
+/// The gradient of a matrix which is zero outside of a few of its rows, such as that of
+/// an embedding table, of which a window only touches the rows of its tokens. Only
+/// these rows are stored, by index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseGrad {
+    width: usize,
+    /// Sorted
+    indices: Vec<usize>,
+    values: Vec<f32>,
+}
+
+impl SparseGrad {
+    /// An empty gradient of a matrix with rows of `width` values
+    pub fn new(width: usize) -> Self {
+        Self {
+            width,
+            indices: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Adds `row` to the row `index`
+    pub fn add_row(&mut self, index: usize, row: &[f32]) -> Result<(), TensorError> {
+        if row.len() != self.width {
+            return Err(TensorError::shape_mismatch(
+                "SparseGrad",
+                &[self.width],
+                &[row.len()],
+            ));
+        }
+        let pos = match self.indices.binary_search(&index) {
+            Ok(pos) => pos,
+            Err(pos) => {
+                self.indices.insert(pos, index);
+                let at = pos * self.width;
+                self.values
+                    .splice(at..at, std::iter::repeat_n(0., self.width));
+                pos
+            }
+        };
+        for (v, r) in self.row_mut(pos).iter_mut().zip(row) {
+            *v += r;
+        }
+        Ok(())
+    }
+
+    /// Indices of the rows, sorted
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    /// The row of the `pos`th index
+    pub fn row(&self, pos: usize) -> &[f32] {
+        &self.values[pos * self.width..(pos + 1) * self.width]
+    }
+
+    fn row_mut(&mut self, pos: usize) -> &mut [f32] {
+        &mut self.values[pos * self.width..(pos + 1) * self.width]
+    }
+
+    pub fn scale(&mut self, coeff: f32) {
+        self.values.iter_mut().for_each(|v| *v *= coeff);
+    }
+
+    pub fn sq_norm(&self) -> f32 {
+        self.values.iter().map(|v| v * v).sum()
+    }
+
+    /// Adds its rows to `dense`, a matrix of the same width
+    pub fn add_to(&self, dense: &mut Tensor<f32>) -> Result<(), TensorError> {
+        let rows = dense.size() / self.width.max(1);
+        if dense.shape().last() != Some(&self.width)
+            || self.indices.last().is_some_and(|i| *i >= rows)
+        {
+            return Err(TensorError::shape_mismatch(
+                "SparseGrad",
+                &[self.indices.last().map_or(0, |i| i + 1), self.width],
+                dense.shape(),
+            ));
+        }
+        let blob = dense.blob_mut();
+        for (pos, index) in self.indices.iter().enumerate() {
+            let row = &mut blob[index * self.width..(index + 1) * self.width];
+            for (d, v) in row.iter_mut().zip(self.row(pos)) {
+                *d += v;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparse_grad() {
+        let mut grad = SparseGrad::new(2);
+        grad.add_row(3, &[1., 2.]).unwrap();
+        grad.add_row(0, &[5., 5.]).unwrap();
+        grad.add_row(3, &[1., 1.]).unwrap();
+        assert!(grad.add_row(1, &[1.]).is_err());
+        assert_eq!(grad.indices(), &[0, 3]);
+        assert_eq!(grad.row(1), &[2., 3.]);
+        grad.scale(2.);
+        assert_eq!(grad.sq_norm(), 4. * (25. + 25. + 4. + 9.));
+
+        let mut dense = Tensor::<f32>::zeros(&[4, 2]);
+        grad.add_to(&mut dense).unwrap();
+        assert_eq!(dense.blob(), &[10., 10., 0., 0., 0., 0., 4., 6.]);
+        assert!(grad.add_to(&mut Tensor::zeros(&[3, 2])).is_err());
+        assert!(grad.add_to(&mut Tensor::zeros(&[4, 3])).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use super::TensorId;
+use crate::tensor::*;
+use serde::Serialize;
+use std::path::Path;
+
+/// Which pass a step of a `Tape` belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pass {
+    Forward,
+    /// The loss of an output, and its gradient, which the backward pass starts from
+    Loss,
+    Backward,
+}
+
+/// An input of a step of a `Tape`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TapeInput {
+    pub id: TensorId,
+    /// Empty for the outputs of functions
+    pub name: String,
+    pub shape: Vec<usize>,
+}
+
+/// A function run by a pass, in the order it ran
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TapeStep {
+    pub pass: Pass,
+    pub op: &'static str,
+    /// The output of the function (Or the tensor the loss is of)
+    pub id: TensorId,
+    pub inputs: Vec<TapeInput>,
+    /// Shape of the output, or of the loss
+    pub output_shape: Vec<usize>,
+    /// Norm of the output when going forward, of its gradient when going backward, the
+    /// mean of the loss for losses
+    pub norm: f32,
+    /// Norms of the gradients sent to the inputs (Empty going forward)
+    pub grad_norms: Vec<f32>,
+}
+
+/// What the passes through a graph did, function by function (See
+/// `Graph::set_tape`), for seeing how backpropagation goes: the forward pass runs the
+/// functions in order, then the backward pass runs them in reverse, each turning the
+/// gradient of its output into ones of its inputs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Tape {
+    pub steps: Vec<TapeStep>,
+}
+
+impl Tape {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Always representable in JSON")
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json())
+    }
+}
+
+pub(super) fn input(names: &[String], id: TensorId, t: &Tensor<f32>) -> TapeInput {
+    TapeInput {
+        id,
+        name: names.get(id).cloned().unwrap_or_default(),
+        shape: t.shape().to_vec(),
+    }
+}
+
+pub(super) fn norm(t: &Tensor<f32>) -> f32 {
+    t.blob().iter().map(|v| v * v).sum::<f32>().sqrt()
+}
@@ -0,0 +1,248 @@
+//! A gRPC service (See `proto/femto_gpt.proto`) serving the same model as the HTTP
+//! server, for environments where gRPC is easier to use than HTTP and server-sent
+//! events.
+
+use crate::eval::continuation_log_likelihood;
+use crate::optimizer::Optimizer;
+use crate::server::{CompletionRequest, Model, Prompt};
+use crate::tokenizer::Tokenizer;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("femto_gpt.v1");
+}
+
+use proto::femto_gpt_server::{FemtoGpt, FemtoGptServer};
+use proto::*;
+
+pub struct Service<O: Optimizer, T: Tokenizer> {
+    model: Arc<Model<O, T>>,
+}
+
+impl<O: Optimizer, T: Tokenizer> Service<O, T> {
+    pub fn new(model: Arc<Model<O, T>>) -> Self {
+        Self { model }
+    }
+}
+
+impl GenerateRequest {
+    fn completion(self) -> CompletionRequest {
+        CompletionRequest {
+            prompt: Prompt::One(self.prompt),
+            max_tokens: self.max_tokens.map_or(16, |n| n as usize),
+            temperature: self.temperature.unwrap_or(1.),
+            top_p: self.top_p.unwrap_or(1.),
+            stream: true,
+            stop: Some(Prompt::Many(self.stop)),
+            truncation: None,
+            min_tokens: 0,
+            length_penalty: 0.,
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<O, T> FemtoGpt for Service<O, T>
+where
+    O: Optimizer + 'static,
+    T: Tokenizer + Send + Sync + 'static,
+{
+    type GenerateStream = ReceiverStream<Result<GenerateResponse, Status>>;
+
+    async fn generate(
+        &self,
+        request: Request<GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStream>, Status> {
+        if let Some(client) = request.remote_addr().map(|addr| addr.ip()) {
+            if let Err(wait) = self.model.admit(client) {
+                return Err(Status::resource_exhausted(format!(
+                    "too many requests, retry in {}s",
+                    wait.as_secs_f64().ceil()
+                )));
+            }
+        }
+        let req = request.into_inner();
+        let prompt = self.model.tokenize_all(&req.prompt);
+        let req = req.completion();
+        if prompt.is_empty() {
+            return Err(Status::invalid_argument("the prompt is empty"));
+        }
+        self.model
+            .limits
+            .check(prompt.len(), req.max_tokens)
+            .map_err(Status::invalid_argument)?;
+        let prompt = self.model.fit(&prompt, &req);
+        let (tx, rx) = mpsc::channel(16);
+        let model = self.model.clone();
+        // Generation stops once the client is gone
+        tokio::task::spawn_blocking(move || {
+            let piece = |text: &str, finish_reason: &str| GenerateResponse {
+                text: text.into(),
+                finish_reason: finish_reason.into(),
+            };
+            let result = model.complete(&req, &prompt, None, |text| {
+                tx.blocking_send(Ok(piece(text, ""))).is_ok()
+            });
+            let last = match result {
+                Ok((finish_reason, _)) => Ok(piece("", finish_reason)),
+                Err(e) => Err(Status::internal(e)),
+            };
+            let _ = tx.blocking_send(last);
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn tokenize(
+        &self,
+        request: Request<TokenizeRequest>,
+    ) -> Result<Response<TokenizeResponse>, Status> {
+        let tokens = self.model.tokenize_all(&request.into_inner().text);
+        Ok(Response::new(TokenizeResponse {
+            tokens: tokens.into_iter().map(|t| t as u32).collect(),
+        }))
+    }
+
+    async fn score(
+        &self,
+        request: Request<ScoreRequest>,
+    ) -> Result<Response<ScoreResponse>, Status> {
+        let req = request.into_inner();
+        let model = self.model.clone();
+        let (log_likelihood, num_tokens) = tokio::task::spawn_blocking(move || {
+            continuation_log_likelihood(
+                &model.gpt(),
+                &model.tokenize_all(&req.context),
+                &model.tokenize_all(&req.continuation),
+            )
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(ScoreResponse {
+            log_likelihood,
+            num_tokens: num_tokens as u32,
+        }))
+    }
+
+    async fn embed(
+        &self,
+        request: Request<EmbedRequest>,
+    ) -> Result<Response<EmbedResponse>, Status> {
+        let req = request.into_inner();
+        let model = self.model.clone();
+        let embeddings = tokio::task::spawn_blocking(move || {
+            req.texts
+                .iter()
+                .map(|text| {
+                    let (values, _) = model.embed(text, req.layer.map(|l| l as usize))?;
+                    Ok(Embedding { values })
+                })
+                .collect::<Result<Vec<_>, String>>()
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::invalid_argument)?;
+        Ok(Response::new(EmbedResponse { embeddings }))
+    }
+}
+
+/// Serves `model` over gRPC on `addr`. Blocks forever, unless the server can't be
+/// started.
+pub fn serve_grpc<O, T>(addr: &str, model: Arc<Model<O, T>>) -> std::io::Result<()>
+where
+    O: Optimizer + 'static,
+    T: Tokenizer + Send + Sync + 'static,
+{
+    let addr = addr
+        .parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    tokio::runtime::Runtime::new()?.block_on(async {
+        tonic::transport::Server::builder()
+            .add_service(FemtoGptServer::new(Service::new(model)))
+            .serve(addr)
+            .await
+            .map_err(std::io::Error::other)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelConfig;
+    use crate::gpt::GPT;
+    use crate::optimizer::Naive;
+    use crate::template::ChatTemplate;
+    use crate::tokenizer::AsciiTokenizer;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_service() {
+        let mut rng = rand::thread_rng();
+        let gpt = GPT::new(
+            &mut rng,
+            128,
+            &ModelConfig {
+                num_tokens: 4,
+                embedding_degree: 8,
+                num_layers: 1,
+                num_heads: 2,
+                ..Default::default()
+            },
+            Naive::new(),
+        )
+        .unwrap();
+        let model = Model::new("test", gpt, AsciiTokenizer, ChatTemplate::default());
+        let service = Service::new(Arc::new(model));
+
+        let tokens = service
+            .tokenize(Request::new(TokenizeRequest { text: "Hi".into() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .tokens;
+        assert_eq!(tokens, [72, 105]);
+
+        let stream = service
+            .generate(Request::new(GenerateRequest {
+                prompt: "Hello".into(),
+                max_tokens: Some(5),
+                temperature: Some(0.),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let pieces = stream.map(|p| p.unwrap()).collect::<Vec<_>>().await;
+        let text = pieces.iter().map(|p| p.text.as_str()).collect::<String>();
+        assert_eq!(text.chars().count(), 5);
+        assert_eq!(pieces.last().unwrap().finish_reason, "length");
+
+        let score = service
+            .score(Request::new(ScoreRequest {
+                context: "Hello".into(),
+                continuation: " there".into(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        // Only as many tokens as fit in the context are scored
+        assert_eq!(score.num_tokens, 4);
+        assert!(score.log_likelihood < 0.);
+
+        let embeddings = service
+            .embed(Request::new(EmbedRequest {
+                texts: vec!["Hi".into(), "There".into()],
+                layer: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .embeddings;
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[0].values.len(), 8);
+    }
+}
@@ -0,0 +1,178 @@
+//! Histograms of the weights and gradients of a model (See `GPT::histograms`), for
+//! spotting dead layers, exploding values and bad initializations, and a CSV file to
+//! log them to during training.
+
+use crate::rotate::{RotatingFile, Rotation};
+use std::path::Path;
+
+/// Bins of the histograms logged during training
+pub const DEFAULT_BINS: usize = 32;
+
+/// Counts of values in equal-width bins spanning `[min, max]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub min: f32,
+    pub max: f32,
+    pub counts: Vec<usize>,
+    /// NaNs and infinities, which are left out of the bins
+    pub non_finite: usize,
+}
+
+impl Histogram {
+    pub fn new(values: &[f32], bins: usize) -> Self {
+        let bins = bins.max(1);
+        let finite = values.iter().filter(|v| v.is_finite());
+        let (min, max) = finite
+            .clone()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| {
+                (lo.min(*v), hi.max(*v))
+            });
+        let (min, max) = if min <= max { (min, max) } else { (0., 0.) };
+        let mut counts = vec![0; bins];
+        let mut non_finite = values.len();
+        for v in finite {
+            // All the values are in the first bin when they are equal
+            let bin = if max > min {
+                (((v - min) / (max - min)) * bins as f32) as usize
+            } else {
+                0
+            };
+            counts[bin.min(bins - 1)] += 1;
+            non_finite -= 1;
+        }
+        Self {
+            min,
+            max,
+            counts,
+            non_finite,
+        }
+    }
+
+    /// Lower and upper bounds of each bin
+    pub fn bins(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        let width = (self.max - self.min) / self.counts.len() as f32;
+        (0..self.counts.len()).map(move |i| {
+            (
+                self.min + width * i as f32,
+                self.min + width * (i + 1) as f32,
+            )
+        })
+    }
+}
+
+/// Histograms of a parameter tensor of a model, and of its gradients
+#[derive(Debug, Clone)]
+pub struct ParamHistograms {
+    pub name: String,
+    pub weights: Histogram,
+    pub grads: Histogram,
+}
+
+const HEADER: &str = "step,tensor,kind,low,high,count\n";
+
+/// A CSV file histograms are appended to, a row per bin:
+/// `step,tensor,kind,low,high,count` (`kind` being `weights` or `grads`). Non-finite
+/// values get a row of their own, with empty bounds.
+pub struct HistogramCsv {
+    file: RotatingFile,
+}
+
+impl HistogramCsv {
+    /// Appends to `path`, which gets a header when it's empty or doesn't exist (A file
+    /// with other columns being rotated first)
+    pub fn new<P: AsRef<Path>>(path: P, rotation: &Rotation) -> std::io::Result<Self> {
+        Self::with_file(RotatingFile::open(path, rotation, Some(HEADER))?)
+    }
+
+    /// Like `new`, dropping the rows logged after `step`, for a training resumed from a
+    /// checkpoint of that step not to log them twice
+    pub fn resume<P: AsRef<Path>>(
+        path: P,
+        rotation: &Rotation,
+        step: usize,
+    ) -> std::io::Result<Self> {
+        let file = RotatingFile::resume(path, rotation, Some(HEADER), |row| {
+            let logged = row.split(',').next().and_then(|s| s.parse::<usize>().ok());
+            logged.is_some_and(|logged| logged > step)
+        })?;
+        Self::with_file(file)
+    }
+
+    fn with_file(mut file: RotatingFile) -> std::io::Result<Self> {
+        if !file.starts_with(HEADER.as_bytes())? {
+            file.rotate()?;
+        }
+        Ok(Self { file })
+    }
+
+    pub fn write(&mut self, step: usize, histograms: &[ParamHistograms]) -> std::io::Result<()> {
+        let mut out = String::new();
+        for h in histograms {
+            for (kind, hist) in [("weights", &h.weights), ("grads", &h.grads)] {
+                for ((low, high), count) in hist.bins().zip(&hist.counts) {
+                    out.push_str(&format!(
+                        "{},{},{},{},{},{}\n",
+                        step, h.name, kind, low, high, count
+                    ));
+                }
+                if hist.non_finite > 0 {
+                    out.push_str(&format!(
+                        "{},{},{},,,{}\n",
+                        step, h.name, kind, hist.non_finite
+                    ));
+                }
+            }
+        }
+        self.file.append(&out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::new(&[0., 1., 2., 3., 4., f32::NAN, f32::INFINITY], 4);
+        assert_eq!((h.min, h.max), (0., 4.));
+        assert_eq!(h.counts, vec![1, 1, 1, 2]);
+        assert_eq!(h.non_finite, 2);
+        assert_eq!(h.bins().next(), Some((0., 1.)));
+
+        let constant = Histogram::new(&[0.5; 3], 2);
+        assert_eq!(constant.counts, vec![3, 0]);
+        let empty = Histogram::new(&[f32::NAN], 2);
+        assert_eq!(
+            (empty.min, empty.counts.clone(), empty.non_finite),
+            (0., vec![0, 0], 1)
+        );
+
+        let path = std::env::temp_dir().join("femto_gpt_test_histograms.csv");
+        let _ = std::fs::remove_file(&path);
+        let params = [ParamHistograms {
+            name: "w".into(),
+            weights: h.clone(),
+            grads: constant,
+        }];
+        let rotation = Rotation::default();
+        HistogramCsv::new(&path, &rotation)
+            .unwrap()
+            .write(1, &params)
+            .unwrap();
+        HistogramCsv::new(&path, &rotation)
+            .unwrap()
+            .write(2, &params)
+            .unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "step,tensor,kind,low,high,count");
+        assert_eq!(lines[1], "1,w,weights,0,1,1");
+        assert!(lines.contains(&"1,w,weights,,,2"));
+        assert_eq!(lines.len(), 1 + 2 * (4 + 1 + 2));
+        // Resumed from step 1
+        HistogramCsv::resume(&path, &rotation, 1).unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(csv.lines().count(), 1 + 4 + 1 + 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
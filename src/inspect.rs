@@ -0,0 +1,149 @@
+use crate::convert::{Checkpoint, Format};
+use femto_gpt::config::Config;
+use femto_gpt::optimizer::Optimizer;
+use femto_gpt::tensor::TensorOps;
+use femto_gpt::tokenizer::Tokenizer;
+use std::fmt::Write;
+
+/// Characters of the vocabulary shown before eliding the rest
+const VOCAB_PREVIEW: usize = 64;
+
+/// Name and version of the format of a checkpoint
+fn format_name(format: Format, bytes: &[u8]) -> String {
+    match format {
+        Format::State => "bincode training state (Unversioned)".into(),
+        Format::Params => format!("femto-gpt tensors, version {}", bytes[4]),
+        Format::Safetensors => "safetensors".into(),
+        Format::Gguf => format!(
+            "GGUF, version {}",
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap())
+        ),
+        Format::Bundle => format!("femto-gpt bundle, version {}", bytes[5]),
+    }
+}
+
+impl Checkpoint {
+    pub fn num_params(&self) -> usize {
+        self.tensors.iter().map(|(_, t)| t.size()).sum()
+    }
+
+    /// A human-readable summary, along with the config and the tokenizer of the model
+    /// (If found)
+    pub fn report(
+        &self,
+        bytes: &[u8],
+        config: Option<&Config>,
+        tokenizer: Option<&dyn Tokenizer>,
+    ) -> String {
+        let mut out = String::new();
+        let format = format_name(self.format, bytes);
+        writeln!(out, "Format: {} ({} bytes)", format, bytes.len()).unwrap();
+        match config {
+            Some(config) => {
+                let m = &config.model;
+                writeln!(
+                    out,
+                    "Model: {} tokens of context, {} embedding-degree, {} layers, {} heads, {} dropout",
+                    m.num_tokens, m.embedding_degree, m.num_layers, m.num_heads, m.dropout
+                )
+                .unwrap();
+            }
+            None => writeln!(out, "Model: unknown (No saved config)").unwrap(),
+        }
+        match &self.optimizer {
+            Some(o) => writeln!(out, "Optimizer: AdamW (Step {})", o.step_num()).unwrap(),
+            None => writeln!(out, "Optimizer: none (Weights only)").unwrap(),
+        }
+        if let Some(seed) = self.data_seed {
+            writeln!(out, "Data seed: {}", seed).unwrap();
+        }
+        if let Some(run) = &self.run {
+            writeln!(
+                out,
+                "Run: {} (Config {}, dataset {}, tokenizer {}, git {}, on {})",
+                run.id,
+                run.config_hash,
+                run.dataset_hash,
+                run.tokenizer_hash.as_deref().unwrap_or("unknown"),
+                run.git.as_deref().unwrap_or("unknown"),
+                run.host
+            )
+            .unwrap();
+        }
+        match tokenizer {
+            Some(tokenizer) => {
+                let vocab = (0..tokenizer.vocab_size())
+                    .filter_map(|tkn| tokenizer.untokenize(&[tkn]).ok())
+                    .collect::<String>();
+                let mut preview = vocab
+                    .chars()
+                    .take(VOCAB_PREVIEW)
+                    .collect::<String>()
+                    .escape_debug()
+                    .to_string();
+                if vocab.chars().count() > VOCAB_PREVIEW {
+                    preview.push_str("...");
+                }
+                let kind = config.map_or("simple".into(), |c| {
+                    format!("{:?}", c.tokenizer.kind).to_lowercase()
+                });
+                writeln!(
+                    out,
+                    "Tokenizer: {}, {} tokens \"{}\"",
+                    kind,
+                    tokenizer.vocab_size(),
+                    preview
+                )
+                .unwrap();
+            }
+            None => writeln!(out, "Tokenizer: not found").unwrap(),
+        }
+        writeln!(out, "Parameters: {}", self.num_params()).unwrap();
+        let width = self.tensors.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+        for (name, t) in self.tensors.iter() {
+            writeln!(
+                out,
+                "  {:width$}  {:<14}  {}",
+                name,
+                format!("{:?}", t.shape()),
+                t.size(),
+                width = width
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use femto_gpt::tokenizer::AsciiTokenizer;
+
+    #[test]
+    fn test_inspect() {
+        let config = Config::default();
+        let mut rng = rand::thread_rng();
+        let gpt = config
+            .model
+            .build(&mut rng, 128, config.optimizer.build())
+            .unwrap();
+        let state = bincode::serialize(&gpt.get_training_state().unwrap()).unwrap();
+        let checkpoint = Checkpoint::read(&state).unwrap();
+        assert_eq!(checkpoint.num_params(), gpt.num_params());
+        let report = checkpoint.report(&state, Some(&config), Some(&AsciiTokenizer));
+        assert!(report.contains("Optimizer: AdamW (Step 0)"));
+        assert!(report.contains("4 layers"));
+        assert!(report.contains("Tokenizer: simple, 128 tokens"));
+
+        let mut params = Vec::new();
+        gpt.write_params(&mut params).unwrap();
+        let checkpoint = Checkpoint::read(&params).unwrap();
+        assert_eq!(checkpoint.num_params(), gpt.num_params());
+        let report = checkpoint.report(&params, None, None);
+        assert!(report.contains("tensors, version 1"));
+        assert!(report.contains("Optimizer: none"));
+
+        assert!(Checkpoint::read(&params[..10]).is_err());
+    }
+}
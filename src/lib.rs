@@ -1,6 +1,38 @@
+pub mod bundle;
+pub mod checkpoint;
+pub mod config;
+pub mod dataset;
+pub mod diff;
+pub mod distributed;
+pub mod ensemble;
+pub mod eval;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixtures;
 pub mod funcs;
+#[cfg(test)]
+mod golden;
 pub mod gpt;
 pub mod graph;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod histogram;
 pub mod optimizer;
+pub mod prelude;
+pub mod privacy;
+pub mod projector;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod rotate;
+pub mod run;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod shard;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod stream;
+pub mod template;
 pub mod tensor;
 pub mod tokenizer;
+pub mod train;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,119 +1,1677 @@
+#[cfg(not(feature = "gpu"))]
+mod bench;
+#[cfg(not(feature = "gpu"))]
+mod clean;
+#[cfg(not(feature = "gpu"))]
+mod cli;
+#[cfg(not(feature = "gpu"))]
+mod convert;
+#[cfg(all(feature = "tui", not(feature = "gpu")))]
+mod dashboard;
+#[cfg(not(feature = "gpu"))]
+mod inspect;
+#[cfg(not(feature = "gpu"))]
+mod plan;
+#[cfg(not(feature = "gpu"))]
+mod prepare;
+#[cfg(not(feature = "gpu"))]
+mod quality;
+#[cfg(not(feature = "gpu"))]
+mod repl;
+#[cfg(not(feature = "gpu"))]
+mod sample;
+#[cfg(not(feature = "gpu"))]
+mod soup;
+
 use femto_gpt::graph::GraphError;
 
+#[cfg(not(feature = "gpu"))]
+use {
+    bench::BenchOptions,
+    clap::{error::ErrorKind, CommandFactory, Parser},
+    cli::{Cli, Command, ModelArgs, RunsCommand},
+    convert::{Checkpoint, Format},
+    femto_gpt::bundle::{self, Bundle},
+    femto_gpt::checkpoint::{write_atomic, AsyncWriter},
+    femto_gpt::config::{Config, ConfigError, ScheduleKind, Scheduler, TokenizerKind},
+    femto_gpt::diff::{Change, Diff},
+    femto_gpt::ensemble::Ensemble,
+    femto_gpt::eval,
+    femto_gpt::gpt::{
+        seeded_rng, CancellationToken, LengthControl, Provenance, Sampling, SoftTargets,
+        StopReason, TrainCallback, TrainContext, TrainSummary, TrainingState, GPT,
+    },
+    femto_gpt::histogram::{self, HistogramCsv},
+    femto_gpt::optimizer::{AdamW, Optimizer},
+    femto_gpt::projector,
+    femto_gpt::run::{self, MetricsLog, RunInfo},
+    femto_gpt::shard,
+    femto_gpt::tensor::{xxh64, DType, TensorOps},
+    femto_gpt::tokenizer::{
+        chars_mask, heal_prompt, AsciiTokenizer, SimpleTokenizer, StreamDecoder, Tokenizer,
+    },
+    femto_gpt::train::{tokenize_documents, train_gpt},
+    serde::Serialize,
+    std::cell::RefCell,
+    std::collections::HashMap,
+    std::fs,
+    std::io::prelude::*,
+    std::io::IsTerminal,
+    std::path::{Path, PathBuf},
+    std::time::Instant,
+    tracing::{debug, info, info_span, warn},
+};
+
+#[cfg(all(feature = "grpc", not(feature = "gpu")))]
+use femto_gpt::grpc::serve_grpc;
+#[cfg(all(feature = "server", not(feature = "gpu")))]
+use femto_gpt::server::{batch, serve, watch, Limits, Model};
+
 #[cfg(not(feature = "gpu"))]
 fn main() -> Result<(), GraphError> {
-    use femto_gpt::gpt::{TrainingState, GPT};
-    use femto_gpt::optimizer::AdamW;
-    use femto_gpt::tokenizer::{SimpleTokenizer, Tokenizer};
-    use std::fs;
-    use std::io::prelude::*;
-    use std::path::Path;
+    let exit =
+        |err: ConfigError| -> ! { Cli::command().error(ErrorKind::ValueValidation, err).exit() };
+    let cli = Cli::parse();
+    // Logs go to stderr, so that generated text can be piped on its own
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level())
+        .with_target(false)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .init();
+    match cli.into_command() {
+        Command::Train(args) => {
+            if let Some(dir) = &args.run_dir {
+                fs::create_dir_all(dir)?;
+                match args.model.state.is_file() {
+                    true => info!(run_dir = %dir.display(), "Resuming the run"),
+                    false => info!(run_dir = %dir.display(), "Starting a new run"),
+                }
+            }
+            let config = args.config().unwrap_or_else(|e| exit(e));
+            let documents = config.load_documents().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let mut text = config.training.document_separator.clone();
+                    text.extend(documents.iter().map(|(_, d)| d.as_str()));
+                    // Reuses the vocabulary of an earlier run (If exists)
+                    let mut grown_from = None;
+                    let tokenizer = if args.model.tokenizer.is_file() || args.model.has_bundle() {
+                        let mut tokenizer = load_model_tokenizer(&args.model)?;
+                        if args.extend_vocab {
+                            let vocab_size = tokenizer.vocab_size();
+                            let added = tokenizer.extend(&text);
+                            if !added.is_empty() {
+                                info!(added = ?added, "Extended the vocabulary");
+                                grown_from = Some(vocab_size);
+                            }
+                        }
+                        tokenizer
+                    } else {
+                        SimpleTokenizer::new(&text)
+                    };
+                    train(
+                        &args.model,
+                        &config,
+                        tokenizer,
+                        grown_from,
+                        &documents,
+                        args.dashboard(),
+                    )
+                }
+                TokenizerKind::Ascii => train(
+                    &args.model,
+                    &config,
+                    AsciiTokenizer,
+                    None,
+                    &documents,
+                    args.dashboard(),
+                ),
+            }
+        }
+        Command::Infer(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            let draft = args
+                .draft
+                .as_ref()
+                .map(|d| Config::load(d.with_extension("toml")))
+                .transpose()
+                .unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    infer(&args, &config, draft.as_ref(), tokenizer)
+                }
+                TokenizerKind::Ascii => infer(&args, &config, draft.as_ref(), AsciiTokenizer),
+            }
+        }
+        Command::Sample(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            let opts = sample::SampleOptions {
+                prompt: args.prompt,
+                n: args.n,
+                seed: args.seed.unwrap_or_else(rand::random),
+                max_tokens: args.max_tokens,
+                sampling: Sampling {
+                    temperature: args.temperature,
+                    top_p: args.top_p,
+                },
+            };
+            let checkpoint = xxh64(&fs::read(&args.model.state)?, 0);
+            let samples = match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    let gpt = load_gpt(&args.model, &config, &tokenizer)?;
+                    sample::run(&gpt, &tokenizer, &opts, checkpoint)?
+                }
+                TokenizerKind::Ascii => {
+                    let gpt = load_gpt(&args.model, &config, &AsciiTokenizer)?;
+                    sample::run(&gpt, &AsciiTokenizer, &opts, checkpoint)?
+                }
+            };
+            let lines = samples
+                .iter()
+                .map(|s| serde_json::to_string(s).unwrap() + "\n")
+                .collect::<String>();
+            match &args.out {
+                Some(path) => {
+                    fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?
+                        .write_all(lines.as_bytes())?;
+                    info!("Appended {} samples to {}", samples.len(), path.display());
+                }
+                None => print!("{}", lines),
+            }
+            Ok(())
+        }
+        Command::Embed(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    embed(&args, &config, tokenizer)
+                }
+                TokenizerKind::Ascii => embed(&args, &config, AsciiTokenizer),
+            }
+        }
+        Command::Projector(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    export_projector(&args, &config, tokenizer)
+                }
+                TokenizerKind::Ascii => export_projector(&args, &config, AsciiTokenizer),
+            }
+        }
+        Command::Attention(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    attention(&args, &config, tokenizer)
+                }
+                TokenizerKind::Ascii => attention(&args, &config, AsciiTokenizer),
+            }
+        }
+        Command::Tape(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    tape(&args, &config, tokenizer)
+                }
+                TokenizerKind::Ascii => tape(&args, &config, AsciiTokenizer),
+            }
+        }
+        Command::Prune(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    prune(&args, &config, tokenizer)
+                }
+                TokenizerKind::Ascii => prune(&args, &config, AsciiTokenizer),
+            }
+        }
+        Command::Quality(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    quality(&args, &config, tokenizer)
+                }
+                TokenizerKind::Ascii => quality(&args, &config, AsciiTokenizer),
+            }
+        }
+        Command::Lens(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    lens(&args, &config, tokenizer)
+                }
+                TokenizerKind::Ascii => lens(&args, &config, AsciiTokenizer),
+            }
+        }
+        Command::Interactive(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            let (max_tokens, temperature) = (args.max_tokens, args.temperature);
+            let length = LengthControl {
+                end_tokens: Vec::new(),
+                min_new_tokens: args.min_new_tokens,
+                length_penalty: args.length_penalty,
+            };
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    let gpt = load_gpt(&args.model, &config, &tokenizer)?;
+                    repl::run(
+                        &gpt,
+                        &tokenizer,
+                        &config.chat,
+                        max_tokens,
+                        temperature,
+                        length,
+                        args.max_duration,
+                    )
+                }
+                TokenizerKind::Ascii => {
+                    let gpt = load_gpt(&args.model, &config, &AsciiTokenizer)?;
+                    repl::run(
+                        &gpt,
+                        &AsciiTokenizer,
+                        &config.chat,
+                        max_tokens,
+                        temperature,
+                        length,
+                        args.max_duration,
+                    )
+                }
+            }
+        }
+        #[cfg(feature = "server")]
+        Command::Serve(args) => {
+            let config = args.model.config().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    let gpt = load_gpt(&args.model, &config, &tokenizer)?;
+                    let vocab_size = tokenizer.vocab_size();
+                    let model = Model::new(&args.name, gpt, tokenizer, config.chat.clone());
+                    run_server(&args, &config, vocab_size, model)
+                }
+                TokenizerKind::Ascii => {
+                    let vocab_size = AsciiTokenizer.vocab_size();
+                    let gpt = load_gpt(&args.model, &config, &AsciiTokenizer)?;
+                    let model = Model::new(&args.name, gpt, AsciiTokenizer, config.chat.clone());
+                    run_server(&args, &config, vocab_size, model)
+                }
+            }
+        }
+        Command::Tokenize(args) => {
+            let tokenizer = load_tokenizer(&args.tokenizer, Some(&args.dataset))?;
+            let tokens = tokenizer.tokenize(&args.text)?;
+            println!(
+                "{}",
+                tokens
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+            Ok(())
+        }
+        Command::Inspect(args) => {
+            let bytes = fs::read(&args.state)?;
+            let mut checkpoint = Checkpoint::read(&bytes).unwrap_or_else(|e| exit(e));
+            let run_path = RunInfo::path_of(&args.state);
+            if checkpoint.run.is_none() && run_path.is_file() {
+                checkpoint.run = Some(RunInfo::load(&run_path).unwrap_or_else(|e| exit(e)));
+            }
+            let config_path = args
+                .config
+                .unwrap_or_else(|| args.state.with_extension("toml"));
+            let config = if config_path.is_file() {
+                Some(Config::load(&config_path).unwrap_or_else(|e| exit(e)))
+            } else {
+                checkpoint.config.clone()
+            };
+            let tokenizer: Option<Box<dyn Tokenizer>> = match config.as_ref() {
+                Some(c) if c.tokenizer.kind == TokenizerKind::Ascii => {
+                    Some(Box::new(AsciiTokenizer))
+                }
+                _ if checkpoint.tokenizer.is_some() => {
+                    let bytes = checkpoint.tokenizer.as_deref().unwrap();
+                    let tokenizer: SimpleTokenizer =
+                        bincode::deserialize(bytes).map_err(invalid_data)?;
+                    Some(Box::new(tokenizer))
+                }
+                _ if args.tokenizer.is_file() => {
+                    Some(Box::new(load_tokenizer(&args.tokenizer, None)?))
+                }
+                _ => None,
+            };
+            print!(
+                "{}",
+                checkpoint.report(&bytes, config.as_ref(), tokenizer.as_deref())
+            );
+            if let Some(path) = args.dot {
+                let config = config.unwrap_or_else(|| {
+                    exit(ConfigError::Invalid(
+                        "the config of the model is needed to draw its graph".into(),
+                    ))
+                });
+                let vocab_size = checkpoint
+                    .tensors
+                    .iter()
+                    .find(|(name, _)| name == "token_embedding")
+                    .map(|(_, t)| t.shape()[0])
+                    .unwrap_or_else(|| {
+                        exit(ConfigError::Invalid(
+                            "the checkpoint has no token embedding".into(),
+                        ))
+                    });
+                fs::write(&path, new_gpt(&config, vocab_size)?.to_dot())?;
+                info!(path = %path.display(), "Wrote the graph");
+            }
+            Ok(())
+        }
+        Command::Convert(args) => {
+            let mut checkpoint =
+                Checkpoint::read(&fs::read(&args.input)?).unwrap_or_else(|e| exit(e));
+            let config_path = args
+                .config
+                .unwrap_or_else(|| args.input.with_extension("toml"));
+            let config = if config_path.is_file() {
+                Some(Config::load(&config_path).unwrap_or_else(|e| exit(e)))
+            } else {
+                checkpoint.config.clone()
+            };
+            if args.drop_optimizer {
+                checkpoint.optimizer = None;
+            }
+            let format = args.to.unwrap_or_else(|| Format::from_path(&args.output));
+            if format == Format::Bundle
+                && checkpoint.tokenizer.is_none()
+                && args.tokenizer.is_file()
+            {
+                checkpoint.tokenizer = Some(fs::read(&args.tokenizer)?);
+            }
+            let bytes = checkpoint
+                .write(format, args.dtype, config.as_ref())
+                .unwrap_or_else(|e| exit(e));
+            fs::write(&args.output, &bytes)?;
+            // The config goes along the converted checkpoint too, for `infer` and such
+            // (Bundles hold it already)
+            if let Some(config) = config.filter(|_| format != Format::Bundle) {
+                config
+                    .save(args.output.with_extension("toml"))
+                    .unwrap_or_else(|e| exit(e));
+            }
+            info!(
+                "Wrote {} ({:?}, {} bytes)",
+                args.output.display(),
+                format,
+                bytes.len()
+            );
+            Ok(())
+        }
+        Command::Diff(args) => {
+            let tensors = |path: &Path| -> Result<HashMap<_, _>, GraphError> {
+                let checkpoint = Checkpoint::read(&fs::read(path)?).unwrap_or_else(|e| exit(e));
+                Ok(checkpoint.tensors.into_iter().collect())
+            };
+            let diff = Diff::new(&tensors(&args.before)?, &tensors(&args.after)?)
+                .map_err(GraphError::from)?;
+            let row = |name: &str, c: &Change| {
+                println!(
+                    "{:<24} {:>10} {:>12.4e} {:>10.4} {:>10.4} {:>12.4e}",
+                    name,
+                    c.num_params,
+                    c.l2_distance,
+                    c.relative_change,
+                    c.cosine_similarity,
+                    c.max_abs_change
+                )
+            };
+            println!(
+                "{:<24} {:>10} {:>12} {:>10} {:>10} {:>12}",
+                "Layer", "Params", "L2 distance", "Relative", "Cosine", "Max change"
+            );
+            for (layer, change) in diff.layers.iter() {
+                row(&layer.to_string(), change);
+            }
+            println!();
+            println!("Most changed tensors:");
+            for (name, change) in diff.most_changed(args.top) {
+                row(name, change);
+            }
+            let unchanged = diff.unchanged().collect::<Vec<_>>();
+            if !unchanged.is_empty() {
+                warn!(
+                    count = unchanged.len(),
+                    "Unchanged tensors (Frozen?): {}",
+                    unchanged.join(", ")
+                );
+            }
+            if !diff.removed.is_empty() {
+                warn!(
+                    "Only in {}: {}",
+                    args.before.display(),
+                    diff.removed.join(", ")
+                );
+            }
+            if !diff.added.is_empty() {
+                warn!(
+                    "Only in {}: {}",
+                    args.after.display(),
+                    diff.added.join(", ")
+                );
+            }
+            Ok(())
+        }
+        Command::Soup(args) => {
+            let checkpoints = args
+                .inputs
+                .iter()
+                .map(|path| Ok(Checkpoint::read(&fs::read(path)?).unwrap_or_else(|e| exit(e))))
+                .collect::<Result<Vec<_>, GraphError>>()?;
+            let config_of = |path: &Path, checkpoint: &Checkpoint| {
+                let path = path.with_extension("toml");
+                if path.is_file() {
+                    Some(Config::load(&path).unwrap_or_else(|e| exit(e)))
+                } else {
+                    checkpoint.config.clone()
+                }
+            };
+            let config = match &args.config {
+                Some(path) => Config::load(path).unwrap_or_else(|e| exit(e)),
+                None => config_of(&args.inputs[0], &checkpoints[0]).unwrap_or_else(|| {
+                    exit(ConfigError::Invalid(format!(
+                        "no config found for {}, pass --config",
+                        args.inputs[0].display()
+                    )))
+                }),
+            };
+            for (path, checkpoint) in args.inputs.iter().zip(&checkpoints).skip(1) {
+                if let Some(other) = config_of(path, checkpoint) {
+                    if other.model != config.model {
+                        exit(ConfigError::Invalid(format!(
+                            "{} is of another model than {}",
+                            path.display(),
+                            args.inputs[0].display()
+                        )));
+                    }
+                }
+            }
+            let weights = checkpoints
+                .iter()
+                .map(|c| c.tensors.clone())
+                .collect::<Vec<_>>();
+            let (tensors, ingredients) = match &args.validation {
+                Some(validation) if args.greedy => {
+                    let tokenizer: Box<dyn Tokenizer> = match &checkpoints[0].tokenizer {
+                        _ if config.tokenizer.kind == TokenizerKind::Ascii => {
+                            Box::new(AsciiTokenizer)
+                        }
+                        Some(bytes) => Box::new(
+                            bincode::deserialize::<SimpleTokenizer>(bytes).map_err(invalid_data)?,
+                        ),
+                        None => Box::new(load_tokenizer(&args.tokenizer, None)?),
+                    };
+                    let tokens = read_validation(tokenizer.as_ref(), validation)?;
+                    let vocab_size = tokenizer.vocab_size();
+                    soup::greedy(&weights, |tensors| {
+                        let mut gpt = new_gpt(&config, vocab_size)?;
+                        gpt.set_training_state(
+                            TrainingState {
+                                tensors: tensors.iter().cloned().collect(),
+                                optimizer: AdamW::new(),
+                                data_seed: 0,
+                                provenance: Default::default(),
+                            },
+                            false,
+                        )?;
+                        let loss = eval::perplexity(&gpt, &tokens)?.ln();
+                        info!("Validation loss: {:.4}", loss);
+                        Ok(loss)
+                    })
+                    .unwrap_or_else(|e| exit(e))
+                }
+                _ => {
+                    let all = weights.iter().collect::<Vec<_>>();
+                    let soup = soup::average(&all).unwrap_or_else(|e| exit(e));
+                    (soup, (0..weights.len()).collect())
+                }
+            };
+            let format = Format::from_path(&args.output);
+            let mut checkpoint = Checkpoint {
+                format,
+                tensors,
+                optimizer: None,
+                data_seed: None,
+                provenance: Default::default(),
+                config: Some(config.clone()),
+                tokenizer: checkpoints.into_iter().next().and_then(|c| c.tokenizer),
+                run: None,
+            };
+            if format == Format::Bundle
+                && checkpoint.tokenizer.is_none()
+                && args.tokenizer.is_file()
+            {
+                checkpoint.tokenizer = Some(fs::read(&args.tokenizer)?);
+            }
+            let bytes = checkpoint
+                .write(format, DType::F32, Some(&config))
+                .unwrap_or_else(|e| exit(e));
+            fs::write(&args.output, &bytes)?;
+            if format != Format::Bundle {
+                config
+                    .save(args.output.with_extension("toml"))
+                    .unwrap_or_else(|e| exit(e));
+            }
+            info!(
+                "Wrote {}, the soup of {}",
+                args.output.display(),
+                ingredients
+                    .iter()
+                    .map(|i| args.inputs[*i].display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            Ok(())
+        }
+        Command::Bench(args) => {
+            let mut opts = BenchOptions::default();
+            if args.quick {
+                opts.matmul_sizes = vec![128, 256];
+                opts.min_runs = 1;
+                opts.min_time = std::time::Duration::from_millis(300);
+            }
+            if let Some(threads) = args.threads {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build_global()
+                    .expect("Unable to set the number of threads");
+            }
+            println!(
+                "femto-gpt {} on {} threads",
+                env!("CARGO_PKG_VERSION"),
+                rayon::current_num_threads()
+            );
+            print!("{}", bench::report(&bench::run(&opts)?));
+            Ok(())
+        }
+        Command::Plan(args) => {
+            let config = args.config().unwrap_or_else(|e| exit(e));
+            // The vocabulary decides the size of the embeddings and of the output layer
+            let vocab_size = match config.tokenizer.kind {
+                TokenizerKind::Ascii => AsciiTokenizer.vocab_size(),
+                TokenizerKind::Simple if args.model.tokenizer.is_file() => {
+                    load_tokenizer(&args.model.tokenizer, None)?.vocab_size()
+                }
+                TokenizerKind::Simple => match config.load_dataset() {
+                    Ok(text) => SimpleTokenizer::new(&text).vocab_size(),
+                    Err(e) => {
+                        warn!("Assuming a vocab of {} tokens ({})", PLAN_VOCAB_SIZE, e);
+                        PLAN_VOCAB_SIZE
+                    }
+                },
+            };
+            if !args.no_measure {
+                info!("Timing a few train steps...");
+            }
+            let plan = plan::estimate(&config, vocab_size, !args.no_measure)?;
+            print!("{}", plan.report(&config));
+            Ok(())
+        }
+        Command::Runs(RunsCommand::List { dir }) => {
+            let runs = run::find_runs(&dir).unwrap_or_else(|e| exit(e));
+            println!(
+                "{:<25} {:<16} {:<16} {:<24} {:<16} CHECKPOINT",
+                "RUN", "CONFIG", "DATASET", "GIT", "HOST"
+            );
+            for found in runs {
+                let run = &found.run;
+                println!(
+                    "{:<25} {:<16} {:<16} {:<24} {:<16} {}",
+                    run.id,
+                    run.config_hash,
+                    run.dataset_hash,
+                    run.git.as_deref().unwrap_or("-"),
+                    run.host,
+                    found.checkpoint.display()
+                );
+            }
+            Ok(())
+        }
+        Command::Dataset(args) => {
+            let text = fs::read_to_string(&args.dataset)?;
+            let tokenizer = if args.tokenizer.is_file() {
+                Some(load_tokenizer(&args.tokenizer, None)?)
+            } else {
+                None
+            };
+            let stats = prepare::Stats::new(&text, tokenizer.as_ref().map(|t| t as &dyn Tokenizer));
+            print!("{}", stats.report());
+            let mut text = text;
+            if let Some(cleaning) = args.cleaning() {
+                if cleaning
+                    .near_duplicates
+                    .is_some_and(|t| !(t > 0. && t <= 1.))
+                {
+                    exit(ConfigError::Invalid(
+                        "near-duplicates should be in (0, 1]".into(),
+                    ));
+                }
+                let (cleaned, report) = clean::clean(&text, args.by, &cleaning);
+                print!("{}", report.report());
+                let clean_out = args
+                    .clean_out
+                    .clone()
+                    .unwrap_or_else(|| args.dataset.with_extension("clean.txt"));
+                fs::write(&clean_out, &cleaned)?;
+                info!(
+                    "Wrote {} ({} characters)",
+                    clean_out.display(),
+                    cleaned.chars().count()
+                );
+                // The split is of the cleaned dataset
+                text = cleaned;
+            }
+            if let Some(val_fraction) = args.val_fraction {
+                if !(0.0..=1.0).contains(&val_fraction) {
+                    exit(ConfigError::Invalid(format!(
+                        "val-fraction ({}) should be in [0, 1]",
+                        val_fraction
+                    )));
+                }
+                let (train, val) = prepare::split(&text, args.by, val_fraction, args.seed);
+                let train_out = args
+                    .train_out
+                    .unwrap_or_else(|| args.dataset.with_extension("train.txt"));
+                let val_out = args
+                    .val_out
+                    .unwrap_or_else(|| args.dataset.with_extension("val.txt"));
+                fs::write(&train_out, &train)?;
+                fs::write(&val_out, &val)?;
+                info!(
+                    "Wrote {} ({} characters) and {} ({} characters)",
+                    train_out.display(),
+                    train.chars().count(),
+                    val_out.display(),
+                    val.chars().count()
+                );
+            }
+            Ok(())
+        }
+        Command::SoftTargets(args) => {
+            let config = args.config().unwrap_or_else(|e| exit(e));
+            let documents = config.load_documents().unwrap_or_else(|e| exit(e));
+            match config.tokenizer.kind {
+                TokenizerKind::Simple => {
+                    let tokenizer = load_model_tokenizer(&args.model)?;
+                    soft_targets(&args, &config, &documents, tokenizer)
+                }
+                TokenizerKind::Ascii => soft_targets(&args, &config, &documents, AsciiTokenizer),
+            }
+        }
+    }
+}
 
-    let training_state_path = Path::new("training_state.dat");
+/// Serves the model over HTTP, and over gRPC too when asked, reloading it when its
+/// training state gets rewritten if asked to
+#[cfg(all(feature = "server", not(feature = "gpu")))]
+fn run_server<T: Tokenizer + Send + Sync + 'static>(
+    args: &cli::ServeArgs,
+    config: &Config,
+    vocab_size: usize,
+    mut model: Model<AdamW, T>,
+) -> Result<(), GraphError> {
+    model.set_session_budget(args.session_budget << 20);
+    model.set_truncation(args.truncate);
+    model.set_limits(Limits {
+        requests_per_minute: args.rate_limit,
+        max_prompt_tokens: args.max_prompt_tokens,
+        max_tokens: args.max_tokens,
+        max_body_bytes: Some(args.max_body_kib << 10),
+        max_duration: args.max_duration,
+    });
+    let model = std::sync::Arc::new(model);
+    if args.watch {
+        let config = config.clone();
+        info!(path = %args.model.state.display(), "Watching the training state");
+        watch(
+            model.clone(),
+            args.model.state.clone(),
+            std::time::Duration::from_secs(2),
+            move |path| {
+                let mut gpt = new_gpt(&config, vocab_size)?;
+                load_training_state(&mut gpt, path)?;
+                Ok::<_, GraphError>(gpt)
+            },
+        );
+    }
+    if let Some(window) = args.batch_window {
+        info!(window_ms = window, "Batching the generations");
+        batch(&model, std::time::Duration::from_millis(window));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = args.grpc_addr.clone() {
+        let model = model.clone();
+        info!("Listening for gRPC on {}", addr);
+        std::thread::spawn(move || {
+            if let Err(e) = serve_grpc(&addr, model) {
+                tracing::error!("Unable to serve gRPC on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        });
+    }
+    info!("Listening on http://{}/v1/completions", args.addr);
+    serve(&args.addr, model, args.workers)?;
+    Ok(())
+}
 
-    let mut rng = rand::thread_rng();
+/// Reuses the vocabulary of an earlier run (If exists) so that token ids stay stable,
+/// otherwise creates a unique char-to-int mapping for all unique characters of `dataset`
+/// Vocab size planned for when neither the tokenizer nor the dataset is found
+#[cfg(not(feature = "gpu"))]
+const PLAN_VOCAB_SIZE: usize = 65;
 
-    // Create a unique char-to-int mapping for all unique characters inside our dataset
-    let dataset_char =
-        fs::read_to_string("dataset.txt").expect("Should have been able to read the file");
-    let tokenizer = SimpleTokenizer::new(&dataset_char);
+/// A corrupted or truncated file
+#[cfg(not(feature = "gpu"))]
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> GraphError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()
+}
+
+#[cfg(not(feature = "gpu"))]
+fn load_tokenizer(path: &Path, dataset: Option<&Path>) -> Result<SimpleTokenizer, GraphError> {
+    match dataset {
+        Some(dataset) if !path.is_file() => Ok(SimpleTokenizer::new(&fs::read_to_string(dataset)?)),
+        _ => {
+            let bytes = fs::read(path)?;
+            Ok(bincode::deserialize(&bytes).map_err(invalid_data)?)
+        }
+    }
+}
 
-    let dataset = tokenizer.tokenize(&dataset_char);
+/// The tokenizer of the model: the one in its bundle, or else the saved one
+#[cfg(not(feature = "gpu"))]
+fn load_model_tokenizer(model: &ModelArgs) -> Result<SimpleTokenizer, GraphError> {
+    if model.has_bundle() {
+        let bundle = Bundle::load(&model.state).map_err(invalid_data)?;
+        return bincode::deserialize(&bundle.tokenizer).map_err(invalid_data);
+    }
+    load_tokenizer(&model.tokenizer, None)
+}
 
-    let batch_size = 32;
+#[cfg(not(feature = "gpu"))]
+fn read_validation<T: Tokenizer + ?Sized>(
+    tokenizer: &T,
+    path: &Path,
+) -> Result<Vec<usize>, GraphError> {
+    // Characters out of the vocabulary are left out
+    let known = tokenizer.known_chars();
+    let text = fs::read_to_string(path)?
+        .chars()
+        .filter(|ch| known.contains(ch))
+        .collect::<String>();
+    Ok(tokenizer.tokenize(&text)?)
+}
+
+#[cfg(not(feature = "gpu"))]
+fn new_gpt(config: &Config, vocab_size: usize) -> Result<GPT<AdamW>, GraphError> {
+    let mut rng = seeded_rng(config.training.seed.unwrap_or_else(rand::random));
+    config
+        .model
+        .build(&mut rng, vocab_size, config.optimizer.build())
+}
+
+#[cfg(not(feature = "gpu"))]
+fn load_training_state(gpt: &mut GPT<AdamW>, path: &Path) -> Result<(), GraphError> {
+    let _span = info_span!("load", path = %path.display()).entered();
+    let timer = Instant::now();
+    let mut ts_file = fs::File::open(path)?;
+    let mut bytes = Vec::new();
+    ts_file.read_to_end(&mut bytes)?;
+    if shard::is_sharded(path) {
+        gpt.load_sharded(path).map_err(invalid_data)?;
+    } else if bundle::is_bundle(path) {
+        let bundle = Bundle::from_bytes(&bytes).map_err(invalid_data)?;
+        bundle.load_into(gpt).map_err(invalid_data)?;
+    } else {
+        let ts: TrainingState<AdamW> = bincode::deserialize(&bytes).map_err(invalid_data)?;
+        gpt.set_training_state(ts, true)?;
+    }
+    debug!(
+        bytes = bytes.len(),
+        elapsed_ms = timer.elapsed().as_millis() as u64,
+        "Loaded the training state"
+    );
+    Ok(())
+}
+
+/// Builds the model and loads its trained weights, failing if `tokenizer` isn't the one
+/// it was trained with (See `GPT::check_tokenizer`)
+#[cfg(not(feature = "gpu"))]
+fn load_gpt<T: Tokenizer + ?Sized>(
+    model: &ModelArgs,
+    config: &Config,
+    tokenizer: &T,
+) -> Result<GPT<AdamW>, GraphError> {
+    let mut gpt = new_gpt(config, tokenizer.vocab_size())?;
+    load_training_state(&mut gpt, &model.state)?;
+    gpt.check_tokenizer(tokenizer)?;
+    Ok(gpt)
+}
+
+#[cfg(not(feature = "gpu"))]
+fn infer<T: Tokenizer>(
+    args: &cli::InferArgs,
+    config: &Config,
+    draft_config: Option<&Config>,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let mut gpt = match args.stream {
+        true => config
+            .model
+            .build_streamed(
+                &args.model.state,
+                tokenizer.vocab_size(),
+                config.optimizer.build(),
+            )
+            .map_err(invalid_data)?,
+        false => load_gpt(&args.model, config, &tokenizer)?,
+    };
+    if let Some(context) = args.context {
+        let trained = gpt.num_tokens();
+        gpt = gpt.with_context_length(context)?;
+        if context > trained {
+            warn!(
+                trained,
+                context, "Generating past the trained context, which the model may not handle"
+            );
+        }
+    }
+    if args.sparse {
+        let count = gpt.set_sparse_products();
+        debug!(count, "Sparse products");
+    }
+    let mut prompt = tokenizer.tokenize(&args.prompt)?;
+    // With pinned tokens, the context rolls instead, keeping the start of the prompt
+    if args.pinned_tokens == 0 {
+        let fitted = gpt.fit_prompt(&prompt, args.max_tokens, args.truncate.unwrap_or_default());
+        if fitted.len() < prompt.len() {
+            warn!(
+                dropped = prompt.len() - fitted.len(),
+                kept = fitted.len(),
+                "The prompt is too long for the context, truncating it"
+            );
+            prompt = fitted;
+        }
+    }
+    let decoder = RefCell::new(StreamDecoder::new(&tokenizer));
+    let print = |ch| {
+        print!("{}", decoder.borrow_mut().push(ch).unwrap_or_default());
+        std::io::stdout().flush().unwrap();
+    };
+    // On Ctrl-C or after --max-duration, the generation stops with what it has
+    let mut cancel = CancellationToken::new();
+    if let Some(max_duration) = args.max_duration {
+        cancel = cancel.with_timeout(max_duration);
+    }
+    let handle = cancel.clone();
+    ctrlc::set_handler(move || handle.cancel()).expect("Unable to set the Ctrl-C handler");
+    let finish = || {
+        println!("{}", decoder.borrow_mut().finish());
+        if cancel.timed_out() {
+            warn!("Stopped after --max-duration");
+        } else if cancel.is_cancelled() {
+            warn!("Interrupted");
+        }
+    };
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let mut rng = seeded_rng(seed);
+    debug!(seed, "Sampling");
+    match (&args.draft, draft_config) {
+        (Some(path), Some(draft_config)) => {
+            let mut draft = new_gpt(draft_config, tokenizer.vocab_size())?;
+            load_training_state(&mut draft, path)?;
+            draft.check_tokenizer(&tokenizer)?;
+            prompt.iter().for_each(|ch| print(*ch));
+            let sampling = Sampling {
+                temperature: 1.,
+                top_p: args.temperature,
+            };
+            let (_, stats) = gpt.generate_speculative(
+                &draft,
+                &mut rng,
+                &prompt,
+                args.max_tokens,
+                args.draft_tokens,
+                &sampling,
+                |ch| {
+                    print(ch);
+                    !cancel.is_cancelled()
+                },
+            )?;
+            finish();
+            info!(
+                drafted = stats.drafted,
+                accepted = stats.accepted,
+                passes = stats.passes,
+                "Accepted {:.1}% of the drafted tokens",
+                stats.acceptance_rate() * 100.
+            );
+        }
+        _ if !args.ensemble.is_empty() => {
+            let mut ensemble = Ensemble::new();
+            ensemble.add(gpt, 1.)?;
+            for path in args.ensemble.iter() {
+                let config = Config::load(path.with_extension("toml")).map_err(invalid_data)?;
+                let mut member = new_gpt(&config, tokenizer.vocab_size())?;
+                load_training_state(&mut member, path)?;
+                member.check_tokenizer(&tokenizer)?;
+                ensemble.add(member, 1.)?;
+            }
+            info!(members = ensemble.len(), "Ensemble");
+            prompt.iter().for_each(|ch| print(*ch));
+            let sampling = Sampling {
+                temperature: 1.,
+                top_p: args.temperature,
+            };
+            ensemble.generate(&mut rng, &prompt, args.max_tokens, &sampling, |ch| {
+                print(ch);
+                !cancel.is_cancelled()
+            })?;
+            finish();
+        }
+        _ => {
+            let healing = match args.no_token_healing || args.only_chars.is_some() {
+                true => None,
+                false => heal_prompt(&tokenizer, &prompt)?,
+            };
+            if let Some(chars) = &args.only_chars {
+                let tokens = chars_mask(&tokenizer, chars)
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(t, allowed)| allowed.then_some(t))
+                    .collect::<Vec<_>>();
+                gpt.restrict_vocab(Some(&tokens))?;
+            }
+            gpt.set_pinned_tokens(args.pinned_tokens);
+            let mut constraint = |generated: &[usize], vocab_size: usize| match &healing {
+                // The first token completes the text of the one the prompt was backed up by
+                Some(healing) if generated.is_empty() => (0..vocab_size)
+                    .map(|t| healing.allowed.contains(&t))
+                    .collect(),
+                _ => vec![true; vocab_size],
+            };
+            let prompt = healing.as_ref().map_or(&prompt, |h| &h.prompt);
+            if let Some(healing) = &healing {
+                debug!(
+                    prefix = healing.prefix,
+                    candidates = healing.allowed.len(),
+                    "Token healing"
+                );
+            }
+            prompt.iter().for_each(|ch| print(*ch));
+            let sampling = Sampling {
+                temperature: 1.,
+                top_p: args.temperature,
+            };
+            let print = |ch| {
+                print(ch);
+                !cancel.is_cancelled()
+            };
+            match &args.trace {
+                Some(path) => {
+                    let mut trace = gpt.generate_traced(
+                        &mut rng,
+                        prompt,
+                        &mut constraint,
+                        args.max_tokens,
+                        &sampling,
+                        args.trace_top_k,
+                        print,
+                    )?;
+                    finish();
+                    trace.label(|t| tokenizer.untokenize(&[t]).ok());
+                    let json = serde_json::to_string_pretty(&trace).map_err(invalid_data)?;
+                    std::fs::write(path, json)?;
+                    info!(path = %path.display(), steps = trace.steps.len(), "Wrote the trace");
+                }
+                None => {
+                    gpt.generate_cancellable(
+                        &mut rng,
+                        prompt,
+                        &mut constraint,
+                        args.max_tokens,
+                        &sampling,
+                        &cancel,
+                        print,
+                    )?;
+                    finish();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the logits of the model over the dataset, for distilling it
+#[cfg(not(feature = "gpu"))]
+fn soft_targets<T: Tokenizer>(
+    args: &cli::SoftTargetsArgs,
+    config: &Config,
+    documents: &[(PathBuf, String)],
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let dataset = tokenize_documents(&tokenizer, documents, &config.training.document_separator)?;
+    let timer = Instant::now();
+    let soft_targets = SoftTargets::compute(&gpt, &dataset, gpt.num_tokens())?;
+    let mut bytes = Vec::new();
+    soft_targets.write(&mut bytes)?;
+    fs::write(&args.out, &bytes)?;
+    info!(
+        windows = soft_targets.num_windows(),
+        bytes = bytes.len(),
+        elapsed_ms = timer.elapsed().as_millis() as u64,
+        "Wrote {}",
+        args.out.display()
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "gpu"))]
+fn embed<T: Tokenizer>(
+    args: &cli::EmbedArgs,
+    config: &Config,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let texts = if args.texts.is_empty() {
+        std::io::stdin().lines().collect::<Result<Vec<_>, _>>()?
+    } else {
+        args.texts.clone()
+    };
+    // Characters outside of the vocabulary are dropped
+    let known = tokenizer.known_chars();
+    for text in texts {
+        let known_text = text
+            .chars()
+            .filter(|ch| known.contains(ch))
+            .collect::<String>();
+        let embedding =
+            gpt.embed_text(&tokenizer.tokenize(&known_text)?, args.layer, args.pooling)?;
+        println!(
+            "{}",
+            serde_json::json!({ "text": text, "embedding": embedding.blob() })
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "gpu"))]
+fn tape<T: Tokenizer>(
+    args: &cli::TapeArgs,
+    config: &Config,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let tokens = tokenizer.tokenize(&args.prompt)?;
+    let tape = gpt.record_tape(&tokens)?;
+    tape.save(&args.out)?;
+    info!(
+        steps = tape.steps.len(),
+        "Wrote the passes over {} tokens to {}",
+        tokens.len().min(gpt.num_tokens() + 1),
+        args.out.display()
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "gpu"))]
+fn attention<T: Tokenizer>(
+    args: &cli::AttentionArgs,
+    config: &Config,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let tokens = tokenizer.tokenize(&args.prompt)?;
+    let mut out = Vec::new();
+    gpt.export_attention_npz(&tokens, &mut out)?;
+    fs::write(&args.out, out)?;
+    info!(
+        "Wrote the attention weights over {} tokens to {}",
+        tokens.len().min(gpt.num_tokens()),
+        args.out.display()
+    );
+    Ok(())
+}
+
+/// Writes the token embeddings and their labels as TSV, and their projection on the
+/// principal components if asked to
+#[cfg(not(feature = "gpu"))]
+fn export_projector<T: Tokenizer>(
+    args: &cli::ProjectorArgs,
+    config: &Config,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let embeddings = gpt.token_embeddings()?;
+    let labels = projector::labels(&tokenizer)?;
+    fs::write(&args.vectors, projector::vectors_tsv(&embeddings))?;
+    fs::write(&args.metadata, projector::metadata_tsv(&labels))?;
+    info!(
+        "Wrote the embeddings of {} tokens to {} and {}",
+        labels.len(),
+        args.vectors.display(),
+        args.metadata.display()
+    );
+    if let Some(path) = &args.pca {
+        let (points, explained) = projector::pca(&embeddings, args.dims)?;
+        fs::write(path, projector::projection_tsv(&labels, &points))?;
+        info!(
+            "Wrote the projection to {} ({:.1}% of the variance explained)",
+            path.display(),
+            explained.iter().sum::<f32>() * 100.
+        );
+    }
+    Ok(())
+}
+
+/// Prints the validation perplexity of the model pruned to each of the sparsities,
+/// lowest first (Each pruning only zeroes more of the weights)
+#[cfg(not(feature = "gpu"))]
+fn prune<T: Tokenizer>(
+    args: &cli::PruneArgs,
+    config: &Config,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let mut gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let tokens = read_validation(&tokenizer, &args.validation)?;
+    info!(
+        "Perplexity: {:.3} (Unpruned)",
+        eval::perplexity(&gpt, &tokens)?
+    );
+    let mut sparsities = args.sparsity.clone();
+    sparsities.sort_by(f32::total_cmp);
+    for sparsity in sparsities {
+        let stats = gpt.prune(sparsity)?;
+        info!(
+            "Perplexity: {:.3} ({:.1}% pruned)",
+            eval::perplexity(&gpt, &tokens)?,
+            stats.sparsity() * 100.
+        );
+    }
+    Ok(())
+}
+
+/// Saves the outputs of the model for a battery of prompts as the reference, or compares
+/// them with the reference ones
+#[cfg(not(feature = "gpu"))]
+fn quality<T: Tokenizer>(
+    args: &cli::QualityArgs,
+    config: &Config,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let checkpoint = xxh64(&fs::read(&args.model.state)?, 0);
+    if args.save {
+        let prompts = match &args.prompts {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(|l| l.replace("\\n", "\n"))
+                .collect(),
+            None => quality::DEFAULT_PROMPTS.map(String::from).to_vec(),
+        };
+        let settings = quality::Reference {
+            checkpoint: String::new(),
+            step: 0,
+            seed: args.seed,
+            max_tokens: args.max_tokens,
+            temperature: args.temperature,
+            top_p: 1.,
+            outputs: Vec::new(),
+        };
+        let reference = quality::generate(&gpt, &tokenizer, &prompts, &settings, checkpoint)?;
+        let json = serde_json::to_string_pretty(&reference).map_err(invalid_data)?;
+        fs::write(&args.reference, json)?;
+        info!(
+            path = %args.reference.display(),
+            prompts = reference.outputs.len(),
+            "Saved the reference outputs"
+        );
+        return Ok(());
+    }
+    let reference: quality::Reference =
+        serde_json::from_slice(&fs::read(&args.reference)?).map_err(invalid_data)?;
+    let (_, comparisons) = quality::compare(&gpt, &tokenizer, &reference, checkpoint)?;
+    for c in comparisons.iter() {
+        println!(
+            "{:<24} similarity {:.3}{}, perplexity {:.3} (Reference: {:.3})",
+            format!("{:?}", c.prompt),
+            c.similarity,
+            if c.exact { " (Exact)" } else { "" },
+            c.perplexity,
+            c.reference_perplexity
+        );
+    }
+    let n = comparisons.len().max(1) as f32;
+    let similarity = comparisons.iter().map(|c| c.similarity).sum::<f32>() / n;
+    let perplexity = comparisons.iter().map(|c| c.perplexity).sum::<f32>() / n;
+    let reference_perplexity = comparisons
+        .iter()
+        .map(|c| c.reference_perplexity)
+        .sum::<f32>()
+        / n;
+    info!(
+        reference_step = reference.step,
+        step = gpt.optimizer().step_num(),
+        exact = comparisons.iter().filter(|c| c.exact).count(),
+        prompts = comparisons.len(),
+        "Similarity {:.3}, perplexity on the reference outputs {:.3} (Reference: {:.3})",
+        similarity,
+        perplexity,
+        reference_perplexity
+    );
+    if let Some(min) = args.min_similarity {
+        if similarity < min {
+            return Err(invalid_data(format!(
+                "the similarity to the reference outputs ({:.3}) is below {}",
+                similarity, min
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Prints a row per transformer block, with the token it predicts after each token
+/// of the prompt and its probability
+#[cfg(not(feature = "gpu"))]
+fn lens<T: Tokenizer>(
+    args: &cli::LensArgs,
+    config: &Config,
+    tokenizer: T,
+) -> Result<(), GraphError> {
+    let gpt = load_gpt(&args.model, config, &tokenizer)?;
+    let tokens = tokenizer.tokenize(&args.prompt)?;
+    let lens = gpt.logit_lens(&tokens)?;
+    let (n, vocab_size) = (lens.shape()[1], lens.shape()[2]);
+    let show = |tkn: usize| format!("{:?}", tokenizer.untokenize(&[tkn]).unwrap_or_default());
+    let mut rows = vec![std::iter::once(String::new())
+        .chain(tokens[tokens.len() - n..].iter().map(|t| show(*t)))
+        .collect::<Vec<_>>()];
+    for (l, logits) in lens.blob().chunks(n * vocab_size).enumerate() {
+        let mut row = vec![format!("layer {}", l)];
+        for logits in logits.chunks(vocab_size) {
+            let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+            let sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>();
+            let best = (0..vocab_size).fold(0, |a, i| if logits[i] > logits[a] { i } else { a });
+            row.push(format!("{} {:.2}", show(best), 1. / sum));
+        }
+        rows.push(row);
+    }
+    let widths = (0..=n)
+        .map(|j| rows.iter().map(|r| r[j].chars().count()).max().unwrap())
+        .collect::<Vec<_>>();
+    for row in rows {
+        let cells = row
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect::<Vec<_>>();
+        println!("{}", cells.join("  ").trim_end());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "gpu"))]
+fn train<T: Tokenizer + Serialize>(
+    model: &ModelArgs,
+    config: &Config,
+    tokenizer: T,
+    grown_from: Option<usize>,
+    documents: &[(PathBuf, String)],
+    show_dashboard: bool,
+) -> Result<(), GraphError> {
+    let training_state_path = model.state.as_path();
+    let tokenizer_path = model.tokenizer.as_path();
+    let config_path = model.saved_config();
+
+    let dataset = tokenize_documents(&tokenizer, documents, &config.training.document_separator)?;
 
-    let num_tokens = 64;
     let vocab_size = tokenizer.vocab_size();
-    let embedding_degree = 64;
-    let num_layers = 4;
-    let num_heads = 4;
-    let head_size = embedding_degree / num_heads;
-    let dropout = 0.0;
-
-    assert_eq!(num_heads * head_size, embedding_degree);
-
-    println!("Vocab-size: {} unique characters", vocab_size);
-
-    let mut gpt = GPT::new(
-        &mut rng,
-        vocab_size,
-        embedding_degree,
-        num_tokens,
-        num_layers,
-        num_heads,
-        head_size,
-        dropout,
-        AdamW::new(),
-    )?;
-
-    println!("Number of parameters: {}", gpt.num_params());
-
-    // Load training data from train_data directory (If exists)
-    // If you want to reuse training_data of a smaller model in a bigger model, you may
-    // first start again with a new optimizer by setting load_optimizer=false
+
+    info!("Vocab-size: {} unique characters", vocab_size);
+
+    let mut gpt = new_gpt(config, vocab_size)?;
+
+    info!("Number of parameters: {}", gpt.num_params());
+
+    // Load the training state of an earlier run (If exists)
     // WARN: YOU CAN ONLY REUSE THE WEIGHTS OF A MODEL WITH DIFFERENT NUM-LAYERS!
     // IT'S NOT POSSIBLE TO CHANGE OTHER PROPERTIES ONCE THE MODEL IS TRAINED!
+    // (Through a model of the vocabulary it was trained with, if extended since)
+    let provenance = Provenance {
+        dataset_hash: Some(run::dataset_hash(documents)),
+        tokenizer_hash: Some(run::tokenizer_hash(&tokenizer)),
+    };
     if training_state_path.is_file() {
-        let mut ts_file = fs::File::open(training_state_path).unwrap();
-        let mut bytes = Vec::new();
-        ts_file.read_to_end(&mut bytes).unwrap();
-        let ts: TrainingState<AdamW> = bincode::deserialize(&bytes).unwrap();
-        gpt.set_training_state(ts, true)?;
+        match grown_from {
+            Some(old_vocab_size) => {
+                let mut old = new_gpt(config, old_vocab_size)?;
+                load_training_state(&mut old, training_state_path)?;
+                let mut state = old.get_training_state()?;
+                state.grow_vocab(&mut rand::thread_rng(), vocab_size)?;
+                gpt.set_training_state(state, true)?;
+            }
+            None => {
+                load_training_state(&mut gpt, training_state_path)?;
+                // Unless extended on purpose, the tokenizer is the one of the checkpoint
+                gpt.check_tokenizer(&tokenizer)?;
+            }
+        }
+        if let Some(previous) = gpt.provenance().dataset_hash {
+            if Some(previous) != provenance.dataset_hash {
+                warn!(
+                    previous = %format!("{:016x}", previous),
+                    "The dataset differs from the one the checkpoint was trained on"
+                );
+            }
+        }
     }
+    gpt.set_provenance(provenance);
+
+    // On Ctrl-C, finish the current step and save everything before exiting
+    let interrupt = gpt.interrupt_handle();
+    ctrlc::set_handler(move || {
+        warn!("Interrupted! Finishing the current step...");
+        interrupt.store(true, std::sync::atomic::Ordering::SeqCst);
+    })
+    .expect("Unable to set the Ctrl-C handler");
+
+    let run = RunInfo::new(config, documents, &tokenizer);
+    info!(
+        run = %run.id,
+        dataset_hash = %run.dataset_hash,
+        tokenizer_hash = run.tokenizer_hash.as_deref().unwrap_or("unknown"),
+        git = run.git.as_deref().unwrap_or("unknown"),
+        "Run"
+    );
 
-    println!();
-    println!("Starting the training loop... (This make take hours to converge! be patient!)");
-    println!();
-
-    let base_lr = 0.001;
-    let min_lr = 0.00001;
-    let warmup_steps = 100;
-    let decay_steps = 50000;
-
-    // Training loop!
-    gpt.train(
-        &dataset,
-        100000,
-        batch_size,
-        None, // or Some(n), limit backward process to last n computations
-        |step| {
-            if step < warmup_steps {
-                (base_lr / warmup_steps as f32) * step as f32
+    // The config (And the run) is saved along the model, so that it can be reused to
+    // resume the training or to run the model
+    let writer = AsyncWriter::new();
+    let save = |gpt: &GPT<AdamW>, tokenizer: &T| {
+        let _span = info_span!("save", path = %training_state_path.display()).entered();
+        let timer = Instant::now();
+        let tokenizer_bytes = bincode::serialize(tokenizer).unwrap();
+        let save_along = || {
+            write_atomic(tokenizer_path, &tokenizer_bytes).expect("Unable to write file");
+            config.save(&config_path).expect("Unable to write file");
+            run.save(RunInfo::path_of(training_state_path))
+                .expect("Unable to write file");
+        };
+        if shard::is_sharded(training_state_path) {
+            // Written in place, one shard at a time
+            gpt.save_sharded(training_state_path, model.shards, true)
+                .expect("Unable to save the shards");
+            save_along();
+            info!(
+                elapsed_ms = timer.elapsed().as_millis() as u64,
+                "Saved the model"
+            );
+            return;
+        }
+        // Only the snapshot is taken here, it may be serialized and written meanwhile
+        let ts = gpt.get_training_state().unwrap();
+        let serialize: Box<dyn FnOnce() -> Result<Vec<u8>, ConfigError> + Send> =
+            if bundle::is_bundle(training_state_path) {
+                // Everything goes in the one file
+                let (config, run) = (config.clone(), run.clone());
+                Box::new(move || {
+                    Bundle {
+                        run: Some(run),
+                        ..Bundle::from_state(config, tokenizer_bytes, ts, true)?
+                    }
+                    .to_bytes()
+                })
             } else {
-                // Fancy LR tuning, thanks to https://github.com/cutoken!
-                f32::max(
-                    min_lr,
-                    base_lr
-                        - (base_lr - min_lr) * (step - warmup_steps) as f32 / decay_steps as f32,
-                )
+                save_along();
+                Box::new(move || Ok(bincode::serialize(&ts)?))
+            };
+        if config.training.async_checkpoints {
+            writer
+                .write(training_state_path, serialize)
+                .expect("Unable to write the previous checkpoint");
+            debug!(
+                elapsed_ms = timer.elapsed().as_millis() as u64,
+                "Saving the model in the background"
+            );
+        } else {
+            let bytes = serialize().expect("Unable to serialize the model");
+            write_atomic(training_state_path, &bytes).expect("Unable to write file");
+            info!(
+                bytes = bytes.len(),
+                elapsed_ms = timer.elapsed().as_millis() as u64,
+                "Saved the model"
+            );
+        }
+    };
+
+    struct Callback<'a, T: Tokenizer, S: Fn(&GPT<AdamW>, &T)> {
+        tokenizer: &'a T,
+        scheduler: Scheduler,
+        scheduler_path: PathBuf,
+        validation: Option<Vec<usize>>,
+        stride: Option<usize>,
+        save: S,
+        histograms: Option<HistogramCsv>,
+        metrics: Option<MetricsLog>,
+        #[cfg(feature = "tui")]
+        dashboard: Option<dashboard::Dashboard>,
+    }
+
+    impl<T: Tokenizer, S: Fn(&GPT<AdamW>, &T)> Callback<'_, T, S> {
+        fn save(&self, gpt: &GPT<AdamW>) -> Result<(), GraphError> {
+            (self.save)(gpt, self.tokenizer);
+            if self.scheduler.config.kind == ScheduleKind::Plateau {
+                self.scheduler
+                    .save(&self.scheduler_path)
+                    .map_err(invalid_data)?;
             }
-        },
-        |gpt| {
+            Ok(())
+        }
+    }
+
+    impl<T: Tokenizer, S: Fn(&GPT<AdamW>, &T)> TrainCallback<AdamW> for Callback<'_, T, S> {
+        fn learning_rate(&mut self, step: usize) -> f32 {
+            self.scheduler.learning_rate(step)
+        }
+        fn on_step(&mut self, ctx: &TrainContext<AdamW>) -> Result<(), GraphError> {
+            info!(
+                step = ctx.stats.step,
+                loss = ctx.stats.loss,
+                accuracy = ctx.stats.accuracy.top1_rate(),
+                top5_accuracy = ctx.stats.accuracy.top5_rate(),
+                grad_norm = ctx.stats.grad_norm,
+                elapsed_ms = ctx.stats.elapsed.as_millis() as u64,
+                tokens_per_second = ctx.stats.tokens_per_second.round(),
+                eta_minutes = ctx.eta.map_or(0, |eta| eta.as_secs() / 60),
+                "Step"
+            );
+            if let Some(metrics) = &mut self.metrics {
+                metrics.log(ctx.stats)?;
+            }
+            #[cfg(feature = "tui")]
+            if let Some(dashboard) = &mut self.dashboard {
+                dashboard.state.update(ctx);
+                if dashboard.stop_requested()? {
+                    ctx.gpt
+                        .interrupt_handle()
+                        .store(true, std::sync::atomic::Ordering::SeqCst);
+                    dashboard.state.status = "Stopping after this step...".into();
+                }
+                dashboard.draw()?;
+            }
+            Ok(())
+        }
+        fn on_eval(&mut self, ctx: &TrainContext<AdamW>) -> Result<(), GraphError> {
+            // Ratios far above 1e-3 hint at a too-large learning rate, far below
+            // at a too-small one (or at a dead layer)
+            let max_ratio =
+                ctx.stats
+                    .update_ratios
+                    .iter()
+                    .fold(("", 0.), |a, (n, r)| if *r > a.1 { (n, *r) } else { a });
+            info!(
+                step = ctx.stats.step,
+                max_update_ratio = max_ratio.1,
+                param = max_ratio.0,
+                "Eval"
+            );
+            if let Some(batch_size) = ctx.stats.noise_scale {
+                info!(
+                    step = ctx.stats.step,
+                    suggested_batch_size = batch_size.round(),
+                    "Gradient noise scale"
+                );
+            }
+            if let Some(epsilon) = ctx.stats.epsilon {
+                let delta = ctx.gpt.dp_sgd().map_or(0., |dp| dp.delta);
+                info!(step = ctx.stats.step, epsilon, delta, "Privacy spent");
+            }
+            if let Some(tokens) = &self.validation {
+                let stride = self.stride.unwrap_or(ctx.gpt.num_tokens());
+                let loss = eval::strided_perplexity(ctx.gpt, tokens, stride)?.ln();
+                info!(step = ctx.stats.step, val_loss = loss, "Validation");
+                if self.scheduler.observe(loss) {
+                    info!(
+                        step = ctx.stats.step,
+                        learning_rate = self.scheduler.learning_rate(ctx.stats.step),
+                        "The validation loss stopped improving, lowered the learning rate"
+                    );
+                }
+            }
+            if let Some(csv) = &mut self.histograms {
+                csv.write(
+                    ctx.stats.step,
+                    &ctx.gpt.histograms(histogram::DEFAULT_BINS)?,
+                )?;
+            }
+
             let mut rng = rand::thread_rng();
             let inference_temperature = 0.5; // How creative? 0.0 min 1.0 max
 
-            println!("Generating text:");
-
-            let inference = gpt.infer(
+            let inference = ctx.gpt.infer(
                 &mut rng,
-                &tokenizer.tokenize("\n"),
+                &self.tokenizer.tokenize("\n")?,
                 200,
                 inference_temperature,
                 |_ch| {},
             )?;
 
-            // Generate 100 character with the currently trained model before
-            // starting the training loop.
-            println!("{}", tokenizer.untokenize(&inference));
+            let sample = self.tokenizer.untokenize(&inference)?;
+            info!("Generated text:\n{}", sample);
+            #[cfg(feature = "tui")]
+            if let Some(dashboard) = &mut self.dashboard {
+                dashboard.state.sample = sample;
+            }
+            Ok(())
+        }
+        fn on_checkpoint(&mut self, ctx: &TrainContext<AdamW>) -> Result<(), GraphError> {
+            self.save(ctx.gpt)
+        }
+        fn on_stop(&mut self, summary: &TrainSummary<AdamW>) -> Result<(), GraphError> {
+            // Whatever stopped the training, none of the last steps gets lost
+            self.save(summary.gpt)?;
+            if summary.reason == StopReason::Interrupted {
+                info!(
+                    "Training interrupted at step {}. Run `femto-gpt train` again to resume.",
+                    summary.gpt.optimizer().step_num()
+                );
+            }
+            Ok(())
+        }
+        fn on_rewind(&mut self, ctx: &TrainContext<AdamW>) -> Result<(), GraphError> {
+            warn!(
+                "Loss spike at step {} (Loss: {}), rewound to the last good checkpoint",
+                ctx.stats.step, ctx.stats.loss
+            );
+            #[cfg(feature = "tui")]
+            if let Some(dashboard) = &mut self.dashboard {
+                dashboard.state.status = format!(
+                    "Loss spike at step {} ({:.4}), rewound",
+                    ctx.stats.step, ctx.stats.loss
+                );
+            }
+            Ok(())
+        }
+    }
 
-            println!("Saving the model...");
-            let ts = gpt.get_training_state().unwrap();
-            let bytes = bincode::serialize(&ts).unwrap();
-            fs::write(training_state_path, &bytes).expect("Unable to write file");
+    info!("Starting the training loop... (This make take hours to converge! be patient!)");
 
-            Ok(())
+    #[cfg(not(feature = "tui"))]
+    let _ = show_dashboard;
+
+    // Plateau schedules pick up where they were, when resuming
+    let scheduler_path = Scheduler::path_of(training_state_path);
+    let scheduler = match Scheduler::load(&scheduler_path) {
+        Ok(saved) if training_state_path.is_file() => Scheduler {
+            config: config.scheduler.clone(),
+            ..saved
         },
-    )?;
+        _ => Scheduler::new(config.scheduler.clone()),
+    };
+    let validation = match &config.training.validation {
+        Some(path) => Some(read_validation(&tokenizer, path)?),
+        None => None,
+    };
+    // When resuming, what was logged after the checkpoint is logged again
+    let resumed_step = gpt.optimizer().step_num();
+    let rotation = &config.training.log_rotation;
+    let mut callback = Callback {
+        tokenizer: &tokenizer,
+        scheduler,
+        scheduler_path,
+        validation,
+        stride: config.training.stride,
+        save,
+        histograms: config
+            .training
+            .histograms
+            .as_ref()
+            .map(|path| match resumed_step {
+                0 => HistogramCsv::new(path, rotation),
+                step => HistogramCsv::resume(path, rotation, step),
+            })
+            .transpose()?,
+        metrics: config
+            .training
+            .metrics
+            .as_ref()
+            .map(|path| match resumed_step {
+                0 => MetricsLog::new(path, &run, rotation),
+                step => MetricsLog::resume(path, &run, rotation, step),
+            })
+            .transpose()?,
+        #[cfg(feature = "tui")]
+        dashboard: show_dashboard.then(dashboard::Dashboard::new),
+    };
+    train_gpt(&mut gpt, config, &dataset, &mut callback).map_err(|e| match e {
+        ConfigError::GraphError(e) => e,
+        e => invalid_data(e),
+    })?;
+    // The last checkpoint may still be being written
+    writer.wait().map_err(invalid_data)?;
 
     Ok(())
 }
@@ -1,9 +1,10 @@
 use serde::{Deserialize, Serialize};
 
-use crate::tensor::{Tensor, TensorError, TensorOps};
+use crate::tensor::{Tensor, TensorError, TensorMutOps, TensorOps};
 use rayon::prelude::*;
+use std::ops::Range;
 
-pub trait Optimizer: Clone + Serialize + serde::de::DeserializeOwned {
+pub trait Optimizer: Clone + Serialize + serde::de::DeserializeOwned + Send + Sync {
     fn step_num(&self) -> usize;
     fn step(
         &mut self,
@@ -11,16 +12,66 @@ pub trait Optimizer: Clone + Serialize + serde::de::DeserializeOwned {
         grads: Vec<&Tensor<f32>>,
         learning_rate: f32,
     ) -> Result<(), TensorError>;
+    /// How weight decay is applied, and to which of the parameters given to `step`
+    /// (In order): all of them when `mask` is empty. Optimizers without weight decay
+    /// ignore it.
+    fn set_weight_decay(&mut self, _mode: DecayMode, _mask: Vec<bool>) {}
+    /// Factors of the learning rate of each of the parameters given to `step` (In
+    /// order): 1 for all of them when `scales` is empty
+    fn set_lr_scales(&mut self, _scales: Vec<f32>) {}
+    /// Rows outside of which the gradients of the parameters given to `step` (In order)
+    /// are zero, for the next step: None for the dense ones. Optimizers may leave the
+    /// other rows as they are.
+    fn set_sparse_rows(&mut self, _rows: Vec<Option<Vec<usize>>>) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecayMode {
+    /// The weights are shrunk by `learning_rate * weight_decay` apart from the
+    /// gradient step, as in PyTorch's `AdamW`
+    #[default]
+    Decoupled,
+    /// `weight_decay * weight` is added to the gradient (L2 regularization), so it goes
+    /// through the adaptive scaling of Adam too, as in PyTorch's `Adam(weight_decay=..)`
+    L2,
+}
+
+/// How weight decay is applied, and to which parameters
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WeightDecay {
+    pub mode: DecayMode,
+    /// Parameters whose names contain any of these aren't decayed (E.g. `bias` and
+    /// `norm` for the biases and the layer normalizations)
+    pub no_decay: Vec<String>,
+}
+
+impl WeightDecay {
+    pub fn applies_to(&self, name: &str) -> bool {
+        !self.no_decay.iter().any(|p| name.contains(p.as_str()))
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Naive {
     t: usize,
+    /// Not saved (See `GPT::set_layer_decay`)
+    #[serde(skip)]
+    lr_scales: Vec<f32>,
+}
+
+impl Default for Naive {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Naive {
     pub fn new() -> Self {
-        Self { t: 0 }
+        Self {
+            t: 0,
+            lr_scales: Vec::new(),
+        }
     }
 }
 
@@ -36,11 +87,15 @@ impl Optimizer for Naive {
         grads: Vec<&Tensor<f32>>,
         learning_rate: f32,
     ) -> Result<(), TensorError> {
-        for (param, grad) in params.into_iter().zip(grads.into_iter()) {
-            *param = (&*param + &(grad * &Tensor::scalar(-learning_rate))?)?;
+        for (i, (param, grad)) in params.into_iter().zip(grads).enumerate() {
+            let scale = self.lr_scales.get(i).copied().unwrap_or(1.);
+            param.scaled_add(-learning_rate * scale, grad)?;
         }
         Ok(())
     }
+    fn set_lr_scales(&mut self, scales: Vec<f32>) {
+        self.lr_scales = scales;
+    }
 }
 
 const EPSILON: f32 = 1e-8;
@@ -53,17 +108,41 @@ pub struct AdamW {
     m: Vec<Tensor<f32>>,
     v: Vec<Tensor<f32>>,
     t: usize,
+    /// Not saved, training states of earlier versions having none: set again before
+    /// every step (See `GPT::set_weight_decay` and `GPT::set_layer_decay`)
+    #[serde(skip)]
+    decay_mode: DecayMode,
+    #[serde(skip)]
+    decay_mask: Vec<bool>,
+    #[serde(skip)]
+    lr_scales: Vec<f32>,
+    /// Only for the next step
+    #[serde(skip)]
+    sparse_rows: Vec<Option<Vec<usize>>>,
+}
+
+impl Default for AdamW {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AdamW {
     pub fn new() -> Self {
+        Self::with_hyperparams(0.9, 0.999, 0.01)
+    }
+    pub fn with_hyperparams(beta1: f32, beta2: f32, weight_decay: f32) -> Self {
         Self {
-            beta1: 0.9,
-            beta2: 0.999,
-            weight_decay: 0.01,
+            beta1,
+            beta2,
+            weight_decay,
             m: Default::default(),
             v: Default::default(),
             t: 0,
+            decay_mode: DecayMode::Decoupled,
+            decay_mask: Vec::new(),
+            lr_scales: Vec::new(),
+            sparse_rows: Vec::new(),
         }
     }
 }
@@ -80,36 +159,168 @@ impl Optimizer for AdamW {
         grads: Vec<&Tensor<f32>>,
         learning_rate: f32,
     ) -> Result<(), TensorError> {
-        if self.m.len() == 0 || self.v.len() == 0 {
+        if self.m.len() != params.len() || self.v.len() != params.len() {
             self.m = vec![Tensor::scalar(0.); params.len()];
             self.v = vec![Tensor::scalar(0.); params.len()];
         }
+        let m_correction = 1. / (1. - self.beta1.powi(self.t as i32 + 1));
+        let v_correction = 1. / (1. - self.beta2.powi(self.t as i32 + 1));
+        let sparse_rows = std::mem::take(&mut self.sparse_rows);
+        let groups = (0..params.len())
+            .map(|i| {
+                let decays = self.decay_mask.get(i).copied().unwrap_or(true);
+                let lr_scale = self.lr_scales.get(i).copied().unwrap_or(1.);
+                let rows = sparse_rows.get(i).cloned().flatten();
+                (decays, learning_rate * lr_scale, rows)
+            })
+            .collect::<Vec<_>>();
         params
             .into_par_iter()
             .zip(grads.into_par_iter())
             .zip(self.m.par_iter_mut())
             .zip(self.v.par_iter_mut())
-            .map(|(((param, grad), m), v)| {
-                // Weight decay
-                *param =
-                    (&*param - &(&*param * &Tensor::scalar(learning_rate * self.weight_decay))?)?;
-
-                *m = (&(&Tensor::scalar(self.beta1) * &*m)?
-                    + &(&Tensor::scalar(1. - self.beta1) * grad)?)?;
-                *v = (&(&Tensor::scalar(self.beta2) * &*v)?
-                    + &(&(&Tensor::scalar(1. - self.beta2) * grad)? * grad)?)?;
-                let m_hat =
-                    (&*m * &Tensor::scalar(1. / (1. - self.beta1.powi(self.t as i32 + 1))))?;
-                let v_hat =
-                    (&*v * &Tensor::scalar(1. / (1. - self.beta2.powi(self.t as i32 + 1))))?;
-
-                let v_hat_sqrt_inv = v_hat.map_values(|f| learning_rate / (f.sqrt() + EPSILON));
-
-                *param = (&*param - &(&m_hat * &v_hat_sqrt_inv)?)?;
+            .zip(groups.into_par_iter())
+            .map(|((((param, grad), m), v), (decays, learning_rate, rows))| {
+                // Moments are allocated lazily, with the shapes of the parameters
+                if m.shape() != param.shape() || v.shape() != param.shape() {
+                    *m = Tensor::zeros(param.shape());
+                    *v = Tensor::zeros(param.shape());
+                }
+                if grad.shape() != param.shape() {
+                    return Err(TensorError::shape_mismatch(
+                        "AdamW",
+                        param.shape(),
+                        grad.shape(),
+                    ));
+                }
+
+                // Sparse gradients only update their rows, moments included
+                let width = param.shape().last().copied().unwrap_or(1).max(1);
+                let ranges: Vec<Range<usize>> = match rows {
+                    Some(rows) => rows
+                        .into_iter()
+                        .map(|r| r * width..((r + 1) * width).min(param.size()))
+                        .collect(),
+                    None => std::iter::once(0..param.size()).collect(),
+                };
+
+                let weight_decay = if decays { self.weight_decay } else { 0. };
+                let (p, g, m, v) = (param.blob_mut(), grad.blob(), m.blob_mut(), v.blob_mut());
+                for i in ranges.into_iter().flatten() {
+                    // Weight decay
+                    let g = match self.decay_mode {
+                        DecayMode::Decoupled => {
+                            p[i] *= 1. - learning_rate * weight_decay;
+                            g[i]
+                        }
+                        DecayMode::L2 => g[i] + weight_decay * p[i],
+                    };
+
+                    m[i] = m[i] * self.beta1 + (1. - self.beta1) * g;
+                    v[i] = self.beta2 * v[i] + (1. - self.beta2) * g * g;
+
+                    let m_hat = m[i] * m_correction;
+                    let v_hat = v[i] * v_correction;
+                    p[i] -= learning_rate * m_hat / (v_hat.sqrt() + EPSILON);
+                }
                 Ok(())
             })
             .collect::<Result<Vec<()>, TensorError>>()?;
         self.t += 1;
         Ok(())
     }
+    fn set_weight_decay(&mut self, mode: DecayMode, mask: Vec<bool>) {
+        self.decay_mode = mode;
+        self.decay_mask = mask;
+    }
+    fn set_lr_scales(&mut self, scales: Vec<f32>) {
+        self.lr_scales = scales;
+    }
+    fn set_sparse_rows(&mut self, rows: Vec<Option<Vec<usize>>>) {
+        self.sparse_rows = rows;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `steps` steps of minimizing `(p - 1)^2` for each parameter, with a weight
+    /// decay of 0.5 applied as `mode` (But not to the second parameter)
+    fn toy(mode: DecayMode, steps: usize) -> Vec<f32> {
+        let mut opt = AdamW::with_hyperparams(0.9, 0.999, 0.5);
+        let mut params = [Tensor::scalar(3.), Tensor::scalar(3.)];
+        for _ in 0..steps {
+            opt.set_weight_decay(mode, vec![true, false]);
+            let grads = params
+                .iter()
+                .map(|p| p.map_values(|p| 2. * (p - 1.)))
+                .collect::<Vec<_>>();
+            opt.step(params.iter_mut().collect(), grads.iter().collect(), 0.01)
+                .unwrap();
+        }
+        params.iter().map(|p| p.blob()[0]).collect()
+    }
+
+    #[test]
+    fn test_weight_decay() {
+        let decoupled = toy(DecayMode::Decoupled, 1);
+        let l2 = toy(DecayMode::L2, 1);
+        // The first Adam step moves each parameter by the learning rate, whatever the
+        // gradient: decoupled decay shrinks the weight on top of that, L2 doesn't
+        assert!((decoupled[0] - (3. * (1. - 0.01 * 0.5) - 0.01)).abs() < 1e-5);
+        assert!((l2[0] - (3. - 0.01)).abs() < 1e-5);
+        assert_eq!(decoupled[1], l2[1]);
+        assert!((decoupled[1] - 2.99).abs() < 1e-5);
+
+        // Minimizing (p - 1)^2 + 0.25 p^2 (L2) gives 0.8, while decoupled decay pulls
+        // the weights towards 0 with a strength independent of the gradient scale
+        let decoupled = toy(DecayMode::Decoupled, 3000);
+        let l2 = toy(DecayMode::L2, 3000);
+        assert!((l2[0] - 0.8).abs() < 0.02);
+        assert!((decoupled[0] - 0.8).abs() > 0.05);
+        assert!(decoupled[0] < 1. && decoupled[0] > 0.);
+        // Undecayed parameters reach the minimum in both
+        assert!((decoupled[1] - 1.).abs() < 0.02 && (l2[1] - 1.).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_sparse_rows() {
+        let mut dense = AdamW::new();
+        let mut sparse = AdamW::new();
+        let mut a = Tensor::raw(&[3, 2], vec![1.; 6]).unwrap();
+        let mut b = a.clone();
+        let grad = Tensor::raw(&[3, 2], vec![0., 0., 1., 2., 0., 0.]).unwrap();
+        dense.step(vec![&mut a], vec![&grad], 0.1).unwrap();
+        sparse.set_sparse_rows(vec![Some(vec![1])]);
+        sparse.step(vec![&mut b], vec![&grad], 0.1).unwrap();
+        // The untouched rows aren't even decayed
+        assert_eq!(&b.blob()[..2], &[1., 1.]);
+        assert_eq!(&b.blob()[4..], &[1., 1.]);
+        assert!(a.blob()[0] < 1.);
+        assert_eq!(&a.blob()[2..4], &b.blob()[2..4]);
+        // The rows are for the next step only
+        sparse.step(vec![&mut b], vec![&grad], 0.1).unwrap();
+        assert!(b.blob()[0] < 1.);
+    }
+
+    #[test]
+    fn test_lr_scales() {
+        let mut opt = AdamW::with_hyperparams(0.9, 0.999, 0.);
+        let mut params = [Tensor::scalar(3.), Tensor::scalar(3.)];
+        let grads = [Tensor::scalar(1.), Tensor::scalar(1.)];
+        opt.set_lr_scales(vec![1., 0.5]);
+        opt.step(params.iter_mut().collect(), grads.iter().collect(), 0.1)
+            .unwrap();
+        assert!((params[0].blob()[0] - 2.9).abs() < 1e-5);
+        assert!((params[1].blob()[0] - 2.95).abs() < 1e-5);
+
+        let mut naive = Naive::new();
+        naive.set_lr_scales(vec![0.5]);
+        naive
+            .step(params.iter_mut().collect(), grads.iter().collect(), 0.1)
+            .unwrap();
+        assert!((params[0].blob()[0] - 2.85).abs() < 1e-5);
+        assert!((params[1].blob()[0] - 2.85).abs() < 1e-5);
+    }
 }
@@ -0,0 +1,198 @@
+//! Estimates of what training with a config takes (Memory, disk and time), without
+//! training, for picking a config that fits the machine.
+
+use femto_gpt::config::Config;
+use femto_gpt::graph::GraphError;
+use rand::Rng;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Train steps measured for the step time (After a warm-up step)
+const MEASURED_STEPS: usize = 2;
+
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub vocab_size: usize,
+    pub params: usize,
+    /// Bytes of the weights
+    pub param_bytes: usize,
+    /// Bytes of the two moment estimates of AdamW
+    pub optimizer_bytes: usize,
+    /// Bytes of the activations (And their gradients) of the samples processed at
+    /// once, one per thread
+    pub activation_bytes: usize,
+    /// Estimated peak memory of a training step
+    pub peak_bytes: usize,
+    /// Size of a saved training state, and of the weights alone
+    pub checkpoint_bytes: usize,
+    pub weights_bytes: usize,
+    pub threads: usize,
+    pub batch_size: usize,
+    pub steps: usize,
+    /// Measured on this machine (If not skipped)
+    pub step_time: Option<Duration>,
+}
+
+pub fn estimate(config: &Config, vocab_size: usize, measure: bool) -> Result<Plan, GraphError> {
+    let mut rng = rand::thread_rng();
+    let m = &config.model;
+    let t = &config.training;
+    let mut gpt = m.build(&mut rng, vocab_size, config.optimizer.build())?;
+    if let Some(num_threads) = t.num_threads {
+        gpt.set_num_threads(num_threads)?;
+    }
+    gpt.set_precision(t.precision);
+    let threads = gpt.num_threads();
+    let params = gpt.num_params();
+    let param_bytes = params * std::mem::size_of::<f32>();
+    // The moment estimates of AdamW are only allocated by the first step, so a fresh
+    // state lacks them
+    let checkpoint_bytes = bincode::serialized_size(&gpt.get_training_state()?)
+        .map_err(|e| GraphError::IoError(std::io::Error::other(e)))?
+        as usize
+        + 2 * param_bytes;
+
+    // A step is processed in rounds of one sample per thread, so timing a single
+    // round is enough
+    let step_time = if measure {
+        let round = threads.min(t.batch_size);
+        let dataset = (0..(m.num_tokens + 1) * round.max(16))
+            .map(|_| rng.gen_range(0..vocab_size))
+            .collect::<Vec<_>>();
+        gpt.train_step(&dataset, round, None, 0.)?;
+        let mut times = Vec::new();
+        for _ in 0..MEASURED_STEPS {
+            let timer = Instant::now();
+            gpt.train_step(&dataset, round, None, 0.)?;
+            times.push(timer.elapsed());
+        }
+        times.sort();
+        Some(times[times.len() / 2] * t.batch_size.div_ceil(round) as u32)
+    } else {
+        None
+    };
+
+    Ok(Plan {
+        vocab_size,
+        params,
+        param_bytes,
+        optimizer_bytes: 2 * param_bytes,
+        activation_bytes: gpt.activation_memory() * threads.min(t.batch_size),
+        peak_bytes: gpt.step_memory(t.batch_size),
+        checkpoint_bytes,
+        weights_bytes: param_bytes,
+        threads,
+        batch_size: t.batch_size,
+        steps: t.steps,
+        step_time,
+    })
+}
+
+fn bytes(n: usize) -> String {
+    let n = n as f64;
+    if n >= 1e9 {
+        format!("{:.2} GB", n / 1e9)
+    } else if n >= 1e6 {
+        format!("{:.1} MB", n / 1e6)
+    } else {
+        format!("{:.1} KB", n / 1e3)
+    }
+}
+
+fn duration(d: Duration) -> String {
+    let s = d.as_secs();
+    if s >= 3600 {
+        format!("{}h{:02}m", s / 3600, s / 60 % 60)
+    } else if s >= 60 {
+        format!("{}m{:02}s", s / 60, s % 60)
+    } else {
+        format!("{:.2}s", d.as_secs_f64())
+    }
+}
+
+impl Plan {
+    pub fn report(&self, config: &Config) -> String {
+        let m = &config.model;
+        let mut out = String::new();
+        writeln!(
+            out,
+            "Model: {} tokens of context, {} embedding-degree, {} layers, {} heads, vocab of {}",
+            m.num_tokens, m.embedding_degree, m.num_layers, m.num_heads, self.vocab_size
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "Training: batch of {} on {} threads, {} steps",
+            self.batch_size, self.threads, self.steps
+        )
+        .unwrap();
+        writeln!(out, "Parameters: {}", self.params).unwrap();
+        writeln!(out, "Memory:").unwrap();
+        writeln!(out, "  weights      {:>10}", bytes(self.param_bytes)).unwrap();
+        writeln!(out, "  optimizer    {:>10}", bytes(self.optimizer_bytes)).unwrap();
+        writeln!(out, "  activations  {:>10}", bytes(self.activation_bytes)).unwrap();
+        writeln!(out, "  peak         {:>10}", bytes(self.peak_bytes)).unwrap();
+        writeln!(out, "Checkpoint:").unwrap();
+        writeln!(
+            out,
+            "  training state  {:>10}",
+            bytes(self.checkpoint_bytes)
+        )
+        .unwrap();
+        writeln!(out, "  weights only    {:>10}", bytes(self.weights_bytes)).unwrap();
+        match self.step_time {
+            Some(step_time) => {
+                let tokens = self.batch_size * m.num_tokens;
+                writeln!(
+                    out,
+                    "Time: {} per step ({:.0} tokens/s), {} for {} steps",
+                    duration(step_time),
+                    tokens as f64 / step_time.as_secs_f64(),
+                    duration(step_time * self.steps as u32),
+                    self.steps
+                )
+                .unwrap();
+            }
+            None => writeln!(out, "Time: not measured").unwrap(),
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use femto_gpt::config::ModelConfig;
+
+    #[test]
+    fn test_plan() {
+        let mut config = Config {
+            model: ModelConfig {
+                num_tokens: 4,
+                embedding_degree: 8,
+                num_layers: 1,
+                num_heads: 2,
+                dropout: 0.,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config.training.batch_size = 4;
+        config.training.num_threads = Some(2);
+        config.training.steps = 10;
+        let plan = estimate(&config, 10, true).unwrap();
+        assert_eq!(plan.threads, 2);
+        assert_eq!(plan.weights_bytes, plan.params * 4);
+        assert!(plan.activation_bytes > 0);
+        assert!(plan.peak_bytes > plan.param_bytes + plan.optimizer_bytes);
+        // Weights, and the two moment estimates
+        assert!(plan.checkpoint_bytes > 3 * plan.param_bytes);
+        assert!(plan.step_time.unwrap() > Duration::ZERO);
+        let report = plan.report(&config);
+        assert!(report.contains("Parameters:"));
+        assert!(report.contains("for 10 steps"));
+
+        let plan = estimate(&config, 10, false).unwrap();
+        assert!(plan.report(&config).contains("Time: not measured"));
+    }
+}
@@ -0,0 +1,14 @@
+//! The types most programs need, for a single `use femto_gpt::prelude::*;`
+
+pub use crate::config::{Config, ConfigError, ModelConfig, TokenizerKind};
+pub use crate::gpt::{
+    Activation, LengthControl, Pooling, PositionalEncoding, Sampling, SamplingStrategy,
+    StopCriteria, StopReason, TokenConstraint, TrainCallback, TrainContext, TrainingState,
+    Truncation, GPT,
+};
+pub use crate::graph::{GraphError, Precision};
+pub use crate::optimizer::{AdamW, Optimizer};
+pub use crate::template::{ChatTemplate, Message, Role};
+pub use crate::tensor::{Tensor, TensorOps};
+pub use crate::tokenizer::{AsciiTokenizer, SimpleTokenizer, Tokenizer};
+pub use crate::train::{train, train_from_files, TrainedModel};
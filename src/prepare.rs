@@ -0,0 +1,173 @@
+use femto_gpt::tensor::xxh64;
+use femto_gpt::tokenizer::Tokenizer;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Rarest characters listed in the report, often worth cleaning up
+const RAREST: usize = 10;
+
+/// What the dataset is split into, for the train/validation split
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Unit {
+    /// Blocks of text separated by blank lines
+    Paragraph,
+    Line,
+}
+
+impl Unit {
+    pub fn separator(self) -> &'static str {
+        match self {
+            Unit::Paragraph => "\n\n",
+            Unit::Line => "\n",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub bytes: usize,
+    pub chars: usize,
+    pub lines: usize,
+    pub paragraphs: usize,
+    /// Unique characters (The vocabulary of a simple tokenizer built from the text)
+    pub unique_chars: usize,
+    /// Ratio of the non-empty lines that repeat an earlier line
+    pub duplicate_lines: f64,
+    /// Characters and their number of occurrences, rarest first
+    pub rarest: Vec<(char, usize)>,
+    /// Number of tokens and of characters missing from the vocabulary, when a
+    /// tokenizer is given
+    pub tokens: Option<(usize, usize)>,
+}
+
+impl Stats {
+    pub fn new(text: &str, tokenizer: Option<&dyn Tokenizer>) -> Self {
+        let mut counts = HashMap::<char, usize>::new();
+        for ch in text.chars() {
+            *counts.entry(ch).or_default() += 1;
+        }
+        let mut rarest = counts.iter().map(|(c, n)| (*c, *n)).collect::<Vec<_>>();
+        rarest.sort_by_key(|(c, n)| (*n, *c));
+        rarest.truncate(RAREST);
+
+        let mut seen = HashSet::new();
+        let (mut lines, mut duplicates) = (0, 0);
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            lines += 1;
+            if !seen.insert(line) {
+                duplicates += 1;
+            }
+        }
+
+        let tokens = tokenizer.map(|t| {
+            let known = t.known_chars();
+            let unknown = text.chars().filter(|ch| !known.contains(ch)).count();
+            let text = text
+                .chars()
+                .filter(|ch| known.contains(ch))
+                .collect::<String>();
+            // Only known characters are left, so tokenizing can't fail
+            (t.tokenize(&text).map_or(0, |t| t.len()), unknown)
+        });
+
+        Self {
+            bytes: text.len(),
+            chars: text.chars().count(),
+            lines: text.lines().count(),
+            paragraphs: units(text, Unit::Paragraph).count(),
+            unique_chars: counts.len(),
+            duplicate_lines: if lines > 0 {
+                duplicates as f64 / lines as f64
+            } else {
+                0.
+            },
+            rarest,
+            tokens,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "Bytes: {}", self.bytes).unwrap();
+        writeln!(out, "Characters: {}", self.chars).unwrap();
+        writeln!(out, "Lines: {}", self.lines).unwrap();
+        writeln!(out, "Paragraphs: {}", self.paragraphs).unwrap();
+        writeln!(out, "Unique characters: {}", self.unique_chars).unwrap();
+        writeln!(out, "Duplicate lines: {:.2}%", self.duplicate_lines * 100.).unwrap();
+        let rarest = self
+            .rarest
+            .iter()
+            .map(|(c, n)| format!("{:?} ({})", c, n))
+            .collect::<Vec<_>>();
+        writeln!(out, "Rarest characters: {}", rarest.join(", ")).unwrap();
+        if let Some((tokens, unknown)) = self.tokens {
+            writeln!(out, "Tokens: {}", tokens).unwrap();
+            writeln!(out, "Characters missing from the tokenizer: {}", unknown).unwrap();
+        }
+        out
+    }
+}
+
+/// The non-blank paragraphs or lines of `text`
+pub fn units(text: &str, unit: Unit) -> impl Iterator<Item = &str> {
+    let parts: Box<dyn Iterator<Item = &str>> = match unit {
+        Unit::Paragraph => Box::new(text.split("\n\n")),
+        Unit::Line => Box::new(text.lines()),
+    };
+    parts
+        .map(|p| p.trim_matches('\n'))
+        .filter(|p| !p.trim().is_empty())
+}
+
+/// Splits `text` into a training and a validation set, with about `val_fraction` of the
+/// paragraphs (Or lines) in the latter. Each of them goes to a set depending on the hash
+/// of its contents, so the split is deterministic and repeated text can't end up on
+/// both sides.
+pub fn split(text: &str, unit: Unit, val_fraction: f64, seed: u64) -> (String, String) {
+    let (mut train, mut val) = (Vec::new(), Vec::new());
+    for part in units(text, unit) {
+        let h = xxh64(part.as_bytes(), seed) as f64 / u64::MAX as f64;
+        if h < val_fraction {
+            val.push(part);
+        } else {
+            train.push(part);
+        }
+    }
+    (
+        train.join(unit.separator()) + "\n",
+        val.join(unit.separator()) + "\n",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use femto_gpt::tokenizer::SimpleTokenizer;
+
+    #[test]
+    fn test_dataset() {
+        let text = "ROMEO:\nHi.\n\nJULIET:\nHi.\n\nROMEO:\nHi.\n";
+        let tokenizer = SimpleTokenizer::new("ROMEO:\n");
+        let stats = Stats::new(text, Some(&tokenizer));
+        assert_eq!(stats.lines, 8);
+        assert_eq!(stats.paragraphs, 3);
+        assert_eq!(stats.duplicate_lines, 3. / 6.);
+        assert_eq!(stats.rarest[0], ('I', 1));
+        assert_eq!(stats.tokens, Some((22, 14)));
+
+        let text = (0..1000)
+            .map(|i| format!("Line {}", i % 500))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (train, val) = split(&text, Unit::Line, 0.1, 0);
+        assert_eq!(
+            split(&text, Unit::Line, 0.1, 0),
+            (train.clone(), val.clone())
+        );
+        let val_lines = val.lines().collect::<HashSet<_>>();
+        assert!((60..140).contains(&val.lines().count()));
+        assert!(train.lines().all(|l| !val_lines.contains(l)));
+        assert_eq!(train.lines().count() + val.lines().count(), 1000);
+        assert_ne!(split(&text, Unit::Line, 0.1, 1).1, val);
+    }
+}
@@ -0,0 +1,136 @@
+//! Differentially private training (DP-SGD, Abadi et al. 2016): the gradients of each
+//! sample are clipped to a maximum norm, and Gaussian noise calibrated to that norm is
+//! added to their sum before the optimizer step, so that no single sample can change
+//! the update much. The privacy spent is tracked with the Rényi DP accountant of the
+//! subsampled Gaussian mechanism (Mironov et al. 2019), as an `(ε, δ)` budget.
+
+/// Rényi orders the budget is computed at, the best one being kept
+const ORDERS: std::ops::RangeInclusive<usize> = 2..=256;
+
+/// Settings of DP-SGD, along with the privacy spent so far
+#[derive(Debug, Clone, PartialEq)]
+pub struct DpSgd {
+    /// Maximum L2 norm of the gradients of a sample
+    pub clip_norm: f32,
+    /// Standard deviation of the noise, relative to `clip_norm`
+    pub noise_multiplier: f32,
+    /// Probability of the `ε` bound not holding
+    pub delta: f64,
+    /// Rényi DP spent so far, at each of `ORDERS`
+    rdp: Vec<f64>,
+    steps: usize,
+}
+
+impl DpSgd {
+    pub fn new(clip_norm: f32, noise_multiplier: f32, delta: f64) -> Self {
+        Self {
+            clip_norm,
+            noise_multiplier,
+            delta,
+            rdp: vec![0.; ORDERS.count()],
+            steps: 0,
+        }
+    }
+
+    /// What the gradients of a sample of norm `norm` get multiplied by. A norm that
+    /// isn't finite is left alone, for the loss scaler to see the overflow.
+    pub fn clip_factor(&self, norm: f32) -> f32 {
+        if norm.is_finite() && norm > self.clip_norm {
+            self.clip_norm / norm
+        } else {
+            1.
+        }
+    }
+
+    /// Standard deviation of the noise added to the summed gradients
+    pub fn noise_std(&self) -> f32 {
+        self.noise_multiplier * self.clip_norm
+    }
+
+    /// Records `steps` steps on batches drawing each sample of the dataset with
+    /// probability `sample_rate` (The batch size over the size of the dataset)
+    pub fn account(&mut self, sample_rate: f64, steps: usize) {
+        let sigma = self.noise_multiplier as f64;
+        for (rdp, order) in self.rdp.iter_mut().zip(ORDERS) {
+            *rdp += steps as f64 * subsampled_gaussian_rdp(sample_rate, sigma, order);
+        }
+        self.steps += steps;
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// The `ε` of the `(ε, δ)`-DP guarantee of the steps recorded so far
+    pub fn epsilon(&self) -> f64 {
+        if self.steps == 0 {
+            return 0.;
+        }
+        self.rdp
+            .iter()
+            .zip(ORDERS)
+            .map(|(rdp, order)| rdp + (1. / self.delta).ln() / (order - 1) as f64)
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+/// Rényi DP at the integer `order` of a step of the Gaussian mechanism of noise
+/// multiplier `sigma`, on a batch drawing each sample with probability `q`
+fn subsampled_gaussian_rdp(q: f64, sigma: f64, order: usize) -> f64 {
+    let alpha = order as f64;
+    if q <= 0. {
+        return 0.;
+    }
+    if sigma <= 0. {
+        return f64::INFINITY;
+    }
+    if q >= 1. {
+        return alpha / (2. * sigma * sigma);
+    }
+    // log of sum_k C(α, k) (1 - q)^(α - k) q^k exp((k² - k) / 2σ²)
+    let mut log_binomial = 0.;
+    let terms = (0..=order)
+        .map(|k| {
+            let k = k as f64;
+            if k > 0. {
+                log_binomial += ((alpha - k + 1.) / k).ln();
+            }
+            log_binomial
+                + (alpha - k) * (1. - q).ln()
+                + k * q.ln()
+                + (k * k - k) / (2. * sigma * sigma)
+        })
+        .collect::<Vec<_>>();
+    let max = terms.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = max + terms.iter().map(|t| (t - max).exp()).sum::<f64>().ln();
+    log_sum / (alpha - 1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dp_sgd() {
+        let mut dp = DpSgd::new(1., 1.1, 1e-5);
+        assert_eq!(dp.clip_factor(0.5), 1.);
+        assert_eq!(dp.clip_factor(4.), 0.25);
+        assert_eq!(dp.clip_factor(f32::INFINITY), 1.);
+        assert_eq!(dp.epsilon(), 0.);
+
+        // MNIST of Abadi et al.: 60 epochs of batches of 256 out of 60000 samples
+        dp.account(256. / 60000., 7031);
+        let half = dp.epsilon();
+        dp.account(256. / 60000., 7031);
+        assert_eq!(dp.steps(), 14062);
+        assert!((dp.epsilon() - 3.01).abs() < 0.05);
+        assert!(half < dp.epsilon());
+
+        let mut dp = DpSgd::new(1., 1., 1e-5);
+        dp.account(1., 1);
+        assert!((dp.epsilon() - 5.30).abs() < 0.01);
+        let mut dp = DpSgd::new(1., 0., 1e-5);
+        dp.account(0.01, 1);
+        assert_eq!(dp.epsilon(), f64::INFINITY);
+    }
+}
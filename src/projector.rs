@@ -0,0 +1,176 @@
+//! Exporting the token embeddings of a model to look at what it learned about its
+//! vocabulary: as the TSV files of the TensorFlow Embedding Projector
+//! (<https://projector.tensorflow.org>, a vector and a label per line), or projected
+//! to a few dimensions with PCA, for plotting.
+
+use crate::tensor::{Tensor, TensorError, TensorOps};
+use crate::tokenizer::{Tokenizer, TokenizerError};
+
+/// Power iterations per principal component
+const PCA_ITERATIONS: usize = 200;
+
+/// One line per row of `embeddings` ([vocab_size, embedding_degree]), its values
+/// separated by tabs
+pub fn vectors_tsv(embeddings: &Tensor<f32>) -> String {
+    let degree = embeddings.shape().last().copied().unwrap_or(0).max(1);
+    let mut out = String::new();
+    for row in embeddings.blob().chunks(degree) {
+        let row = row.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        out.push_str(&row.join("\t"));
+        out.push('\n');
+    }
+    out
+}
+
+/// The text of a token, as a label fitting on a line of a TSV file
+pub fn label(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// The label of every token of the vocabulary, in order
+pub fn labels<T: Tokenizer + ?Sized>(tokenizer: &T) -> Result<Vec<String>, TokenizerError> {
+    (0..tokenizer.vocab_size())
+        .map(|i| Ok(label(&tokenizer.untokenize(&[i])?)))
+        .collect()
+}
+
+/// One label per line, the metadata of `vectors_tsv` (Without a header, as the
+/// projector wants for a single column)
+pub fn metadata_tsv(labels: &[String]) -> String {
+    labels.iter().map(|l| format!("{}\n", l)).collect()
+}
+
+/// The rows of `embeddings` ([n, degree]) projected on their `dims` principal
+/// components ([n, dims]), along with the fraction of the variance each explains.
+/// Deterministic, the sign of each component being chosen so that its largest
+/// coordinate is positive.
+pub fn pca(embeddings: &Tensor<f32>, dims: usize) -> Result<(Tensor<f32>, Vec<f32>), TensorError> {
+    let shape = embeddings.shape();
+    if shape.len() != 2 || dims == 0 || dims > shape[1] {
+        return Err(TensorError::shape_mismatch("pca", &[0, dims], shape));
+    }
+    let (n, degree) = (shape[0], shape[1]);
+    let data = embeddings
+        .blob()
+        .iter()
+        .map(|v| *v as f64)
+        .collect::<Vec<_>>();
+    let mut mean = vec![0.; degree];
+    for row in data.chunks(degree) {
+        for (m, v) in mean.iter_mut().zip(row) {
+            *m += v / n as f64;
+        }
+    }
+    let mut cov = vec![0.; degree * degree];
+    for row in data.chunks(degree) {
+        for i in 0..degree {
+            for j in 0..degree {
+                cov[i * degree + j] += (row[i] - mean[i]) * (row[j] - mean[j]) / n as f64;
+            }
+        }
+    }
+    let total = (0..degree).map(|i| cov[i * degree + i]).sum::<f64>();
+
+    let mut components = Vec::new();
+    let mut explained = Vec::new();
+    for _ in 0..dims {
+        // Starting from the same vector every time, for the same projection
+        let mut v = (0..degree)
+            .map(|i| 1. + i as f64 / degree as f64)
+            .collect::<Vec<_>>();
+        let mut eigenvalue = 0.;
+        for _ in 0..PCA_ITERATIONS {
+            let w = (0..degree)
+                .map(|i| (0..degree).map(|j| cov[i * degree + j] * v[j]).sum::<f64>())
+                .collect::<Vec<_>>();
+            let norm = w.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm == 0. {
+                break;
+            }
+            eigenvalue = norm;
+            v = w.into_iter().map(|x| x / norm).collect();
+        }
+        let largest = v
+            .iter()
+            .copied()
+            .fold(0., |a: f64, b| if b.abs() > a.abs() { b } else { a });
+        if largest < 0. {
+            v.iter_mut().for_each(|x| *x = -*x);
+        }
+        // Deflated, for the next iterations to find the next component
+        for i in 0..degree {
+            for j in 0..degree {
+                cov[i * degree + j] -= eigenvalue * v[i] * v[j];
+            }
+        }
+        explained.push(if total > 0. {
+            (eigenvalue / total) as f32
+        } else {
+            0.
+        });
+        components.push(v);
+    }
+
+    let mut points = Vec::with_capacity(n * dims);
+    for row in data.chunks(degree) {
+        for c in components.iter() {
+            let dot = (0..degree).map(|i| (row[i] - mean[i]) * c[i]).sum::<f64>();
+            points.push(dot as f32);
+        }
+    }
+    Ok((Tensor::raw(&[n, dims], points)?, explained))
+}
+
+/// A header (`label`, then `x`, `y`, `z` or `pc1`, `pc2`...), then the label and the
+/// coordinates of every point ([n, dims]) per line
+pub fn projection_tsv(labels: &[String], points: &Tensor<f32>) -> String {
+    let dims = points.shape().last().copied().unwrap_or(0).max(1);
+    let mut header = vec!["label".to_string()];
+    header.extend((0..dims).map(|d| match dims {
+        ..=3 => ["x", "y", "z"][d].to_string(),
+        _ => format!("pc{}", d + 1),
+    }));
+    let mut out = header.join("\t") + "\n";
+    for (label, row) in labels.iter().zip(points.blob().chunks(dims)) {
+        out.push_str(label);
+        for v in row {
+            out.push('\t');
+            out.push_str(&v.to_string());
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::SimpleTokenizer;
+
+    #[test]
+    fn test_projector() {
+        let tokenizer = SimpleTokenizer::new("a\tb\n");
+        let labels = labels(&tokenizer).unwrap();
+        assert_eq!(labels, ["\\t", "\\n", "a", "b"]);
+        assert_eq!(metadata_tsv(&labels), "\\t\n\\n\na\nb\n");
+
+        // Points on a line, along with a little noise across it
+        let embeddings = Tensor::raw(&[4, 2], vec![0., 0., 1., 1.1, 2., 1.9, 3., 3.]).unwrap();
+        assert_eq!(vectors_tsv(&embeddings).lines().nth(1), Some("1\t1.1"));
+        let (points, explained) = pca(&embeddings, 2).unwrap();
+        assert!(explained[0] > 0.99 && explained[1] < 0.01);
+        assert!((explained.iter().sum::<f32>() - 1.).abs() < 1e-4);
+        let xs = points.blob().chunks(2).map(|p| p[0]).collect::<Vec<_>>();
+        assert!(xs.windows(2).all(|w| w[0] < w[1]));
+        assert!((xs[3] - xs[0] - 18f32.sqrt()).abs() < 0.01);
+        assert!(pca(&embeddings, 3).is_err());
+
+        let (points, _) = pca(&embeddings, 1).unwrap();
+        let tsv = projection_tsv(&labels, &points);
+        assert!(tsv.starts_with("label\tx\n\\t\t-2.12"));
+        assert_eq!(tsv.lines().count(), 5);
+    }
+}
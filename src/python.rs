@@ -0,0 +1,277 @@
+//! Python bindings, built with `maturin develop --features python` (See
+//! `pyproject.toml`). E.g.
+//!
+//! ```python
+//! import femto_gpt
+//!
+//! tokenizer = femto_gpt.Tokenizer.from_text(text)
+//! gpt = femto_gpt.GPT(tokenizer, num_layers=2)
+//! losses = gpt.train(text.split("\n\n"), steps=100)
+//! print(gpt.generate("ROMEO:", max_tokens=100, temperature=0.8))
+//! gpt.save("training_state.dat", "tokenizer.dat")
+//! ```
+
+// Triggered by the code generated by `#[pymethods]`
+#![allow(clippy::useless_conversion)]
+
+use crate::config::{Config, TokenizerKind};
+use crate::dataset::Packed;
+use crate::gpt::{Sampling, TrainingState, GPT};
+use crate::optimizer::AdamW;
+use crate::tokenizer::{AsciiTokenizer, SimpleTokenizer, Tokenizer};
+use pyo3::exceptions::{PyIOError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use std::path::PathBuf;
+
+fn runtime_error<E: std::fmt::Display>(e: E) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+fn value_error<E: std::fmt::Display>(e: E) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+#[derive(Clone)]
+enum AnyTokenizer {
+    Simple(SimpleTokenizer),
+    Ascii,
+}
+
+impl AnyTokenizer {
+    fn inner(&self) -> &dyn Tokenizer {
+        match self {
+            AnyTokenizer::Simple(t) => t,
+            AnyTokenizer::Ascii => &AsciiTokenizer,
+        }
+    }
+    fn kind(&self) -> TokenizerKind {
+        match self {
+            AnyTokenizer::Simple(_) => TokenizerKind::Simple,
+            AnyTokenizer::Ascii => TokenizerKind::Ascii,
+        }
+    }
+}
+
+#[pyclass(name = "Tokenizer", module = "femto_gpt")]
+#[derive(Clone)]
+pub struct PyTokenizer {
+    tokenizer: AnyTokenizer,
+}
+
+impl PyTokenizer {
+    fn new(tokenizer: AnyTokenizer) -> Self {
+        Self { tokenizer }
+    }
+}
+
+#[pymethods]
+impl PyTokenizer {
+    /// One token per unique character of `text`
+    #[staticmethod]
+    fn from_text(text: &str) -> Self {
+        Self::new(AnyTokenizer::Simple(SimpleTokenizer::new(text)))
+    }
+
+    /// One token per ASCII character
+    #[staticmethod]
+    fn ascii() -> Self {
+        Self::new(AnyTokenizer::Ascii)
+    }
+
+    /// Loads a tokenizer saved by `save` (Or by the `femto-gpt` command)
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        let bytes = std::fs::read(path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        let tokenizer = bincode::deserialize(&bytes).map_err(value_error)?;
+        Ok(Self::new(AnyTokenizer::Simple(tokenizer)))
+    }
+
+    fn save(&self, path: PathBuf) -> PyResult<()> {
+        let bytes = match &self.tokenizer {
+            AnyTokenizer::Simple(t) => bincode::serialize(t),
+            AnyTokenizer::Ascii => bincode::serialize(&AsciiTokenizer),
+        }
+        .map_err(runtime_error)?;
+        std::fs::write(path, bytes).map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    #[getter]
+    fn vocab_size(&self) -> usize {
+        self.tokenizer.inner().vocab_size()
+    }
+
+    fn encode(&self, text: &str) -> PyResult<Vec<usize>> {
+        self.tokenizer.inner().tokenize(text).map_err(value_error)
+    }
+
+    fn decode(&self, tokens: Vec<usize>) -> PyResult<String> {
+        self.tokenizer
+            .inner()
+            .untokenize(&tokens)
+            .map_err(value_error)
+    }
+}
+
+#[pyclass(name = "GPT", module = "femto_gpt")]
+pub struct PyGPT {
+    gpt: GPT<AdamW>,
+    tokenizer: PyTokenizer,
+    config: Config,
+}
+
+impl PyGPT {
+    fn build(tokenizer: PyTokenizer, config: Config) -> PyResult<Self> {
+        config.validate().map_err(value_error)?;
+        let mut rng = rand::thread_rng();
+        let gpt = config
+            .model
+            .build(&mut rng, tokenizer.vocab_size(), config.optimizer.build())
+            .map_err(runtime_error)?;
+        Ok(Self {
+            gpt,
+            tokenizer,
+            config,
+        })
+    }
+}
+
+#[pymethods]
+impl PyGPT {
+    #[new]
+    #[pyo3(signature = (tokenizer, num_tokens=64, embedding_degree=64, num_layers=4, num_heads=4, dropout=0.0))]
+    fn new(
+        tokenizer: PyTokenizer,
+        num_tokens: usize,
+        embedding_degree: usize,
+        num_layers: usize,
+        num_heads: usize,
+        dropout: f64,
+    ) -> PyResult<Self> {
+        let mut config = Config::default();
+        config.tokenizer.kind = tokenizer.tokenizer.kind();
+        config.model.num_tokens = num_tokens;
+        config.model.embedding_degree = embedding_degree;
+        config.model.num_layers = num_layers;
+        config.model.num_heads = num_heads;
+        config.model.dropout = dropout;
+        Self::build(tokenizer, config)
+    }
+
+    /// Loads a model saved by `save` (Or trained with the `femto-gpt` command). The
+    /// config defaults to the one saved along the training state.
+    #[staticmethod]
+    #[pyo3(signature = (state="training_state.dat".into(), tokenizer="tokenizer.dat".into(), config=None))]
+    fn load(state: PathBuf, tokenizer: PathBuf, config: Option<PathBuf>) -> PyResult<Self> {
+        let config = config.unwrap_or_else(|| state.with_extension("toml"));
+        let config = Config::load(config).map_err(value_error)?;
+        let tokenizer = match config.tokenizer.kind {
+            TokenizerKind::Simple => PyTokenizer::load(tokenizer)?,
+            TokenizerKind::Ascii => PyTokenizer::ascii(),
+        };
+        let mut model = Self::build(tokenizer, config)?;
+        let bytes = std::fs::read(state).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        let ts: TrainingState<AdamW> = bincode::deserialize(&bytes).map_err(value_error)?;
+        model
+            .gpt
+            .set_training_state(ts, true)
+            .map_err(runtime_error)?;
+        model
+            .gpt
+            .check_tokenizer(model.tokenizer.tokenizer.inner())
+            .map_err(value_error)?;
+        Ok(model)
+    }
+
+    /// Saves the training state, along with the tokenizer and the config
+    #[pyo3(signature = (state="training_state.dat".into(), tokenizer="tokenizer.dat".into()))]
+    fn save(&self, state: PathBuf, tokenizer: PathBuf) -> PyResult<()> {
+        let ts = self.gpt.get_training_state().map_err(runtime_error)?;
+        let bytes = bincode::serialize(&ts).map_err(runtime_error)?;
+        std::fs::write(&state, bytes).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        self.tokenizer.save(tokenizer)?;
+        self.config
+            .save(state.with_extension("toml"))
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    #[getter]
+    fn num_params(&self) -> usize {
+        self.gpt.num_params()
+    }
+
+    #[getter]
+    fn tokenizer(&self) -> PyTokenizer {
+        self.tokenizer.clone()
+    }
+
+    /// The config of the model, as TOML
+    #[getter]
+    fn config(&self) -> String {
+        self.config.to_toml()
+    }
+
+    /// Continues `prompt` with `max_tokens` tokens
+    #[pyo3(signature = (prompt, max_tokens=200, temperature=1.0, top_p=1.0))]
+    fn generate(
+        &self,
+        py: Python<'_>,
+        prompt: &str,
+        max_tokens: usize,
+        temperature: f32,
+        top_p: f32,
+    ) -> PyResult<String> {
+        let tokens = self.tokenizer.encode(prompt)?;
+        if tokens.is_empty() {
+            return Err(value_error("the prompt is empty"));
+        }
+        let tokens = &tokens[tokens.len().saturating_sub(self.gpt.num_tokens())..];
+        let sampling = Sampling { temperature, top_p };
+        let output = py
+            .allow_threads(|| {
+                let mut rng = rand::thread_rng();
+                self.gpt
+                    .generate(&mut rng, tokens, max_tokens, &sampling, |_| true)
+            })
+            .map_err(runtime_error)?;
+        self.tokenizer.decode(output)
+    }
+
+    /// Trains (Or fine-tunes) the model on a list of documents, for `steps` steps with
+    /// a constant learning-rate. Returns the loss of each step.
+    #[pyo3(signature = (texts, steps=100, batch_size=8, learning_rate=0.001))]
+    fn train(
+        &mut self,
+        py: Python<'_>,
+        texts: Vec<String>,
+        steps: usize,
+        batch_size: usize,
+        learning_rate: f32,
+    ) -> PyResult<Vec<f32>> {
+        let documents = texts
+            .iter()
+            .filter(|t| !t.is_empty())
+            .map(|t| self.tokenizer.encode(t))
+            .collect::<PyResult<Vec<_>>>()?;
+        // Documents are separated by a newline, when it's in the vocabulary
+        let eos = self.tokenizer.encode("\n").map_or(0, |t| t[0]);
+        let dataset = Packed::new(documents.iter().map(|d| d.as_slice()).collect(), eos)
+            .ok_or_else(|| value_error("there are no documents to train on"))?;
+        let gpt = &mut self.gpt;
+        py.allow_threads(|| {
+            (0..steps)
+                .map(|_| {
+                    gpt.train_step(&dataset, batch_size, None, learning_rate)
+                        .map(|stats| stats.loss)
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(runtime_error)
+    }
+}
+
+#[pymodule]
+fn femto_gpt(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyTokenizer>()?;
+    m.add_class::<PyGPT>()?;
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! A fixed battery of seeded prompts run through a checkpoint, its outputs being
+//! compared with the reference outputs of an earlier checkpoint: how similar they are,
+//! and how likely the model finds the reference outputs. A regression signal for
+//! changes to the training code.
+
+use femto_gpt::eval;
+use femto_gpt::gpt::{seeded_rng, Sampling, Truncation, GPT};
+use femto_gpt::graph::GraphError;
+use femto_gpt::optimizer::Optimizer;
+use femto_gpt::tokenizer::Tokenizer;
+use serde::{Deserialize, Serialize};
+
+/// Prompts run when none are given, their characters out of the vocabulary of the model
+/// being dropped
+pub const DEFAULT_PROMPTS: [&str; 8] = [
+    "\n",
+    "The ",
+    "ROMEO:\n",
+    "Once upon a time",
+    "I am ",
+    "What is ",
+    "1. ",
+    "\"",
+];
+
+/// The outputs of a checkpoint for a battery of prompts, along with the settings they
+/// were generated with (As JSON)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reference {
+    /// XXH64 of the training state file, in hex
+    pub checkpoint: String,
+    /// Training step of the checkpoint
+    pub step: usize,
+    /// The `i`th prompt is run with `seed + i`
+    pub seed: u64,
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub outputs: Vec<Output>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Output {
+    pub prompt: String,
+    pub seed: u64,
+    pub text: String,
+    /// Perplexity of the model on the prompt followed by the text
+    pub perplexity: f32,
+}
+
+/// How the output of a prompt compares with the reference one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub prompt: String,
+    /// One minus the edit distance between the texts, over the length of the longer
+    pub similarity: f32,
+    pub exact: bool,
+    /// Perplexity of the model on the reference output
+    pub perplexity: f32,
+    /// Perplexity of the reference model on its own output
+    pub reference_perplexity: f32,
+}
+
+/// Levenshtein distance between the characters of `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+pub fn similarity(a: &str, b: &str) -> f32 {
+    let len = a.chars().count().max(b.chars().count());
+    if len == 0 {
+        return 1.;
+    }
+    1. - edit_distance(a, b) as f32 / len as f32
+}
+
+/// The tokens of `text`, without the characters outside of the vocabulary
+fn tokenize_known<T: Tokenizer>(tokenizer: &T, text: &str) -> Result<Vec<usize>, GraphError> {
+    let known = tokenizer.known_chars();
+    let text = text
+        .chars()
+        .filter(|ch| known.contains(ch))
+        .collect::<String>();
+    Ok(tokenizer.tokenize(&text)?)
+}
+
+/// The tokens of `prompt` the model sees before generating `max_tokens`
+fn prompt_tokens<O: Optimizer, T: Tokenizer>(
+    gpt: &GPT<O>,
+    tokenizer: &T,
+    prompt: &str,
+    max_tokens: usize,
+) -> Result<Vec<usize>, GraphError> {
+    let tokens = tokenize_known(tokenizer, prompt)?;
+    Ok(gpt.fit_prompt(&tokens, max_tokens, Truncation::Tail))
+}
+
+/// Runs the `prompts` through the model (Skipping those with no character of its
+/// vocabulary), with the settings `reference` gives
+pub fn generate<O: Optimizer, T: Tokenizer>(
+    gpt: &GPT<O>,
+    tokenizer: &T,
+    prompts: &[String],
+    settings: &Reference,
+    checkpoint: u64,
+) -> Result<Reference, GraphError> {
+    let seeded = prompts
+        .iter()
+        .enumerate()
+        .map(|(i, prompt)| (prompt.clone(), settings.seed.wrapping_add(i as u64)))
+        .collect::<Vec<_>>();
+    run(gpt, tokenizer, &seeded, settings, checkpoint)
+}
+
+fn run<O: Optimizer, T: Tokenizer>(
+    gpt: &GPT<O>,
+    tokenizer: &T,
+    prompts: &[(String, u64)],
+    settings: &Reference,
+    checkpoint: u64,
+) -> Result<Reference, GraphError> {
+    let sampling = Sampling {
+        temperature: settings.temperature,
+        top_p: settings.top_p,
+    };
+    let mut outputs = Vec::new();
+    for (prompt, seed) in prompts {
+        let tokens = prompt_tokens(gpt, tokenizer, prompt, settings.max_tokens)?;
+        if tokens.is_empty() {
+            continue;
+        }
+        let mut rng = seeded_rng(*seed);
+        let generated =
+            gpt.generate(&mut rng, &tokens, settings.max_tokens, &sampling, |_| true)?;
+        outputs.push(Output {
+            prompt: prompt.clone(),
+            seed: *seed,
+            text: tokenizer.untokenize(&generated)?,
+            perplexity: eval::perplexity(gpt, &[tokens, generated].concat())?,
+        });
+    }
+    Ok(Reference {
+        checkpoint: format!("{:016x}", checkpoint),
+        step: gpt.optimizer().step_num(),
+        outputs,
+        ..settings.clone()
+    })
+}
+
+/// Runs the prompts of `reference` through the model with the same settings, comparing
+/// the outputs with the reference ones. Returns the outputs too.
+pub fn compare<O: Optimizer, T: Tokenizer>(
+    gpt: &GPT<O>,
+    tokenizer: &T,
+    reference: &Reference,
+    checkpoint: u64,
+) -> Result<(Reference, Vec<Comparison>), GraphError> {
+    let prompts = reference
+        .outputs
+        .iter()
+        .map(|o| (o.prompt.clone(), o.seed))
+        .collect::<Vec<_>>();
+    let outputs = run(gpt, tokenizer, &prompts, reference, checkpoint)?;
+    let comparisons = reference
+        .outputs
+        .iter()
+        .zip(&outputs.outputs)
+        .map(|(expected, output)| {
+            let tokens = [
+                prompt_tokens(gpt, tokenizer, &expected.prompt, reference.max_tokens)?,
+                tokenize_known(tokenizer, &expected.text)?,
+            ]
+            .concat();
+            Ok(Comparison {
+                prompt: expected.prompt.clone(),
+                similarity: similarity(&expected.text, &output.text),
+                exact: expected.text == output.text,
+                perplexity: eval::perplexity(gpt, &tokens)?,
+                reference_perplexity: expected.perplexity,
+            })
+        })
+        .collect::<Result<Vec<_>, GraphError>>()?;
+    Ok((outputs, comparisons))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use femto_gpt::config::ModelConfig;
+    use femto_gpt::optimizer::AdamW;
+    use femto_gpt::tokenizer::SimpleTokenizer;
+
+    #[test]
+    fn test_quality() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(similarity("", ""), 1.);
+        assert_eq!(similarity("abcd", "abce"), 0.75);
+
+        let tokenizer = SimpleTokenizer::new("ROMEO: Hi.\n");
+        let model = ModelConfig {
+            num_tokens: 8,
+            embedding_degree: 8,
+            num_layers: 1,
+            num_heads: 2,
+            dropout: 0.,
+            ..Default::default()
+        };
+        let gpt = model
+            .build(&mut seeded_rng(0), tokenizer.vocab_size(), AdamW::new())
+            .unwrap();
+        let settings = Reference {
+            checkpoint: String::new(),
+            step: 0,
+            seed: 42,
+            max_tokens: 10,
+            temperature: 0.,
+            top_p: 1.,
+            outputs: Vec::new(),
+        };
+        let prompts = DEFAULT_PROMPTS.map(String::from);
+        let reference = generate(&gpt, &tokenizer, &prompts, &settings, 0xabc).unwrap();
+        // The quote has no character of the vocabulary
+        assert_eq!(reference.outputs.len(), DEFAULT_PROMPTS.len() - 1);
+        assert_eq!(reference.checkpoint, "0000000000000abc");
+
+        // The same checkpoint gives the same outputs
+        let (outputs, comparisons) = compare(&gpt, &tokenizer, &reference, 0xabc).unwrap();
+        assert_eq!(outputs, reference);
+        assert!(comparisons.iter().all(|c| c.exact && c.similarity == 1.));
+        let c = &comparisons[1];
+        assert!((c.perplexity - c.reference_perplexity).abs() < 1e-3);
+
+        let other = model
+            .build(&mut seeded_rng(1), tokenizer.vocab_size(), AdamW::new())
+            .unwrap();
+        let (_, comparisons) = compare(&other, &tokenizer, &reference, 0xdef).unwrap();
+        assert!(comparisons.iter().any(|c| !c.exact));
+    }
+}
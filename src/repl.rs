@@ -0,0 +1,215 @@
+use femto_gpt::gpt::{CancellationToken, LengthControl, Sampling, GPT};
+use femto_gpt::graph::GraphError;
+use femto_gpt::optimizer::Optimizer;
+use femto_gpt::template::{visible_end, ChatTemplate, Message, Role};
+use femto_gpt::tokenizer::{StreamDecoder, Tokenizer};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const HELP: &str = "\
+Type some text and the model will continue it. Commands:
+  /reset         Forget the conversation so far
+  /temp <T>      Set the temperature (0.0 is greedy)
+  /save [PATH]   Save the conversation to a file [default: conversation.txt]
+  /help          Show this message
+  /quit          Exit (Or Ctrl-D)
+Ctrl-C cuts the answer being written short.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Text(String),
+    Reset,
+    Temperature(f32),
+    Save(PathBuf),
+    Help,
+    Quit,
+}
+
+impl Input {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let Some(cmd) = line.strip_prefix('/') else {
+            return Ok(Input::Text(line.to_string()));
+        };
+        let mut parts = cmd.split_whitespace();
+        let name = parts.next().unwrap_or_default();
+        let arg = parts.next();
+        if parts.next().is_some() {
+            return Err(format!("too many arguments for /{}", name));
+        }
+        match (name, arg) {
+            ("reset", None) => Ok(Input::Reset),
+            ("temp", Some(t)) => t
+                .parse()
+                .ok()
+                .filter(|t: &f32| *t >= 0.)
+                .map(Input::Temperature)
+                .ok_or_else(|| format!("invalid temperature '{}'", t)),
+            ("save", path) => Ok(Input::Save(path.unwrap_or("conversation.txt").into())),
+            ("help", None) => Ok(Input::Help),
+            ("quit" | "exit", None) => Ok(Input::Quit),
+            _ => Err(format!("unknown command '{}', try /help", line)),
+        }
+    }
+}
+
+/// Chats with the model on the terminal. Each input is appended to the conversation as
+/// a turn of the user, and the model writes the turn of the assistant, its output being
+/// streamed as it's generated. Only the last tokens of the conversation that fit in the
+/// context of the model are fed to it. The lengths of the answers are controlled by
+/// `length`, whose end token is the first of the turns of the user. Answers taking
+/// longer than `max_duration`, or interrupted with Ctrl-C, are cut short.
+pub fn run<O: Optimizer, T: Tokenizer>(
+    gpt: &GPT<O>,
+    tokenizer: &T,
+    template: &ChatTemplate,
+    max_tokens: usize,
+    mut temperature: f32,
+    mut length: LengthControl,
+    max_duration: Option<Duration>,
+) -> Result<(), GraphError> {
+    let mut rng = rand::thread_rng();
+    // Characters outside of the vocabulary are dropped from the inputs
+    let known = tokenizer.known_chars();
+    let tokenize = |text: &str| {
+        tokenizer.tokenize(
+            &text
+                .chars()
+                .filter(|ch| known.contains(ch))
+                .collect::<String>(),
+        )
+    };
+    let stop = template.stop();
+    length.end_tokens = match &stop {
+        Some(stop) => tokenize(stop)?.into_iter().take(1).collect(),
+        None => Vec::new(),
+    };
+    let mut conversation: Vec<usize> = Vec::new();
+
+    // Cancelled but while an answer is being written, so that Ctrl-C exits otherwise
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let handle = cancel.clone();
+    ctrlc::set_handler(move || {
+        if handle.is_cancelled() {
+            println!();
+            std::process::exit(130);
+        }
+        handle.cancel();
+    })
+    .map_err(std::io::Error::other)?;
+
+    println!("{}", HELP);
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        match Input::parse(line) {
+            Ok(Input::Text(text)) => {
+                let mut turn = String::new();
+                if conversation.is_empty() {
+                    turn.extend(template.system_turn());
+                }
+                turn.push_str(&template.turn(&Message::new(Role::User, &text)));
+                turn.push_str(&template.assistant_prefix);
+                conversation.extend(tokenize(&turn)?);
+                if conversation.is_empty() {
+                    continue;
+                }
+                let prompt = &conversation[conversation.len().saturating_sub(gpt.num_tokens())..];
+                // Same sampling as `GPT::infer`
+                let sampling = Sampling {
+                    temperature: 1.,
+                    top_p: temperature,
+                };
+                let mut answer = String::new();
+                let mut shown = 0;
+                let mut decoder = StreamDecoder::new(tokenizer);
+                let mut length = length.clone();
+                cancel.reset();
+                let answer_cancel = match max_duration {
+                    Some(max_duration) => cancel.clone().with_timeout(max_duration),
+                    None => cancel.clone(),
+                };
+                let result = gpt.generate_cancellable(
+                    &mut rng,
+                    prompt,
+                    &mut length,
+                    max_tokens,
+                    &sampling,
+                    &answer_cancel,
+                    |ch| {
+                        answer.push_str(&decoder.push(ch).unwrap_or_default());
+                        let (end, stopped) = visible_end(&answer, stop.as_deref());
+                        if end > shown {
+                            print!("{}", &answer[shown..end]);
+                            let _ = std::io::stdout().flush();
+                            shown = end;
+                        }
+                        !stopped
+                    },
+                );
+                let cut_short = answer_cancel.is_cancelled();
+                cancel.cancel();
+                result?;
+                answer.push_str(&decoder.finish());
+                // The answer ends like any other turn
+                let (end, _) = visible_end(&answer, stop.as_deref());
+                if end > shown {
+                    print!("{}", &answer[shown..end]);
+                }
+                conversation.extend(tokenize(&format!(
+                    "{}{}",
+                    &answer[..end],
+                    template.separator
+                ))?);
+                println!();
+                if cut_short {
+                    println!("(Cut short)");
+                }
+            }
+            Ok(Input::Reset) => {
+                conversation.clear();
+                println!("Conversation cleared.");
+            }
+            Ok(Input::Temperature(t)) => {
+                temperature = t;
+                println!("Temperature set to {}.", t);
+            }
+            Ok(Input::Save(path)) => {
+                std::fs::write(&path, tokenizer.untokenize(&conversation)?)?;
+                println!("Conversation saved to {}.", path.display());
+            }
+            Ok(Input::Help) => println!("{}", HELP),
+            Ok(Input::Quit) => return Ok(()),
+            Err(err) => println!("{}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(Input::parse("hi there"), Ok(Input::Text("hi there".into())));
+        assert_eq!(Input::parse("/reset"), Ok(Input::Reset));
+        assert_eq!(Input::parse("/temp 0.8"), Ok(Input::Temperature(0.8)));
+        assert_eq!(
+            Input::parse("/save"),
+            Ok(Input::Save("conversation.txt".into()))
+        );
+        assert_eq!(Input::parse("/save a.txt"), Ok(Input::Save("a.txt".into())));
+        assert!(Input::parse("/temp").is_err());
+        assert!(Input::parse("/temp -1").is_err());
+        assert!(Input::parse("/reset now").is_err());
+        assert!(Input::parse("/fly").is_err());
+    }
+}
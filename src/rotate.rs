@@ -0,0 +1,337 @@
+//! Log files appended to during training (The metrics and the histograms), rotated
+//! once too big or too old so that runs of several weeks don't end up with a single
+//! huge file, the rotated files being gzipped.
+//!
+//! `metrics.jsonl` is rotated to `metrics.1.jsonl.gz`, then `metrics.2.jsonl.gz`...
+//! the highest number being the most recent. Every file starts with the header of the
+//! log (The columns of a CSV, the run of a metrics file), so that each can be read on
+//! its own.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// When log files are rotated. Never, by default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rotation {
+    /// Rotate before the file grows over this many bytes
+    pub max_bytes: Option<u64>,
+    /// Rotate once the file was started this many hours ago
+    pub max_hours: Option<f64>,
+    /// Gzip the rotated files
+    pub compress: bool,
+    /// Rotated files kept, the oldest ones being deleted (All of them if not given)
+    pub keep: Option<usize>,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            max_hours: None,
+            compress: true,
+            keep: None,
+        }
+    }
+}
+
+/// A file lines are appended to, rotated as `rotation` says
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    /// Written at the start of every file
+    header: Option<String>,
+    file: File,
+    len: u64,
+    started: SystemTime,
+    /// Whether nothing but the header was written to the file yet
+    fresh: bool,
+}
+
+impl RotatingFile {
+    /// Appends to `path`, which gets `header` (A line, or several) when it's empty or
+    /// doesn't exist. A last line cut short (By a crash, while it was being written)
+    /// is dropped.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        rotation: &Rotation,
+        header: Option<&str>,
+    ) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), rotation, header, None)
+    }
+
+    /// Like `open`, also dropping the last lines of the file as long as `drop` holds
+    /// for them, e.g. those logged after the checkpoint a training is resumed from,
+    /// which are logged again
+    pub fn resume<P: AsRef<Path>, F: FnMut(&str) -> bool>(
+        path: P,
+        rotation: &Rotation,
+        header: Option<&str>,
+        mut drop: F,
+    ) -> io::Result<Self> {
+        Self::open_with(path.as_ref(), rotation, header, Some(&mut drop))
+    }
+
+    fn open_with(
+        path: &Path,
+        rotation: &Rotation,
+        header: Option<&str>,
+        drop: Option<&mut dyn FnMut(&str) -> bool>,
+    ) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let metadata = file.metadata()?;
+        let len = kept_len(&mut file, metadata.len(), drop)?;
+        if len < metadata.len() {
+            file.set_len(len)?;
+        }
+        let started = if len > 0 {
+            metadata.created().or_else(|_| metadata.modified())?
+        } else {
+            SystemTime::now()
+        };
+        let mut f = Self {
+            path: path.to_path_buf(),
+            rotation: rotation.clone(),
+            header: header.map(String::from),
+            file,
+            len,
+            started,
+            fresh: false,
+        };
+        if len == 0 {
+            f.write_header()?;
+        }
+        Ok(f)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether nothing but the header was written to the current file yet, i.e. it was
+    /// empty when opened, or was just rotated
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Whether the current file starts with `prefix`
+    pub fn starts_with(&self, prefix: &[u8]) -> io::Result<bool> {
+        let mut start = Vec::with_capacity(prefix.len());
+        File::open(&self.path)?
+            .take(prefix.len() as u64)
+            .read_to_end(&mut start)?;
+        Ok(start == prefix)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if let Some(header) = &self.header {
+            self.file.write_all(header.as_bytes())?;
+            self.len += header.len() as u64;
+        }
+        self.fresh = true;
+        Ok(())
+    }
+
+    fn should_rotate(&self, len: usize) -> bool {
+        if self.fresh {
+            return false;
+        }
+        let too_big = self
+            .rotation
+            .max_bytes
+            .is_some_and(|max| self.len + len as u64 > max);
+        let too_old = self.rotation.max_hours.is_some_and(|hours| {
+            let age = self.started.elapsed().unwrap_or(Duration::ZERO);
+            age.as_secs_f64() >= hours * 3600.
+        });
+        too_big || too_old
+    }
+
+    /// Appends `text` (Whole lines), rotating the file first if it's due
+    pub fn append(&mut self, text: &str) -> io::Result<()> {
+        if self.should_rotate(text.len()) {
+            self.rotate()?;
+        }
+        // A single write, for a line not to be cut in two if the process dies
+        self.file.write_all(text.as_bytes())?;
+        self.len += text.len() as u64;
+        self.fresh = false;
+        Ok(())
+    }
+
+    /// The rotated files, with their numbers, oldest first
+    pub fn rotated(&self) -> io::Result<Vec<(usize, PathBuf)>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let (stem, ext) = self.stem_ext();
+        let mut rotated = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let num = name
+                .strip_prefix(&format!("{}.", stem))
+                .map(|rest| rest.strip_suffix(".gz").unwrap_or(rest))
+                .and_then(|rest| match &ext {
+                    Some(ext) => rest.strip_suffix(&format!(".{}", ext)),
+                    None => Some(rest),
+                })
+                .and_then(|num| num.parse::<usize>().ok());
+            if let Some(num) = num {
+                rotated.push((num, path));
+            }
+        }
+        rotated.sort();
+        Ok(rotated)
+    }
+
+    fn stem_ext(&self) -> (String, Option<String>) {
+        let name = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().into_owned());
+        match name(self.path.extension()) {
+            Some(ext) => (name(self.path.file_stem()).unwrap_or_default(), Some(ext)),
+            None => (name(self.path.file_name()).unwrap_or_default(), None),
+        }
+    }
+
+    /// Moves the current file aside (Compressing it, if asked to), and starts a new one
+    pub fn rotate(&mut self) -> io::Result<()> {
+        let rotated = self.rotated()?;
+        let num = rotated.last().map_or(1, |(num, _)| num + 1);
+        let (stem, ext) = self.stem_ext();
+        let name = match ext {
+            Some(ext) => format!("{}.{}.{}", stem, num, ext),
+            None => format!("{}.{}", stem, num),
+        };
+        let target = self.path.with_file_name(name);
+        self.file.flush()?;
+        std::fs::rename(&self.path, &target)?;
+        if self.rotation.compress {
+            let mut gz = target.clone().into_os_string();
+            gz.push(".gz");
+            let mut encoder = GzEncoder::new(File::create(gz)?, Compression::default());
+            io::copy(&mut File::open(&target)?, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            std::fs::remove_file(target)?;
+        }
+        if let Some(keep) = self.rotation.keep {
+            let rotated = self.rotated()?;
+            for (_, path) in &rotated[..rotated.len().saturating_sub(keep)] {
+                std::fs::remove_file(path)?;
+            }
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = 0;
+        self.started = SystemTime::now();
+        self.write_header()
+    }
+}
+
+/// Length of a file of `len` bytes once a last line cut short is dropped, along with
+/// the last lines `drop` holds for
+fn kept_len(
+    file: &mut File,
+    len: u64,
+    drop: Option<&mut dyn FnMut(&str) -> bool>,
+) -> io::Result<u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+    let mut last = [0];
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.read_exact(&mut last)?;
+    if last[0] == b'\n' && drop.is_none() {
+        return Ok(len);
+    }
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut bytes)?;
+    let mut end = bytes.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    if let Some(drop) = drop {
+        while end > 0 {
+            let line = &bytes[..end - 1];
+            let start = line.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+            if !drop(&String::from_utf8_lossy(&line[start..])) {
+                break;
+            }
+            end = start;
+        }
+    }
+    Ok(end as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join("femto_gpt_test_rotation");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.csv");
+        let rotation = Rotation {
+            max_bytes: Some(12),
+            keep: Some(2),
+            ..Default::default()
+        };
+        let mut log = RotatingFile::open(&path, &rotation, Some("a,b\n")).unwrap();
+        assert!(log.is_fresh());
+        for i in 0..10 {
+            log.append(&format!("{},{}\n", i, i)).unwrap();
+        }
+        // 2 lines of 4 bytes per file, after the header
+        let rotated = log.rotated().unwrap();
+        assert_eq!(
+            rotated.iter().map(|(num, _)| *num).collect::<Vec<_>>(),
+            [3, 4]
+        );
+        assert!(rotated[0].1.to_str().unwrap().ends_with("log.3.csv.gz"));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(File::open(&rotated[1].1).unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "a,b\n6,6\n7,7\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a,b\n8,8\n9,9\n");
+
+        // Resumed after a crash in the middle of a line
+        drop(log);
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(b"10,1").unwrap();
+        let mut log = RotatingFile::open(&path, &Rotation::default(), Some("a,b\n")).unwrap();
+        assert!(!log.is_fresh());
+        log.append("10,10\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "a,b\n8,8\n9,9\n10,10\n"
+        );
+        let log = RotatingFile::resume(&path, &Rotation::default(), Some("a,b\n"), |l| l != "8,8")
+            .unwrap();
+        assert_eq!(log.len(), 8);
+        assert!(log.starts_with(b"a,b\n").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+//! Metadata of training runs, so that a checkpoint or a metrics file can be traced
+//! back to the settings and the data it came from.
+//!
+//! `femto-gpt train` saves the `RunInfo` of the run next to the training state (As
+//! `<state>.run.json`), or in the `run` section of bundles, and as the first line of
+//! its metrics file (See `MetricsLog`). `find_runs` looks for them in a directory.
+//!
+//! The hashes of the dataset and of the tokenizer of a run are kept in its checkpoints
+//! as well (See `gpt::Provenance`), which are checked against them.
+
+use crate::config::{Config, ConfigError};
+use crate::gpt::StepStats;
+use crate::rotate::{RotatingFile, Rotation};
+use crate::tensor::xxh64;
+use crate::tokenizer::Tokenizer;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Extension of the files `RunInfo::save` writes, after that of the training state
+pub const EXTENSION: &str = "run.json";
+
+/// Where and how a model was trained
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunInfo {
+    /// Start time and config hash, e.g. `20240131-235959-0123abcd`
+    pub id: String,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    /// XXH64 of the config (As TOML), in hex
+    pub config_hash: String,
+    /// XXH64 of the dataset, in hex
+    pub dataset_hash: String,
+    /// Hash of the vocabulary of the tokenizer, in hex (See `tokenizer_hash`; missing
+    /// from the runs of older versions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer_hash: Option<String>,
+    /// `git describe` of the working directory, if it's in a repository
+    pub git: Option<String>,
+    pub host: String,
+    pub os: String,
+    pub cpus: usize,
+    pub version: String,
+}
+
+/// `YYYYMMDD-HHMMSS` of a Unix timestamp, in UTC
+fn format_time(secs: u64) -> String {
+    // Howard Hinnant's civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let rem = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn git_describe() -> Option<String> {
+    let out = std::process::Command::new("git")
+        .args(["describe", "--always", "--dirty", "--tags"])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    let describe = String::from_utf8(out.stdout).ok()?.trim().to_string();
+    (out.status.success() && !describe.is_empty()).then_some(describe)
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// XXH64 of the documents of a dataset, in order
+pub fn dataset_hash(documents: &[(PathBuf, String)]) -> u64 {
+    let hashes = documents
+        .iter()
+        .flat_map(|(_, text)| xxh64(text.as_bytes(), 0).to_le_bytes())
+        .collect::<Vec<_>>();
+    xxh64(&hashes, 0)
+}
+
+/// XXH64 of the strings of the tokens of a tokenizer, in order. (Two tokenizers with
+/// the same hash turn the same text into the same tokens, whatever their type)
+pub fn tokenizer_hash<T: Tokenizer + ?Sized>(tokenizer: &T) -> u64 {
+    let mut bytes = Vec::new();
+    for token in 0..tokenizer.vocab_size() {
+        let string = tokenizer.untokenize(&[token]).unwrap_or_default();
+        bytes.extend((string.len() as u64).to_le_bytes());
+        bytes.extend(string.as_bytes());
+    }
+    xxh64(&bytes, 0)
+}
+
+impl RunInfo {
+    /// The run starting now, training with `config` on `documents`, tokenized by
+    /// `tokenizer`
+    pub fn new<T: Tokenizer + ?Sized>(
+        config: &Config,
+        documents: &[(PathBuf, String)],
+        tokenizer: &T,
+    ) -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let config_hash = format!("{:016x}", xxh64(config.to_toml().as_bytes(), 0));
+        Self {
+            id: format!("{}-{}", format_time(started_at), &config_hash[..8]),
+            started_at,
+            config_hash,
+            dataset_hash: format!("{:016x}", dataset_hash(documents)),
+            tokenizer_hash: Some(format!("{:016x}", tokenizer_hash(tokenizer))),
+            git: git_describe(),
+            host: hostname(),
+            os: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            version: env!("CARGO_PKG_VERSION").into(),
+        }
+    }
+
+    /// Where the run of a training state is saved
+    pub fn path_of(state: &Path) -> PathBuf {
+        state.with_extension(EXTENSION)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("A run is always representable in JSON")
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, ConfigError> {
+        serde_json::from_slice(json)
+            .map_err(|e| ConfigError::Invalid(format!("invalid run: {}", e)))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Self::from_json(&std::fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        Ok(std::fs::write(path, self.to_json())?)
+    }
+}
+
+/// A line of a metrics file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetricsRecord {
+    /// The first line, describing the run
+    Run { run: RunInfo },
+    Step {
+        step: usize,
+        loss: f32,
+        learning_rate: f32,
+        grad_norm: f32,
+        accuracy: f32,
+        tokens_per_second: f32,
+        /// Privacy budget spent so far, when training with DP-SGD
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        epsilon: Option<f64>,
+    },
+}
+
+/// Metrics of every training step, appended to a JSONL file after the `RunInfo` of
+/// the run, so that each run in the file starts with its own, as does every file the
+/// log is rotated to
+pub struct MetricsLog {
+    file: RotatingFile,
+}
+
+fn json_line(record: &MetricsRecord) -> std::io::Result<String> {
+    let mut line = serde_json::to_string(record).map_err(std::io::Error::other)?;
+    line.push('\n');
+    Ok(line)
+}
+
+impl MetricsLog {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        run: &RunInfo,
+        rotation: &Rotation,
+    ) -> std::io::Result<Self> {
+        let header = json_line(&MetricsRecord::Run { run: run.clone() })?;
+        Self::with_file(RotatingFile::open(path, rotation, Some(&header))?, header)
+    }
+
+    /// Like `new`, dropping the steps logged after `step`, for a training resumed from a
+    /// checkpoint of that step not to log them twice
+    pub fn resume<P: AsRef<Path>>(
+        path: P,
+        run: &RunInfo,
+        rotation: &Rotation,
+        step: usize,
+    ) -> std::io::Result<Self> {
+        let header = json_line(&MetricsRecord::Run { run: run.clone() })?;
+        let file = RotatingFile::resume(path, rotation, Some(&header), |line| {
+            matches!(
+                serde_json::from_str(line),
+                Ok(MetricsRecord::Step { step: logged, .. }) if logged > step
+            )
+        })?;
+        Self::with_file(file, header)
+    }
+
+    fn with_file(mut file: RotatingFile, header: String) -> std::io::Result<Self> {
+        // The header only gets written to new files
+        if !file.is_fresh() {
+            file.append(&header)?;
+        }
+        Ok(Self { file })
+    }
+
+    pub fn log(&mut self, stats: &StepStats) -> std::io::Result<()> {
+        self.file.append(&json_line(&MetricsRecord::Step {
+            step: stats.step,
+            loss: stats.loss,
+            learning_rate: stats.learning_rate,
+            grad_norm: stats.grad_norm,
+            accuracy: stats.accuracy.top1_rate(),
+            tokens_per_second: stats.tokens_per_second,
+            epsilon: stats.epsilon,
+        })?)
+    }
+}
+
+/// A run found in a directory, with the checkpoint it belongs to
+#[derive(Debug, Clone)]
+pub struct FoundRun {
+    pub run: RunInfo,
+    pub checkpoint: PathBuf,
+}
+
+/// The runs of the checkpoints in `dir` and its subdirectories (Training states with
+/// their `.run.json` next to them, and bundles), oldest first
+pub fn find_runs<P: AsRef<Path>>(dir: P) -> Result<Vec<FoundRun>, ConfigError> {
+    let mut runs = Vec::new();
+    let mut dirs = vec![dir.as_ref().to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if path.is_dir() {
+                if !name.starts_with('.') && name != "target" {
+                    dirs.push(path);
+                }
+            } else if let Some(stem) = name.strip_suffix(&format!(".{}", EXTENSION)) {
+                // The training state is whatever has the same stem
+                let checkpoint = std::fs::read_dir(&dir)?
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .find(|p| {
+                        p.file_stem().and_then(|s| s.to_str()) == Some(stem)
+                            && !matches!(
+                                p.extension().and_then(|e| e.to_str()),
+                                Some("toml" | "optimizer")
+                            )
+                    })
+                    .unwrap_or_else(|| dir.join(stem));
+                runs.push(FoundRun {
+                    run: RunInfo::load(&path)?,
+                    checkpoint,
+                });
+            } else if crate::bundle::is_bundle(&path) {
+                if let Ok(Some(run)) = crate::bundle::Bundle::load(&path).map(|b| b.run) {
+                    runs.push(FoundRun {
+                        run,
+                        checkpoint: path,
+                    });
+                }
+            }
+        }
+    }
+    runs.sort_by(|a, b| (a.run.started_at, &a.run.id).cmp(&(b.run.started_at, &b.run.id)));
+    Ok(runs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::SimpleTokenizer;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "19700101-000000");
+        assert_eq!(format_time(951782400 + 3661), "20000229-010101");
+        assert_eq!(format_time(1706745599), "20240131-235959");
+    }
+
+    #[test]
+    fn test_runs() {
+        let dir = std::env::temp_dir().join("femto_gpt_test_runs");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        let documents = vec![("a.txt".into(), "abc".to_string())];
+        let config = Config::default();
+        let tokenizer = SimpleTokenizer::new("abcd");
+        let run = RunInfo::new(&config, &documents, &tokenizer);
+        assert!(run.id.ends_with(&run.config_hash[..8]));
+        assert_eq!(
+            run.dataset_hash,
+            RunInfo::new(&config, &documents, &tokenizer).dataset_hash
+        );
+        let other = vec![("a.txt".into(), "abd".to_string())];
+        assert_ne!(
+            run.dataset_hash,
+            RunInfo::new(&config, &other, &tokenizer).dataset_hash
+        );
+        assert_eq!(
+            run.tokenizer_hash,
+            RunInfo::new(&config, &documents, &SimpleTokenizer::new("dcba")).tokenizer_hash
+        );
+        assert_ne!(
+            run.tokenizer_hash,
+            RunInfo::new(&config, &documents, &SimpleTokenizer::new("abcde")).tokenizer_hash
+        );
+        let old = RunInfo {
+            tokenizer_hash: None,
+            ..run.clone()
+        };
+        assert_eq!(RunInfo::from_json(old.to_json().as_bytes()).unwrap(), old);
+
+        let state = dir.join("b").join("training_state.dat");
+        std::fs::write(&state, b"").unwrap();
+        run.save(RunInfo::path_of(&state)).unwrap();
+        let found = find_runs(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].run, run);
+        assert_eq!(found[0].checkpoint, state);
+
+        let path = dir.join("metrics.jsonl");
+        let rotation = Rotation::default();
+        MetricsLog::new(&path, &run, &rotation).unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let first: MetricsRecord = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(first, MetricsRecord::Run { run: run.clone() });
+
+        // Steps 1 and 2 were logged, then the training crashed and is resumed from step 1
+        let mut log = MetricsLog::new(&path, &run, &rotation).unwrap();
+        for step in 1..=2 {
+            let record = MetricsRecord::Step {
+                step,
+                loss: 1.,
+                learning_rate: 1.,
+                grad_norm: 1.,
+                accuracy: 0.,
+                tokens_per_second: 1.,
+                epsilon: (step == 2).then_some(0.5),
+            };
+            log.file.append(&json_line(&record).unwrap()).unwrap();
+        }
+        MetricsLog::resume(&path, &run, &rotation, 1).unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let records = lines
+            .lines()
+            .map(|l| serde_json::from_str::<MetricsRecord>(l).unwrap())
+            .map(|r| match r {
+                MetricsRecord::Run { .. } => None,
+                MetricsRecord::Step { step, .. } => Some(step),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(records, [None, None, Some(1), None]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//! Batches of samples, recorded along with everything needed to generate them again:
+//! the checkpoint they came from, the sampling settings and the seed.
+
+use femto_gpt::gpt::{seeded_rng, Sampling, GPT};
+use femto_gpt::graph::GraphError;
+use femto_gpt::optimizer::Optimizer;
+use femto_gpt::tokenizer::Tokenizer;
+use serde::{Deserialize, Serialize};
+
+/// A generated sample, as a line of the JSONL output
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SampleRecord {
+    pub index: usize,
+    /// Seed of this very sample: `--seed <SEED> --n 1` generates it again
+    pub seed: u64,
+    pub prompt: String,
+    pub text: String,
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub top_p: f32,
+    /// XXH64 of the training state file, in hex
+    pub checkpoint: String,
+    /// Training step of the checkpoint
+    pub step: usize,
+    pub version: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SampleOptions {
+    pub prompt: String,
+    pub n: usize,
+    /// Sample `i` is generated with `seed + i`
+    pub seed: u64,
+    pub max_tokens: usize,
+    pub sampling: Sampling,
+}
+
+pub fn run<O: Optimizer, T: Tokenizer>(
+    gpt: &GPT<O>,
+    tokenizer: &T,
+    opts: &SampleOptions,
+    checkpoint: u64,
+) -> Result<Vec<SampleRecord>, GraphError> {
+    let prompt = tokenizer.tokenize(&opts.prompt)?;
+    // Only the end of a long prompt fits in the context
+    let prompt = &prompt[prompt.len().saturating_sub(gpt.num_tokens())..];
+    (0..opts.n)
+        .map(|index| {
+            let seed = opts.seed.wrapping_add(index as u64);
+            let mut rng = seeded_rng(seed);
+            let tokens =
+                gpt.generate(&mut rng, prompt, opts.max_tokens, &opts.sampling, |_| true)?;
+            Ok(SampleRecord {
+                index,
+                seed,
+                prompt: opts.prompt.clone(),
+                text: tokenizer.untokenize(&tokens)?,
+                max_tokens: opts.max_tokens,
+                temperature: opts.sampling.temperature,
+                top_p: opts.sampling.top_p,
+                checkpoint: format!("{:016x}", checkpoint),
+                step: gpt.optimizer().step_num(),
+                version: env!("CARGO_PKG_VERSION").into(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use femto_gpt::config::ModelConfig;
+    use femto_gpt::optimizer::AdamW;
+    use femto_gpt::tokenizer::SimpleTokenizer;
+
+    #[test]
+    fn test_sample() {
+        let tokenizer = SimpleTokenizer::new("ROMEO: Hi.\n");
+        let model = ModelConfig {
+            num_tokens: 8,
+            embedding_degree: 8,
+            num_layers: 1,
+            num_heads: 2,
+            dropout: 0.,
+            ..Default::default()
+        };
+        let gpt = model
+            .build(
+                &mut rand::thread_rng(),
+                tokenizer.vocab_size(),
+                AdamW::new(),
+            )
+            .unwrap();
+        let opts = SampleOptions {
+            prompt: "ROMEO:".into(),
+            n: 3,
+            seed: 42,
+            max_tokens: 10,
+            sampling: Sampling::default(),
+        };
+        let samples = run(&gpt, &tokenizer, &opts, 0xabc).unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[2].seed, 44);
+        assert_eq!(samples[0].checkpoint, "0000000000000abc");
+
+        // The third sample, on its own
+        let again = SampleOptions {
+            n: 1,
+            seed: 44,
+            ..opts
+        };
+        assert_eq!(
+            run(&gpt, &tokenizer, &again, 0xabc).unwrap()[0].text,
+            samples[2].text
+        );
+        let line = serde_json::to_string(&samples[0]).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SampleRecord>(&line).unwrap(),
+            samples[0]
+        );
+    }
+}
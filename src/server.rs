@@ -0,0 +1,1511 @@
+//! An HTTP server exposing a model through OpenAI-compatible `/v1/completions`,
+//! `/v1/chat/completions` and `/v1/embeddings` endpoints, so that existing OpenAI
+//! clients can talk to it. The model can be reloaded while serving (See `watch`), e.g.
+//! from the checkpoints of a training run going on. Chats can be kept on the server as
+//! named sessions (See `Sessions`), for clients to only send the new turns and the
+//! model to only run those, and the generations going on can be batched together (See
+//! `batch`). Clients can be limited in how often and how much they ask (See `Limits`).
+
+use crate::gpt::{CancellationToken, KvCache, LengthControl, Pooling, Sampling, Truncation, GPT};
+use crate::optimizer::Optimizer;
+use crate::template::{ChatTemplate, Message};
+use crate::tensor::TensorOps;
+use crate::tokenizer::{StreamDecoder, Tokenizer};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Body of a `/v1/completions` request. (Unsupported fields are ignored)
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionRequest {
+    pub prompt: Prompt,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default = "default_one")]
+    pub temperature: f32,
+    #[serde(default = "default_one")]
+    pub top_p: f32,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stop: Option<Prompt>,
+    /// Part of a prompt too long for the context to keep, room being left for the
+    /// `max_tokens` to generate (The server's, see `Model::set_truncation`, if not given)
+    #[serde(default)]
+    pub truncation: Option<Truncation>,
+    /// Tokens to generate before a stop sequence may start (See `LengthControl`)
+    #[serde(default)]
+    pub min_tokens: usize,
+    /// Added to the logits of the first tokens of the stop sequences for every token
+    /// generated after `min_tokens`
+    #[serde(default)]
+    pub length_penalty: f32,
+    /// Seconds the generation may take, after which it stops with the finish reason
+    /// "timeout" (Up to the server's limit, see `Limits::max_duration`)
+    #[serde(default)]
+    pub timeout: Option<f64>,
+}
+
+/// Body of a `/v1/chat/completions` request, the conversation being written out with
+/// the chat template of the model
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub messages: Vec<Message>,
+    /// Name of a conversation kept on the server: the messages are its new turns, and
+    /// the answer is added to it (A new one is started if unknown, e.g. evicted)
+    #[serde(default)]
+    pub session: Option<String>,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default = "default_one")]
+    pub temperature: f32,
+    #[serde(default = "default_one")]
+    pub top_p: f32,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stop: Option<Prompt>,
+    #[serde(default)]
+    pub truncation: Option<Truncation>,
+    /// Tokens to generate before the turn of the assistant may end
+    #[serde(default)]
+    pub min_tokens: usize,
+    #[serde(default)]
+    pub length_penalty: f32,
+    #[serde(default)]
+    pub timeout: Option<f64>,
+}
+
+/// Body of a `/v1/embeddings` request
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmbeddingRequest {
+    pub input: Prompt,
+}
+
+fn default_max_tokens() -> usize {
+    16
+}
+
+fn default_one() -> f32 {
+    1.
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Choice {
+    text: String,
+    index: usize,
+    logprobs: Option<()>,
+    finish_reason: Option<&'static str>,
+}
+
+/// Default memory budget of the sessions (64 MiB)
+pub const DEFAULT_SESSION_BUDGET: usize = 64 << 20;
+
+/// A conversation kept on the server
+struct Session {
+    tokens: Vec<usize>,
+    /// The attention of the model over its last window, unless a request is using it
+    cache: Option<KvCache>,
+    /// When it was last used, in calls to `Sessions`
+    last_used: u64,
+}
+
+impl Session {
+    fn memory(&self) -> usize {
+        self.tokens.len() * std::mem::size_of::<usize>()
+            + self.cache.as_ref().map_or(0, |c| c.memory())
+    }
+}
+
+/// A conversation taken out of `Sessions` for a request to continue it
+#[derive(Debug, Clone, Default)]
+pub struct SessionState {
+    pub tokens: Vec<usize>,
+    pub cache: KvCache,
+    /// The model the cache is of (See `Sessions::invalidate`)
+    epoch: u64,
+}
+
+#[derive(Default)]
+struct SessionsInner {
+    sessions: HashMap<String, Session>,
+    clock: u64,
+    memory: usize,
+    epoch: u64,
+}
+
+impl SessionsInner {
+    fn remove(&mut self, name: &str) -> bool {
+        match self.sessions.remove(name) {
+            Some(session) => {
+                self.memory -= session.memory();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Conversations kept on the server by name, as the tokens they're written as (So
+/// that they're only tokenized once) and the attention of the model over them (So that
+/// only their new tokens are run through it, see `GPT::generate_cached`), the least
+/// recently used ones being dropped once they take more than the memory budget
+pub struct Sessions {
+    budget: usize,
+    inner: Mutex<SessionsInner>,
+}
+
+impl Sessions {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            inner: Mutex::new(SessionsInner::default()),
+        }
+    }
+
+    /// The conversation named `name`, if it's kept. Its cache is taken out until it's
+    /// put back, the requests continuing it meanwhile starting from an empty one.
+    pub fn get(&self, name: &str) -> Option<SessionState> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let (clock, epoch) = (inner.clock, inner.epoch);
+        let session = inner.sessions.get_mut(name)?;
+        session.last_used = clock;
+        let state = SessionState {
+            tokens: session.tokens.clone(),
+            cache: session.cache.take().unwrap_or_default(),
+            epoch,
+        };
+        inner.memory -= state.cache.memory();
+        Some(state)
+    }
+
+    /// A conversation that isn't kept yet
+    pub fn start(&self) -> SessionState {
+        SessionState {
+            epoch: self.inner.lock().unwrap().epoch,
+            ..Default::default()
+        }
+    }
+
+    /// Keeps `state` as the conversation named `name`, dropping the least recently
+    /// used other ones while over budget. (A conversation over budget on its own isn't
+    /// kept) Its cache is dropped if the model changed since it was taken out.
+    pub fn put(&self, name: &str, state: SessionState) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let session = Session {
+            tokens: state.tokens,
+            cache: (state.epoch == inner.epoch).then_some(state.cache),
+            last_used: inner.clock,
+        };
+        inner.memory += session.memory();
+        if let Some(old) = inner.sessions.insert(name.into(), session) {
+            inner.memory -= old.memory();
+        }
+        while inner.memory > self.budget {
+            let Some(lru) = inner
+                .sessions
+                .iter()
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(name, _)| name.clone())
+            else {
+                break;
+            };
+            inner.remove(&lru);
+        }
+    }
+
+    /// Drops the caches of the conversations, including the ones taken out, for the
+    /// model they're of was replaced
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.epoch += 1;
+        for session in inner.sessions.values_mut() {
+            session.cache = None;
+        }
+        inner.memory = inner.sessions.values().map(|s| s.memory()).sum();
+    }
+
+    /// Forgets the conversation named `name`. Returns whether it was kept.
+    pub fn remove(&self, name: &str) -> bool {
+        self.inner.lock().unwrap().remove(name)
+    }
+
+    /// Number of conversations kept, and the bytes they take
+    pub fn usage(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        (inner.sessions.len(), inner.memory)
+    }
+}
+
+/// What clients may ask of the server, for a public one not to be overwhelmed by a few
+/// of them. Unlimited, by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// Requests a client (By IP address) may make per minute, in bursts of up to as
+    /// many (None at all for 0)
+    pub requests_per_minute: Option<u32>,
+    /// Tokens the prompt of a request may have (Before it's truncated to fit in the
+    /// context). The turns a chat session kept on the server already had don't count.
+    pub max_prompt_tokens: Option<usize>,
+    /// `max_tokens` a request may ask for
+    pub max_tokens: Option<usize>,
+    /// Size of the body of a request, in bytes
+    pub max_body_bytes: Option<usize>,
+    /// Time a generation may take, whatever the request asks for
+    pub max_duration: Option<Duration>,
+}
+
+impl Limits {
+    /// Why a request with a prompt of `prompt_tokens` generating `max_tokens` is
+    /// refused, if it is
+    pub fn check(&self, prompt_tokens: usize, max_tokens: usize) -> Result<(), String> {
+        if let Some(max) = self.max_prompt_tokens.filter(|max| prompt_tokens > *max) {
+            return Err(format!(
+                "the prompt has {} tokens, more than the {} allowed",
+                prompt_tokens, max
+            ));
+        }
+        if let Some(max) = self.max_tokens.filter(|max| max_tokens > *max) {
+            return Err(format!(
+                "max_tokens is {}, more than the {} allowed",
+                max_tokens, max
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Longest "timeout" a request may ask for, whatever the server's limit
+const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 3600);
+
+/// Clients that used up their requests for now get no more buckets than this, the
+/// full ones being dropped first
+const MAX_CLIENTS: usize = 1 << 16;
+
+/// Token buckets rate-limiting the requests of each client
+struct RateLimiter {
+    per_minute: Option<u32>,
+    /// Requests each client may still make, as of when it was last updated
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(per_minute: Option<u32>) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a request from the bucket of `client` at `now`, or tells how long until
+    /// there's one again
+    fn admit(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let per_minute = match self.per_minute {
+            None => return Ok(()),
+            Some(0) => return Err(Duration::from_secs(60)),
+            Some(per_minute) => per_minute,
+        };
+        let capacity = per_minute as f64;
+        let per_sec = capacity / 60.;
+        let refill = |(left, since): (f64, Instant)| {
+            (left + now.saturating_duration_since(since).as_secs_f64() * per_sec).min(capacity)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| refill(*bucket) < capacity);
+            if buckets.len() >= MAX_CLIENTS {
+                return Err(Duration::from_secs(1));
+            }
+        }
+        let left = buckets
+            .get(&client)
+            .map_or(capacity, |bucket| refill(*bucket));
+        if left < 1. {
+            buckets.insert(client, (left, now));
+            let wait = Duration::try_from_secs_f64((1. - left) / per_sec);
+            return Err(wait.unwrap_or(Duration::from_secs(60)));
+        }
+        buckets.insert(client, (left - 1., now));
+        Ok(())
+    }
+}
+
+/// A generation handed to the batcher (See `batch`)
+struct Job {
+    /// The prompt, then the tokens generated so far
+    tokens: Vec<usize>,
+    /// Tokens left to generate
+    count: usize,
+    sampling: Sampling,
+    /// Where the tokens go as they're generated, until the receiver is dropped
+    tx: mpsc::Sender<Result<usize, String>>,
+    cancel: CancellationToken,
+}
+
+/// A model being served, shared by the workers
+pub struct Model<O: Optimizer, T: Tokenizer> {
+    pub(crate) name: String,
+    /// Swapped for a newer one by `reload`, the requests going on keeping the one they
+    /// started with
+    gpt: RwLock<Arc<GPT<O>>>,
+    pub(crate) tokenizer: T,
+    template: ChatTemplate,
+    /// Characters of the vocabulary, the others are dropped from the prompts
+    known: HashSet<char>,
+    sessions: Sessions,
+    /// Part of the prompts too long for the context kept, by default
+    truncation: Truncation,
+    pub(crate) limits: Limits,
+    rate_limiter: RateLimiter,
+    /// Where the generations go when they're batched
+    batcher: OnceLock<mpsc::Sender<Job>>,
+    next_id: AtomicUsize,
+}
+
+impl<O: Optimizer, T: Tokenizer> Model<O, T> {
+    pub fn new(name: &str, gpt: GPT<O>, tokenizer: T, template: ChatTemplate) -> Self {
+        let known = tokenizer.known_chars();
+        Self {
+            name: name.into(),
+            gpt: RwLock::new(Arc::new(gpt)),
+            tokenizer,
+            template,
+            known,
+            sessions: Sessions::new(DEFAULT_SESSION_BUDGET),
+            truncation: Truncation::default(),
+            limits: Limits::default(),
+            rate_limiter: RateLimiter::new(None),
+            batcher: OnceLock::new(),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Memory the sessions may take, in bytes (See `Sessions`)
+    pub fn set_session_budget(&mut self, budget: usize) {
+        self.sessions = Sessions::new(budget);
+    }
+
+    /// Part of the prompts too long for the context kept, when the requests don't say
+    pub fn set_truncation(&mut self, truncation: Truncation) {
+        self.truncation = truncation;
+    }
+
+    /// What clients may ask of the server (See `Limits`)
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.rate_limiter = RateLimiter::new(limits.requests_per_minute);
+        self.limits = limits;
+    }
+
+    /// Counts a request of `client` against its rate limit. Tells how long until it
+    /// may make another when it's over it.
+    pub(crate) fn admit(&self, client: IpAddr) -> Result<(), Duration> {
+        self.rate_limiter.admit(client, Instant::now())
+    }
+
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// The model currently served
+    pub(crate) fn gpt(&self) -> Arc<GPT<O>> {
+        self.gpt.read().unwrap().clone()
+    }
+
+    /// Serves `gpt` from now on. (Its vocabulary should be that of the tokenizer)
+    pub fn reload(&self, gpt: GPT<O>) {
+        *self.gpt.write().unwrap() = Arc::new(gpt);
+        self.sessions.invalidate();
+    }
+
+    /// The tokens of `text`, without the characters outside of the vocabulary
+    pub(crate) fn tokenize_all(&self, text: &str) -> Vec<usize> {
+        let text = text
+            .chars()
+            .filter(|ch| self.known.contains(ch))
+            .collect::<String>();
+        // Only known characters are left, so tokenizing can't fail
+        self.tokenizer.tokenize(&text).unwrap_or_default()
+    }
+
+    /// The last tokens of `text` that fit in the context of the model
+    pub(crate) fn tokenize(&self, text: &str) -> Vec<usize> {
+        let tokens = self.tokenize_all(text);
+        tokens[tokens.len().saturating_sub(self.gpt().num_tokens())..].to_vec()
+    }
+
+    /// The tokens of a prompt that fit in the context of the model along with the
+    /// tokens `req` generates, truncated as it says (See `GPT::fit_prompt`)
+    pub(crate) fn fit(&self, tokens: &[usize], req: &CompletionRequest) -> Vec<usize> {
+        let truncation = req.truncation.unwrap_or(self.truncation);
+        let fitted = self.gpt().fit_prompt(tokens, req.max_tokens, truncation);
+        if fitted.len() < tokens.len() {
+            warn!(
+                dropped = tokens.len() - fitted.len(),
+                kept = fitted.len(),
+                ?truncation,
+                "Truncating a prompt too long for the context"
+            );
+        }
+        fitted
+    }
+
+    /// The conversation `req` continues (Up to its generation prompt), when it's kept
+    /// on the server
+    fn session_state(&self, req: &ChatCompletionRequest) -> Option<SessionState> {
+        let name = req.session.as_ref()?;
+        let mut state = self
+            .sessions
+            .get(name)
+            .unwrap_or_else(|| self.sessions.start());
+        let text = if state.tokens.is_empty() {
+            self.template.render(&req.messages, true)
+        } else {
+            let mut text = req
+                .messages
+                .iter()
+                .map(|m| self.template.turn(m))
+                .collect::<String>();
+            text.push_str(&self.template.assistant_prefix);
+            text
+        };
+        state.tokens.extend(self.tokenize_all(&text));
+        Some(state)
+    }
+
+    /// Embedding of `text` (Mean of the final hidden states), along with its number
+    /// of tokens
+    pub(crate) fn embed(
+        &self,
+        text: &str,
+        layer: Option<usize>,
+    ) -> Result<(Vec<f32>, usize), String> {
+        let tokens = self.tokenize(text);
+        let embedding = self
+            .gpt()
+            .embed_text(&tokens, layer, Pooling::Mean)
+            .map_err(|e| e.to_string())?;
+        Ok((embedding.blob().to_vec(), tokens.len()))
+    }
+
+    /// The completion request answering a conversation: the assistant's turn ends
+    /// where the user's would start
+    fn chat_request(&self, req: ChatCompletionRequest) -> CompletionRequest {
+        let mut stops = match req.stop {
+            None => vec![],
+            Some(Prompt::One(s)) => vec![s],
+            Some(Prompt::Many(s)) => s,
+        };
+        stops.extend(self.template.stop());
+        CompletionRequest {
+            prompt: Prompt::One(self.template.render(&req.messages, true)),
+            max_tokens: req.max_tokens,
+            temperature: req.temperature,
+            top_p: req.top_p,
+            stream: req.stream,
+            stop: Some(Prompt::Many(stops)),
+            truncation: req.truncation,
+            min_tokens: req.min_tokens,
+            length_penalty: req.length_penalty,
+            timeout: req.timeout,
+        }
+    }
+
+    /// Generates up to `count` tokens after `prompt`, handing each to `callback` (Which
+    /// returns whether to go on) until `cancel` is cancelled, alone or along with the
+    /// other generations going on when they're batched. (Generations whose `length` is
+    /// controlled, or that continue the attention of a `cache`, run alone)
+    #[allow(clippy::too_many_arguments)]
+    fn generate<F: FnMut(usize) -> bool>(
+        &self,
+        prompt: &[usize],
+        count: usize,
+        sampling: &Sampling,
+        length: &LengthControl,
+        cancel: &CancellationToken,
+        cache: Option<&mut KvCache>,
+        mut callback: F,
+    ) -> Result<Vec<usize>, String> {
+        let mut rng = rand::thread_rng();
+        let mut length = length.clone();
+        if let Some(cache) = cache {
+            return self
+                .gpt()
+                .generate_cached(
+                    &mut rng,
+                    cache,
+                    prompt,
+                    &mut length,
+                    count,
+                    sampling,
+                    cancel,
+                    callback,
+                )
+                .map_err(|e| e.to_string());
+        }
+        let batcher = self.batcher.get().filter(|_| length.is_none());
+        let Some(batcher) = batcher else {
+            return self
+                .gpt()
+                .generate_cancellable(
+                    &mut rng,
+                    prompt,
+                    &mut length,
+                    count,
+                    sampling,
+                    cancel,
+                    callback,
+                )
+                .map_err(|e| e.to_string());
+        };
+        let (tx, rx) = mpsc::channel();
+        let job = Job {
+            tokens: prompt.to_vec(),
+            count,
+            sampling: sampling.clone(),
+            tx,
+            cancel: cancel.clone(),
+        };
+        batcher
+            .send(job)
+            .map_err(|_| "the batcher is gone".to_string())?;
+        let mut tokens = Vec::new();
+        // Dropping the receiver tells the batcher to stop
+        for token in rx {
+            let token = token?;
+            tokens.push(token);
+            if !callback(token) {
+                break;
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Runs the completion, handing each new piece of text to `emit` (Which returns
+    /// whether to go on). Returns the finish reason and the number of prompt and
+    /// completion tokens. (The attention of the model over the tokens of a session is
+    /// kept in its `cache`)
+    pub(crate) fn complete<F: FnMut(&str) -> bool>(
+        &self,
+        req: &CompletionRequest,
+        prompt: &[usize],
+        cache: Option<&mut KvCache>,
+        mut emit: F,
+    ) -> Result<(&'static str, usize), String> {
+        let mut stops = match &req.stop {
+            None => vec![],
+            Some(Prompt::One(s)) => vec![s.clone()],
+            Some(Prompt::Many(s)) => s.clone(),
+        };
+        stops.retain(|s| !s.is_empty());
+        let sampling = Sampling {
+            temperature: req.temperature,
+            top_p: req.top_p,
+        };
+        let length = LengthControl {
+            end_tokens: stops
+                .iter()
+                .filter_map(|s| self.tokenize_all(s).first().copied())
+                .collect(),
+            min_new_tokens: req.min_tokens,
+            length_penalty: req.length_penalty,
+        };
+        let mut text = String::new();
+        let mut decoder = StreamDecoder::new(&self.tokenizer);
+        // Text that could be the start of a stop sequence is held back
+        let mut emitted = 0;
+        let mut finish_reason = "length";
+        let timeout = req
+            .timeout
+            .and_then(|t| Duration::try_from_secs_f64(t).ok())
+            .map(|t| t.min(MAX_TIMEOUT))
+            .into_iter()
+            .chain(self.limits.max_duration)
+            .min();
+        let cancel = match timeout {
+            Some(timeout) => CancellationToken::new().with_timeout(timeout),
+            None => CancellationToken::new(),
+        };
+        let generate = |tkn| {
+            text.push_str(&decoder.push(tkn).unwrap_or_default());
+            if let Some(pos) = stops.iter().filter_map(|s| text.find(s.as_str())).min() {
+                if pos > emitted {
+                    emit(&text[emitted..pos]);
+                }
+                finish_reason = "stop";
+                return false;
+            }
+            let held = stops
+                .iter()
+                .flat_map(|s| s.char_indices().skip(1).map(|(i, _)| &s[..i]))
+                .filter(|p| text.ends_with(p))
+                .map(|p| p.len())
+                .max()
+                .unwrap_or(0);
+            let end = text.len() - held;
+            if end > emitted {
+                let go_on = emit(&text[emitted..end]);
+                emitted = end;
+                return go_on;
+            }
+            true
+        };
+        let tokens = self.generate(
+            prompt,
+            req.max_tokens,
+            &sampling,
+            &length,
+            &cancel,
+            cache,
+            generate,
+        )?;
+        if finish_reason == "length" && tokens.len() < req.max_tokens && cancel.timed_out() {
+            finish_reason = "timeout";
+        }
+        if finish_reason != "stop" {
+            text.push_str(&decoder.finish());
+        }
+        if finish_reason != "stop" && emitted < text.len() {
+            emit(&text[emitted..]);
+        }
+        Ok((finish_reason, tokens.len()))
+    }
+}
+
+/// Whether a completion answers a `/v1/chat/completions` request, which changes the
+/// shape of the responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Completions,
+    Chat,
+}
+
+/// A completion, or a piece of it when streaming
+fn completion_chunk(
+    endpoint: Endpoint,
+    stream: bool,
+    id: &str,
+    created: u64,
+    model: &str,
+    text: &str,
+    finish_reason: Option<&'static str>,
+) -> serde_json::Value {
+    match endpoint {
+        Endpoint::Completions => json!({
+            "id": id,
+            "object": "text_completion",
+            "created": created,
+            "model": model,
+            "choices": [Choice { text: text.into(), index: 0, logprobs: None, finish_reason }],
+        }),
+        Endpoint::Chat => {
+            let (object, choice) = if stream {
+                (
+                    "chat.completion.chunk",
+                    json!({ "index": 0, "delta": { "content": text }, "finish_reason": finish_reason }),
+                )
+            } else {
+                (
+                    "chat.completion",
+                    json!({
+                        "index": 0,
+                        "message": { "role": "assistant", "content": text },
+                        "finish_reason": finish_reason,
+                    }),
+                )
+            };
+            json!({
+                "id": id,
+                "object": object,
+                "created": created,
+                "model": model,
+                "choices": [choice],
+            })
+        }
+    }
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_data(body.to_string())
+        .with_status_code(StatusCode(status))
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    let kind = match status {
+        429 => "rate_limit_error",
+        500.. => "server_error",
+        _ => "invalid_request_error",
+    };
+    json_response(
+        status,
+        json!({ "error": { "message": message, "type": kind } }),
+    )
+}
+
+/// The body of `request`, unless it's longer than `max` bytes
+fn read_body(request: &mut Request, max: Option<usize>) -> std::io::Result<Option<String>> {
+    let max = max.unwrap_or(usize::MAX);
+    if request.body_length().is_some_and(|len| len > max) {
+        return Ok(None);
+    }
+    // Chunked bodies don't tell their length upfront
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(max.saturating_add(1) as u64)
+        .read_to_string(&mut body)?;
+    Ok((body.len() <= max).then_some(body))
+}
+
+/// Server-sent events, read by the server as they are produced by the generation
+struct EventStream {
+    events: mpsc::Receiver<String>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for EventStream {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.events.recv() {
+                Ok(event) => {
+                    self.buf = format!("data: {}\n\n", event).into_bytes();
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// A conversation kept on the server: its name, and its tokens up to the answer
+type SessionTurn = (String, SessionState);
+
+/// Adds the answer to the conversation it completes
+fn end_turn<O: Optimizer, T: Tokenizer>(
+    model: &Model<O, T>,
+    session: Option<SessionTurn>,
+    answer: &str,
+) {
+    if let Some((name, mut state)) = session {
+        let answer = format!("{}{}", answer, model.template.separator);
+        state.tokens.extend(model.tokenize_all(&answer));
+        model.sessions.put(&name, state);
+    }
+}
+
+/// Runs a completion and answers `request` with it, streamed or in one piece. The
+/// prompt of the conversation `session` is given as its tokens instead.
+fn respond<O, T>(
+    model: &Arc<Model<O, T>>,
+    request: Request,
+    req: CompletionRequest,
+    endpoint: Endpoint,
+    mut session: Option<SessionTurn>,
+) -> std::io::Result<()>
+where
+    O: Optimizer + 'static,
+    T: Tokenizer + Send + Sync + 'static,
+{
+    // The new turns of a kept conversation are what the request sends
+    let sent = match &req.prompt {
+        Prompt::One(p) => model.tokenize_all(p),
+        Prompt::Many(p) if p.len() == 1 => model.tokenize_all(&p[0]),
+        Prompt::Many(_) => {
+            return request.respond(error_response(400, "only one prompt is supported"))
+        }
+    };
+    if let Err(e) = model.limits.check(sent.len(), req.max_tokens) {
+        return request.respond(error_response(400, &e));
+    }
+    let prompt = match &session {
+        Some((_, state)) => state.tokens.clone(),
+        None => sent,
+    };
+    if prompt.is_empty() {
+        return request.respond(error_response(400, "the prompt is empty"));
+    }
+    let prompt_tokens = prompt.len();
+    let prompt = model.fit(&prompt, &req);
+    let prefix = match endpoint {
+        Endpoint::Completions => "cmpl",
+        Endpoint::Chat => "chatcmpl",
+    };
+    let id = format!(
+        "{}-{}",
+        prefix,
+        model.next_id.fetch_add(1, Ordering::SeqCst)
+    );
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    if req.stream {
+        let (tx, rx) = mpsc::channel();
+        let model = model.clone();
+        std::thread::spawn(move || {
+            let chunk = |text: &str, finish_reason| {
+                completion_chunk(
+                    endpoint,
+                    true,
+                    &id,
+                    created,
+                    &model.name,
+                    text,
+                    finish_reason,
+                )
+                .to_string()
+            };
+            // Generation stops once the client is gone
+            let mut answer = String::new();
+            let cache = session.as_mut().map(|(_, state)| &mut state.cache);
+            let result = model.complete(&req, &prompt, cache, |text| {
+                answer.push_str(text);
+                tx.send(chunk(text, None)).is_ok()
+            });
+            let last = match result {
+                Ok((finish_reason, _)) => {
+                    end_turn(&model, session, &answer);
+                    chunk("", Some(finish_reason))
+                }
+                Err(e) => json!({ "error": { "message": e } }).to_string(),
+            };
+            let _ = tx.send(last);
+            let _ = tx.send("[DONE]".into());
+        });
+        let stream = EventStream {
+            events: rx,
+            buf: Vec::new(),
+            pos: 0,
+        };
+        let response = Response::new(
+            StatusCode(200),
+            vec![
+                Header::from_bytes("Content-Type", "text/event-stream").unwrap(),
+                Header::from_bytes("Cache-Control", "no-cache").unwrap(),
+            ],
+            stream,
+            None,
+            None,
+        );
+        request.respond(response)
+    } else {
+        let mut text = String::new();
+        let cache = session.as_mut().map(|(_, state)| &mut state.cache);
+        match model.complete(&req, &prompt, cache, |piece| {
+            text.push_str(piece);
+            true
+        }) {
+            Ok((finish_reason, completion_tokens)) => {
+                end_turn(model, session, &text);
+                let mut body = completion_chunk(
+                    endpoint,
+                    false,
+                    &id,
+                    created,
+                    &model.name,
+                    &text,
+                    Some(finish_reason),
+                );
+                body["usage"] = json!({
+                    "prompt_tokens": prompt.len(),
+                    "completion_tokens": completion_tokens,
+                    "total_tokens": prompt.len() + completion_tokens,
+                    "truncated_prompt_tokens": prompt_tokens - prompt.len(),
+                });
+                request.respond(json_response(200, body))
+            }
+            Err(e) => request.respond(error_response(500, &e)),
+        }
+    }
+}
+
+fn handle<O, T>(model: &Arc<Model<O, T>>, mut request: Request) -> std::io::Result<()>
+where
+    O: Optimizer + 'static,
+    T: Tokenizer + Send + Sync + 'static,
+{
+    if let Some(client) = request.remote_addr().map(|addr| addr.ip()) {
+        if let Err(wait) = model.admit(client) {
+            let retry_after = wait.as_secs_f64().ceil().to_string();
+            let response = error_response(429, "too many requests, retry later")
+                .with_header(Header::from_bytes("Retry-After", retry_after).unwrap());
+            return request.respond(response);
+        }
+    }
+    let body = match request.method() {
+        Method::Post => match read_body(&mut request, model.limits.max_body_bytes)? {
+            Some(body) => body,
+            None => return request.respond(error_response(413, "the request is too large")),
+        },
+        _ => String::new(),
+    };
+    match (request.method(), request.url()) {
+        (Method::Get, "/v1/models") => {
+            let body = json!({
+                "object": "list",
+                "data": [{ "id": model.name, "object": "model", "owned_by": "femto-gpt" }],
+            });
+            request.respond(json_response(200, body))
+        }
+        (Method::Post, "/v1/completions") => match serde_json::from_str(&body) {
+            Ok(req) => respond(model, request, req, Endpoint::Completions, None),
+            Err(e) => request.respond(error_response(400, &e.to_string())),
+        },
+        (Method::Post, "/v1/chat/completions") => match serde_json::from_str(&body) {
+            Ok(req) => {
+                let session = model
+                    .session_state(&req)
+                    .map(|state| (req.session.clone().unwrap_or_default(), state));
+                let req = model.chat_request(req);
+                respond(model, request, req, Endpoint::Chat, session)
+            }
+            Err(e) => request.respond(error_response(400, &e.to_string())),
+        },
+        (Method::Post, "/v1/embeddings") => {
+            let req: EmbeddingRequest = match serde_json::from_str(&body) {
+                Ok(req) => req,
+                Err(e) => return request.respond(error_response(400, &e.to_string())),
+            };
+            let inputs = match req.input {
+                Prompt::One(s) => vec![s],
+                Prompt::Many(s) => s,
+            };
+            let mut data = Vec::new();
+            let mut num_tokens = 0;
+            for (index, input) in inputs.iter().enumerate() {
+                match model.embed(input, None) {
+                    Ok((embedding, n)) => {
+                        num_tokens += n;
+                        data.push(json!({ "object": "embedding", "index": index, "embedding": embedding }));
+                    }
+                    Err(e) => return request.respond(error_response(400, &e)),
+                }
+            }
+            let body = json!({
+                "object": "list",
+                "data": data,
+                "model": model.name,
+                "usage": { "prompt_tokens": num_tokens, "total_tokens": num_tokens },
+            });
+            request.respond(json_response(200, body))
+        }
+        (Method::Delete, url) if url.starts_with("/v1/sessions/") => {
+            let name = &url["/v1/sessions/".len()..];
+            if model.sessions.remove(name) {
+                let body = json!({ "id": name, "object": "session", "deleted": true });
+                request.respond(json_response(200, body))
+            } else {
+                request.respond(error_response(404, "no such session"))
+            }
+        }
+        _ => request.respond(error_response(404, "not found")),
+    }
+}
+
+/// Polls `path` every `interval`, serving the model `load` reads from it whenever the
+/// file gets rewritten (E.g. by a training run going on, so that its progress can be
+/// watched live). Requests going on finish with the model they started with. A file
+/// that fails to load, e.g. being written, is tried again at the next poll.
+pub fn watch<O, T, F, E>(
+    model: Arc<Model<O, T>>,
+    path: PathBuf,
+    interval: Duration,
+    load: F,
+) -> JoinHandle<()>
+where
+    O: Optimizer + 'static,
+    T: Tokenizer + Send + Sync + 'static,
+    F: Fn(&Path) -> Result<GPT<O>, E> + Send + 'static,
+    E: Display,
+{
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut loaded = modified(&path);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let current = modified(&path);
+        if current.is_none() || current == loaded {
+            continue;
+        }
+        match load(&path) {
+            Ok(gpt) => {
+                model.reload(gpt);
+                loaded = current;
+                info!(path = %path.display(), "Reloaded the model");
+            }
+            Err(e) => warn!(path = %path.display(), "Unable to reload the model: {}", e),
+        }
+    })
+}
+
+/// Batches the generations of `model` from now on: requests arriving within `window`
+/// of each other start together, then every step generates the next token of all the
+/// generations going on at once (Joined by the requests arriving meanwhile), packing
+/// their contexts into as few forward passes as they fit in (See
+/// `GPT::sample_packed`). Short prompts under concurrent load take much fewer passes.
+pub fn batch<O, T>(model: &Arc<Model<O, T>>, window: Duration) -> JoinHandle<()>
+where
+    O: Optimizer + 'static,
+    T: Tokenizer + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    // Batching twice leaves the first batcher in charge, the second stopping at once
+    let _ = model.batcher.set(tx);
+    let model = Arc::downgrade(model);
+    std::thread::spawn(move || run_batcher(model, rx, window))
+}
+
+/// Generates the tokens of the jobs sent on `jobs`, until the model is dropped
+fn run_batcher<O: Optimizer, T: Tokenizer>(
+    model: Weak<Model<O, T>>,
+    jobs: mpsc::Receiver<Job>,
+    window: Duration,
+) {
+    let mut active: Vec<Job> = Vec::new();
+    let mut rng = rand::thread_rng();
+    loop {
+        if active.is_empty() {
+            match jobs.recv() {
+                Ok(job) => active.push(job),
+                Err(_) => return,
+            }
+            let deadline = Instant::now() + window;
+            while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                match jobs.recv_timeout(left) {
+                    Ok(job) => active.push(job),
+                    Err(_) => break,
+                }
+            }
+        }
+        active.extend(jobs.try_iter());
+        active.retain(|job| job.count > 0 && !job.cancel.is_cancelled());
+        if active.is_empty() {
+            continue;
+        }
+        let Some(gpt) = model.upgrade().map(|m| m.gpt()) else {
+            return;
+        };
+        let sequences = active
+            .iter()
+            .map(|j| j.tokens.as_slice())
+            .collect::<Vec<_>>();
+        let sampling = active
+            .iter()
+            .map(|j| j.sampling.clone())
+            .collect::<Vec<_>>();
+        match gpt.sample_packed(&mut rng, &sequences, &sampling) {
+            Ok(next) => {
+                for (job, token) in active.iter_mut().zip(next) {
+                    job.tokens.push(token);
+                    job.count -= 1;
+                    if job.tx.send(Ok(token)).is_err() {
+                        job.count = 0;
+                    }
+                }
+            }
+            Err(e) => {
+                for job in active.drain(..) {
+                    let _ = job.tx.send(Err(e.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Serves `model` on `addr`, handling up to `num_workers` requests concurrently.
+/// Blocks forever, unless the server can't be started.
+pub fn serve<O, T>(addr: &str, model: Arc<Model<O, T>>, num_workers: usize) -> std::io::Result<()>
+where
+    O: Optimizer + 'static,
+    T: Tokenizer + Send + Sync + 'static,
+{
+    let server = Arc::new(tiny_http::Server::http(addr).map_err(std::io::Error::other)?);
+    let workers = (0..num_workers.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let model = model.clone();
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    let _ = handle(&model, request);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelConfig;
+    use crate::optimizer::Naive;
+    use crate::tokenizer::AsciiTokenizer;
+    use rand::SeedableRng;
+
+    fn config(num_tokens: usize, embedding_degree: usize) -> ModelConfig {
+        ModelConfig {
+            num_tokens,
+            embedding_degree,
+            num_layers: 1,
+            num_heads: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_complete() {
+        let req: CompletionRequest = serde_json::from_str(r#"{"prompt": ["ab"]}"#).unwrap();
+        assert_eq!(req.max_tokens, 16);
+        assert_eq!(req.temperature, 1.);
+        assert!(!req.stream);
+
+        let mut rng = rand::thread_rng();
+        let model = Model::new(
+            "test",
+            GPT::new(&mut rng, 128, &config(4, 8), Naive::new()).unwrap(),
+            AsciiTokenizer,
+            ChatTemplate {
+                user_prefix: "Q: ".into(),
+                assistant_prefix: "A: ".into(),
+                ..Default::default()
+            },
+        );
+        let prompt = model.tokenize("Hello");
+        assert_eq!(prompt.len(), 4);
+        let mut req: CompletionRequest =
+            serde_json::from_str(r#"{"prompt": "", "max_tokens": 10, "temperature": 0}"#).unwrap();
+        // Half of the context is left to the prompt
+        let long = model.tokenize_all("Hello");
+        assert_eq!(model.fit(&long, &req), model.tokenize_all("lo"));
+        req.truncation = Some(Truncation::Ends);
+        assert_eq!(model.fit(&long, &req), model.tokenize_all("Ho"));
+        let mut text = String::new();
+        let (reason, count) = model
+            .complete(&req, &prompt, None, |s| {
+                text.push_str(s);
+                true
+            })
+            .unwrap();
+        assert_eq!((reason, count, text.chars().count()), ("length", 10, 10));
+
+        // Greedy decoding gives the same text again, which is cut at the stop sequence
+        req.stop = Some(Prompt::One(text[3..6].into()));
+        let mut stopped = String::new();
+        let (reason, _) = model
+            .complete(&req, &prompt, None, |s| {
+                stopped.push_str(s);
+                true
+            })
+            .unwrap();
+        assert_eq!(reason, "stop");
+        assert_eq!(stopped, text[..text.find(&text[3..6]).unwrap()]);
+
+        // Generations running out of time stop early
+        req.stop = None;
+        req.timeout = Some(0.);
+        let (reason, count) = model.complete(&req, &prompt, None, |_| true).unwrap();
+        assert_eq!((reason, count), ("timeout", 0));
+        req.timeout = Some(60.);
+        assert_eq!(
+            model.complete(&req, &prompt, None, |_| true).unwrap().0,
+            "length"
+        );
+        req.timeout = Some(1e19);
+        assert_eq!(
+            model.complete(&req, &prompt, None, |_| true).unwrap().0,
+            "length"
+        );
+        let mut model = model;
+        model.set_limits(Limits {
+            max_duration: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        assert_eq!(
+            model.complete(&req, &prompt, None, |_| true).unwrap().0,
+            "timeout"
+        );
+
+        let chat: ChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [{"role": "user", "content": "Hi"}], "stop": "."}"#,
+        )
+        .unwrap();
+        let (embedding, n) = model.embed("Hello", None).unwrap();
+        assert_eq!((embedding.len(), n), (8, 4));
+        assert_eq!(model.embed("Hello", Some(0)).unwrap().0.len(), 8);
+        assert!(model.embed("Hello", Some(1)).is_err());
+        assert!(model.embed("", None).is_err());
+
+        let req = model.chat_request(chat);
+        assert!(matches!(&req.prompt, Prompt::One(p) if p == "Q: Hi\nA: "));
+        assert!(matches!(&req.stop, Some(Prompt::Many(s)) if s == &[".", "\nQ: "]));
+
+        // Kept conversations are continued with the new turns only
+        let chat: ChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [{"role": "user", "content": "Hi"}], "session": "a"}"#,
+        )
+        .unwrap();
+        let state = model.session_state(&chat).unwrap();
+        assert_eq!(state.tokens, model.tokenize_all("Q: Hi\nA: "));
+        end_turn(&model, Some(("a".into(), state)), "Hello");
+        let mut state = model.session_state(&chat).unwrap();
+        assert_eq!(
+            state.tokens,
+            model.tokenize_all("Q: Hi\nA: Hello\nQ: Hi\nA: ")
+        );
+        assert_eq!(model.sessions().usage().0, 1);
+        // Along with the attention of the model over their last window
+        model.set_limits(Limits::default());
+        let prompt = model.fit(&state.tokens, &req);
+        model
+            .complete(&req, &prompt, Some(&mut state.cache), |_| true)
+            .unwrap();
+        assert!(!state.cache.is_empty());
+        let size = state.tokens.len() * std::mem::size_of::<usize>();
+        let memory = state.cache.memory();
+        end_turn(&model, Some(("a".into(), state)), "Hello");
+        assert!(model.sessions().usage().1 >= size + memory);
+        // Which is stale once the model changes, even for the requests going on
+        let state = model.sessions().get("a").unwrap();
+        let tokens = state.tokens.clone();
+        assert!(!state.cache.is_empty());
+        model.reload(GPT::new(&mut rng, 128, &config(4, 8), Naive::new()).unwrap());
+        model.sessions().put("a", state);
+        let state = model.sessions().get("a").unwrap();
+        assert_eq!(state.tokens, tokens);
+        assert!(state.cache.is_empty());
+        assert!(model.sessions().remove("a"));
+        assert!(!model.sessions().remove("a"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            requests_per_minute: Some(2),
+            max_prompt_tokens: Some(4),
+            max_tokens: Some(8),
+            max_body_bytes: None,
+            max_duration: None,
+        };
+        assert!(limits.check(4, 8).is_ok());
+        assert!(limits.check(5, 8).is_err());
+        assert!(limits.check(4, 9).is_err());
+        assert!(Limits::default().check(usize::MAX, usize::MAX).is_ok());
+
+        let limiter = RateLimiter::new(limits.requests_per_minute);
+        let (a, b) = ([127, 0, 0, 1].into(), [10, 0, 0, 1].into());
+        let now = Instant::now();
+        assert!(limiter.admit(a, now).is_ok());
+        assert!(limiter.admit(a, now).is_ok());
+        assert_eq!(limiter.admit(a, now), Err(Duration::from_secs(30)));
+        // Other clients have their own buckets, which fill up again over time
+        assert!(limiter.admit(b, now).is_ok());
+        assert!(limiter.admit(a, now + Duration::from_secs(15)).is_err());
+        assert!(limiter.admit(a, now + Duration::from_secs(30)).is_ok());
+        assert!(RateLimiter::new(None).admit(a, now).is_ok());
+        let closed = RateLimiter::new(Some(0));
+        assert_eq!(closed.admit(a, now), Err(Duration::from_secs(60)));
+        assert!(closed.admit(a, now).is_err());
+    }
+
+    #[test]
+    fn test_batch() {
+        let gpt = || {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+            GPT::new(&mut rng, 128, &config(16, 8), Naive::new()).unwrap()
+        };
+        let alone = Model::new("test", gpt(), AsciiTokenizer, ChatTemplate::default());
+        let batched = Arc::new(Model::new(
+            "test",
+            gpt(),
+            AsciiTokenizer,
+            ChatTemplate::default(),
+        ));
+        let _batcher = batch(&batched, Duration::from_millis(20));
+        let greedy = Sampling::greedy();
+        let none = CancellationToken::new();
+        let prompts = [&[72, 105][..], &[65], &[66, 67, 68]];
+        let expected = prompts
+            .iter()
+            .map(|p| {
+                alone
+                    .generate(
+                        p,
+                        6,
+                        &greedy,
+                        &LengthControl::default(),
+                        &none,
+                        None,
+                        |_| true,
+                    )
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let workers = prompts
+            .iter()
+            .map(|p| {
+                let (model, greedy, p) = (batched.clone(), greedy.clone(), p.to_vec());
+                std::thread::spawn(move || {
+                    let none = CancellationToken::new();
+                    model
+                        .generate(
+                            &p,
+                            6,
+                            &greedy,
+                            &LengthControl::default(),
+                            &none,
+                            None,
+                            |_| true,
+                        )
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for (worker, expected) in workers.into_iter().zip(expected) {
+            assert_eq!(worker.join().unwrap(), expected);
+        }
+        // Generations stop when asked to
+        let mut count = 0;
+        let tokens = batched
+            .generate(
+                &[72],
+                6,
+                &greedy,
+                &LengthControl::default(),
+                &none,
+                None,
+                |_| {
+                    count += 1;
+                    count < 2
+                },
+            )
+            .unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert!(batched
+            .generate(
+                &[72],
+                0,
+                &greedy,
+                &LengthControl::default(),
+                &none,
+                None,
+                |_| { true }
+            )
+            .unwrap()
+            .is_empty());
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        assert!(batched
+            .generate(
+                &[72],
+                6,
+                &greedy,
+                &LengthControl::default(),
+                &cancelled,
+                None,
+                |_| true
+            )
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let size = std::mem::size_of::<usize>();
+        let sessions = Sessions::new(10 * size);
+        let state = |tokens| SessionState {
+            tokens,
+            ..sessions.start()
+        };
+        let tokens = |name| sessions.get(name).map(|s| s.tokens);
+        sessions.put("a", state(vec![1; 4]));
+        sessions.put("b", state(vec![2; 4]));
+        assert_eq!(sessions.usage(), (2, 8 * size));
+        // The least recently used one makes room
+        assert!(sessions.get("a").is_some());
+        sessions.put("c", state(vec![3; 4]));
+        assert_eq!(tokens("a"), Some(vec![1; 4]));
+        assert_eq!(tokens("b"), None);
+        // Replacing one frees its tokens
+        sessions.put("c", state(vec![3; 6]));
+        assert_eq!(sessions.usage(), (2, 10 * size));
+        // Over budget on its own
+        sessions.put("d", state(vec![4; 11]));
+        assert_eq!(sessions.usage(), (0, 0));
+    }
+
+    #[test]
+    fn test_watch() {
+        let gpt = |embedding_degree| {
+            GPT::new(
+                &mut rand::thread_rng(),
+                128,
+                &config(4, embedding_degree),
+                Naive::new(),
+            )
+            .unwrap()
+        };
+        let model = Arc::new(Model::new(
+            "test",
+            gpt(8),
+            AsciiTokenizer,
+            ChatTemplate::default(),
+        ));
+        let path = std::env::temp_dir().join("femto_gpt_test_watch.dat");
+        std::fs::write(&path, b"8").unwrap();
+        let old = model.gpt();
+        let _watcher = watch(
+            model.clone(),
+            path.clone(),
+            Duration::from_millis(10),
+            move |path| std::fs::read_to_string(path).unwrap().parse().map(gpt),
+        );
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(Arc::ptr_eq(&old, &model.gpt()));
+
+        // A file that doesn't load leaves the model as it was
+        let rewrite = |content: &[u8], secs| {
+            std::fs::write(&path, content).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        };
+        rewrite(b"?", 1);
+        assert!(Arc::ptr_eq(&old, &model.gpt()));
+        rewrite(b"16", 2);
+        assert_eq!(model.embed("Hi", None).unwrap().0.len(), 16);
+        // The requests going on keep the model they started with
+        assert_eq!(
+            old.embed_text(&[72], None, Pooling::Mean).unwrap().size(),
+            8
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
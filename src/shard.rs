@@ -0,0 +1,282 @@
+//! Sharded checkpoints, for models too big to be written or read as one blob: the
+//! parameters are spread over several shard files (As written by `write_tensors`),
+//! listed in a JSON index along with the names of the tensors each holds and its
+//! XXH64, and what the model was trained on (See `gpt::Provenance`). The optimizer state
+//! and the data seed go in a file of their own (Bincode).
+//!
+//! Given `model.json` as the index, the shards are `model-00001-of-00004.params` and
+//! so on, and the optimizer state `model.optimizer`, next to it.
+//!
+//! Shards are loaded a few at a time, in parallel, each being dropped once copied
+//! into the model: at no point are all the weights held twice.
+
+use crate::config::ConfigError;
+use crate::gpt::{Provenance, TrainingState, GPT};
+use crate::graph::GraphError;
+use crate::optimizer::Optimizer;
+use crate::tensor::{read_tensors, write_tensors, xxh64, Tensor, TensorOps};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const EXTENSION: &str = "json";
+const VERSION: u32 = 1;
+
+/// The index of a sharded checkpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardIndex {
+    pub version: u32,
+    pub shards: Vec<Shard>,
+    /// File holding the optimizer state and the data seed, if saved
+    pub optimizer: Option<ShardFile>,
+    /// Unknown in the indexes of earlier versions
+    #[serde(default)]
+    pub provenance: Provenance,
+}
+
+/// A file of a sharded checkpoint, relative to its index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardFile {
+    pub file: String,
+    pub xxh64: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    #[serde(flatten)]
+    pub file: ShardFile,
+    /// Names of the parameters in the shard
+    pub tensors: Vec<String>,
+}
+
+fn invalid<S: Into<String>>(reason: S) -> ConfigError {
+    ConfigError::InvalidShards(reason.into())
+}
+
+/// Whether `path` is the index of a sharded checkpoint, going by its extension
+pub fn is_sharded<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().and_then(|e| e.to_str()) == Some(EXTENSION)
+}
+
+/// Splits the parameters, in order, into `num_shards` groups of about the same size
+fn split(sizes: &[usize], num_shards: usize) -> Vec<std::ops::Range<usize>> {
+    let total = sizes.iter().sum::<usize>();
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut acc = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        acc += size;
+        // Shard k ends once k+1 n-ths of the total are in
+        if acc * num_shards >= total * (ranges.len() + 1) && ranges.len() + 1 < num_shards {
+            ranges.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < sizes.len() || ranges.is_empty() {
+        ranges.push(start..sizes.len());
+    }
+    ranges
+}
+
+/// Reads a file of the checkpoint, checking it's the one the index refers to
+fn read_file(dir: &Path, file: &ShardFile) -> Result<Vec<u8>, ConfigError> {
+    let bytes = std::fs::read(dir.join(&file.file))?;
+    if xxh64(&bytes, 0) != file.xxh64 {
+        return Err(invalid(format!("checksum mismatch in {}", file.file)));
+    }
+    Ok(bytes)
+}
+
+impl ShardIndex {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let index: ShardIndex = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| invalid(format!("invalid index: {}", e)))?;
+        if index.version != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        Ok(index)
+    }
+
+    /// Paths of all the files of the checkpoint, index excluded
+    pub fn files(&self, index_path: &Path) -> Vec<PathBuf> {
+        let dir = index_path.parent().unwrap_or(Path::new(""));
+        self.shards
+            .iter()
+            .map(|s| &s.file)
+            .chain(&self.optimizer)
+            .map(|f| dir.join(&f.file))
+            .collect()
+    }
+}
+
+impl<O: Optimizer> GPT<O> {
+    /// Writes the parameters as (At most) `num_shards` shards, and their index at
+    /// `index_path`, along with the optimizer state if `with_optimizer`. The shards
+    /// are written one after the other, so only one is ever held in memory.
+    pub fn save_sharded<P: AsRef<Path>>(
+        &self,
+        index_path: P,
+        num_shards: usize,
+        with_optimizer: bool,
+    ) -> Result<ShardIndex, ConfigError> {
+        let index_path = index_path.as_ref();
+        let dir = index_path.parent().unwrap_or(Path::new(""));
+        let stem = index_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| invalid("invalid index path"))?;
+        let params = self.named_params()?;
+        let sizes = params.iter().map(|(_, t)| t.size()).collect::<Vec<_>>();
+        let ranges = split(&sizes, num_shards.max(1));
+        let mut shards = Vec::new();
+        for (i, range) in ranges.iter().enumerate() {
+            let tensors = params[range.clone()]
+                .iter()
+                .map(|(name, t)| (*name, t.as_ref()))
+                .collect::<Vec<_>>();
+            let mut bytes = Vec::new();
+            write_tensors(&mut bytes, &tensors).map_err(GraphError::from)?;
+            let file = format!("{}-{:05}-of-{:05}.params", stem, i + 1, ranges.len());
+            std::fs::write(dir.join(&file), &bytes)?;
+            shards.push(Shard {
+                file: ShardFile {
+                    file,
+                    xxh64: xxh64(&bytes, 0),
+                },
+                tensors: tensors.iter().map(|(name, _)| name.to_string()).collect(),
+            });
+        }
+        let optimizer = if with_optimizer {
+            let bytes = bincode::serialize(&(self.optimizer(), self.data_seed()))?;
+            let file = format!("{}.optimizer", stem);
+            std::fs::write(dir.join(&file), &bytes)?;
+            Some(ShardFile {
+                file,
+                xxh64: xxh64(&bytes, 0),
+            })
+        } else {
+            None
+        };
+        let index = ShardIndex {
+            version: VERSION,
+            shards,
+            optimizer,
+            provenance: self.provenance(),
+        };
+        let json = serde_json::to_string_pretty(&index).map_err(|e| invalid(e.to_string()))?;
+        std::fs::write(index_path, json)?;
+        Ok(index)
+    }
+
+    /// Loads a checkpoint written by `save_sharded` (With the optimizer state, if
+    /// saved), failing if a parameter of the model is in none of the shards. As many
+    /// shards as there are threads are read and decoded at once.
+    pub fn load_sharded<P: AsRef<Path>>(&mut self, index_path: P) -> Result<(), ConfigError> {
+        let index_path = index_path.as_ref();
+        let dir = index_path.parent().unwrap_or(Path::new(""));
+        let index = ShardIndex::load(index_path)?;
+        let mut missing = self
+            .named_params()?
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<_>>();
+        for shards in index.shards.chunks(rayon::current_num_threads()) {
+            let decoded = shards
+                .par_iter()
+                .map(|shard| {
+                    let bytes = read_file(dir, &shard.file)?;
+                    let tensors = read_tensors::<f32, _>(&bytes[..]).map_err(GraphError::from)?;
+                    Ok(tensors)
+                })
+                .collect::<Result<Vec<Vec<(String, Tensor<f32>)>>, ConfigError>>()?;
+            for (name, tensor) in decoded.into_iter().flatten() {
+                if self.load_param("load_sharded", &name, &tensor)? {
+                    missing.retain(|m| *m != name);
+                }
+            }
+        }
+        if !missing.is_empty() {
+            return Err(invalid(format!("no weights for {}", missing.join(", "))));
+        }
+        if let Some(file) = &index.optimizer {
+            let (optimizer, data_seed): (O, u64) = bincode::deserialize(&read_file(dir, file)?)?;
+            let state = TrainingState {
+                tensors: Default::default(),
+                optimizer,
+                data_seed,
+                provenance: index.provenance,
+            };
+            self.set_training_state(state, true)?;
+        }
+        self.set_provenance(index.provenance);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::optimizer::AdamW;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(&[1, 1, 1, 1], 2), vec![0..2, 2..4]);
+        assert_eq!(split(&[10, 1, 1], 2), vec![0..1, 1..3]);
+        assert_eq!(split(&[1, 1], 4), vec![0..1, 1..2]);
+        assert_eq!(split(&[5], 1), vec![0..1]);
+    }
+
+    #[test]
+    fn test_sharded() {
+        let dir = std::env::temp_dir().join("femto_gpt_test_sharded");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = Config::default();
+        config.model.num_tokens = 4;
+        config.model.embedding_degree = 8;
+        config.model.num_layers = 2;
+        config.model.num_heads = 2;
+        let build = || {
+            config
+                .model
+                .build(&mut rand::thread_rng(), 10, AdamW::new())
+                .unwrap()
+        };
+        let mut gpt = build();
+        gpt.set_data_seed(42);
+        gpt.set_provenance(Provenance {
+            dataset_hash: Some(1),
+            tokenizer_hash: Some(2),
+        });
+
+        let path = dir.join("model.json");
+        assert!(is_sharded(&path));
+        let index = gpt.save_sharded(&path, 3, true).unwrap();
+        assert_eq!(index.shards.len(), 3);
+        assert!(index.files(&path).iter().all(|f| f.is_file()));
+        assert!(dir.join("model-00002-of-00003.params").is_file());
+
+        let mut loaded = build();
+        loaded.load_sharded(&path).unwrap();
+        assert_eq!(loaded.data_seed(), 42);
+        assert_eq!(loaded.provenance(), gpt.provenance());
+        let state = gpt.get_training_state().unwrap();
+        let loaded_state = loaded.get_training_state().unwrap();
+        for (name, t) in state.tensors {
+            assert_eq!(loaded_state.tensors[&name].blob(), t.blob());
+        }
+
+        // A corrupted shard, or one missing from the index, is rejected
+        let shard = dir.join(&index.shards[1].file.file);
+        let mut bytes = std::fs::read(&shard).unwrap();
+        bytes[10] ^= 1;
+        std::fs::write(&shard, &bytes).unwrap();
+        assert!(build().load_sharded(&path).is_err());
+        let mut partial = index.clone();
+        partial.shards.remove(1);
+        std::fs::write(&path, serde_json::to_string(&partial).unwrap()).unwrap();
+        assert!(build().load_sharded(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
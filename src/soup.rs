@@ -0,0 +1,127 @@
+//! Model soups: the weights of several runs of the same model (E.g. fine-tuned with
+//! different hyperparameters from the same checkpoint) averaged into one, which often
+//! beats each of them. Greedy soups only take in the runs that lower the validation
+//! loss, best ones first.
+
+use femto_gpt::config::ConfigError;
+use femto_gpt::graph::GraphError;
+use femto_gpt::tensor::{Tensor, TensorMutOps, TensorOps};
+
+pub type Weights = Vec<(String, Tensor<f32>)>;
+
+/// Mean of `checkpoints`, which should all hold the same tensors (By name and shape),
+/// in any order
+pub fn average(checkpoints: &[&Weights]) -> Result<Weights, ConfigError> {
+    let (first, others) = checkpoints
+        .split_first()
+        .ok_or_else(|| ConfigError::Invalid("nothing to average".into()))?;
+    let mut sum = (*first).clone();
+    sum.sort_by(|a, b| a.0.cmp(&b.0));
+    for (i, weights) in others.iter().enumerate() {
+        let mut weights = weights.iter().collect::<Vec<_>>();
+        weights.sort_by(|a, b| a.0.cmp(&b.0));
+        if !sum
+            .iter()
+            .map(|(n, _)| n)
+            .eq(weights.iter().map(|(n, _)| n))
+        {
+            return Err(ConfigError::Invalid(format!(
+                "checkpoint {} doesn't hold the same tensors as the first one",
+                i + 2
+            )));
+        }
+        for ((name, total), (_, t)) in sum.iter_mut().zip(weights) {
+            if total.shape() != t.shape() {
+                return Err(ConfigError::Invalid(format!(
+                    "{} has shape {:?} in checkpoint {}, instead of {:?}",
+                    name,
+                    t.shape(),
+                    i + 2,
+                    total.shape()
+                )));
+            }
+            total.add_assign(t).map_err(GraphError::from)?;
+        }
+    }
+    let coeff = 1. / checkpoints.len() as f32;
+    Ok(sum
+        .into_iter()
+        .map(|(name, t)| (name, t.map_values(|f| f * coeff)))
+        .collect())
+}
+
+/// The greedy soup of `checkpoints`: starting from the one of lowest `loss`, each of
+/// the others (In order of their losses) is kept in the average if that doesn't make
+/// the loss worse. Returns the soup and the indices of the checkpoints in it.
+pub fn greedy<F>(checkpoints: &[Weights], mut loss: F) -> Result<(Weights, Vec<usize>), ConfigError>
+where
+    F: FnMut(&Weights) -> Result<f32, ConfigError>,
+{
+    let mut losses = checkpoints
+        .iter()
+        .enumerate()
+        .map(|(i, w)| Ok((i, loss(w)?)))
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    losses.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let (best, mut best_loss) = *losses
+        .first()
+        .ok_or_else(|| ConfigError::Invalid("nothing to average".into()))?;
+    let mut ingredients = vec![best];
+    let mut soup = average(&[&checkpoints[best]])?;
+    for (i, _) in losses.into_iter().skip(1) {
+        let candidate = ingredients
+            .iter()
+            .chain([&i])
+            .map(|j| &checkpoints[*j])
+            .collect::<Vec<_>>();
+        let candidate = average(&candidate)?;
+        let candidate_loss = loss(&candidate)?;
+        tracing::debug!(checkpoint = i, loss = candidate_loss, "Tried a soup");
+        if candidate_loss <= best_loss {
+            soup = candidate;
+            best_loss = candidate_loss;
+            ingredients.push(i);
+        }
+    }
+    Ok((soup, ingredients))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weights(a: f32, b: f32) -> Weights {
+        vec![
+            ("b".into(), Tensor::vector(&[b, b])),
+            ("a".into(), Tensor::scalar(a)),
+        ]
+    }
+
+    #[test]
+    fn test_average() {
+        let soup = average(&[&weights(1., 2.), &weights(3., 6.)]).unwrap();
+        assert_eq!(soup[0].0, "a");
+        assert_eq!(soup[0].1.blob(), &[2.]);
+        assert_eq!(soup[1].1.blob(), &[4., 4.]);
+        let other = vec![("c".to_string(), Tensor::scalar(1.))];
+        assert!(average(&[&weights(1., 2.), &other]).is_err());
+        let reshaped = vec![
+            ("a".to_string(), Tensor::scalar(1.)),
+            ("b".to_string(), Tensor::vector(&[1., 2., 3.])),
+        ];
+        assert!(average(&[&weights(1., 2.), &reshaped]).is_err());
+    }
+
+    #[test]
+    fn test_greedy() {
+        // The loss is the distance of `a` to 2: averaging 1 and 3 helps, 10 doesn't
+        let checkpoints = vec![weights(10., 0.), weights(1., 0.), weights(3., 0.)];
+        let (soup, ingredients) = greedy(&checkpoints, |w| {
+            let a = w.iter().find(|(n, _)| n == "a").unwrap();
+            Ok((a.1.blob()[0] - 2.).abs())
+        })
+        .unwrap();
+        assert_eq!(ingredients, vec![1, 2]);
+        assert_eq!(soup[0].1.blob(), &[2.]);
+    }
+}
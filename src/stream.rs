@@ -0,0 +1,143 @@
+//! Inference for models larger than the memory: the parameters stay in their file
+//! (As written by `GPT::write_params`, or a sharded checkpoint), memory-mapped, and
+//! are only read while the functions that take them run (See `Graph::stream`). Each
+//! forward pass reads them all again, so it's as slow as the disk.
+
+use crate::config::{ConfigError, ModelConfig};
+use crate::gpt::GPT;
+use crate::graph::{Graph, GraphError, TensorSource};
+use crate::optimizer::AdamW;
+use crate::shard::{self, ShardIndex};
+use crate::tensor::{index_tensors, Tensor};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The parameters of a params file or of the shards of a checkpoint, memory-mapped.
+/// Only the headers are read when opening them: the values are read, and their
+/// checksums checked, tensor by tensor, as they're loaded.
+pub struct MappedParams {
+    files: Vec<Mmap>,
+    /// The file and the bytes of the frame of each tensor, by name
+    index: HashMap<String, (usize, Range<usize>)>,
+}
+
+fn map(path: &Path) -> Result<Mmap, ConfigError> {
+    let file = std::fs::File::open(path)?;
+    // Safety: the file must not be modified while it's mapped, which would at worst
+    // fail the checksum of the tensors read
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+impl MappedParams {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let files = if shard::is_sharded(path) {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            ShardIndex::load(path)?
+                .shards
+                .iter()
+                .map(|s| map(&dir.join(&s.file.file)))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            vec![map(path)?]
+        };
+        let mut index = HashMap::new();
+        for (i, file) in files.iter().enumerate() {
+            for (name, range) in index_tensors::<f32>(file).map_err(GraphError::from)? {
+                index.insert(name, (i, range));
+            }
+        }
+        Ok(Self { files, index })
+    }
+}
+
+impl TensorSource for MappedParams {
+    fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+    fn load(&self, name: &str) -> Result<Tensor<f32>, GraphError> {
+        let (file, range) = self
+            .index
+            .get(name)
+            .ok_or_else(|| GraphError::MissingStreamedTensor(name.into()))?;
+        Ok(Tensor::from_frame(&self.files[*file][range.clone()])?)
+    }
+}
+
+impl ModelConfig {
+    /// Builds the model with its parameters streamed from `path`, a params file or the
+    /// index of a sharded checkpoint (See `GPT::stream_params`), without ever holding
+    /// them all: until they're streamed, they're zeros, which take no memory
+    pub fn build_streamed<P: AsRef<Path>>(
+        &self,
+        path: P,
+        vocab_size: usize,
+        optimizer: AdamW,
+    ) -> Result<GPT<AdamW>, ConfigError> {
+        let mut gpt = GPT::new_in(
+            Graph::placeholders(),
+            &mut rand::thread_rng(),
+            vocab_size,
+            self,
+            optimizer,
+        )?;
+        gpt.stream_params(Arc::new(MappedParams::open(path)?))?;
+        Ok(gpt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::gpt::Sampling;
+    use crate::tensor::TensorOps;
+
+    #[test]
+    fn test_streamed() {
+        let dir = std::env::temp_dir().join("femto_gpt_test_streamed");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = Config::default();
+        config.model.num_tokens = 4;
+        config.model.embedding_degree = 8;
+        config.model.num_layers = 2;
+        config.model.num_heads = 2;
+        let gpt = config
+            .model
+            .build(&mut rand::thread_rng(), 10, AdamW::new())
+            .unwrap();
+        let params = dir.join("model.params");
+        gpt.write_params(std::fs::File::create(&params).unwrap())
+            .unwrap();
+        let sharded = dir.join("model.json");
+        gpt.save_sharded(&sharded, 3, false).unwrap();
+
+        let generate = |gpt: &GPT<AdamW>| {
+            let mut rng = rand::thread_rng();
+            gpt.generate(&mut rng, &[1, 2], 8, &Sampling::greedy(), |_| true)
+                .unwrap()
+        };
+        let expected = generate(&gpt);
+        for path in [&params, &sharded] {
+            let mut streamed = config.model.build_streamed(path, 10, AdamW::new()).unwrap();
+            assert_eq!(generate(&streamed), expected);
+            // The parameters aren't held, to be saved
+            let state = streamed.get_training_state();
+            assert!(matches!(state, Err(GraphError::Streamed)));
+            assert!(streamed.restrict_vocab(Some(&[1, 2])).is_err());
+        }
+
+        // Of another model
+        let mut wider = config.model.clone();
+        wider.embedding_degree = 16;
+        assert!(wider.build_streamed(&params, 10, AdamW::new()).is_err());
+        let mapped = MappedParams::open(&params).unwrap();
+        assert!(mapped.contains("head_map_bias"));
+        assert_eq!(mapped.load("head_map_bias").unwrap().shape(), [10]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
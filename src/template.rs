@@ -0,0 +1,176 @@
+//! Turns conversations into the text a model is trained on and prompted with. The
+//! template is part of the config of the model, so that the chat modes format the
+//! conversations exactly like the training data was.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: Role, content: &str) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+}
+
+/// Each turn is written as its role's prefix, its content, then the separator. The
+/// defaults (No prefixes, one turn per line) match plain text models.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatTemplate {
+    /// System prompt starting every conversation that has none (If not empty)
+    pub system: String,
+    pub system_prefix: String,
+    pub user_prefix: String,
+    pub assistant_prefix: String,
+    pub separator: String,
+}
+
+impl Default for ChatTemplate {
+    fn default() -> Self {
+        Self {
+            system: String::new(),
+            system_prefix: String::new(),
+            user_prefix: String::new(),
+            assistant_prefix: String::new(),
+            separator: "\n".into(),
+        }
+    }
+}
+
+impl ChatTemplate {
+    fn prefix(&self, role: Role) -> &str {
+        match role {
+            Role::System => &self.system_prefix,
+            Role::User => &self.user_prefix,
+            Role::Assistant => &self.assistant_prefix,
+        }
+    }
+
+    pub fn turn(&self, message: &Message) -> String {
+        format!(
+            "{}{}{}",
+            self.prefix(message.role),
+            message.content,
+            self.separator
+        )
+    }
+
+    /// The default system turn, for conversations that don't start with their own
+    pub fn system_turn(&self) -> Option<String> {
+        (!self.system.is_empty()).then(|| self.turn(&Message::new(Role::System, &self.system)))
+    }
+
+    /// The text of a conversation. With `generation_prompt`, it ends with the prefix
+    /// of the assistant, for the model to write its answer.
+    pub fn render(&self, messages: &[Message], generation_prompt: bool) -> String {
+        let mut text = String::new();
+        if messages.first().map(|m| m.role) != Some(Role::System) {
+            text.extend(self.system_turn());
+        }
+        for message in messages {
+            text.push_str(&self.turn(message));
+        }
+        if generation_prompt {
+            text.push_str(&self.assistant_prefix);
+        }
+        text
+    }
+
+    /// Text at which the answer of the assistant ends, where the model starts writing
+    /// the next turn of the user. (Unknown when users have no prefix)
+    pub fn stop(&self) -> Option<String> {
+        (!self.user_prefix.is_empty()).then(|| format!("{}{}", self.separator, self.user_prefix))
+    }
+}
+
+/// How much of the text generated so far can be shown: all of it but what could be
+/// the start of `stop`, or what comes before `stop` once it's generated. (In which
+/// case the generation is over)
+pub fn visible_end(text: &str, stop: Option<&str>) -> (usize, bool) {
+    let Some(stop) = stop.filter(|s| !s.is_empty()) else {
+        return (text.len(), false);
+    };
+    if let Some(pos) = text.find(stop) {
+        return (pos, true);
+    }
+    let held = stop
+        .char_indices()
+        .skip(1)
+        .map(|(i, _)| &stop[..i])
+        .filter(|p| text.ends_with(p))
+        .map(|p| p.len())
+        .max()
+        .unwrap_or(0);
+    (text.len() - held, false)
+}
+
+/// A conversation of a JSONL chat dataset: `{"messages": [{"role": ..., "content": ...}]}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conversation {
+    pub messages: Vec<Message>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let template = ChatTemplate {
+            system: "Be brief.".into(),
+            system_prefix: "### System: ".into(),
+            user_prefix: "### User: ".into(),
+            assistant_prefix: "### Assistant: ".into(),
+            separator: "\n".into(),
+        };
+        let messages = [
+            Message::new(Role::User, "Hi"),
+            Message::new(Role::Assistant, "Hello"),
+            Message::new(Role::User, "Bye"),
+        ];
+        assert_eq!(
+            template.render(&messages, true),
+            "### System: Be brief.\n### User: Hi\n### Assistant: Hello\n### User: Bye\n### Assistant: "
+        );
+        // A system message of the conversation replaces the default one
+        let own = [Message::new(Role::System, "Be kind."), messages[0].clone()];
+        assert_eq!(
+            template.render(&own, false),
+            "### System: Be kind.\n### User: Hi\n"
+        );
+        assert_eq!(template.stop().as_deref(), Some("\n### User: "));
+
+        let plain = ChatTemplate::default();
+        assert_eq!(plain.render(&messages[..2], true), "Hi\nHello\n");
+        assert_eq!(plain.stop(), None);
+
+        assert_eq!(
+            visible_end("Bye\n### U", template.stop().as_deref()),
+            (3, false)
+        );
+        assert_eq!(
+            visible_end("Bye\n### User: x", template.stop().as_deref()),
+            (3, true)
+        );
+        assert_eq!(visible_end("Bye\n", None), (4, false));
+
+        let conversation: Conversation =
+            serde_json::from_str(r#"{"messages": [{"role": "user", "content": "Hi"}]}"#).unwrap();
+        assert_eq!(conversation.messages, messages[..1]);
+    }
+}
@@ -0,0 +1,478 @@
+use super::*;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserializer, Serializer};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+const TENSOR_MAGIC: &[u8] = b"FGTN";
+const TENSORS_MAGIC: &[u8] = b"FGTM";
+const VERSION: u8 = 1;
+
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge(acc: u64, val: u64) -> u64 {
+    (acc ^ xxh64_round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+fn u64_at(data: &[u8], i: usize) -> u64 {
+    u64::from_le_bytes(data[i..i + 8].try_into().unwrap())
+}
+
+/// The XXH64 hash of `data`
+pub fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let len = data.len();
+    let mut i = 0;
+    let mut h = if len >= 32 {
+        let mut v = [
+            seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+            seed.wrapping_add(PRIME64_2),
+            seed,
+            seed.wrapping_sub(PRIME64_1),
+        ];
+        while i + 32 <= len {
+            for (j, v) in v.iter_mut().enumerate() {
+                *v = xxh64_round(*v, u64_at(data, i + j * 8));
+            }
+            i += 32;
+        }
+        let mut h = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        for v in v {
+            h = xxh64_merge(h, v);
+        }
+        h
+    } else {
+        seed.wrapping_add(PRIME64_5)
+    };
+    h = h.wrapping_add(len as u64);
+    while i + 8 <= len {
+        h ^= xxh64_round(0, u64_at(data, i));
+        h = h
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+        i += 8;
+    }
+    if i + 4 <= len {
+        let k = u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as u64;
+        h ^= k.wrapping_mul(PRIME64_1);
+        h = h
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        i += 4;
+    }
+    for b in &data[i..] {
+        h ^= (*b as u64).wrapping_mul(PRIME64_5);
+        h = h.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+fn invalid(msg: &str) -> TensorError {
+    TensorError::InvalidFormat(msg.into())
+}
+
+/// Reads the fields of a frame in order, failing instead of panicking on
+/// truncated input
+pub(super) struct Cursor<'a> {
+    pub bytes: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], TensorError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or_else(|| invalid("truncated"))?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or_else(|| invalid("truncated"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+    pub fn u8(&mut self) -> Result<u8, TensorError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn u32(&mut self) -> Result<u32, TensorError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, TensorError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    /// Checks the trailing XXH64 checksum of everything read so far
+    fn checksum(&mut self) -> Result<(), TensorError> {
+        let expected = xxh64(&self.bytes[..self.pos], 0);
+        if self.u64()? != expected {
+            return Err(invalid("checksum mismatch"));
+        }
+        Ok(())
+    }
+}
+
+impl<V: NpyElement> Tensor<V> {
+    /// Serializes the tensor as a self-describing frame: magic, version, dtype,
+    /// shape, little-endian values and an XXH64 checksum of all of it. The same
+    /// tensor always gives the very same bytes.
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.dim() * 8 + self.size() * V::SIZE);
+        out.extend(TENSOR_MAGIC);
+        out.push(VERSION);
+        out.push(V::DESCR.len() as u8);
+        out.extend(V::DESCR.as_bytes());
+        out.push(self.dim() as u8);
+        for d in self.shape() {
+            out.extend((*d as u64).to_le_bytes());
+        }
+        out.extend_from_slice(&self.as_bytes());
+        out.extend(xxh64(&out, 0).to_le_bytes());
+        out
+    }
+
+    pub fn from_frame(bytes: &[u8]) -> Result<Self, TensorError> {
+        let mut cur = Cursor { bytes, pos: 0 };
+        let t = Self::read_frame(&mut cur)?;
+        if cur.pos != bytes.len() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(t)
+    }
+
+    fn read_frame(cur: &mut Cursor) -> Result<Self, TensorError> {
+        let start = cur.pos;
+        let shape = Self::frame_header(cur)?;
+        let size = shape
+            .iter()
+            .try_fold(V::SIZE, |a, b| a.checked_mul(*b))
+            .ok_or_else(|| invalid("invalid shape"))?;
+        let data = cur.take(size)?;
+        let mut frame = Cursor {
+            bytes: &cur.bytes[start..],
+            pos: cur.pos - start,
+        };
+        frame.checksum()?;
+        cur.pos = start + frame.pos;
+        Tensor::from_bytes(&shape, data)
+    }
+
+    /// Moves past a frame, only reading its header
+    fn skip_frame(cur: &mut Cursor) -> Result<(), TensorError> {
+        let shape = Self::frame_header(cur)?;
+        let size = shape
+            .iter()
+            .try_fold(V::SIZE, |a, b| a.checked_mul(*b))
+            .and_then(|size| size.checked_add(8))
+            .ok_or_else(|| invalid("invalid shape"))?;
+        cur.take(size)?;
+        Ok(())
+    }
+
+    /// Reads the header of a frame, up to its shape
+    fn frame_header(cur: &mut Cursor) -> Result<Vec<usize>, TensorError> {
+        if cur.take(TENSOR_MAGIC.len())? != TENSOR_MAGIC {
+            return Err(invalid("not a tensor frame"));
+        }
+        if cur.u8()? != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let descr_len = cur.u8()? as usize;
+        let descr = cur.take(descr_len)?;
+        if descr != V::DESCR.as_bytes() {
+            return Err(invalid(&format!(
+                "expected dtype {}, found {}",
+                V::DESCR,
+                String::from_utf8_lossy(descr)
+            )));
+        }
+        let dim = cur.u8()? as usize;
+        (0..dim).map(|_| Ok(cur.u64()? as usize)).collect()
+    }
+}
+
+/// Writes named tensors as a single checksummed file, ordered by name so that the
+/// output only depends on the contents
+pub fn write_tensors<V: NpyElement, W: Write>(
+    mut w: W,
+    tensors: &[(&str, &Tensor<V>)],
+) -> Result<(), TensorError> {
+    let mut sorted = tensors.to_vec();
+    sorted.sort_by_key(|(name, _)| *name);
+    let mut out = Vec::new();
+    out.extend(TENSORS_MAGIC);
+    out.push(VERSION);
+    out.extend((sorted.len() as u64).to_le_bytes());
+    for (name, tensor) in sorted {
+        out.extend((name.len() as u64).to_le_bytes());
+        out.extend(name.as_bytes());
+        out.extend(tensor.to_frame());
+    }
+    out.extend(xxh64(&out, 0).to_le_bytes());
+    w.write_all(&out)?;
+    Ok(())
+}
+
+/// The names of the tensors of a file written by `write_tensors`, with the range of
+/// the bytes of the frame of each (For `Tensor::from_frame`), their values being
+/// neither read nor checked: for picking tensors out of a memory-mapped file
+pub fn index_tensors<V: NpyElement>(
+    bytes: &[u8],
+) -> Result<Vec<(String, std::ops::Range<usize>)>, TensorError> {
+    let mut cur = Cursor { bytes, pos: 0 };
+    if cur.take(TENSORS_MAGIC.len())? != TENSORS_MAGIC {
+        return Err(invalid("not a tensors file"));
+    }
+    if cur.u8()? != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    let count = cur.u64()?;
+    let mut index = Vec::new();
+    for _ in 0..count {
+        let name_len = cur.u64()? as usize;
+        let name = std::str::from_utf8(cur.take(name_len)?)
+            .map_err(|_| invalid("name is not utf-8"))?
+            .to_string();
+        let start = cur.pos;
+        Tensor::<V>::skip_frame(&mut cur)?;
+        index.push((name, start..cur.pos));
+    }
+    Ok(index)
+}
+
+pub fn read_tensors<V: NpyElement, R: Read>(
+    mut r: R,
+) -> Result<Vec<(String, Tensor<V>)>, TensorError> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    let mut cur = Cursor {
+        bytes: &bytes,
+        pos: 0,
+    };
+    if cur.take(TENSORS_MAGIC.len())? != TENSORS_MAGIC {
+        return Err(invalid("not a tensors file"));
+    }
+    if cur.u8()? != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    let count = cur.u64()?;
+    let mut tensors = Vec::new();
+    for _ in 0..count {
+        let name_len = cur.u64()? as usize;
+        let name = std::str::from_utf8(cur.take(name_len)?)
+            .map_err(|_| invalid("name is not utf-8"))?
+            .to_string();
+        tensors.push((name, Tensor::read_frame(&mut cur)?));
+    }
+    cur.checksum()?;
+    if cur.pos != bytes.len() {
+        return Err(invalid("trailing bytes"));
+    }
+    Ok(tensors)
+}
+
+/// A length-prefixed sequence of values, serialized as a tuple of its bytes: the
+/// length as a little-endian `u64`, then the little-endian values. With bincode,
+/// tuples having no length prefix, this gives the very same bytes as a `Vec` with
+/// bincode's default options, though not depending on the options it is used with.
+struct LeSeq<'a, V>(&'a [V]);
+
+impl<V: NpyElement> Serialize for LeSeq<'_, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = Vec::with_capacity(8 + self.0.len() * V::SIZE);
+        bytes.extend((self.0.len() as u64).to_le_bytes());
+        for v in self.0 {
+            v.write_le(&mut bytes);
+        }
+        let mut tuple = serializer.serialize_tuple(bytes.len())?;
+        for b in &bytes {
+            tuple.serialize_element(b)?;
+        }
+        tuple.end()
+    }
+}
+
+struct LeSeqVisitor<V>(PhantomData<V>);
+
+impl<'de, V: NpyElement> Visitor<'de> for LeSeqVisitor<V> {
+    type Value = Vec<V>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a length-prefixed sequence of {} values", V::DESCR)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<V>, A::Error> {
+        let mut take = |bytes: &mut Vec<u8>, count: usize| {
+            for _ in 0..count {
+                let b = seq
+                    .next_element::<u8>()?
+                    .ok_or_else(|| de::Error::custom("truncated sequence"))?;
+                bytes.push(b);
+            }
+            Ok::<_, A::Error>(())
+        };
+        let mut len = Vec::with_capacity(8);
+        take(&mut len, 8)?;
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        let size = usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_mul(V::SIZE))
+            .ok_or_else(|| de::Error::custom("sequence too long"))?;
+        // Not trusting the length for the allocation
+        let mut bytes = Vec::with_capacity(size.min(1 << 20));
+        take(&mut bytes, size)?;
+        Ok(bytes.chunks_exact(V::SIZE).map(V::read_le).collect())
+    }
+}
+
+/// Reads a `LeSeq`, the length of the tuple being unknown until its prefix is read
+fn read_le_seq<'de, V: NpyElement, A: SeqAccess<'de>>(
+    seq: &mut A,
+) -> Result<Option<Vec<V>>, A::Error> {
+    struct Seed<V>(PhantomData<V>);
+    impl<'de, V: NpyElement> de::DeserializeSeed<'de> for Seed<V> {
+        type Value = Vec<V>;
+        fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<Vec<V>, D::Error> {
+            d.deserialize_tuple(usize::MAX, LeSeqVisitor(PhantomData))
+        }
+    }
+    seq.next_element_seed(Seed(PhantomData))
+}
+
+/// The values, then the shape, both as `LeSeq`s. Checkpoints written on one platform
+/// load on any other, whatever its endianness and word size.
+impl<V: NpyElement> Serialize for Tensor<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&LeSeq(&self.blob[..]))?;
+        tuple.serialize_element(&LeSeq(&self.shape[..]))?;
+        tuple.end()
+    }
+}
+
+struct TensorVisitor<V>(PhantomData<V>);
+
+impl<'de, V: NpyElement> Visitor<'de> for TensorVisitor<V> {
+    type Value = Tensor<V>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a tensor of {} values", V::DESCR)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Tensor<V>, A::Error> {
+        let blob =
+            read_le_seq::<V, _>(&mut seq)?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let shape = read_le_seq::<usize, _>(&mut seq)?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Tensor::raw(&shape, blob).map_err(|e| de::Error::custom(format!("{:?}", e)))
+    }
+}
+
+impl<'de, V: NpyElement> Deserialize<'de> for Tensor<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, TensorVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxh64() {
+        assert_eq!(xxh64(b"", 0), 0xEF46DB3751D8E999);
+        assert_eq!(xxh64(b"a", 0), 0xD24EC4F1A98C6E5B);
+        assert_eq!(xxh64(b"abc", 0), 0x44BC2CF5AD770999);
+        assert_eq!(
+            xxh64(b"Nobody inspects the spammish repetition", 0),
+            0xFBCEA83C8A378BF1
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        let t = Tensor::raw(&[2, 2], vec![1., 2., 3., 4.]).unwrap();
+        let frame = t.to_frame();
+        assert_eq!(frame, t.clone().to_frame());
+        assert_eq!(Tensor::<f32>::from_frame(&frame).unwrap().blob(), t.blob());
+        assert!(Tensor::<usize>::from_frame(&frame).is_err());
+        assert!(Tensor::<f32>::from_frame(&frame[..frame.len() - 1]).is_err());
+        let mut corrupted = frame.clone();
+        corrupted[20] ^= 1;
+        assert!(Tensor::<f32>::from_frame(&corrupted).is_err());
+
+        let b = Tensor::vector(&[5.]);
+        let mut bytes = Vec::new();
+        write_tensors(&mut bytes, &[("b", &b), ("a", &t)]).unwrap();
+        let tensors = read_tensors::<f32, _>(&bytes[..]).unwrap();
+        assert_eq!(tensors[0].0, "a");
+        assert_eq!(tensors[1].1.blob(), &[5.]);
+        let index = index_tensors::<f32>(&bytes).unwrap();
+        assert_eq!(index[1].0, "b");
+        let frame = Tensor::<f32>::from_frame(&bytes[index[0].1.clone()]).unwrap();
+        assert_eq!(frame.blob(), t.blob());
+        bytes.pop();
+        assert!(read_tensors::<f32, _>(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_portable_serde() {
+        use bincode::Options;
+        let t = Tensor::raw(&[2, 1], vec![1., -2.]).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            2, 0, 0, 0, 0, 0, 0, 0, // Number of values
+            0, 0, 0x80, 0x3f, 0, 0, 0, 0xc0, // 1.0 and -2.0
+            2, 0, 0, 0, 0, 0, 0, 0, // Number of dimensions
+            2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(bincode::serialize(&t).unwrap(), expected);
+        // The same bytes with big-endian options, as if written on such a platform
+        let big_endian = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_big_endian();
+        assert_eq!(big_endian.serialize(&t).unwrap(), expected);
+        let read: Tensor<f32> = big_endian.deserialize(&expected).unwrap();
+        assert_eq!(read.shape(), &[2, 1]);
+        assert_eq!(read.blob(), &[1., -2.]);
+        // Same layout as the tensors of earlier checkpoints
+        #[derive(serde::Serialize)]
+        struct Legacy {
+            blob: Vec<f32>,
+            shape: Vec<usize>,
+        }
+        let legacy = Legacy {
+            blob: vec![1., -2.],
+            shape: vec![2, 1],
+        };
+        assert_eq!(bincode::serialize(&legacy).unwrap(), expected);
+
+        let json = serde_json::to_string(&t).unwrap();
+        let read: Tensor<f32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.blob(), t.blob());
+        assert!(bincode::deserialize::<Tensor<f32>>(&expected[..20]).is_err());
+        let mut bad_shape = expected;
+        bad_shape[24] = 3;
+        assert!(bincode::deserialize::<Tensor<f32>>(&bad_shape).is_err());
+    }
+}
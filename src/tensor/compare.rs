@@ -0,0 +1,137 @@
+use super::*;
+use std::fmt;
+
+/// Where and by how much two tensors differ, as found by `compare`
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub actual_shape: Vec<usize>,
+    pub expected_shape: Vec<usize>,
+    /// Multi-dimensional index of the first element out of tolerance
+    pub first_index: Vec<usize>,
+    pub actual: f32,
+    pub expected: f32,
+    pub max_abs_error: f32,
+    pub max_rel_error: f32,
+    pub num_mismatched: usize,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.actual_shape != self.expected_shape {
+            return write!(
+                f,
+                "shapes differ: {:?} != {:?}",
+                self.actual_shape, self.expected_shape
+            );
+        }
+        write!(
+            f,
+            "{} of {} elements differ, first at {:?} ({} != {}), max abs error {}, max rel error {}",
+            self.num_mismatched,
+            self.actual_shape.iter().product::<usize>(),
+            self.first_index,
+            self.actual,
+            self.expected,
+            self.max_abs_error,
+            self.max_rel_error
+        )
+    }
+}
+
+fn unravel(mut pos: usize, shape: &[usize]) -> Vec<usize> {
+    let mut index = vec![0; shape.len()];
+    for d in (0..shape.len()).rev() {
+        index[d] = pos % shape[d];
+        pos /= shape[d];
+    }
+    index
+}
+
+/// Checks that `|actual - expected| <= atol + rtol * |expected|` holds for every
+/// element, like NumPy's `allclose`. (NaNs never match)
+pub fn compare<T1: TensorOps<f32>, T2: TensorOps<f32>>(
+    actual: &T1,
+    expected: &T2,
+    rtol: f32,
+    atol: f32,
+) -> Result<(), Mismatch> {
+    let mut mismatch = Mismatch {
+        actual_shape: actual.shape().to_vec(),
+        expected_shape: expected.shape().to_vec(),
+        first_index: Vec::new(),
+        actual: 0.,
+        expected: 0.,
+        max_abs_error: 0.,
+        max_rel_error: 0.,
+        num_mismatched: 0,
+    };
+    if actual.shape() != expected.shape() {
+        return Err(mismatch);
+    }
+    for (i, (a, e)) in actual.blob().iter().zip(expected.blob().iter()).enumerate() {
+        let err = (a - e).abs();
+        if err.is_nan() || err > atol + rtol * e.abs() {
+            if mismatch.num_mismatched == 0 {
+                mismatch.first_index = unravel(i, actual.shape());
+                mismatch.actual = *a;
+                mismatch.expected = *e;
+            }
+            mismatch.num_mismatched += 1;
+        }
+        mismatch.max_abs_error = mismatch.max_abs_error.max(err);
+        if *e != 0. {
+            mismatch.max_rel_error = mismatch.max_rel_error.max(err / e.abs());
+        }
+    }
+    if mismatch.num_mismatched > 0 {
+        Err(mismatch)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn allclose<T1: TensorOps<f32>, T2: TensorOps<f32>>(
+    actual: &T1,
+    expected: &T2,
+    rtol: f32,
+    atol: f32,
+) -> bool {
+    compare(actual, expected, rtol, atol).is_ok()
+}
+
+/// Panics with a report of the differences if the tensors are not close
+#[track_caller]
+pub fn assert_close<T1: TensorOps<f32>, T2: TensorOps<f32>>(
+    actual: &T1,
+    expected: &T2,
+    rtol: f32,
+    atol: f32,
+) {
+    if let Err(m) = compare(actual, expected, rtol, atol) {
+        panic!("tensors are not close: {}", m);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let a = Tensor::raw(&[2, 2], vec![1., 2., 3., 4.]).unwrap();
+        let b = Tensor::raw(&[2, 2], vec![1., 2.001, 3.5, 4.]).unwrap();
+        assert!(allclose(&a, &a, 0., 0.));
+        assert!(allclose(&a, &b, 0.2, 0.));
+        let m = compare(&a, &b, 1e-3, 1e-3).unwrap_err();
+        assert_eq!(m.first_index, vec![1, 0]);
+        assert_eq!(m.num_mismatched, 1);
+        assert_eq!(m.max_abs_error, 0.5);
+        assert!(!allclose(&a, &Tensor::vector(&[1., 2., 3., 4.]), 1., 1.));
+        assert!(!allclose(
+            &Tensor::scalar(f32::NAN),
+            &Tensor::scalar(f32::NAN),
+            1.,
+            1.
+        ));
+    }
+}
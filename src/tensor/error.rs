@@ -2,8 +2,47 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum TensorError {
-    #[error("unexpected tensor shape!")]
-    UnexpectedShape,
+    #[error("{op}: unexpected tensor shape {shape:?}")]
+    UnexpectedShape { op: &'static str, shape: Vec<usize> },
+    #[error("{op}: expected shape {expected:?}, got {actual:?}")]
+    ShapeMismatch {
+        op: &'static str,
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    #[error("{op}: axis {axis} is out of range for shape {shape:?}")]
+    InvalidAxis {
+        op: &'static str,
+        axis: usize,
+        shape: Vec<usize>,
+    },
     #[error("invalid index!")]
     InvalidIndex,
+    #[error("invalid format: {0}")]
+    InvalidFormat(String),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+impl TensorError {
+    pub fn unexpected_shape(op: &'static str, shape: &[usize]) -> Self {
+        Self::UnexpectedShape {
+            op,
+            shape: shape.to_vec(),
+        }
+    }
+    pub fn shape_mismatch(op: &'static str, expected: &[usize], actual: &[usize]) -> Self {
+        Self::ShapeMismatch {
+            op,
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+        }
+    }
+    pub fn invalid_axis(op: &'static str, axis: usize, shape: &[usize]) -> Self {
+        Self::InvalidAxis {
+            op,
+            axis,
+            shape: shape.to_vec(),
+        }
+    }
 }
@@ -0,0 +1,133 @@
+use super::*;
+use std::fmt;
+
+/// Number of leading and trailing entries of a dimension shown before the rest
+/// is elided with `...`
+const EDGE_ITEMS: usize = 3;
+
+fn dtype<V>() -> &'static str {
+    let name = std::any::type_name::<V>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Nested, NumPy-like preview of the values, large dimensions being truncated
+fn fmt_values<V: fmt::Debug>(
+    f: &mut fmt::Formatter<'_>,
+    blob: &[V],
+    shape: &[usize],
+    depth: usize,
+) -> fmt::Result {
+    if shape.is_empty() {
+        return write!(f, "{:?}", blob[0]);
+    }
+    let n = shape[0];
+    let sub_size = shape[1..].iter().product::<usize>();
+    let shown: Vec<Option<usize>> = if n > 2 * EDGE_ITEMS {
+        (0..EDGE_ITEMS)
+            .map(Some)
+            .chain(std::iter::once(None))
+            .chain((n - EDGE_ITEMS..n).map(Some))
+            .collect()
+    } else {
+        (0..n).map(Some).collect()
+    };
+    write!(f, "[")?;
+    for (j, i) in shown.into_iter().enumerate() {
+        if j > 0 {
+            if shape.len() > 1 {
+                write!(f, ",\n{}", " ".repeat(depth + 1))?;
+            } else {
+                write!(f, ", ")?;
+            }
+        }
+        match i {
+            Some(i) => fmt_values(
+                f,
+                &blob[i * sub_size..(i + 1) * sub_size],
+                &shape[1..],
+                depth + 1,
+            )?,
+            None => write!(f, "...")?,
+        }
+    }
+    write!(f, "]")
+}
+
+/// Shape, element type and statistics of the values, followed by a preview
+fn fmt_summary<V: TensorElement + fmt::Debug>(
+    f: &mut fmt::Formatter<'_>,
+    blob: &[V],
+    shape: &[usize],
+) -> fmt::Result {
+    write!(f, "Tensor(shape={:?}, dtype={}", shape, dtype::<V>())?;
+    if !blob.is_empty() {
+        let (mut min, mut max, mut sum) = (f32::INFINITY, f32::NEG_INFINITY, 0.);
+        for v in blob.iter().map(|v| v.as_f32()) {
+            min = min.min(v);
+            max = max.max(v);
+            sum += v;
+        }
+        write!(
+            f,
+            ", min={}, max={}, mean={}",
+            min,
+            max,
+            sum / blob.len() as f32
+        )?;
+    }
+    writeln!(f, ")")?;
+    fmt_values(f, blob, shape, 0)
+}
+
+impl<V: TensorElement + fmt::Debug> fmt::Debug for Tensor<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_summary(f, self.blob(), self.shape())
+    }
+}
+
+impl<V: TensorElement + fmt::Debug> fmt::Display for Tensor<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_values(f, self.blob(), self.shape(), 0)
+    }
+}
+
+impl<V: TensorElement + fmt::Debug> fmt::Debug for TensorView<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_summary(f, self.blob(), self.shape())
+    }
+}
+
+impl<V: TensorElement + fmt::Debug> fmt::Debug for TensorMutView<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_summary(f, self.blob(), self.shape())
+    }
+}
+
+impl fmt::Debug for F16 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_f32())
+    }
+}
+
+impl fmt::Debug for BF16 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_f32())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_print() {
+        let t = Tensor::raw(&[2, 3], vec![1., -1., 0.5, 2., 0., 3.5]).unwrap();
+        assert_eq!(
+            format!("{:?}", t),
+            "Tensor(shape=[2, 3], dtype=f32, min=-1, max=3.5, mean=1)\n[[1.0, -1.0, 0.5],\n [2.0, 0.0, 3.5]]"
+        );
+        let long = Tensor::raw(&[10], (0..10).collect()).unwrap();
+        assert_eq!(format!("{}", long), "[0, 1, 2, ..., 7, 8, 9]");
+        assert_eq!(format!("{}", Tensor::scalar(true)), "true");
+    }
+}
@@ -0,0 +1,343 @@
+use super::*;
+use std::io::{Read, Write};
+
+const GGUF_MAGIC: &[u8] = b"GGUF";
+const GGUF_VERSION: u32 = 3;
+const DEFAULT_ALIGNMENT: usize = 32;
+
+const GGML_TYPE_F32: u32 = 0;
+const GGML_TYPE_F16: u32 = 1;
+const GGML_TYPE_Q8_0: u32 = 8;
+/// Values per block of `Q8_0`, which stores a `F16` scale followed by the `i8` values
+const Q8_0_BLOCK: usize = 32;
+/// Arrays nested deeper are rejected rather than read recursively
+const MAX_ARRAY_DEPTH: usize = 8;
+
+fn invalid(msg: &str) -> TensorError {
+    TensorError::InvalidFormat(msg.into())
+}
+
+/// A metadata value of a GGUF file
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    fn type_id(&self) -> u32 {
+        match self {
+            GgufValue::U8(_) => 0,
+            GgufValue::I8(_) => 1,
+            GgufValue::U16(_) => 2,
+            GgufValue::I16(_) => 3,
+            GgufValue::U32(_) => 4,
+            GgufValue::I32(_) => 5,
+            GgufValue::F32(_) => 6,
+            GgufValue::Bool(_) => 7,
+            GgufValue::String(_) => 8,
+            GgufValue::Array(_) => 9,
+            GgufValue::U64(_) => 10,
+            GgufValue::I64(_) => 11,
+            GgufValue::F64(_) => 12,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            GgufValue::U8(v) => out.extend(v.to_le_bytes()),
+            GgufValue::I8(v) => out.extend(v.to_le_bytes()),
+            GgufValue::U16(v) => out.extend(v.to_le_bytes()),
+            GgufValue::I16(v) => out.extend(v.to_le_bytes()),
+            GgufValue::U32(v) => out.extend(v.to_le_bytes()),
+            GgufValue::I32(v) => out.extend(v.to_le_bytes()),
+            GgufValue::U64(v) => out.extend(v.to_le_bytes()),
+            GgufValue::I64(v) => out.extend(v.to_le_bytes()),
+            GgufValue::F32(v) => out.extend(v.to_le_bytes()),
+            GgufValue::F64(v) => out.extend(v.to_le_bytes()),
+            GgufValue::Bool(v) => out.push(*v as u8),
+            GgufValue::String(v) => write_string(out, v),
+            GgufValue::Array(values) => {
+                out.extend(values.first().map_or(0, |v| v.type_id()).to_le_bytes());
+                out.extend((values.len() as u64).to_le_bytes());
+                for v in values {
+                    v.write(out);
+                }
+            }
+        }
+    }
+
+    /// Reads a value nested in `depth` arrays
+    fn read(cur: &mut Cursor, type_id: u32, depth: usize) -> Result<Self, TensorError> {
+        let mut bytes = |n: usize| cur.take(n);
+        Ok(match type_id {
+            0 => GgufValue::U8(bytes(1)?[0]),
+            1 => GgufValue::I8(bytes(1)?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(bytes(2)?.try_into().unwrap())),
+            3 => GgufValue::I16(i16::from_le_bytes(bytes(2)?.try_into().unwrap())),
+            4 => GgufValue::U32(u32::from_le_bytes(bytes(4)?.try_into().unwrap())),
+            5 => GgufValue::I32(i32::from_le_bytes(bytes(4)?.try_into().unwrap())),
+            6 => GgufValue::F32(f32::from_le_bytes(bytes(4)?.try_into().unwrap())),
+            7 => GgufValue::Bool(bytes(1)?[0] != 0),
+            8 => GgufValue::String(read_string(cur)?),
+            9 if depth >= MAX_ARRAY_DEPTH => return Err(invalid("arrays nested too deep")),
+            9 => {
+                let type_id = cur.u32()?;
+                let len = cur.u64()?;
+                let mut values = Vec::new();
+                for _ in 0..len {
+                    values.push(GgufValue::read(cur, type_id, depth + 1)?);
+                }
+                GgufValue::Array(values)
+            }
+            10 => GgufValue::U64(u64::from_le_bytes(bytes(8)?.try_into().unwrap())),
+            11 => GgufValue::I64(i64::from_le_bytes(bytes(8)?.try_into().unwrap())),
+            12 => GgufValue::F64(f64::from_le_bytes(bytes(8)?.try_into().unwrap())),
+            _ => return Err(invalid("unknown metadata type")),
+        })
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as u64).to_le_bytes());
+    out.extend(s.as_bytes());
+}
+
+fn read_string(cur: &mut Cursor) -> Result<String, TensorError> {
+    let len = cur.u64()? as usize;
+    String::from_utf8(cur.take(len)?.to_vec()).map_err(|_| invalid("string is not utf-8"))
+}
+
+fn pad(out: &mut Vec<u8>, alignment: usize) {
+    out.resize(out.len().next_multiple_of(alignment), 0);
+}
+
+/// Writes named tensors as a [GGUF](https://github.com/ggerganov/ggml/blob/master/docs/gguf.md)
+/// file, along with `metadata`. Matrices are converted to `dtype` (`Q8_0` for int8,
+/// when their rows are made of whole blocks), while vectors stay `F32`.
+pub fn write_gguf<W: Write>(
+    mut w: W,
+    tensors: &[(&str, &Tensor<f32>)],
+    dtype: DType,
+    metadata: &[(&str, GgufValue)],
+) -> Result<(), TensorError> {
+    let mut sorted = tensors.to_vec();
+    sorted.sort_by_key(|(name, _)| *name);
+
+    let mut out = Vec::new();
+    out.extend(GGUF_MAGIC);
+    out.extend(GGUF_VERSION.to_le_bytes());
+    out.extend((sorted.len() as u64).to_le_bytes());
+    out.extend((metadata.len() as u64).to_le_bytes());
+    for (key, value) in metadata {
+        write_string(&mut out, key);
+        out.extend(value.type_id().to_le_bytes());
+        value.write(&mut out);
+    }
+
+    let mut data = Vec::new();
+    for (name, t) in sorted {
+        let row = t.shape().last().copied().unwrap_or(1);
+        let (ggml_type, bytes) = match dtype {
+            _ if t.dim() < 2 => (GGML_TYPE_F32, t.as_bytes().into_owned()),
+            DType::F32 => (GGML_TYPE_F32, t.as_bytes().into_owned()),
+            DType::F16 => (GGML_TYPE_F16, t.to_f16().as_bytes().into_owned()),
+            DType::Int8 if !row.is_multiple_of(Q8_0_BLOCK) => {
+                (GGML_TYPE_F32, t.as_bytes().into_owned())
+            }
+            DType::Int8 => {
+                let (values, scales) = quantize_i8(t.blob(), Q8_0_BLOCK);
+                let mut bytes = Vec::new();
+                for (block, scale) in values.chunks(Q8_0_BLOCK).zip(scales) {
+                    bytes.extend(F16::from_f32(scale).0.to_le_bytes());
+                    bytes.extend(block.iter().map(|v| *v as u8));
+                }
+                (GGML_TYPE_Q8_0, bytes)
+            }
+        };
+        pad(&mut data, DEFAULT_ALIGNMENT);
+        write_string(&mut out, name);
+        out.extend((t.dim() as u32).to_le_bytes());
+        // Dimensions are listed from the fastest varying one
+        for d in t.shape().iter().rev() {
+            out.extend((*d as u64).to_le_bytes());
+        }
+        out.extend(ggml_type.to_le_bytes());
+        out.extend((data.len() as u64).to_le_bytes());
+        data.extend(bytes);
+    }
+    pad(&mut out, DEFAULT_ALIGNMENT);
+    w.write_all(&out)?;
+    w.write_all(&data)?;
+    Ok(())
+}
+
+/// Reads the `F32`, `F16` and `Q8_0` tensors of a GGUF file as `f32`, ordered by name.
+/// Also returns the metadata.
+#[allow(clippy::type_complexity)]
+pub fn read_gguf<R: Read>(
+    mut r: R,
+) -> Result<(Vec<(String, Tensor<f32>)>, Vec<(String, GgufValue)>), TensorError> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    let mut cur = Cursor {
+        bytes: &bytes,
+        pos: 0,
+    };
+    if cur.take(GGUF_MAGIC.len())? != GGUF_MAGIC {
+        return Err(invalid("not a GGUF file"));
+    }
+    if !(2..=GGUF_VERSION).contains(&cur.u32()?) {
+        return Err(invalid("unsupported version"));
+    }
+    let num_tensors = cur.u64()?;
+    let num_metadata = cur.u64()?;
+    let mut metadata = Vec::new();
+    for _ in 0..num_metadata {
+        let key = read_string(&mut cur)?;
+        let type_id = cur.u32()?;
+        metadata.push((key, GgufValue::read(&mut cur, type_id, 0)?));
+    }
+    let alignment = metadata
+        .iter()
+        .find_map(|(k, v)| match (k.as_str(), v) {
+            ("general.alignment", GgufValue::U32(a)) if *a > 0 => Some(*a as usize),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_ALIGNMENT);
+
+    let mut infos = Vec::new();
+    for _ in 0..num_tensors {
+        let name = read_string(&mut cur)?;
+        let dim = cur.u32()? as usize;
+        let mut shape = (0..dim)
+            .map(|_| cur.u64().map(|d| d as usize))
+            .collect::<Result<Vec<_>, _>>()?;
+        shape.reverse();
+        let ggml_type = cur.u32()?;
+        let offset = cur.u64()? as usize;
+        infos.push((name, shape, ggml_type, offset));
+    }
+    let data = &bytes[cur.pos.next_multiple_of(alignment).min(bytes.len())..];
+
+    let mut tensors = Vec::new();
+    for (name, shape, ggml_type, offset) in infos {
+        let size = shape
+            .iter()
+            .try_fold(1usize, |size, d| size.checked_mul(*d))
+            .ok_or_else(|| invalid("tensor too large"))?;
+        let len = match ggml_type {
+            GGML_TYPE_F32 => size.checked_mul(4),
+            GGML_TYPE_F16 => size.checked_mul(2),
+            GGML_TYPE_Q8_0 => size.div_ceil(Q8_0_BLOCK).checked_mul(2 + Q8_0_BLOCK),
+            _ => return Err(invalid(&format!("unsupported tensor type {}", ggml_type))),
+        };
+        let bytes = len
+            .and_then(|len| offset.checked_add(len))
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| invalid("truncated"))?;
+        let tensor = match ggml_type {
+            GGML_TYPE_F32 => Tensor::<f32>::from_bytes(&shape, bytes)?,
+            GGML_TYPE_F16 => Tensor::<F16>::from_bytes(&shape, bytes)?.to_f32(),
+            _ => {
+                let mut values = Vec::with_capacity(size);
+                let mut scales = Vec::with_capacity(size / Q8_0_BLOCK);
+                for block in bytes.chunks(2 + Q8_0_BLOCK) {
+                    scales.push(F16(u16::from_le_bytes([block[0], block[1]])).to_f32());
+                    values.extend(block[2..].iter().map(|v| *v as i8));
+                }
+                Tensor::raw(&shape, dequantize_i8(&values, &scales, Q8_0_BLOCK))?
+            }
+        };
+        tensors.push((name, tensor));
+    }
+    tensors.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok((tensors, metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gguf() {
+        let mut rng = rand::thread_rng();
+        let a = Tensor::<f32>::rand(&mut rng, &[3, 64]);
+        let b = Tensor::vector(&[3., 4.]);
+        let metadata = [
+            (
+                "general.architecture",
+                GgufValue::String("femto-gpt".into()),
+            ),
+            ("femto-gpt.block_count", GgufValue::U32(4)),
+            (
+                "tokenizer.ggml.tokens",
+                GgufValue::Array(vec![GgufValue::String("a".into())]),
+            ),
+        ];
+        for dtype in [DType::F32, DType::F16, DType::Int8] {
+            let mut bytes = Vec::new();
+            write_gguf(&mut bytes, &[("b", &b), ("a", &a)], dtype, &metadata).unwrap();
+            let (tensors, read_metadata) = read_gguf(&bytes[..]).unwrap();
+            assert_eq!(
+                read_metadata,
+                metadata
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(tensors[0].0, "a");
+            assert_eq!(tensors[0].1.shape(), &[3, 64]);
+            let tolerance = match dtype {
+                DType::F32 => 0.,
+                DType::F16 => 1e-3,
+                DType::Int8 => 1e-2,
+            };
+            for (x, y) in tensors[0].1.blob().iter().zip(a.blob()) {
+                assert!((x - y).abs() <= tolerance, "{:?}: {} {}", dtype, x, y);
+            }
+            assert_eq!(tensors[1].1.blob(), &[3., 4.]);
+            assert!(read_gguf(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_gguf_malformed() {
+        let header = |num_tensors: u64, num_metadata: u64| {
+            let mut bytes = GGUF_MAGIC.to_vec();
+            bytes.extend(GGUF_VERSION.to_le_bytes());
+            bytes.extend(num_tensors.to_le_bytes());
+            bytes.extend(num_metadata.to_le_bytes());
+            bytes
+        };
+
+        // Arrays of arrays of ...
+        let mut nested = header(0, 1);
+        write_string(&mut nested, "key");
+        for _ in 0..10_000 {
+            nested.extend(9u32.to_le_bytes());
+            nested.extend(1u64.to_le_bytes());
+        }
+        assert!(read_gguf(&nested[..]).is_err());
+
+        // A tensor whose size overflows
+        let mut huge = header(1, 0);
+        write_string(&mut huge, "a");
+        huge.extend(2u32.to_le_bytes());
+        huge.extend([u64::MAX / 2, 4].iter().flat_map(|d| d.to_le_bytes()));
+        huge.extend(GGML_TYPE_F32.to_le_bytes());
+        huge.extend(0u64.to_le_bytes());
+        assert!(read_gguf(&huge[..]).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use super::*;
+
+/// IEEE 754 half-precision float, stored as its raw bits. Only meant for storage;
+/// convert to `f32` to do arithmetic.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[repr(transparent)]
+pub struct F16(pub u16);
+
+/// Brain floating point, i.e. the upper 16 bits of an `f32`. Same range as an
+/// `f32` with less precision than an `F16`.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[repr(transparent)]
+pub struct BF16(pub u16);
+
+/// Drops the lowest `shift` bits of `v`, rounding to the nearest (Even on ties)
+fn round_shift(v: u32, shift: u32) -> u32 {
+    let rem = v & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+    let res = v >> shift;
+    if rem > half || (rem == half && res & 1 == 1) {
+        res + 1
+    } else {
+        res
+    }
+}
+
+impl F16 {
+    pub fn from_f32(v: f32) -> Self {
+        let bits = v.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let exp = ((bits >> 23) & 0xff) as i32;
+        let man = bits & 0x7fffff;
+        if exp == 0xff {
+            // Infinity stays infinity, NaN stays NaN
+            return F16(sign | 0x7c00 | if man != 0 { 0x200 } else { 0 });
+        }
+        let exp = exp - 127 + 15;
+        if exp >= 0x1f {
+            return F16(sign | 0x7c00);
+        }
+        if exp <= 0 {
+            if exp < -10 {
+                return F16(sign);
+            }
+            // Subnormal (Rounding up to the smallest normal gives the right bits too)
+            return F16(sign | round_shift(man | 0x800000, (14 - exp) as u32) as u16);
+        }
+        // A carry out of the mantissa correctly bumps the exponent
+        F16(sign | (((exp as u32) << 10) + round_shift(man, 13)) as u16)
+    }
+    pub fn to_f32(self) -> f32 {
+        let sign = ((self.0 & 0x8000) as u32) << 16;
+        let exp = ((self.0 >> 10) & 0x1f) as u32;
+        let man = (self.0 & 0x3ff) as u32;
+        match exp {
+            0 => {
+                let v = man as f32 * 2f32.powi(-24);
+                if sign != 0 {
+                    -v
+                } else {
+                    v
+                }
+            }
+            0x1f => f32::from_bits(sign | 0x7f800000 | (man << 13)),
+            _ => f32::from_bits(sign | ((exp + 112) << 23) | (man << 13)),
+        }
+    }
+}
+
+impl BF16 {
+    pub fn from_f32(v: f32) -> Self {
+        let bits = v.to_bits();
+        if v.is_nan() {
+            return BF16((bits >> 16) as u16 | 0x40);
+        }
+        BF16(round_shift(bits, 16) as u16)
+    }
+    pub fn to_f32(self) -> f32 {
+        f32::from_bits((self.0 as u32) << 16)
+    }
+}
+
+impl TensorElement for F16 {
+    fn zero() -> Self {
+        F16(0)
+    }
+    fn one() -> Self {
+        F16(0x3c00)
+    }
+    fn as_f32(self) -> f32 {
+        self.to_f32()
+    }
+}
+
+impl TensorElement for BF16 {
+    fn zero() -> Self {
+        BF16(0)
+    }
+    fn one() -> Self {
+        BF16(0x3f80)
+    }
+    fn as_f32(self) -> f32 {
+        self.to_f32()
+    }
+}
+
+impl NpyElement for F16 {
+    const DESCR: &'static str = "<f2";
+    const SIZE: usize = 2;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend(self.0.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        F16(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+impl Tensor<f32> {
+    /// Half-precision copy of the tensor, taking half the memory
+    pub fn to_f16(&self) -> Tensor<F16> {
+        self.map_values(F16::from_f32)
+    }
+    pub fn to_bf16(&self) -> Tensor<BF16> {
+        self.map_values(BF16::from_f32)
+    }
+}
+
+impl Tensor<F16> {
+    pub fn to_f32(&self) -> Tensor<f32> {
+        self.map_values(F16::to_f32)
+    }
+}
+
+impl Tensor<BF16> {
+    pub fn to_f32(&self) -> Tensor<f32> {
+        self.map_values(BF16::to_f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_half_conversions() {
+        for (v, f16, bf16) in [
+            (1.0f32, 0x3c00, 0x3f80),
+            (-2.0, 0xc000, 0xc000),
+            (0.1, 0x2e66, 0x3dcd),
+            (65504.0, 0x7bff, 0x4780),
+            (1e6, 0x7c00, 0x4974),
+            (6e-8, 0x0001, 0x3381),
+            (f32::INFINITY, 0x7c00, 0x7f80),
+        ] {
+            assert_eq!(F16::from_f32(v), F16(f16), "{}", v);
+            assert_eq!(BF16::from_f32(v), BF16(bf16), "{}", v);
+        }
+        assert!(F16::from_f32(f32::NAN).to_f32().is_nan());
+        assert!(BF16::from_f32(f32::NAN).to_f32().is_nan());
+
+        let t = Tensor::vector(&[0.5, -3.25, 1024., 2f32.powi(-24)]);
+        assert_eq!(t.to_f16().to_f32().blob(), t.blob());
+        assert_eq!(t.to_bf16().to_f32().blob()[..3], t.blob()[..3]);
+    }
+}
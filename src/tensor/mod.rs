@@ -1,59 +1,91 @@
+mod codec;
+mod compare;
 mod elements;
 mod error;
+mod format;
+mod gguf;
+mod half;
 mod helper;
+mod npy;
 mod ops;
+mod quant;
+mod safetensors;
+mod strided;
+mod tokens;
 mod view;
+pub use codec::*;
+pub use compare::*;
 pub use elements::*;
 pub use error::*;
-pub use helper::*;
+pub use gguf::*;
+pub use half::*;
+pub use npy::*;
 pub use ops::*;
+pub use quant::*;
+pub use safetensors::*;
+pub use strided::*;
 pub use view::*;
 
 use rand::prelude::*;
 use rand_distr::Normal;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::ops::*;
+use std::sync::Arc;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Values are shared between clones of a tensor, and only get copied when one of
+/// the clones is mutated. (Copy-on-write) Serialized in a portable form, see
+/// `codec.rs`
+#[derive(Clone)]
 pub struct Tensor<V: TensorElement> {
-    blob: Vec<V>,
+    blob: Arc<Vec<V>>,
     shape: Vec<usize>,
 }
 
 impl<V: TensorElement> Tensor<V> {
     pub fn raw(shape: &[usize], blob: Vec<V>) -> Result<Self, TensorError> {
-        let sz = shape.iter().fold(1, |c, s| c * s);
+        let sz = shape.iter().product::<usize>();
         if sz != blob.len() {
-            return Err(TensorError::UnexpectedShape);
+            return Err(TensorError::shape_mismatch("raw", shape, &[blob.len()]));
         }
         Ok(Self {
-            blob,
+            blob: Arc::new(blob),
             shape: shape.to_vec(),
         })
     }
+    /// Takes the values back out of the tensor, without copying them unless they
+    /// are shared with another tensor
+    pub fn into_vec(self) -> Vec<V> {
+        Arc::try_unwrap(self.blob).unwrap_or_else(|blob| blob.to_vec())
+    }
+    /// Whether the values are stored in the same memory as those of `other`
+    pub fn shares_storage(&self, other: &Tensor<V>) -> bool {
+        Arc::ptr_eq(&self.blob, &other.blob)
+    }
     pub fn tril(n: usize) -> Self {
         Tensor {
             blob: (0..n * n)
                 .map(|i| if i % n <= i / n { V::one() } else { V::zero() })
-                .collect(),
+                .collect::<Vec<_>>()
+                .into(),
             shape: vec![n, n],
         }
     }
     pub fn scalar(v: V) -> Self {
         Tensor {
-            blob: vec![v],
+            blob: vec![v].into(),
             shape: vec![],
         }
     }
     pub fn vector(v: &[V]) -> Self {
         Tensor {
-            blob: v.to_vec(),
+            blob: v.to_vec().into(),
             shape: vec![v.len()],
         }
     }
     pub fn constant(shape: &[usize], value: V) -> Self {
         Tensor {
-            blob: vec![value; shape.iter().fold(1, |curr, s| curr * s)],
+            blob: vec![value; shape.iter().product::<usize>()].into(),
             shape: shape.to_vec(),
         }
     }
@@ -63,23 +95,109 @@ impl<V: TensorElement> Tensor<V> {
     pub fn ones(shape: &[usize]) -> Self {
         Self::constant(shape, V::one())
     }
+    /// Concatenates tensors along dimension `axis`. All the other dimensions must
+    /// match.
+    pub fn cat<T: TensorOps<V>>(tensors: &[&T], axis: usize) -> Result<Self, TensorError> {
+        let first = tensors
+            .first()
+            .ok_or(TensorError::unexpected_shape("cat", &[0]))?;
+        let dim = first.dim();
+        if axis >= dim {
+            return Err(TensorError::invalid_axis("cat", axis, first.shape()));
+        }
+        for t in tensors {
+            if t.dim() != dim
+                || t.shape()[..axis] != first.shape()[..axis]
+                || t.shape()[axis + 1..] != first.shape()[axis + 1..]
+            {
+                return Err(TensorError::shape_mismatch("cat", first.shape(), t.shape()));
+            }
+        }
+        let inner = first.shape()[axis + 1..].iter().product::<usize>();
+        let outer = first.shape()[..axis].iter().product::<usize>();
+        let mut blob = Vec::with_capacity(tensors.iter().map(|t| t.size()).sum());
+        for o in 0..outer {
+            for t in tensors {
+                let chunk = t.shape()[axis] * inner;
+                blob.extend_from_slice(&t.blob()[o * chunk..(o + 1) * chunk]);
+            }
+        }
+        let mut shape = first.shape().to_vec();
+        shape[axis] = tensors.iter().map(|t| t.shape()[axis]).sum();
+        Tensor::raw(&shape, blob)
+    }
+    /// Stacks tensors of the same shape along a new dimension, inserted at `axis`.
+    /// (E.g. batching prompts of equal length)
+    pub fn stack<T: TensorOps<V>>(tensors: &[&T], axis: usize) -> Result<Self, TensorError> {
+        let first = tensors
+            .first()
+            .ok_or(TensorError::unexpected_shape("stack", &[0]))?;
+        if axis > first.dim() {
+            return Err(TensorError::invalid_axis("stack", axis, first.shape()));
+        }
+        if let Some(t) = tensors.iter().find(|t| t.shape() != first.shape()) {
+            return Err(TensorError::shape_mismatch(
+                "stack",
+                first.shape(),
+                t.shape(),
+            ));
+        }
+        let mut shape = first.shape().to_vec();
+        shape.insert(axis, 1);
+        let expanded = tensors
+            .iter()
+            .map(|t| TensorView {
+                mirror: t.tensor(),
+                offset: t.offset(),
+                shape: shape.clone(),
+            })
+            .collect::<Vec<_>>();
+        Self::cat(&expanded.iter().collect::<Vec<_>>(), axis)
+    }
     pub fn rand_range<R: Rng>(r: &mut R, start: f32, end: f32, shape: &[usize]) -> Tensor<f32> {
-        Tensor::<f32> {
-            blob: (0..shape.iter().fold(1, |curr, s| curr * s))
-                .map(|_| r.gen_range(start..end))
-                .collect(),
+        Tensor::rand_uniform(r, start, end, shape)
+    }
+    pub fn rand<R: Rng>(r: &mut R, shape: &[usize]) -> Tensor<f32> {
+        Tensor::rand_normal(r, 0.0, 0.02, shape)
+    }
+}
+
+impl Tensor<f32> {
+    /// Samples uniformly from `[lo, hi)`
+    pub fn rand_uniform<R: Rng>(r: &mut R, lo: f32, hi: f32, shape: &[usize]) -> Self {
+        Tensor {
+            blob: (0..shape.iter().product::<usize>())
+                .map(|_| r.gen_range(lo..hi))
+                .collect::<Vec<_>>()
+                .into(),
             shape: shape.to_vec(),
         }
     }
-    pub fn rand<R: Rng>(r: &mut R, shape: &[usize]) -> Tensor<f32> {
-        let normal = Normal::new(0.0, 0.02).unwrap();
-        Tensor::<f32> {
-            blob: (0..shape.iter().fold(1, |curr, s| curr * s))
+    pub fn rand_normal<R: Rng>(r: &mut R, mean: f32, std: f32, shape: &[usize]) -> Self {
+        let normal = Normal::new(mean, std).unwrap();
+        Tensor {
+            blob: (0..shape.iter().product::<usize>())
                 .map(|_| normal.sample(r))
-                .collect(),
+                .collect::<Vec<_>>()
+                .into(),
             shape: shape.to_vec(),
         }
     }
+    /// Glorot initialization, keeping the variance of the activations and of the
+    /// gradients roughly the same across a linear layer
+    pub fn xavier_uniform<R: Rng>(
+        r: &mut R,
+        fan_in: usize,
+        fan_out: usize,
+        shape: &[usize],
+    ) -> Self {
+        let limit = (6. / (fan_in + fan_out) as f32).sqrt();
+        Self::rand_uniform(r, -limit, limit, shape)
+    }
+    /// He initialization, suited for layers followed by a ReLU/GELU
+    pub fn kaiming_normal<R: Rng>(r: &mut R, fan_in: usize, shape: &[usize]) -> Self {
+        Self::rand_normal(r, 0., (2. / fan_in as f32).sqrt(), shape)
+    }
 }
 
 impl<T: TensorOps<bool>> From<&T> for Tensor<f32> {
@@ -100,12 +218,71 @@ pub trait TensorMutOps<V: TensorElement>: TensorOps<V> {
     }
     fn set<T: TensorOps<V>>(&mut self, t: T) -> Result<(), TensorError> {
         if self.shape() != t.shape() {
-            return Err(TensorError::UnexpectedShape);
+            return Err(TensorError::shape_mismatch("set", self.shape(), t.shape()));
         }
         self.blob_mut().clone_from_slice(t.blob());
         Ok(())
     }
-    fn get_mut(&mut self, ind: usize) -> Result<TensorMutView<V>, TensorError> {
+    /// Replaces each element `a` with `f(a, b)`, `b` being the corresponding
+    /// element of `other`, which may be broadcast to the shape of this tensor
+    fn zip_assign<T: TensorOps<V>, F: Fn(V, V) -> V>(
+        &mut self,
+        other: &T,
+        f: F,
+    ) -> Result<(), TensorError> {
+        let shape = self.shape().to_vec();
+        if broadcast_shape(&shape, other.shape())? != shape {
+            return Err(TensorError::shape_mismatch(
+                "in-place op",
+                &shape,
+                other.shape(),
+            ));
+        }
+        let other_blob = other.blob();
+        let blob = self.blob_mut();
+        if shape == other.shape() {
+            for (a, b) in blob.iter_mut().zip(other_blob.iter()) {
+                *a = f(*a, *b);
+            }
+        } else {
+            ops::broadcast_for_each(&shape, &shape, other.shape(), |p, q| {
+                blob[p] = f(blob[p], other_blob[q]);
+            });
+        }
+        Ok(())
+    }
+    fn add_assign<T: TensorOps<V>>(&mut self, other: &T) -> Result<(), TensorError>
+    where
+        V: std::ops::Add<Output = V>,
+    {
+        self.zip_assign(other, |a, b| a + b)
+    }
+    fn sub_assign<T: TensorOps<V>>(&mut self, other: &T) -> Result<(), TensorError>
+    where
+        V: std::ops::Sub<Output = V>,
+    {
+        self.zip_assign(other, |a, b| a - b)
+    }
+    fn mul_assign<T: TensorOps<V>>(&mut self, other: &T) -> Result<(), TensorError>
+    where
+        V: std::ops::Mul<Output = V>,
+    {
+        self.zip_assign(other, |a, b| a * b)
+    }
+    fn scale(&mut self, coeff: V)
+    where
+        V: std::ops::Mul<Output = V>,
+    {
+        self.blob_mut().iter_mut().for_each(|v| *v = *v * coeff);
+    }
+    /// `self += alpha * other` (AXPY)
+    fn scaled_add<T: TensorOps<V>>(&mut self, alpha: V, other: &T) -> Result<(), TensorError>
+    where
+        V: std::ops::Add<Output = V> + std::ops::Mul<Output = V>,
+    {
+        self.zip_assign(other, |a, b| a + alpha * b)
+    }
+    fn get_mut(&mut self, ind: usize) -> Result<TensorMutView<'_, V>, TensorError> {
         if ind >= self.len() {
             return Err(TensorError::InvalidIndex);
         }
@@ -118,6 +295,8 @@ pub trait TensorMutOps<V: TensorElement>: TensorOps<V> {
     }
 }
 
+// `len` is the size of the first dimension, not of the blob
+#[allow(clippy::len_without_is_empty)]
 pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
     fn shape(&self) -> &[usize];
     fn blob(&self) -> &[V];
@@ -131,10 +310,97 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
         self.blob().iter().cloned().sum::<V>().as_f32() / self.size() as f32
     }
 
-    fn keep_right(&self, dims: usize) -> Result<TensorView<V>, TensorError> {
+    /// Sums the elements over the dimensions that were broadcast to get from
+    /// `shape` to the shape of this tensor. (I.e. reverses broadcasting, which is
+    /// what the gradient of a broadcast operand needs)
+    fn sum_to(&self, shape: &[usize]) -> Result<Tensor<V>, TensorError>
+    where
+        V: std::ops::Add<Output = V>,
+    {
+        if broadcast_shape(shape, self.shape())? != self.shape() {
+            return Err(TensorError::shape_mismatch("sum_to", shape, self.shape()));
+        }
+        if shape == self.shape() {
+            return Ok(self.view().into());
+        }
+        let mut result = vec![V::zero(); shape.iter().product()];
+        let blob = self.blob();
+        ops::broadcast_for_each(self.shape(), self.shape(), shape, |p, r| {
+            result[r] = result[r] + blob[p];
+        });
+        Tensor::raw(shape, result)
+    }
+
+    /// Reduces dimension `axis` by applying `f` to the elements along it. The
+    /// reduced dimension is kept with a size of 1 when `keepdim` is set, so that the
+    /// result broadcasts back against the tensor.
+    fn reduce<W: TensorElement, F: Fn(&[V]) -> W>(
+        &self,
+        axis: usize,
+        keepdim: bool,
+        f: F,
+    ) -> Result<Tensor<W>, TensorError> {
+        let n = *self
+            .shape()
+            .get(axis)
+            .ok_or_else(|| TensorError::invalid_axis("reduce", axis, self.shape()))?;
+        let inner = self.shape()[axis + 1..].iter().product::<usize>();
+        let outer = self.shape()[..axis].iter().product::<usize>();
+        let blob = self.blob();
+        let mut result = Vec::with_capacity(outer * inner);
+        let mut elems = Vec::with_capacity(n);
+        for o in 0..outer {
+            for i in 0..inner {
+                elems.clear();
+                elems.extend((0..n).map(|j| blob[(o * n + j) * inner + i]));
+                result.push(f(&elems));
+            }
+        }
+        let mut shape = self.shape().to_vec();
+        if keepdim {
+            shape[axis] = 1;
+        } else {
+            shape.remove(axis);
+        }
+        Tensor::raw(&shape, result)
+    }
+    fn sum_axis(&self, axis: usize, keepdim: bool) -> Result<Tensor<V>, TensorError>
+    where
+        V: std::ops::Add<Output = V>,
+    {
+        self.reduce(axis, keepdim, |e| e.iter().fold(V::zero(), |a, b| a + *b))
+    }
+    fn max_axis(&self, axis: usize, keepdim: bool) -> Result<Tensor<V>, TensorError>
+    where
+        V: PartialOrd,
+    {
+        if self.shape().get(axis) == Some(&0) {
+            return Err(TensorError::unexpected_shape("max_axis", self.shape()));
+        }
+        self.reduce(axis, keepdim, |e| {
+            e.iter()
+                .skip(1)
+                .fold(e[0], |a, b| if *b > a { *b } else { a })
+        })
+    }
+    fn mean_axis(&self, axis: usize, keepdim: bool) -> Result<Tensor<f32>, TensorError> {
+        self.reduce(axis, keepdim, |e| {
+            e.iter().map(|v| v.as_f32()).sum::<f32>() / e.len() as f32
+        })
+    }
+    /// Population variance (I.e. divided by the number of elements)
+    fn var_axis(&self, axis: usize, keepdim: bool) -> Result<Tensor<f32>, TensorError> {
+        self.reduce(axis, keepdim, |e| {
+            let n_inv = 1. / e.len() as f32;
+            let avg = e.iter().map(|v| v.as_f32()).sum::<f32>() * n_inv;
+            e.iter().map(|v| (v.as_f32() - avg).powi(2)).sum::<f32>() * n_inv
+        })
+    }
+
+    fn keep_right(&self, dims: usize) -> Result<TensorView<'_, V>, TensorError> {
         let mut shape = self.shape().to_vec();
         if shape.len() < dims {
-            return Err(TensorError::UnexpectedShape);
+            return Err(TensorError::unexpected_shape("keep_right", &shape));
         } else if shape.len() == dims {
             shape.insert(0, 1);
         } else {
@@ -152,7 +418,7 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
 
     fn map_values<W: TensorElement, F: Fn(V) -> W + Sync + Send>(&self, f: F) -> Tensor<W> {
         Tensor {
-            blob: self.blob().iter().map(|v| f(*v)).collect::<Vec<_>>(),
+            blob: self.blob().iter().map(|v| f(*v)).collect::<Vec<_>>().into(),
             shape: self.shape().to_vec(),
         }
     }
@@ -166,27 +432,55 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
         f: F,
     ) -> Result<Tensor<W>, TensorError> {
         let blob = self
-            .keep_right(dim)?
-            .inners()
-            .into_iter()
-            .map(|v| f(v))
+            .par_chunks(dim)?
+            .map(f)
             .collect::<Result<Vec<_>, TensorError>>()?;
-        if !blob.iter().all(|t| t.shape() == blob[0].shape()) {
-            return Err(TensorError::UnexpectedShape);
+        if let Some(t) = blob.iter().find(|t| t.shape() != blob[0].shape()) {
+            return Err(TensorError::shape_mismatch(
+                "map",
+                blob[0].shape(),
+                t.shape(),
+            ));
         }
         let mut out_shape = self.shape()[..self.dim() - dim].to_vec();
         out_shape.extend(blob[0].shape());
         Ok(Tensor {
-            blob: blob.into_iter().map(|t| t.blob).flatten().collect(),
+            blob: blob
+                .into_iter()
+                .flat_map(|t| t.into_vec())
+                .collect::<Vec<_>>()
+                .into(),
             shape: out_shape,
         })
     }
 
+    /// Parallel iterator over the sub-tensors made of the last `dims` dimensions,
+    /// i.e. one item per index of the leading dimensions (In order)
+    fn par_chunks<'a>(
+        &'a self,
+        dims: usize,
+    ) -> Result<impl IndexedParallelIterator<Item = TensorView<'a, V>>, TensorError>
+    where
+        V: 'a,
+    {
+        let rows = self.keep_right(dims)?;
+        let sub_shape = rows.shape()[1..].to_vec();
+        let sub_size = sub_shape.iter().product::<usize>();
+        let (mirror, offset) = (self.tensor(), self.offset());
+        Ok((0..rows.shape()[0])
+            .into_par_iter()
+            .map(move |i| TensorView {
+                mirror,
+                offset: offset + i * sub_size,
+                shape: sub_shape.clone(),
+            }))
+    }
+
     fn scalar(&self) -> Result<V, TensorError> {
         if self.dim() == 0 {
             Ok(self.blob()[0])
         } else {
-            Err(TensorError::UnexpectedShape)
+            Err(TensorError::shape_mismatch("scalar", &[], self.shape()))
         }
     }
     fn inners<'a>(&'a self) -> Vec<TensorView<'a, V>> {
@@ -198,12 +492,12 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
         self.shape().len()
     }
     fn len(&self) -> usize {
-        *self.shape().get(0).unwrap_or(&0) // Scalar has a len of 0
+        *self.shape().first().unwrap_or(&0) // Scalar has a len of 0
     }
     fn size(&self) -> usize {
-        self.shape().iter().fold(1, |curr, s| curr * s)
+        self.shape().iter().product()
     }
-    fn view(&self) -> TensorView<V> {
+    fn view(&self) -> TensorView<'_, V> {
         TensorView {
             mirror: self.tensor(),
             offset: self.offset(),
@@ -211,7 +505,11 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
         }
     }
 
-    fn get(&self, ind: usize) -> Result<TensorView<V>, TensorError> {
+    fn strided(&self) -> StridedView<'_, V> {
+        StridedView::new(self)
+    }
+
+    fn get(&self, ind: usize) -> Result<TensorView<'_, V>, TensorError> {
         if ind >= self.len() {
             return Err(TensorError::InvalidIndex);
         }
@@ -223,23 +521,140 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
         })
     }
 
-    fn transpose(&self) -> Result<Tensor<V>, TensorError> {
-        self.map(2, |m| {
-            let d0 = m.shape()[0];
-            let d1 = m.shape()[1];
-            let mut dat = Vec::with_capacity(d1 * d0);
-            let m_blob = m.blob();
-            for j in 0..d1 {
-                for i in 0..d0 {
-                    dat.push(m_blob[i * d1 + j]);
+    /// Keeps `len` elements of dimension `axis`, starting from `start`, without
+    /// copying any data
+    fn narrow(
+        &self,
+        axis: usize,
+        start: usize,
+        len: usize,
+    ) -> Result<StridedView<'_, V>, TensorError> {
+        self.strided().narrow(axis, start, len)
+    }
+
+    /// Copies the elements within `range` of dimension `axis` into a new tensor.
+    /// (E.g. `t.slice(1, 2..)` is NumPy's `t[:, 2:]`)
+    fn slice<R: RangeBounds<usize>>(
+        &self,
+        axis: usize,
+        range: R,
+    ) -> Result<Tensor<V>, TensorError> {
+        let dim_len = *self
+            .shape()
+            .get(axis)
+            .ok_or_else(|| TensorError::invalid_axis("slice", axis, self.shape()))?;
+        let start = match range.start_bound() {
+            Bound::Included(s) => *s,
+            Bound::Excluded(s) => s + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(e) => e + 1,
+            Bound::Excluded(e) => *e,
+            Bound::Unbounded => dim_len,
+        };
+        if start > end {
+            return Err(TensorError::InvalidIndex);
+        }
+        Ok(self.narrow(axis, start, end - start)?.into())
+    }
+
+    /// Gathers the given elements of dimension `axis`, in the given order and
+    /// possibly repeated. (E.g. looking up the rows of an embedding table)
+    fn index_select(&self, axis: usize, indices: &[usize]) -> Result<Tensor<V>, TensorError> {
+        let n = *self
+            .shape()
+            .get(axis)
+            .ok_or_else(|| TensorError::invalid_axis("index_select", axis, self.shape()))?;
+        if indices.iter().any(|i| *i >= n) {
+            return Err(TensorError::InvalidIndex);
+        }
+        let inner = self.shape()[axis + 1..].iter().product::<usize>();
+        let blob = self.blob();
+        let mut result = Vec::with_capacity(self.size() / n.max(1) * indices.len());
+        for outer in blob.chunks(n * inner) {
+            for i in indices {
+                result.extend_from_slice(&outer[i * inner..(i + 1) * inner]);
+            }
+        }
+        let mut shape = self.shape().to_vec();
+        shape[axis] = indices.len();
+        Tensor::raw(&shape, result)
+    }
+
+    /// Index of the largest element of each row (I.e. along the last dimension)
+    fn argmax(&self) -> Result<Tensor<usize>, TensorError>
+    where
+        V: PartialOrd,
+    {
+        if self.dim() == 0 {
+            return Err(TensorError::unexpected_shape("argmax", self.shape()));
+        }
+        self.map(1, |row| {
+            let mut best = 0;
+            for (i, v) in row.blob().iter().enumerate() {
+                if *v > row.blob()[best] {
+                    best = i;
                 }
             }
-            Ok(Tensor {
-                blob: dat,
-                shape: [d1, d0].to_vec(),
-            })
+            Ok(Tensor::scalar(best))
         })
     }
+
+    /// Indices that would sort each row, ties keeping their original order
+    fn argsort(&self, descending: bool) -> Result<Tensor<usize>, TensorError>
+    where
+        V: PartialOrd,
+    {
+        if self.dim() == 0 {
+            return Err(TensorError::unexpected_shape("argsort", self.shape()));
+        }
+        self.map(1, |row| {
+            let blob = row.blob();
+            let mut indices = (0..blob.len()).collect::<Vec<_>>();
+            indices.sort_by(|a, b| {
+                let ord = blob[*a]
+                    .partial_cmp(&blob[*b])
+                    .unwrap_or(std::cmp::Ordering::Equal);
+                if descending {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+            Ok(Tensor::vector(&indices))
+        })
+    }
+
+    /// The `k` largest elements of each row in descending order, along with their
+    /// indices
+    fn topk(&self, k: usize) -> Result<(Tensor<V>, Tensor<usize>), TensorError>
+    where
+        V: PartialOrd,
+    {
+        if self.dim() == 0 || k > self.shape()[self.dim() - 1] {
+            return Err(TensorError::unexpected_shape("topk", self.shape()));
+        }
+        let indices = self.argsort(true)?.slice(self.dim() - 1, ..k)?;
+        let blob = self.blob();
+        let row_len = self.shape()[self.dim() - 1];
+        let values = indices
+            .blob()
+            .chunks(k.max(1))
+            .enumerate()
+            .flat_map(|(r, inds)| inds.iter().map(move |i| blob[r * row_len + i]))
+            .collect();
+        Ok((Tensor::raw(indices.shape(), values)?, indices))
+    }
+
+    /// Swaps the last two dimensions
+    fn transpose(&self) -> Result<Tensor<V>, TensorError> {
+        let dim = self.dim();
+        if dim < 2 {
+            return Err(TensorError::unexpected_shape("transpose", self.shape()));
+        }
+        Ok(self.strided().transpose(dim - 2, dim - 1)?.into())
+    }
 }
 
 impl<V: TensorElement> TensorMutOps<V> for Tensor<V> {
@@ -247,7 +662,7 @@ impl<V: TensorElement> TensorMutOps<V> for Tensor<V> {
         self
     }
     fn blob_mut(&mut self) -> &mut [V] {
-        &mut self.blob
+        Arc::make_mut(&mut self.blob).as_mut_slice()
     }
 }
 
@@ -266,3 +681,138 @@ impl<V: TensorElement> TensorOps<V> for Tensor<V> {
         &self.blob
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argmax_argsort_topk() {
+        let t = Tensor::raw(&[2, 4], vec![0.1, 0.7, -1., 0.7, 3., 2., 1., 0.]).unwrap();
+        assert_eq!(t.argmax().unwrap().blob(), &[1, 0]);
+        let sorted = t.argsort(false).unwrap();
+        assert_eq!(sorted.shape(), &[2, 4]);
+        assert_eq!(sorted.blob(), &[2, 0, 1, 3, 3, 2, 1, 0]);
+        let (values, indices) = t.topk(2).unwrap();
+        assert_eq!(indices.shape(), &[2, 2]);
+        assert_eq!(indices.blob(), &[1, 3, 0, 1]);
+        assert_eq!(values.blob(), &[0.7, 0.7, 3., 2.]);
+        assert!(t.topk(5).is_err());
+    }
+
+    #[test]
+    fn test_in_place_ops() {
+        let mut t = Tensor::raw(&[2, 2], vec![1., 2., 3., 4.]).unwrap();
+        t.add_assign(&Tensor::vector(&[1., -1.])).unwrap();
+        assert_eq!(t.blob(), &[2., 1., 4., 3.]);
+        t.scaled_add(0.5, &Tensor::constant(&[2, 2], 2.)).unwrap();
+        t.scale(2.);
+        assert_eq!(t.blob(), &[6., 4., 10., 8.]);
+        t.get_mut(1)
+            .unwrap()
+            .mul_assign(&Tensor::scalar(0.5))
+            .unwrap();
+        assert_eq!(t.blob(), &[6., 4., 5., 4.]);
+        assert!(t.add_assign(&Tensor::zeros(&[3, 2, 2])).is_err());
+    }
+
+    #[test]
+    fn test_initializers() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let stats = |t: &Tensor<f32>| {
+            let mean = t.mean();
+            let var = t.blob().iter().map(|v| (v - mean).powi(2)).sum::<f32>() / t.size() as f32;
+            (mean, var.sqrt())
+        };
+        let (mean, std) = stats(&Tensor::rand_normal(&mut rng, 1., 2., &[100, 100]));
+        assert!((mean - 1.).abs() < 0.05 && (std - 2.).abs() < 0.05);
+        let (mean, std) = stats(&Tensor::kaiming_normal(&mut rng, 50, &[100, 100]));
+        assert!(mean.abs() < 0.01 && (std - 0.2).abs() < 0.01);
+        let t = Tensor::xavier_uniform(&mut rng, 20, 40, &[20, 40]);
+        assert!(t.blob().iter().all(|v| v.abs() <= 0.317));
+        let t = Tensor::rand_uniform(&mut rng, 2., 3., &[1000]);
+        assert!(t.blob().iter().all(|v| (2. ..3.).contains(v)));
+    }
+
+    #[test]
+    fn test_cat_and_stack() {
+        let a = Tensor::raw(&[2, 2], vec![0, 1, 2, 3]).unwrap();
+        let b = Tensor::raw(&[2, 1], vec![4, 5]).unwrap();
+        let c = Tensor::cat(&[&a, &b], 1).unwrap();
+        assert_eq!(c.shape(), &[2, 3]);
+        assert_eq!(c.blob(), &[0, 1, 4, 2, 3, 5]);
+        assert!(Tensor::cat(&[&a, &b], 0).is_err());
+        let rows = Tensor::cat(&[&a, &a.slice(0, 1..).unwrap()], 0).unwrap();
+        assert_eq!(rows.blob(), &[0, 1, 2, 3, 2, 3]);
+
+        let s = Tensor::stack(&[&a, &a], 0).unwrap();
+        assert_eq!(s.shape(), &[2, 2, 2]);
+        assert_eq!(s.blob(), &[0, 1, 2, 3, 0, 1, 2, 3]);
+        let s = Tensor::stack(&[&a, &a], 2).unwrap();
+        assert_eq!(s.shape(), &[2, 2, 2]);
+        assert_eq!(s.blob(), &[0, 0, 1, 1, 2, 2, 3, 3]);
+        assert!(Tensor::stack(&[&a, &b], 0).is_err());
+    }
+
+    #[test]
+    fn test_reductions() {
+        let t = Tensor::raw(&[2, 3], vec![1., 2., 3., 4., 6., 8.]).unwrap();
+        let s = t.sum_axis(0, false).unwrap();
+        assert_eq!(s.shape(), &[3]);
+        assert_eq!(s.blob(), &[5., 8., 11.]);
+        let m = t.mean_axis(1, true).unwrap();
+        assert_eq!(m.shape(), &[2, 1]);
+        assert_eq!(m.blob(), &[2., 6.]);
+        assert_eq!((&t - &m).unwrap().blob(), &[-1., 0., 1., -2., 0., 2.]);
+        assert_eq!(t.var_axis(1, false).unwrap().blob(), &[2. / 3., 8. / 3.]);
+        assert_eq!(t.max_axis(0, false).unwrap().blob(), &[4., 6., 8.]);
+        assert!(t.sum_axis(2, false).is_err());
+    }
+
+    #[test]
+    fn test_par_chunks() {
+        let t = Tensor::raw(&[2, 2, 2], (0..8).collect()).unwrap();
+        let rows = t
+            .par_chunks(1)
+            .unwrap()
+            .map(|r| r.blob().iter().sum::<usize>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![1, 5, 9, 13]);
+        let second = t.get(1).unwrap();
+        let mats = second.par_chunks(2).unwrap().collect::<Vec<_>>();
+        assert_eq!(mats.len(), 1);
+        assert_eq!(mats[0].blob(), &[4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let a = Tensor::vector(&[1., 2., 3.]);
+        let mut b = a.clone();
+        assert!(b.shares_storage(&a));
+        let c: Tensor<f32> = a.view().into();
+        assert!(c.shares_storage(&a));
+        b.blob_mut()[0] = 5.;
+        assert!(!b.shares_storage(&a));
+        assert_eq!(a.blob(), &[1., 2., 3.]);
+        assert_eq!(b.blob(), &[5., 2., 3.]);
+        assert_eq!(c.into_vec(), vec![1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_shape_errors() {
+        let a = Tensor::<f32>::zeros(&[2, 3]);
+        let b = Tensor::<f32>::zeros(&[4, 2]);
+        assert_eq!(
+            (&a ^ &b).unwrap_err().to_string(),
+            "matmul: expected shape [2, 3], got [4, 2]"
+        );
+        assert_eq!(
+            (&a + &b).unwrap_err().to_string(),
+            "broadcast: expected shape [2, 3], got [4, 2]"
+        );
+        assert_eq!(
+            a.sum_axis(2, false).unwrap_err().to_string(),
+            "reduce: axis 2 is out of range for shape [2, 3]"
+        );
+    }
+}
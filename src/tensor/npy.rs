@@ -0,0 +1,466 @@
+use super::*;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+/// Elements with a fixed little-endian byte representation, as used by NumPy's
+/// `.npy` format
+pub trait NpyElement: TensorElement {
+    /// NumPy's type descriptor
+    const DESCR: &'static str;
+    const SIZE: usize;
+    fn write_le(self, out: &mut Vec<u8>);
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+impl NpyElement for f32 {
+    const DESCR: &'static str = "<f4";
+    const SIZE: usize = 4;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend(self.to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        f32::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl NpyElement for usize {
+    const DESCR: &'static str = "<u8";
+    const SIZE: usize = 8;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.extend((self as u64).to_le_bytes());
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().unwrap()) as usize
+    }
+}
+
+impl NpyElement for bool {
+    const DESCR: &'static str = "|b1";
+    const SIZE: usize = 1;
+    fn write_le(self, out: &mut Vec<u8>) {
+        out.push(self as u8);
+    }
+    fn read_le(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+fn invalid(msg: &str) -> TensorError {
+    TensorError::InvalidFormat(msg.into())
+}
+
+/// Number of values of `shape`, None if it overflows
+fn checked_size(shape: &[usize]) -> Option<usize> {
+    shape
+        .iter()
+        .try_fold(1usize, |size, d| size.checked_mul(*d))
+}
+
+/// Value of `key` in the Python dict literal of a `.npy` header
+fn header_field<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find(',').or_else(|| rest.find('}'))?
+    };
+    Some(rest[..end].trim())
+}
+
+impl<V: NpyElement> Tensor<V> {
+    /// The values as little-endian bytes. Borrowed without any copy when the
+    /// memory layout of the elements already matches. (I.e. on little-endian hosts)
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        if cfg!(target_endian = "little") && std::mem::size_of::<V>() == V::SIZE {
+            // SAFETY: Elements are plain values of `V::SIZE` bytes, without padding
+            Cow::Borrowed(unsafe {
+                std::slice::from_raw_parts(
+                    self.blob.as_ptr() as *const u8,
+                    self.blob.len() * V::SIZE,
+                )
+            })
+        } else {
+            let mut bytes = Vec::with_capacity(self.blob.len() * V::SIZE);
+            for v in self.blob.iter() {
+                v.write_le(&mut bytes);
+            }
+            Cow::Owned(bytes)
+        }
+    }
+
+    /// Builds a tensor out of little-endian bytes, e.g. a memory-mapped weight file
+    pub fn from_bytes(shape: &[usize], bytes: &[u8]) -> Result<Self, TensorError> {
+        if checked_size(shape).and_then(|size| size.checked_mul(V::SIZE)) != Some(bytes.len()) {
+            return Err(TensorError::shape_mismatch(
+                "from_bytes",
+                shape,
+                &[bytes.len() / V::SIZE],
+            ));
+        }
+        Tensor::raw(shape, bytes.chunks_exact(V::SIZE).map(V::read_le).collect())
+    }
+
+    pub fn to_npy(&self) -> Vec<u8> {
+        let shape = match self.shape.len() {
+            0 => "()".to_string(),
+            1 => format!("({},)", self.shape[0]),
+            _ => format!(
+                "({})",
+                self.shape
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            V::DESCR,
+            shape
+        );
+        // Magic, version, header-length and the header itself are aligned to 64 bytes
+        let unpadded = NPY_MAGIC.len() + 4 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+        header.push('\n');
+
+        let mut out =
+            Vec::with_capacity(NPY_MAGIC.len() + 4 + header.len() + self.size() * V::SIZE);
+        out.extend(NPY_MAGIC);
+        out.extend([1, 0]);
+        out.extend((header.len() as u16).to_le_bytes());
+        out.extend(header.as_bytes());
+        out.extend_from_slice(&self.as_bytes());
+        out
+    }
+
+    pub fn from_npy(bytes: &[u8]) -> Result<Self, TensorError> {
+        if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+            return Err(invalid("not an npy file"));
+        }
+        let (header_len, header_start) = match bytes[6] {
+            1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+            2 | 3 if bytes.len() >= 12 => (
+                u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+                12,
+            ),
+            _ => return Err(invalid("unsupported npy version")),
+        };
+        let data_start = header_start + header_len;
+        let header = std::str::from_utf8(
+            bytes
+                .get(header_start..data_start)
+                .ok_or_else(|| invalid("truncated header"))?,
+        )
+        .map_err(|_| invalid("header is not utf-8"))?;
+
+        let descr = header_field(header, "descr").ok_or_else(|| invalid("missing descr"))?;
+        if descr.trim_matches('\'') != V::DESCR {
+            return Err(invalid(&format!(
+                "expected dtype {}, found {}",
+                V::DESCR,
+                descr
+            )));
+        }
+        if header_field(header, "fortran_order") != Some("False") {
+            return Err(invalid("fortran-ordered arrays are not supported"));
+        }
+        let shape = header_field(header, "shape")
+            .ok_or_else(|| invalid("missing shape"))?
+            .trim_matches(|c| c == '(' || c == ')')
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<usize>().map_err(|_| invalid("invalid shape")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let data = checked_size(&shape)
+            .and_then(|size| size.checked_mul(V::SIZE))
+            .and_then(|len| data_start.checked_add(len))
+            .and_then(|data_end| bytes.get(data_start..data_end))
+            .ok_or_else(|| invalid("truncated data"))?;
+        Tensor::from_bytes(&shape, data)
+    }
+
+    pub fn write_npy<W: Write>(&self, mut w: W) -> Result<(), TensorError> {
+        w.write_all(&self.to_npy())?;
+        Ok(())
+    }
+
+    pub fn read_npy<R: Read>(mut r: R) -> Result<Self, TensorError> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        Self::from_npy(&bytes)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Writes the tensors as an (Uncompressed) `.npz` archive, which is a zip file of
+/// `.npy` files, loadable with `numpy.load`
+pub fn write_npz<V: NpyElement, W: Write>(
+    mut w: W,
+    tensors: &[(&str, &Tensor<V>)],
+) -> Result<(), TensorError> {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for (name, tensor) in tensors {
+        let name = format!("{}.npy", name);
+        let data = tensor.to_npy();
+        let crc = crc32(&data);
+        let offset = out.len() as u32;
+
+        out.extend(0x04034b50u32.to_le_bytes());
+        out.extend(20u16.to_le_bytes()); // Version needed
+        out.extend(0u16.to_le_bytes()); // Flags
+        out.extend(0u16.to_le_bytes()); // Stored (No compression)
+        out.extend(0u32.to_le_bytes()); // Modification time/date
+        out.extend(crc.to_le_bytes());
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend((name.len() as u16).to_le_bytes());
+        out.extend(0u16.to_le_bytes()); // Extra field length
+        out.extend(name.as_bytes());
+        out.extend(&data);
+
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(20u16.to_le_bytes()); // Version made by
+        central.extend(20u16.to_le_bytes()); // Version needed
+        central.extend(0u16.to_le_bytes());
+        central.extend(0u16.to_le_bytes());
+        central.extend(0u32.to_le_bytes());
+        central.extend(crc.to_le_bytes());
+        central.extend((data.len() as u32).to_le_bytes());
+        central.extend((data.len() as u32).to_le_bytes());
+        central.extend((name.len() as u16).to_le_bytes());
+        central.extend([0u8; 12]); // Extra, comment, disk, internal and external attributes
+        central.extend(offset.to_le_bytes());
+        central.extend(name.as_bytes());
+    }
+    let central_offset = out.len() as u32;
+    out.extend(&central);
+    out.extend(0x06054b50u32.to_le_bytes());
+    out.extend([0u8; 4]); // Disk numbers
+    out.extend((tensors.len() as u16).to_le_bytes());
+    out.extend((tensors.len() as u16).to_le_bytes());
+    out.extend((central.len() as u32).to_le_bytes());
+    out.extend(central_offset.to_le_bytes());
+    out.extend(0u16.to_le_bytes()); // Comment length
+    w.write_all(&out)?;
+    Ok(())
+}
+
+/// Value of a zip field which doesn't fit it, the actual value being in the zip64
+/// extra field (Or record)
+const ZIP64_MARKER: u32 = 0xffffffff;
+
+/// Reads the tensors of an `.npz` archive, in the order they are stored. Only
+/// uncompressed archives (`numpy.savez`, not `numpy.savez_compressed`) are supported.
+/// The entries are found through the central directory, which has their sizes even
+/// when their local headers don't (Zip64 entries, as numpy writes, or ones followed
+/// by a data descriptor).
+pub fn read_npz<V: NpyElement, R: Read>(mut r: R) -> Result<Vec<(String, Tensor<V>)>, TensorError> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    let field = |i: usize, n: usize| -> Result<u64, TensorError> {
+        let field = i
+            .checked_add(n)
+            .and_then(|end| bytes.get(i..end))
+            .ok_or_else(|| invalid("truncated zip"))?;
+        Ok(field.iter().rev().fold(0u64, |v, b| (v << 8) | *b as u64))
+    };
+    let u16_at = |i: usize| field(i, 2).map(|v| v as usize);
+    let u32_at = |i: usize| field(i, 4);
+    let u64_at = |i: usize| field(i, 8);
+    // Offsets in the archive, pointing inside of it
+    let offset_at = |i: usize, n: usize| -> Result<usize, TensorError> {
+        usize::try_from(field(i, n)?)
+            .ok()
+            .filter(|offset| *offset < bytes.len())
+            .ok_or_else(|| invalid("truncated zip"))
+    };
+
+    // The end of central directory record, followed by a comment of up to 64KiB
+    let eocd = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .take(1 << 16)
+        .find(|i| u32_at(*i).ok() == Some(0x06054b50))
+        .ok_or_else(|| invalid("not a zip archive"))?;
+    let (num_entries, central) =
+        if u16_at(eocd + 10)? == 0xffff || u32_at(eocd + 16)? == ZIP64_MARKER as u64 {
+            // Zip64 end of central directory locator, then record
+            let locator = eocd
+                .checked_sub(20)
+                .filter(|l| u32_at(*l).ok() == Some(0x07064b50))
+                .ok_or_else(|| invalid("missing zip64 end of central directory"))?;
+            let record = offset_at(locator + 8, 8)?;
+            if u32_at(record)? != 0x06064b50 {
+                return Err(invalid("missing zip64 end of central directory"));
+            }
+            let num_entries = usize::try_from(u64_at(record + 32)?)
+                .map_err(|_| invalid("invalid zip64 end of central directory"))?;
+            (num_entries, offset_at(record + 48, 8)?)
+        } else {
+            (u16_at(eocd + 10)?, offset_at(eocd + 16, 4)?)
+        };
+
+    let mut result = Vec::new();
+    let mut pos = central;
+    for _ in 0..num_entries {
+        if u32_at(pos)? != 0x02014b50 {
+            return Err(invalid("invalid central directory"));
+        }
+        let method = u16_at(pos + 10)?;
+        let crc = u32_at(pos + 16)? as u32;
+        let mut size = u32_at(pos + 20)?;
+        let uncompressed = u32_at(pos + 24)?;
+        let name_len = u16_at(pos + 28)?;
+        let extra_len = u16_at(pos + 30)?;
+        let comment_len = u16_at(pos + 32)?;
+        let mut offset = u32_at(pos + 42)?;
+        let name_start = pos + 46;
+        if method != 0 {
+            return Err(invalid("compressed npz archives are not supported"));
+        }
+        let name = std::str::from_utf8(
+            bytes
+                .get(name_start..name_start + name_len)
+                .ok_or_else(|| invalid("truncated zip"))?,
+        )
+        .map_err(|_| invalid("file name is not utf-8"))?;
+        // The zip64 extra field has the values marked as not fitting, in this order
+        let mut extra = name_start + name_len;
+        let extra_end = extra + extra_len;
+        while extra + 4 <= extra_end {
+            let (id, len) = (u16_at(extra)?, u16_at(extra + 2)?);
+            if id == 0x0001 {
+                let mut value = extra + 4;
+                if uncompressed == ZIP64_MARKER as u64 {
+                    value += 8;
+                }
+                if size == ZIP64_MARKER as u64 {
+                    size = u64_at(value)?;
+                    value += 8;
+                }
+                if offset == ZIP64_MARKER as u64 {
+                    offset = u64_at(value)?;
+                }
+            }
+            extra += 4 + len;
+        }
+        pos = extra_end + comment_len;
+
+        let local = usize::try_from(offset)
+            .ok()
+            .filter(|local| *local < bytes.len())
+            .ok_or_else(|| invalid("truncated zip"))?;
+        if u32_at(local)? != 0x04034b50 {
+            return Err(invalid("invalid local header"));
+        }
+        let data_start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+        let data = usize::try_from(size)
+            .ok()
+            .and_then(|size| data_start.checked_add(size))
+            .and_then(|data_end| bytes.get(data_start..data_end))
+            .ok_or_else(|| invalid("truncated zip"))?;
+        if crc32(data) != crc {
+            return Err(invalid("checksum mismatch"));
+        }
+        result.push((
+            name.strip_suffix(".npy").unwrap_or(name).to_string(),
+            Tensor::from_npy(data)?,
+        ));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_npy_roundtrip() {
+        let t = Tensor::raw(&[2, 3], vec![1., -2., 3.5, 0., 1e-3, 7.]).unwrap();
+        let bytes = t.to_npy();
+        assert_eq!(
+            bytes[..10].len() + u16::from_le_bytes([bytes[8], bytes[9]]) as usize,
+            128
+        );
+        let header = std::str::from_utf8(&bytes[10..128]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+        let loaded = Tensor::<f32>::from_npy(&bytes).unwrap();
+        assert_eq!(loaded.shape(), t.shape());
+        assert_eq!(loaded.blob(), t.blob());
+        assert!(Tensor::<usize>::from_npy(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let t = Tensor::raw(&[2], vec![1.5f32, -2.]).unwrap();
+        assert_eq!(&t.as_bytes()[..4], &1.5f32.to_le_bytes());
+        let loaded = Tensor::<f32>::from_bytes(&[2], &t.as_bytes()).unwrap();
+        assert_eq!(loaded.into_vec(), vec![1.5, -2.]);
+        assert!(Tensor::<f32>::from_bytes(&[3], &t.as_bytes()).is_err());
+        let t = Tensor::vector(&[1usize, 1 << 40]);
+        assert_eq!(t.as_bytes().len(), 16);
+        assert_eq!(
+            Tensor::<usize>::from_bytes(&[2], &t.as_bytes())
+                .unwrap()
+                .blob(),
+            t.blob()
+        );
+    }
+
+    #[test]
+    fn test_read_savez() {
+        // Written like `numpy.savez` does (See scripts/npz_fixture.py), in zip64
+        let bytes = include_bytes!("../../fixtures/npz/savez.npz");
+        let loaded = read_npz::<f32, _>(&bytes[..]).unwrap();
+        let names = loaded.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(loaded[0].1.shape(), &[2, 3]);
+        assert_eq!(loaded[0].1.blob(), &[1., -2., 3.5, 0., 1e-3, 7.]);
+        assert_eq!(loaded[1].1.blob(), &[0.25, 0.5, 0.75, 1.]);
+        assert_eq!(loaded[2].1.shape(), &[] as &[usize]);
+        assert_eq!(loaded[2].1.blob(), &[42.]);
+        assert!(read_npz::<f32, _>(&bytes[..bytes.len() - 1]).is_err());
+
+        // A shape whose size overflows
+        let header =
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (4611686018427387904, 8), }";
+        let mut huge = NPY_MAGIC.to_vec();
+        huge.extend([1, 0]);
+        huge.extend((header.len() as u16).to_le_bytes());
+        huge.extend(header.as_bytes());
+        assert!(Tensor::<f32>::from_npy(&huge).is_err());
+        assert!(Tensor::<f32>::from_bytes(&[usize::MAX, 2], &[]).is_err());
+    }
+
+    #[test]
+    fn test_npz_roundtrip() {
+        let a = Tensor::vector(&[1usize, 2, 3]);
+        let b = Tensor::scalar(5usize);
+        let mut bytes = Vec::new();
+        write_npz(&mut bytes, &[("a", &a), ("b", &b)]).unwrap();
+        let loaded = read_npz::<usize, _>(&bytes[..]).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].0, "a");
+        assert_eq!(loaded[0].1.blob(), a.blob());
+        assert_eq!(loaded[1].1.shape(), &[] as &[usize]);
+    }
+}
@@ -1,7 +1,72 @@
 use super::*;
 
+/// Shape of the result of an elementwise operation between tensors of shapes `a`
+/// and `b`, following NumPy's broadcasting rules. (E.g. [B, T, C] and [C] give
+/// [B, T, C], while [B, 1, T] and [B, H, T] give [B, H, T])
+pub fn broadcast_shape(a: &[usize], b: &[usize]) -> Result<Vec<usize>, TensorError> {
+    let dim = a.len().max(b.len());
+    (0..dim)
+        .map(|i| {
+            let x = if i + a.len() >= dim {
+                a[i + a.len() - dim]
+            } else {
+                1
+            };
+            let y = if i + b.len() >= dim {
+                b[i + b.len() - dim]
+            } else {
+                1
+            };
+            match (x, y) {
+                _ if x == y => Ok(x),
+                (1, _) => Ok(y),
+                (_, 1) => Ok(x),
+                _ => Err(TensorError::shape_mismatch("broadcast", a, b)),
+            }
+        })
+        .collect()
+}
+
+/// Strides for reading a tensor of `shape` as if it had the broadcast shape `out`
+fn broadcast_strides(shape: &[usize], out: &[usize]) -> Vec<usize> {
+    let mut strides = vec![0; out.len()];
+    let mut stride = 1;
+    for i in (0..shape.len()).rev() {
+        strides[i + out.len() - shape.len()] = if shape[i] == 1 { 0 } else { stride };
+        stride *= shape[i];
+    }
+    strides
+}
+
+/// Walks over the elements of the broadcast shape `out` in row-major order, giving
+/// the positions of the corresponding elements of tensors of shapes `a` and `b`
+pub(super) fn broadcast_for_each<F: FnMut(usize, usize)>(
+    out: &[usize],
+    a: &[usize],
+    b: &[usize],
+    mut f: F,
+) {
+    let a_strides = broadcast_strides(a, out);
+    let b_strides = broadcast_strides(b, out);
+    let mut index = vec![0; out.len()];
+    let (mut pa, mut pb) = (0, 0);
+    for _ in 0..out.iter().product::<usize>() {
+        f(pa, pb);
+        for d in (0..out.len()).rev() {
+            index[d] += 1;
+            pa += a_strides[d];
+            pb += b_strides[d];
+            if index[d] < out[d] {
+                break;
+            }
+            pa -= a_strides[d] * index[d];
+            pb -= b_strides[d] * index[d];
+            index[d] = 0;
+        }
+    }
+}
+
 pub fn binary<
-    'a,
     V: TensorElement,
     W: TensorElement,
     T1: TensorOps<V>,
@@ -12,23 +77,33 @@ pub fn binary<
     b: &T2,
     f: F,
 ) -> Result<Tensor<W>, TensorError> {
-    let (a, b, rev) = if a.dim() > b.dim() {
-        (a.view(), b.view(), false)
+    let shape = broadcast_shape(a.shape(), b.shape())?;
+    let (a_blob, b_blob) = (a.blob(), b.blob());
+    let blob = if a.shape() == b.shape() {
+        a_blob
+            .iter()
+            .zip(b_blob.iter())
+            .map(|(a, b)| f(*a, *b))
+            .collect()
+    } else if b.size() > 0 && a.shape().ends_with(b.shape()) {
+        // Fast path for the common case of e.g. adding a bias to every row
+        a_blob
+            .chunks(b.size())
+            .flat_map(|a| a.iter().zip(b_blob.iter()).map(|(a, b)| f(*a, *b)))
+            .collect()
+    } else if a.size() > 0 && b.shape().ends_with(a.shape()) {
+        b_blob
+            .chunks(a.size())
+            .flat_map(|b| a_blob.iter().zip(b.iter()).map(|(a, b)| f(*a, *b)))
+            .collect()
     } else {
-        (b.view(), a.view(), true)
+        let mut blob = Vec::with_capacity(shape.iter().product());
+        broadcast_for_each(&shape, a.shape(), b.shape(), |pa, pb| {
+            blob.push(f(a_blob[pa], b_blob[pb]))
+        });
+        blob
     };
-    a.map(b.dim(), |a| {
-        assert_eq!(a.shape(), b.shape());
-        let (a, b) = if rev { (&b, &a) } else { (&a, &b) };
-        Tensor::raw(
-            a.shape(),
-            a.blob()
-                .iter()
-                .zip(b.blob().iter())
-                .map(|(a, b)| f(*a, *b))
-                .collect(),
-        )
-    })
+    Tensor::raw(&shape, blob)
 }
 
 impl<'a, V: TensorElement + std::ops::Add<Output = V>> Add for &TensorView<'a, V> {
@@ -56,44 +131,7 @@ impl<
 {
     type Output = Result<Tensor<V>, TensorError>;
     fn bitxor(self, other: &TensorView<V>) -> Self::Output {
-        let (a, b, rev) = if self.dim() > other.dim() {
-            (self.view(), other.view(), false)
-        } else {
-            (other.view(), self.view(), true)
-        };
-        a.map(b.dim(), |a| {
-            assert_eq!(a.shape()[..a.dim() - 2], b.shape()[..b.dim() - 2]);
-            let (a, b) = if rev { (&b, &a) } else { (&a, &b) };
-            let data = a
-                .keep_right(2)?
-                .inners()
-                .iter()
-                .zip(b.keep_right(2)?.inners().iter())
-                .map(|(a, b)| {
-                    let m = a.shape()[0];
-                    let n = a.shape()[1];
-                    let p = b.shape()[1];
-                    let mut result = vec![
-                            <<V as std::ops::Mul>::Output as std::ops::Add>::Output::zero();
-                            m * p
-                        ];
-                    let a_blob = a.blob();
-                    let b_blob = b.blob();
-                    for i in 0..m {
-                        for k in 0..n {
-                            for j in 0..p {
-                                result[i * p + j] += a_blob[i * n + k] * b_blob[k * p + j];
-                            }
-                        }
-                    }
-                    result
-                })
-                .flatten()
-                .collect();
-            let mut final_shape = a.shape().to_vec();
-            final_shape[a.dim() - 1] = b.shape()[b.dim() - 1];
-            Tensor::raw(&final_shape, data)
-        })
+        self.strided().matmul(&other.strided())
     }
 }
 impl<'a> Not for &TensorView<'a, bool> {
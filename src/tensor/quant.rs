@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+/// Precision the weights of a model are exported with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DType {
+    #[default]
+    F32,
+    F16,
+    /// Symmetric 8-bit quantization, with a scale per block of values
+    Int8,
+}
+
+impl FromStr for DType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(DType::F32),
+            "f16" => Ok(DType::F16),
+            "int8" => Ok(DType::Int8),
+            _ => Err(format!("unknown dtype '{}' (Expected f32, f16 or int8)", s)),
+        }
+    }
+}
+
+/// Quantizes each block of `block` consecutive values to `i8`, with the scale that
+/// maps the largest magnitude of the block to 127. Returns the values and the scales.
+pub fn quantize_i8(values: &[f32], block: usize) -> (Vec<i8>, Vec<f32>) {
+    let mut quantized = Vec::with_capacity(values.len());
+    let mut scales = Vec::with_capacity(values.len() / block.max(1));
+    for chunk in values.chunks(block.max(1)) {
+        let max = chunk.iter().fold(0f32, |m, v| m.max(v.abs()));
+        let scale = max / 127.;
+        let inv = if scale > 0. { 1. / scale } else { 0. };
+        quantized.extend(chunk.iter().map(|v| (v * inv).round() as i8));
+        scales.push(scale);
+    }
+    (quantized, scales)
+}
+
+pub fn dequantize_i8(values: &[i8], scales: &[f32], block: usize) -> Vec<f32> {
+    values
+        .chunks(block.max(1))
+        .zip(scales)
+        .flat_map(|(chunk, scale)| chunk.iter().map(move |v| *v as f32 * scale))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantize_i8() {
+        let values = [0.5, -1.27, 0.01, 0., 0., 0., 3.];
+        let (quantized, scales) = quantize_i8(&values, 3);
+        assert_eq!(quantized, [50, -127, 1, 0, 0, 0, 127]);
+        assert_eq!(scales.len(), 3);
+        let restored = dequantize_i8(&quantized, &scales, 3);
+        for (v, r) in values.iter().zip(restored) {
+            assert!((v - r).abs() < 0.01, "{} {}", v, r);
+        }
+        assert_eq!("int8".parse::<DType>(), Ok(DType::Int8));
+        assert!("i4".parse::<DType>().is_err());
+    }
+}
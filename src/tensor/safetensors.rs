@@ -0,0 +1,204 @@
+use super::*;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+fn invalid(msg: &str) -> TensorError {
+    TensorError::InvalidFormat(msg.into())
+}
+
+/// Name of the tensor holding the row scales of the int8 tensor `name`
+fn scale_name(name: &str) -> String {
+    format!("{}.scale", name)
+}
+
+/// Writes named tensors in the [safetensors](https://github.com/huggingface/safetensors)
+/// format, converted to `dtype`, along with string metadata. Int8 tensors are stored as
+/// `I8`, with the scales of their rows (Last axis) in an extra `F32` tensor named
+/// `<name>.scale`.
+pub fn write_safetensors<W: Write>(
+    mut w: W,
+    tensors: &[(&str, &Tensor<f32>)],
+    dtype: DType,
+    metadata: &[(&str, &str)],
+) -> Result<(), TensorError> {
+    let mut sorted = tensors.to_vec();
+    sorted.sort_by_key(|(name, _)| *name);
+    let mut entries: Vec<(String, &str, Vec<usize>, Vec<u8>)> = Vec::new();
+    for (name, t) in sorted {
+        match dtype {
+            DType::F32 => entries.push((
+                name.into(),
+                "F32",
+                t.shape().to_vec(),
+                t.as_bytes().into_owned(),
+            )),
+            DType::F16 => entries.push((
+                name.into(),
+                "F16",
+                t.shape().to_vec(),
+                t.to_f16().as_bytes().into_owned(),
+            )),
+            DType::Int8 => {
+                let row = t.shape().last().copied().unwrap_or(1);
+                let (values, scales) = quantize_i8(t.blob(), row);
+                let values = values.into_iter().map(|v| v as u8).collect();
+                entries.push((name.into(), "I8", t.shape().to_vec(), values));
+                let scales = Tensor::vector(&scales);
+                entries.push((
+                    scale_name(name),
+                    "F32",
+                    scales.shape().to_vec(),
+                    scales.as_bytes().into_owned(),
+                ));
+            }
+        }
+    }
+
+    let mut header = Map::new();
+    if !metadata.is_empty() {
+        let metadata = metadata
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::from(*v)))
+            .collect::<Map<_, _>>();
+        header.insert("__metadata__".into(), metadata.into());
+    }
+    let mut offset = 0;
+    for (name, dtype, shape, data) in entries.iter() {
+        header.insert(
+            name.clone(),
+            json!({ "dtype": dtype, "shape": shape, "data_offsets": [offset, offset + data.len()] }),
+        );
+        offset += data.len();
+    }
+    let mut header = Value::Object(header).to_string().into_bytes();
+    // The data is 8-byte aligned
+    while !header.len().is_multiple_of(8) {
+        header.push(b' ');
+    }
+    w.write_all(&(header.len() as u64).to_le_bytes())?;
+    w.write_all(&header)?;
+    for (_, _, _, data) in entries.iter() {
+        w.write_all(data)?;
+    }
+    Ok(())
+}
+
+/// Reads the tensors written by `write_safetensors` (Or by other tools, as long as they
+/// are `F32`, `F16` or `I8` with scales) as `f32`, ordered by name. Also returns the
+/// metadata.
+#[allow(clippy::type_complexity)]
+pub fn read_safetensors<R: Read>(
+    mut r: R,
+) -> Result<(Vec<(String, Tensor<f32>)>, Vec<(String, String)>), TensorError> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    let mut cur = Cursor {
+        bytes: &bytes,
+        pos: 0,
+    };
+    let header_len = cur.u64()? as usize;
+    let header: Value = serde_json::from_slice(cur.take(header_len)?)
+        .map_err(|e| invalid(&format!("invalid header: {}", e)))?;
+    let data = &bytes[cur.pos..];
+    let Value::Object(header) = header else {
+        return Err(invalid("invalid header"));
+    };
+
+    let mut metadata = Vec::new();
+    let mut raw = BTreeMap::new();
+    for (name, entry) in header.iter() {
+        if name == "__metadata__" {
+            for (k, v) in entry
+                .as_object()
+                .ok_or_else(|| invalid("invalid metadata"))?
+            {
+                let v = v.as_str().ok_or_else(|| invalid("invalid metadata"))?;
+                metadata.push((k.clone(), v.to_string()));
+            }
+            continue;
+        }
+        let dtype = entry["dtype"]
+            .as_str()
+            .ok_or_else(|| invalid("missing dtype"))?;
+        let shape = entry["shape"]
+            .as_array()
+            .and_then(|s| {
+                s.iter()
+                    .map(|d| d.as_u64().map(|d| d as usize))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| invalid("invalid shape"))?;
+        let offsets = entry["data_offsets"]
+            .as_array()
+            .and_then(|o| {
+                o.iter()
+                    .map(|o| o.as_u64().map(|o| o as usize))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .unwrap_or_else(Vec::new);
+        let tensor_data = match offsets[..] {
+            [begin, end] if begin <= end => data.get(begin..end),
+            _ => None,
+        }
+        .ok_or_else(|| invalid("invalid data offsets"))?;
+        raw.insert(name.clone(), (dtype, shape, tensor_data));
+    }
+
+    let mut tensors = Vec::new();
+    for (name, (dtype, shape, data)) in raw.iter() {
+        let tensor = match *dtype {
+            "F32" => Tensor::<f32>::from_bytes(shape, data)?,
+            "F16" => Tensor::<F16>::from_bytes(shape, data)?.to_f32(),
+            "I8" => {
+                let Some(("F32", _, scales)) = raw.get(&scale_name(name)) else {
+                    return Err(invalid(&format!("missing the scales of {}", name)));
+                };
+                let scales = Tensor::<f32>::from_bytes(&[scales.len() / 4], scales)?;
+                let row = shape.last().copied().unwrap_or(1);
+                let values = data.iter().map(|v| *v as i8).collect::<Vec<_>>();
+                if scales.size() * row != shape.iter().product::<usize>() {
+                    return Err(invalid(&format!("wrong number of scales for {}", name)));
+                }
+                Tensor::raw(shape, dequantize_i8(&values, scales.blob(), row))?
+            }
+            dtype => return Err(invalid(&format!("unsupported dtype {}", dtype))),
+        };
+        tensors.push((name.clone(), tensor));
+    }
+    // Scales are only there for the int8 tensors
+    tensors.retain(|(name, _)| {
+        !name
+            .strip_suffix(".scale")
+            .is_some_and(|base| matches!(raw.get(base), Some(("I8", _, _))))
+    });
+    Ok((tensors, metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safetensors() {
+        let a = Tensor::raw(&[2, 3], vec![1., -2., 0.5, 0.25, 100., -0.125]).unwrap();
+        let b = Tensor::vector(&[3.]);
+        for dtype in [DType::F32, DType::F16, DType::Int8] {
+            let mut bytes = Vec::new();
+            write_safetensors(&mut bytes, &[("b", &b), ("a", &a)], dtype, &[("k", "v")]).unwrap();
+            let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+            assert_eq!((8 + header_len) % 8, 0);
+            let (tensors, metadata) = read_safetensors(&bytes[..]).unwrap();
+            assert_eq!(metadata, vec![("k".to_string(), "v".to_string())]);
+            assert_eq!(tensors.len(), 2);
+            assert_eq!(tensors[0].0, "a");
+            assert_eq!(tensors[0].1.shape(), &[2, 3]);
+            let tolerance = if dtype == DType::Int8 { 0.5 } else { 0. };
+            for (x, y) in tensors[0].1.blob().iter().zip(a.blob()) {
+                assert!((x - y).abs() <= tolerance, "{:?}: {} {}", dtype, x, y);
+            }
+            assert_eq!(tensors[1].1.blob(), &[3.]);
+        }
+        assert!(read_safetensors(&b"\x10\0\0\0\0\0\0\0{}"[..]).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use super::*;
+
+/// A view over the data of a tensor, where each dimension has its own stride. It
+/// makes transposing, narrowing and stepping through a tensor possible without
+/// copying any data. (Data is only copied when it's turned back into a `Tensor`)
+#[derive(Debug, Clone)]
+pub struct StridedView<'a, V: TensorElement> {
+    pub(super) mirror: &'a Tensor<V>,
+    pub(super) offset: usize,
+    pub(super) shape: Vec<usize>,
+    pub(super) strides: Vec<usize>,
+}
+
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+impl<'a, V: TensorElement> StridedView<'a, V> {
+    pub fn new<T: TensorOps<V>>(t: &'a T) -> Self {
+        Self {
+            mirror: t.tensor(),
+            offset: t.offset(),
+            shape: t.shape().to_vec(),
+            strides: contiguous_strides(t.shape()),
+        }
+    }
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+    pub fn dim(&self) -> usize {
+        self.shape.len()
+    }
+    pub fn size(&self) -> usize {
+        self.shape.iter().product()
+    }
+    pub fn is_contiguous(&self) -> bool {
+        self.strides == contiguous_strides(&self.shape)
+    }
+
+    /// Swaps two dimensions
+    pub fn transpose(&self, a: usize, b: usize) -> Result<Self, TensorError> {
+        if a >= self.dim() || b >= self.dim() {
+            return Err(TensorError::invalid_axis(
+                "transpose",
+                a.max(b),
+                &self.shape,
+            ));
+        }
+        let mut view = self.clone();
+        view.shape.swap(a, b);
+        view.strides.swap(a, b);
+        Ok(view)
+    }
+
+    /// Reorders the dimensions, the `i`th dimension of the result being the
+    /// `dims[i]`th dimension of the view
+    pub fn permute(&self, dims: &[usize]) -> Result<Self, TensorError> {
+        let mut sorted = dims.to_vec();
+        sorted.sort();
+        if sorted != (0..self.dim()).collect::<Vec<_>>() {
+            return Err(TensorError::shape_mismatch("permute", &self.shape, dims));
+        }
+        Ok(Self {
+            mirror: self.mirror,
+            offset: self.offset,
+            shape: dims.iter().map(|d| self.shape[*d]).collect(),
+            strides: dims.iter().map(|d| self.strides[*d]).collect(),
+        })
+    }
+
+    /// Keeps `len` elements of dimension `dim`, starting from `start`
+    pub fn narrow(&self, dim: usize, start: usize, len: usize) -> Result<Self, TensorError> {
+        if dim >= self.dim() {
+            return Err(TensorError::invalid_axis("narrow", dim, &self.shape));
+        }
+        if start + len > self.shape[dim] {
+            return Err(TensorError::InvalidIndex);
+        }
+        let mut view = self.clone();
+        view.offset += start * self.strides[dim];
+        view.shape[dim] = len;
+        Ok(view)
+    }
+
+    /// Keeps every `step`th element of dimension `dim`
+    pub fn step(&self, dim: usize, step: usize) -> Result<Self, TensorError> {
+        if dim >= self.dim() || step == 0 {
+            return Err(TensorError::invalid_axis("step", dim, &self.shape));
+        }
+        let mut view = self.clone();
+        view.shape[dim] = self.shape[dim].div_ceil(step);
+        view.strides[dim] *= step;
+        Ok(view)
+    }
+
+    /// Picks the `ind`th element of dimension `dim`, dropping that dimension
+    pub fn select(&self, dim: usize, ind: usize) -> Result<Self, TensorError> {
+        if dim >= self.dim() {
+            return Err(TensorError::invalid_axis("select", dim, &self.shape));
+        }
+        if ind >= self.shape[dim] {
+            return Err(TensorError::InvalidIndex);
+        }
+        let mut view = self.clone();
+        view.offset += ind * self.strides[dim];
+        view.shape.remove(dim);
+        view.strides.remove(dim);
+        Ok(view)
+    }
+
+    pub fn at(&self, index: &[usize]) -> Result<V, TensorError> {
+        if index.len() != self.dim() {
+            return Err(TensorError::shape_mismatch("at", &self.shape, index));
+        }
+        let mut pos = self.offset;
+        for ((i, s), st) in index.iter().zip(self.shape.iter()).zip(self.strides.iter()) {
+            if i >= s {
+                return Err(TensorError::InvalidIndex);
+            }
+            pos += i * st;
+        }
+        Ok(self.mirror.blob[pos])
+    }
+
+    /// Iterates over the elements in row-major order of the view
+    pub fn iter(&self) -> impl Iterator<Item = V> + '_ {
+        let mut index = vec![0; self.dim()];
+        let mut pos = self.offset;
+        let mut left = self.size();
+        std::iter::from_fn(move || {
+            if left == 0 {
+                return None;
+            }
+            left -= 1;
+            let v = self.mirror.blob[pos];
+            // Increment the multi-dimensional index, like an odometer
+            for d in (0..index.len()).rev() {
+                index[d] += 1;
+                pos += self.strides[d];
+                if index[d] < self.shape[d] {
+                    break;
+                }
+                pos -= self.strides[d] * index[d];
+                index[d] = 0;
+            }
+            Some(v)
+        })
+    }
+}
+
+impl<V: TensorElement + Mul<Output = V> + Add<Output = V> + AddAssign> StridedView<'_, V> {
+    /// Offset of the `index`th matrix of a batch the view is broadcast to (Its batch
+    /// dimensions being the last ones of the batch)
+    fn matrix_offset(&self, mut index: usize) -> usize {
+        let mut offset = self.offset;
+        for d in (0..self.dim() - 2).rev() {
+            offset += index % self.shape[d] * self.strides[d];
+            index /= self.shape[d];
+        }
+        offset
+    }
+
+    /// Product of the matrices of the last two dimensions, the batch dimensions of
+    /// the view with fewer of them being broadcast (Like `^`). The strides are
+    /// followed as they are, so transposed views get multiplied without being copied.
+    pub fn matmul(&self, other: &StridedView<V>) -> Result<Tensor<V>, TensorError> {
+        if self.dim() < 2 || other.dim() < 2 {
+            let shape = if self.dim() < 2 {
+                self.shape()
+            } else {
+                other.shape()
+            };
+            return Err(TensorError::unexpected_shape("matmul", shape));
+        }
+        let (a_batch, b_batch) = (
+            &self.shape[..self.dim() - 2],
+            &other.shape[..other.dim() - 2],
+        );
+        let batch = if a_batch.len() >= b_batch.len() {
+            a_batch
+        } else {
+            b_batch
+        };
+        let [m, k] = [self.shape[self.dim() - 2], self.shape[self.dim() - 1]];
+        let [k2, n] = [other.shape[other.dim() - 2], other.shape[other.dim() - 1]];
+        if k != k2 || !batch.ends_with(a_batch) || !batch.ends_with(b_batch) {
+            return Err(TensorError::shape_mismatch(
+                "matmul",
+                &self.shape,
+                &other.shape,
+            ));
+        }
+        let [a_row, a_col] = [self.strides[self.dim() - 2], self.strides[self.dim() - 1]];
+        let [b_row, b_col] = [
+            other.strides[other.dim() - 2],
+            other.strides[other.dim() - 1],
+        ];
+        let (a_blob, b_blob) = (self.mirror.blob(), other.mirror.blob());
+        let mut shape = batch.to_vec();
+        shape.extend([m, n]);
+        let mut blob = vec![V::zero(); shape.iter().product()];
+        blob.par_chunks_mut((m * n).max(1))
+            .enumerate()
+            .for_each(|(index, out)| {
+                let (a_off, b_off) = (self.matrix_offset(index), other.matrix_offset(index));
+                for i in 0..m {
+                    for p in 0..k {
+                        let a = a_blob[a_off + i * a_row + p * a_col];
+                        let b_off = b_off + p * b_row;
+                        for j in 0..n {
+                            out[i * n + j] += a * b_blob[b_off + j * b_col];
+                        }
+                    }
+                }
+            });
+        Tensor::raw(&shape, blob)
+    }
+}
+
+impl<V: TensorElement> From<StridedView<'_, V>> for Tensor<V> {
+    fn from(view: StridedView<'_, V>) -> Tensor<V> {
+        let blob = if view.is_contiguous() {
+            view.mirror.blob()[view.offset..view.offset + view.size()].to_vec()
+        } else {
+            let mut blob = Vec::with_capacity(view.size());
+            blob.extend(view.iter());
+            blob
+        };
+        Tensor {
+            blob: blob.into(),
+            shape: view.shape,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strided_ops() {
+        let t = Tensor::raw(&[2, 3], vec![0, 1, 2, 3, 4, 5]).unwrap();
+        let view = t.strided();
+        let tr: Tensor<usize> = view.transpose(0, 1).unwrap().into();
+        assert_eq!(tr.shape(), &[3, 2]);
+        assert_eq!(tr.blob(), &[0, 3, 1, 4, 2, 5]);
+        assert_eq!(tr.blob(), t.transpose().unwrap().blob());
+
+        let narrowed = view.narrow(1, 1, 2).unwrap();
+        assert!(!narrowed.is_contiguous());
+        assert_eq!(narrowed.iter().collect::<Vec<_>>(), vec![1, 2, 4, 5]);
+
+        let stepped = view.step(1, 2).unwrap();
+        assert_eq!(stepped.iter().collect::<Vec<_>>(), vec![0, 2, 3, 5]);
+
+        let col = view.select(1, 2).unwrap();
+        assert_eq!(col.iter().collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(view.at(&[1, 0]).unwrap(), 3);
+
+        let tr = view.transpose(0, 1).unwrap();
+        let product = tr.matmul(&view).unwrap();
+        assert_eq!(product.shape(), &[3, 3]);
+        assert_eq!(
+            product.blob(),
+            (&t.transpose().unwrap() ^ &t).unwrap().blob()
+        );
+        assert!(view.matmul(&view).is_err());
+    }
+
+    #[test]
+    fn test_slice_and_index_select() {
+        let t = Tensor::raw(&[2, 3], vec![0, 1, 2, 3, 4, 5]).unwrap();
+        let s = t.slice(1, 1..).unwrap();
+        assert_eq!(s.shape(), &[2, 2]);
+        assert_eq!(s.blob(), &[1, 2, 4, 5]);
+        assert_eq!(t.slice(0, ..=0).unwrap().blob(), &[0, 1, 2]);
+        assert!(t.slice(1, 2..4).is_err());
+
+        let rows = t.index_select(0, &[1, 1, 0]).unwrap();
+        assert_eq!(rows.shape(), &[3, 3]);
+        assert_eq!(rows.blob(), &[3, 4, 5, 3, 4, 5, 0, 1, 2]);
+        let cols = t.index_select(1, &[2, 0]).unwrap();
+        assert_eq!(cols.blob(), &[2, 0, 5, 3]);
+        assert!(t.index_select(1, &[3]).is_err());
+    }
+}
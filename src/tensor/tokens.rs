@@ -0,0 +1,68 @@
+use super::*;
+
+impl Tensor<usize> {
+    /// Shifts every row (I.e. along the last dimension) `n` positions to the left,
+    /// filling the end with `fill`. (E.g. next-token targets out of the inputs)
+    pub fn shift(&self, n: usize, fill: usize) -> Result<Self, TensorError> {
+        let row_len = *self
+            .shape()
+            .last()
+            .ok_or_else(|| TensorError::unexpected_shape("shift", self.shape()))?;
+        let mut blob = Vec::with_capacity(self.size());
+        for row in self.blob().chunks(row_len.max(1)) {
+            let n = n.min(row.len());
+            blob.extend_from_slice(&row[n..]);
+            blob.resize(blob.len() + n, fill);
+        }
+        Tensor::raw(self.shape(), blob)
+    }
+
+    /// Expands every token into a vector of `classes` elements, all zero but the
+    /// one at the index of the token
+    pub fn one_hot(&self, classes: usize) -> Result<Tensor<f32>, TensorError> {
+        let mut blob = vec![0.; self.size() * classes];
+        for (i, t) in self.blob().iter().enumerate() {
+            if *t >= classes {
+                return Err(TensorError::InvalidIndex);
+            }
+            blob[i * classes + t] = 1.;
+        }
+        let mut shape = self.shape().to_vec();
+        shape.push(classes);
+        Tensor::raw(&shape, blob)
+    }
+
+    /// Looks up the rows of `table` at the indices in this tensor, the result having
+    /// the shape of this tensor followed by the shape of a row. (E.g. embeddings)
+    pub fn gather<W: TensorElement, T: TensorOps<W>>(
+        &self,
+        table: &T,
+    ) -> Result<Tensor<W>, TensorError> {
+        let rows = table.index_select(0, self.blob())?;
+        let mut shape = self.shape().to_vec();
+        shape.extend(&table.shape()[1..]);
+        Tensor::raw(&shape, rows.into_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_ops() {
+        let t = Tensor::raw(&[2, 3], vec![1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(t.shift(1, 0).unwrap().blob(), &[2, 3, 0, 5, 6, 0]);
+        assert_eq!(t.shift(5, 9).unwrap().blob(), &[9, 9, 9, 9, 9, 9]);
+
+        let one_hot = Tensor::vector(&[0, 2]).one_hot(3).unwrap();
+        assert_eq!(one_hot.shape(), &[2, 3]);
+        assert_eq!(one_hot.blob(), &[1., 0., 0., 0., 0., 1.]);
+        assert!(Tensor::vector(&[3]).one_hot(3).is_err());
+
+        let table = Tensor::raw(&[7, 2], (0..14).map(|v| v as f32).collect()).unwrap();
+        let embedded = t.gather(&table).unwrap();
+        assert_eq!(embedded.shape(), &[2, 3, 2]);
+        assert_eq!(&embedded.blob()[..4], &[2., 3., 4., 5.]);
+    }
+}
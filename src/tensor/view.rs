@@ -1,13 +1,13 @@
 use super::*;
+use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct TensorView<'a, V: TensorElement> {
     pub(super) mirror: &'a Tensor<V>,
     pub(super) offset: usize,
     pub(super) shape: Vec<usize>,
 }
 
-#[derive(Debug)]
 pub struct TensorMutView<'a, V: TensorElement> {
     pub(super) mirror: &'a mut Tensor<V>,
     pub(super) offset: usize,
@@ -16,8 +16,14 @@ pub struct TensorMutView<'a, V: TensorElement> {
 
 impl<V: TensorElement> From<TensorView<'_, V>> for Tensor<V> {
     fn from(view: TensorView<'_, V>) -> Tensor<V> {
+        // A view of a whole tensor shares its storage
+        let blob = if view.offset == 0 && view.size() == view.mirror.blob.len() {
+            view.mirror.blob.clone()
+        } else {
+            view.blob().to_vec().into()
+        };
         Tensor {
-            blob: view.blob().to_vec(),
+            blob,
             shape: view.shape().to_vec(),
         }
     }
@@ -25,23 +31,26 @@ impl<V: TensorElement> From<TensorView<'_, V>> for Tensor<V> {
 
 impl<V: TensorElement> From<TensorMutView<'_, V>> for Tensor<V> {
     fn from(view: TensorMutView<'_, V>) -> Tensor<V> {
-        view.into()
+        Tensor {
+            blob: view.blob().to_vec().into(),
+            shape: view.shape().to_vec(),
+        }
     }
 }
 
 impl<V: TensorElement> TensorMutOps<V> for TensorMutView<'_, V> {
     fn tensor_mut(&mut self) -> &mut Tensor<V> {
-        &mut self.mirror
+        self.mirror
     }
     fn blob_mut(&mut self) -> &mut [V] {
         let sz = self.size();
-        &mut self.mirror.blob[self.offset..self.offset + sz]
+        &mut Arc::make_mut(&mut self.mirror.blob)[self.offset..self.offset + sz]
     }
 }
 
 impl<V: TensorElement> TensorOps<V> for TensorMutView<'_, V> {
     fn tensor(&self) -> &Tensor<V> {
-        &self.mirror
+        self.mirror
     }
     fn offset(&self) -> usize {
         self.offset
@@ -57,7 +66,7 @@ impl<V: TensorElement> TensorOps<V> for TensorMutView<'_, V> {
 
 impl<V: TensorElement> TensorOps<V> for TensorView<'_, V> {
     fn tensor(&self) -> &Tensor<V> {
-        &self.mirror
+        self.mirror
     }
     fn offset(&self) -> usize {
         self.offset
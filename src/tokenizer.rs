@@ -1,11 +1,160 @@
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TokenizerError {
+    #[error("{0:?} is not in the vocabulary")]
+    UnknownChar(char),
+    #[error("token {0} is not in the vocabulary")]
+    UnknownToken(usize),
+}
 
 pub trait Tokenizer {
     fn vocab_size(&self) -> usize;
-    fn tokenize(&self, string: &str) -> Vec<usize>;
-    fn untokenize(&self, tokens: &[usize]) -> String;
+    fn tokenize(&self, string: &str) -> Result<Vec<usize>, TokenizerError>;
+    fn untokenize(&self, tokens: &[usize]) -> Result<String, TokenizerError>;
+
+    /// Characters of the vocabulary, for dropping the others from a text before
+    /// tokenizing it
+    fn known_chars(&self) -> HashSet<char> {
+        (0..self.vocab_size())
+            .filter_map(|tkn| self.untokenize(&[tkn]).ok())
+            .flat_map(|s| s.chars().collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+/// A prompt backed up by its last token, which may be the start of a longer one. (E.g.
+/// a prompt ending with `http`, where `https` is a token: the model hardly ever saw
+/// `http` followed by `s` as two tokens, and would rather not complete it so)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenHealing {
+    /// The prompt without its last token
+    pub prompt: Vec<usize>,
+    /// The text of the removed token
+    pub prefix: String,
+    /// The tokens whose text starts with `prefix`, one of which the first generated
+    /// token should be
+    pub allowed: Vec<usize>,
+}
+
+/// Token healing of `prompt`: `None` when its last token is the only one of the
+/// vocabulary starting with its text, as it's then complete. (Always the case with
+/// a tokenizer of single characters)
+pub fn heal_prompt<T: Tokenizer + ?Sized>(
+    tokenizer: &T,
+    prompt: &[usize],
+) -> Result<Option<TokenHealing>, TokenizerError> {
+    // Something has to be left to generate from
+    if prompt.len() < 2 {
+        return Ok(None);
+    }
+    let prefix = tokenizer.untokenize(&prompt[prompt.len() - 1..])?;
+    let allowed = (0..tokenizer.vocab_size())
+        .filter(|tkn| {
+            tokenizer
+                .untokenize(&[*tkn])
+                .is_ok_and(|s| s.starts_with(&prefix))
+        })
+        .collect::<Vec<_>>();
+    if allowed.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(TokenHealing {
+        prompt: prompt[..prompt.len() - 1].to_vec(),
+        prefix,
+        allowed,
+    }))
+}
+
+/// Which tokens are made of `chars` only, for constraining generation to them (E.g.
+/// digits; see `gpt::TokenConstraint`)
+pub fn chars_mask<T: Tokenizer + ?Sized>(tokenizer: &T, chars: &str) -> Vec<bool> {
+    (0..tokenizer.vocab_size())
+        .map(|tkn| {
+            tokenizer
+                .untokenize(&[tkn])
+                .is_ok_and(|s| !s.is_empty() && s.chars().all(|c| chars.contains(c)))
+        })
+        .collect()
+}
+
+/// Decodes generated tokens into text as they come, for streaming it. Text is only
+/// handed out once it's complete: a token may hold part of a character (E.g. with
+/// tokenizers of bytes, whose undecodable tails show as `U+FFFD`), or decode differently
+/// after its predecessor than alone (E.g. the leading space of a word), so the pending
+/// tokens are decoded after the last one already handed out, and held back while they
+/// end with half a character.
+pub struct StreamDecoder<'a, T: Tokenizer + ?Sized> {
+    tokenizer: &'a T,
+    tokens: Vec<usize>,
+    /// Tokens before it are only kept as context for the next ones
+    prefix: usize,
+    /// Tokens from it on haven't been handed out yet
+    read: usize,
+}
+
+/// Most tokens held back waiting for the end of a character (Which takes at most 4
+/// bytes), past which they are handed out anyway
+const MAX_PENDING: usize = 4;
+
+impl<'a, T: Tokenizer + ?Sized> StreamDecoder<'a, T> {
+    pub fn new(tokenizer: &'a T) -> Self {
+        Self {
+            tokenizer,
+            tokens: Vec::new(),
+            prefix: 0,
+            read: 0,
+        }
+    }
+
+    /// Text completed by `token`, possibly empty. Unknown tokens are left out.
+    pub fn push(&mut self, token: usize) -> Result<String, TokenizerError> {
+        self.tokens.push(token);
+        match self.pending() {
+            Ok(text)
+                if text.ends_with(char::REPLACEMENT_CHARACTER)
+                    && self.tokens.len() - self.read < MAX_PENDING =>
+            {
+                Ok(String::new())
+            }
+            Ok(text) => {
+                self.prefix = self.read;
+                self.read = self.tokens.len();
+                Ok(text)
+            }
+            Err(e) => {
+                self.tokens.pop();
+                Err(e)
+            }
+        }
+    }
+
+    /// The text held back, complete or not, after which the decoder starts over
+    pub fn finish(&mut self) -> String {
+        let text = self.pending().unwrap_or_default();
+        self.tokens.clear();
+        self.prefix = 0;
+        self.read = 0;
+        text
+    }
+
+    /// Text of the tokens not handed out yet
+    fn pending(&self) -> Result<String, TokenizerError> {
+        let context = self
+            .tokenizer
+            .untokenize(&self.tokens[self.prefix..self.read])?;
+        let text = self.tokenizer.untokenize(&self.tokens[self.prefix..])?;
+        match text.strip_prefix(&context) {
+            Some(rest) => Ok(rest.to_string()),
+            // The context decodes differently before the new tokens
+            None => self.tokenizer.untokenize(&self.tokens[self.read..]),
+        }
+    }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SimpleTokenizer {
     vocab_size: usize,
     ch_to_int: HashMap<char, usize>,
@@ -36,42 +185,236 @@ impl SimpleTokenizer {
             ch_to_int,
         }
     }
+
+    /// Adds the characters of `dataset` missing from the vocabulary, after the existing
+    /// ones, so that the tokens of the latter stay the same. Returns the added ones.
+    pub fn extend(&mut self, dataset: &str) -> Vec<char> {
+        let mut added = dataset
+            .chars()
+            .filter(|ch| !self.ch_to_int.contains_key(ch))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        added.sort();
+        for ch in added.iter() {
+            self.ch_to_int.insert(*ch, self.vocab_size);
+            self.int_to_ch.insert(self.vocab_size, *ch);
+            self.vocab_size += 1;
+        }
+        added
+    }
 }
 
 impl Tokenizer for SimpleTokenizer {
     fn vocab_size(&self) -> usize {
         self.vocab_size
     }
-    fn tokenize(&self, string: &str) -> Vec<usize> {
+    fn tokenize(&self, string: &str) -> Result<Vec<usize>, TokenizerError> {
         string
             .chars()
-            .map(|ch| self.ch_to_int.get(&ch).unwrap().clone())
+            .map(|ch| {
+                self.ch_to_int
+                    .get(&ch)
+                    .copied()
+                    .ok_or(TokenizerError::UnknownChar(ch))
+            })
             .collect()
     }
-    fn untokenize(&self, tokens: &[usize]) -> String {
+    fn untokenize(&self, tokens: &[usize]) -> Result<String, TokenizerError> {
         tokens
             .iter()
-            .map(|tkn| self.int_to_ch.get(tkn).unwrap().clone())
+            .map(|tkn| {
+                self.int_to_ch
+                    .get(tkn)
+                    .copied()
+                    .ok_or(TokenizerError::UnknownToken(*tkn))
+            })
             .collect()
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AsciiTokenizer;
 
 impl Tokenizer for AsciiTokenizer {
     fn vocab_size(&self) -> usize {
         128
     }
-    fn tokenize(&self, string: &str) -> Vec<usize> {
+    fn tokenize(&self, string: &str) -> Result<Vec<usize>, TokenizerError> {
         string
             .chars()
-            .map(|ch| u8::try_from(ch).unwrap() as usize)
+            .map(|ch| match ch.is_ascii() {
+                true => Ok(ch as usize),
+                false => Err(TokenizerError::UnknownChar(ch)),
+            })
             .collect()
     }
-    fn untokenize(&self, tokens: &[usize]) -> String {
+    fn untokenize(&self, tokens: &[usize]) -> Result<String, TokenizerError> {
         tokens
             .iter()
-            .map(|tkn| char::from(TryInto::<u8>::try_into(*tkn).unwrap()))
+            .map(|tkn| match u8::try_from(*tkn) {
+                Ok(b) if b.is_ascii() => Ok(char::from(b)),
+                _ => Err(TokenizerError::UnknownToken(*tkn)),
+            })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown() {
+        let tokenizer = SimpleTokenizer::new("ab");
+        assert_eq!(tokenizer.tokenize("ba").unwrap(), [1, 0]);
+        assert_eq!(
+            tokenizer.tokenize("abc"),
+            Err(TokenizerError::UnknownChar('c'))
+        );
+        assert_eq!(
+            tokenizer.untokenize(&[0, 2]),
+            Err(TokenizerError::UnknownToken(2))
+        );
+        assert_eq!(
+            AsciiTokenizer.tokenize("é"),
+            Err(TokenizerError::UnknownChar('é'))
+        );
+        assert_eq!(
+            AsciiTokenizer.untokenize(&[200]),
+            Err(TokenizerError::UnknownToken(200))
+        );
+        assert_eq!(AsciiTokenizer.untokenize(&[72, 105]).unwrap(), "Hi");
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut tokenizer = SimpleTokenizer::new("ba");
+        assert_eq!(tokenizer.extend("cab!"), ['!', 'c']);
+        assert_eq!(tokenizer.vocab_size(), 4);
+        assert_eq!(tokenizer.tokenize("abc!").unwrap(), [0, 1, 3, 2]);
+        assert_eq!(tokenizer.untokenize(&[3, 2]).unwrap(), "c!");
+        assert!(tokenizer.extend("abc").is_empty());
+    }
+
+    /// Longest-match tokenizer over a fixed vocabulary, like a BPE one
+    struct Words(&'static [&'static str]);
+
+    impl Tokenizer for Words {
+        fn vocab_size(&self) -> usize {
+            self.0.len()
+        }
+        fn tokenize(&self, mut string: &str) -> Result<Vec<usize>, TokenizerError> {
+            let mut tokens = Vec::new();
+            while let Some(ch) = string.chars().next() {
+                let (tkn, word) = self
+                    .0
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, w)| string.starts_with(*w))
+                    .max_by_key(|(_, w)| w.len())
+                    .ok_or(TokenizerError::UnknownChar(ch))?;
+                tokens.push(tkn);
+                string = &string[word.len()..];
+            }
+            Ok(tokens)
+        }
+        fn untokenize(&self, tokens: &[usize]) -> Result<String, TokenizerError> {
+            tokens
+                .iter()
+                .map(|t| {
+                    self.0
+                        .get(*t)
+                        .copied()
+                        .ok_or(TokenizerError::UnknownToken(*t))
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_heal_prompt() {
+        let words = Words(&["a", "b", " ", "ab", "abc", "ba"]);
+        let prompt = words.tokenize("b ab").unwrap();
+        assert_eq!(prompt, [1, 2, 3]);
+        assert_eq!(
+            heal_prompt(&words, &prompt).unwrap(),
+            Some(TokenHealing {
+                prompt: vec![1, 2],
+                prefix: "ab".into(),
+                allowed: vec![3, 4],
+            })
+        );
+        // Nothing longer starts with "ba", nor with single characters
+        assert_eq!(
+            heal_prompt(&words, &words.tokenize("a ba").unwrap()),
+            Ok(None)
+        );
+        assert_eq!(heal_prompt(&words, &[3]), Ok(None));
+        let simple = SimpleTokenizer::new("abc");
+        assert_eq!(heal_prompt(&simple, &[0, 1, 2]), Ok(None));
+    }
+
+    #[test]
+    fn test_chars_mask() {
+        let words = Words(&["a", "b", " ", "ab", "abc", "ba"]);
+        assert_eq!(
+            chars_mask(&words, "ab"),
+            [true, true, false, true, false, true]
+        );
+        assert_eq!(
+            chars_mask(&AsciiTokenizer, "0123456789")[48..58],
+            [true; 10]
+        );
+        assert_eq!(
+            chars_mask(&AsciiTokenizer, "0123456789")
+                .iter()
+                .filter(|a| **a)
+                .count(),
+            10
+        );
+    }
+
+    /// Tokenizer of bytes, decoding lossily
+    struct Bytes;
+
+    impl Tokenizer for Bytes {
+        fn vocab_size(&self) -> usize {
+            256
+        }
+        fn tokenize(&self, string: &str) -> Result<Vec<usize>, TokenizerError> {
+            Ok(string.bytes().map(usize::from).collect())
+        }
+        fn untokenize(&self, tokens: &[usize]) -> Result<String, TokenizerError> {
+            let bytes = tokens
+                .iter()
+                .map(|t| u8::try_from(*t).map_err(|_| TokenizerError::UnknownToken(*t)))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        }
+    }
+
+    #[test]
+    fn test_stream_decoder() {
+        let mut decoder = StreamDecoder::new(&Bytes);
+        let mut chunks = Bytes
+            .tokenize("h€é!")
+            .unwrap()
+            .into_iter()
+            .map(|t| decoder.push(t).unwrap())
+            .collect::<Vec<_>>();
+        chunks.push(decoder.finish());
+        assert_eq!(chunks, ["h", "", "", "€", "", "é", "!", ""]);
+
+        // Unknown tokens are left out, and unfinished characters flushed at the end
+        assert!(decoder.push(0xe2).unwrap().is_empty());
+        assert!(decoder.push(1000).is_err());
+        assert_eq!(decoder.finish(), "\u{fffd}");
+        assert_eq!(decoder.push(b'a' as usize).unwrap(), "a");
+
+        let words = Words(&["a", "b", " ", "ab"]);
+        let mut decoder = StreamDecoder::new(&words);
+        assert_eq!(decoder.push(3).unwrap(), "ab");
+        assert_eq!(decoder.push(2).unwrap(), " ");
+    }
+}
@@ -0,0 +1,475 @@
+//! Training in a single call, for programs embedding femtoGPT: the dataset, the
+//! tokenizer, the model and the schedule are all set up from a config, the way
+//! `femto-gpt train` does it.
+
+use crate::checkpoint::write_atomic;
+use crate::config::{Config, ConfigError, Scheduler, TokenizerKind};
+use crate::dataset::{inverse_frequencies, Dataset, Strided};
+use crate::eval::strided_perplexity;
+use crate::gpt::{
+    seeded_rng, NoiseScale, Provenance, Sampling, SpikeGuard, StopCriteria, StopReason,
+    TrainCallback, TrainContext, GPT,
+};
+use crate::graph::GraphError;
+use crate::histogram::{HistogramCsv, DEFAULT_BINS};
+use crate::optimizer::{AdamW, Optimizer};
+use crate::run::{dataset_hash, tokenizer_hash};
+use crate::tokenizer::{AsciiTokenizer, SimpleTokenizer, Tokenizer, TokenizerError};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// A model trained by `train`, along with its tokenizer and config
+pub struct TrainedModel {
+    pub config: Config,
+    pub gpt: GPT<AdamW>,
+    pub tokenizer: Box<dyn Tokenizer + Send + Sync>,
+    pub stop_reason: StopReason,
+    /// The tokenizer, as `Config::load_model` reads it back (Empty for ASCII)
+    tokenizer_bytes: Vec<u8>,
+}
+
+impl TrainedModel {
+    /// Continues `prompt`. Characters out of the vocabulary are left out of the prompt.
+    pub fn generate(
+        &self,
+        prompt: &str,
+        max_tokens: usize,
+        sampling: &Sampling,
+    ) -> Result<String, GraphError> {
+        let known = self.tokenizer.known_chars();
+        let prompt = prompt
+            .chars()
+            .filter(|ch| known.contains(ch))
+            .collect::<String>();
+        let prompt = self.tokenizer.tokenize(&prompt)?;
+        let prompt = &prompt[prompt.len().saturating_sub(self.gpt.num_tokens())..];
+        let tokens = self.gpt.generate(
+            &mut rand::thread_rng(),
+            prompt,
+            max_tokens,
+            sampling,
+            |_| true,
+        )?;
+        Ok(self.tokenizer.untokenize(&tokens)?)
+    }
+
+    /// Saves the training state and the tokenizer, and the config next to the state
+    /// (With a `.toml` extension), like `femto-gpt train` does.
+    pub fn save<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        state: P,
+        tokenizer: Q,
+    ) -> Result<(), ConfigError> {
+        let state = state.as_ref();
+        let state_bytes = bincode::serialize(&self.gpt.get_training_state()?)?;
+        write_atomic(state, &state_bytes)?;
+        write_atomic(tokenizer.as_ref(), &self.tokenizer_bytes)?;
+        self.config.save(state.with_extension("toml"))
+    }
+
+    /// Saves everything, optimizer state included, as a single `.femto` file (See
+    /// `bundle`), which `GPT::load_bundle` and `femto-gpt infer --state` read back
+    pub fn save_bundle<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let f = std::fs::File::create(path)?;
+        self.gpt.save_bundle(
+            std::io::BufWriter::new(f),
+            &self.config,
+            &self.tokenizer_bytes,
+            true,
+        )
+    }
+}
+
+/// Logs the functions of `gpt` whose results stray the most from f64 on a window of
+/// `dataset` (See `GPT::check_numerics`), warning about those off by more than 0.1%
+pub fn check_numerics<O: Optimizer>(gpt: &GPT<O>, dataset: &[usize]) -> Result<(), GraphError> {
+    let window = dataset.sample(&mut rand::thread_rng(), gpt.num_tokens());
+    for d in gpt.check_numerics(&window)?.iter().take(5) {
+        if d.max() > 1e-3 {
+            warn!(
+                op = d.op,
+                tensor = d.id,
+                forward = d.forward,
+                backward = d.backward,
+                "Diverges from f64"
+            );
+        } else {
+            info!(
+                op = d.op,
+                tensor = d.id,
+                forward = d.forward,
+                backward = d.backward,
+                "Divergence from f64"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Windows `GPT::overfit_sanity_check` is run on
+const OVERFIT_WINDOWS: usize = 2;
+
+/// Checks that `gpt` memorizes the first windows of `dataset` at the learning-rate `lr`
+/// (See `GPT::overfit_sanity_check`), failing otherwise
+pub fn overfit_check<O: Optimizer>(
+    gpt: &mut GPT<O>,
+    dataset: &[usize],
+    lr: f32,
+) -> Result<(), GraphError> {
+    let batch = dataset
+        .chunks(gpt.num_tokens() + 1)
+        .take(OVERFIT_WINDOWS)
+        .filter(|w| w.len() > 1)
+        .map(|w| w.to_vec())
+        .collect::<Vec<_>>();
+    let losses = gpt.overfit_sanity_check(&batch, lr)?;
+    info!(
+        steps = losses.len(),
+        loss = losses[losses.len() - 1],
+        "Overfitted a single batch"
+    );
+    Ok(())
+}
+
+/// The documents as a single stream of tokens, with the tokens of `separator` in
+/// between
+pub fn tokenize_documents<T: Tokenizer + ?Sized>(
+    tokenizer: &T,
+    documents: &[(PathBuf, String)],
+    separator: &str,
+) -> Result<Vec<usize>, TokenizerError> {
+    let separator = tokenizer.tokenize(separator)?;
+    let mut dataset = Vec::new();
+    for (i, (path, text)) in documents.iter().enumerate() {
+        if i > 0 {
+            dataset.extend_from_slice(&separator);
+        }
+        let tokens = tokenizer.tokenize(text)?;
+        info!(file = %path.display(), tokens = tokens.len(), "Loaded");
+        dataset.extend(tokens);
+    }
+    Ok(dataset)
+}
+
+struct Schedule {
+    scheduler: Scheduler,
+    histograms: Option<HistogramCsv>,
+    validation: Option<Vec<usize>>,
+    stride: Option<usize>,
+}
+
+impl TrainCallback<AdamW> for Schedule {
+    fn learning_rate(&mut self, step: usize) -> f32 {
+        self.scheduler.learning_rate(step)
+    }
+    fn on_eval(&mut self, ctx: &TrainContext<AdamW>) -> Result<(), GraphError> {
+        info!(step = ctx.stats.step, loss = ctx.stats.loss, "Step");
+        if let Some(tokens) = &self.validation {
+            let stride = self.stride.unwrap_or(ctx.gpt.num_tokens());
+            let loss = strided_perplexity(ctx.gpt, tokens, stride)?.ln();
+            info!(step = ctx.stats.step, val_loss = loss, "Validation");
+            self.scheduler.observe(loss);
+        }
+        if let Some(csv) = &mut self.histograms {
+            csv.write(ctx.stats.step, &ctx.gpt.histograms(DEFAULT_BINS)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Trains `gpt` (New or resumed) on `dataset`, the tokens of the documents of
+/// `config`, as the rest of `config` says: the options of the training are set up,
+/// then the model is trained until the stop criteria are met, `callback` being told
+/// about the steps. Both `train` and `femto-gpt train` go through it.
+pub fn train_gpt<C: TrainCallback<AdamW>>(
+    gpt: &mut GPT<AdamW>,
+    config: &Config,
+    dataset: &[usize],
+    callback: &mut C,
+) -> Result<StopReason, ConfigError> {
+    // Samples of a batch are spread over as many threads as CPUs, unless configured
+    if let Some(num_threads) = config.training.num_threads {
+        gpt.set_num_threads(num_threads)?;
+    }
+    if config.training.spike_guard {
+        gpt.set_spike_guard(Some(SpikeGuard::default()));
+    }
+    gpt.set_weight_decay(config.optimizer.weight_decay());
+    gpt.set_layer_decay(config.optimizer.layer_decay as f32);
+    gpt.set_sparse_embeddings(config.optimizer.sparse_embeddings);
+    if config.training.noise_scale {
+        gpt.set_noise_scale(Some(NoiseScale::default()));
+    }
+    if config.training.frequency_weighted_loss {
+        gpt.set_token_weights(Some(inverse_frequencies(dataset, gpt.vocab_size())))?;
+    }
+    if let Some(sparsity) = config.training.prune {
+        let stats = gpt.prune(sparsity)?;
+        info!(
+            tensors = stats.tensors.len(),
+            "Pruned {:.1}% of the weights",
+            stats.sparsity() * 100.
+        );
+    }
+    if !config.training.full_precision.is_empty() {
+        let count = gpt.set_full_precision(&config.training.full_precision);
+        info!(count, "Keeping tensors in f32");
+    }
+    gpt.set_precision(config.training.precision);
+    if config.training.fuse_ops {
+        let count = gpt.fuse()?;
+        info!(count, "Fusing the functions");
+    }
+    if config.training.tensor_parallel > 1 {
+        let count = gpt.set_tensor_parallel(config.training.tensor_parallel);
+        info!(
+            count,
+            shards = config.training.tensor_parallel,
+            "Sharding the products"
+        );
+    }
+    if !config.training.fake_quant.is_empty() {
+        let count =
+            gpt.set_fake_quant(&config.training.fake_quant, config.training.fake_quant_bits);
+        info!(
+            count,
+            bits = config.training.fake_quant_bits,
+            "Fake-quantizing"
+        );
+    }
+    if config.training.check_numerics {
+        check_numerics(gpt, dataset)?;
+    }
+    if config.training.overfit_check {
+        overfit_check(gpt, dataset, config.scheduler.base_lr as f32)?;
+    }
+    let mut soft_targets = None;
+    if let Some(distillation) = &config.training.distillation {
+        let (loaded, soft) = distillation.load(gpt.vocab_size(), gpt.num_tokens())?;
+        info!(teacher = %distillation.teacher.display(), "Distilling");
+        gpt.set_distillation(Some(loaded));
+        soft_targets = soft;
+    }
+    let strided = config
+        .training
+        .stride
+        .and_then(|stride| Strided::new(dataset, stride));
+    if let Some(dp) = &config.training.dp_sgd {
+        let windows = match (&soft_targets, &strided) {
+            (Some(soft_targets), _) => soft_targets.num_windows(),
+            (None, Some(strided)) => strided.num_windows(gpt.num_tokens()),
+            (None, None) => dataset.num_windows(gpt.num_tokens()),
+        };
+        let mut dp_sgd = dp.build();
+        // The steps before a restart spent privacy too
+        let sample_rate = config.training.batch_size as f64 / windows.max(1) as f64;
+        dp_sgd.account(sample_rate, gpt.optimizer().step_num());
+        info!(
+            clip_norm = dp.clip_norm,
+            noise_multiplier = dp.noise_multiplier,
+            sample_rate,
+            "Training with DP-SGD"
+        );
+        gpt.set_dp_sgd(Some(dp_sgd));
+    }
+
+    let stop = StopCriteria {
+        max_steps: Some(config.training.steps),
+        max_epochs: config.training.epochs,
+        // Checked by `Config::validate`
+        max_duration: config
+            .training
+            .max_duration
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok()),
+        target_loss: config.training.target_loss,
+    };
+    let batch_size = config.training.batch_size;
+    // On the windows of the soft targets, when distilling from them
+    let stop_reason = match (&soft_targets, &strided) {
+        (Some(soft_targets), _) => {
+            gpt.train(soft_targets.as_ref(), &stop, batch_size, None, callback)?
+        }
+        (None, Some(strided)) => gpt.train(strided, &stop, batch_size, None, callback)?,
+        (None, None) => gpt.train(dataset, &stop, batch_size, None, callback)?,
+    };
+    Ok(stop_reason)
+}
+
+/// Trains a new model as described by `config`, on its dataset
+pub fn train(config: &Config) -> Result<TrainedModel, ConfigError> {
+    let documents = config.load_documents()?;
+    let separator = &config.training.document_separator;
+    let (tokenizer, tokenizer_bytes): (Box<dyn Tokenizer + Send + Sync>, _) =
+        match config.tokenizer.kind {
+            TokenizerKind::Simple => {
+                let mut text = separator.clone();
+                text.extend(documents.iter().map(|(_, d)| d.as_str()));
+                let tokenizer = SimpleTokenizer::new(&text);
+                let bytes = bincode::serialize(&tokenizer)?;
+                (Box::new(tokenizer), bytes)
+            }
+            TokenizerKind::Ascii => (Box::new(AsciiTokenizer), Vec::new()),
+        };
+    let dataset = tokenize_documents(tokenizer.as_ref(), &documents, separator)?;
+
+    let mut gpt = config.model.build(
+        &mut seeded_rng(config.training.seed.unwrap_or_else(rand::random)),
+        tokenizer.vocab_size(),
+        config.optimizer.build(),
+    )?;
+    gpt.set_provenance(Provenance {
+        dataset_hash: Some(dataset_hash(&documents)),
+        tokenizer_hash: Some(tokenizer_hash(tokenizer.as_ref())),
+    });
+    let validation = match &config.training.validation {
+        Some(path) => {
+            // Characters out of the vocabulary are left out
+            let known = tokenizer.known_chars();
+            let text = std::fs::read_to_string(path)?
+                .chars()
+                .filter(|ch| known.contains(ch))
+                .collect::<String>();
+            Some(tokenizer.tokenize(&text)?)
+        }
+        None => None,
+    };
+    let rotation = &config.training.log_rotation;
+    let mut schedule = Schedule {
+        scheduler: Scheduler::new(config.scheduler.clone()),
+        validation,
+        stride: config.training.stride,
+        histograms: config
+            .training
+            .histograms
+            .as_ref()
+            .map(|path| HistogramCsv::new(path, rotation))
+            .transpose()?,
+    };
+    let stop_reason = train_gpt(&mut gpt, config, &dataset, &mut schedule)?;
+    Ok(TrainedModel {
+        config: config.clone(),
+        gpt,
+        tokenizer,
+        stop_reason,
+        tokenizer_bytes,
+    })
+}
+
+/// Trains a new model as described by the config file at `config_path` (TOML). The
+/// paths of the config are relative to the working directory.
+pub fn train_from_files<P: AsRef<Path>>(config_path: P) -> Result<TrainedModel, ConfigError> {
+    train(&Config::load(config_path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelConfig;
+    use crate::gpt::LossScaler;
+    use crate::graph::Precision;
+
+    #[test]
+    fn test_train_from_files() {
+        let dir = std::env::temp_dir().join("femto_gpt_test_train");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("dataset.txt"),
+            "ROMEO: Hi.\nJULIET: Hey.\n".repeat(4),
+        )
+        .unwrap();
+        let config = format!(
+            "[model]\nnum_tokens = 4\nembedding_degree = 8\nnum_layers = 1\nnum_heads = 2\n\
+             [training]\ndataset = {:?}\nsteps = 3\nbatch_size = 2\n",
+            dir.join("dataset.txt")
+        );
+        std::fs::write(dir.join("config.toml"), config).unwrap();
+
+        let model = train_from_files(dir.join("config.toml")).unwrap();
+        assert_eq!(model.stop_reason, StopReason::Steps);
+        assert_eq!(model.gpt.optimizer().step_num(), 3);
+        // 'x' is not in the vocabulary
+        let text = model.generate("xROMEO:", 5, &Sampling::default()).unwrap();
+        assert_eq!(text.chars().count(), 5);
+
+        model
+            .save(dir.join("state.dat"), dir.join("tokenizer.dat"))
+            .unwrap();
+        let config = Config::load(dir.join("state.toml")).unwrap();
+        let (gpt, tokenizer) = config
+            .load_model(
+                &std::fs::read(dir.join("tokenizer.dat")).unwrap(),
+                &std::fs::read(dir.join("state.dat")).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(gpt.num_params(), model.gpt.num_params());
+        assert_eq!(tokenizer.vocab_size(), model.tokenizer.vocab_size());
+        // The state of a model of another size is rejected
+        let mut wider = ModelConfig {
+            embedding_degree: 16,
+            ..config.model.clone()
+        }
+        .build(
+            &mut rand::thread_rng(),
+            tokenizer.vocab_size(),
+            AdamW::new(),
+        )
+        .unwrap();
+        let state = model.gpt.get_training_state().unwrap();
+        assert!(wider.set_training_state(state, true).is_err());
+
+        model.save_bundle(dir.join("model.femto")).unwrap();
+        let (gpt, _, config) =
+            GPT::load_bundle(std::fs::File::open(dir.join("model.femto")).unwrap()).unwrap();
+        assert_eq!(config, model.config);
+        assert_eq!(gpt.optimizer().step_num(), 3);
+
+        assert!(train_from_files(dir.join("missing.toml")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_train_mixed_precision() {
+        let dir = std::env::temp_dir().join("femto_gpt_test_train_mixed");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("dataset.txt"), "abcabcabd\n".repeat(8)).unwrap();
+        let config = format!(
+            "[model]\nnum_tokens = 4\nembedding_degree = 8\nnum_layers = 1\nnum_heads = 2\n\
+             [training]\ndataset = {:?}\nsteps = 4\nbatch_size = 2\nprecision = \"mixed\"\n\
+             full_precision = [\"*norm*\"]\n",
+            dir.join("dataset.txt")
+        );
+        std::fs::write(dir.join("config.toml"), config).unwrap();
+
+        let model = train_from_files(dir.join("config.toml")).unwrap();
+        assert_eq!(model.gpt.precision(), Precision::Mixed);
+        let scaler = model.gpt.loss_scaler().unwrap();
+        assert!(scaler.scale >= 1. && scaler.scale <= 65536.);
+        let text = model.generate("abc", 5, &Sampling::default()).unwrap();
+        assert_eq!(text.chars().count(), 5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_loss_scaler() {
+        let mut scaler = LossScaler::default();
+        scaler.growth_interval = 2;
+        scaler.update(false);
+        assert_eq!(scaler.scale, 32768.);
+        scaler.update(true);
+        assert_eq!(scaler.scale, 32768.);
+        scaler.update(true);
+        assert_eq!(scaler.scale, 65536.);
+        // An overflow resets the count of good steps
+        scaler.update(true);
+        scaler.update(false);
+        scaler.update(true);
+        assert_eq!(scaler.scale, 32768.);
+        for _ in 0..40 {
+            scaler.update(false);
+        }
+        assert_eq!(scaler.scale, 1.);
+    }
+}
@@ -0,0 +1,128 @@
+//! A wasm-bindgen wrapper for running trained models in the browser. Build it with
+//! `wasm-pack build --target web -- --features wasm`.
+//!
+//! Only inference is supported on `wasm32-unknown-unknown`, training needs a clock.
+
+use crate::config::Config;
+use crate::gpt::{Sampling, GPT};
+use crate::optimizer::AdamW;
+use crate::tokenizer::Tokenizer;
+use std::collections::HashSet;
+use wasm_bindgen::prelude::*;
+
+fn js_error<E: std::fmt::Display>(e: E) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+/// A model, and the text it has been fed or has generated so far
+#[wasm_bindgen]
+pub struct Model {
+    gpt: GPT<AdamW>,
+    tokenizer: Box<dyn Tokenizer + Send + Sync>,
+    /// Characters of the vocabulary, the others are dropped from the prompts
+    known: HashSet<char>,
+    context: Vec<usize>,
+}
+
+#[wasm_bindgen]
+impl Model {
+    /// Builds the model described by `config` (The TOML saved along the training
+    /// state), and loads its weights. `weights` is either a `TrainingState` or the
+    /// output of `GPT::write_params`, and `tokenizer` the saved tokenizer (Which may be
+    /// empty for the ASCII tokenizer).
+    #[wasm_bindgen(constructor)]
+    pub fn new(config: &str, tokenizer: &[u8], weights: &[u8]) -> Result<Model, JsError> {
+        let config = Config::from_toml(config).map_err(js_error)?;
+        let (gpt, tokenizer) = config.load_model(tokenizer, weights).map_err(js_error)?;
+        let known = tokenizer.known_chars();
+        Ok(Model {
+            gpt,
+            tokenizer,
+            known,
+            context: Vec::new(),
+        })
+    }
+
+    #[wasm_bindgen(js_name = numParams)]
+    pub fn num_params(&self) -> usize {
+        self.gpt.num_params()
+    }
+
+    #[wasm_bindgen(js_name = vocabSize)]
+    pub fn vocab_size(&self) -> usize {
+        self.tokenizer.vocab_size()
+    }
+
+    /// Forgets the text so far, and starts again from `prompt`
+    #[wasm_bindgen(js_name = setPrompt)]
+    pub fn set_prompt(&mut self, prompt: &str) {
+        self.context.clear();
+        self.push(prompt);
+    }
+
+    /// Appends `text` to the text so far
+    pub fn push(&mut self, text: &str) {
+        let text = text
+            .chars()
+            .filter(|ch| self.known.contains(ch))
+            .collect::<String>();
+        // Only known characters are left, so tokenizing can't fail
+        self.context
+            .extend(self.tokenizer.tokenize(&text).unwrap_or_default());
+    }
+
+    /// Generates the next token, appends it to the text so far and returns its text
+    #[wasm_bindgen(js_name = nextToken)]
+    pub fn next_token(&mut self, temperature: f32, top_p: f32) -> Result<String, JsError> {
+        if self.context.is_empty() {
+            return Err(JsError::new("there is no prompt"));
+        }
+        let prompt = &self.context[self.context.len().saturating_sub(self.gpt.num_tokens())..];
+        let mut rng = rand::thread_rng();
+        let sampling = Sampling { temperature, top_p };
+        let tokens = self
+            .gpt
+            .generate(&mut rng, prompt, 1, &sampling, |_| true)
+            .map_err(js_error)?;
+        self.context.extend(&tokens);
+        self.tokenizer.untokenize(&tokens).map_err(js_error)
+    }
+
+    /// Continues `prompt` with up to `max_tokens` tokens
+    pub fn generate(
+        &mut self,
+        prompt: &str,
+        max_tokens: usize,
+        temperature: f32,
+        top_p: f32,
+    ) -> Result<String, JsError> {
+        self.set_prompt(prompt);
+        let mut text = String::new();
+        for _ in 0..max_tokens {
+            text.push_str(&self.next_token(temperature, top_p)?);
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model() {
+        let config = "[model]\nnum_tokens = 4\nembedding_degree = 8\nnum_layers = 1\nnum_heads = 2\n\n[tokenizer]\nkind = \"ascii\"\n";
+        let parsed = Config::from_toml(config).unwrap();
+        let mut rng = rand::thread_rng();
+        let gpt = parsed.model.build(&mut rng, 128, AdamW::new()).unwrap();
+        let mut weights = Vec::new();
+        gpt.write_params(&mut weights).unwrap();
+
+        let mut model = Model::new(config, &[], &weights).unwrap();
+        assert_eq!(model.num_params(), gpt.num_params());
+        let greedy = model.generate("Hello", 6, 0., 1.).unwrap();
+        assert_eq!(greedy.len(), 6);
+        assert_eq!(model.context.len(), 11);
+        assert_eq!(model.generate("Hello", 6, 0., 1.).unwrap(), greedy);
+    }
+}